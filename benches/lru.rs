@@ -0,0 +1,38 @@
+// Timing of LruCache get/put at growing sizes; with O(1) operations the
+// per-op cost should stay flat as the cache grows. Run with `cargo bench`.
+use foobar_db::db::lru::LruCache;
+use std::hint::black_box;
+use std::time::Instant;
+
+const OPS: usize = 1_000_000;
+
+fn bench(capacity: usize) {
+    let cache = LruCache::new(capacity);
+    for i in 0..capacity {
+        cache.put(i, i, None);
+    }
+
+    let start = Instant::now();
+    for i in 0..OPS {
+        black_box(cache.get(&(i % capacity)));
+    }
+    let get = start.elapsed() / OPS as u32;
+
+    // Keys past the capacity, so every put evicts
+    let start = Instant::now();
+    for i in 0..OPS {
+        cache.put(capacity + i, i, None);
+    }
+    let put = start.elapsed() / OPS as u32;
+
+    println!(
+        "capacity {:>9}: get {:?}/op, put {:?}/op",
+        capacity, get, put
+    );
+}
+
+fn main() {
+    for capacity in [1_000, 10_000, 100_000, 1_000_000] {
+        bench(capacity);
+    }
+}
@@ -0,0 +1,89 @@
+// Parsing of a pipeline of SET requests the way a connection does it, under
+// the server's log filter at trace level. Unless built with `parser-trace`,
+// none of the parser's events may get through, and the run fails if one does.
+// Run with `cargo bench --bench parser`.
+use foobar_db::protocal::request::MAX_DEPTH;
+use foobar_db::server::logging::{self, PARSER_TARGET};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use stream_resp::parser::{ParseError, Parser};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+
+const PIPELINE: usize = 100;
+const ROUNDS: usize = 10_000;
+
+// Counts the parser's events that reach the log
+struct ParserEvents(Arc<AtomicUsize>);
+
+impl<S: Subscriber> Layer<S> for ParserEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target().starts_with(PARSER_TARGET) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn pipeline() -> Vec<u8> {
+    let mut buf = Vec::new();
+    for i in 0..PIPELINE {
+        let key = format!("key:{}", i);
+        let value = format!("value:{}", i);
+        buf.extend_from_slice(
+            format!(
+                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                key.len(),
+                key,
+                value.len(),
+                value
+            )
+            .as_bytes(),
+        );
+    }
+    buf
+}
+
+fn main() {
+    let events = Arc::new(AtomicUsize::new(0));
+    let _guard = tracing_subscriber::registry()
+        .with(ParserEvents(events.clone()))
+        .with(logging::targets(LevelFilter::TRACE))
+        .set_default();
+
+    let requests = pipeline();
+    let mut parser = Parser::new(MAX_DEPTH, usize::MAX);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        parser.buffer.extend_from_slice(&requests);
+        let mut parsed = 0;
+        while parsed < PIPELINE {
+            match parser.try_parse() {
+                Ok(Some(resp)) => {
+                    black_box(resp);
+                    parsed += 1;
+                }
+                Err(ParseError::InvalidFormat(reason))
+                    if reason == "Maximum parsing iterations exceeded" => {}
+                other => panic!("pipeline failed to parse: {:?}", other),
+            }
+        }
+        parser.buffer.clear();
+        parser.clear_buffer(0);
+    }
+    let per_request = start.elapsed() / (PIPELINE * ROUNDS) as u32;
+
+    let logged = events.load(Ordering::Relaxed);
+    println!(
+        "{} requests: {:?}/request, {} parser events logged",
+        PIPELINE * ROUNDS,
+        per_request,
+        logged
+    );
+    if !cfg!(feature = "parser-trace") {
+        assert_eq!(logged, 0, "the parser logged on the hot path");
+    }
+}
@@ -0,0 +1,90 @@
+// Pipelined SET/GET throughput of a server reading and writing its clients
+// through io_uring, against one on the epoll path, both on loopback. Each
+// client writes a pipeline and reads all of its replies before the next.
+// Both servers have one listener, so the io_uring one serves every client
+// on a single thread where epoll spreads them over the runtime's workers.
+// Run with `cargo bench --bench uring --features uring`.
+use foobar_db::server::server::ServerConfig;
+use foobar_db::server::uring;
+use foobar_db::test_util::TestServer;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CLIENTS: usize = 16;
+const PIPELINE: usize = 64;
+const ROUNDS: usize = 2_000;
+
+// SET and GET of the same key, PIPELINE requests in all, and the bytes of
+// their replies
+fn pipeline(client: usize) -> (Vec<u8>, usize) {
+    let mut buf = Vec::new();
+    for i in 0..PIPELINE / 2 {
+        let key = format!("key:{}:{}", client, i);
+        buf.extend_from_slice(
+            format!(
+                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$5\r\nvalue\r\n*2\r\n$3\r\nGET\r\n${}\r\n{}\r\n",
+                key.len(),
+                key,
+                key.len(),
+                key
+            )
+            .as_bytes(),
+        );
+    }
+    (
+        buf,
+        PIPELINE / 2 * (b"+OK\r\n".len() + b"$5\r\nvalue\r\n".len()),
+    )
+}
+
+async fn client(addr: SocketAddr, id: usize) {
+    let (requests, reply_len) = pipeline(id);
+    let mut replies = vec![0; reply_len];
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    for _ in 0..ROUNDS {
+        stream.write_all(&requests).await.unwrap();
+        stream.read_exact(&mut replies).await.unwrap();
+    }
+}
+
+async fn bench(io_uring: bool) -> Duration {
+    let server = TestServer::with_config(ServerConfig {
+        protected_mode: false,
+        io_uring,
+        ..ServerConfig::default()
+    })
+    .await;
+    // One round first, so both start from a warm server
+    client(server.addr(), CLIENTS).await;
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|id| tokio::spawn(client(server.addr(), id)))
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = uring::probe() {
+        eprintln!("io_uring is not available: {}", e);
+        return;
+    }
+    let requests = (CLIENTS * PIPELINE * ROUNDS) as f64;
+    for (name, io_uring) in [("epoll", false), ("io_uring", true)] {
+        let elapsed = bench(io_uring).await;
+        println!(
+            "{:>8}: {} clients, pipeline {}: {:.0} requests/s",
+            name,
+            CLIENTS,
+            PIPELINE,
+            requests / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,22 @@
+// cargo +nightly fuzz run parser
+//
+// Splits the input where the first bytes say, then runs it through the bare
+// parser and the server's request path. check_parser panics when one of its
+// invariants breaks, which libFuzzer reports as a crash.
+#![no_main]
+
+use foobar_db::test_util::check_parser;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&count, rest)) = data.split_first() else {
+        return;
+    };
+    let count = (count as usize % 8).min(rest.len() / 2);
+    let (header, input) = rest.split_at(count * 2);
+    let splits: Vec<usize> = header
+        .chunks(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]) as usize)
+        .collect();
+    check_parser(input, &splits);
+});
@@ -0,0 +1,292 @@
+// Derives for the command layer of foobar_db.
+//
+// #[derive(CommandTable)] goes on the Command enum. Each variant names the
+// commands it serves with one #[command] attribute per name:
+//
+//     #[command(name = "get", arity = 2, flags = READ_FAST, keys = ONE_KEY,
+//               group = "string", summary = "...", parse)]
+//     Get { key: String },
+//
+// From those it generates, in an impl of the enum:
+// - TABLE, a CommandSpec per attribute in variant order, behind COMMAND
+// - check_arity, the argument count check from_resp does before anything
+// - parse_args, which builds the variants whose attributes say parse from
+//   their arguments in field order: a String or i64 field takes one, a last
+//   Vec<String> takes the rest. The arity has to fit those fields.
+//
+// The expansion refers to table::spec and the flag and key constants of
+// src/protocal/table.rs, and to the extract_ helpers of Command, so it only
+// builds inside the command module. A variant's #[cfg] carries over to
+// everything generated for it.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Expr, ExprLit, ExprUnary, Fields,
+    GenericArgument, Lit, LitStr, PathArguments, Result, Type, UnOp, Variant,
+};
+
+#[proc_macro_derive(CommandTable, attributes(command))]
+pub fn derive_command_table(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// What one #[command] attribute says
+struct Spec {
+    name: LitStr,
+    arity: i64,
+    flags: Expr,
+    keys: Expr,
+    group: LitStr,
+    summary: LitStr,
+    parse: bool,
+}
+
+impl Spec {
+    fn from_attr(attr: &Attribute) -> Result<Self> {
+        let (mut name, mut arity, mut flags, mut keys) = (None, None, None, None);
+        let (mut group, mut summary, mut parse) = (None, None, false);
+        attr.parse_nested_meta(|meta| {
+            let key = meta.path.get_ident().map(|ident| ident.to_string());
+            match key.as_deref() {
+                Some("name") => name = Some(meta.value()?.parse::<LitStr>()?),
+                Some("arity") => arity = Some(integer(&meta.value()?.parse()?)?),
+                Some("flags") => flags = Some(meta.value()?.parse()?),
+                Some("keys") => keys = Some(meta.value()?.parse()?),
+                Some("group") => group = Some(meta.value()?.parse()?),
+                Some("summary") => summary = Some(meta.value()?.parse()?),
+                Some("parse") => parse = true,
+                _ => return Err(meta.error("unknown command attribute")),
+            }
+            Ok(())
+        })?;
+        let missing = |what: &str| Error::new_spanned(attr, format!("command without {}", what));
+        let name = name.ok_or_else(|| missing("a name"))?;
+        if name.value() != name.value().to_lowercase() {
+            return Err(Error::new_spanned(&name, "command names are lowercase"));
+        }
+        Ok(Self {
+            name,
+            arity: arity.ok_or_else(|| missing("an arity"))?,
+            flags: flags.ok_or_else(|| missing("flags"))?,
+            keys: keys.ok_or_else(|| missing("keys"))?,
+            group: group.ok_or_else(|| missing("a group"))?,
+            summary: summary.ok_or_else(|| missing("a summary"))?,
+            parse,
+        })
+    }
+
+    // The name as from_resp matches it
+    fn upper(&self) -> String {
+        self.name.value().to_uppercase()
+    }
+}
+
+fn integer(expr: &Expr) -> Result<i64> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(int), ..
+        }) => int.base10_parse(),
+        Expr::Unary(ExprUnary {
+            op: UnOp::Neg(_),
+            expr,
+            ..
+        }) => Ok(-integer(expr)?),
+        _ => Err(Error::new_spanned(expr, "expected an integer")),
+    }
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(input, "CommandTable derives for enums"));
+    };
+    let ident = &input.ident;
+    let (mut table, mut arities, mut parses) = (vec![], vec![], vec![]);
+    for variant in &data.variants {
+        let cfgs: Vec<_> = variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("cfg"))
+            .collect();
+        let specs = variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("command"))
+            .map(Spec::from_attr)
+            .collect::<Result<Vec<_>>>()?;
+        for spec in &specs {
+            let Spec {
+                name,
+                arity,
+                flags,
+                keys,
+                group,
+                summary,
+                ..
+            } = spec;
+            table.push(quote! {
+                #(#cfgs)*
+                spec(#name, #arity, #flags, #keys, #group, #summary)
+            });
+            let upper = spec.upper();
+            arities.push(quote! {
+                #(#cfgs)*
+                #upper => #arity,
+            });
+        }
+        let parsed: Vec<_> = specs.iter().filter(|spec| spec.parse).collect();
+        if parsed.is_empty() {
+            continue;
+        }
+        let build = construct(ident, variant, &parsed)?;
+        let names = parsed.iter().map(|spec| spec.upper());
+        parses.push(quote! {
+            #(#cfgs)*
+            #(#names)|* => #build,
+        });
+    }
+
+    Ok(quote! {
+        impl #ident {
+            // Entries of the COMMAND table, from the #[command] attributes
+            pub(crate) const TABLE: &'static [crate::protocal::table::CommandSpec] = {
+                use crate::protocal::table::*;
+                &[#(#table),*]
+            };
+
+            // Whether a command of the table got as many arguments as its
+            // arity says, counting the name. Other names pass.
+            fn check_arity(name: &str, argc: usize) -> ::std::result::Result<(), ::anyhow::Error> {
+                let arity: i64 = match name {
+                    #(#arities)*
+                    _ => return Ok(()),
+                };
+                let argc = argc as i64;
+                if argc == arity || (arity < 0 && argc >= -arity) {
+                    Ok(())
+                } else {
+                    Err(::anyhow::anyhow!(CommandError::WrongNumberOfArguments {
+                        command: name.to_lowercase(),
+                    }))
+                }
+            }
+
+            // The command for name when its arguments are all it takes, after
+            // check_arity. None for commands parsed by hand.
+            fn parse_args(
+                name: &str,
+                array: &[::stream_resp::resp::RespValue],
+            ) -> ::std::result::Result<Option<#ident>, ::anyhow::Error> {
+                Ok(Some(match name {
+                    #(#parses)*
+                    _ => return Ok(None),
+                }))
+            }
+        }
+    })
+}
+
+// Builds variant from the arguments after the name, checking the arity of
+// every spec against its fields
+fn construct(ident: &syn::Ident, variant: &Variant, specs: &[&Spec]) -> Result<TokenStream2> {
+    let name = &variant.ident;
+    let fields = match &variant.fields {
+        Fields::Unit => {
+            return check_fields(variant, specs, 0, false).map(|()| quote!(#ident::#name))
+        }
+        Fields::Named(fields) => &fields.named,
+        Fields::Unnamed(_) => {
+            return Err(Error::new_spanned(
+                variant,
+                "parse builds unit variants and ones with named fields",
+            ))
+        }
+    };
+    let mut inits = vec![];
+    let mut rest = false;
+    for (i, field) in fields.iter().enumerate() {
+        let field_name = field.ident.as_ref().expect("named field");
+        let index = i + 1;
+        if rest {
+            return Err(Error::new_spanned(field, "the Vec<String> field goes last"));
+        }
+        inits.push(match Arg::of(&field.ty) {
+            Some(Arg::String) => quote!(#field_name: Self::extract_string(&array[#index])?),
+            Some(Arg::Integer) => quote!(#field_name: Self::extract_integer(&array[#index])?),
+            Some(Arg::Rest) => {
+                rest = true;
+                quote! {
+                    #field_name: array[#index..]
+                        .iter()
+                        .map(Self::extract_string)
+                        .collect::<::std::result::Result<Vec<_>, _>>()?
+                }
+            }
+            None => {
+                return Err(Error::new_spanned(
+                    &field.ty,
+                    "parse takes String and i64 fields, and a last Vec<String>",
+                ))
+            }
+        });
+    }
+    check_fields(variant, specs, fields.len(), rest)?;
+    Ok(quote!(#ident::#name { #(#inits),* }))
+}
+
+// The arity of each spec has to be the name plus one argument per field,
+// with a last Vec<String> taking any number, or at least one
+fn check_fields(variant: &Variant, specs: &[&Spec], fields: usize, rest: bool) -> Result<()> {
+    let fields = fields as i64;
+    for spec in specs {
+        let fits = if rest {
+            spec.arity == -fields || spec.arity == -(fields + 1)
+        } else {
+            spec.arity == fields + 1
+        };
+        if !fits {
+            return Err(Error::new_spanned(
+                &spec.name,
+                format!(
+                    "arity {} doesn't fit the fields of {}",
+                    spec.arity, variant.ident
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+enum Arg {
+    String,
+    Integer,
+    Rest,
+}
+
+impl Arg {
+    fn of(ty: &Type) -> Option<Self> {
+        let Type::Path(path) = ty else {
+            return None;
+        };
+        let segment = path.path.segments.last()?;
+        match segment.ident.to_string().as_str() {
+            "String" => Some(Self::String),
+            "i64" => Some(Self::Integer),
+            "Vec" => {
+                let PathArguments::AngleBracketed(args) = &segment.arguments else {
+                    return None;
+                };
+                match args.args.first()? {
+                    GenericArgument::Type(inner) => {
+                        matches!(Self::of(inner)?, Self::String).then_some(Self::Rest)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use clap::Parser;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stream_resp::parser::{ParseError, Parser as RespParser};
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Load generator for foobar_db", long_about = None)]
+struct Config {
+    #[arg(short = 'H', long = "host", default_value = "127.0.0.1")]
+    host: String,
+
+    #[arg(short = 'p', long = "port", default_value = "6379")]
+    port: u16,
+
+    // Parallel connections
+    #[arg(short = 'c', long = "clients", default_value = "50")]
+    clients: usize,
+
+    // Total requests over all connections
+    #[arg(short = 'n', long = "requests", default_value = "100000")]
+    requests: usize,
+
+    // Requests written before waiting for their replies
+    #[arg(short = 'P', long = "pipeline", default_value = "1")]
+    pipeline: usize,
+
+    // SET payload size in bytes
+    #[arg(short = 'd', long = "data-size", default_value = "3")]
+    data_size: usize,
+
+    // Keys are drawn from key:0 .. key:<keyspace>
+    #[arg(short = 'r', long = "keyspace", default_value = "10000")]
+    keyspace: usize,
+
+    // Weighted command mix, e.g. set=1,get=4,incr=1
+    #[arg(long = "mix", default_value = "set=1,get=1")]
+    mix: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Set,
+    Get,
+    Incr,
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<(Op, u32)>, String> {
+    let mut ops = vec![];
+    for part in mix.split(',').filter(|p| !p.is_empty()) {
+        let (name, weight) = part.split_once('=').unwrap_or((part, "1"));
+        let op = match name.trim().to_ascii_lowercase().as_str() {
+            "set" => Op::Set,
+            "get" => Op::Get,
+            "incr" => Op::Incr,
+            other => return Err(format!("unknown command '{}' in mix", other)),
+        };
+        let weight = weight
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid weight '{}' in mix", weight))?;
+        ops.push((op, weight));
+    }
+    if ops.iter().all(|(_, weight)| *weight == 0) {
+        return Err("command mix is empty".to_string());
+    }
+    Ok(ops)
+}
+
+fn pick(mix: &[(Op, u32)], rng: &mut impl Rng) -> Op {
+    let total: u32 = mix.iter().map(|(_, w)| w).sum();
+    let mut roll = rng.gen_range(0..total);
+    for (op, weight) in mix {
+        if roll < *weight {
+            return *op;
+        }
+        roll -= weight;
+    }
+    mix[0].0
+}
+
+fn encode(buf: &mut Vec<u8>, args: &[&[u8]]) {
+    buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    // Round trip of each pipeline, spread over the requests in it
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+async fn run_client(
+    config: Arc<Config>,
+    mix: Arc<Vec<(Op, u32)>>,
+    remaining: Arc<AtomicUsize>,
+) -> Result<Stats, Box<dyn std::error::Error + Send + Sync>> {
+    let mut stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    stream.set_nodelay(true)?;
+    let mut parser = RespParser::new(10, usize::MAX);
+    let mut rng = StdRng::from_entropy();
+    let payload = vec![b'x'; config.data_size];
+    let mut stats = Stats::default();
+    let mut out = Vec::new();
+    let depth = config.pipeline.max(1);
+
+    loop {
+        let taken = remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(depth.min(left).max(1))
+            })
+            .unwrap_or(0);
+        let batch = depth.min(taken);
+        if batch == 0 {
+            break;
+        }
+
+        out.clear();
+        for _ in 0..batch {
+            let key = format!("key:{}", rng.gen_range(0..config.keyspace.max(1)));
+            match pick(&mix, &mut rng) {
+                Op::Set => encode(&mut out, &[b"SET", key.as_bytes(), &payload]),
+                Op::Get => encode(&mut out, &[b"GET", key.as_bytes()]),
+                Op::Incr => encode(&mut out, &[b"INCR", key.as_bytes()]),
+            }
+        }
+
+        let start = Instant::now();
+        stream.write_all(&out).await?;
+        let mut replies = 0;
+        while replies < batch {
+            match parser.try_parse() {
+                Ok(Some(reply)) => {
+                    replies += 1;
+                    if matches!(reply, RespValue::Error(_)) {
+                        stats.errors += 1;
+                    }
+                }
+                // Reply not complete yet
+                Ok(None) | Err(ParseError::UnexpectedEof | ParseError::NotEnoughData) => {
+                    if stream.read_buf(&mut parser.buffer).await? == 0 {
+                        return Err("server closed the connection".into());
+                    }
+                }
+                Err(e) => return Err(format!("bad reply: {:?}", e).into()),
+            }
+        }
+        let per_request = start.elapsed() / batch as u32;
+        stats
+            .latencies
+            .extend(std::iter::repeat_n(per_request, batch));
+    }
+    Ok(stats)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Arc::new(Config::parse());
+    let mix = match parse_mix(&config.mix) {
+        Ok(mix) => Arc::new(mix),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let remaining = Arc::new(AtomicUsize::new(config.requests));
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..config.clients.max(1))
+        .map(|_| tokio::spawn(run_client(config.clone(), mix.clone(), remaining.clone())))
+        .collect();
+
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for handle in handles {
+        match handle.await {
+            Ok(Ok(stats)) => {
+                latencies.extend(stats.latencies);
+                errors += stats.errors;
+            }
+            Ok(Err(e)) => eprintln!("client failed: {}", e),
+            Err(e) => eprintln!("client panicked: {}", e),
+        }
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    let done = latencies.len();
+    println!(
+        "{} requests in {:.2}s, {} clients, pipeline {}, {} byte payload, mix {}",
+        done,
+        elapsed.as_secs_f64(),
+        config.clients,
+        config.pipeline,
+        config.data_size,
+        config.mix
+    );
+    println!(
+        "throughput: {:.0} requests/s",
+        done as f64 / elapsed.as_secs_f64()
+    );
+    for p in [50.0, 95.0, 99.0, 99.9] {
+        println!("p{:<5} {:?}", p, percentile(&latencies, p));
+    }
+    println!("max    {:?}", latencies.last().copied().unwrap_or_default());
+    println!("errors {}", errors);
+}
+
+//EOF
@@ -0,0 +1,96 @@
+use clap::Parser;
+use foobar_db::sentinel::{Sentinel, SentinelConfig};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::prelude::*;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Failover agent for foobar_db", long_about = None)]
+struct Config {
+    #[arg(short = 'H', long = "bind", default_value = "127.0.0.1")]
+    bind: String,
+
+    #[arg(short = 'P', long = "port", default_value = "26379")]
+    port: u16,
+
+    // Name clients look the master up by
+    #[arg(long = "name", default_value = "mymaster")]
+    name: String,
+
+    // host:port of the master to monitor
+    #[arg(long = "master")]
+    master: String,
+
+    // host:port of a replica, besides those the master reports
+    #[arg(long = "replica")]
+    replicas: Vec<String>,
+
+    // host:port of another sentinel monitoring the same master
+    #[arg(long = "peer")]
+    peers: Vec<String>,
+
+    // Sentinels that must see the master down before a failover
+    #[arg(long = "quorum", default_value = "2")]
+    quorum: usize,
+
+    #[arg(long = "down-after-ms", default_value = "5000")]
+    down_after_ms: u64,
+
+    #[arg(long = "failover-timeout-ms", default_value = "60000")]
+    failover_timeout_ms: u64,
+
+    #[arg(long = "ping-interval-ms", default_value = "1000")]
+    ping_interval_ms: u64,
+
+    // off, error, warn, info, debug or trace
+    #[arg(long = "loglevel", default_value = "info")]
+    loglevel: LevelFilter,
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::parse();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(config.loglevel)
+        .init();
+
+    let listener = match TcpListener::bind((config.bind.as_str(), config.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}:{}: {}", config.bind, config.port, e);
+            std::process::exit(1);
+        }
+    };
+    let sentinel = Sentinel::new(SentinelConfig {
+        name: config.name,
+        master: config.master,
+        replicas: config.replicas,
+        peers: config.peers,
+        quorum: config.quorum,
+        down_after: Duration::from_millis(config.down_after_ms),
+        failover_timeout: Duration::from_millis(config.failover_timeout_ms),
+        ping_interval: Duration::from_millis(config.ping_interval_ms),
+    });
+    info!(
+        "Sentinel {} listening on {}:{}, monitoring {}",
+        sentinel.run_id(),
+        config.bind,
+        config.port,
+        sentinel.master()
+    );
+
+    tokio::select! {
+        res = sentinel.clone().serve(listener) => {
+            if let Err(e) = res {
+                eprintln!("Sentinel stopped: {}", e);
+                std::process::exit(1);
+            }
+        }
+        _ = sentinel.monitor() => {}
+        _ = signal::ctrl_c() => {}
+    }
+}
@@ -1,11 +1,33 @@
 use clap::Parser;
+use foobar_db::db::backend::{Backend, StorageKind};
+use foobar_db::db::compression::Codec;
+use foobar_db::db::eviction::EvictionPolicy;
+use foobar_db::db::lru::CachePolicy;
+use foobar_db::db::rdb;
+use foobar_db::protocal::command::ExecContext;
+use foobar_db::server::aof::{self, AppendFsync};
+use foobar_db::server::config_file;
+use foobar_db::server::listener::ListenOptions;
+use foobar_db::server::logging;
+use foobar_db::server::namespace::NamespaceConfig;
+#[cfg(feature = "otel")]
+use foobar_db::server::otel;
+use foobar_db::server::ratelimit::RateLimitKey;
 use foobar_db::server::server::{Server, ServerConfig};
 use jemallocator::Jemalloc;
-use num_cpus;
-use std::fs;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tokio::runtime::Builder;
 use tokio::signal;
-use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, Registry};
 use vergen::{BuildBuilder, CargoBuilder, Emitter, RustcBuilder, SysinfoBuilder};
 
 #[global_allocator]
@@ -14,15 +36,218 @@ static GLOBAL: Jemalloc = Jemalloc;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Config {
-    #[arg(short = 'H', long = "host", default_value = "127.0.0.1")]
-    host: String,
+    // One or more addresses, e.g. --bind 127.0.0.1 ::1
+    #[arg(
+        short = 'H',
+        long = "bind",
+        alias = "host",
+        num_args = 1..,
+        default_value = "127.0.0.1"
+    )]
+    bind: Vec<String>,
 
     #[arg(short = 'P', long = "port", default_value = "6379")]
     port: u16,
 
+    // yes or no: only accept loopback clients, as no password can be set
+    #[arg(
+        long = "protected-mode",
+        default_value = "yes",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    protected_mode: bool,
+
+    // Connections queued by the kernel before they are accepted
+    #[arg(long = "tcp-backlog", default_value = "511")]
+    tcp_backlog: u32,
+
+    // yes or no: SO_REUSEADDR on the listening sockets
+    #[arg(
+        long = "reuse-addr",
+        default_value = "yes",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    reuse_addr: bool,
+
+    // Socket buffer sizes of client connections in bytes, 0 for the system
+    // default
+    #[arg(long = "tcp-send-buffer", default_value = "0")]
+    tcp_send_buffer: usize,
+
+    #[arg(long = "tcp-recv-buffer", default_value = "0")]
+    tcp_recv_buffer: usize,
+
+    // Listeners per address sharing the port with SO_REUSEPORT, 0 for one
+    // per worker thread, 1 for a single listener
+    #[arg(long = "listeners", default_value = "1")]
+    listeners: usize,
+
+    // Reads and writes client sockets through io_uring, each listener on a
+    // thread of its own
+    #[cfg(feature = "uring")]
+    #[arg(long = "io-uring")]
+    io_uring: bool,
+
     #[arg(short = 'M', long = "max-connections", default_value = "1000")]
     max_connections: usize,
 
+    #[arg(short = 'D', long = "databases", default_value = "16")]
+    databases: usize,
+
+    #[arg(long = "maxmemory-policy", default_value = "noeviction")]
+    maxmemory_policy: EvictionPolicy,
+
+    #[arg(long = "latency-monitor-threshold", default_value = "0")]
+    latency_monitor_threshold: u64,
+
+    #[arg(long = "shards", default_value = "0")]
+    shards: usize,
+
+    // memory, disk or tiered (disk and tiered need the `disk` feature)
+    #[arg(long = "storage", default_value = "memory")]
+    storage: StorageKind,
+
+    #[arg(long = "dir", default_value = ".")]
+    dir: PathBuf,
+
+    #[arg(long = "hot-keys", default_value = "100000")]
+    hot_keys: usize,
+
+    // none, write-through, write-around or read-through[:ttl seconds]
+    #[arg(long = "cache-policy", default_value = "none")]
+    cache_policy: CachePolicy,
+
+    #[arg(long = "cache-size", default_value = "64")]
+    cache_size: usize,
+
+    // Per-worker read views for read-heavy loads, eventually consistent,
+    // usually one per core. 0 turns them off.
+    #[arg(long = "read-views", default_value = "0")]
+    read_views: usize,
+
+    // Commands per second per client, 0 for no limit
+    #[arg(long = "ratelimit-rate", default_value = "0")]
+    ratelimit_rate: u64,
+
+    #[arg(long = "ratelimit-burst", default_value = "0")]
+    ratelimit_burst: u64,
+
+    // client or user
+    #[arg(long = "ratelimit-key", default_value = "client")]
+    ratelimit_key: RateLimitKey,
+
+    // Milliseconds a command may run, 0 for no limit
+    #[arg(long = "command-timeout", default_value = "0")]
+    command_timeout: u64,
+
+    // Most elements in one request array
+    #[arg(long = "max-request-args", default_value = "65536")]
+    max_request_args: usize,
+
+    // Largest request in bytes
+    #[arg(long = "max-request-bytes", default_value = "67108864")]
+    max_request_bytes: usize,
+
+    // Collections up to these sizes keep the compact encoding
+    #[arg(long = "hash-max-listpack-entries", default_value = "128")]
+    hash_max_listpack_entries: usize,
+
+    #[arg(long = "hash-max-listpack-value", default_value = "64")]
+    hash_max_listpack_value: usize,
+
+    #[arg(long = "set-max-listpack-entries", default_value = "128")]
+    set_max_listpack_entries: usize,
+
+    #[arg(long = "set-max-listpack-value", default_value = "64")]
+    set_max_listpack_value: usize,
+
+    // Elements when positive, -1 to -5 for 4 to 64 KB
+    #[arg(
+        long = "list-max-listpack-size",
+        default_value = "-2",
+        allow_negative_numbers = true
+    )]
+    list_max_listpack_size: i64,
+
+    // none, or lz4 or zstd when built with that feature, for strings of at
+    // least compression-threshold bytes
+    #[arg(long = "compression", default_value = "none")]
+    compression: Codec,
+
+    #[arg(long = "compression-threshold", default_value = "1024")]
+    compression_threshold: usize,
+
+    // Bytes of the replication stream kept for replicas to continue from
+    #[arg(long = "repl-backlog-size", default_value = "1048576")]
+    repl_backlog_size: usize,
+
+    // user:password[:max-keys[:max-memory]], once per namespace: clients
+    // that AUTH as user get a keyspace of their own, limited to max-keys
+    // keys and max-memory bytes
+    #[arg(long = "namespace")]
+    namespaces: Vec<NamespaceConfig>,
+
+    // Line sent to clients as they connect, none by default as Redis
+    // clients don't expect one
+    #[arg(long = "welcome")]
+    welcome: Option<String>,
+
+    // Logs every write to dir/appendfilename and replays it at startup
+    #[arg(long = "appendonly")]
+    appendonly: bool,
+
+    #[arg(long = "appendfilename", default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    // always, everysec or no
+    #[arg(long = "appendfsync", default_value = "everysec")]
+    appendfsync: AppendFsync,
+
+    // Saves the dataset to dir/dbfilename on shutdown and loads it at
+    // startup when appendonly is off
+    #[arg(long = "save-on-shutdown")]
+    save_on_shutdown: bool,
+
+    #[arg(long = "dbfilename", default_value = "dump.rdb")]
+    dbfilename: String,
+
+    // Redis RDB file loaded at startup
+    #[arg(long = "import-rdb")]
+    import_rdb: Option<String>,
+
+    // RESP commands run at startup without replies, from a file or - for
+    // stdin, like redis-cli --pipe
+    #[arg(long = "pipe-import", conflicts_with = "import_rdb")]
+    pipe_import: Option<String>,
+
+    // off, error, warn, info, debug or trace
+    #[arg(long = "loglevel", default_value = "info")]
+    loglevel: LevelFilter,
+
+    // Appends to this file instead of logging to stdout
+    #[arg(long = "logfile")]
+    logfile: Option<PathBuf>,
+
+    // redis.conf style file of runtime settings: loglevel and the parameters
+    // of CONFIG SET. Applied over the command line at startup and again on
+    // SIGHUP.
+    #[arg(long = "config")]
+    config_file: Option<PathBuf>,
+
+    // OTLP/HTTP traces endpoint command spans are exported to, e.g.
+    // http://localhost:4318/v1/traces
+    #[cfg(feature = "otel")]
+    #[arg(long = "otel-endpoint")]
+    otel_endpoint: Option<String>,
+
+    // Verifies an RDB or AOF file and exits, telling where it is damaged
+    #[arg(long = "check")]
+    check: Option<PathBuf>,
+
+    // With --check, cuts a damaged file back to its last whole record
+    #[arg(long = "fix", requires = "check")]
+    fix: bool,
+
     #[arg(short = 'b', long = "build info")]
     build_info: bool,
 }
@@ -35,35 +260,202 @@ async fn run_server(mut server: Server) {
             .expect("Failed to install Ctrl+C handler");
     };
 
-    // Run server until Ctrl+C is received
+    // Run server until Ctrl+C or SHUTDOWN, then flush what persistence holds
     tokio::select! {
-        _ = server.run() => {},
-        _ = ctrl_c => {
-            server.close().await;
+        result = server.run() => {
+            if let Err(e) = result {
+                error!("Server error: {}", e);
+            }
+        },
+        _ = ctrl_c => {},
+    }
+    server.close().await;
+}
+
+// Lets the log level change while the server runs
+type LogFilter = reload::Handle<Targets, Registry>;
+
+// What logging keeps once set up
+struct Logging {
+    filter: LogFilter,
+    // Flushed on exit
+    #[cfg(feature = "otel")]
+    tracer: Option<SdkTracerProvider>,
+}
+
+fn init_logging(config: &Config) -> Logging {
+    let writer = match &config.logfile {
+        Some(path) => match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => BoxMakeWriter::new(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    // Filters the log alone, exported spans don't depend on the log level
+    let (filter, handle) = reload::Layer::new(logging::targets(config.loglevel));
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .with_ansi(config.logfile.is_none())
+            .with_writer(writer)
+            .with_filter(filter),
+    );
+    #[cfg(feature = "otel")]
+    {
+        let tracer = config.otel_endpoint.as_deref().map(|endpoint| {
+            otel::provider(endpoint).unwrap_or_else(|e| {
+                eprintln!("Failed to set up OTLP export to {}: {}", endpoint, e);
+                std::process::exit(1);
+            })
+        });
+        registry.with(tracer.as_ref().map(otel::layer)).init();
+        Logging {
+            filter: handle,
+            tracer,
         }
     }
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        Logging { filter: handle }
+    }
 }
 
-fn main() {
-    tracing_subscriber::fmt::init();
+// Applies every setting of the config file, going on past the ones that
+// fail, which are returned
+fn apply_config(
+    path: &Path,
+    ctx: &ExecContext<Backend>,
+    log: &LogFilter,
+) -> Result<(), Vec<String>> {
+    let entries = config_file::load(path).map_err(|e| vec![e])?;
+    let mut errors = Vec::new();
+    for entry in entries {
+        let result = match entry.name.as_str() {
+            "loglevel" => entry
+                .value
+                .parse::<LevelFilter>()
+                .map_err(|e| e.to_string())
+                .and_then(|level| {
+                    log.reload(logging::targets(level))
+                        .map_err(|e| e.to_string())
+                }),
+            name => ctx
+                .config_set(name, &entry.value)
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            errors.push(format!("{}:{}: {}", path.display(), entry.line, e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
 
+// Re-reads the config file on every SIGHUP. Clients stay connected, bad
+// settings are logged and the rest still applied.
+#[cfg(unix)]
+async fn reload_on_sighup(path: PathBuf, ctx: ExecContext<Backend>, log: LogFilter) {
+    let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match apply_config(&path, &ctx, &log) {
+            Ok(()) => info!("Reloaded config from {}", path.display()),
+            Err(errors) => {
+                for e in errors {
+                    warn!("Config reload: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn main() {
     let config = Config::parse();
+    let logging = init_logging(&config);
 
     if config.build_info {
         print_build_info();
         return;
     }
+    if let Some(path) = &config.check {
+        std::process::exit(check_file(path, config.fix));
+    }
 
     let server_config = ServerConfig {
-        host: config.host,
+        bind: config.bind,
         port: config.port,
+        protected_mode: config.protected_mode,
+        listen: ListenOptions {
+            backlog: config.tcp_backlog,
+            reuse_addr: config.reuse_addr,
+            send_buffer: config.tcp_send_buffer,
+            recv_buffer: config.tcp_recv_buffer,
+            listeners: config.listeners,
+        },
+        #[cfg(feature = "uring")]
+        io_uring: config.io_uring,
         max_connections: config.max_connections,
+        databases: config.databases,
+        maxmemory_policy: config.maxmemory_policy,
+        latency_monitor_threshold: config.latency_monitor_threshold,
+        shards: config.shards,
+        storage: config.storage,
+        dir: config.dir,
+        hot_keys: config.hot_keys,
+        cache_policy: config.cache_policy,
+        cache_size: config.cache_size,
+        read_views: config.read_views,
+        ratelimit_rate: config.ratelimit_rate,
+        ratelimit_burst: config.ratelimit_burst,
+        ratelimit_key: config.ratelimit_key,
+        command_timeout: config.command_timeout,
+        max_request_args: config.max_request_args,
+        max_request_bytes: config.max_request_bytes,
+        hash_max_listpack_entries: config.hash_max_listpack_entries,
+        hash_max_listpack_value: config.hash_max_listpack_value,
+        set_max_listpack_entries: config.set_max_listpack_entries,
+        set_max_listpack_value: config.set_max_listpack_value,
+        list_max_listpack_size: config.list_max_listpack_size,
+        compression: config.compression,
+        compression_threshold: config.compression_threshold,
+        repl_backlog_size: config.repl_backlog_size,
+        appendonly: config.appendonly,
+        appendfilename: config.appendfilename,
+        appendfsync: config.appendfsync,
+        save_on_shutdown: config.save_on_shutdown,
+        dbfilename: config.dbfilename,
+        welcome: config.welcome,
+        namespaces: config.namespaces,
     };
 
     print_banner();
 
-    let server = Server::new(server_config);
-
+    let server = match Server::try_new(server_config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Failed to open storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &config.config_file {
+        if let Err(errors) = apply_config(path, &server.context(), &logging.filter) {
+            for e in errors {
+                eprintln!("Bad config: {}", e);
+            }
+            std::process::exit(1);
+        }
+    }
     info!("Starting server...");
 
     let runtime: tokio::runtime::Runtime = Builder::new_multi_thread()
@@ -74,8 +466,154 @@ fn main() {
         .unwrap();
 
     runtime.block_on(async {
+        #[cfg(unix)]
+        if let Some(path) = config.config_file {
+            tokio::spawn(reload_on_sighup(
+                path,
+                server.context(),
+                logging.filter.clone(),
+            ));
+        }
+        if let Err(e) = server.recover().await {
+            eprintln!("Failed to recover the dataset: {}", e);
+            std::process::exit(1);
+        }
+        // Clients are taken while the import runs, and get LOADING errors
+        if let Some(path) = config.import_rdb {
+            let import = match server.spawn_import_rdb(path.clone()) {
+                Ok(import) => import,
+                Err(e) => {
+                    eprintln!("Failed to import {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+            tokio::spawn(async move {
+                let result = match import.await {
+                    Ok(result) => result,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to import {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            });
+        }
+        if let Some(path) = config.pipe_import {
+            let import = match server.spawn_pipe_import(path.clone()) {
+                Ok(import) => import,
+                Err(e) => {
+                    eprintln!("Failed to import {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            };
+            tokio::spawn(async move {
+                let result = match import.await {
+                    Ok(result) => result,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to import {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            });
+        }
         run_server(server).await;
     });
+
+    #[cfg(feature = "otel")]
+    if let Some(tracer) = logging.tracer {
+        if let Err(e) = tracer.shutdown() {
+            eprintln!("Failed to flush exported spans: {}", e);
+        }
+    }
+}
+
+// --check, exiting with 0 when the file is intact or was fixed. Snapshots
+// start with their magic, anything else is taken for an AOF.
+fn check_file(path: &Path, fix: bool) -> i32 {
+    let file = match fs::read(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    if file.starts_with(b"REDIS") {
+        check_rdb(path, &file, fix)
+    } else {
+        check_aof(path, &file, fix)
+    }
+}
+
+fn check_rdb(path: &Path, file: &[u8], fix: bool) -> i32 {
+    let check = rdb::check(file);
+    let Some(error) = &check.error else {
+        println!("{}: OK, {} keys", path.display(), check.entries.len());
+        return 0;
+    };
+    println!(
+        "{}: {}, {} keys and {} of {} bytes before it are intact",
+        path.display(),
+        error,
+        check.entries.len(),
+        check.valid,
+        file.len()
+    );
+    if !fix {
+        return 1;
+    }
+    if !check.fixable() {
+        println!("Not fixed: the damage can't be located");
+        return 1;
+    }
+    let fixed = rdb::truncate(file, check.valid);
+    write_fixed(path, &fixed, &format!("{} keys", check.entries.len()))
+}
+
+// Every AOF record has a checksum of its own, so damage is always located
+fn check_aof(path: &Path, file: &[u8], fix: bool) -> i32 {
+    let check = aof::check(file);
+    let Some(error) = &check.error else {
+        println!(
+            "{}: OK, {} records, {} commands",
+            path.display(),
+            check.records,
+            check.commands
+        );
+        return 0;
+    };
+    println!(
+        "{}: {} at byte {}, {} records and {} of {} bytes before it are intact",
+        path.display(),
+        error,
+        check.valid,
+        check.records,
+        check.valid,
+        file.len()
+    );
+    if !fix {
+        return 1;
+    }
+    write_fixed(
+        path,
+        &file[..check.valid],
+        &format!("{} records", check.records),
+    )
+}
+
+// Written aside first so a failure leaves the original alone
+fn write_fixed(path: &Path, fixed: &[u8], kept: &str) -> i32 {
+    let aside = path.with_extension("fixing");
+    match fs::write(&aside, fixed).and_then(|_| fs::rename(&aside, path)) {
+        Ok(()) => {
+            println!("Truncated {} to {}", path.display(), kept);
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to fix {}: {}", path.display(), e);
+            1
+        }
+    }
 }
 
 fn print_banner() {
@@ -0,0 +1,297 @@
+// Client for a cluster of nodes. CLUSTER SLOTS tells which node serves each
+// hash slot, commands go to the node owning their key, and MOVED and ASK
+// redirects are followed without the caller seeing them.
+use super::reply::{self, ClientError, FromReply};
+use super::{ClientConfig, FoobarClient};
+pub use crate::protocal::slot::key_slot;
+use crate::protocal::slot::SLOT_COUNT;
+use crate::protocal::table;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use stream_resp::resp::RespValue;
+
+// Redirects followed for one command before giving up
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, PartialEq)]
+enum Redirect {
+    // The slot has a new owner for good
+    Moved(u16, String),
+    // The slot is being migrated, ask the target for this one command
+    Ask(u16, String),
+}
+
+// Parses "MOVED 3999 127.0.0.1:6381" and "ASK 3999 127.0.0.1:6381"
+fn parse_redirect(msg: &str) -> Option<Redirect> {
+    let mut parts = msg.split(' ');
+    let kind = parts.next()?;
+    let slot = parts.next()?.parse().ok()?;
+    let addr = parts.next()?.to_string();
+    match kind {
+        "MOVED" => Some(Redirect::Moved(slot, addr)),
+        "ASK" => Some(Redirect::Ask(slot, addr)),
+        _ => None,
+    }
+}
+
+fn integer(value: &RespValue) -> Option<i64> {
+    match value {
+        RespValue::Integer(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn text<'a>(value: &'a RespValue) -> Option<&'a str> {
+    match value {
+        RespValue::BulkString(Some(s)) | RespValue::SimpleString(s) => Some(s),
+        _ => None,
+    }
+}
+
+// Turns a CLUSTER SLOTS reply into (first slot, last slot, master address)
+// ranges. Each entry is [start, end, [host, port, id], replicas...], an
+// empty host means the node that was asked.
+fn parse_slots(
+    reply: RespValue<'static>,
+    default_host: &str,
+) -> Result<Vec<(u16, u16, String)>, ClientError> {
+    let entries = match reply {
+        RespValue::Array(Some(entries)) => entries,
+        reply => return Err(ClientError::UnexpectedReply(reply)),
+    };
+    let mut ranges = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let range = match &entry {
+            RespValue::Array(Some(fields)) if fields.len() >= 3 => {
+                let start = integer(&fields[0]);
+                let end = integer(&fields[1]);
+                let master = match &fields[2] {
+                    RespValue::Array(Some(node)) if node.len() >= 2 => {
+                        text(&node[0]).zip(integer(&node[1]))
+                    }
+                    _ => None,
+                };
+                match (start, end, master) {
+                    (Some(start), Some(end), Some((host, port)))
+                        if 0 <= start && start <= end && end < SLOT_COUNT as i64 =>
+                    {
+                        let host = if host.is_empty() { default_host } else { host };
+                        Some((start as u16, end as u16, format!("{}:{}", host, port)))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match range {
+            Some(range) => ranges.push(range),
+            None => return Err(ClientError::UnexpectedReply(entry)),
+        }
+    }
+    Ok(ranges)
+}
+
+struct Inner {
+    config: ClientConfig,
+    seed: FoobarClient,
+    // Owner of each slot, None for slots no node serves
+    slots: RwLock<Vec<Option<Arc<str>>>>,
+    // A pooled client per node address
+    nodes: Mutex<HashMap<String, FoobarClient>>,
+}
+
+// Cheap to clone, clones share the slot map and the connections
+#[derive(Clone)]
+pub struct ClusterClient {
+    inner: Arc<Inner>,
+}
+
+impl ClusterClient {
+    // Connects to one node of the cluster and loads the slot map from it
+    pub async fn connect(addr: impl Into<String>) -> Result<Self, ClientError> {
+        Self::with_config(ClientConfig {
+            addr: addr.into(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    // config.addr is the first node asked, the other settings apply to the
+    // pool of every node
+    pub async fn with_config(config: ClientConfig) -> Result<Self, ClientError> {
+        let seed = FoobarClient::with_config(config.clone()).await?;
+        let client = Self {
+            inner: Arc::new(Inner {
+                nodes: Mutex::new(HashMap::from([(config.addr.clone(), seed.clone())])),
+                config,
+                seed,
+                slots: RwLock::new(vec![None; SLOT_COUNT]),
+            }),
+        };
+        client.refresh_slots().await?;
+        Ok(client)
+    }
+
+    // Reloads the slot map with CLUSTER SLOTS
+    pub async fn refresh_slots(&self) -> Result<(), ClientError> {
+        let reply = self.inner.seed.query(&["CLUSTER", "SLOTS"]).await?;
+        let seed_host = self
+            .inner
+            .config
+            .addr
+            .rsplit_once(':')
+            .map_or(self.inner.config.addr.as_str(), |(host, _)| host);
+        let ranges = parse_slots(reply, seed_host)?;
+
+        let mut slots = vec![None; SLOT_COUNT];
+        for (start, end, addr) in ranges {
+            let addr: Arc<str> = addr.into();
+            for slot in &mut slots[start as usize..=end as usize] {
+                *slot = Some(addr.clone());
+            }
+        }
+        *self.inner.slots.write().unwrap_or_else(|e| e.into_inner()) = slots;
+        Ok(())
+    }
+
+    // Address of the node owning slot, None if no node serves it
+    pub fn slot_owner(&self, slot: u16) -> Option<String> {
+        let slots = self.inner.slots.read().unwrap_or_else(|e| e.into_inner());
+        slots
+            .get(slot as usize)
+            .cloned()
+            .flatten()
+            .map(|addr| addr.to_string())
+    }
+
+    // The client of one node, connecting on first use
+    async fn node(&self, addr: &str) -> Result<FoobarClient, ClientError> {
+        if let Some(client) = self
+            .inner
+            .nodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(addr)
+        {
+            return Ok(client.clone());
+        }
+        let client = FoobarClient::with_config(ClientConfig {
+            addr: addr.to_string(),
+            ..self.inner.config.clone()
+        })
+        .await?;
+        let mut nodes = self.inner.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(nodes.entry(addr.to_string()).or_insert(client).clone())
+    }
+
+    // Slot of the command's first key, None for commands without keys
+    fn command_slot(args: &[&str]) -> Option<u16> {
+        let first_key = match table::lookup(args.first()?) {
+            Some(spec) if spec.first_key > 0 => spec.first_key as usize,
+            Some(_) => return None,
+            None => 1,
+        };
+        args.get(first_key).map(|key| key_slot(key))
+    }
+
+    // Runs any command on the node owning its first key, converting the
+    // reply to T. Commands without keys, or on a slot no node serves, go to
+    // the node the client connected to.
+    pub async fn query<T: FromReply>(&self, args: &[&str]) -> Result<T, ClientError> {
+        let mut addr = Self::command_slot(args)
+            .and_then(|slot| self.slot_owner(slot))
+            .unwrap_or_else(|| self.inner.config.addr.clone());
+        let mut asking = false;
+
+        for _ in 0..=MAX_REDIRECTS {
+            let node = self.node(&addr).await?;
+            let result = if asking {
+                // ASKING lets the importing node serve the one command after it
+                let mut pipeline = node.pipeline();
+                pipeline.cmd(&["ASKING"]).cmd(args);
+                let mut replies = pipeline.execute().await?;
+                reply::convert(replies.pop().expect("one reply per command"))
+            } else {
+                node.query(args).await
+            };
+
+            match result {
+                Err(ClientError::Server(msg)) => match parse_redirect(&msg) {
+                    Some(Redirect::Moved(slot, to)) => {
+                        let mut slots = self.inner.slots.write().unwrap_or_else(|e| e.into_inner());
+                        slots[slot as usize % SLOT_COUNT] = Some(to.as_str().into());
+                        addr = to;
+                        asking = false;
+                    }
+                    Some(Redirect::Ask(_, to)) => {
+                        addr = to;
+                        asking = true;
+                    }
+                    None => return Err(ClientError::Server(msg)),
+                },
+                result => return result,
+            }
+        }
+        Err(ClientError::TooManyRedirects)
+    }
+
+    typed_commands!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(
+            parse_redirect("MOVED 3999 127.0.0.1:6381"),
+            Some(Redirect::Moved(3999, "127.0.0.1:6381".to_string()))
+        );
+        assert_eq!(
+            parse_redirect("ASK 1 10.0.0.2:7000"),
+            Some(Redirect::Ask(1, "10.0.0.2:7000".to_string()))
+        );
+        assert_eq!(parse_redirect("ERR unknown command"), None);
+        assert_eq!(parse_redirect("MOVED x 127.0.0.1:6381"), None);
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let node = |host: &'static str, port| {
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(Cow::Borrowed(host))),
+                RespValue::Integer(port),
+                RespValue::BulkString(Some(Cow::Borrowed("id"))),
+            ]))
+        };
+        let reply = RespValue::Array(Some(vec![
+            RespValue::Array(Some(vec![
+                RespValue::Integer(0),
+                RespValue::Integer(8191),
+                node("10.0.0.1", 7000),
+                node("10.0.0.3", 7002),
+            ])),
+            RespValue::Array(Some(vec![
+                RespValue::Integer(8192),
+                RespValue::Integer(16383),
+                node("", 7001),
+            ])),
+        ]));
+        assert_eq!(
+            parse_slots(reply, "10.0.0.9").unwrap(),
+            vec![
+                (0, 8191, "10.0.0.1:7000".to_string()),
+                (8192, 16383, "10.0.0.9:7001".to_string()),
+            ]
+        );
+
+        let bad = RespValue::Array(Some(vec![RespValue::Array(Some(vec![
+            RespValue::Integer(0),
+            RespValue::Integer(16384),
+            node("10.0.0.1", 7000),
+        ]))]));
+        assert!(parse_slots(bad, "").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+// One connection to the server. Requests go out whole and the replies are
+// read back in order.
+use crate::client::reply::ClientError;
+use crate::protocal::reply;
+use bytes::BytesMut;
+use std::borrow::Cow;
+use stream_resp::parser::{ParseError, Parser};
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Deepest reply nesting accepted
+const MAX_REPLY_DEPTH: usize = 32;
+
+// Appends a command as an array of bulk strings, the form servers expect
+pub fn encode(args: &[&str], buf: &mut BytesMut) {
+    let command = RespValue::Array(Some(
+        args.iter()
+            .map(|arg| RespValue::BulkString(Some(Cow::Borrowed(*arg))))
+            .collect(),
+    ));
+    reply::encode(&command, buf);
+}
+
+pub struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    pub async fn connect(addr: &str) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream })
+    }
+
+    // Writes the encoded commands and reads one reply for each. Error
+    // replies are returned like any other.
+    pub async fn request(
+        &mut self,
+        request: &[u8],
+        replies: usize,
+    ) -> Result<Vec<RespValue<'static>>, ClientError> {
+        self.stream.write_all(request).await?;
+        let mut parser = Parser::new(MAX_REPLY_DEPTH, usize::MAX);
+        let mut out = Vec::with_capacity(replies);
+        while out.len() < replies {
+            match parser.try_parse() {
+                // Invalidation pushes are not replies
+                Ok(Some(RespValue::Push(_))) => {}
+                Ok(Some(reply)) => out.push(reply),
+                Err(ParseError::InvalidFormat(reason))
+                    if reason == "Maximum parsing iterations exceeded" => {}
+                Ok(None) | Err(ParseError::UnexpectedEof | ParseError::NotEnoughData) => {
+                    if self.stream.read_buf(&mut parser.buffer).await? == 0 {
+                        return Err(ClientError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                    }
+                }
+                Err(e) => return Err(ClientError::Protocol(format!("{:?}", e))),
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,260 @@
+// Async client for FoobarDB, built with the `client` feature. It speaks
+// plain RESP2, so it works against Redis as well. Commands go out over a
+// pool of connections, the typed methods convert replies with FromReply, and
+// a Pipeline sends many commands in one write. ClusterClient routes them to
+// the node owning the key.
+
+// The typed commands, shared by FoobarClient and ClusterClient. Both have a
+// query method the expansions call.
+macro_rules! typed_commands {
+    () => {
+        pub async fn ping(&self) -> Result<(), ClientError> {
+            self.query(&["PING"]).await
+        }
+
+        pub async fn get(&self, key: &str) -> Result<Option<String>, ClientError> {
+            self.query(&["GET", key]).await
+        }
+
+        pub async fn set(&self, key: &str, value: &str) -> Result<(), ClientError> {
+            self.query(&["SET", key, value]).await
+        }
+
+        pub async fn set_ex(
+            &self,
+            key: &str,
+            value: &str,
+            seconds: u64,
+        ) -> Result<(), ClientError> {
+            self.query(&["SETEX", key, &seconds.to_string(), value])
+                .await
+        }
+
+        // Returns how many of the keys existed
+        pub async fn del(&self, keys: &[&str]) -> Result<i64, ClientError> {
+            self.query(&[&["DEL"], keys].concat()).await
+        }
+
+        // Returns the value after the increment, a missing key counting as 0
+        pub async fn incr(&self, key: &str) -> Result<i64, ClientError> {
+            self.query(&["INCR", key]).await
+        }
+
+        // False when the key doesn't exist
+        pub async fn expire(&self, key: &str, seconds: i64) -> Result<bool, ClientError> {
+            self.query(&["EXPIRE", key, &seconds.to_string()]).await
+        }
+
+        // Seconds left, -1 without a deadline and -2 for a missing key
+        pub async fn ttl(&self, key: &str) -> Result<i64, ClientError> {
+            self.query(&["TTL", key]).await
+        }
+
+        // Returns the length of the list after the push
+        pub async fn lpush(&self, key: &str, values: &[&str]) -> Result<i64, ClientError> {
+            self.query(&[&["LPUSH", key], values].concat()).await
+        }
+
+        pub async fn rpush(&self, key: &str, values: &[&str]) -> Result<i64, ClientError> {
+            self.query(&[&["RPUSH", key], values].concat()).await
+        }
+
+        pub async fn lpop(&self, key: &str) -> Result<Option<String>, ClientError> {
+            self.query(&["LPOP", key]).await
+        }
+
+        pub async fn rpop(&self, key: &str) -> Result<Option<String>, ClientError> {
+            self.query(&["RPOP", key]).await
+        }
+
+        pub async fn llen(&self, key: &str) -> Result<i64, ClientError> {
+            self.query(&["LLEN", key]).await
+        }
+
+        pub async fn lrange(
+            &self,
+            key: &str,
+            start: i64,
+            stop: i64,
+        ) -> Result<Vec<String>, ClientError> {
+            self.query(&["LRANGE", key, &start.to_string(), &stop.to_string()])
+                .await
+        }
+
+        // Returns how many members were new
+        pub async fn sadd(&self, key: &str, members: &[&str]) -> Result<i64, ClientError> {
+            self.query(&[&["SADD", key], members].concat()).await
+        }
+
+        pub async fn srem(&self, key: &str, members: &[&str]) -> Result<i64, ClientError> {
+            self.query(&[&["SREM", key], members].concat()).await
+        }
+
+        pub async fn smembers(&self, key: &str) -> Result<Vec<String>, ClientError> {
+            self.query(&["SMEMBERS", key]).await
+        }
+
+        // Returns how many fields were new
+        pub async fn hset(&self, key: &str, field: &str, value: &str) -> Result<i64, ClientError> {
+            self.query(&["HSET", key, field, value]).await
+        }
+
+        pub async fn hget(&self, key: &str, field: &str) -> Result<Option<String>, ClientError> {
+            self.query(&["HGET", key, field]).await
+        }
+    };
+}
+
+mod cluster;
+mod connection;
+mod pool;
+mod reply;
+
+pub use cluster::{key_slot, ClusterClient};
+pub use reply::{ClientError, FromReply};
+
+use bytes::BytesMut;
+use pool::Pool;
+use std::sync::Arc;
+use std::time::Duration;
+use stream_resp::resp::RespValue;
+use tokio::time::timeout;
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    // host:port of the server
+    pub addr: String,
+    // Most connections in use at once, callers wait beyond that
+    pub max_connections: usize,
+    // Connections opened up front and kept ready
+    pub min_idle: usize,
+    // Connections idle for this long answer a PING before they are reused
+    pub health_check_after: Duration,
+    pub connect_timeout: Duration,
+    // Attempts at opening a connection before giving up, with the pause
+    // between them doubling from backoff_min up to backoff_max
+    pub connect_attempts: u32,
+    pub backoff_min: Duration,
+    pub backoff_max: Duration,
+    // How long to wait for replies, None for no limit. A blocking command
+    // needs a limit above its own timeout.
+    pub request_timeout: Option<Duration>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:6379".to_string(),
+            max_connections: 8,
+            min_idle: 0,
+            health_check_after: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            connect_attempts: 5,
+            backoff_min: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(2),
+            request_timeout: None,
+        }
+    }
+}
+
+// Cheap to clone, clones share the pool
+#[derive(Clone)]
+pub struct FoobarClient {
+    pool: Arc<Pool>,
+}
+
+impl FoobarClient {
+    // Connects with the default config, checking the server answers PING
+    pub async fn connect(addr: impl Into<String>) -> Result<Self, ClientError> {
+        Self::with_config(ClientConfig {
+            addr: addr.into(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn with_config(config: ClientConfig) -> Result<Self, ClientError> {
+        let client = Self {
+            pool: Arc::new(Pool::new(config)),
+        };
+        client.pool.fill().await?;
+        client.ping().await?;
+        Ok(client)
+    }
+
+    // Runs any command, converting the reply to T
+    pub async fn query<T: FromReply>(&self, args: &[&str]) -> Result<T, ClientError> {
+        let mut request = BytesMut::new();
+        connection::encode(args, &mut request);
+        let mut replies = self.send(&request, 1).await?;
+        reply::convert(replies.remove(0))
+    }
+
+    async fn send(
+        &self,
+        request: &[u8],
+        replies: usize,
+    ) -> Result<Vec<RespValue<'static>>, ClientError> {
+        let mut conn = self.pool.get().await?;
+        let result = match self.pool.config().request_timeout {
+            Some(limit) => timeout(limit, conn.request(request, replies))
+                .await
+                .unwrap_or(Err(ClientError::Timeout)),
+            None => conn.request(request, replies).await,
+        };
+        // The connection is closed on any failure. After a timeout the late
+        // reply would otherwise be read as the next request's.
+        match result {
+            Ok(replies) => Ok(replies),
+            Err(e) => {
+                conn.discard();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            client: self.clone(),
+            request: BytesMut::new(),
+            commands: 0,
+        }
+    }
+
+    typed_commands!();
+}
+
+// Commands queued to go out in one write on one connection
+pub struct Pipeline {
+    client: FoobarClient,
+    request: BytesMut,
+    commands: usize,
+}
+
+impl Pipeline {
+    pub fn cmd(&mut self, args: &[&str]) -> &mut Self {
+        connection::encode(args, &mut self.request);
+        self.commands += 1;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands == 0
+    }
+
+    // Sends the queued commands and returns their replies in order. A
+    // command that failed has its error reply in its place, the pipeline
+    // is empty again afterwards.
+    pub async fn execute(&mut self) -> Result<Vec<RespValue<'static>>, ClientError> {
+        let request = self.request.split();
+        let commands = std::mem::take(&mut self.commands);
+        if commands == 0 {
+            return Ok(Vec::new());
+        }
+        self.client.send(&request, commands).await
+    }
+}
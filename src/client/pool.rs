@@ -0,0 +1,169 @@
+// Connections to one server, opened on demand and kept for reuse. Opening
+// retries with exponential backoff, and connections that sat idle for a while
+// must answer a PING before they are handed out again.
+use crate::client::connection::Connection;
+use crate::client::reply::ClientError;
+use crate::client::ClientConfig;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+
+const PING: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+
+struct Idle {
+    conn: Connection,
+    since: Instant,
+}
+
+pub struct Pool {
+    config: ClientConfig,
+    idle: Mutex<Vec<Idle>>,
+    // One permit per connection that may be in use
+    permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    pub fn new(config: ClientConfig) -> Self {
+        let permits = Arc::new(Semaphore::new(config.max_connections.max(1)));
+        Self {
+            config,
+            idle: Mutex::new(Vec::new()),
+            permits,
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    fn idle(&self) -> MutexGuard<'_, Vec<Idle>> {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Opens connections until min_idle of them are waiting
+    pub async fn fill(&self) -> Result<(), ClientError> {
+        let wanted = self.config.min_idle.min(self.config.max_connections);
+        while self.idle().len() < wanted {
+            let conn = self.open().await?;
+            self.idle().push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    // Connects, retrying with a doubling pause between attempts
+    async fn open(&self) -> Result<Connection, ClientError> {
+        let mut backoff = self.config.backoff_min;
+        let mut attempt = 1;
+        loop {
+            let connect = Connection::connect(&self.config.addr);
+            let result = match timeout(self.config.connect_timeout, connect).await {
+                Ok(result) => result,
+                Err(_) => Err(ClientError::Timeout),
+            };
+            match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt >= self.config.connect_attempts => return Err(e),
+                Err(e) => {
+                    debug!(
+                        "Connecting to {} failed ({}), retrying in {:?}",
+                        self.config.addr, e, backoff
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.backoff_max);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Whether a connection idle since then still answers
+    async fn healthy(&self, conn: &mut Connection, since: Instant) -> bool {
+        if since.elapsed() < self.config.health_check_after {
+            return true;
+        }
+        matches!(
+            timeout(self.config.connect_timeout, conn.request(PING, 1)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    // Waits while max_connections are in use, then hands out a healthy idle
+    // connection or opens a new one
+    pub async fn get(self: &Arc<Self>) -> Result<PooledConnection, ClientError> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+        let conn = loop {
+            let idle = self.idle().pop();
+            match idle {
+                Some(Idle { mut conn, since }) => {
+                    if self.healthy(&mut conn, since).await {
+                        break conn;
+                    }
+                    debug!("Dropped a dead connection to {}", self.config.addr);
+                }
+                None => break self.open().await?,
+            }
+        };
+        Ok(PooledConnection {
+            pool: self.clone(),
+            conn: Some(conn),
+            _permit: permit,
+        })
+    }
+}
+
+// Goes back to the pool when dropped, unless discarded
+pub struct PooledConnection {
+    pool: Arc<Pool>,
+    conn: Option<Connection>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    // Closes a connection that failed, its stream can't be trusted anymore.
+    // A replacement is opened in the background when min_idle asks for one.
+    pub fn discard(mut self) {
+        self.conn = None;
+        if self.pool.config.min_idle > 0 {
+            let pool = self.pool.clone();
+            tokio::spawn(async move {
+                let _ = pool.fill().await;
+            });
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection taken")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection taken")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle().push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+    }
+}
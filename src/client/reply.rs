@@ -0,0 +1,160 @@
+// Errors of the client and the conversion of replies to Rust types
+use std::fmt;
+use stream_resp::resp::RespValue;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    // No connection or no reply within the configured time
+    Timeout,
+    // The server sent something that isn't RESP
+    Protocol(String),
+    // An error reply, e.g. "WRONGTYPE Operation against a key ..."
+    Server(String),
+    // A reply of another type than the command returns
+    UnexpectedReply(RespValue<'static>),
+    // A cluster kept redirecting the command
+    TooManyRedirects,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Self::Server(msg) => write!(f, "{}", msg),
+            Self::UnexpectedReply(reply) => write!(f, "unexpected reply: {:?}", reply),
+            Self::TooManyRedirects => write!(f, "too many cluster redirects"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// Types a reply converts to. Error replies never get here, they become
+// ClientError::Server first.
+pub trait FromReply: Sized {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError>;
+}
+
+// Turns an error reply into ClientError::Server, anything else converts
+pub fn convert<T: FromReply>(reply: RespValue<'static>) -> Result<T, ClientError> {
+    match reply {
+        RespValue::Error(msg) | RespValue::BulkError(Some(msg)) => {
+            Err(ClientError::Server(msg.into_owned()))
+        }
+        reply => T::from_reply(reply),
+    }
+}
+
+impl FromReply for RespValue<'static> {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        Ok(reply)
+    }
+}
+
+// Status replies such as +OK
+impl FromReply for () {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::SimpleString(_) => Ok(()),
+            reply => Err(ClientError::UnexpectedReply(reply)),
+        }
+    }
+}
+
+impl FromReply for String {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::SimpleString(s)
+            | RespValue::BulkString(Some(s))
+            | RespValue::VerbatimString(Some(s)) => Ok(s.into_owned()),
+            reply => Err(ClientError::UnexpectedReply(reply)),
+        }
+    }
+}
+
+impl FromReply for i64 {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::Integer(n) => Ok(n),
+            reply => Err(ClientError::UnexpectedReply(reply)),
+        }
+    }
+}
+
+// Integer replies of 0 and 1, like EXPIRE's
+impl FromReply for bool {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::Integer(n) => Ok(n != 0),
+            RespValue::Boolean(b) => Ok(b),
+            reply => Err(ClientError::UnexpectedReply(reply)),
+        }
+    }
+}
+
+// Nil in RESP2 and RESP3 becomes None
+impl<T: FromReply> FromReply for Option<T> {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::BulkString(None) | RespValue::Array(None) | RespValue::Null => Ok(None),
+            reply => T::from_reply(reply).map(Some),
+        }
+    }
+}
+
+impl<T: FromReply> FromReply for Vec<T> {
+    fn from_reply(reply: RespValue<'static>) -> Result<Self, ClientError> {
+        match reply {
+            RespValue::Array(Some(items)) | RespValue::Set(Some(items)) => {
+                items.into_iter().map(convert).collect()
+            }
+            RespValue::Array(None) | RespValue::Null => Ok(Vec::new()),
+            reply => Err(ClientError::UnexpectedReply(reply)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn bulk(s: &'static str) -> RespValue<'static> {
+        RespValue::BulkString(Some(Cow::Borrowed(s)))
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(convert::<String>(bulk("v")).unwrap(), "v");
+        assert_eq!(
+            convert::<Option<String>>(RespValue::BulkString(None)).unwrap(),
+            None
+        );
+        assert_eq!(convert::<Option<String>>(RespValue::Null).unwrap(), None);
+        assert_eq!(convert::<i64>(RespValue::Integer(3)).unwrap(), 3);
+        assert!(convert::<bool>(RespValue::Integer(1)).unwrap());
+        assert_eq!(
+            convert::<Vec<String>>(RespValue::Array(Some(vec![bulk("a"), bulk("b")]))).unwrap(),
+            vec!["a", "b"]
+        );
+        convert::<()>(RespValue::SimpleString(Cow::Borrowed("OK"))).unwrap();
+
+        assert!(matches!(
+            convert::<String>(RespValue::Error(Cow::Borrowed("ERR nope"))),
+            Err(ClientError::Server(msg)) if msg == "ERR nope"
+        ));
+        assert!(matches!(
+            convert::<i64>(bulk("1")),
+            Err(ClientError::UnexpectedReply(_))
+        ));
+    }
+}
@@ -0,0 +1,267 @@
+// Storage the server runs on, picked with --storage. Storage has generic
+// methods so it cannot be a trait object; the server holds this enum and
+// every call is dispatched to the selected implementation.
+#[cfg(feature = "disk")]
+use crate::db::disk::SledStorage;
+use crate::db::storage::{DashMapStorage, Result, Storage, StorageError, Update};
+#[cfg(feature = "disk")]
+use crate::db::tiered::TieredStorage;
+use crate::db::value::Value;
+use std::borrow::Borrow;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageKind {
+    #[default]
+    Memory,
+    Disk,
+    // Memory for recently used keys, disk for the rest
+    Tiered,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "disk" => Ok(Self::Disk),
+            "tiered" => Ok(Self::Tiered),
+            _ => Err(format!("invalid storage '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory => write!(f, "memory"),
+            Self::Disk => write!(f, "disk"),
+            Self::Tiered => write!(f, "tiered"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Backend {
+    Memory(DashMapStorage<String, Value>),
+    #[cfg(feature = "disk")]
+    Disk(SledStorage),
+    #[cfg(feature = "disk")]
+    // Boxed, as it holds a cold tier and a hot one besides
+    Tiered(Box<TieredStorage>),
+}
+
+impl Backend {
+    // One storage per logical database. Disk and tiered databases share a
+    // single sled instance under `dir`, one tree each; tiered ones keep up to
+    // `hot_keys` keys in memory.
+    pub fn open_all(
+        kind: StorageKind,
+        dir: &Path,
+        databases: usize,
+        hot_keys: usize,
+    ) -> Result<Vec<Self>> {
+        match kind {
+            StorageKind::Memory => Ok((0..databases)
+                .map(|_| Self::Memory(DashMapStorage::new()))
+                .collect()),
+            #[cfg(feature = "disk")]
+            StorageKind::Disk | StorageKind::Tiered => {
+                let db = sled::open(dir).map_err(|e| StorageError::Internal(e.to_string()))?;
+                (0..databases)
+                    .map(|index| {
+                        let cold = SledStorage::open(&db, &format!("db{}", index))?;
+                        Ok(match kind {
+                            StorageKind::Tiered => {
+                                Self::Tiered(Box::new(TieredStorage::new(cold, hot_keys)))
+                            }
+                            _ => Self::Disk(cold),
+                        })
+                    })
+                    .collect()
+            }
+            #[cfg(not(feature = "disk"))]
+            StorageKind::Disk | StorageKind::Tiered => {
+                let _ = (dir, hot_keys);
+                Err(StorageError::InvalidOperation(format!(
+                    "{} storage needs a build with the `disk` feature",
+                    kind
+                )))
+            }
+        }
+    }
+
+    // Makes the data on disk complete, for a server on its way out
+    pub fn flush(&self) -> Result<()> {
+        match self {
+            Self::Memory(_) => Ok(()),
+            #[cfg(feature = "disk")]
+            Self::Disk(storage) => storage.flush(),
+            #[cfg(feature = "disk")]
+            Self::Tiered(storage) => storage.flush(),
+        }
+    }
+
+    // Whether calls can wait on disk I/O, so must not run as if they were
+    // quick on a runtime worker
+    pub fn blocks(&self) -> bool {
+        !matches!(self, Self::Memory(_))
+    }
+}
+
+// Runs f, which may wait on disk I/O, telling a multi-threaded runtime so it
+// hands the other tasks of this worker to the rest meanwhile. On any other
+// thread f just runs.
+pub fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// blocking for a command, which is a future
+pub async fn blocking_exec<F: Future>(fut: F) -> F::Output {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(fut))
+        }
+        _ => fut.await,
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Memory(DashMapStorage::new())
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $s:ident => $call:expr) => {
+        match $self {
+            Backend::Memory($s) => $call,
+            #[cfg(feature = "disk")]
+            Backend::Disk($s) => $call,
+            #[cfg(feature = "disk")]
+            Backend::Tiered($s) => $call,
+        }
+    };
+}
+
+impl Storage<String, Value> for Backend {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Value>>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        dispatch!(self, s => s.get(key))
+    }
+
+    fn set(&self, key: String, value: Value) -> Result<Option<Value>> {
+        dispatch!(self, s => s.set(key, value))
+    }
+
+    fn insert_if_absent(&self, key: String, value: Value) -> Result<bool> {
+        dispatch!(self, s => s.insert_if_absent(key, value))
+    }
+
+    fn update<F, R>(&self, key: String, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut Value>) -> (Update<Value>, R),
+    {
+        dispatch!(self, s => s.update(key, f))
+    }
+
+    fn delete<Q>(&self, key: &Q) -> Result<Option<Value>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        dispatch!(self, s => s.delete(key))
+    }
+
+    fn version<Q>(&self, key: &Q) -> Result<u64>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        dispatch!(self, s => s.version(key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Value,
+    ) -> Result<Option<u64>> {
+        dispatch!(self, s => s.compare_and_swap(key, expected_version, value))
+    }
+
+    fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> Result<bool>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        dispatch!(self, s => s.compare_and_delete(key, expected_version))
+    }
+
+    fn clear(&self) -> Result<()> {
+        dispatch!(self, s => s.clear())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        dispatch!(self, s => s.keys())
+    }
+
+    fn len(&self) -> usize {
+        dispatch!(self, s => s.len())
+    }
+
+    fn info(&self) -> Vec<(&'static str, u64)> {
+        dispatch!(self, s => s.info())
+    }
+
+    fn set_expiry(&self, key: &String, when: Option<u64>) -> Result<()> {
+        dispatch!(self, s => s.set_expiry(key, when))
+    }
+
+    fn expiries(&self) -> Result<Vec<(String, u64)>> {
+        dispatch!(self, s => s.expiries())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_exec() {
+        // A task queued behind a blocked worker still gets to run
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = tokio::spawn(async move {
+            blocking_exec(async move { rx.recv_timeout(Duration::from_secs(5)).is_ok() }).await
+        });
+        tokio::spawn(async move { tx.send(()).unwrap() })
+            .await
+            .unwrap();
+        assert!(waiter.await.unwrap());
+        assert_eq!(blocking(|| 1), 1);
+        // Commands of a disk backed server nest the two
+        assert_eq!(blocking_exec(async { blocking(|| 1) }).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_exec_current_thread() {
+        assert_eq!(blocking_exec(async { 1 }).await, 1);
+        assert_eq!(blocking(|| 1), 1);
+    }
+}
@@ -0,0 +1,155 @@
+// String values as bytes. A string is stored as a Value::Str, or as a
+// Value::Compressed (see compression.rs). ByteString reads a string in
+// either form and writes back the one that fits, so string commands work on
+// the bytes alone.
+use crate::db::compression::{self, Compression, CompressionStats};
+use crate::db::value::Value;
+use std::borrow::Cow;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteString<'a>(Cow<'a, [u8]>);
+
+impl<'a> ByteString<'a> {
+    pub fn new(bytes: impl Into<Cow<'a, [u8]>>) -> Self {
+        Self(bytes.into())
+    }
+
+    // The string a stored value holds, None for the other types
+    pub fn from_stored(value: &'a Value) -> Option<Self> {
+        match value {
+            Value::Str(bytes) => Some(Self(Cow::Borrowed(bytes))),
+            _ => compression::decompress(value).map(|bytes| Self(Cow::Owned(bytes))),
+        }
+    }
+
+    // Length of the string a stored value holds, without decompressing it
+    pub fn stored_len(value: &Value) -> Option<usize> {
+        match value {
+            Value::Str(bytes) => Some(bytes.len()),
+            Value::Compressed(compressed) => Some(compressed.len()),
+            _ => None,
+        }
+    }
+
+    pub fn into_owned(self) -> ByteString<'static> {
+        ByteString(Cow::Owned(self.0.into_owned()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Writes bytes at offset, padding with zero bytes up to it
+    pub fn set_range(&mut self, offset: usize, bytes: &[u8]) {
+        let string = self.0.to_mut();
+        if string.len() < offset + bytes.len() {
+            string.resize(offset + bytes.len(), 0);
+        }
+        string[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // Stored form without compression
+    pub fn into_value(self) -> Value {
+        Value::Str(self.0.into_owned())
+    }
+
+    // Stored form, compressed under config when that pays off, which stats
+    // counts
+    pub fn into_stored(self, config: &Compression, stats: &CompressionStats) -> Value {
+        match compression::compress(&self.0, config) {
+            Some(packed) => {
+                if let Value::Compressed(compressed) = &packed {
+                    stats.record(self.len(), compressed.stored_len());
+                }
+                packed
+            }
+            None => self.into_value(),
+        }
+    }
+
+    // As sent to clients. Replies carry text, so bytes that aren't UTF-8
+    // come out replaced.
+    pub fn to_reply(&self) -> RespValue<'static> {
+        RespValue::BulkString(Some(Cow::Owned(
+            String::from_utf8_lossy(&self.0).into_owned(),
+        )))
+    }
+}
+
+// A stored value as a string command sets it: a plain string is written
+// the way into_stored picks, anything else is left alone
+pub fn stored(value: Value, config: &Compression, stats: &CompressionStats) -> Value {
+    match value {
+        Value::Str(bytes) => ByteString::new(bytes).into_stored(config, stats),
+        value => value,
+    }
+}
+
+// A read's view of a stored value, with a compressed string decompressed
+pub fn expanded(value: Option<Arc<Value>>) -> Option<Arc<Value>> {
+    match value.as_deref().and_then(compression::decompress) {
+        Some(bytes) => Some(Arc::new(Value::Str(bytes))),
+        None => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::listpack::compact;
+
+    #[test]
+    fn test_stored_forms() {
+        let text = ByteString::new(b"hello".as_slice());
+        let value = text.clone().into_value();
+        assert_eq!(value, Value::str("hello"));
+        assert_eq!(ByteString::from_stored(&value), Some(text));
+        assert_eq!(ByteString::stored_len(&value), Some(5));
+
+        // Bytes that aren't UTF-8 are kept, and only replaced in replies
+        let binary = ByteString::new(vec![0xc3, 0x28, 0x00, 0xff]);
+        let value = binary.clone().into_value();
+        assert_eq!(ByteString::from_stored(&value), Some(binary.clone()));
+        assert_eq!(
+            binary.to_reply(),
+            RespValue::BulkString(Some(Cow::Borrowed("\u{fffd}(\0\u{fffd}")))
+        );
+
+        // Collections aren't strings, compact ones included
+        let list = compact(
+            &Value::List(vec!["a".to_string()].into()),
+            &Default::default(),
+        )
+        .unwrap();
+        assert_eq!(ByteString::from_stored(&list), None);
+        assert_eq!(ByteString::stored_len(&list), None);
+        assert_eq!(ByteString::from_stored(&Value::List(vec![].into())), None);
+    }
+
+    #[test]
+    fn test_set_range() {
+        let mut string = ByteString::new(b"Hello".as_slice());
+        string.set_range(6, b"World");
+        assert_eq!(string.as_bytes(), b"Hello\0World");
+        string.set_range(0, b"J");
+        assert_eq!(string.as_bytes(), b"Jello\0World");
+
+        // Halves of a character make it whole again
+        let mut split = ByteString::new(vec![]);
+        split.set_range(0, &[0xc3]);
+        let value = split.into_value();
+        let mut split = ByteString::from_stored(&value).unwrap().into_owned();
+        split.set_range(1, &[0xa9]);
+        assert_eq!(split.into_value(), Value::str("é"));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::db::db::now_ms;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The time TTLs, idle times and the latency monitor go by. It follows the
+// system clock unless frozen, and can be moved ahead, so tests and DEBUG can
+// make keys expire or age without waiting. Unix milliseconds like now_ms.
+#[derive(Debug, Default)]
+pub struct Clock {
+    // Added to the system time while running
+    offset: AtomicU64,
+    // The time it stands at while frozen, 0 while running
+    frozen: AtomicU64,
+}
+
+impl Clock {
+    pub fn now_ms(&self) -> u64 {
+        match self.frozen.load(Ordering::SeqCst) {
+            0 => now_ms().saturating_add(self.offset.load(Ordering::SeqCst)),
+            at => at,
+        }
+    }
+
+    pub fn now_secs(&self) -> u64 {
+        self.now_ms() / 1000
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst) != 0
+    }
+
+    // Stops the clock where it is, a frozen clock stays put
+    pub fn freeze(&self) {
+        let now = self.now_ms().max(1);
+        let _ = self
+            .frozen
+            .compare_exchange(0, now, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    // Runs again from the time it was frozen at
+    pub fn resume(&self) {
+        let at = self.frozen.swap(0, Ordering::SeqCst);
+        if at != 0 {
+            self.offset
+                .store(at.saturating_sub(now_ms()), Ordering::SeqCst);
+        }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        let moved = self
+            .frozen
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |at| {
+                (at != 0).then(|| at.saturating_add(ms))
+            });
+        if moved.is_err() {
+            self.offset.fetch_add(ms, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let clock = Clock::default();
+        assert!(clock.now_ms().abs_diff(now_ms()) < 1000);
+
+        clock.freeze();
+        assert!(clock.is_frozen());
+        let at = clock.now_ms();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(clock.now_ms(), at);
+        clock.advance(60_000);
+        assert_eq!(clock.now_ms(), at + 60_000);
+
+        // Runs on from where it stood, not from the system time
+        clock.resume();
+        assert!(!clock.is_frozen());
+        assert!(clock.now_ms() >= at + 60_000);
+        assert!(clock.now_ms() < at + 61_000);
+        clock.advance(1000);
+        assert!(clock.now_ms() >= at + 61_000);
+    }
+}
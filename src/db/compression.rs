@@ -0,0 +1,235 @@
+// Transparent compression of large string values. A string of at least
+// the threshold is stored compressed when that makes it smaller, and reads
+// get the plain bytes back (see bytestring.rs). The stored form is a
+// Value::Compressed, the compressed bytes with their codec and plain length.
+//
+// The codecs are behind the `lz4` and `zstd` features. Values are written
+// out plain by DUMP and RDB saves, so nothing compressed leaves the process.
+use crate::db::value::Value;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "no" => Ok(Self::None),
+            "lz4" if cfg!(feature = "lz4") => Ok(Self::Lz4),
+            "zstd" if cfg!(feature = "zstd") => Ok(Self::Zstd),
+            codec @ ("lz4" | "zstd") => Err(format!(
+                "{} compression needs the `{}` feature",
+                codec, codec
+            )),
+            _ => Err(format!("invalid compression '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Lz4 => write!(f, "lz4"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    // Bytes a string needs before it is compressed
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            codec: Codec::None,
+            threshold: 1024,
+        }
+    }
+}
+
+// Strings compressed so far and their bytes before and after, for INFO
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    values: AtomicU64,
+    plain_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn record(&self, plain: usize, stored: usize) {
+        self.values.fetch_add(1, Ordering::Relaxed);
+        self.plain_bytes.fetch_add(plain as u64, Ordering::Relaxed);
+        self.stored_bytes
+            .fetch_add(stored as u64, Ordering::Relaxed);
+    }
+
+    // (values, plain bytes, stored bytes)
+    pub fn totals(&self) -> (u64, u64, u64) {
+        (
+            self.values.load(Ordering::Relaxed),
+            self.plain_bytes.load(Ordering::Relaxed),
+            self.stored_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// A string stored compressed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compressed {
+    codec: Codec,
+    // Bytes of the plain string
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl Compressed {
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    // Length of the plain string
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Bytes it takes compressed
+    pub fn stored_len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+pub fn is_compressed(value: &Value) -> bool {
+    matches!(value, Value::Compressed(_))
+}
+
+// Bytes compressed with codec, None when it isn't built in
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn encode(codec: Codec, bytes: &[u8]) -> Option<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => Some(lz4_flex::compress(bytes)),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::compress(bytes, ZSTD_LEVEL).ok(),
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decode(codec: Codec, bytes: &[u8], len: usize) -> Option<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => lz4_flex::decompress(bytes, len).ok(),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::bulk::decompress(bytes, len).ok(),
+        _ => None,
+    }
+}
+
+// Compressed form of a string at or over the threshold, when it comes out
+// smaller
+pub fn compress(bytes: &[u8], config: &Compression) -> Option<Value> {
+    if bytes.len() < config.threshold {
+        return None;
+    }
+    let packed = encode(config.codec, bytes)?;
+    (packed.len() < bytes.len()).then(|| {
+        Value::Compressed(Compressed {
+            codec: config.codec,
+            len: bytes.len(),
+            bytes: packed,
+        })
+    })
+}
+
+// The plain bytes of a compressed value, None for anything else
+pub fn decompress(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Compressed(compressed) => {
+            decode(compressed.codec, &compressed.bytes, compressed.len)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_names() {
+        assert_eq!("none".parse::<Codec>().unwrap(), Codec::None);
+        assert_eq!("lz4".parse::<Codec>().is_ok(), cfg!(feature = "lz4"));
+        assert_eq!("ZSTD".parse::<Codec>().is_ok(), cfg!(feature = "zstd"));
+        assert!("gzip".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        let config = Compression {
+            codec: Codec::None,
+            threshold: 0,
+        };
+        assert!(compress("a".repeat(4096).as_bytes(), &config).is_none());
+        assert!(decompress(&Value::str("lz4:3:abc")).is_none());
+        assert!(!is_compressed(&Value::str("lz4:3:abc")));
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_round_trip() {
+        let codecs = [
+            #[cfg(feature = "lz4")]
+            Codec::Lz4,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+        ];
+        // Any bytes, not only text
+        let mut large = "foobar_db compresses repetitive strings. "
+            .repeat(100)
+            .into_bytes();
+        large.extend_from_slice(&[0xff, 0x00, 0xc3]);
+        for codec in codecs {
+            let config = Compression {
+                codec,
+                threshold: 1024,
+            };
+            let stored = compress(&large, &config).unwrap();
+            assert!(matches!(&stored, Value::Compressed(c) if c.len() == large.len()));
+            assert_eq!(decompress(&stored).unwrap(), large);
+
+            // Small, or not smaller compressed: kept as is
+            assert!(compress(b"short", &config).is_none());
+            let mut x = 0x9e37_79b9_7f4a_7c15u64;
+            let noise: Vec<u8> = (0..2048)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    (x >> 32) as u8
+                })
+                .collect();
+            assert!(compress(&noise, &config).is_none());
+        }
+    }
+}
@@ -1,9 +1,88 @@
-use crate::db::lru::LruCache;
-use crate::db::storage::Storage;
+use crate::db::clock::Clock;
+use crate::db::compression::{Compression, CompressionStats};
+use crate::db::eviction::{lru_clock, Access, EvictionPolicy, LfuCounter, LFU_INIT_VAL};
+use crate::db::expire::{self, ExpireInfo, ExpireStats, EXPIRE_BATCH};
+use crate::db::hotkeys::HotKeys;
+use crate::db::lazyfree::{FreeEffort, LazyFree};
+use crate::db::listpack::ListpackLimits;
+use crate::db::lru::{CachePolicy, CacheStats, LruCache};
+use crate::db::readview::{ReadViews, View};
+use crate::db::storage::{Storage, Update};
+use crate::db::ttlheap::TtlHeap;
+use crate::db::types::{Typed, ValueType, WrongTypeError};
 use anyhow::{Error, Ok};
-use std::hash::Hash;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{futures::Notified, Notify};
+
+// The logical databases of a server, addressed by SELECT index
+pub type Databases<S, K, V> = Arc<Vec<Arc<DB<S, K, V>>>>;
+
+// Told the name of every key written, after the write
+pub type WriteHook<K> = Box<dyn Fn(&K) + Send + Sync>;
+
+// Told the name of every key the db expired itself, after it is gone
+pub type ExpireHook<K> = Box<dyn Fn(&K) + Send + Sync>;
+
+// Value and deadline of a key, None when it doesn't exist
+type Kept<V> = Option<(Arc<V>, Option<u64>)>;
+
+// What a snapshot keeps of the keys written since it was taken, as they
+// were then
+struct Frozen<K, V> {
+    // Unix ms the snapshot was taken at
+    at: u64,
+    kept: DashMap<K, Kept<V>>,
+}
+
+// Condition flags accepted by the EXPIRE family (NX | XX | GT | LT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExpireCondition {
+    #[default]
+    Always,
+    IfNoExpiry,
+    IfHasExpiry,
+    IfGreater,
+    IfLess,
+}
+
+// How GETEX should leave the TTL of the key it reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlUpdate {
+    Keep,
+    Persist,
+    At(u64),
+}
+
+// TTL state of a key as reported by TTL/EXPIRETIME and friends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    NoKey,
+    Persistent,
+    At(u64),
+}
+
+// Milliseconds since the unix epoch, the unit of every stored expiry
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Position of a key in SCAN order. DefaultHasher::new always uses the same
+// keys, so cursors stay valid for the life of the process.
+fn scan_hash<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
 
 pub struct DB<S, K, V>
 where
@@ -12,7 +91,42 @@ where
     V: Clone + Send + Sync + 'static,
 {
     storage: Arc<S>,
+    // Absolute unix-ms deadlines of volatile keys, kept apart from the values
+    expires: DashMap<K, u64>,
+    // The same deadlines in order, for active expiry
+    ttl: TtlHeap<K>,
+    expire_stats: ExpireStats,
     cache: Arc<LruCache<K, V>>,
+    cache_policy: RwLock<CachePolicy>,
+    // Bumped by every write once the storage holds the new value. A cache
+    // fill that sees it move may be racing that write and backs out.
+    cache_epoch: AtomicU64,
+    lazyfree: LazyFree<V>,
+    // Shared by single-key operations, taken exclusively by multi-key ones
+    // so nothing observes the keyspace halfway through them
+    barrier: RwLock<()>,
+    // Woken whenever a list gains elements, for blocking pops
+    ready: Notify,
+    policy: RwLock<EvictionPolicy>,
+    // Sizes up to which collections keep the compact encoding
+    listpack: RwLock<ListpackLimits>,
+    // How large strings are compressed, and what that saved
+    compression: RwLock<Compression>,
+    compression_stats: CompressionStats,
+    // Access frequency per key under an LFU policy, last access otherwise
+    access: DashMap<K, Access>,
+    hotkeys: HotKeys<K>,
+    write_hook: OnceLock<WriteHook<K>>,
+    expire_hook: OnceLock<ExpireHook<K>>,
+    // Timers that came due while their key was held, for the next cycle
+    busy: Mutex<Vec<(K, u64)>>,
+    // Per-worker copies reads are served from, when enabled
+    views: OnceLock<ReadViews<K, V>>,
+    // Snapshots in progress
+    frozen: RwLock<Vec<Arc<Frozen<K, V>>>>,
+    clock: Arc<Clock>,
+    // Whether active_expire runs, DEBUG SET-ACTIVE-EXPIRE turns it off
+    active_expire: AtomicBool,
     _marker: PhantomData<(K, V)>,
 }
 
@@ -25,29 +139,1828 @@ where
     pub fn new(storage: S, cache_size: usize) -> Self {
         Self {
             storage: Arc::new(storage),
+            expires: DashMap::new(),
+            ttl: TtlHeap::default(),
+            expire_stats: ExpireStats::default(),
             cache: Arc::new(LruCache::new(cache_size)),
+            cache_policy: RwLock::new(CachePolicy::default()),
+            cache_epoch: AtomicU64::new(0),
+            lazyfree: LazyFree::new(),
+            barrier: RwLock::new(()),
+            ready: Notify::new(),
+            policy: RwLock::new(EvictionPolicy::default()),
+            listpack: RwLock::new(ListpackLimits::default()),
+            compression: RwLock::new(Compression::default()),
+            compression_stats: CompressionStats::default(),
+            access: DashMap::new(),
+            hotkeys: HotKeys::new(),
+            write_hook: OnceLock::new(),
+            expire_hook: OnceLock::new(),
+            busy: Mutex::new(Vec::new()),
+            views: OnceLock::new(),
+            frozen: RwLock::new(Vec::new()),
+            clock: Arc::new(Clock::default()),
+            active_expire: AtomicBool::new(true),
             _marker: PhantomData,
         }
     }
 
+    // Goes by clock instead of one of its own, so dbs sharing it agree on
+    // the time
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Arc<Clock> {
+        &self.clock
+    }
+
+    pub fn set_active_expire(&self, enabled: bool) {
+        self.active_expire.store(enabled, Ordering::SeqCst);
+    }
+
+    fn shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.barrier.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.barrier.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn eviction_policy(&self) -> EvictionPolicy {
+        *self.policy.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Switching between LFU and the others drops what was tracked, keys not
+    // used since then count as just used
+    pub fn set_eviction_policy(&self, policy: EvictionPolicy) {
+        let old = std::mem::replace(
+            &mut *self.policy.write().unwrap_or_else(|e| e.into_inner()),
+            policy,
+        );
+        if old.is_lfu() != policy.is_lfu() {
+            self.access.clear();
+        }
+    }
+
+    pub fn listpack_limits(&self) -> ListpackLimits {
+        *self.listpack.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Applies to collections as they are next written
+    pub fn set_listpack_limits(&self, limits: ListpackLimits) {
+        *self.listpack.write().unwrap_or_else(|e| e.into_inner()) = limits;
+    }
+
+    pub fn compression(&self) -> Compression {
+        *self.compression.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Applies to strings as they are next written
+    pub fn set_compression(&self, compression: Compression) {
+        *self.compression.write().unwrap_or_else(|e| e.into_inner()) = compression;
+    }
+
+    pub fn compression_stats(&self) -> &CompressionStats {
+        &self.compression_stats
+    }
+
+    pub fn cache_policy(&self) -> CachePolicy {
+        *self.cache_policy.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Waits out every operation in flight and starts with an empty cache, so
+    // nothing cached under the old policy is served under the new one
+    pub fn set_cache_policy(&self, policy: CachePolicy) {
+        let _guard = self.exclusive();
+        *self.cache_policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+        self.cache.clear();
+    }
+
+    // Caches value unless a write landed after `epoch` was read, in which
+    // case the value may already be stale
+    fn cache_fill(&self, key: &K, value: Arc<V>, epoch: u64, ttl: Option<Duration>) {
+        self.cache.put(key.clone(), value, ttl);
+        if self.cache_epoch.load(Ordering::SeqCst) != epoch {
+            self.cache.remove(key);
+        }
+    }
+
+    // Installs the hook once, later calls are ignored
+    pub fn set_write_hook(&self, hook: WriteHook<K>) {
+        let _ = self.write_hook.set(hook);
+    }
+
+    // Installs the hook once, later calls are ignored
+    pub fn set_expire_hook(&self, hook: ExpireHook<K>) {
+        let _ = self.expire_hook.set(hook);
+    }
+
+    // Serves reads from `views` per-worker views, kept eventually consistent
+    // through the change stream. Set once, before serving.
+    pub fn set_read_views(&self, views: usize) {
+        let _ = self.views.set(ReadViews::new(views));
+    }
+
+    pub fn read_view_stats(&self) -> CacheStats {
+        self.views
+            .get()
+            .map_or(CacheStats::default(), |views| views.stats())
+    }
+
+    // Called after every write to key, with the new value when it was written
+    // whole and the policy writes through
+    fn written(&self, key: &K, value: Option<Arc<V>>) {
+        let policy = self.cache_policy();
+        if policy != CachePolicy::None {
+            let epoch = self.cache_epoch.fetch_add(1, Ordering::SeqCst) + 1;
+            match value {
+                Some(value) if policy.writes_through() => self.cache_fill(key, value, epoch, None),
+                _ => {
+                    self.cache.remove(key);
+                }
+            }
+        }
+        if let Some(views) = self.views.get() {
+            views.changed(key);
+        }
+        // After the cache, so whoever the hook tells reads the new value
+        if let Some(hook) = self.write_hook.get() {
+            hook(key);
+        }
+    }
+
+    // Copy of a value about to be written, for write-through caching
+    fn cached_copy(&self, value: &V) -> Option<Arc<V>> {
+        self.cache_policy()
+            .writes_through()
+            .then(|| Arc::new(value.clone()))
+    }
+
+    // Counts an access to key for hot key tracking and eviction
+    fn touch(&self, key: &K) {
+        let now = self.clock.now_ms();
+        self.hotkeys.record(key, now);
+        if self.eviction_policy().is_lfu() {
+            let now = now / 60_000;
+            let mut access = self
+                .access
+                .entry(key.clone())
+                .or_insert(Access::Lfu(LfuCounter::new(now)));
+            match access.value_mut() {
+                Access::Lfu(counter) => counter.touch(now),
+                other => *other = Access::Lfu(LfuCounter::new(now)),
+            }
+        } else if let Some(mut access) = self.access.get_mut(key) {
+            // Only an existing entry, so reads don't clone the key
+            *access = Access::Lru(lru_clock(now));
+        } else {
+            self.access.insert(key.clone(), Access::Lru(lru_clock(now)));
+        }
+    }
+
+    // A new value was stored at key: it starts at LFU_INIT_VAL under LFU,
+    // and counts as used under the others
+    fn stored(&self, key: &K) {
+        if self.eviction_policy().is_lfu() {
+            self.access.remove(key);
+        } else {
+            self.access
+                .insert(key.clone(), Access::Lru(lru_clock(self.clock.now_ms())));
+        }
+    }
+
+    // Called before anything about key changes: snapshots in progress keep
+    // what it holds now, when this is the first change since they were taken
+    fn preserve(&self, key: &K) -> Result<(), Error> {
+        let frozen = self.frozen.read().unwrap_or_else(|e| e.into_inner());
+        for frozen in frozen.iter() {
+            // The entry stays locked until the value is kept, so a second
+            // writer can't change it first
+            if let Entry::Vacant(entry) = frozen.kept.entry(key.clone()) {
+                let value = self.storage.get(key)?;
+                let when = self.expires.get(key).map(|when| *when);
+                entry.insert(value.map(|value| (value, when)));
+            }
+        }
+        Ok(())
+    }
+
+    // Sets the deadline of key and schedules it, read views let go of it after
+    fn set_deadline(&self, key: K, when: u64) -> Result<(), Error> {
+        self.storage.set_expiry(&key, Some(when))?;
+        self.ttl.push(key.clone(), when);
+        match self.views.get() {
+            Some(views) => {
+                self.expires.insert(key.clone(), when);
+                views.changed(&key);
+            }
+            None => {
+                self.expires.insert(key, when);
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the deadline of a key that stays. Returns whether it had one.
+    fn drop_deadline(&self, key: &K) -> Result<bool, Error> {
+        if self.expires.remove(key).is_none() {
+            return Ok(false);
+        }
+        self.storage.set_expiry(key, None)?;
+        Ok(true)
+    }
+
+    // Drops the per-key metadata of a key that was removed or replaced by a
+    // new value
+    fn forget(&self, key: &K) -> Result<(), Error> {
+        self.access.remove(key);
+        self.drop_deadline(key)?;
+        Ok(())
+    }
+
+    // Loads the deadlines the storage kept from an earlier run, called once
+    // by the server before it serves anything. Returns how many there were.
+    pub fn load_expiries(&self) -> Result<usize, Error> {
+        let expiries = self.storage.expiries()?;
+        let loaded = expiries.len();
+        for (key, when) in expiries {
+            self.ttl.push(key.clone(), when);
+            self.expires.insert(key, when);
+        }
+        Ok(loaded)
+    }
+
+    // Lazy expiration: drops the key if its deadline has passed.
+    // Returns true when the key was expired by this call.
+    //
+    // The value is deleted only if it is still the one the deadline belonged
+    // to: its version is taken before the deadline is, and a write landing
+    // in between changes it, so the write survives.
+    fn expire_if_needed(&self, key: &K) -> Result<bool, Error> {
+        if self
+            .expires
+            .get(key)
+            .is_none_or(|when| *when > self.clock.now_ms())
+        {
+            return Ok(false);
+        }
+        self.preserve(key)?;
+        let version = self.storage.version(key)?;
+        let expired = self
+            .expires
+            .remove_if(key, |_, when| *when <= self.clock.now_ms())
+            .is_some()
+            && self.storage.compare_and_delete(key, version)?;
+        if expired {
+            self.expire_stats.expired();
+            self.access.remove(key);
+            self.written(key, None);
+            if let Some(hook) = self.expire_hook.get() {
+                hook(key);
+            }
+        }
+        Ok(expired)
+    }
+
+    fn get_unlocked(&self, key: &K) -> Result<Option<Arc<V>>, Error> {
+        self.expire_if_needed(key)?;
+        let policy = self.cache_policy();
+        if policy == CachePolicy::None {
+            return self.storage.get(key).map_err(Error::from);
+        }
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(value));
+        }
+        let epoch = self.cache_epoch.load(Ordering::SeqCst);
+        let value = self.storage.get(key)?;
+        if let Some(value) = &value {
+            self.cache_fill(key, value.clone(), epoch, policy.ttl());
+        }
+        Ok(value)
+    }
+
     pub fn get(&self, key: &K) -> Result<Option<Arc<V>>, Error> {
-        self.storage.get(key).map_err(Error::from)
+        if let Some(views) = self.views.get() {
+            if let Some(mut view) = views.view() {
+                return self.get_viewed(views, &mut view, key);
+            }
+        }
+        let _guard = self.shared();
+        let value = self.get_unlocked(key)?;
+        if value.is_some() {
+            self.touch(key);
+        }
+        Ok(value)
     }
 
+    // get through the calling worker's read view. A hit takes no lock and
+    // touches no shared map; a miss reads as usual and keeps the value.
+    fn get_viewed(
+        &self,
+        views: &ReadViews<K, V>,
+        view: &mut View<K, V>,
+        key: &K,
+    ) -> Result<Option<Arc<V>>, Error> {
+        if let Some((value, touch)) = view.get(key, self.clock.now_ms()) {
+            if touch {
+                self.touch(key);
+            }
+            return Ok(Some(value));
+        }
+        let seq = view.seq();
+        let _guard = self.shared();
+        let value = self.get_unlocked(key)?;
+        if let Some(value) = &value {
+            self.touch(key);
+            let expires_at = self.expires.get(key).map(|when| *when);
+            views.fill(view, key, value.clone(), expires_at, seq);
+        }
+        Ok(value)
+    }
+
+    // get for commands of one data type: a value of another type fails with
+    // WrongTypeError
+    pub fn get_typed(&self, key: &K, expected: ValueType) -> Result<Option<Arc<V>>, Error>
+    where
+        V: Typed,
+    {
+        let value = self.get(key)?;
+        match value.as_deref() {
+            Some(value) if value.value_type() != expected => Err(WrongTypeError.into()),
+            _ => Ok(value),
+        }
+    }
+
+    pub fn exists(&self, key: &K) -> Result<bool, Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    // Type of the value at key, without counting as an access to it
+    pub fn type_of(&self, key: &K) -> Result<Option<ValueType>, Error>
+    where
+        V: Typed,
+    {
+        Ok(self.peek(key)?.map(|value| value.value_type()))
+    }
+
+    // The value at key without counting as an access to it, for looking at
+    // the data rather than serving it
+    pub fn peek(&self, key: &K) -> Result<Option<Arc<V>>, Error> {
+        let _guard = self.shared();
+        self.get_unlocked(key)
+    }
+
+    // Read-modify-write of one key under the storage entry lock. Expired keys
+    // are seen as missing; deleting the value also drops its TTL while
+    // in-place edits keep it.
+    pub fn update<F, R>(&self, key: K, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Option<&mut V>) -> (Update<V>, R),
+    {
+        let _guard = self.shared();
+        self.expire_if_needed(&key)?;
+        self.preserve(&key)?;
+        let mut stored = false;
+        let mut forgotten = Ok(());
+        let result = self.storage.update(key.clone(), |value| {
+            let existed = value.is_some();
+            let (update, result) = f(value);
+            if matches!(update, Update::Delete) || (!existed && matches!(update, Update::Set(_))) {
+                forgotten = self.forget(&key);
+            }
+            stored = match update {
+                Update::Keep => existed,
+                Update::Set(_) => true,
+                Update::Delete => false,
+            };
+            (update, result)
+        })?;
+        forgotten?;
+        self.written(&key, None);
+        if stored {
+            self.touch(&key);
+        }
+        Ok(result)
+    }
+
+    // update for commands of one data type. The closure only runs when the key
+    // is missing or holds the expected type, otherwise nothing changes and
+    // the call fails with WrongTypeError.
+    pub fn update_typed<F, R>(&self, key: K, expected: ValueType, f: F) -> Result<R, Error>
+    where
+        V: Typed,
+        F: FnOnce(Option<&mut V>) -> (Update<V>, R),
+    {
+        self.update(key, |value| match value {
+            Some(value) if value.value_type() != expected => (Update::Keep, Err(WrongTypeError)),
+            value => {
+                let (update, result) = f(value);
+                (update, std::result::Result::Ok(result))
+            }
+        })?
+        .map_err(Error::from)
+    }
+
+    // Read-modify-write of two distinct keys as one step: every other DB
+    // operation is held off until the closure's updates are applied.
+    pub fn update_pair<F, R>(&self, first: K, second: K, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Option<&mut V>, Option<&mut V>) -> (Update<V>, Update<V>, R),
+    {
+        let _guard = self.exclusive();
+        self.expire_if_needed(&first)?;
+        self.expire_if_needed(&second)?;
+        self.preserve(&first)?;
+        self.preserve(&second)?;
+
+        // Nobody else can look at the keys now, so work on owned values
+        let mut first_value = self.storage.delete(&first)?;
+        let mut second_value = self.storage.delete(&second)?;
+        let (first_update, second_update, result) = f(first_value.as_mut(), second_value.as_mut());
+        self.apply_update(first, first_value, first_update)?;
+        self.apply_update(second, second_value, second_update)?;
+        Ok(result)
+    }
+
+    // Consistent snapshot of several keys, no write lands in between
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<Arc<V>>>, Error> {
+        let _guard = self.exclusive();
+        keys.iter().map(|key| self.get_unlocked(key)).collect()
+    }
+
+    // Reads keys and replaces dst with what the closure computes from them
+    // as one step. A stored result starts without a TTL.
+    pub fn store_from<F, R>(&self, keys: &[K], dst: K, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(Vec<Option<Arc<V>>>) -> (Update<V>, R),
+    {
+        self.store_with(dst, |get| {
+            let values = keys.iter().map(get).collect::<Result<Vec<_>, Error>>()?;
+            Ok(f(values))
+        })
+    }
+
+    // Runs the closure with a lookup for any key, for reads whose keys are
+    // only known as they go. Nothing else runs on the db meanwhile.
+    pub fn read_with<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&dyn Fn(&K) -> Result<Option<Arc<V>>, Error>) -> Result<R, Error>,
+    {
+        let _guard = self.exclusive();
+        f(&|key| self.get_unlocked(key))
+    }
+
+    // Like read_with, then replaces dst with what the closure computed, all
+    // as one step. A stored result starts without a TTL.
+    pub fn store_with<F, R>(&self, dst: K, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&dyn Fn(&K) -> Result<Option<Arc<V>>, Error>) -> Result<(Update<V>, R), Error>,
+    {
+        let _guard = self.exclusive();
+        let (update, result) = f(&|key| self.get_unlocked(key))?;
+        if !matches!(update, Update::Keep) {
+            self.preserve(&dst)?;
+        }
+        match update {
+            Update::Keep => {}
+            Update::Set(value) => {
+                self.forget(&dst)?;
+                let cached = self.cached_copy(&value);
+                self.storage.set(dst.clone(), value)?;
+                self.stored(&dst);
+                self.written(&dst, cached);
+            }
+            Update::Delete => {
+                self.forget(&dst)?;
+                self.storage.delete(&dst)?;
+                self.written(&dst, None);
+            }
+        }
+        Ok(result)
+    }
+
+    fn apply_update(&self, key: K, value: Option<V>, update: Update<V>) -> Result<(), Error> {
+        match (update, value) {
+            (Update::Keep, Some(value)) => {
+                self.storage.set(key.clone(), value)?;
+            }
+            (Update::Keep, None) => {}
+            (Update::Set(new), old) => {
+                if old.is_none() {
+                    self.forget(&key)?;
+                }
+                self.storage.set(key.clone(), new)?;
+            }
+            (Update::Delete, _) => {
+                self.forget(&key)?;
+            }
+        }
+        self.written(&key, None);
+        Ok(())
+    }
+
+    // RESTORE: stores value with an optional deadline. Unless replace is set
+    // an existing key is left alone and false returned.
+    pub fn restore(
+        &self,
+        key: K,
+        value: V,
+        when: Option<u64>,
+        replace: bool,
+    ) -> Result<bool, Error> {
+        let _guard = self.shared();
+        self.expire_if_needed(&key)?;
+        self.preserve(&key)?;
+        let cached = self.cached_copy(&value);
+        let mut forgotten = Ok(());
+        let stored = self
+            .storage
+            .update(key.clone(), |existing| match existing {
+                Some(_) if !replace => (Update::Keep, false),
+                _ => {
+                    forgotten = self.forget(&key);
+                    (Update::Set(value), true)
+                }
+            })?;
+        forgotten?;
+        if stored {
+            self.stored(&key);
+            self.written(&key, cached);
+            if let Some(when) = when {
+                self.set_deadline(key, when)?;
+            }
+        }
+        Ok(stored)
+    }
+
+    // GETDEL: removes the key and hands back its value in one step. A value
+    // of another type is left in place.
+    pub fn get_del(&self, key: &K, expected: ValueType) -> Result<Option<V>, Error>
+    where
+        V: Typed,
+    {
+        self.update_typed(key.clone(), expected, |value| match value {
+            Some(value) => (Update::Delete, Some(value.clone())),
+            None => (Update::Keep, None),
+        })
+    }
+
+    // GETEX: reads the value and adjusts its TTL while holding the entry, so a
+    // concurrent SET cannot slip in between the read and the TTL change.
+    pub fn get_ex(&self, key: &K, expected: ValueType, ttl: TtlUpdate) -> Result<Option<V>, Error>
+    where
+        V: Typed,
+    {
+        let now = self.clock.now_ms();
+        let mut deadline = Ok(());
+        let value = self.update_typed(key.clone(), expected, |value| match value {
+            None => (Update::Keep, None),
+            Some(value) => {
+                let value = value.clone();
+                match ttl {
+                    TtlUpdate::Keep => (Update::Keep, Some(value)),
+                    TtlUpdate::Persist => {
+                        deadline = self.drop_deadline(key).map(|_| ());
+                        (Update::Keep, Some(value))
+                    }
+                    TtlUpdate::At(when) if when <= now => (Update::Delete, Some(value)),
+                    TtlUpdate::At(when) => {
+                        deadline = self.set_deadline(key.clone(), when);
+                        (Update::Keep, Some(value))
+                    }
+                }
+            }
+        })?;
+        deadline?;
+        Ok(value)
+    }
+
+    // Plain SET semantics: the new value starts without a TTL
     pub fn set(&self, key: K, value: V) -> Result<Option<V>, Error> {
-        self.storage.set(key, value).map_err(Error::from)
+        let _guard = self.shared();
+        self.preserve(&key)?;
+        self.forget(&key)?;
+        let cached = self.cached_copy(&value);
+        let old = self.storage.set(key.clone(), value)?;
+        self.stored(&key);
+        self.written(&key, cached);
+        Ok(old)
+    }
+
+    // SET with a TTL, used by SETEX/PSETEX
+    pub fn set_with_expiry(&self, key: K, value: V, when: u64) -> Result<Option<V>, Error> {
+        let _guard = self.shared();
+        self.preserve(&key)?;
+        let cached = self.cached_copy(&value);
+        let old = self.storage.set(key.clone(), value)?;
+        self.stored(&key);
+        self.written(&key, cached);
+        self.set_deadline(key, when)?;
+        Ok(old)
     }
 
-    pub fn delete(&self, keys: &Vec<K>) -> Result<(), Error> {
+    // Returns the number of keys that existed, an expired key doesn't count
+    pub fn delete(&self, keys: &[K]) -> Result<usize, Error> {
+        let _guard = self.shared();
+        let mut removed = 0;
         for k in keys.iter() {
-            match self.storage.delete(k) {
-                Err(e) => {
-                    return Err(Error::from(e));
+            if self.expire_if_needed(k)? {
+                continue;
+            }
+            self.preserve(k)?;
+            self.forget(k)?;
+            if self.storage.delete(k)?.is_some() {
+                removed += 1;
+            }
+            self.written(k, None);
+        }
+        Ok(removed)
+    }
+
+    // Removes the keys right away but leaves dropping big values to the
+    // lazyfree thread. Returns the number of keys that existed.
+    pub fn unlink(&self, keys: &[K]) -> Result<usize, Error>
+    where
+        V: FreeEffort,
+    {
+        let _guard = self.shared();
+        let mut removed = 0;
+        for k in keys.iter() {
+            if self.expire_if_needed(k)? {
+                continue;
+            }
+            self.preserve(k)?;
+            self.forget(k)?;
+            let value = self.storage.delete(k)?;
+            self.written(k, None);
+            if let Some(value) = value {
+                self.lazyfree.free(value);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Copies the value at src into dst_db under dst, TTL included. Without
+    // replace the copy only happens when dst is vacant, decided atomically by
+    // the storage.
+    pub fn copy_to(&self, src: &K, dst_db: &Self, dst: K, replace: bool) -> Result<bool, Error> {
+        let value = match self.get(src)? {
+            Some(value) => value.as_ref().clone(),
+            None => return Ok(false),
+        };
+        let when = self.expires.get(src).map(|e| *e);
+
+        let _guard = dst_db.shared();
+        dst_db.expire_if_needed(&dst)?;
+        dst_db.preserve(&dst)?;
+        let cached = dst_db.cached_copy(&value);
+        if replace {
+            dst_db.forget(&dst)?;
+            dst_db.storage.set(dst.clone(), value)?;
+        } else if !dst_db.storage.insert_if_absent(dst.clone(), value)? {
+            return Ok(false);
+        }
+        dst_db.stored(&dst);
+        dst_db.written(&dst, cached);
+
+        if let Some(when) = when {
+            dst_db.set_deadline(dst, when)?;
+        }
+        Ok(true)
+    }
+
+    // Moves key into dst_db if it exists here and is absent there. Both
+    // databases are held exclusively for the whole move, so no other
+    // operation sees the key in both or in neither. They are locked in
+    // address order, so moves in opposite directions can't deadlock.
+    pub fn move_to(&self, key: &K, dst_db: &Self) -> Result<bool, Error> {
+        if std::ptr::eq(self, dst_db) {
+            return Ok(false);
+        }
+        let (first, second) = if (self as *const Self) < (dst_db as *const Self) {
+            (self, dst_db)
+        } else {
+            (dst_db, self)
+        };
+        let _first = first.exclusive();
+        let _second = second.exclusive();
+
+        self.expire_if_needed(key)?;
+        dst_db.expire_if_needed(key)?;
+        if dst_db.storage.get(key)?.is_some() {
+            return Ok(false);
+        }
+        let value = match self.storage.get(key)? {
+            Some(value) => V::clone(&value),
+            None => return Ok(false),
+        };
+
+        // Into the destination first, so a failed write leaves the key
+        // where it was
+        dst_db.preserve(key)?;
+        dst_db.storage.set(key.clone(), value)?;
+        self.preserve(key)?;
+        self.storage.delete(key)?;
+        self.written(key, None);
+        self.access.remove(key);
+        let when = self.expires.remove(key).map(|(_, when)| when);
+
+        dst_db.stored(key);
+        dst_db.written(key, None);
+        if let Some(when) = when {
+            dst_db.set_deadline(key.clone(), when)?;
+        }
+        Ok(true)
+    }
+
+    // Sets an absolute deadline in unix ms. A deadline in the past deletes
+    // the key right away. Returns false if the key is missing or the
+    // condition rejected the update.
+    pub fn expire_at(&self, key: &K, when: u64, condition: ExpireCondition) -> Result<bool, Error> {
+        let _guard = self.shared();
+        if self.get_unlocked(key)?.is_none() {
+            return Ok(false);
+        }
+
+        let current = self.expires.get(key).map(|e| *e);
+        let allowed = match condition {
+            ExpireCondition::Always => true,
+            ExpireCondition::IfNoExpiry => current.is_none(),
+            ExpireCondition::IfHasExpiry => current.is_some(),
+            // A key without TTL counts as expiring at infinity
+            ExpireCondition::IfGreater => current.is_some_and(|c| when > c),
+            ExpireCondition::IfLess => current.is_none_or(|c| when < c),
+        };
+        if !allowed {
+            return Ok(false);
+        }
+
+        self.preserve(key)?;
+        if when <= self.clock.now_ms() {
+            self.forget(key)?;
+            self.storage.delete(key)?;
+            self.written(key, None);
+        } else {
+            self.set_deadline(key.clone(), when)?;
+        }
+        Ok(true)
+    }
+
+    // Drops the TTL of an existing key. Returns true if there was one.
+    pub fn persist(&self, key: &K) -> Result<bool, Error> {
+        let _guard = self.shared();
+        if self.get_unlocked(key)?.is_none() {
+            return Ok(false);
+        }
+        self.preserve(key)?;
+        self.drop_deadline(key)
+    }
+
+    pub fn expiry(&self, key: &K) -> Result<Expiry, Error> {
+        let _guard = self.shared();
+        if self.get_unlocked(key)?.is_none() {
+            return Ok(Expiry::NoKey);
+        }
+        Ok(match self.expires.get(key) {
+            Some(when) => Expiry::At(*when),
+            None => Expiry::Persistent,
+        })
+    }
+
+    // OBJECT FREQ: the decayed LFU counter of key, without counting this
+    // lookup as an access. None when the key does not exist.
+    pub fn frequency(&self, key: &K) -> Result<Option<u8>, Error> {
+        let _guard = self.shared();
+        if self.get_unlocked(key)?.is_none() {
+            return Ok(None);
+        }
+        let now = self.clock.now_ms() / 60_000;
+        Ok(Some(match self.access.get(key).as_deref() {
+            Some(Access::Lfu(counter)) => counter.value(now),
+            _ => LFU_INIT_VAL,
+        }))
+    }
+
+    // OBJECT IDLETIME: seconds since key was last used, without counting this
+    // lookup. None when the key does not exist.
+    pub fn idle_time(&self, key: &K) -> Result<Option<u64>, Error> {
+        let _guard = self.shared();
+        if self.get_unlocked(key)?.is_none() {
+            return Ok(None);
+        }
+        let now = lru_clock(self.clock.now_ms());
+        Ok(Some(match self.access.get(key).as_deref() {
+            Some(Access::Lru(last)) => now.saturating_sub(*last) as u64,
+            _ => 0,
+        }))
+    }
+
+    // TOUCH: counts an access to each existing key, and how many existed
+    pub fn touch_keys(&self, keys: &[K]) -> Result<usize, Error> {
+        let _guard = self.shared();
+        let mut found = 0;
+        for key in keys {
+            if self.get_unlocked(key)?.is_some() {
+                self.touch(key);
+                found += 1;
+            }
+        }
+        Ok(found)
+    }
+
+    // Wakes tasks blocked in a blocking list pop so they retry
+    pub fn signal_ready(&self) {
+        self.ready.notify_waiters();
+    }
+
+    // Future that completes on the next signal_ready. Enable it before
+    // checking the list to avoid missing a push in between.
+    pub fn ready(&self) -> Notified<'_> {
+        self.ready.notified()
+    }
+
+    // HOTKEYS: the most accessed keys lately, busiest first
+    pub fn hot_keys(&self, count: usize) -> Vec<(K, u32)> {
+        self.hotkeys.hottest(count)
+    }
+
+    pub fn lazyfree_pending(&self) -> usize {
+        self.lazyfree.pending()
+    }
+
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    // One SCAN step: about count keys from cursor on, and the cursor to go
+    // on from, 0 once done. Keys are visited in the order of a fixed hash of
+    // the key rather than of where the storage keeps them, so a key present
+    // for the whole iteration is returned exactly once however the table is
+    // resized meanwhile; keys added or removed during it may or may not be.
+    // Keys sharing a hash come in one step, which can exceed count. Every
+    // step goes over all keys, as storages only hand them out whole.
+    pub fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<K>), Error> {
+        self.scan_checked(cursor, count, |_| Ok(()))
+    }
+
+    // scan calling check at every key it goes over, with the number of
+    // keys so far, and giving up with its error
+    pub fn scan_checked(
+        &self,
+        cursor: u64,
+        count: usize,
+        check: impl Fn(usize) -> Result<(), Error>,
+    ) -> Result<(u64, Vec<K>), Error> {
+        let _guard = self.shared();
+        let now = self.clock.now_ms();
+        let mut batch: Vec<(u64, K)> = Vec::new();
+        for (step, key) in self.storage.keys()?.into_iter().enumerate() {
+            check(step)?;
+            let hash = scan_hash(&key);
+            if hash >= cursor && self.expires.get(&key).is_none_or(|when| *when > now) {
+                batch.push((hash, key));
+            }
+        }
+        let count = count.max(1);
+        let next = if batch.len() > count {
+            batch.select_nth_unstable_by_key(count - 1, |(hash, _)| *hash);
+            let last = batch[count - 1].0;
+            batch.retain(|(hash, _)| *hash <= last);
+            // Wraps to 0 past the last possible hash, which also ends it
+            last.wrapping_add(1)
+        } else {
+            0
+        };
+        batch.sort_unstable_by_key(|(hash, _)| *hash);
+        Ok((next, batch.into_iter().map(|(_, key)| key).collect()))
+    }
+
+    // Bytes the live entries take by size's measure, in one pass over the
+    // storage
+    pub fn memory_usage<F>(&self, size: F) -> Result<usize, Error>
+    where
+        F: Fn(&K, &V) -> usize,
+    {
+        let _guard = self.shared();
+        let now = self.clock.now_ms();
+        let mut total = 0;
+        for key in self.storage.keys()? {
+            if self.expires.get(&key).is_some_and(|when| *when <= now) {
+                continue;
+            }
+            if let Some(value) = self.storage.get(&key)? {
+                total += size(&key, &value);
+            }
+        }
+        Ok(total)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty()
+    }
+
+    pub fn expires_len(&self) -> usize {
+        self.expires.len()
+    }
+
+    // One active expiry cycle, run by the server whenever the next deadline
+    // comes. Drops the keys whose timers are due, skipping tombstones, until
+    // none are left or the deadline passes, and returns true in the latter
+    // case. Each key is dropped holding what lock returns for it; a key it
+    // returns None for is in use and tried again next cycle.
+    pub fn active_expire<G>(
+        &self,
+        deadline: Instant,
+        lock: impl Fn(&K) -> Option<G>,
+    ) -> Result<bool, Error> {
+        if !self.active_expire.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        let started = Instant::now();
+        let volatile = self.expires.len();
+        let mut expired = 0;
+        let mut due = std::mem::take(&mut *self.busy.lock().unwrap_or_else(|e| e.into_inner()));
+        let mut busy = Vec::new();
+        let timed_out = loop {
+            due.extend(self.ttl.pop_due(self.clock.now_ms(), EXPIRE_BATCH));
+            if due.is_empty() {
+                break false;
+            }
+            let _guard = self.shared();
+            for (key, when) in due.drain(..) {
+                // A key given another deadline since, or deleted, has a
+                // timer elsewhere or none
+                if self.expires.get(&key).is_none_or(|at| *at != when) {
+                    continue;
+                }
+                match lock(&key) {
+                    Some(_held) => {
+                        if self.expire_if_needed(&key)? {
+                            expired += 1;
+                        }
+                    }
+                    None => busy.push((key, when)),
                 }
-                _ => (),
+            }
+            if Instant::now() >= deadline {
+                break true;
+            }
+        };
+        *self.busy.lock().unwrap_or_else(|e| e.into_inner()) = busy;
+        // Tombstones are dropped once they outnumber the live timers
+        if self.ttl.len() > expire::TOMBSTONES_MIN.max(2 * self.expires.len()) {
+            let _guard = self.shared();
+            self.ttl
+                .retain(|key, when| self.expires.get(key).is_some_and(|at| *at == when));
+        }
+        self.expire_stats
+            .cycle(volatile, expired, timed_out, started.elapsed());
+        Ok(timed_out)
+    }
+
+    // Deadline of the earliest timer, for the server to sleep until
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.ttl.next()
+    }
+
+    // Resolves when a key gets a deadline sooner than any before
+    pub fn expiry_sooner(&self) -> Notified<'_> {
+        self.ttl.sooner()
+    }
+
+    pub fn expire_info(&self) -> ExpireInfo {
+        self.expire_stats.info()
+    }
+
+    // DEBUG RELOAD: takes every live key out and stores what f makes of its
+    // value in its place, deadline kept, with all other access held off.
+    // Nothing changes when f fails for any key. Returns the keys reloaded.
+    pub fn reload<F>(&self, f: F) -> Result<usize, Error>
+    where
+        F: Fn(&V) -> Result<V, Error>,
+    {
+        let _guard = self.exclusive();
+        let now = self.clock.now_ms();
+        let keys = self.storage.keys()?;
+        let mut entries = Vec::new();
+        for key in &keys {
+            let when = self.expires.get(key).map(|when| *when);
+            if when.is_some_and(|when| when <= now) {
+                continue;
+            }
+            if let Some(value) = self.storage.get(key)? {
+                entries.push((key.clone(), f(&value)?, when));
+            }
+        }
+
+        for key in &keys {
+            self.preserve(key)?;
+        }
+        self.storage.clear()?;
+        self.expires.clear();
+        self.ttl.clear();
+        self.access.clear();
+        self.cache.clear();
+        self.cache_epoch.fetch_add(1, Ordering::SeqCst);
+        let reloaded = entries.len();
+        for (key, value, when) in entries {
+            self.storage.set(key.clone(), value)?;
+            if let Some(when) = when {
+                self.storage.set_expiry(&key, Some(when))?;
+                self.expires.insert(key.clone(), when);
+                self.ttl.push(key, when);
+            }
+        }
+        if let Some(views) = self.views.get() {
+            views.changed_all();
+        }
+        Ok(reloaded)
+    }
+
+    // Drops expired cache entries, run periodically by the server
+    pub fn purge_cache(&self) -> usize {
+        self.cache.purge()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn storage_info(&self) -> Vec<(&'static str, u64)> {
+        self.storage.info()
+    }
+
+    // The storage itself, for what only its own type offers
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+}
+
+// The data of a db as it was when the snapshot was taken, while writes to
+// it go on: the first change to each key after that keeps what it replaces
+// for the snapshot. Keeping stops once the snapshot is dropped.
+pub struct Snapshot<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Hash + Eq + Send + Sync + Clone + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    db: Arc<DB<S, K, V>>,
+    frozen: Arc<Frozen<K, V>>,
+}
+
+// Snapshots of the dbs all at the same point in time. Each db is held off
+// only while its snapshot is set up, nothing is copied.
+pub fn snapshot<S, K, V>(dbs: &[Arc<DB<S, K, V>>]) -> Vec<Snapshot<S, K, V>>
+where
+    S: Storage<K, V>,
+    K: Hash + Eq + Send + Sync + Clone + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    let _guards: Vec<_> = dbs.iter().map(|db| db.exclusive()).collect();
+    dbs.iter()
+        .map(|db| {
+            let frozen = Arc::new(Frozen {
+                at: db.clock.now_ms(),
+                kept: DashMap::new(),
+            });
+            db.frozen
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(frozen.clone());
+            Snapshot {
+                db: db.clone(),
+                frozen,
+            }
+        })
+        .collect()
+}
+
+impl<S, K, V> Snapshot<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Hash + Eq + Send + Sync + Clone + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    // Every key live when the snapshot was taken, with its value and
+    // deadline then
+    pub fn for_each<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&K, &V, Option<u64>) -> Result<(), Error>,
+    {
+        let live = |when: Option<u64>| when.is_none_or(|when| when > self.frozen.at);
+        let mut seen = HashSet::new();
+        for key in self.db.storage.keys()? {
+            let value = self.db.storage.get(&key)?;
+            let when = self.db.expires.get(&key).map(|when| *when);
+            // Checked after the read: a change in between kept the value
+            // first, and the kept one is what counts
+            if self.frozen.kept.contains_key(&key) {
+                continue;
+            }
+            if let Some(value) = value {
+                if live(when) {
+                    f(&key, &value, when)?;
+                }
+                seen.insert(key);
+            }
+        }
+        // Keys changed since, collected first so writers aren't held up
+        let kept: Vec<_> = self
+            .frozen
+            .kept
+            .iter()
+            .filter(|entry| !seen.contains(entry.key()))
+            .filter_map(|entry| {
+                let (value, when) = entry.value().as_ref()?;
+                Some((entry.key().clone(), value.clone(), *when))
+            })
+            .collect();
+        for (key, value, when) in kept {
+            if live(when) {
+                f(&key, &value, when)?;
             }
         }
         Ok(())
     }
 }
-//EOF
+
+impl<S, K, V> Drop for Snapshot<S, K, V>
+where
+    S: Storage<K, V>,
+    K: Hash + Eq + Send + Sync + Clone + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.db
+            .frozen
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|frozen| !Arc::ptr_eq(frozen, &self.frozen));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::{self, DashMapStorage};
+    use std::borrow::Borrow;
+
+    fn new_db() -> DB<DashMapStorage<String, String>, String, String> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    #[test]
+    fn test_expire_at_and_persist() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::Persistent);
+
+        let when = now_ms() + 60_000;
+        assert!(db.expire_at(&key, when, ExpireCondition::Always).unwrap());
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::At(when));
+
+        assert!(db.persist(&key).unwrap());
+        assert!(!db.persist(&key).unwrap());
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::Persistent);
+
+        let missing = "missing".to_string();
+        assert!(!db
+            .expire_at(&missing, when, ExpireCondition::Always)
+            .unwrap());
+        assert_eq!(db.expiry(&missing).unwrap(), Expiry::NoKey);
+    }
+
+    #[test]
+    fn test_past_deadline_deletes_key() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+
+        assert!(db.expire_at(&key, 1, ExpireCondition::Always).unwrap());
+        assert!(db.get(&key).unwrap().is_none());
+        assert_eq!(db.expires_len(), 0);
+    }
+
+    #[test]
+    fn test_delete_counts_existing_keys() {
+        let db = new_db();
+        db.set("a".to_string(), "1".to_string()).unwrap();
+        db.set("b".to_string(), "2".to_string()).unwrap();
+
+        let keys = ["a".to_string(), "missing".to_string(), "b".to_string()];
+        assert_eq!(db.delete(&keys).unwrap(), 2);
+        assert_eq!(db.delete(&keys).unwrap(), 0);
+        assert!(db.get(&"a".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_move_to() {
+        let (src, dst) = (new_db(), new_db());
+        let key = "key".to_string();
+        src.set(key.clone(), "value".to_string()).unwrap();
+        let when = now_ms() + 60_000;
+        src.expire_at(&key, when, ExpireCondition::Always).unwrap();
+
+        assert!(src.move_to(&key, &dst).unwrap());
+        assert!(src.get(&key).unwrap().is_none());
+        assert_eq!(
+            dst.get(&key).unwrap().as_deref(),
+            Some(&"value".to_string())
+        );
+        assert_eq!(dst.expiry(&key).unwrap(), Expiry::At(when));
+        assert!(!src.move_to(&key, &dst).unwrap());
+
+        // Taken at the destination: both stay
+        src.set(key.clone(), "other".to_string()).unwrap();
+        assert!(!src.move_to(&key, &dst).unwrap());
+        assert_eq!(
+            src.get(&key).unwrap().as_deref(),
+            Some(&"other".to_string())
+        );
+        assert_eq!(
+            dst.get(&key).unwrap().as_deref(),
+            Some(&"value".to_string())
+        );
+        assert!(!dst.move_to(&key, &dst).unwrap());
+    }
+
+    #[test]
+    fn test_move_to_both_ways() {
+        let (a, b) = (new_db(), new_db());
+        let keys: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            a.set(key.clone(), key.to_uppercase()).unwrap();
+        }
+        // Moves in opposite directions neither deadlock nor drop a key
+        std::thread::scope(|scope| {
+            for (from, to) in [(&a, &b), (&b, &a)] {
+                let keys = &keys;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        for key in keys {
+                            from.move_to(key, to).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        for key in &keys {
+            let found = [a.get(key).unwrap(), b.get(key).unwrap()];
+            let found: Vec<_> = found.into_iter().flatten().collect();
+            assert_eq!(found.len(), 1);
+            assert_eq!(*found[0], key.to_uppercase());
+        }
+    }
+
+    #[test]
+    fn test_get_ex_and_get_del() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+
+        let when = now_ms() + 60_000;
+        assert_eq!(
+            db.get_ex(&key, ValueType::String, TtlUpdate::At(when))
+                .unwrap(),
+            Some("value".to_string())
+        );
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::At(when));
+
+        db.get_ex(&key, ValueType::String, TtlUpdate::Persist)
+            .unwrap();
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::Persistent);
+
+        // A deadline in the past still returns the value but deletes the key
+        assert_eq!(
+            db.get_ex(&key, ValueType::String, TtlUpdate::At(1))
+                .unwrap(),
+            Some("value".to_string())
+        );
+        assert!(!db.exists(&key).unwrap());
+
+        db.set(key.clone(), "value".to_string()).unwrap();
+        assert_eq!(
+            db.get_del(&key, ValueType::String).unwrap(),
+            Some("value".to_string())
+        );
+        assert_eq!(db.get_del(&key, ValueType::String).unwrap(), None);
+    }
+
+    #[test]
+    fn test_update_pair() {
+        let db = new_db();
+        let (a, b) = ("a".to_string(), "b".to_string());
+        db.set(a.clone(), "1".to_string()).unwrap();
+        db.expire_at(&a, now_ms() + 60_000, ExpireCondition::Always)
+            .unwrap();
+
+        let moved = db
+            .update_pair(a.clone(), b.clone(), |first, second| {
+                assert!(second.is_none());
+                let value = first.unwrap().clone();
+                (Update::Delete, Update::Set(value), true)
+            })
+            .unwrap();
+        assert!(moved);
+        assert!(!db.exists(&a).unwrap());
+        assert_eq!(db.expiry(&a).unwrap(), Expiry::NoKey);
+        assert_eq!(*db.get(&b).unwrap().unwrap(), "1");
+        assert_eq!(db.expiry(&b).unwrap(), Expiry::Persistent);
+    }
+
+    #[test]
+    fn test_lfu_tracking() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        db.get(&key).unwrap();
+        assert!(matches!(
+            db.access.get(&key).as_deref(),
+            Some(Access::Lru(_))
+        ));
+
+        db.set_eviction_policy(EvictionPolicy::AllKeysLfu);
+        assert_eq!(db.frequency(&key).unwrap(), Some(LFU_INIT_VAL));
+        db.get(&key).unwrap();
+        assert_eq!(db.frequency(&key).unwrap(), Some(LFU_INIT_VAL + 1));
+
+        // A new value starts over, a removed key has no frequency
+        db.set(key.clone(), "other".to_string()).unwrap();
+        assert_eq!(db.frequency(&key).unwrap(), Some(LFU_INIT_VAL));
+        db.delete(std::slice::from_ref(&key)).unwrap();
+        assert_eq!(db.frequency(&key).unwrap(), None);
+        assert!(db.access.is_empty());
+    }
+
+    #[test]
+    fn test_idle_time() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        assert_eq!(db.idle_time(&key).unwrap(), Some(0));
+
+        // Last used 100 seconds ago, looking doesn't count as a use
+        let then = lru_clock(now_ms()) - 100;
+        db.access.insert(key.clone(), Access::Lru(then));
+        assert!(db.idle_time(&key).unwrap().unwrap() >= 100);
+        assert!(db.idle_time(&key).unwrap().unwrap() >= 100);
+        db.get(&key).unwrap();
+        assert!(db.idle_time(&key).unwrap().unwrap() < 100);
+
+        db.access.insert(key.clone(), Access::Lru(then));
+        let keys = [key.clone(), "missing".to_string()];
+        assert_eq!(db.touch_keys(&keys).unwrap(), 1);
+        assert!(db.idle_time(&key).unwrap().unwrap() < 100);
+        assert_eq!(db.idle_time(&"missing".to_string()).unwrap(), None);
+
+        // Rewritten keys count as used
+        db.access.insert(key.clone(), Access::Lru(then));
+        db.set(key.clone(), "other".to_string()).unwrap();
+        assert!(db.idle_time(&key).unwrap().unwrap() < 100);
+    }
+
+    #[test]
+    fn test_expire_conditions() {
+        let db = new_db();
+        let key = "key".to_string();
+        db.set(key.clone(), "value".to_string()).unwrap();
+        let when = now_ms() + 60_000;
+
+        assert!(!db
+            .expire_at(&key, when, ExpireCondition::IfHasExpiry)
+            .unwrap());
+        assert!(!db
+            .expire_at(&key, when, ExpireCondition::IfGreater)
+            .unwrap());
+        assert!(db
+            .expire_at(&key, when, ExpireCondition::IfNoExpiry)
+            .unwrap());
+        assert!(!db
+            .expire_at(&key, when, ExpireCondition::IfNoExpiry)
+            .unwrap());
+        assert!(!db
+            .expire_at(&key, when - 1, ExpireCondition::IfGreater)
+            .unwrap());
+        assert!(db
+            .expire_at(&key, when - 1, ExpireCondition::IfLess)
+            .unwrap());
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::At(when - 1));
+
+        // SET drops the TTL
+        db.set(key.clone(), "value".to_string()).unwrap();
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::Persistent);
+    }
+    #[test]
+    fn test_active_expire() {
+        let db = new_db();
+        for i in 0..100 {
+            db.set_with_expiry(format!("gone{}", i), "v".to_string(), 1)
+                .unwrap();
+        }
+        for i in 0..5 {
+            db.set_with_expiry(format!("live{}", i), "v".to_string(), now_ms() + 60_000)
+                .unwrap();
+        }
+        db.set("plain".to_string(), "v".to_string()).unwrap();
+
+        // One cycle takes every due key and leaves the rest
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert!(!db.active_expire(deadline, |_| Some(())).unwrap());
+        assert_eq!(db.expire_info().expired_keys, 100);
+        assert_eq!(db.len(), 6);
+        assert_eq!(db.expires_len(), 5);
+        assert!(db.next_expiry().unwrap() > now_ms());
+    }
+
+    #[test]
+    fn test_expire_hook() {
+        let db = new_db();
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let heard = expired.clone();
+        db.set_expire_hook(Box::new(move |key: &String| {
+            heard.lock().unwrap().push(key.clone())
+        }));
+        db.set_with_expiry("held".to_string(), "v".to_string(), 1)
+            .unwrap();
+        db.set_with_expiry("free".to_string(), "v".to_string(), 1)
+            .unwrap();
+
+        // A key in use is left to the next cycle
+        let deadline = Instant::now() + Duration::from_secs(10);
+        db.active_expire(deadline, |key: &String| (key != "held").then_some(()))
+            .unwrap();
+        assert_eq!(*expired.lock().unwrap(), vec!["free".to_string()]);
+        assert_eq!(db.next_expiry(), None);
+        db.active_expire(deadline, |_| Some(())).unwrap();
+        assert_eq!(expired.lock().unwrap().len(), 2);
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn test_expire_tombstones() {
+        let db = new_db();
+        let clock = db.clock().clone();
+        clock.freeze();
+        let now = clock.now_ms();
+        let extended = "extended".to_string();
+        let reset = "reset".to_string();
+        db.set_with_expiry(extended.clone(), "v".to_string(), now + 100)
+            .unwrap();
+        db.set_with_expiry(reset.clone(), "v".to_string(), now + 100)
+            .unwrap();
+        // The old timers stay behind as tombstones
+        assert!(db
+            .expire_at(&extended, now + 10_000, ExpireCondition::Always)
+            .unwrap());
+        db.delete(std::slice::from_ref(&reset)).unwrap();
+        db.set(reset.clone(), "v".to_string()).unwrap();
+        assert_eq!(db.next_expiry(), Some(now + 100));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        clock.advance(100);
+        db.active_expire(deadline, |_| Some(())).unwrap();
+        assert_eq!(db.expire_info().expired_keys, 0);
+        assert!(db.get(&extended).unwrap().is_some());
+        assert!(db.get(&reset).unwrap().is_some());
+        assert_eq!(db.next_expiry(), Some(now + 10_000));
+
+        clock.advance(9_900);
+        db.active_expire(deadline, |_| Some(())).unwrap();
+        assert_eq!(db.expire_info().expired_keys, 1);
+        assert!(db.get(&extended).unwrap().is_none());
+        assert_eq!(db.next_expiry(), None);
+    }
+
+    #[test]
+    fn test_clock() {
+        let db = new_db();
+        let clock = db.clock().clone();
+        clock.freeze();
+        let key = "key".to_string();
+        db.set_with_expiry(key.clone(), "v".to_string(), clock.now_ms() + 10_000)
+            .unwrap();
+        db.set("plain".to_string(), "v".to_string()).unwrap();
+
+        clock.advance(9_999);
+        assert!(db.get(&key).unwrap().is_some());
+        // Idle times count whole seconds of the clock
+        let idle = db.idle_time(&"plain".to_string()).unwrap();
+        assert!(matches!(idle, Some(9 | 10)));
+
+        // Turned off, the cycle leaves the key to be found on lookup
+        clock.advance(1);
+        db.set_active_expire(false);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        db.active_expire(deadline, |_| Some(())).unwrap();
+        assert_eq!(db.expires_len(), 1);
+        db.set_active_expire(true);
+        db.active_expire(deadline, |_| Some(())).unwrap();
+        assert_eq!(db.expires_len(), 0);
+        assert!(db.get(&key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_policies() {
+        let key = "key".to_string();
+        let db = new_db();
+        db.set(key.clone(), "1".to_string()).unwrap();
+        db.get(&key).unwrap();
+        assert_eq!(db.cache_stats(), CacheStats::default());
+
+        for policy in [
+            CachePolicy::WriteThrough,
+            CachePolicy::WriteAround,
+            CachePolicy::ReadThrough {
+                ttl: Duration::from_secs(60),
+            },
+        ] {
+            let db = new_db();
+            db.set_cache_policy(policy);
+            db.set(key.clone(), "1".to_string()).unwrap();
+            assert_eq!(*db.get(&key).unwrap().unwrap(), "1");
+            assert_eq!(*db.get(&key).unwrap().unwrap(), "1");
+            // Only write-through has the value cached before the first read
+            let hits = if policy.writes_through() { 2 } else { 1 };
+            assert_eq!(db.cache_stats().hits, hits);
+
+            // No write leaves a stale copy behind
+            db.update(key.clone(), |value| {
+                *value.unwrap() = "2".to_string();
+                (Update::Keep, ())
+            })
+            .unwrap();
+            assert_eq!(*db.get(&key).unwrap().unwrap(), "2");
+            db.set(key.clone(), "3".to_string()).unwrap();
+            assert_eq!(*db.get(&key).unwrap().unwrap(), "3");
+            assert!(db.expire_at(&key, 1, ExpireCondition::Always).unwrap());
+            assert!(db.get(&key).unwrap().is_none());
+        }
+    }
+
+    // Storage that stores a pending write just before it deletes, the way
+    // a SET landing between the deadline check and the delete would
+    #[derive(Debug, Default)]
+    struct Racing {
+        inner: DashMapStorage<String, String>,
+        pending: Mutex<Option<(String, String)>>,
+    }
+
+    impl Racing {
+        fn land(&self) {
+            if let Some((key, value)) = self.pending.lock().unwrap().take() {
+                self.inner.set(key, value).unwrap();
+            }
+        }
+    }
+
+    impl Storage<String, String> for Racing {
+        fn get<Q>(&self, key: &Q) -> storage::Result<Option<Arc<String>>>
+        where
+            String: Borrow<Q>,
+            Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+        {
+            self.inner.get(key)
+        }
+
+        fn set(&self, key: String, value: String) -> storage::Result<Option<String>> {
+            self.inner.set(key, value)
+        }
+
+        fn insert_if_absent(&self, key: String, value: String) -> storage::Result<bool> {
+            self.inner.insert_if_absent(key, value)
+        }
+
+        fn update<F, R>(&self, key: String, f: F) -> storage::Result<R>
+        where
+            F: FnOnce(Option<&mut String>) -> (Update<String>, R),
+        {
+            self.inner.update(key, f)
+        }
+
+        fn delete<Q>(&self, key: &Q) -> storage::Result<Option<String>>
+        where
+            String: Borrow<Q>,
+            Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+        {
+            self.land();
+            self.inner.delete(key)
+        }
+
+        fn version<Q>(&self, key: &Q) -> storage::Result<u64>
+        where
+            String: Borrow<Q>,
+            Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+        {
+            self.inner.version(key)
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: String,
+            expected_version: u64,
+            value: String,
+        ) -> storage::Result<Option<u64>> {
+            self.inner.compare_and_swap(key, expected_version, value)
+        }
+
+        fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> storage::Result<bool>
+        where
+            String: Borrow<Q>,
+            Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+        {
+            self.land();
+            self.inner.compare_and_delete(key, expected_version)
+        }
+
+        fn clear(&self) -> storage::Result<()> {
+            self.inner.clear()
+        }
+
+        fn keys(&self) -> storage::Result<Vec<String>> {
+            self.inner.keys()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+    }
+
+    #[test]
+    fn test_expire_races_set() {
+        let key = "key".to_string();
+        let db: DB<Racing, String, String> = DB::new(Racing::default(), 16);
+        db.clock().freeze();
+        let now = db.clock().now_ms();
+        db.set_with_expiry(key.clone(), "old".to_string(), now)
+            .unwrap();
+
+        // A SET landing while the read expires the old value survives it
+        *db.storage.pending.lock().unwrap() = Some((key.clone(), "new".to_string()));
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "new");
+        assert_eq!(db.expire_info().expired_keys, 0);
+        assert_eq!(db.expiry(&key).unwrap(), Expiry::Persistent);
+    }
+
+    #[test]
+    fn test_read_views() {
+        let key = "key".to_string();
+        let db = new_db();
+        db.set_read_views(4);
+        db.clock().freeze();
+        db.set(key.clone(), "1".to_string()).unwrap();
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "1");
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "1");
+        assert_eq!(db.read_view_stats().hits, 1);
+
+        // Every kind of write reaches the views before the next read
+        db.update(key.clone(), |value| {
+            *value.unwrap() = "2".to_string();
+            (Update::Keep, ())
+        })
+        .unwrap();
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "2");
+        db.set(key.clone(), "3".to_string()).unwrap();
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "3");
+
+        // A deadline set after the value was viewed still applies
+        let when = db.clock().now_ms() + 1000;
+        assert!(db.expire_at(&key, when, ExpireCondition::Always).unwrap());
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "3");
+        db.clock().advance(1000);
+        assert!(db.get(&key).unwrap().is_none());
+
+        db.set(key.clone(), "4".to_string()).unwrap();
+        assert_eq!(*db.get(&key).unwrap().unwrap(), "4");
+        db.delete(std::slice::from_ref(&key)).unwrap();
+        assert!(db.get(&key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reload() {
+        let db = new_db();
+        let when = now_ms() + 60_000;
+        db.set("plain".to_string(), "a".to_string()).unwrap();
+        db.set_with_expiry("volatile".to_string(), "b".to_string(), when)
+            .unwrap();
+        db.set_with_expiry("expired".to_string(), "c".to_string(), 1)
+            .unwrap();
+
+        assert_eq!(db.reload(|value| Ok(value.to_uppercase())).unwrap(), 2);
+        assert_eq!(*db.get(&"plain".to_string()).unwrap().unwrap(), "A");
+        assert_eq!(*db.get(&"volatile".to_string()).unwrap().unwrap(), "B");
+        assert_eq!(
+            db.expiry(&"volatile".to_string()).unwrap(),
+            Expiry::At(when)
+        );
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.expires_len(), 1);
+
+        // A failed reload leaves the keys as they were
+        assert!(db.reload(|_| Err(anyhow::anyhow!("bad value"))).is_err());
+        assert_eq!(*db.get(&"plain".to_string()).unwrap().unwrap(), "A");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dbs = vec![Arc::new(new_db()), Arc::new(new_db())];
+        let when = now_ms() + 60_000;
+        dbs[0].set("a".to_string(), "1".to_string()).unwrap();
+        dbs[0].set("b".to_string(), "2".to_string()).unwrap();
+        dbs[1]
+            .set_with_expiry("c".to_string(), "3".to_string(), when)
+            .unwrap();
+        let contents = |snapshot: &Snapshot<_, String, String>| {
+            let mut entries = vec![];
+            snapshot
+                .for_each(|key, value, when| {
+                    entries.push((key.clone(), value.clone(), when));
+                    Ok(())
+                })
+                .unwrap();
+            entries.sort();
+            entries
+        };
+
+        // Writes after the snapshot don't show in it
+        let snapshots = snapshot(&dbs);
+        dbs[0].set("a".to_string(), "changed".to_string()).unwrap();
+        dbs[0].delete(&["b".to_string()]).unwrap();
+        dbs[0].set("new".to_string(), "4".to_string()).unwrap();
+        dbs[1].persist(&"c".to_string()).unwrap();
+        assert_eq!(
+            contents(&snapshots[0]),
+            vec![
+                ("a".to_string(), "1".to_string(), None),
+                ("b".to_string(), "2".to_string(), None),
+            ]
+        );
+        assert_eq!(
+            contents(&snapshots[1]),
+            vec![("c".to_string(), "3".to_string(), Some(when))]
+        );
+        assert_eq!(*dbs[0].get(&"a".to_string()).unwrap().unwrap(), "changed");
+
+        // Once dropped, writes no longer keep the old values
+        drop(snapshots);
+        assert!(dbs[0].frozen.read().unwrap().is_empty());
+        assert_eq!(contents(&snapshot(&dbs)[0]).len(), 2);
+    }
+
+    #[test]
+    fn test_scan() {
+        let db = new_db();
+        for i in 0..1000 {
+            db.set(format!("kept:{}", i), "v".to_string()).unwrap();
+            db.set(format!("gone:{}", i), "v".to_string()).unwrap();
+        }
+        db.set_with_expiry("expired".to_string(), "v".to_string(), 1)
+            .unwrap();
+
+        // The table grows and shrinks between steps, keys present all along
+        // still come exactly once
+        let (mut cursor, mut seen, mut step) = (0, vec![], 0);
+        loop {
+            let (next, keys) = db.scan(cursor, 50).unwrap();
+            assert!(keys.len() <= 50);
+            seen.extend(keys);
+            step += 1;
+            if step == 3 {
+                for i in 0..5000 {
+                    db.set(format!("new:{}", i), "v".to_string()).unwrap();
+                }
+            }
+            if step == 6 {
+                let gone: Vec<_> = (0..1000).map(|i| format!("gone:{}", i)).collect();
+                db.delete(&gone).unwrap();
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        let kept: Vec<_> = seen.iter().filter(|key| key.starts_with("kept:")).collect();
+        assert_eq!(kept.len(), 1000);
+        assert_eq!(kept.iter().collect::<HashSet<_>>().len(), 1000);
+        assert!(!seen.contains(&"expired".to_string()));
+        assert_eq!(db.scan(0, 10).unwrap().1.len(), 10);
+
+        // The check sees every key and can stop the pass
+        let checked = std::sync::atomic::AtomicUsize::new(0);
+        db.scan_checked(0, 10, |_| {
+            checked.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(checked.into_inner(), db.len());
+        let stopped = db.scan_checked(0, 10, |step| match step {
+            100 => Err(anyhow::anyhow!("stop")),
+            _ => Ok(()),
+        });
+        assert_eq!(stopped.unwrap_err().to_string(), "stop");
+    }
+}
@@ -0,0 +1,404 @@
+// Storage kept on disk in a sled tree, for datasets larger than memory.
+// Values are stored in the DUMP encoding and decoded on every read. Their
+// deadlines go in a second tree, written through as DB changes them and
+// loaded back by DB when it opens, so they survive a restart too.
+use crate::db::dump;
+use crate::db::storage::{Result, Storage, StorageError, Update, Versions};
+use crate::db::value::Value;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Writers to keys in the same stripe are serialized, which keeps update()
+// atomic without a transaction per call
+const LOCK_STRIPES: usize = 64;
+
+#[derive(Debug)]
+pub struct SledStorage {
+    tree: sled::Tree,
+    // Unix ms deadline of each volatile key, big endian
+    expires: sled::Tree,
+    locks: Vec<Mutex<()>>,
+    // sled only counts entries by scanning, so the count is kept here
+    len: AtomicUsize,
+    // In memory like expiries, keys from an earlier run get one when first
+    // asked for
+    versions: Versions<String>,
+}
+
+fn internal(e: impl std::fmt::Display) -> StorageError {
+    StorageError::Internal(e.to_string())
+}
+
+fn encode(value: &Value) -> Result<Vec<u8>> {
+    dump::serialize(value).map_err(internal)
+}
+
+fn decode(bytes: &[u8]) -> Result<Value> {
+    dump::deserialize(bytes).map_err(internal)
+}
+
+impl SledStorage {
+    pub fn new(tree: sled::Tree, expires: sled::Tree) -> Self {
+        let len = AtomicUsize::new(tree.len());
+        Self {
+            tree,
+            expires,
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            len,
+            versions: Versions::default(),
+        }
+    }
+
+    // The storage in the tree named name, deadlines in name.expires
+    pub fn open(db: &sled::Db, name: &str) -> Result<Self> {
+        let tree = db.open_tree(name).map_err(internal)?;
+        let expires = db
+            .open_tree(format!("{}.expires", name))
+            .map_err(internal)?;
+        Ok(Self::new(tree, expires))
+    }
+
+    // Writes out what sled still buffers, which it otherwise does on its own
+    // every half second
+    pub fn flush(&self) -> Result<()> {
+        self.tree.flush().map_err(internal)?;
+        self.expires.flush().map(|_| ()).map_err(internal)
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = (hasher.finish() % LOCK_STRIPES as u64) as usize;
+        self.locks[stripe].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read(&self, key: &str) -> Result<Option<Value>> {
+        match self.tree.get(key).map_err(internal)? {
+            Some(bytes) => decode(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Called with the key's stripe held, like the writes
+    fn current_version(&self, key: &str) -> Result<u64> {
+        match self.versions.get(key) {
+            Some(version) => Ok(version),
+            None if self.tree.contains_key(key).map_err(internal)? => {
+                Ok(self.versions.bump(key.to_string()))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn write(&self, key: &str, value: &Value) -> Result<Option<Value>> {
+        let old = self.tree.insert(key, encode(value)?).map_err(internal)?;
+        self.versions.bump(key.to_string());
+        match old {
+            Some(bytes) => decode(&bytes).map(Some),
+            None => {
+                self.len.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+
+    // Removes the value but keeps its deadline, for a key that leaves the
+    // cold tier of a TieredStorage while it lives on in the hot one
+    pub fn take(&self, key: &str) -> Result<Option<Value>> {
+        let _guard = self.lock(key);
+        self.remove(key)
+    }
+
+    fn remove(&self, key: &str) -> Result<Option<Value>> {
+        self.versions.forget(key);
+        match self.tree.remove(key).map_err(internal)? {
+            Some(bytes) => {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                decode(&bytes).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Storage<String, Value> for SledStorage {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Value>>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        Ok(self.read(&key.to_owned())?.map(Arc::new))
+    }
+
+    fn set(&self, key: String, value: Value) -> Result<Option<Value>> {
+        let _guard = self.lock(&key);
+        self.write(&key, &value)
+    }
+
+    fn insert_if_absent(&self, key: String, value: Value) -> Result<bool> {
+        let _guard = self.lock(&key);
+        if self.tree.contains_key(&key).map_err(internal)? {
+            return Ok(false);
+        }
+        self.write(&key, &value)?;
+        Ok(true)
+    }
+
+    fn update<F, R>(&self, key: String, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut Value>) -> (Update<Value>, R),
+    {
+        let _guard = self.lock(&key);
+        let mut current = self.read(&key)?;
+        let (update, result) = f(current.as_mut());
+        match update {
+            // The closure may have changed the value in place
+            Update::Keep => {
+                if let Some(value) = current {
+                    self.write(&key, &value)?;
+                }
+            }
+            Update::Set(value) => {
+                self.write(&key, &value)?;
+            }
+            Update::Delete => {
+                self.remove(&key)?;
+            }
+        }
+        Ok(result)
+    }
+
+    fn delete<Q>(&self, key: &Q) -> Result<Option<Value>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        self.expires.remove(key.as_str()).map_err(internal)?;
+        self.remove(&key)
+    }
+
+    fn version<Q>(&self, key: &Q) -> Result<u64>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        self.current_version(&key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Value,
+    ) -> Result<Option<u64>> {
+        let _guard = self.lock(&key);
+        if self.current_version(&key)? != expected_version {
+            return Ok(None);
+        }
+        self.write(&key, &value)?;
+        self.current_version(&key).map(Some)
+    }
+
+    fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> Result<bool>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        if self.current_version(&key)? != expected_version {
+            return Ok(false);
+        }
+        self.expires.remove(key.as_str()).map_err(internal)?;
+        Ok(self.remove(&key)?.is_some())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.tree.clear().map_err(internal)?;
+        self.expires.clear().map_err(internal)?;
+        self.versions.clear();
+        self.len.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.tree
+            .iter()
+            .keys()
+            .map(|key| {
+                let key = key.map_err(internal)?;
+                String::from_utf8(key.to_vec()).map_err(internal)
+            })
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn set_expiry(&self, key: &String, when: Option<u64>) -> Result<()> {
+        match when {
+            Some(when) => self.expires.insert(key.as_str(), &when.to_be_bytes()),
+            None => self.expires.remove(key.as_str()),
+        }
+        .map(|_| ())
+        .map_err(internal)
+    }
+
+    // A deadline can outlive its key when the process died between the two
+    // writes; it is left out, and dropped when the key is written again
+    fn expiries(&self) -> Result<Vec<(String, u64)>> {
+        let mut expiries = Vec::new();
+        for entry in self.expires.iter() {
+            let (key, when) = entry.map_err(internal)?;
+            if !self.tree.contains_key(&key).map_err(internal)? {
+                continue;
+            }
+            let when: [u8; 8] = when
+                .as_ref()
+                .try_into()
+                .map_err(|_| internal("bad deadline"))?;
+            let key = String::from_utf8(key.to_vec()).map_err(internal)?;
+            expiries.push((key, u64::from_be_bytes(when)));
+        }
+        Ok(expiries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &str) -> Value {
+        Value::str(s)
+    }
+
+    #[test]
+    fn test_sled_storage() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = SledStorage::open(&db, "db0").unwrap();
+
+        assert!(storage.set("a".to_string(), bulk("1")).unwrap().is_none());
+        assert!(!storage
+            .insert_if_absent("a".to_string(), bulk("2"))
+            .unwrap());
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("1"));
+
+        // In place changes under Keep are written back
+        storage
+            .update("list".to_string(), |v| {
+                assert!(v.is_none());
+                (Update::Set(Value::List(vec!["x".to_string()].into())), ())
+            })
+            .unwrap();
+        storage
+            .update("list".to_string(), |v| {
+                if let Some(Value::List(items)) = v {
+                    items.push_back("y".to_string());
+                }
+                (Update::Keep, ())
+            })
+            .unwrap();
+        assert_eq!(
+            *storage.get("list").unwrap().unwrap(),
+            Value::List(vec!["x".to_string(), "y".to_string()].into())
+        );
+        assert_eq!(storage.len(), 2);
+
+        assert_eq!(storage.delete("a").unwrap(), Some(bulk("1")));
+        assert_eq!(storage.len(), 1);
+        storage.clear().unwrap();
+        assert!(storage.is_empty());
+    }
+
+    #[test]
+    fn test_sled_expiries() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = SledStorage::open(&db, "db0").unwrap();
+        for key in ["a", "b", "c"] {
+            storage.set(key.to_string(), bulk(key)).unwrap();
+        }
+        storage.set_expiry(&"a".to_string(), Some(100)).unwrap();
+        storage.set_expiry(&"b".to_string(), Some(200)).unwrap();
+        storage.set_expiry(&"b".to_string(), None).unwrap();
+        storage.set_expiry(&"c".to_string(), Some(300)).unwrap();
+        storage.delete("c").unwrap();
+        // Left behind by a key that never made it to disk
+        storage.set_expiry(&"d".to_string(), Some(400)).unwrap();
+
+        let storage = SledStorage::open(&db, "db0").unwrap();
+        assert_eq!(storage.expiries().unwrap(), [("a".to_string(), 100)]);
+        storage.clear().unwrap();
+        assert!(storage.expiries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_db_deadlines_survive_reopen() {
+        use crate::db::db::{now_ms, ExpireCondition, Expiry, DB};
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let when = now_ms() + 60_000;
+        {
+            let store = DB::new(SledStorage::open(&db, "db0").unwrap(), 0);
+            store
+                .set_with_expiry("a".to_string(), bulk("1"), when)
+                .unwrap();
+            store.set("b".to_string(), bulk("2")).unwrap();
+            store
+                .expire_at(&"b".to_string(), when, ExpireCondition::Always)
+                .unwrap();
+            store.persist(&"b".to_string()).unwrap();
+            // Overwritten without a deadline
+            store
+                .set_with_expiry("c".to_string(), bulk("3"), when)
+                .unwrap();
+            store.set("c".to_string(), bulk("4")).unwrap();
+        }
+
+        let store = DB::new(SledStorage::open(&db, "db0").unwrap(), 0);
+        assert_eq!(store.load_expiries().unwrap(), 1);
+        assert_eq!(store.expiry(&"a".to_string()).unwrap(), Expiry::At(when));
+        assert_eq!(store.expiry(&"b".to_string()).unwrap(), Expiry::Persistent);
+        assert_eq!(store.expiry(&"c".to_string()).unwrap(), Expiry::Persistent);
+    }
+
+    #[test]
+    fn test_sled_versions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStorage::open(&db, "db0")
+            .unwrap()
+            .set("a".to_string(), bulk("1"))
+            .unwrap();
+
+        // A key from before still counts as present
+        let storage = SledStorage::open(&db, "db0").unwrap();
+        let version = storage.version("a").unwrap();
+        assert_ne!(version, 0);
+        assert_eq!(storage.version("a").unwrap(), version);
+        assert_eq!(
+            storage
+                .compare_and_swap("a".to_string(), 0, bulk("2"))
+                .unwrap(),
+            None
+        );
+        let swapped = storage
+            .compare_and_swap("a".to_string(), version, bulk("2"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.version("a").unwrap(), swapped);
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("2"));
+
+        storage.delete("a").unwrap();
+        assert_eq!(storage.version("a").unwrap(), 0);
+        assert!(storage
+            .compare_and_swap("a".to_string(), 0, bulk("3"))
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,261 @@
+// Serialized form of a stored value, used by DUMP/RESTORE. Layout:
+//
+//   type byte | body | version (u16 LE) | checksum (u64 LE, FNV-1a of all
+//   preceding bytes)
+//
+// Lengths and counts in the body are u32 LE. Strings on the wire are UTF-8
+// only, so DUMP hands the payload out hex encoded.
+use crate::db::bytestring::ByteString;
+use crate::db::sortedset::SortedSet;
+use crate::db::value::Value;
+use std::fmt;
+
+pub const DUMP_VERSION: u16 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+#[cfg(feature = "json")]
+const TYPE_JSON: u8 = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DumpError {
+    // Version or checksum do not match
+    BadPayload,
+    // Checksum fine, but the body does not describe a value
+    Corrupt,
+    Unsupported,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadPayload => write!(f, "DUMP payload version or checksum are wrong"),
+            Self::Corrupt => write!(f, "Bad data format"),
+            Self::Unsupported => write!(f, "value type cannot be dumped"),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_bytes(out, s.as_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// Compact collections and compressed strings are written in their full form
+pub fn serialize(value: &Value) -> Result<Vec<u8>, DumpError> {
+    let mut out = vec![];
+    match value {
+        Value::Listpack(packed) => return serialize(&packed.expand()),
+        Value::Str(_) | Value::Compressed(_) => {
+            let string = ByteString::from_stored(value).ok_or(DumpError::Unsupported)?;
+            out.push(TYPE_STRING);
+            put_bytes(&mut out, string.as_bytes());
+        }
+        Value::List(items) => {
+            out.push(TYPE_LIST);
+            out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                put_str(&mut out, item);
+            }
+        }
+        Value::Set(items) => {
+            out.push(TYPE_SET);
+            out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                put_str(&mut out, item);
+            }
+        }
+        Value::Hash(pairs) => {
+            out.push(TYPE_HASH);
+            out.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+            for (field, value) in pairs {
+                put_str(&mut out, field);
+                put_str(&mut out, value);
+            }
+        }
+        Value::ZSet(pairs) => {
+            out.push(TYPE_ZSET);
+            out.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+            for (member, score) in pairs {
+                put_str(&mut out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Stream(_) => return Err(DumpError::Unsupported),
+        // As its text
+        #[cfg(feature = "json")]
+        Value::Json(doc) => {
+            out.push(TYPE_JSON);
+            put_str(&mut out, &doc.to_string());
+        }
+    }
+    out.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+    let sum = checksum(&out);
+    out.extend_from_slice(&sum.to_le_bytes());
+    Ok(out)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], DumpError> {
+        if self.bytes.len() < n {
+            return Err(DumpError::Corrupt);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<usize, DumpError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, DumpError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn text(&mut self) -> Result<String, DumpError> {
+        let len = self.u32()?;
+        let s = std::str::from_utf8(self.take(len)?).map_err(|_| DumpError::Corrupt)?;
+        Ok(s.to_string())
+    }
+}
+
+pub fn deserialize(payload: &[u8]) -> Result<Value, DumpError> {
+    if payload.len() < 11 {
+        return Err(DumpError::BadPayload);
+    }
+    let (data, sum) = payload.split_at(payload.len() - 8);
+    if checksum(data) != u64::from_le_bytes(sum.try_into().unwrap()) {
+        return Err(DumpError::BadPayload);
+    }
+    let (body, version) = data.split_at(data.len() - 2);
+    if u16::from_le_bytes(version.try_into().unwrap()) > DUMP_VERSION {
+        return Err(DumpError::BadPayload);
+    }
+
+    let mut reader = Reader { bytes: &body[1..] };
+    let value = match body[0] {
+        // Any bytes, where elements are text
+        TYPE_STRING => {
+            let len = reader.u32()?;
+            ByteString::new(reader.take(len)?).into_value()
+        }
+        TYPE_LIST | TYPE_SET => {
+            let count = reader.u32()?;
+            let items = (0..count)
+                .map(|_| reader.text())
+                .collect::<Result<Vec<_>, _>>()?;
+            if body[0] == TYPE_SET {
+                Value::Set(items.into_iter().collect())
+            } else {
+                Value::List(items.into())
+            }
+        }
+        TYPE_HASH => {
+            let count = reader.u32()?;
+            let pairs = (0..count)
+                .map(|_| Ok((reader.text()?, reader.text()?)))
+                .collect::<Result<_, DumpError>>()?;
+            Value::Hash(pairs)
+        }
+        TYPE_ZSET => {
+            let count = reader.u32()?;
+            // The count is untrusted until the entries are actually read
+            let mut pairs = SortedSet::new();
+            for _ in 0..count {
+                pairs.insert(reader.text()?, reader.f64()?);
+            }
+            Value::ZSet(pairs)
+        }
+        #[cfg(feature = "json")]
+        TYPE_JSON => {
+            let text = reader.text()?;
+            Value::Json(serde_json::from_str(&text).map_err(|_| DumpError::Corrupt)?)
+        }
+        _ => return Err(DumpError::Corrupt),
+    };
+    if !reader.bytes.is_empty() {
+        return Err(DumpError::Corrupt);
+    }
+    Ok(value)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, DumpError> {
+    if !s.len().is_multiple_of(2) {
+        return Err(DumpError::BadPayload);
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or(DumpError::BadPayload)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let values = [
+            Value::str("hello"),
+            Value::List(strings(&["a", "b"]).into()),
+            Value::Set(strings(&["x"]).into_iter().collect()),
+            Value::Hash([("f".to_string(), "v".to_string())].into()),
+            Value::ZSet(vec![("m".to_string(), 1.5)].into()),
+            // Bytes that aren't UTF-8
+            Value::Str(vec![0xff, 0x00, 0xc3]),
+            #[cfg(feature = "json")]
+            Value::Json(serde_json::json!({"a": [1, "b", null], "c": {"d": 1.5}})),
+        ];
+        for value in values {
+            let payload = serialize(&value).unwrap();
+            let hex = to_hex(&payload);
+            assert_eq!(deserialize(&from_hex(&hex).unwrap()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_bad_payload() {
+        let mut payload = serialize(&Value::str("hello")).unwrap();
+        payload[2] ^= 1;
+        assert_eq!(deserialize(&payload), Err(DumpError::BadPayload));
+        assert_eq!(deserialize(b"short"), Err(DumpError::BadPayload));
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zé").is_err());
+        assert!(serialize(&Value::Stream(vec![])).is_err());
+    }
+}
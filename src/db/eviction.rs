@@ -0,0 +1,152 @@
+use rand::Rng;
+use std::fmt;
+use std::str::FromStr;
+
+// New keys start here so they are not the first to go under LFU
+pub const LFU_INIT_VAL: u8 = 5;
+// Higher factor means more hits are needed to grow the counter
+pub const LFU_LOG_FACTOR: f64 = 10.0;
+// Minutes for the counter to lose one point while the key sits idle
+pub const LFU_DECAY_TIME: u64 = 1;
+
+// Same names as Redis' maxmemory-policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn is_lfu(&self) -> bool {
+        matches!(self, Self::AllKeysLfu | Self::VolatileLfu)
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(Self::NoEviction),
+            "allkeys-lru" => Ok(Self::AllKeysLru),
+            "volatile-lru" => Ok(Self::VolatileLru),
+            "allkeys-lfu" => Ok(Self::AllKeysLfu),
+            "volatile-lfu" => Ok(Self::VolatileLfu),
+            "allkeys-random" => Ok(Self::AllKeysRandom),
+            "volatile-random" => Ok(Self::VolatileRandom),
+            "volatile-ttl" => Ok(Self::VolatileTtl),
+            _ => Err(format!("invalid maxmemory policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::VolatileLru => "volatile-lru",
+            Self::AllKeysLfu => "allkeys-lfu",
+            Self::VolatileLfu => "volatile-lfu",
+            Self::AllKeysRandom => "allkeys-random",
+            Self::VolatileRandom => "volatile-random",
+            Self::VolatileTtl => "volatile-ttl",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// What is kept per key for eviction, like the lru field of a Redis object:
+// how often it's used under the LFU policies, when it was last used under
+// the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Lfu(LfuCounter),
+    // Seconds since the unix epoch, see lru_clock
+    Lru(u32),
+}
+
+// The coarse clock idle times are kept in, seconds since the unix epoch
+pub fn lru_clock(now_ms: u64) -> u32 {
+    (now_ms / 1000) as u32
+}
+
+// Morris style access counter: grows logarithmically with hits and loses a
+// point for every LFU_DECAY_TIME minutes without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LfuCounter {
+    counter: u8,
+    // Minutes since the unix epoch of the last decay
+    last_decay: u64,
+}
+
+impl LfuCounter {
+    pub fn new(now_min: u64) -> Self {
+        Self {
+            counter: LFU_INIT_VAL,
+            last_decay: now_min,
+        }
+    }
+
+    // Counter value after applying the decay owed up to now
+    pub fn value(&self, now_min: u64) -> u8 {
+        let periods = now_min.saturating_sub(self.last_decay) / LFU_DECAY_TIME;
+        self.counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    pub fn touch(&mut self, now_min: u64) {
+        self.counter = self.value(now_min);
+        self.last_decay = now_min;
+        if self.counter == u8::MAX {
+            return;
+        }
+        let base = self.counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        if rand::thread_rng().gen::<f64>() < p {
+            self.counter += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_names() {
+        for name in ["noeviction", "allkeys-lfu", "volatile-ttl"] {
+            assert_eq!(name.parse::<EvictionPolicy>().unwrap().to_string(), name);
+        }
+        assert!("ALLKEYS-LFU".parse::<EvictionPolicy>().unwrap().is_lfu());
+        assert!("sometimes".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        let mut counter = LfuCounter::new(0);
+        assert_eq!(counter.value(0), LFU_INIT_VAL);
+
+        // The first hits above the initial value always count
+        counter.touch(0);
+        assert_eq!(counter.value(0), LFU_INIT_VAL + 1);
+
+        // ...later ones less and less often
+        for _ in 0..10_000 {
+            counter.touch(0);
+        }
+        let hot = counter.value(0);
+        assert!(hot > LFU_INIT_VAL + 5 && hot < u8::MAX, "counter {}", hot);
+
+        // One point lost per idle minute
+        assert_eq!(counter.value(3), hot - 3);
+        assert_eq!(counter.value(100_000), 0);
+    }
+}
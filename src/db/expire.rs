@@ -0,0 +1,85 @@
+// Active expiration. Lazy expiry only drops a key when it is touched, so keys
+// nobody reads again would stay in memory forever. Every deadline is also
+// scheduled in the db's TTL heap (see ttlheap.rs), and the server runs a
+// cycle whenever the earliest one comes, dropping the keys that are due.
+// A cycle stops on its time budget and the next one picks up the rest.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Timers taken off the heap at once, between checks of the time budget
+pub const EXPIRE_BATCH: usize = 64;
+// Longest the server sleeps between cycles, so a clock moved forward is
+// noticed, and the time a cycle may use
+pub const EXPIRE_MAX_SLEEP: Duration = Duration::from_secs(1);
+pub const EXPIRE_CYCLE_BUDGET: Duration = Duration::from_millis(25);
+// Timers kept before tombstones are worth dropping
+pub const TOMBSTONES_MIN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ExpireInfo {
+    // Keys expired lazily or by the cycle
+    pub expired_keys: u64,
+    // Running estimate of the percent of volatile keys a cycle finds expired
+    pub stale_perc: f64,
+    // Cycles that stopped on the time budget rather than on a clean sample
+    pub time_cap_reached: u64,
+    pub cycle_cpu_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ExpireStats {
+    expired_keys: AtomicU64,
+    // Hundredths of a percent, so the estimate fits an atomic
+    stale: AtomicU64,
+    time_cap_reached: AtomicU64,
+    cycle_us: AtomicU64,
+}
+
+impl ExpireStats {
+    pub fn expired(&self) {
+        self.expired_keys.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Folds one cycle over volatile keys into the stats. Like Redis the stale
+    // estimate moves 5% of the way towards each new cycle.
+    pub fn cycle(&self, volatile: usize, expired: usize, timed_out: bool, spent: Duration) {
+        if let Some(current) = (expired * 10_000).checked_div(volatile) {
+            let current = current as u64;
+            let stale = self.stale.load(Ordering::Relaxed);
+            self.stale
+                .store((current * 5 + stale * 95) / 100, Ordering::Relaxed);
+        }
+        if timed_out {
+            self.time_cap_reached.fetch_add(1, Ordering::Relaxed);
+        }
+        self.cycle_us
+            .fetch_add(spent.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ExpireInfo {
+        ExpireInfo {
+            expired_keys: self.expired_keys.load(Ordering::Relaxed),
+            stale_perc: self.stale.load(Ordering::Relaxed) as f64 / 100.0,
+            time_cap_reached: self.time_cap_reached.load(Ordering::Relaxed),
+            cycle_cpu_ms: self.cycle_us.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = ExpireStats::default();
+        stats.expired();
+        stats.cycle(20, 20, true, Duration::from_millis(3));
+        stats.cycle(0, 0, false, Duration::from_millis(1));
+        let info = stats.info();
+        assert_eq!(info.expired_keys, 1);
+        assert_eq!(info.stale_perc, 5.0);
+        assert_eq!(info.time_cap_reached, 1);
+        assert_eq!(info.cycle_cpu_ms, 4);
+    }
+}
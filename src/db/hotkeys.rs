@@ -0,0 +1,164 @@
+// Hot key detection: every access bumps a count-min sketch and the keys with
+// the highest estimates are kept in a short top list. All counts are halved
+// once per window, so the list follows what was busy lately rather than
+// since startup.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+const DEPTH: usize = 4;
+const WIDTH: usize = 1024;
+
+// Keys HOTKEYS can report at most
+pub const HOTKEYS_MAX: usize = 32;
+// Milliseconds between two halvings of the counts
+pub const HOTKEYS_WINDOW_MS: u64 = 60_000;
+
+pub struct HotKeys<K> {
+    // DEPTH rows of WIDTH counters, a key bumps one counter per row
+    counters: Vec<AtomicU32>,
+    // Highest estimates first
+    top: Mutex<Vec<(K, u32)>>,
+    // Lowest count of a full top list, smaller estimates skip the lock
+    floor: AtomicU32,
+    // Unix ms of the next halving
+    next_decay: AtomicU64,
+}
+
+impl<K> HotKeys<K>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            counters: (0..DEPTH * WIDTH).map(|_| AtomicU32::new(0)).collect(),
+            top: Mutex::new(Vec::with_capacity(HOTKEYS_MAX + 1)),
+            floor: AtomicU32::new(0),
+            next_decay: AtomicU64::new(0),
+        }
+    }
+
+    fn top(&self) -> MutexGuard<'_, Vec<(K, u32)>> {
+        self.top.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // One counter per row, picked by double hashing a single 64 bit hash
+    fn slots(key: &K) -> [usize; DEPTH] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        std::array::from_fn(|row| row * WIDTH + h1.wrapping_add(row.wrapping_mul(h2)) % WIDTH)
+    }
+
+    fn update_floor(&self, top: &[(K, u32)]) {
+        let floor = match top.len() {
+            HOTKEYS_MAX => top.last().map_or(0, |(_, count)| *count),
+            _ => 0,
+        };
+        self.floor.store(floor, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, key: &K, now_ms: u64) {
+        self.decay_if_due(now_ms);
+        let estimate = Self::slots(key)
+            .iter()
+            .map(|&slot| self.counters[slot].fetch_add(1, Ordering::Relaxed) + 1)
+            .min()
+            .unwrap_or(0);
+        if estimate <= self.floor.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut top = self.top();
+        match top.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = estimate,
+            None => top.push((key.clone(), estimate)),
+        }
+        top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        top.truncate(HOTKEYS_MAX);
+        self.update_floor(&top);
+    }
+
+    fn decay_if_due(&self, now_ms: u64) {
+        let due = self.next_decay.load(Ordering::Relaxed);
+        // Only the caller that moves the deadline does the halving
+        if now_ms < due
+            || self
+                .next_decay
+                .compare_exchange(
+                    due,
+                    now_ms + HOTKEYS_WINDOW_MS,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return;
+        }
+        for counter in &self.counters {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n / 2));
+        }
+        let mut top = self.top();
+        for entry in top.iter_mut() {
+            entry.1 /= 2;
+        }
+        top.retain(|(_, count)| *count > 0);
+        self.update_floor(&top);
+    }
+
+    // Up to count keys with their estimated accesses, busiest first. Deleted
+    // keys stay listed until their count decays.
+    pub fn hottest(&self, count: usize) -> Vec<(K, u32)> {
+        self.top().iter().take(count).cloned().collect()
+    }
+}
+
+impl<K> Default for HotKeys<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hottest_and_decay() {
+        let hotkeys = HotKeys::new();
+        for i in 0..100 {
+            hotkeys.record(&"hot", 0);
+            if i % 10 == 0 {
+                hotkeys.record(&"warm", 0);
+            }
+        }
+        hotkeys.record(&"cold", 0);
+        // Each key has its own counters here, so the estimates are exact
+        assert_eq!(hotkeys.hottest(2), vec![("hot", 100), ("warm", 10)]);
+        assert_eq!(hotkeys.hottest(10).len(), 3);
+
+        // A window later every count is halved, "cold" drops out
+        hotkeys.record(&"warm", HOTKEYS_WINDOW_MS);
+        assert_eq!(hotkeys.hottest(10), vec![("hot", 50), ("warm", 6)]);
+    }
+
+    #[test]
+    fn test_full_list_keeps_the_busiest() {
+        let hotkeys = HotKeys::new();
+        for key in 0..HOTKEYS_MAX * 4 {
+            for _ in 0..=key {
+                hotkeys.record(&key, 0);
+            }
+        }
+        let hottest = hotkeys.hottest(HOTKEYS_MAX);
+        assert_eq!(hottest.len(), HOTKEYS_MAX);
+        assert_eq!(hottest[0].0, HOTKEYS_MAX * 4 - 1);
+        // Estimates never undercount
+        assert!(hottest.iter().all(|(key, count)| *count as usize > *key));
+    }
+}
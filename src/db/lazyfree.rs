@@ -0,0 +1,177 @@
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use tracing::error;
+
+use crate::db::value::Value;
+
+// Values whose free effort is above this are handed to the lazyfree thread.
+// Same cut-off Redis uses for its lazyfree_lazy_* policies.
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+// Rough cost of dropping a value, counted in allocations.
+pub trait FreeEffort {
+    fn free_effort(&self) -> usize;
+}
+
+impl FreeEffort for Value {
+    fn free_effort(&self) -> usize {
+        match self {
+            Value::List(items) => items.len(),
+            Value::Set(items) => items.len(),
+            Value::Hash(pairs) => pairs.len(),
+            Value::ZSet(pairs) => pairs.len(),
+            Value::Stream(entries) => entries.len(),
+            _ => 1,
+        }
+    }
+}
+
+// What the lazyfree thread drops, boxed so databases of any value type can
+// share it, with the counters of the queue that sent it
+type Job = (Box<dyn Send>, Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    pending: AtomicUsize,
+    freed: AtomicUsize,
+}
+
+// The one lazyfree thread of the process, started by the first value sent
+// to it. None when it could not be started.
+static WORKER: OnceLock<Option<Sender<Job>>> = OnceLock::new();
+
+fn worker() -> Option<&'static Sender<Job>> {
+    WORKER
+        .get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<Job>();
+            let spawned = thread::Builder::new()
+                .name("lazyfree".to_string())
+                .spawn(move || {
+                    for (value, counters) in rx {
+                        drop(value);
+                        counters.pending.fetch_sub(1, Ordering::Relaxed);
+                        counters.freed.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            match spawned {
+                Ok(_) => Some(tx),
+                Err(e) => {
+                    error!("Failed to start the lazyfree thread: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+// Lazy free queue of a DB: values sent here are dropped on the process wide
+// lazyfree thread so the connection handler that unlinked them does not pay
+// for the deallocation. Queues are cheap, a DB made for a moment starts no
+// thread of its own.
+pub struct LazyFree<V> {
+    counters: Arc<Counters>,
+    _marker: PhantomData<fn(V)>,
+}
+
+impl<V> LazyFree<V>
+where
+    V: Send + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            counters: Arc::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn free(&self, value: V)
+    where
+        V: FreeEffort,
+    {
+        if value.free_effort() <= LAZYFREE_THRESHOLD {
+            drop(value);
+            return;
+        }
+        let Some(worker) = worker() else {
+            drop(value);
+            return;
+        };
+
+        self.counters.pending.fetch_add(1, Ordering::Relaxed);
+        let sent = worker.send((Box::new(value), self.counters.clone()));
+        if let Err(mpsc::SendError((value, _))) = sent {
+            // Worker is gone, nothing left to do but free it here
+            self.counters.pending.fetch_sub(1, Ordering::Relaxed);
+            drop(value);
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.counters.pending.load(Ordering::Relaxed)
+    }
+
+    pub fn freed(&self) -> usize {
+        self.counters.freed.load(Ordering::Relaxed)
+    }
+}
+
+impl<V> Default for LazyFree<V>
+where
+    V: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_small_values_freed_inline() {
+        let lazyfree = LazyFree::new();
+        lazyfree.free(Value::str("value"));
+        lazyfree.free(Value::List(vec!["a".to_string(), "b".to_string()].into()));
+
+        assert_eq!(lazyfree.pending(), 0);
+        assert_eq!(lazyfree.freed(), 0);
+    }
+
+    #[test]
+    fn test_large_values_freed_in_background() {
+        let lazyfree = LazyFree::new();
+        let items = (0..LAZYFREE_THRESHOLD * 2).map(|i| i.to_string()).collect();
+        lazyfree.free(Value::List(items));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lazyfree.freed() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lazyfree.freed(), 1);
+        assert_eq!(lazyfree.pending(), 0);
+    }
+
+    #[test]
+    fn test_queues_share_one_thread() {
+        let first = LazyFree::new();
+        let second = LazyFree::new();
+        let items: Vec<String> = (0..LAZYFREE_THRESHOLD * 2).map(|i| i.to_string()).collect();
+        first.free(Value::List(items.clone().into()));
+        second.free(Value::List(items.clone().into()));
+        second.free(Value::List(items.into()));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (first.freed() < 1 || second.freed() < 2) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Each queue counts its own values
+        assert_eq!(first.freed(), 1);
+        assert_eq!(second.freed(), 2);
+        assert!(std::ptr::eq(worker().unwrap(), worker().unwrap()));
+    }
+}
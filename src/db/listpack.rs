@@ -0,0 +1,559 @@
+// Compact encoding of small hashes, lists and sets. A collection under the
+// limits is kept as one string of length-prefixed entries instead of a
+// String per element, which saves an allocation and most of the per
+// element overhead. The common edits (setting hash fields, adding and
+// removing set members, pushing onto a list) work on the compact form in
+// place; anything else expands it for the edit and packs it again if it
+// still fits. Once a collection outgrows the limits it is converted to the
+// full form, a hash table for hashes and sets, and stays there like in
+// Redis.
+use crate::db::storage::Update;
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use indexmap::IndexMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListpackLimits {
+    // Fields of a compact hash, and bytes of any field or value
+    pub hash_max_entries: usize,
+    pub hash_max_value: usize,
+    pub set_max_entries: usize,
+    pub set_max_value: usize,
+    // Elements when positive, otherwise -1 to -5 for 4 to 64 KB of entries
+    pub list_max_size: i64,
+}
+
+impl Default for ListpackLimits {
+    fn default() -> Self {
+        Self {
+            hash_max_entries: 128,
+            hash_max_value: 64,
+            set_max_entries: 128,
+            set_max_value: 64,
+            list_max_size: -2,
+        }
+    }
+}
+
+// A compact hash, list or set: its entries one after another, each
+// prefixed with its length, a hash's fields and values alternating
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listpack {
+    kind: ValueType,
+    entries: usize,
+    data: String,
+}
+
+// An entry and the bytes its record takes
+type Record<'a> = (Range<usize>, &'a str);
+
+fn record(entry: &str) -> String {
+    format!("{}:{}", entry.len(), entry)
+}
+
+// Entries of a listpack in order
+pub struct Entries<'a>(&'a str);
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let (len, rest) = self.0.split_once(':')?;
+        let len = len.parse::<usize>().unwrap_or(0).min(rest.len());
+        let (entry, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some(entry)
+    }
+}
+
+impl Listpack {
+    fn pack<'a>(kind: ValueType, entries: impl Iterator<Item = &'a str>) -> Self {
+        let mut packed = Self {
+            kind,
+            entries: 0,
+            data: String::new(),
+        };
+        for entry in entries {
+            packed.push(entry);
+        }
+        packed
+    }
+
+    fn push(&mut self, entry: &str) {
+        self.data.push_str(&record(entry));
+        self.entries += 1;
+    }
+
+    // Each entry with the bytes its record takes, length prefix included
+    fn records(&self) -> impl Iterator<Item = Record<'_>> {
+        let mut at = 0;
+        std::iter::from_fn(move || {
+            let (len, rest) = self.data[at..].split_once(':')?;
+            let len_end = at + len.len() + 1;
+            let end = len_end + len.parse::<usize>().unwrap_or(0).min(rest.len());
+            let record = at..end;
+            at = end;
+            Some((record, &self.data[len_end..end]))
+        })
+    }
+
+    // Records of a hash's fields and their values
+    fn pairs(&self) -> impl Iterator<Item = (Record<'_>, Record<'_>)> {
+        let mut records = self.records();
+        std::iter::from_fn(move || Some((records.next()?, records.next()?)))
+    }
+
+    // The value of a hash's field
+    pub fn field(&self, field: &str) -> Option<&str> {
+        self.pairs()
+            .find(|((_, f), _)| *f == field)
+            .map(|(_, (_, value))| value)
+    }
+
+    // The field and value at position i of a hash
+    pub fn pair(&self, i: usize) -> Option<(&str, &str)> {
+        self.pairs()
+            .nth(i)
+            .map(|((_, field), (_, value))| (field, value))
+    }
+
+    // Sets a hash's field, true when it is new
+    pub fn set_field(&mut self, field: &str, value: &str) -> bool {
+        let old = self
+            .pairs()
+            .find(|((_, f), _)| *f == field)
+            .map(|(_, (old, _))| old);
+        match old {
+            Some(old) => {
+                self.data.replace_range(old, &record(value));
+                false
+            }
+            None => {
+                self.push(field);
+                self.push(value);
+                true
+            }
+        }
+    }
+
+    // Whether a set has member, or a list the element
+    pub fn contains(&self, member: &str) -> bool {
+        self.iter().any(|entry| entry == member)
+    }
+
+    // Adds a member to a set, true when it is new
+    pub fn insert(&mut self, member: &str) -> bool {
+        if self.contains(member) {
+            return false;
+        }
+        self.push(member);
+        true
+    }
+
+    // Removes a member from a set, true when it was there
+    pub fn remove(&mut self, member: &str) -> bool {
+        let record = self
+            .records()
+            .find(|(_, entry)| *entry == member)
+            .map(|(record, _)| record);
+        match record {
+            Some(record) => {
+                self.data.replace_range(record, "");
+                self.entries -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn push_front(&mut self, element: &str) {
+        self.data.insert_str(0, &record(element));
+        self.entries += 1;
+    }
+
+    pub fn push_back(&mut self, element: &str) {
+        self.push(element);
+    }
+
+    // Whether it is still within the limits for its type
+    pub fn fits(&self, limits: &ListpackLimits) -> bool {
+        fits(self.kind, self.len(), self.iter(), limits)
+    }
+
+    // Hash, List or Set
+    pub fn value_type(&self) -> ValueType {
+        self.kind
+    }
+
+    // Elements as the type counts them: fields of a hash, members of a set
+    pub fn len(&self) -> usize {
+        match self.kind {
+            ValueType::Hash => self.entries / 2,
+            _ => self.entries,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    // Bytes the entries take
+    pub fn bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn iter(&self) -> Entries<'_> {
+        Entries(&self.data)
+    }
+
+    // The full form
+    pub fn expand(&self) -> Value {
+        match self.kind {
+            ValueType::Hash => {
+                let mut pairs = IndexMap::with_capacity(self.len());
+                let mut entries = self.iter();
+                while let (Some(field), Some(value)) = (entries.next(), entries.next()) {
+                    pairs.insert(field.to_string(), value.to_string());
+                }
+                Value::Hash(pairs)
+            }
+            ValueType::List => Value::List(self.iter().map(str::to_string).collect()),
+            _ => Value::Set(self.iter().map(str::to_string).collect()),
+        }
+    }
+}
+
+pub fn is_compact(value: &Value) -> bool {
+    matches!(value, Value::Listpack(_))
+}
+
+// Full form of a compact value, None for anything else
+pub fn expand(value: &Value) -> Option<Value> {
+    match value {
+        Value::Listpack(packed) => Some(packed.expand()),
+        _ => None,
+    }
+}
+
+// Whether a collection of kind with len elements and these entries fits the
+// limits for a compact one
+fn fits<'a>(
+    kind: ValueType,
+    len: usize,
+    mut entries: impl Iterator<Item = &'a str>,
+    limits: &ListpackLimits,
+) -> bool {
+    match kind {
+        ValueType::Hash => {
+            len <= limits.hash_max_entries && entries.all(|e| e.len() <= limits.hash_max_value)
+        }
+        ValueType::Set => {
+            len <= limits.set_max_entries && entries.all(|e| e.len() <= limits.set_max_value)
+        }
+        _ => match limits.list_max_size {
+            size if size > 0 => len <= size as usize,
+            size => {
+                let max_bytes = 4096 << ((-size).clamp(1, 5) - 1);
+                entries.map(|e| e.len() + 4).sum::<usize>() <= max_bytes
+            }
+        },
+    }
+}
+
+// Compact form of a hash, list or set that fits the limits
+pub fn compact(value: &Value, limits: &ListpackLimits) -> Option<Value> {
+    let packed = match value {
+        Value::Hash(pairs) => {
+            let entries = pairs
+                .iter()
+                .flat_map(|(field, value)| [field.as_str(), value.as_str()]);
+            fits(ValueType::Hash, pairs.len(), entries.clone(), limits)
+                .then(|| Listpack::pack(ValueType::Hash, entries))
+        }
+        Value::Set(items) => {
+            let entries = items.iter().map(String::as_str);
+            fits(ValueType::Set, items.len(), entries.clone(), limits)
+                .then(|| Listpack::pack(ValueType::Set, entries))
+        }
+        Value::List(items) => {
+            let entries = items.iter().map(String::as_str);
+            fits(ValueType::List, items.len(), entries.clone(), limits)
+                .then(|| Listpack::pack(ValueType::List, entries))
+        }
+        _ => None,
+    };
+    packed.map(Value::Listpack)
+}
+
+// OBJECT ENCODING name of a stored value
+pub fn encoding(value: &Value) -> &'static str {
+    match value {
+        Value::Str(s) if std::str::from_utf8(s).is_ok_and(|s| s.parse::<i64>().is_ok()) => "int",
+        Value::Str(s) if s.len() <= 44 => "embstr",
+        Value::Listpack(_) => "listpack",
+        Value::Compressed(_) => "compressed",
+        Value::Hash(_) | Value::Set(_) => "hashtable",
+        Value::List(_) => "quicklist",
+        // An ordered index plus a member map, in any size, as Redis'
+        // skiplist encoding is
+        Value::ZSet(_) => "skiplist",
+        Value::Stream(_) => "stream",
+        _ => "raw",
+    }
+}
+
+// A read's view of a stored value, in the full form
+pub fn expanded(value: Option<Arc<Value>>) -> Option<Arc<Value>> {
+    match value.as_deref().and_then(expand) {
+        Some(full) => Some(Arc::new(full)),
+        None => value,
+    }
+}
+
+type Edit<R> = (Update<Value>, R);
+
+// Packs what an edit stored, when it fits. A value in the full form is only
+// packed if it was created by the edit.
+fn repack<R>(
+    value: Option<&mut Value>,
+    was_compact: bool,
+    (update, result): Edit<R>,
+    limits: &ListpackLimits,
+) -> Edit<R> {
+    let update = match update {
+        Update::Keep => {
+            if let Some(value) = value.filter(|_| was_compact) {
+                if let Some(packed) = compact(value, limits) {
+                    *value = packed;
+                }
+            }
+            Update::Keep
+        }
+        Update::Set(new) => Update::Set(compact(&new, limits).unwrap_or(new)),
+        Update::Delete => Update::Delete,
+    };
+    (update, result)
+}
+
+// Runs an update closure on the full form of value, for DB::update
+pub fn edit<R, F>(value: Option<&mut Value>, limits: &ListpackLimits, f: F) -> Edit<R>
+where
+    F: FnOnce(Option<&mut Value>) -> Edit<R>,
+{
+    match value {
+        Some(value) => {
+            let was_compact = match expand(value) {
+                Some(full) => {
+                    *value = full;
+                    true
+                }
+                None => false,
+            };
+            let edited = f(Some(&mut *value));
+            repack(Some(value), was_compact, edited, limits)
+        }
+        None => {
+            let edited = f(None);
+            repack(None, true, edited, limits)
+        }
+    }
+}
+
+// Like edit, but a compact value is handed to f as it is, for edits that
+// work on the compact form in place. If the edit takes it past the limits
+// it is converted to the full form for good.
+pub fn edit_in_place<R, F>(value: Option<&mut Value>, limits: &ListpackLimits, f: F) -> Edit<R>
+where
+    F: FnOnce(Option<&mut Value>) -> Edit<R>,
+{
+    let Some(value) = value else {
+        return edit(None, limits, f);
+    };
+    let (update, result) = f(Some(&mut *value));
+    let update = match update {
+        Update::Keep => {
+            if let Value::Listpack(packed) = value {
+                if !packed.fits(limits) {
+                    *value = packed.expand();
+                }
+            }
+            Update::Keep
+        }
+        Update::Set(new) => Update::Set(compact(&new, limits).unwrap_or(new)),
+        Update::Delete => Update::Delete,
+    };
+    (update, result)
+}
+
+// edit for the two values of DB::update_pair
+pub fn edit_pair<R, F>(
+    first: Option<&mut Value>,
+    second: Option<&mut Value>,
+    limits: &ListpackLimits,
+    f: F,
+) -> (Update<Value>, Update<Value>, R)
+where
+    F: FnOnce(Option<&mut Value>, Option<&mut Value>) -> (Update<Value>, Update<Value>, R),
+{
+    let mut second_update = Update::Keep;
+    let (first_update, result) = edit(first, limits, |first| {
+        let (update, (first_update, result)) = edit(second, limits, |second| {
+            let (first_update, second_update, result) = f(first, second);
+            (second_update, (first_update, result))
+        });
+        second_update = update;
+        (first_update, result)
+    });
+    (first_update, second_update, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn pair(f: &str, v: &str) -> (String, String) {
+        (f.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let limits = ListpackLimits::default();
+        for full in [
+            Value::List(strings(&["a", "", "1:2:3", "ünï"]).into()),
+            Value::Set(strings(&["x", "y"]).into_iter().collect()),
+            Value::Hash([pair("f", "v"), pair("g", "")].into()),
+        ] {
+            let packed = compact(&full, &limits).unwrap();
+            assert!(is_compact(&packed));
+            assert_eq!(encoding(&packed), "listpack");
+            assert_eq!(expand(&packed), Some(full));
+        }
+        assert_eq!(expand(&Value::str("1:a")), None);
+        assert_eq!(compact(&Value::str("a"), &limits), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = ListpackLimits {
+            hash_max_entries: 1,
+            hash_max_value: 3,
+            set_max_entries: 2,
+            set_max_value: 3,
+            list_max_size: 2,
+        };
+        assert!(compact(&Value::Hash([pair("f", "v")].into()), &limits).is_some());
+        assert!(compact(&Value::Hash([pair("f", "long")].into()), &limits).is_none());
+        let two = [pair("f", "v"), pair("g", "v")].into();
+        assert!(compact(&Value::Hash(two), &limits).is_none());
+        let three = strings(&["a", "b", "c"]);
+        assert!(compact(&Value::Set(three.iter().cloned().collect()), &limits).is_none());
+        assert!(compact(&Value::List(three.into()), &limits).is_none());
+
+        // Negative list sizes count bytes: -1 is 4 KB
+        let limits = ListpackLimits {
+            list_max_size: -1,
+            ..limits
+        };
+        let big = Value::List(vec!["x".repeat(5000)].into());
+        assert!(compact(&big, &limits).is_none());
+    }
+
+    #[test]
+    fn test_edit_converts_once() {
+        let limits = ListpackLimits {
+            list_max_size: 2,
+            ..Default::default()
+        };
+        let push = |value: Option<&mut Value>, item: &str| {
+            edit(value, &limits, |value| match value {
+                Some(Value::List(items)) => {
+                    items.push_back(item.to_string());
+                    (Update::Keep, ())
+                }
+                _ => (Update::Set(Value::List(vec![item.to_string()].into())), ()),
+            })
+        };
+
+        let (Update::Set(mut value), ()) = push(None, "a") else {
+            panic!("expected a new list");
+        };
+        assert!(is_compact(&value));
+        push(Some(&mut value), "b");
+        assert!(is_compact(&value));
+        push(Some(&mut value), "c");
+        assert_eq!(encoding(&value), "quicklist");
+
+        // Shrinking back under the limit keeps the full form
+        edit(Some(&mut value), &limits, |value| {
+            if let Some(Value::List(items)) = value {
+                items.keep(0, 1);
+            }
+            (Update::Keep, ())
+        });
+        assert_eq!(value, Value::List(strings(&["a"]).into()));
+    }
+
+    #[test]
+    fn test_edit_in_place() {
+        let limits = ListpackLimits {
+            hash_max_entries: 2,
+            ..Default::default()
+        };
+        let Some(Value::Listpack(mut hash)) =
+            compact(&Value::Hash([pair("f", "v")].into()), &limits)
+        else {
+            panic!("expected a compact hash");
+        };
+        assert!(!hash.set_field("f", "longer"));
+        assert!(hash.set_field("g", ""));
+        assert_eq!(hash.field("f"), Some("longer"));
+        assert_eq!(hash.pair(1), Some(("g", "")));
+        assert_eq!(hash.len(), 2);
+        assert_eq!(
+            hash.expand(),
+            Value::Hash([pair("f", "longer"), pair("g", "")].into())
+        );
+
+        let Some(Value::Listpack(mut set)) = compact(
+            &Value::Set(strings(&["a", "b"]).into_iter().collect()),
+            &limits,
+        ) else {
+            panic!("expected a compact set");
+        };
+        assert!(!set.insert("a"));
+        assert!(set.insert("c"));
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), ["b", "c"]);
+
+        let mut list = Listpack::pack(ValueType::List, ["b"].into_iter());
+        list.push_front("a");
+        list.push_back("c");
+        assert_eq!(list.iter().collect::<Vec<_>>(), ["a", "b", "c"]);
+
+        // Edited in place while it fits, converted once it doesn't
+        let mut value = Value::Listpack(hash);
+        let set_field = |value: Option<&mut Value>, field: &str| {
+            edit_in_place(value, &limits, |value| match value {
+                Some(Value::Listpack(packed)) => (Update::Keep, packed.set_field(field, "v")),
+                Some(Value::Hash(pairs)) => (
+                    Update::Keep,
+                    pairs.insert(field.to_string(), "v".into()).is_none(),
+                ),
+                _ => (Update::Keep, false),
+            })
+        };
+        assert!(!set_field(Some(&mut value), "g").1);
+        assert!(is_compact(&value));
+        assert!(set_field(Some(&mut value), "h").1);
+        assert_eq!(encoding(&value), "hashtable");
+        assert!(set_field(Some(&mut value), "i").1);
+        assert_eq!(encoding(&value), "hashtable");
+    }
+}
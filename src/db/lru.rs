@@ -1,57 +1,385 @@
 use std::collections::HashMap;
-use std::collections::VecDeque;
+use std::fmt;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 
 // LRU Cache entry
 struct Entry<V> {
     value: Arc<V>,
+    // Past this the entry is treated as absent and dropped on the next touch
     expiry: Option<Instant>,
 }
-// LRU Cache implementation
+
+impl<V> Entry<V> {
+    fn expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|at| at <= now)
+    }
+}
+
+// Slot of the intrusive recency list. Nodes live in a slab and link to each
+// other by index, so moving an entry to the front is O(1).
+struct Node<K, V> {
+    key: K,
+    entry: Entry<V>,
+    prev: usize,
+    next: usize,
+}
+
+const NIL: usize = usize::MAX;
+
+struct Inner<K, V> {
+    map: HashMap<K, usize>,
+    slots: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    // Most recently used
+    head: usize,
+    // Least recently used, evicted first
+    tail: usize,
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn node(&mut self, index: usize) -> &mut Node<K, V> {
+        self.slots[index].as_mut().expect("linked slot is occupied")
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (prev, next) = {
+            let node = self.node(index);
+            (node.prev, node.next)
+        };
+        match prev {
+            NIL => self.head = next,
+            prev => self.node(prev).next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.node(next).prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, index: usize) {
+        let head = self.head;
+        {
+            let node = self.node(index);
+            node.prev = NIL;
+            node.next = head;
+        }
+        match head {
+            NIL => self.tail = index,
+            head => self.node(head).prev = index,
+        }
+        self.head = index;
+    }
+
+    fn insert(&mut self, key: K, entry: Entry<V>) {
+        let node = Node {
+            key: key.clone(),
+            entry,
+            prev: NIL,
+            next: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index] = Some(node);
+                index
+            }
+            None => {
+                self.slots.push(Some(node));
+                self.slots.len() - 1
+            }
+        };
+        self.map.insert(key, index);
+        self.push_front(index);
+    }
+
+    fn remove_at(&mut self, index: usize) -> Entry<V> {
+        self.unlink(index);
+        let node = self.slots[index].take().expect("linked slot is occupied");
+        self.free.push(index);
+        self.map.remove(&node.key);
+        node.entry
+    }
+}
+
+// How DB keeps its cache in step with the storage. Reads go through the
+// cache under every policy but None; the policies differ in what a write
+// leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CachePolicy {
+    // The cache is not used, every read goes to the storage
+    #[default]
+    None,
+    // Whole values written by SET and friends are cached right away, in place
+    // edits drop the cached copy
+    WriteThrough,
+    // Writes only drop the cached copy, the next read fetches it again
+    WriteAround,
+    // Like WriteAround, but a cached value is trusted for at most `ttl`
+    ReadThrough {
+        ttl: Duration,
+    },
+}
+
+const DEFAULT_READ_THROUGH_TTL: Duration = Duration::from_secs(60);
+
+impl CachePolicy {
+    pub fn writes_through(&self) -> bool {
+        matches!(self, Self::WriteThrough)
+    }
+
+    // How long a value read from the storage stays cached
+    pub fn ttl(&self) -> Option<Duration> {
+        match self {
+            Self::ReadThrough { ttl } => Some(*ttl),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    // read-through takes an optional TTL in seconds: read-through:30
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let (name, ttl) = match s.split_once(':') {
+            Some((name, ttl)) => (name, Some(ttl)),
+            None => (s.as_str(), None),
+        };
+        match (name, ttl) {
+            ("none", None) => Ok(Self::None),
+            ("write-through", None) => Ok(Self::WriteThrough),
+            ("write-around", None) => Ok(Self::WriteAround),
+            ("read-through", None) => Ok(Self::ReadThrough {
+                ttl: DEFAULT_READ_THROUGH_TTL,
+            }),
+            ("read-through", Some(ttl)) => match ttl.parse::<u64>() {
+                Ok(secs) if secs > 0 => Ok(Self::ReadThrough {
+                    ttl: Duration::from_secs(secs),
+                }),
+                _ => Err(format!("invalid cache ttl '{}'", ttl)),
+            },
+            _ => Err(format!("invalid cache policy '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::WriteThrough => write!(f, "write-through"),
+            Self::WriteAround => write!(f, "write-around"),
+            Self::ReadThrough { ttl } => write!(f, "read-through:{}", ttl.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// LRU Cache implementation, shared behind the DB so a background task can
+// purge it while connections use it
 pub struct LruCache<K, V> {
-    map: HashMap<K, (V, Instant)>,
-    queue: VecDeque<K>,
+    inner: Mutex<Inner<K, V>>,
     capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl<K, V> LruCache<K, V>
 where
     K: Hash + Eq + Clone,
-    V: Clone,
 {
     pub fn new(capacity: usize) -> Self {
         Self {
-            map: HashMap::with_capacity(capacity),
-            queue: VecDeque::with_capacity(capacity),
+            inner: Mutex::new(Inner {
+                map: HashMap::with_capacity(capacity),
+                slots: Vec::with_capacity(capacity),
+                free: vec![],
+                head: NIL,
+                tail: NIL,
+            }),
             capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
         }
     }
 
-    pub fn get(&mut self, key: &K) -> Option<V> {
-        if let Some((value, _)) = self.map.get(key) {
-            // Move to front of queue
-            if let Some(index) = self.queue.iter().position(|x| x == key) {
-                self.queue.remove(index);
-                self.queue.push_front(key.clone());
+    fn inner(&self) -> MutexGuard<'_, Inner<K, V>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let mut inner = self.inner();
+        let found = match inner.map.get(key).copied() {
+            Some(index) if inner.node(index).entry.expired(Instant::now()) => {
+                inner.remove_at(index);
+                None
             }
-            Some(value.clone())
+            Some(index) => {
+                // Move to front of the list
+                inner.unlink(index);
+                inner.push_front(index);
+                Some(inner.node(index).entry.value.clone())
+            }
+            None => None,
+        };
+        let counter = if found.is_some() {
+            &self.hits
         } else {
-            None
-        }
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
     }
 
-    pub fn put(&mut self, key: K, value: V) {
+    // `ttl` of None keeps the entry until it is evicted or removed
+    pub fn put(&self, key: K, value: impl Into<Arc<V>>, ttl: Option<Duration>) {
+        let entry = Entry {
+            value: value.into(),
+            expiry: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        let mut inner = self.inner();
+        if let Some(index) = inner.map.get(&key).copied() {
+            inner.node(index).entry = entry;
+            inner.unlink(index);
+            inner.push_front(index);
+            return;
+        }
+        if self.capacity == 0 {
+            return;
+        }
         // Remove oldest if at capacity
-        if self.map.len() >= self.capacity {
-            if let Some(old_key) = self.queue.pop_back() {
-                self.map.remove(&old_key);
-            }
+        if inner.map.len() >= self.capacity && inner.tail != NIL {
+            let tail = inner.tail;
+            inner.remove_at(tail);
+        }
+        inner.insert(key, entry);
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Arc<V>> {
+        let mut inner = self.inner();
+        let index = inner.map.get(key).copied()?;
+        Some(inner.remove_at(index).value)
+    }
+
+    // Drops every expired entry, returns how many went
+    pub fn purge(&self) -> usize {
+        let now = Instant::now();
+        let mut inner = self.inner();
+        let expired: Vec<usize> = inner
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                slot.as_ref()
+                    .filter(|node| node.entry.expired(now))
+                    .map(|_| index)
+            })
+            .collect();
+        for &index in &expired {
+            inner.remove_at(index);
         }
+        expired.len()
+    }
 
-        // Insert new entry
-        self.map.insert(key.clone(), (value, Instant::now()));
-        self.queue.push_front(key);
+    pub fn clear(&self) {
+        let mut inner = self.inner();
+        inner.map.clear();
+        inner.slots.clear();
+        inner.free.clear();
+        inner.head = NIL;
+        inner.tail = NIL;
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_and_purge() {
+        let cache = LruCache::new(2);
+        cache.put("a", 1, None);
+        cache.put("b", 2, Some(Duration::ZERO));
+        assert_eq!(cache.get(&"a").as_deref(), Some(&1));
+        // Expired entries read as absent and are dropped
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.len(), 1);
+
+        // At capacity the least recently used entry goes, here "a"
+        cache.put("c", 3, Some(Duration::ZERO));
+        cache.put("d", 4, Some(Duration::from_secs(60)));
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2 });
+
+        assert_eq!(cache.purge(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.remove(&"d").as_deref(), Some(&4));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_recency_order() {
+        let cache = LruCache::new(3);
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            cache.put(key, value, None);
+        }
+        // Touching "a" and rewriting "b" leaves "c" as the oldest
+        cache.get(&"a");
+        cache.put("b", 20, None);
+        cache.put("d", 4, None);
+        assert_eq!(cache.get(&"c"), None);
+        assert_eq!(cache.get(&"b").as_deref(), Some(&20));
+
+        // Freed slots are reused
+        cache.remove(&"a");
+        cache.put("e", 5, None);
+        cache.put("f", 6, None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&"d"), None);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        cache.put("g", 7, None);
+        assert_eq!(cache.get(&"g").as_deref(), Some(&7));
+    }
+
+    #[test]
+    fn test_cache_policy_names() {
+        for name in ["none", "write-through", "write-around", "read-through:30"] {
+            assert_eq!(name.parse::<CachePolicy>().unwrap().to_string(), name);
+        }
+        assert_eq!(
+            "READ-THROUGH".parse::<CachePolicy>().unwrap().ttl(),
+            Some(DEFAULT_READ_THROUGH_TTL)
+        );
+        assert!("read-through:0".parse::<CachePolicy>().is_err());
+        assert!("write-through:5".parse::<CachePolicy>().is_err());
     }
 }
@@ -1,3 +1,25 @@
+pub mod backend;
+pub mod bytestring;
+pub mod clock;
+pub mod compression;
+#[allow(clippy::module_inception)]
 pub mod db;
-mod lru;
+#[cfg(feature = "disk")]
+pub mod disk;
+pub mod dump;
+pub mod eviction;
+pub mod expire;
+pub mod hotkeys;
+pub mod lazyfree;
+pub mod listpack;
+pub mod lru;
+pub mod quicklist;
+pub mod rdb;
+pub mod readview;
+pub mod sortedset;
 pub mod storage;
+#[cfg(feature = "disk")]
+pub mod tiered;
+pub mod ttlheap;
+pub mod types;
+pub mod value;
@@ -0,0 +1,377 @@
+// The elements of a list too big to pack, like Redis' quicklist: a deque of
+// segments of up to SEGMENT elements each. Pushes and pops at either end are
+// O(1), reaching an index walks segments rather than elements, and an edit
+// in the middle only shifts the elements of one segment. Segments emptied
+// by removals are dropped and underfull neighbours merged, so a list never
+// holds much more than one segment's bookkeeping per SEGMENT elements.
+use std::collections::{vec_deque, VecDeque};
+use std::fmt;
+use std::iter::Flatten;
+
+// Elements per segment
+pub const SEGMENT: usize = 128;
+
+pub type Iter<'a> = Flatten<vec_deque::Iter<'a, VecDeque<String>>>;
+
+#[derive(Clone, Default)]
+pub struct QuickList {
+    segments: VecDeque<VecDeque<String>>,
+    len: usize,
+}
+
+impl QuickList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_front(&mut self, element: String) {
+        match self.segments.front_mut() {
+            Some(segment) if segment.len() < SEGMENT => segment.push_front(element),
+            _ => {
+                let mut segment = VecDeque::with_capacity(SEGMENT);
+                segment.push_back(element);
+                self.segments.push_front(segment);
+            }
+        }
+        self.len += 1;
+    }
+
+    pub fn push_back(&mut self, element: String) {
+        match self.segments.back_mut() {
+            Some(segment) if segment.len() < SEGMENT => segment.push_back(element),
+            _ => {
+                let mut segment = VecDeque::with_capacity(SEGMENT);
+                segment.push_back(element);
+                self.segments.push_back(segment);
+            }
+        }
+        self.len += 1;
+    }
+
+    pub fn pop_front(&mut self) -> Option<String> {
+        let segment = self.segments.front_mut()?;
+        let element = segment.pop_front();
+        if segment.is_empty() {
+            self.segments.pop_front();
+        }
+        self.len -= 1;
+        element
+    }
+
+    pub fn pop_back(&mut self) -> Option<String> {
+        let segment = self.segments.back_mut()?;
+        let element = segment.pop_back();
+        if segment.is_empty() {
+            self.segments.pop_back();
+        }
+        self.len -= 1;
+        element
+    }
+
+    // Segment and offset in it of the element at index, walking from the
+    // nearer end
+    fn locate(&self, index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.len / 2 {
+            let mut offset = index;
+            for (i, segment) in self.segments.iter().enumerate() {
+                if offset < segment.len() {
+                    return Some((i, offset));
+                }
+                offset -= segment.len();
+            }
+        } else {
+            let mut from_back = self.len - 1 - index;
+            for (i, segment) in self.segments.iter().enumerate().rev() {
+                if from_back < segment.len() {
+                    return Some((i, segment.len() - 1 - from_back));
+                }
+                from_back -= segment.len();
+            }
+        }
+        None
+    }
+
+    pub fn get(&self, index: usize) -> Option<&String> {
+        let (segment, offset) = self.locate(index)?;
+        self.segments[segment].get(offset)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut String> {
+        let (segment, offset) = self.locate(index)?;
+        self.segments[segment].get_mut(offset)
+    }
+
+    // Puts element at index, shifting what follows; index may be len
+    pub fn insert(&mut self, index: usize, element: String) {
+        assert!(index <= self.len, "insert index out of range");
+        if index == 0 {
+            return self.push_front(element);
+        }
+        if index == self.len {
+            return self.push_back(element);
+        }
+        let (mut segment, mut offset) = self.locate(index).expect("index in range");
+        // A full segment is split in two first
+        if self.segments[segment].len() >= SEGMENT {
+            let half = self.segments[segment].len() / 2;
+            let tail = self.segments[segment].split_off(half);
+            self.segments.insert(segment + 1, tail);
+            if offset >= half {
+                segment += 1;
+                offset -= half;
+            }
+        }
+        self.segments[segment].insert(offset, element);
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<String> {
+        let (segment, offset) = self.locate(index)?;
+        let element = self.segments[segment].remove(offset);
+        self.len -= 1;
+        self.settle(segment);
+        element
+    }
+
+    // Drops segment if it was emptied, or merges it into a neighbour when
+    // both fit in one
+    fn settle(&mut self, segment: usize) {
+        if self.segments[segment].is_empty() {
+            self.segments.remove(segment);
+            return;
+        }
+        if segment + 1 < self.segments.len()
+            && self.segments[segment].len() + self.segments[segment + 1].len() <= SEGMENT / 2
+        {
+            let next = self.segments.remove(segment + 1).expect("next segment");
+            self.segments[segment].extend(next);
+        }
+    }
+
+    // Keeps the elements f accepts, in order
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&String) -> bool,
+    {
+        for segment in self.segments.iter_mut() {
+            segment.retain(&mut f);
+        }
+        self.rebuild();
+    }
+
+    // Repacks the segments after removals all over the list
+    fn rebuild(&mut self) {
+        let mut segments: VecDeque<VecDeque<String>> = VecDeque::new();
+        for segment in self.segments.drain(..) {
+            match segments.back_mut() {
+                Some(last) if last.len() + segment.len() <= SEGMENT => last.extend(segment),
+                _ if segment.is_empty() => {}
+                _ => segments.push_back(segment),
+            }
+        }
+        self.len = segments.iter().map(VecDeque::len).sum();
+        self.segments = segments;
+    }
+
+    // Keeps the elements in from..to
+    pub fn keep(&mut self, from: usize, to: usize) {
+        let to = to.min(self.len);
+        if from >= to {
+            return self.clear();
+        }
+        let mut back = self.len - to;
+        while back > 0 {
+            let segment = self.segments.back_mut().expect("elements past to");
+            if segment.len() <= back {
+                back -= segment.len();
+                self.segments.pop_back();
+            } else {
+                segment.truncate(segment.len() - back);
+                back = 0;
+            }
+        }
+        let mut front = from;
+        while front > 0 {
+            let segment = self.segments.front_mut().expect("elements before from");
+            if segment.len() <= front {
+                front -= segment.len();
+                self.segments.pop_front();
+            } else {
+                segment.drain(..front);
+                front = 0;
+            }
+        }
+        self.len = to - from;
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.segments.iter().flatten()
+    }
+
+    // The elements from index on, without walking the ones before it
+    pub fn iter_from(&self, index: usize) -> impl Iterator<Item = &String> + '_ {
+        let (segment, offset) = self.locate(index).unwrap_or((self.segments.len(), 0));
+        self.segments.range(segment..).flatten().skip(offset)
+    }
+
+    // Segments the elements are spread over
+    pub fn segments(&self) -> usize {
+        self.segments.len()
+    }
+}
+
+impl PartialEq for QuickList {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for QuickList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl Extend<String> for QuickList {
+    fn extend<I: IntoIterator<Item = String>>(&mut self, iter: I) {
+        for element in iter {
+            self.push_back(element);
+        }
+    }
+}
+
+impl FromIterator<String> for QuickList {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Self {
+        let mut list = Self::new();
+        list.extend(iter);
+        list
+    }
+}
+
+impl From<Vec<String>> for QuickList {
+    fn from(elements: Vec<String>) -> Self {
+        elements.into_iter().collect()
+    }
+}
+
+impl IntoIterator for QuickList {
+    type Item = String;
+    type IntoIter = Flatten<vec_deque::IntoIter<VecDeque<String>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.segments.into_iter().flatten()
+    }
+}
+
+impl<'a> IntoIterator for &'a QuickList {
+    type Item = &'a String;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.segments.iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(n: usize) -> Vec<String> {
+        (0..n).map(|i| i.to_string()).collect()
+    }
+
+    fn check(list: &QuickList, expected: &[String]) {
+        assert_eq!(list.len(), expected.len());
+        assert!(list.iter().eq(expected.iter()));
+        for (i, element) in expected.iter().enumerate() {
+            assert_eq!(list.get(i), Some(element));
+        }
+        assert_eq!(list.get(expected.len()), None);
+        assert!(list.segments.iter().all(|segment| !segment.is_empty()));
+    }
+
+    #[test]
+    fn test_ends() {
+        let mut list = QuickList::new();
+        let mut expected = VecDeque::new();
+        for i in 0..1000 {
+            if i % 3 == 0 {
+                list.push_front(i.to_string());
+                expected.push_front(i.to_string());
+            } else {
+                list.push_back(i.to_string());
+                expected.push_back(i.to_string());
+            }
+        }
+        check(&list, &Vec::from(expected.clone()));
+        assert!(list.segments() <= 1000 / SEGMENT + 2);
+        for _ in 0..400 {
+            assert_eq!(list.pop_front(), expected.pop_front());
+            assert_eq!(list.pop_back(), expected.pop_back());
+        }
+        check(&list, &Vec::from(expected.clone()));
+        while list.pop_back().is_some() {}
+        assert!(list.is_empty());
+        assert_eq!(list.segments(), 0);
+        assert_eq!(list.pop_front(), None);
+    }
+
+    #[test]
+    fn test_middle() {
+        let mut expected = numbers(500);
+        let mut list = QuickList::from(expected.clone());
+        for i in [0, 1, 127, 128, 129, 250, 499, 500] {
+            list.insert(i, format!("x{}", i));
+            expected.insert(i, format!("x{}", i));
+        }
+        check(&list, &expected);
+        *list.get_mut(300).unwrap() = "set".to_string();
+        expected[300] = "set".to_string();
+        for i in [400, 0, 128, 5] {
+            assert_eq!(list.remove(i), Some(expected.remove(i)));
+        }
+        check(&list, &expected);
+        assert_eq!(list.remove(expected.len()), None);
+        assert!(list.iter_from(200).eq(expected[200..].iter()));
+        assert_eq!(list.iter_from(expected.len()).count(), 0);
+
+        list.retain(|element| element.len() != 2);
+        expected.retain(|element| element.len() != 2);
+        check(&list, &expected);
+        assert_eq!(list.segments(), expected.len().div_ceil(SEGMENT));
+    }
+
+    #[test]
+    fn test_keep() {
+        let expected = numbers(1000);
+        for (from, to) in [
+            (0, 1000),
+            (1, 999),
+            (130, 700),
+            (500, 501),
+            (0, 0),
+            (900, 2000),
+        ] {
+            let mut list = QuickList::from(expected.clone());
+            list.keep(from, to);
+            let to = to.min(expected.len());
+            check(&list, &expected[from.min(to)..to]);
+        }
+    }
+}
@@ -0,0 +1,1051 @@
+// Reader for Redis RDB dump files, so data can be brought over from Redis.
+// Strings, lists, sets, sorted sets and hashes are supported in all their
+// on-disk encodings (plain, ziplist, listpack, intset, quicklist); modules,
+// streams and functions are not. Values are converted to db::value::Value,
+// and keys or elements whose bytes are not UTF-8 are skipped since only
+// strings hold bytes here. The writer only uses the plain encodings, which
+// every Redis version loads. Damaged files are found by
+// the CRC64 trailer or where parsing stops; check reports how much of one is
+// intact, and truncate keeps that part.
+use crate::db::bytestring::{self, ByteString};
+use crate::db::db::{now_ms, Databases, Snapshot};
+use crate::db::listpack;
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use std::fmt;
+
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+const OP_SLOT_INFO: u8 = 0xF4;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RdbError {
+    BadHeader,
+    UnexpectedEnd,
+    Corrupt(&'static str),
+    Unsupported(u8),
+    Checksum,
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadHeader => write!(f, "not an RDB file"),
+            Self::UnexpectedEnd => write!(f, "RDB file is truncated"),
+            Self::Corrupt(what) => write!(f, "corrupt RDB file: {}", what),
+            Self::Unsupported(kind) => write!(f, "unsupported RDB type or opcode {}", kind),
+            Self::Checksum => write!(f, "RDB checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for RdbError {}
+
+#[derive(Debug, PartialEq)]
+pub struct RdbEntry {
+    pub db: usize,
+    pub key: String,
+    pub value: Value,
+    // Unix milliseconds
+    pub expire_at: Option<u64>,
+    // Offset in the file just past the entry
+    pub end: usize,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RdbStats {
+    pub loaded: usize,
+    pub expired: usize,
+    // Keys or values that are not UTF-8, or in a db this server lacks
+    pub skipped: usize,
+}
+
+// CRC-64/Jones, reflected, as used for the RDB trailer
+pub fn crc64(bytes: &[u8]) -> u64 {
+    let mut crc = 0u64;
+    for byte in bytes {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95AC9329AC4BC9B5
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn lzf_decompress(input: &[u8], expected: usize) -> Result<Vec<u8>, RdbError> {
+    let corrupt = || RdbError::Corrupt("bad LZF data");
+    // The expected size is untrusted, don't reserve more than the input can expand to
+    let mut out = Vec::with_capacity(expected.min(input.len() * 2));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(corrupt)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1F) << 8) + low + 1;
+            let start = out.len().checked_sub(back).ok_or_else(corrupt)?;
+            // Byte by byte, the reference may overlap what it produces
+            for k in 0..len + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != expected {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+enum Length {
+    Len(u64),
+    Int8,
+    Int16,
+    Int32,
+    Lzf,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(n).ok_or(RdbError::UnexpectedEnd)?;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(RdbError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn le<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn length_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3F) as u64),
+            1 => Length::Len((((first & 0x3F) as u64) << 8) | self.u8()? as u64),
+            2 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.le()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.le()?)),
+                _ => return Err(RdbError::Corrupt("bad length")),
+            },
+            _ => match first & 0x3F {
+                0 => Length::Int8,
+                1 => Length::Int16,
+                2 => Length::Int32,
+                3 => Length::Lzf,
+                _ => return Err(RdbError::Corrupt("bad string encoding")),
+            },
+        })
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_encoding()? {
+            Length::Len(len) => Ok(len),
+            _ => Err(RdbError::Corrupt("expected a length")),
+        }
+    }
+
+    // Counts come from the file, so nothing is reserved up front from them
+    fn count(&mut self) -> Result<usize, RdbError> {
+        let count = self.length()?;
+        usize::try_from(count).map_err(|_| RdbError::Corrupt("count too large"))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        Ok(match self.length_encoding()? {
+            Length::Len(len) => {
+                let len = usize::try_from(len).map_err(|_| RdbError::UnexpectedEnd)?;
+                self.take(len)?.to_vec()
+            }
+            Length::Int8 => (self.u8()? as i8).to_string().into_bytes(),
+            Length::Int16 => i16::from_le_bytes(self.le()?).to_string().into_bytes(),
+            Length::Int32 => i32::from_le_bytes(self.le()?).to_string().into_bytes(),
+            Length::Lzf => {
+                let compressed = self.count()?;
+                let expected = self.count()?;
+                lzf_decompress(self.take(compressed)?, expected)?
+            }
+        })
+    }
+
+    // Score of the original ZSET type, a length prefixed decimal
+    fn text_double(&mut self) -> Result<f64, RdbError> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = self.take(len as usize)?;
+                std::str::from_utf8(text)
+                    .ok()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or(RdbError::Corrupt("bad score"))
+            }
+        }
+    }
+}
+
+// Element of a ziplist or listpack: a string or an integer
+enum Packed {
+    Str(Vec<u8>),
+    Int(i64),
+}
+
+impl Packed {
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Packed::Str(s) => s,
+            Packed::Int(i) => i.to_string().into_bytes(),
+        }
+    }
+}
+
+fn ziplist(blob: &[u8]) -> Result<Vec<Packed>, RdbError> {
+    let mut r = Reader::new(blob);
+    r.take(10)?; // zlbytes, zltail, zllen
+    let mut items = vec![];
+    loop {
+        let prevlen = r.u8()?;
+        if prevlen == 0xFF {
+            return Ok(items);
+        }
+        if prevlen == 0xFE {
+            r.take(4)?;
+        }
+        let enc = r.u8()?;
+        let item = match enc >> 6 {
+            0 => Packed::Str(r.take((enc & 0x3F) as usize)?.to_vec()),
+            1 => {
+                let len = (((enc & 0x3F) as usize) << 8) | r.u8()? as usize;
+                Packed::Str(r.take(len)?.to_vec())
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.le()?) as usize;
+                Packed::Str(r.take(len)?.to_vec())
+            }
+            _ => Packed::Int(match enc {
+                0xC0 => i16::from_le_bytes(r.le()?) as i64,
+                0xD0 => i32::from_le_bytes(r.le()?) as i64,
+                0xE0 => i64::from_le_bytes(r.le()?),
+                0xF0 => {
+                    let b: [u8; 3] = r.le()?;
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                }
+                0xFE => r.u8()? as i8 as i64,
+                0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                _ => return Err(RdbError::Corrupt("bad ziplist entry")),
+            }),
+        };
+        items.push(item);
+    }
+}
+
+fn listpack(blob: &[u8]) -> Result<Vec<Packed>, RdbError> {
+    let mut r = Reader::new(blob);
+    r.take(6)?; // total bytes, element count
+    let mut items = vec![];
+    loop {
+        let start = r.pos;
+        let enc = r.u8()?;
+        if enc == 0xFF {
+            return Ok(items);
+        }
+        let item = if enc & 0x80 == 0 {
+            Packed::Int((enc & 0x7F) as i64)
+        } else if enc & 0xC0 == 0x80 {
+            Packed::Str(r.take((enc & 0x3F) as usize)?.to_vec())
+        } else if enc & 0xE0 == 0xC0 {
+            let v = (((enc & 0x1F) as i64) << 8) | r.u8()? as i64;
+            Packed::Int(if v >= 1 << 12 { v - (1 << 13) } else { v })
+        } else if enc & 0xF0 == 0xE0 {
+            let len = (((enc & 0x0F) as usize) << 8) | r.u8()? as usize;
+            Packed::Str(r.take(len)?.to_vec())
+        } else {
+            match enc {
+                0xF0 => {
+                    let len = u32::from_le_bytes(r.le()?) as usize;
+                    Packed::Str(r.take(len)?.to_vec())
+                }
+                0xF1 => Packed::Int(i16::from_le_bytes(r.le()?) as i64),
+                0xF2 => {
+                    let b: [u8; 3] = r.le()?;
+                    Packed::Int((i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64)
+                }
+                0xF3 => Packed::Int(i32::from_le_bytes(r.le()?) as i64),
+                0xF4 => Packed::Int(i64::from_le_bytes(r.le()?)),
+                _ => return Err(RdbError::Corrupt("bad listpack entry")),
+            }
+        };
+        // Skip the back-length, sized after the entry it follows
+        let entry_len = r.pos - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        r.take(backlen)?;
+        items.push(item);
+    }
+}
+
+fn intset(blob: &[u8]) -> Result<Vec<Packed>, RdbError> {
+    let mut r = Reader::new(blob);
+    let width = u32::from_le_bytes(r.le()?);
+    let len = u32::from_le_bytes(r.le()?);
+    (0..len)
+        .map(|_| {
+            Ok(Packed::Int(match width {
+                2 => i16::from_le_bytes(r.le()?) as i64,
+                4 => i32::from_le_bytes(r.le()?) as i64,
+                8 => i64::from_le_bytes(r.le()?),
+                _ => return Err(RdbError::Corrupt("bad intset encoding")),
+            }))
+        })
+        .collect()
+}
+
+// Decoded value before the UTF-8 check
+enum Raw {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    ZSet(Vec<(Vec<u8>, f64)>),
+    Hash(Pairs),
+}
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn pairs(items: Vec<Packed>) -> Result<Pairs, RdbError> {
+    if !items.len().is_multiple_of(2) {
+        return Err(RdbError::Corrupt("odd number of hash entries"));
+    }
+    let mut items = items.into_iter().map(Packed::into_bytes);
+    let mut out = vec![];
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        out.push((field, value));
+    }
+    Ok(out)
+}
+
+fn scored(items: Vec<Packed>) -> Result<Vec<(Vec<u8>, f64)>, RdbError> {
+    pairs(items)?
+        .into_iter()
+        .map(|(member, score)| {
+            let score = std::str::from_utf8(&score)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(RdbError::Corrupt("bad score"))?;
+            Ok((member, score))
+        })
+        .collect()
+}
+
+fn bytes(items: Vec<Packed>) -> Vec<Vec<u8>> {
+    items.into_iter().map(Packed::into_bytes).collect()
+}
+
+fn read_value(r: &mut Reader, kind: u8) -> Result<Raw, RdbError> {
+    Ok(match kind {
+        TYPE_STRING => Raw::String(r.string()?),
+        TYPE_LIST | TYPE_SET => {
+            let count = r.count()?;
+            let items = (0..count).map(|_| r.string()).collect::<Result<_, _>>()?;
+            if kind == TYPE_SET {
+                Raw::Set(items)
+            } else {
+                Raw::List(items)
+            }
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let count = r.count()?;
+            let mut items = vec![];
+            for _ in 0..count {
+                let member = r.string()?;
+                let score = if kind == TYPE_ZSET_2 {
+                    f64::from_le_bytes(r.le()?)
+                } else {
+                    r.text_double()?
+                };
+                items.push((member, score));
+            }
+            Raw::ZSet(items)
+        }
+        TYPE_HASH => {
+            let count = r.count()?;
+            let items = (0..count)
+                .map(|_| Ok((r.string()?, r.string()?)))
+                .collect::<Result<_, RdbError>>()?;
+            Raw::Hash(items)
+        }
+        TYPE_LIST_ZIPLIST => Raw::List(bytes(ziplist(&r.string()?)?)),
+        TYPE_SET_INTSET => Raw::Set(bytes(intset(&r.string()?)?)),
+        TYPE_SET_LISTPACK => Raw::Set(bytes(listpack(&r.string()?)?)),
+        TYPE_ZSET_ZIPLIST => Raw::ZSet(scored(ziplist(&r.string()?)?)?),
+        TYPE_ZSET_LISTPACK => Raw::ZSet(scored(listpack(&r.string()?)?)?),
+        TYPE_HASH_ZIPLIST => Raw::Hash(pairs(ziplist(&r.string()?)?)?),
+        TYPE_HASH_LISTPACK => Raw::Hash(pairs(listpack(&r.string()?)?)?),
+        TYPE_LIST_QUICKLIST => {
+            let count = r.count()?;
+            let mut items = vec![];
+            for _ in 0..count {
+                items.extend(bytes(ziplist(&r.string()?)?));
+            }
+            Raw::List(items)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let count = r.count()?;
+            let mut items = vec![];
+            for _ in 0..count {
+                let container = r.length()?;
+                let node = r.string()?;
+                if container == QUICKLIST_NODE_PLAIN {
+                    items.push(node);
+                } else {
+                    items.extend(bytes(listpack(&node)?));
+                }
+            }
+            Raw::List(items)
+        }
+        other => return Err(RdbError::Unsupported(other)),
+    })
+}
+
+fn text(s: Vec<u8>) -> Option<String> {
+    String::from_utf8(s).ok()
+}
+
+// Stored layout of the value, None when some element is not UTF-8. String
+// values take any bytes.
+fn into_value(raw: Raw) -> Option<Value> {
+    Some(match raw {
+        Raw::String(s) => Value::Str(s),
+        Raw::List(items) => Value::List(items.into_iter().map(text).collect::<Option<_>>()?),
+        Raw::Set(items) => Value::Set(items.into_iter().map(text).collect::<Option<_>>()?),
+        Raw::Hash(items) => Value::Hash(
+            items
+                .into_iter()
+                .map(|(f, v)| Some((text(f)?, text(v)?)))
+                .collect::<Option<_>>()?,
+        ),
+        Raw::ZSet(items) => {
+            let pairs = items
+                .into_iter()
+                .map(|(member, score)| Some((text(member)?, score)))
+                .collect::<Option<_>>()?;
+            Value::ZSet(pairs)
+        }
+    })
+}
+
+// Every key in the file, in file order. Entries with strings that are not
+// UTF-8 are counted in the second value and left out.
+pub fn parse(file: &[u8]) -> Result<(Vec<RdbEntry>, usize), RdbError> {
+    let check = check(file);
+    match check.error {
+        Some(e) => Err(e),
+        None => Ok((check.entries, check.skipped)),
+    }
+}
+
+// What check found in a file: the keys read before any damage, and how much
+// of the file they cover
+#[derive(Debug, PartialEq)]
+pub struct RdbCheck {
+    pub entries: Vec<RdbEntry>,
+    pub skipped: usize,
+    // Length of the leading part made of whole records that read cleanly
+    pub valid: usize,
+    pub error: Option<RdbError>,
+}
+
+impl RdbCheck {
+    // Whether truncate can drop the damage: a bad checksum doesn't say where
+    // it is, and a bad header leaves nothing to keep
+    pub fn fixable(&self) -> bool {
+        matches!(
+            self.error,
+            Some(RdbError::UnexpectedEnd | RdbError::Corrupt(_) | RdbError::Unsupported(_))
+        )
+    }
+}
+
+// Reads the whole file like parse, but keeps what it read up to the first
+// error
+pub fn check(file: &[u8]) -> RdbCheck {
+    let mut check = RdbCheck {
+        entries: vec![],
+        skipped: 0,
+        valid: 0,
+        error: None,
+    };
+    let version = (file.len() >= 9 && &file[..5] == b"REDIS")
+        .then(|| std::str::from_utf8(&file[5..9]).ok()?.parse::<u32>().ok())
+        .flatten();
+    match version {
+        Some(version) => check.error = read_records(file, version, &mut check).err(),
+        None => check.error = Some(RdbError::BadHeader),
+    }
+    check
+}
+
+// The aux fields a file starts with, as many as read cleanly. A prefix of
+// the file will do.
+pub fn aux(file: &[u8]) -> Vec<(String, String)> {
+    let mut fields = vec![];
+    if file.len() < 9 || &file[..5] != b"REDIS" {
+        return fields;
+    }
+    let mut r = Reader::new(file);
+    r.pos = 9;
+    while file.get(r.pos) == Some(&OP_AUX) {
+        r.pos += 1;
+        let (Ok(name), Ok(value)) = (r.string(), r.string()) else {
+            break;
+        };
+        if let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) {
+            fields.push((name, value));
+        }
+    }
+    fields
+}
+
+fn read_records(file: &[u8], version: u32, check: &mut RdbCheck) -> Result<(), RdbError> {
+    let mut r = Reader::new(file);
+    r.pos = 9;
+    check.valid = r.pos;
+    let mut db = 0;
+    let mut expire_at = None;
+    // Set by the opcodes that qualify the key after them
+    let mut prefixed = false;
+    loop {
+        if !prefixed {
+            check.valid = r.pos;
+        }
+        let op = r.u8()?;
+        match op {
+            OP_EOF => break,
+            OP_SELECTDB => db = r.count()?,
+            OP_RESIZEDB => {
+                r.length()?;
+                r.length()?;
+            }
+            OP_SLOT_INFO => {
+                r.length()?;
+                r.length()?;
+                r.length()?;
+            }
+            OP_AUX => {
+                r.string()?;
+                r.string()?;
+            }
+            OP_EXPIRETIME_MS => {
+                expire_at = Some(u64::from_le_bytes(r.le()?));
+                prefixed = true;
+            }
+            OP_EXPIRETIME => {
+                expire_at = Some(u32::from_le_bytes(r.le()?) as u64 * 1000);
+                prefixed = true;
+            }
+            OP_FREQ => {
+                r.u8()?;
+                prefixed = true;
+            }
+            OP_IDLE => {
+                r.length()?;
+                prefixed = true;
+            }
+            OP_FUNCTION2 => {
+                r.string()?;
+            }
+            OP_MODULE_AUX => return Err(RdbError::Unsupported(op)),
+            kind => {
+                let key = r.string()?;
+                let value = read_value(&mut r, kind)?;
+                prefixed = false;
+                match (String::from_utf8(key), into_value(value)) {
+                    (Ok(key), Some(value)) => check.entries.push(RdbEntry {
+                        db,
+                        key,
+                        value,
+                        expire_at: expire_at.take(),
+                        end: r.pos,
+                    }),
+                    _ => {
+                        check.skipped += 1;
+                        expire_at = None;
+                    }
+                }
+            }
+        }
+    }
+
+    // Version 5 and later end with a CRC64 of everything before it, where
+    // zero means the writer had checksums turned off
+    if version >= 5 {
+        let end = r.pos;
+        let expected = u64::from_le_bytes(r.le()?);
+        if expected != 0 && crc64(&file[..end]) != expected {
+            return Err(RdbError::Checksum);
+        }
+    }
+    Ok(())
+}
+
+// The first valid bytes of a damaged file, closed with EOF and a fresh
+// checksum so it loads again
+pub fn truncate(file: &[u8], valid: usize) -> Vec<u8> {
+    let mut out = file[..valid].to_vec();
+    out.push(OP_EOF);
+    let version = std::str::from_utf8(&file[5..9])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok());
+    if version.is_some_and(|version| version >= 5) {
+        let crc = crc64(&out);
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+    out
+}
+
+// Loads an RDB file into the databases. Keys already expired are dropped,
+// existing keys are overwritten.
+pub fn load<S>(file: &[u8], dbs: &Databases<S, String, Value>) -> Result<RdbStats, RdbError>
+where
+    S: Storage<String, Value>,
+{
+    load_with_progress(file, dbs, |_| {})
+}
+
+// Like load, telling progress how far into the file it has got, key by key
+pub fn load_with_progress<S, F>(
+    file: &[u8],
+    dbs: &Databases<S, String, Value>,
+    mut progress: F,
+) -> Result<RdbStats, RdbError>
+where
+    S: Storage<String, Value>,
+    F: FnMut(usize),
+{
+    let (entries, skipped) = parse(file)?;
+    let mut stats = RdbStats {
+        skipped,
+        ..Default::default()
+    };
+    let now = now_ms();
+    for entry in entries {
+        progress(entry.end);
+        let Some(db) = dbs.get(entry.db) else {
+            stats.skipped += 1;
+            continue;
+        };
+        // Small collections are packed like ones built by commands
+        let limits = db.listpack_limits();
+        let value = listpack::compact(&entry.value, &limits).unwrap_or(entry.value);
+        let value = bytestring::stored(value, &db.compression(), db.compression_stats());
+        let stored = match entry.expire_at {
+            Some(when) if when <= now => {
+                stats.expired += 1;
+                continue;
+            }
+            Some(when) => db.set_with_expiry(entry.key, value, when),
+            None => db.set(entry.key, value),
+        };
+        if stored.is_ok() {
+            stats.loaded += 1;
+        }
+    }
+    progress(file.len());
+    Ok(stats)
+}
+
+fn put_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(0x80);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+    put_length(out, s.len() as u64);
+    out.extend_from_slice(s);
+}
+
+// Type byte and body of a stored value, None for a type RDB files here
+// don't carry
+fn put_value(out: &mut Vec<u8>, value: &Value) -> Option<u8> {
+    if let Some(full) = listpack::expand(value) {
+        return put_value(out, &full);
+    }
+    if let Some(string) = ByteString::from_stored(value) {
+        put_string(out, string.as_bytes());
+        return Some(TYPE_STRING);
+    }
+    let strings = |out: &mut Vec<u8>, len: usize, items: &mut dyn Iterator<Item = &String>| {
+        put_length(out, len as u64);
+        for item in items {
+            put_string(out, item.as_bytes());
+        }
+    };
+    Some(match value {
+        Value::List(items) => {
+            strings(out, items.len(), &mut items.iter());
+            TYPE_LIST
+        }
+        Value::Set(items) => {
+            strings(out, items.len(), &mut items.iter());
+            TYPE_SET
+        }
+        Value::Hash(pairs) => {
+            put_length(out, pairs.len() as u64);
+            for (field, value) in pairs {
+                put_string(out, field.as_bytes());
+                put_string(out, value.as_bytes());
+            }
+            TYPE_HASH
+        }
+        Value::ZSet(pairs) => {
+            put_length(out, pairs.len() as u64);
+            for (member, score) in pairs {
+                put_string(out, member.as_bytes());
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+            TYPE_ZSET_2
+        }
+        _ => return None,
+    })
+}
+
+// The snapshots of the dbs, in db order, as an RDB file for a replica's
+// full resync
+pub fn save<S>(snapshots: &[Snapshot<S, String, Value>]) -> Result<Vec<u8>, anyhow::Error>
+where
+    S: Storage<String, Value>,
+{
+    save_with_aux(snapshots, &[])
+}
+
+// Like save, with aux fields after the header
+pub fn save_with_aux<S>(
+    snapshots: &[Snapshot<S, String, Value>],
+    aux: &[(&str, &str)],
+) -> Result<Vec<u8>, anyhow::Error>
+where
+    S: Storage<String, Value>,
+{
+    let mut file = b"REDIS0011".to_vec();
+    for (name, value) in aux {
+        file.push(OP_AUX);
+        put_string(&mut file, name.as_bytes());
+        put_string(&mut file, value.as_bytes());
+    }
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        let mut selected = false;
+        snapshot.for_each(|key, value, when| {
+            if !selected {
+                file.push(OP_SELECTDB);
+                put_length(&mut file, index as u64);
+                selected = true;
+            }
+            if let Some(when) = when {
+                file.push(OP_EXPIRETIME_MS);
+                file.extend_from_slice(&when.to_le_bytes());
+            }
+            let mut body = vec![];
+            let kind = put_value(&mut body, value)
+                .ok_or_else(|| anyhow::anyhow!("value of key {} can't be saved", key))?;
+            file.push(kind);
+            put_string(&mut file, key.as_bytes());
+            file.extend(body);
+            Ok(())
+        })?;
+    }
+    file.push(OP_EOF);
+    let crc = crc64(&file);
+    file.extend_from_slice(&crc.to_le_bytes());
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::{snapshot, DB};
+    use crate::db::storage::DashMapStorage;
+    use std::sync::Arc;
+
+    fn string(out: &mut Vec<u8>, s: &[u8]) {
+        out.push(s.len() as u8);
+        out.extend_from_slice(s);
+    }
+
+    fn listpack_of(items: &[&[u8]]) -> Vec<u8> {
+        let mut body = vec![];
+        for item in items {
+            body.push(0x80 | item.len() as u8);
+            body.extend_from_slice(item);
+            body.push(item.len() as u8 + 1);
+        }
+        let mut blob = ((body.len() + 7) as u32).to_le_bytes().to_vec();
+        blob.extend_from_slice(&(items.len() as u16).to_le_bytes());
+        blob.extend(body);
+        blob.push(0xFF);
+        blob
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut file = b"REDIS0011".to_vec();
+        file.push(OP_AUX);
+        string(&mut file, b"redis-ver");
+        string(&mut file, b"7.2.0");
+        file.extend_from_slice(&[OP_SELECTDB, 0, OP_RESIZEDB, 4, 1]);
+
+        file.push(TYPE_STRING);
+        string(&mut file, b"greeting");
+        string(&mut file, b"hello");
+
+        // Integer encoded string with a TTL far in the future
+        file.push(OP_EXPIRETIME_MS);
+        file.extend_from_slice(&u64::MAX.to_le_bytes());
+        file.push(TYPE_STRING);
+        string(&mut file, b"counter");
+        file.extend_from_slice(&[0xC1, 0x39, 0x30]);
+
+        file.push(TYPE_SET_INTSET);
+        string(&mut file, b"ids");
+        let mut intset = 2u32.to_le_bytes().to_vec();
+        intset.extend_from_slice(&2u32.to_le_bytes());
+        intset.extend_from_slice(&[1, 0, 2, 0]);
+        string(&mut file, &intset);
+
+        file.push(TYPE_HASH_LISTPACK);
+        string(&mut file, b"user");
+        string(&mut file, &listpack_of(&[b"name", b"ann"]));
+
+        file.push(OP_EOF);
+        let crc = crc64(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn test_check() {
+        let file = sample();
+        let intact = check(&file);
+        assert_eq!(intact.error, None);
+        assert_eq!(intact.entries.len(), 4);
+
+        // Cut inside the hash, the three keys before it are kept
+        let cut = &file[..file.len() - 12];
+        let damaged = check(cut);
+        assert_eq!(damaged.error, Some(RdbError::UnexpectedEnd));
+        assert!(damaged.fixable());
+        assert_eq!(damaged.entries.len(), 3);
+        assert_eq!(damaged.valid, damaged.entries[2].end);
+        let (entries, _) = parse(&truncate(cut, damaged.valid)).unwrap();
+        assert_eq!(entries, damaged.entries);
+
+        // A cut between a TTL and its key drops the TTL too
+        let ttl = file
+            .windows(9)
+            .position(|w| w[0] == OP_EXPIRETIME_MS && w[1..] == u64::MAX.to_le_bytes())
+            .unwrap();
+        let damaged = check(&file[..ttl + 9]);
+        assert_eq!(damaged.valid, ttl);
+        assert_eq!(parse(&truncate(&file, damaged.valid)).unwrap().0.len(), 1);
+
+        // Flipped bits only show in the checksum, which can't say where
+        let mut flipped = file.clone();
+        let at = flipped.windows(5).position(|w| w == b"hello").unwrap();
+        flipped[at] = b'j';
+        let damaged = check(&flipped);
+        assert_eq!(damaged.error, Some(RdbError::Checksum));
+        assert!(!damaged.fixable());
+        assert!(!check(b"RDB").fixable());
+    }
+
+    #[test]
+    fn test_crc64_and_lzf() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(
+            lzf_decompress(&[2, b'a', b'b', b'c', 0x20, 0x02], 6).unwrap(),
+            b"abcabc"
+        );
+        assert!(lzf_decompress(&[0x20, 0x02], 3).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let (entries, skipped) = parse(&sample()).unwrap();
+        assert_eq!(skipped, 0);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].key, "greeting");
+        assert_eq!(entries[1].value, Value::str("12345"));
+        assert_eq!(entries[1].expire_at, Some(u64::MAX));
+        assert_eq!(
+            entries[2].value,
+            Value::Set(["1".to_string(), "2".to_string()].into())
+        );
+        assert_eq!(
+            entries[3].value,
+            Value::Hash([("name".to_string(), "ann".to_string())].into())
+        );
+
+        let mut broken = sample();
+        let at = broken.len() - 12;
+        broken[at] ^= 1;
+        assert_eq!(parse(&broken).unwrap_err(), RdbError::Checksum);
+        assert_eq!(parse(b"NOTREDIS0").unwrap_err(), RdbError::BadHeader);
+        assert_eq!(parse(&sample()[..30]).unwrap_err(), RdbError::UnexpectedEnd);
+    }
+
+    #[test]
+    fn test_load() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(DashMapStorage::new(), 16))]);
+        let mut offsets = vec![];
+        let stats = load_with_progress(&sample(), &dbs, |at| offsets.push(at)).unwrap();
+        assert_eq!(stats.loaded, 4);
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(offsets.last(), Some(&sample().len()));
+        assert!(dbs[0].get(&"user".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_save() {
+        let dbs = Arc::new(vec![
+            Arc::new(DB::new(DashMapStorage::new(), 16)),
+            Arc::new(DB::new(DashMapStorage::new(), 16)),
+        ]);
+        load(&sample(), &dbs).unwrap();
+        let long = "x".repeat(20_000);
+        dbs[1].set("long".to_string(), Value::str(&long)).unwrap();
+        dbs[1]
+            .set(
+                "board".to_string(),
+                Value::ZSet(vec![("ann".to_string(), 1.5)].into()),
+            )
+            .unwrap();
+        dbs[1]
+            .set(
+                "queue".to_string(),
+                Value::List(vec!["a".to_string(), "b".to_string()].into()),
+            )
+            .unwrap();
+        let blob = Value::Str(vec![0xff, 0x00, 0xc3]);
+        dbs[1].set("blob".to_string(), blob.clone()).unwrap();
+
+        // What was saved loads back as it was, packed values included
+        let (mut entries, skipped) = parse(&save(&snapshot(&dbs)).unwrap()).unwrap();
+        assert_eq!(skipped, 0);
+        entries.sort_by(|a, b| (a.db, &a.key).cmp(&(b.db, &b.key)));
+        let keys: Vec<_> = entries.iter().map(|e| (e.db, e.key.as_str())).collect();
+        assert_eq!(
+            keys,
+            [
+                (0, "counter"),
+                (0, "greeting"),
+                (0, "ids"),
+                (0, "user"),
+                (1, "blob"),
+                (1, "board"),
+                (1, "long"),
+                (1, "queue")
+            ]
+        );
+        assert_eq!(entries[0].expire_at, Some(u64::MAX));
+        assert_eq!(entries[1].expire_at, None);
+        assert_eq!(entries[3].value, parse(&sample()).unwrap().0[3].value);
+        assert_eq!(entries[4].value, blob);
+        assert_eq!(
+            entries[5].value,
+            Value::ZSet(vec![("ann".to_string(), 1.5)].into())
+        );
+        assert_eq!(entries[6].value, Value::str(&long));
+        assert_eq!(
+            entries[7].value,
+            Value::List(vec!["a".to_string(), "b".to_string()].into())
+        );
+    }
+
+    #[test]
+    fn test_aux() {
+        assert_eq!(
+            aux(&sample()),
+            [("redis-ver".to_string(), "7.2.0".to_string())]
+        );
+        let dbs = Arc::new(vec![Arc::new(DB::new(DashMapStorage::new(), 16))]);
+        dbs[0].set("a".to_string(), Value::str("1")).unwrap();
+        let file =
+            save_with_aux(&snapshot(&dbs), &[("aof-id", "ab"), ("aof-offset", "42")]).unwrap();
+        let fields = [
+            ("aof-id".to_string(), "ab".to_string()),
+            ("aof-offset".to_string(), "42".to_string()),
+        ];
+        assert_eq!(aux(&file), fields);
+        // The head of the file is enough, and the rest loads as usual
+        assert_eq!(aux(&file[..35]), fields);
+        assert_eq!(aux(&file[..20]), fields[..1]);
+        assert_eq!(parse(&file).unwrap().0.len(), 1);
+        assert!(aux(b"REDIS").is_empty());
+    }
+}
@@ -0,0 +1,309 @@
+// Per-worker read views of a database, for read-heavy loads. Each worker
+// thread reads through a view of its own, a plain map nobody else touches,
+// so a hit costs no shared lock or DashMap shard. Writes publish the keys
+// they change to a change stream; a view applies what was published since it
+// last looked before serving anything, so it serves no value older than the
+// last write it could have seen. A view that fell further behind than the
+// stream keeps starts over empty.
+use crate::db::lru::CacheStats;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+// Entries a view holds before it drops some for new ones
+pub const VIEW_CAPACITY: usize = 16 * 1024;
+
+// Changes kept for views to catch up from
+const CHANGES_KEPT: usize = 4096;
+
+// A hit counts as an access to the key once in this many, keeping eviction
+// and hot key tracking roughly informed without touching them every time
+pub const TOUCH_EVERY: u64 = 32;
+
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Worker threads are numbered as they first read
+    static SLOT: Cell<usize> = Cell::new(NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+}
+
+#[derive(Debug)]
+enum Change<K> {
+    Key(K),
+    // Everything, after a reload
+    All,
+}
+
+struct Entry<V> {
+    value: Arc<V>,
+    // Unix ms deadline the key had when read, past it the view passes
+    expires_at: Option<u64>,
+}
+
+pub struct View<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    // Last change applied
+    seq: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K, V> View<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    // The value at key when the view has it and its deadline hasn't passed.
+    // Returns whether the hit should count as an access.
+    pub fn get(&mut self, key: &K, now_ms: u64) -> Option<(Arc<V>, bool)> {
+        match self.entries.get(key) {
+            Some(entry) if entry.expires_at.is_none_or(|at| at > now_ms) => {
+                self.hits += 1;
+                Some((entry.value.clone(), self.hits.is_multiple_of(TOUCH_EVERY)))
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Change the view is up to date with, for fill
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+// Padded to a cache line pair so views of different workers never share one
+#[repr(align(128))]
+struct Padded<T>(T);
+
+struct Changes<K> {
+    log: VecDeque<Change<K>>,
+    // Sequence number of the first change in log
+    first: u64,
+}
+
+pub struct ReadViews<K, V> {
+    views: Vec<Padded<Mutex<View<K, V>>>>,
+    changes: Mutex<Changes<K>>,
+    // Changes published so far
+    seq: AtomicU64,
+}
+
+impl<K, V> ReadViews<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(views: usize) -> Self {
+        Self {
+            views: (0..views.max(1))
+                .map(|_| {
+                    Padded(Mutex::new(View {
+                        entries: HashMap::new(),
+                        seq: 0,
+                        hits: 0,
+                        misses: 0,
+                    }))
+                })
+                .collect(),
+            changes: Mutex::new(Changes {
+                log: VecDeque::new(),
+                first: 0,
+            }),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.views.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.views.is_empty()
+    }
+
+    // The calling thread's view, caught up with the change stream. None when
+    // another thread holds it, the caller reads past the views then.
+    pub fn view(&self) -> Option<MutexGuard<'_, View<K, V>>> {
+        let slot = SLOT.with(|slot| slot.get()) % self.views.len();
+        let mut view = match self.views[slot].0.try_lock() {
+            Ok(view) => view,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        if view.seq != self.seq.load(Ordering::Acquire) {
+            self.catch_up(&mut view);
+        }
+        Some(view)
+    }
+
+    fn catch_up(&self, view: &mut View<K, V>) {
+        let changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        let last = changes.first + changes.log.len() as u64;
+        if view.seq < changes.first {
+            view.entries.clear();
+        } else {
+            for change in changes.log.iter().skip((view.seq - changes.first) as usize) {
+                match change {
+                    Change::Key(key) => {
+                        view.entries.remove(key);
+                    }
+                    Change::All => view.entries.clear(),
+                }
+            }
+        }
+        view.seq = last;
+    }
+
+    // Keeps a value read at seq in view, unless a change was published since
+    // which it may predate
+    pub fn fill(
+        &self,
+        view: &mut View<K, V>,
+        key: &K,
+        value: Arc<V>,
+        expires_at: Option<u64>,
+        seq: u64,
+    ) {
+        if self.seq.load(Ordering::Acquire) != seq {
+            return;
+        }
+        if view.entries.len() >= VIEW_CAPACITY && !view.entries.contains_key(key) {
+            // Any entry will do, views hold what is read a lot anyway
+            if let Some(old) = view.entries.keys().next().cloned() {
+                view.entries.remove(&old);
+            }
+        }
+        view.entries
+            .insert(key.clone(), Entry { value, expires_at });
+    }
+
+    // Called after key or its deadline changed
+    pub fn changed(&self, key: &K) {
+        self.publish(Change::Key(key.clone()));
+    }
+
+    // Called after the whole database changed
+    pub fn changed_all(&self) {
+        self.publish(Change::All);
+    }
+
+    fn publish(&self, change: Change<K>) {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        changes.log.push_back(change);
+        if changes.log.len() > CHANGES_KEPT {
+            changes.log.pop_front();
+            changes.first += 1;
+        }
+        // Under the lock, so views catch up to exactly what the log holds
+        self.seq
+            .store(changes.first + changes.log.len() as u64, Ordering::Release);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.views
+            .iter()
+            .fold(CacheStats::default(), |mut stats, view| {
+                let view = view.0.lock().unwrap_or_else(|e| e.into_inner());
+                stats.hits += view.hits;
+                stats.misses += view.misses;
+                stats
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(views: &ReadViews<String, u64>, key: &str, now_ms: u64) -> Option<u64> {
+        let mut view = views.view().unwrap();
+        view.get(&key.to_string(), now_ms).map(|(value, _)| *value)
+    }
+
+    fn fill(views: &ReadViews<String, u64>, key: &str, value: u64, expires_at: Option<u64>) {
+        let mut view = views.view().unwrap();
+        let seq = view.seq();
+        views.fill(
+            &mut view,
+            &key.to_string(),
+            Arc::new(value),
+            expires_at,
+            seq,
+        );
+    }
+
+    #[test]
+    fn test_read_views() {
+        let views = ReadViews::new(4);
+        assert_eq!(read(&views, "a", 0), None);
+        fill(&views, "a", 1, None);
+        fill(&views, "b", 2, Some(100));
+        assert_eq!(read(&views, "a", 0), Some(1));
+        assert_eq!(read(&views, "b", 99), Some(2));
+        assert_eq!(read(&views, "b", 100), None);
+
+        // A change drops the key from every view before it is read again
+        views.changed(&"a".to_string());
+        assert_eq!(read(&views, "a", 0), None);
+        assert_eq!(read(&views, "b", 0), Some(2));
+
+        // A value read before a change isn't kept
+        let mut view = views.view().unwrap();
+        let seq = view.seq();
+        views.changed(&"c".to_string());
+        views.fill(&mut view, &"c".to_string(), Arc::new(3), None, seq);
+        drop(view);
+        assert_eq!(read(&views, "c", 0), None);
+
+        views.changed_all();
+        assert_eq!(read(&views, "b", 0), None);
+
+        // Falling behind the changes kept empties the view
+        fill(&views, "d", 4, None);
+        for i in 0..CHANGES_KEPT + 1 {
+            views.changed(&format!("other{}", i));
+        }
+        assert_eq!(read(&views, "d", 0), None);
+
+        // Views stay bounded
+        for i in 0..VIEW_CAPACITY as u64 + 10 {
+            fill(&views, &i.to_string(), i, None);
+        }
+        let view = views.view().unwrap();
+        assert_eq!(view.entries.len(), VIEW_CAPACITY);
+        drop(view);
+
+        let stats = views.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 6));
+    }
+
+    #[test]
+    fn test_views_per_thread() {
+        let views = Arc::new(ReadViews::<String, u64>::new(64));
+        fill(&views, "a", 1, None);
+        // Threads reading at once each get a view, or pass
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let views = views.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        if let Some(mut view) = views.view() {
+                            if view.get(&"a".to_string(), 0).is_none() {
+                                let seq = view.seq();
+                                views.fill(&mut view, &"a".to_string(), Arc::new(1), None, seq);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let stats = views.stats();
+        assert!(stats.hits > 0);
+    }
+}
@@ -0,0 +1,170 @@
+// The members of a sorted set: a vector ordered by (score, member), found
+// by binary search, and a map from each member to its score so a member is
+// located without a scan. Reading by rank is an index into the vector;
+// adding or re-scoring a member is a binary search plus a shift of the
+// elements after it.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::slice;
+
+pub type Iter<'a> = slice::Iter<'a, (String, f64)>;
+
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    order: Vec<(String, f64)>,
+    scores: HashMap<String, f64>,
+}
+
+fn compare(a: (&str, f64), b: (&str, f64)) -> Ordering {
+    a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0))
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Where (member, score) is or would go in the order
+    fn search(&self, member: &str, score: f64) -> Result<usize, usize> {
+        self.order
+            .binary_search_by(|(m, s)| compare((m, *s), (member, score)))
+    }
+
+    // Adds or re-scores member, true when it was not there before
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            if old.total_cmp(&score) == Ordering::Equal {
+                return false;
+            }
+            if let Ok(at) = self.search(&member, old) {
+                self.order.remove(at);
+            }
+        }
+        let at = self.search(&member, score).unwrap_or_else(|at| at);
+        self.order.insert(at, (member, score));
+        old.is_none()
+    }
+
+    // Removes member, returning its score
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        if let Ok(at) = self.search(member, score) {
+            self.order.remove(at);
+        }
+        Some(score)
+    }
+
+    // (member, score) pairs in order
+    pub fn iter(&self) -> Iter<'_> {
+        self.order.iter()
+    }
+
+    // The pair at rank i
+    pub fn get(&self, i: usize) -> Option<(&str, f64)> {
+        self.order
+            .get(i)
+            .map(|(member, score)| (member.as_str(), *score))
+    }
+}
+
+// Equal when they hold the same members with the same scores
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.order == other.order
+    }
+}
+
+impl Extend<(String, f64)> for SortedSet {
+    fn extend<I: IntoIterator<Item = (String, f64)>>(&mut self, iter: I) {
+        for (member, score) in iter {
+            self.insert(member, score);
+        }
+    }
+}
+
+impl FromIterator<(String, f64)> for SortedSet {
+    fn from_iter<I: IntoIterator<Item = (String, f64)>>(iter: I) -> Self {
+        let mut set = Self::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl From<Vec<(String, f64)>> for SortedSet {
+    fn from(pairs: Vec<(String, f64)>) -> Self {
+        pairs.into_iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSet {
+    type Item = &'a (String, f64);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.order.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(set: &SortedSet) {
+        assert!(set
+            .order
+            .windows(2)
+            .all(|w| compare((&w[0].0, w[0].1), (&w[1].0, w[1].1)) == Ordering::Less));
+        assert_eq!(set.scores.len(), set.order.len());
+        for (member, score) in set {
+            assert_eq!(set.score(member), Some(*score));
+        }
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set = SortedSet::new();
+        for i in 0..500 {
+            assert!(set.insert(format!("m{}", i), ((i * 7) % 50) as f64));
+        }
+        check(&set);
+        assert_eq!(set.len(), 500);
+
+        // Re-scoring moves the member and adds nothing
+        assert!(!set.insert("m3".to_string(), -1.0));
+        assert!(!set.insert("m3".to_string(), -1.0));
+        assert_eq!(set.get(0), Some(("m3", -1.0)));
+        check(&set);
+
+        for i in (0..500).step_by(2) {
+            assert!(set.remove(&format!("m{}", i)).is_some());
+        }
+        assert_eq!(set.remove("m0"), None);
+        assert_eq!(set.len(), 250);
+        check(&set);
+    }
+
+    #[test]
+    fn test_ties_by_member() {
+        let set: SortedSet = vec![
+            ("b".to_string(), 1.0),
+            ("c".to_string(), 0.5),
+            ("a".to_string(), 1.0),
+        ]
+        .into();
+        let members: Vec<&str> = set.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(members, ["c", "a", "b"]);
+    }
+}
@@ -1,10 +1,11 @@
 #![warn(unused_imports)]
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
 use std::borrow::Borrow;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 #[derive(Debug)]
@@ -28,6 +29,14 @@ impl Error for StorageError {}
 
 pub type Result<T> = std::result::Result<T, StorageError>;
 
+// What Storage::update should do with the entry once the closure returns
+#[derive(Debug, PartialEq)]
+pub enum Update<V> {
+    Keep,
+    Set(V),
+    Delete,
+}
+
 // Storage trait
 pub trait Storage<K, V>: Send + Sync + Debug
 where
@@ -37,18 +46,137 @@ where
     fn get<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Hash + Eq;
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>;
 
     fn set(&self, key: K, value: V) -> Result<Option<V>>;
 
+    // Atomically stores the value only when the key is vacant.
+    // Returns false and leaves the existing value untouched otherwise.
+    fn insert_if_absent(&self, key: K, value: V) -> Result<bool>;
+
+    // Atomic read-modify-write of a single entry. The closure sees the current
+    // value (mutable in place) and decides whether to keep, replace or delete
+    // it; no other writer can touch the key until it returns.
+    fn update<F, R>(&self, key: K, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut V>) -> (Update<V>, R);
+
     fn delete<Q>(&self, key: &Q) -> Result<Option<V>>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Hash + Eq;
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>;
+
+    // Version of the entry at key, 0 when there is none. Every write to the
+    // key gives it a new one, and versions are never reused, so a key that
+    // was deleted and written again doesn't look unchanged.
+    fn version<Q>(&self, key: &Q) -> Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>;
+
+    // Stores value only when the entry is still at expected_version, 0 for
+    // a key that must not exist. Returns the version written, None when the
+    // entry changed since and nothing was stored.
+    fn compare_and_swap(&self, key: K, expected_version: u64, value: V) -> Result<Option<u64>>;
+
+    // Deletes the entry only when it is still at expected_version. Returns
+    // whether it was deleted.
+    fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>;
 
     fn clear(&self) -> Result<()>;
 
+    // Every key stored, in no particular order
+    fn keys(&self) -> Result<Vec<K>>;
+
     fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Extra counters reported under `# Storage` by INFO
+    fn info(&self) -> Vec<(&'static str, u64)> {
+        Vec::new()
+    }
+
+    // Told every change to the deadline of a key that stays, None when it
+    // loses it, by a DB that keeps its deadlines in memory. A storage that
+    // outlives the process keeps them too, and drops a key's deadline with
+    // the key.
+    fn set_expiry(&self, _key: &K, _when: Option<u64>) -> Result<()> {
+        Ok(())
+    }
+
+    // Deadlines of the keys kept from an earlier run, for DB to load
+    fn expiries(&self) -> Result<Vec<(K, u64)>> {
+        Ok(Vec::new())
+    }
+}
+
+// Versions of the keys of a storage that can't keep them with its values.
+// The storage changes them while it holds the key's lock.
+#[derive(Debug)]
+pub struct Versions<K>
+where
+    K: Hash + Eq,
+{
+    versions: DashMap<K, u64>,
+    last: AtomicU64,
+}
+
+impl<K> Default for Versions<K>
+where
+    K: Hash + Eq,
+{
+    fn default() -> Self {
+        Self {
+            versions: DashMap::new(),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K> Versions<K>
+where
+    K: Hash + Eq,
+{
+    // None for a key written before anything was tracked, or not at all
+    pub fn get<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.versions.get(key).map(|version| *version)
+    }
+
+    // A new version for a key just written
+    pub fn bump(&self, key: K) -> u64 {
+        let version = self.last.fetch_add(1, Ordering::Relaxed) + 1;
+        self.versions.insert(key, version);
+        version
+    }
+
+    pub fn forget<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        self.versions.remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.versions.clear();
+    }
+}
+
+// A value and the version it was written at
+#[derive(Debug, Clone)]
+struct Versioned<V> {
+    value: V,
+    version: u64,
 }
 
 // DashMap Storage implementation
@@ -58,10 +186,13 @@ where
     K: Hash + Eq + Debug,
     V: Debug,
 {
-    data: DashMap<K, V>,
+    data: DashMap<K, Versioned<V>>,
+    // Last version given out, one sequence for all keys
+    version: AtomicU64,
     state: StorageStats,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default, Clone)]
 struct StorageStats {
     operations: u64,
@@ -77,6 +208,7 @@ where
     pub fn new() -> Self {
         Self {
             data: DashMap::new(),
+            version: AtomicU64::new(0),
             state: StorageStats {
                 operations: 0,
                 hits: 0,
@@ -84,6 +216,27 @@ where
             },
         }
     }
+
+    fn next_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn versioned(&self, value: V) -> Versioned<V> {
+        Versioned {
+            value,
+            version: self.next_version(),
+        }
+    }
+}
+
+impl<K, V> Default for DashMapStorage<K, V>
+where
+    K: Hash + Eq + Send + Sync + Debug + 'static,
+    V: Clone + Send + Sync + Debug + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<K, V> Storage<K, V> for DashMapStorage<K, V>
@@ -94,22 +247,99 @@ where
     fn get<Q>(&self, key: &Q) -> Result<Option<Arc<V>>>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Hash + Eq,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
     {
-        let result = self.data.get(key).map(|r| Arc::new(r.value().clone()));
+        let result = self
+            .data
+            .get(key)
+            .map(|r| Arc::new(r.value().value.clone()));
         Ok(result)
     }
 
     fn set(&self, key: K, value: V) -> Result<Option<V>> {
-        Ok(self.data.insert(key, value))
+        let value = self.versioned(value);
+        Ok(self.data.insert(key, value).map(|old| old.value))
+    }
+
+    fn insert_if_absent(&self, key: K, value: V) -> Result<bool> {
+        match self.data.entry(key) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(self.versioned(value));
+                Ok(true)
+            }
+        }
+    }
+
+    fn update<F, R>(&self, key: K, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut V>) -> (Update<V>, R),
+    {
+        match self.data.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let (update, result) = f(Some(&mut entry.get_mut().value));
+                match update {
+                    // The closure may have changed the value in place
+                    Update::Keep => entry.get_mut().version = self.next_version(),
+                    Update::Set(value) => {
+                        entry.insert(self.versioned(value));
+                    }
+                    Update::Delete => {
+                        entry.remove();
+                    }
+                }
+                Ok(result)
+            }
+            Entry::Vacant(entry) => {
+                let (update, result) = f(None);
+                if let Update::Set(value) = update {
+                    entry.insert(self.versioned(value));
+                }
+                Ok(result)
+            }
+        }
     }
 
     fn delete<Q>(&self, key: &Q) -> Result<Option<V>>
     where
         K: Borrow<Q>,
-        Q: ?Sized + Hash + Eq,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
     {
-        Ok(self.data.remove(key).map(|(_, v)| v))
+        Ok(self.data.remove(key).map(|(_, v)| v.value))
+    }
+
+    fn version<Q>(&self, key: &Q) -> Result<u64>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        Ok(self.data.get(key).map_or(0, |entry| entry.version))
+    }
+
+    fn compare_and_swap(&self, key: K, expected_version: u64, value: V) -> Result<Option<u64>> {
+        let value = self.versioned(value);
+        let version = value.version;
+        match self.data.entry(key) {
+            Entry::Occupied(mut entry) if entry.get().version == expected_version => {
+                entry.insert(value);
+            }
+            Entry::Vacant(entry) if expected_version == 0 => {
+                entry.insert(value);
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(version))
+    }
+
+    fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+    {
+        Ok(self
+            .data
+            .remove_if(key, |_, entry| entry.version == expected_version)
+            .is_some())
     }
 
     fn clear(&self) -> Result<()> {
@@ -117,6 +347,10 @@ where
         Ok(())
     }
 
+    fn keys(&self) -> Result<Vec<K>> {
+        Ok(self.data.iter().map(|entry| entry.key().clone()).collect())
+    }
+
     fn len(&self) -> usize {
         self.data.len()
     }
@@ -130,6 +364,7 @@ where
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
+            version: AtomicU64::new(self.version.load(Ordering::Relaxed)),
             state: self.state.clone(),
         }
     }
@@ -166,6 +401,106 @@ mod tests {
         assert_eq!(storage.get("key2").unwrap(), None);
     }
 
+    #[tokio::test]
+    async fn test_insert_if_absent() {
+        let storage: DashMapStorage<String, i32> = DashMapStorage::new();
+
+        assert!(storage.insert_if_absent("key1".to_string(), 1).unwrap());
+        assert!(!storage.insert_if_absent("key1".to_string(), 2).unwrap());
+        assert_eq!(*storage.get("key1").unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let storage: DashMapStorage<String, i32> = DashMapStorage::new();
+
+        // Vacant entry can be created
+        let seen = storage
+            .update("counter".to_string(), |v| (Update::Set(1), v.is_some()))
+            .unwrap();
+        assert!(!seen);
+
+        // Occupied entry is mutated in place
+        let value = storage
+            .update("counter".to_string(), |v| {
+                let v = v.unwrap();
+                *v += 41;
+                (Update::Keep, *v)
+            })
+            .unwrap();
+        assert_eq!(value, 42);
+        assert_eq!(*storage.get("counter").unwrap().unwrap(), 42);
+
+        // And removed
+        storage
+            .update("counter".to_string(), |_| (Update::Delete, ()))
+            .unwrap();
+        assert_eq!(storage.get("counter").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let storage: DashMapStorage<String, i32> = DashMapStorage::new();
+
+        // 0 stands for a missing key
+        assert_eq!(storage.version("n").unwrap(), 0);
+        assert_eq!(
+            storage.compare_and_swap("n".to_string(), 1, 1).unwrap(),
+            None
+        );
+        let first = storage
+            .compare_and_swap("n".to_string(), 0, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.version("n").unwrap(), first);
+        assert_eq!(
+            storage.compare_and_swap("n".to_string(), 0, 2).unwrap(),
+            None
+        );
+
+        // An increment that lost the race reads again and retries
+        let read = storage.version("n").unwrap();
+        storage
+            .update("n".to_string(), |v| {
+                *v.unwrap() += 10;
+                (Update::Keep, ())
+            })
+            .unwrap();
+        assert_eq!(
+            storage.compare_and_swap("n".to_string(), read, 2).unwrap(),
+            None
+        );
+        let read = storage.version("n").unwrap();
+        let current = *storage.get("n").unwrap().unwrap();
+        let swapped = storage
+            .compare_and_swap("n".to_string(), read, current + 1)
+            .unwrap()
+            .unwrap();
+        assert!(swapped > read);
+        assert_eq!(*storage.get("n").unwrap().unwrap(), 12);
+
+        // Deleted and written again is a change too
+        storage.delete("n").unwrap();
+        assert_eq!(storage.version("n").unwrap(), 0);
+        storage.set("n".to_string(), 12).unwrap();
+        assert_eq!(
+            storage
+                .compare_and_swap("n".to_string(), swapped, 13)
+                .unwrap(),
+            None
+        );
+
+        // A delete that lost the race leaves the newer value
+        let read = storage.version("n").unwrap();
+        storage.set("n".to_string(), 14).unwrap();
+        assert!(!storage.compare_and_delete("n", read).unwrap());
+        assert_eq!(*storage.get("n").unwrap().unwrap(), 14);
+        let read = storage.version("n").unwrap();
+        assert!(storage.compare_and_delete("n", read).unwrap());
+        assert!(storage.get("n").unwrap().is_none());
+        assert!(!storage.compare_and_delete("n", 0).unwrap());
+    }
+
     #[tokio::test]
     async fn test_clone() {
         let storage: DashMapStorage<String, String> = DashMapStorage::new();
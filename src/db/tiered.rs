@@ -0,0 +1,499 @@
+// Two tier storage: recently used keys stay in memory, the rest spill to a
+// sled tree and are faulted back in when touched. A key read back in keeps
+// its copy on disk until it is written or deleted, so it is never only in
+// memory because it was read; hot keys beyond `max_hot` are moved out least
+// recently used first.
+use crate::db::disk::SledStorage;
+use crate::db::storage::{DashMapStorage, Result, Storage, Update, Versions};
+use crate::db::value::Value;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Every operation on a key holds its stripe, so moving it between tiers
+// never races with a reader or writer of the same key
+const LOCK_STRIPES: usize = 64;
+
+// Hot keys by last access. The tick is a logical clock, not a time.
+#[derive(Debug, Default)]
+struct AccessOrder {
+    ticks: HashMap<String, u64>,
+    order: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl AccessOrder {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        if let Some(old) = self.ticks.insert(key.to_string(), self.clock) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.clock, key.to_string());
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn oldest(&mut self) -> Option<String> {
+        let (_, key) = self.order.pop_first()?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+#[derive(Debug, Default)]
+struct TierStats {
+    hot_hits: AtomicU64,
+    cold_hits: AtomicU64,
+    misses: AtomicU64,
+    spilled: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct TieredStorage {
+    hot: DashMapStorage<String, Value>,
+    cold: SledStorage,
+    access: Mutex<AccessOrder>,
+    // Hot keys whose copy on disk is still current, read in and not written
+    // since. Spilling them only drops the hot copy.
+    clean: Mutex<HashSet<String>>,
+    locks: Vec<Mutex<()>>,
+    max_hot: usize,
+    stats: TierStats,
+    // Kept here rather than by the tiers, so moving a key between them
+    // doesn't change its version
+    versions: Versions<String>,
+}
+
+impl TieredStorage {
+    pub fn new(cold: SledStorage, max_hot: usize) -> Self {
+        Self {
+            hot: DashMapStorage::new(),
+            cold,
+            access: Mutex::new(AccessOrder::default()),
+            clean: Mutex::new(HashSet::new()),
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            max_hot: max_hot.max(1),
+            stats: TierStats::default(),
+            versions: Versions::default(),
+        }
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = (hasher.finish() % LOCK_STRIPES as u64) as usize;
+        self.locks[stripe].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn access(&self) -> MutexGuard<'_, AccessOrder> {
+        self.access.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn clean(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.clean.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Brings a cold key into the hot tier, leaving its copy on disk. Gives
+    // the value when it was cold.
+    fn fault_in(&self, key: &str) -> Result<Option<Value>> {
+        if self.cold.is_empty() || self.hot.get(key)?.is_some() {
+            return Ok(None);
+        }
+        let Some(value) = self.cold.get(key)? else {
+            return Ok(None);
+        };
+        self.hot.set(key.to_string(), (*value).clone())?;
+        self.clean().insert(key.to_string());
+        Ok(Some((*value).clone()))
+    }
+
+    // Drops the copy on disk of a key about to be written, giving it back.
+    // Its deadline stays on disk, wherever the key lives.
+    fn discard_cold(&self, key: &str) -> Result<Option<Value>> {
+        self.clean().remove(key);
+        if self.cold.is_empty() {
+            return Ok(None);
+        }
+        self.cold.take(key)
+    }
+
+    // Drops the key from disk, deadline and all
+    fn delete_cold(&self, key: &str) -> Result<Option<Value>> {
+        self.clean().remove(key);
+        self.cold.delete(key)
+    }
+
+    // Moves a hot key to disk, unless the copy there is still current
+    fn move_out(&self, key: String) -> Result<bool> {
+        let Some(value) = self.hot.delete(&key)? else {
+            return Ok(false);
+        };
+        if !self.clean().remove(&key) {
+            self.cold.set(key, value)?;
+        }
+        Ok(true)
+    }
+
+    // Called with the key's stripe held. Keys on disk from an earlier run get
+    // a version when first asked for.
+    fn current_version(&self, key: &str) -> Result<u64> {
+        match self.versions.get(key) {
+            Some(version) => Ok(version),
+            None if self.hot.get(key)?.is_some() || self.cold.get(key)?.is_some() => {
+                Ok(self.versions.bump(key.to_string()))
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Moves every hot key to disk and flushes it, so the keys outlive the
+    // process. Keys touched meanwhile stay hot.
+    pub fn flush(&self) -> Result<()> {
+        while let Some(key) = self.access().oldest() {
+            let _guard = self.lock(&key);
+            self.move_out(key)?;
+        }
+        self.cold.flush()
+    }
+
+    // Moves least recently used keys to disk until the hot tier fits. Called
+    // without any stripe held, it takes each victim's stripe itself.
+    fn spill(&self) -> Result<()> {
+        while self.hot.len() > self.max_hot {
+            let Some(victim) = self.access().oldest() else {
+                break;
+            };
+            let _guard = self.lock(&victim);
+            if self.move_out(victim)? {
+                self.stats.spilled.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage<String, Value> for TieredStorage {
+    fn get<Q>(&self, key: &Q) -> Result<Option<Arc<Value>>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let found = {
+            let _guard = self.lock(&key);
+            if let Some(value) = self.hot.get::<String>(&key)? {
+                self.stats.hot_hits.fetch_add(1, Ordering::Relaxed);
+                self.access().touch(&key);
+                Some(value)
+            } else if let Some(value) = self.fault_in(&key)? {
+                self.stats.cold_hits.fetch_add(1, Ordering::Relaxed);
+                self.access().touch(&key);
+                Some(Arc::new(value))
+            } else {
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        };
+        self.spill()?;
+        Ok(found)
+    }
+
+    fn set(&self, key: String, value: Value) -> Result<Option<Value>> {
+        let old = {
+            let _guard = self.lock(&key);
+            let cold = self.discard_cold(&key)?;
+            self.access().touch(&key);
+            self.versions.bump(key.clone());
+            self.hot.set(key, value)?.or(cold)
+        };
+        self.spill()?;
+        Ok(old)
+    }
+
+    fn insert_if_absent(&self, key: String, value: Value) -> Result<bool> {
+        let inserted = {
+            let _guard = self.lock(&key);
+            self.access().touch(&key);
+            self.fault_in(&key)?;
+            match self.hot.get(&key)? {
+                Some(_) => false,
+                None => {
+                    self.versions.bump(key.clone());
+                    self.hot.insert_if_absent(key, value)?
+                }
+            }
+        };
+        self.spill()?;
+        Ok(inserted)
+    }
+
+    fn update<F, R>(&self, key: String, f: F) -> Result<R>
+    where
+        F: FnOnce(Option<&mut Value>) -> (Update<Value>, R),
+    {
+        let result = {
+            let _guard = self.lock(&key);
+            self.fault_in(&key)?;
+            let mut present = false;
+            let mut written = false;
+            let result = self.hot.update(key.clone(), |value| {
+                let existed = value.is_some();
+                let (update, result) = f(value);
+                written = !matches!(update, Update::Keep);
+                present = match update {
+                    Update::Keep => existed,
+                    Update::Set(_) => true,
+                    Update::Delete => false,
+                };
+                (update, result)
+            })?;
+            if written && !present {
+                self.delete_cold(&key)?;
+            } else if written {
+                self.discard_cold(&key)?;
+            }
+            if present {
+                self.access().touch(&key);
+                self.versions.bump(key);
+            } else {
+                self.access().forget(&key);
+                self.versions.forget(&key);
+            }
+            result
+        };
+        self.spill()?;
+        Ok(result)
+    }
+
+    fn delete<Q>(&self, key: &Q) -> Result<Option<Value>>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        self.access().forget(&key);
+        self.versions.forget::<str>(&key);
+        let hot = self.hot.delete::<String>(&key)?;
+        let cold = self.delete_cold(&key)?;
+        Ok(hot.or(cold))
+    }
+
+    fn version<Q>(&self, key: &Q) -> Result<u64>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        self.current_version(&key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected_version: u64,
+        value: Value,
+    ) -> Result<Option<u64>> {
+        let version = {
+            let _guard = self.lock(&key);
+            if self.current_version(&key)? != expected_version {
+                return Ok(None);
+            }
+            self.discard_cold(&key)?;
+            self.access().touch(&key);
+            self.hot.set(key.clone(), value)?;
+            self.versions.bump(key)
+        };
+        self.spill()?;
+        Ok(Some(version))
+    }
+
+    fn compare_and_delete<Q>(&self, key: &Q, expected_version: u64) -> Result<bool>
+    where
+        String: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = String>,
+    {
+        let key = key.to_owned();
+        let _guard = self.lock(&key);
+        if self.current_version(&key)? != expected_version {
+            return Ok(false);
+        }
+        self.access().forget(&key);
+        self.versions.forget::<str>(&key);
+        let hot = self.hot.delete::<String>(&key)?;
+        let cold = self.delete_cold(&key)?;
+        Ok(hot.or(cold).is_some())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.hot.clear()?;
+        self.cold.clear()?;
+        self.versions.clear();
+        self.clean().clear();
+        *self.access() = AccessOrder::default();
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let clean = self.clean().clone();
+        let mut keys = self.hot.keys()?;
+        keys.retain(|key| !clean.contains(key));
+        keys.extend(self.cold.keys()?);
+        Ok(keys)
+    }
+
+    // Clean keys are in both tiers
+    fn len(&self) -> usize {
+        (self.hot.len() + self.cold.len()).saturating_sub(self.clean().len())
+    }
+
+    fn info(&self) -> Vec<(&'static str, u64)> {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        vec![
+            ("tier_hot_keys", self.hot.len() as u64),
+            ("tier_cold_keys", self.cold.len() as u64),
+            ("tier_hot_hits", load(&self.stats.hot_hits)),
+            ("tier_cold_hits", load(&self.stats.cold_hits)),
+            ("tier_misses", load(&self.stats.misses)),
+            ("tier_spilled_keys", load(&self.stats.spilled)),
+        ]
+    }
+
+    // Kept by the cold tier whichever tier the key is in
+    fn set_expiry(&self, key: &String, when: Option<u64>) -> Result<()> {
+        self.cold.set_expiry(key, when)
+    }
+
+    fn expiries(&self) -> Result<Vec<(String, u64)>> {
+        self.cold.expiries()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulk(s: &str) -> Value {
+        Value::str(s)
+    }
+
+    fn stat(storage: &TieredStorage, name: &str) -> u64 {
+        storage
+            .info()
+            .into_iter()
+            .find(|(field, _)| *field == name)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_spill_and_fault_in() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = TieredStorage::new(SledStorage::open(&db, "db0").unwrap(), 2);
+
+        for key in ["a", "b", "c"] {
+            storage.set(key.to_string(), bulk(key)).unwrap();
+        }
+        // "a" was least recently used and went to disk
+        assert_eq!(storage.len(), 3);
+        assert_eq!(stat(&storage, "tier_hot_keys"), 2);
+        assert_eq!(stat(&storage, "tier_spilled_keys"), 1);
+
+        // Reading it brings it back and pushes out "b", keeping its copy on
+        // disk
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("a"));
+        assert_eq!(stat(&storage, "tier_cold_hits"), 1);
+        assert_eq!(stat(&storage, "tier_cold_keys"), 2);
+        assert_eq!(storage.len(), 3);
+        let mut keys = storage.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, ["a", "b", "c"]);
+
+        // Writes find keys in either tier
+        assert!(!storage
+            .insert_if_absent("b".to_string(), bulk("x"))
+            .unwrap());
+        assert_eq!(*storage.get("b").unwrap().unwrap(), bulk("b"));
+        assert_eq!(storage.delete("c").unwrap(), Some(bulk("c")));
+        assert_eq!(storage.get("c").unwrap(), None);
+        assert_eq!(stat(&storage, "tier_misses"), 1);
+        assert_eq!(storage.len(), 2);
+    }
+
+    #[test]
+    fn test_read_keeps_cold_copy() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree("db0").unwrap();
+        let storage = TieredStorage::new(SledStorage::open(&db, "db0").unwrap(), 1);
+        storage.set("a".to_string(), bulk("a")).unwrap();
+        storage.set("b".to_string(), bulk("b")).unwrap();
+        storage.set("c".to_string(), bulk("c")).unwrap();
+
+        // Read back and gone without a flush, as in a crash: still on disk
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("a"));
+        drop(storage);
+        let storage = TieredStorage::new(SledStorage::open(&db, "db0").unwrap(), 1);
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("a"));
+
+        // Spilled again without being written to disk again
+        storage.get("b").unwrap();
+        assert_eq!(stat(&storage, "tier_spilled_keys"), 1);
+        assert_eq!(stat(&storage, "tier_cold_keys"), 3);
+
+        // A write drops the copy on disk, a delete drops both
+        storage.set("b".to_string(), bulk("x")).unwrap();
+        assert_eq!(stat(&storage, "tier_cold_keys"), 2);
+        assert_eq!(storage.delete("b").unwrap(), Some(bulk("x")));
+        storage
+            .update("a".to_string(), |_| (Update::Delete, ()))
+            .unwrap();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_versions_across_tiers() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = TieredStorage::new(SledStorage::open(&db, "db0").unwrap(), 1);
+
+        storage.set("a".to_string(), bulk("1")).unwrap();
+        let version = storage.version("a").unwrap();
+        // Spilled and faulted back in, but not changed
+        storage.set("b".to_string(), bulk("b")).unwrap();
+        assert_eq!(stat(&storage, "tier_cold_keys"), 1);
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("1"));
+        assert_eq!(storage.version("a").unwrap(), version);
+
+        let swapped = storage
+            .compare_and_swap("a".to_string(), version, bulk("2"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            storage
+                .compare_and_swap("a".to_string(), version, bulk("3"))
+                .unwrap(),
+            None
+        );
+        storage
+            .update("a".to_string(), |_| (Update::Set(bulk("4")), ()))
+            .unwrap();
+        assert_ne!(storage.version("a").unwrap(), swapped);
+        assert_eq!(*storage.get("a").unwrap().unwrap(), bulk("4"));
+
+        // b went cold when a was written
+        assert_eq!(
+            storage
+                .compare_and_swap("b".to_string(), 0, bulk("x"))
+                .unwrap(),
+            None
+        );
+    }
+}
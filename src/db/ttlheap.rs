@@ -0,0 +1,199 @@
+// Deadlines of volatile keys in order, so active expiry goes straight to the
+// keys that are due instead of sampling for them, and sleeps until the next
+// one. Timers are spread over min-heaps by key so setting TTLs doesn't
+// contend on one lock. A timer is never updated or removed in place: when a
+// key gets a new deadline a new timer is pushed, and the old one, like the
+// timer of a deleted key, is a tombstone the expirer recognises by its
+// deadline no longer being the key's and skips.
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+const SHARDS: usize = 16;
+
+struct Timer<K> {
+    when: u64,
+    key: K,
+}
+
+// Ordered by deadline alone, reversed so the heap's top is the earliest
+impl<K> Ord for Timer<K> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.when.cmp(&self.when)
+    }
+}
+
+impl<K> PartialOrd for Timer<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K> PartialEq for Timer<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.when == other.when
+    }
+}
+
+impl<K> Eq for Timer<K> {}
+
+pub struct TtlHeap<K> {
+    shards: Vec<Mutex<BinaryHeap<Timer<K>>>>,
+    // Timers held, tombstones included
+    len: AtomicUsize,
+    // No later than the earliest timer, u64::MAX when there is none
+    earliest: AtomicU64,
+    // Told of a timer earlier than any before it
+    sooner: Notify,
+}
+
+impl<K> Default for TtlHeap<K> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(BinaryHeap::new())).collect(),
+            len: AtomicUsize::new(0),
+            earliest: AtomicU64::new(u64::MAX),
+            sooner: Notify::new(),
+        }
+    }
+}
+
+impl<K> TtlHeap<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn shard(&self, key: &K) -> &Mutex<BinaryHeap<Timer<K>>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // Schedules key for when, leaving any timer it had as a tombstone
+    pub fn push(&self, key: K, when: u64) {
+        self.shard(&key)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Timer { when, key });
+        self.len.fetch_add(1, Ordering::Relaxed);
+        if when < self.earliest.fetch_min(when, Ordering::SeqCst) {
+            self.sooner.notify_one();
+        }
+    }
+
+    // Takes up to max timers due at now, tombstones included
+    pub fn pop_due(&self, now: u64, max: usize) -> Vec<(K, u64)> {
+        let mut due = Vec::new();
+        for shard in &self.shards {
+            let mut heap = shard.lock().unwrap_or_else(|e| e.into_inner());
+            while due.len() < max && heap.peek().is_some_and(|timer| timer.when <= now) {
+                let timer = heap.pop().expect("peeked timer");
+                due.push((timer.key, timer.when));
+            }
+        }
+        self.len.fetch_sub(due.len(), Ordering::Relaxed);
+        self.earliest
+            .store(self.next().unwrap_or(u64::MAX), Ordering::SeqCst);
+        due
+    }
+
+    // Deadline of the earliest timer, which may be a tombstone
+    pub fn next(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| {
+                let heap = shard.lock().unwrap_or_else(|e| e.into_inner());
+                heap.peek().map(|timer| timer.when)
+            })
+            .min()
+    }
+
+    // Resolves once a timer earlier than all others is pushed
+    pub fn sooner(&self) -> Notified<'_> {
+        self.sooner.notified()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Drops the timers live rejects, the tombstones
+    pub fn retain<F>(&self, mut live: F)
+    where
+        F: FnMut(&K, u64) -> bool,
+    {
+        for shard in &self.shards {
+            let mut heap = shard.lock().unwrap_or_else(|e| e.into_inner());
+            let before = heap.len();
+            heap.retain(|timer| live(&timer.key, timer.when));
+            self.len.fetch_sub(before - heap.len(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut heap = shard.lock().unwrap_or_else(|e| e.into_inner());
+            self.len.fetch_sub(heap.len(), Ordering::Relaxed);
+            heap.clear();
+        }
+        self.earliest.store(u64::MAX, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ttl_heap() {
+        let heap = TtlHeap::default();
+        assert_eq!(heap.next(), None);
+        for i in (0..100u64).rev() {
+            heap.push(format!("key{}", i), 1000 + i);
+        }
+        // A new deadline leaves the old timer behind
+        heap.push("key0".to_string(), 5000);
+        assert_eq!(heap.len(), 101);
+        assert_eq!(heap.next(), Some(1000));
+
+        assert!(heap.pop_due(999, 100).is_empty());
+        let due = heap.pop_due(1009, 100);
+        assert_eq!(due.len(), 10);
+        assert!(due.iter().all(|(_, when)| *when <= 1009));
+        assert_eq!(heap.next(), Some(1010));
+        assert_eq!(heap.pop_due(2000, 50).len(), 50);
+        assert_eq!(heap.len(), 41);
+
+        heap.retain(|_, when| when != 5000);
+        assert_eq!(heap.len(), 40);
+        let rest = heap.pop_due(u64::MAX, usize::MAX);
+        assert_eq!(rest.len(), 40);
+        assert!(rest.iter().all(|(key, _)| key != "key0"));
+        assert!(heap.is_empty());
+        assert_eq!(heap.next(), None);
+    }
+
+    #[tokio::test]
+    async fn test_sooner() {
+        let heap = TtlHeap::default();
+        heap.push("late".to_string(), 2000);
+        let woken = tokio::time::timeout(Duration::from_millis(20), heap.sooner()).await;
+        assert!(woken.is_ok());
+        // Only an earlier timer wakes the expirer again
+        heap.push("later".to_string(), 3000);
+        let woken = tokio::time::timeout(Duration::from_millis(20), heap.sooner()).await;
+        assert!(woken.is_err());
+        heap.push("soon".to_string(), 1000);
+        let woken = tokio::time::timeout(Duration::from_millis(20), heap.sooner()).await;
+        assert!(woken.is_ok());
+    }
+}
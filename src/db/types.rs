@@ -0,0 +1,128 @@
+// Type tags of stored values. Commands of one data type ask the DB for a key
+// of that type and get WrongTypeError for anything else, instead of each
+// command matching on the stored representation itself.
+use crate::db::value::Value;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    List,
+    Set,
+    ZSet,
+    Hash,
+    Stream,
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl ValueType {
+    // Name as TYPE reports it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::List => "list",
+            Self::Set => "set",
+            Self::ZSet => "zset",
+            Self::Hash => "hash",
+            Self::Stream => "stream",
+            // As RedisJSON names its type
+            #[cfg(feature = "json")]
+            Self::Json => "ReJSON-RL",
+        }
+    }
+
+    // Type named as TYPE reports it, in any case
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::String,
+            Self::List,
+            Self::Set,
+            Self::ZSet,
+            Self::Hash,
+            Self::Stream,
+            #[cfg(feature = "json")]
+            Self::Json,
+        ]
+        .into_iter()
+        .find(|kind| kind.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub trait Typed {
+    fn value_type(&self) -> ValueType;
+}
+
+impl Typed for Value {
+    fn value_type(&self) -> ValueType {
+        match self {
+            Value::Str(_) => ValueType::String,
+            Value::List(_) => ValueType::List,
+            Value::Set(_) => ValueType::Set,
+            Value::Hash(_) => ValueType::Hash,
+            // Sorted sets, geo indexes included
+            Value::ZSet(_) => ValueType::ZSet,
+            Value::Stream(_) => ValueType::Stream,
+            #[cfg(feature = "json")]
+            Value::Json(_) => ValueType::Json,
+            Value::Listpack(packed) => packed.value_type(),
+            Value::Compressed(_) => ValueType::String,
+        }
+    }
+}
+
+impl Typed for String {
+    fn value_type(&self) -> ValueType {
+        ValueType::String
+    }
+}
+
+// The key holds a value of another type than the operation expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongTypeError;
+
+impl fmt::Display for WrongTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operation against a key holding the wrong kind of value")
+    }
+}
+
+impl Error for WrongTypeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::listpack::{compact, ListpackLimits};
+    use crate::db::sortedset::SortedSet;
+
+    #[test]
+    fn test_value_types() {
+        let limits = ListpackLimits::default();
+        for (value, expected) in [
+            (Value::str("v"), ValueType::String),
+            (Value::List(vec!["a".to_string()].into()), ValueType::List),
+            (Value::Set(["a".to_string()].into()), ValueType::Set),
+            (
+                Value::Hash([("f".to_string(), "v".to_string())].into()),
+                ValueType::Hash,
+            ),
+            (Value::ZSet(SortedSet::new()), ValueType::ZSet),
+            (Value::Stream(vec![]), ValueType::Stream),
+            #[cfg(feature = "json")]
+            (Value::Json(serde_json::json!({"a": 1})), ValueType::Json),
+        ] {
+            assert_eq!(value.value_type(), expected);
+            // The compact encoding keeps the type of the full form
+            if let Some(packed) = compact(&value, &limits) {
+                assert_eq!(packed.value_type(), expected);
+            }
+        }
+    }
+}
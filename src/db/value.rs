@@ -0,0 +1,160 @@
+// Values as the db stores them, apart from how they go over the wire.
+// Commands work on these and build their replies from them; to_resp and
+// from_resp convert to and from the RESP layout values used to be stored
+// in, for DUMP-like paths and tests.
+use crate::db::bytestring::ByteString;
+use crate::db::compression::Compressed;
+use crate::db::listpack::Listpack;
+use crate::db::quicklist::QuickList;
+use crate::db::sortedset::SortedSet;
+use indexmap::{IndexMap, IndexSet};
+use std::borrow::Cow;
+use stream_resp::resp::RespValue;
+
+// An entry of a stream: its ID as milliseconds and sequence, and its fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: (u64, u64),
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    // Any bytes
+    Str(Vec<u8>),
+    List(QuickList),
+    // Fields and their values, indexable like sets
+    Hash(IndexMap<String, String>),
+    // Members in insertion order, up to removals, so one can be picked by
+    // index
+    Set(IndexSet<String>),
+    // Members and scores, ordered by score and then member
+    ZSet(SortedSet),
+    // Entries in ID order
+    Stream(Vec<StreamEntry>),
+    // A JSON document, with the json feature
+    #[cfg(feature = "json")]
+    Json(serde_json::Value),
+    // A small hash, list or set in its compact form (see listpack.rs)
+    Listpack(Listpack),
+    // A large string kept compressed (see compression.rs)
+    Compressed(Compressed),
+}
+
+fn bulk(s: &str) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s.to_string())))
+}
+
+fn text(item: &RespValue) -> Option<String> {
+    match item {
+        RespValue::BulkString(Some(s)) | RespValue::SimpleString(s) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+impl Value {
+    pub fn str(s: impl Into<String>) -> Self {
+        Self::Str(s.into().into_bytes())
+    }
+
+    // The RESP layout of the value: strings as bulk strings, lists as
+    // arrays, sets as sets, hashes as maps, sorted sets as a push of
+    // alternating members and scores, and streams as XRANGE replies them
+    pub fn to_resp(&self) -> RespValue<'static> {
+        match self {
+            Self::Str(_) | Self::Compressed(_) => ByteString::from_stored(self)
+                .map_or(RespValue::BulkString(None), |string| string.to_reply()),
+            Self::Listpack(packed) => packed.expand().to_resp(),
+            Self::List(items) => RespValue::Array(Some(items.iter().map(|s| bulk(s)).collect())),
+            Self::Set(items) => RespValue::Set(Some(items.iter().map(|s| bulk(s)).collect())),
+            Self::Hash(pairs) => RespValue::Map(Some(
+                pairs
+                    .iter()
+                    .map(|(field, value)| (bulk(field), bulk(value)))
+                    .collect(),
+            )),
+            Self::ZSet(pairs) => RespValue::Push(Some(
+                pairs
+                    .iter()
+                    .flat_map(|(member, score)| [bulk(member), RespValue::Double(*score)])
+                    .collect(),
+            )),
+            Self::Stream(entries) => RespValue::Array(Some(
+                entries
+                    .iter()
+                    .map(|entry| {
+                        let fields = entry
+                            .fields
+                            .iter()
+                            .flat_map(|(field, value)| [bulk(field), bulk(value)])
+                            .collect();
+                        RespValue::Array(Some(vec![
+                            bulk(&format!("{}-{}", entry.id.0, entry.id.1)),
+                            RespValue::Array(Some(fields)),
+                        ]))
+                    })
+                    .collect(),
+            )),
+            // Serialized, as JSON.GET replies it
+            #[cfg(feature = "json")]
+            Self::Json(doc) => bulk(&doc.to_string()),
+        }
+    }
+
+    // The value in the layout to_resp gives, None for anything else
+    pub fn from_resp(value: &RespValue) -> Option<Self> {
+        let strings = |items: &[RespValue]| items.iter().map(text).collect::<Option<Vec<_>>>();
+        Some(match value {
+            RespValue::BulkString(Some(s)) => Self::str(s.to_string()),
+            RespValue::Array(Some(items)) => Self::List(strings(items)?.into()),
+            RespValue::Set(Some(items)) => Self::Set(strings(items)?.into_iter().collect()),
+            RespValue::Map(Some(pairs)) => Self::Hash(
+                pairs
+                    .iter()
+                    .map(|(field, value)| Some((text(field)?, text(value)?)))
+                    .collect::<Option<_>>()?,
+            ),
+            RespValue::Push(Some(flat)) => Self::ZSet(
+                flat.chunks(2)
+                    .map(|pair| match pair {
+                        [member, RespValue::Double(score)] => Some((text(member)?, *score)),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resp_round_trip() {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        for value in [
+            Value::str("hello"),
+            Value::List(strings(&["a", "b"]).into()),
+            Value::Set(strings(&["x"]).into_iter().collect()),
+            Value::Hash([("f".to_string(), "v".to_string())].into()),
+            Value::ZSet(vec![("m".to_string(), 1.5)].into()),
+        ] {
+            assert_eq!(Value::from_resp(&value.to_resp()), Some(value));
+        }
+
+        let stream = Value::Stream(vec![StreamEntry {
+            id: (1, 0),
+            fields: vec![("f".to_string(), "v".to_string())],
+        }]);
+        assert_eq!(
+            stream.to_resp(),
+            RespValue::Array(Some(vec![RespValue::Array(Some(vec![
+                bulk("1-0"),
+                RespValue::Array(Some(vec![bulk("f"), bulk("v")])),
+            ]))]))
+        );
+        assert_eq!(Value::from_resp(&RespValue::Integer(1)), None);
+    }
+}
@@ -0,0 +1,202 @@
+// In-process handle on a set of databases, for applications that want
+// foobar_db as a cache without the TCP server. Everything goes through the
+// same Command parsing and execution as the network path.
+use crate::db::clock::Clock;
+use crate::db::db::{Databases, DB};
+use crate::db::expire::{EXPIRE_CYCLE_BUDGET, EXPIRE_MAX_SLEEP};
+use crate::db::storage::DashMapStorage;
+use crate::db::value::Value;
+use crate::protocal::command::{Command, CommandError, ExecContext};
+use anyhow::{anyhow, Error};
+use std::borrow::Cow;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use stream_resp::resp::RespValue;
+use tracing::error;
+
+type Storage = DashMapStorage<String, Value>;
+
+const DEFAULT_DATABASES: usize = 16;
+
+#[derive(Clone)]
+pub struct FoobarDb {
+    dbs: Databases<Storage, String, Value>,
+    db_index: usize,
+}
+
+impl FoobarDb {
+    pub fn open() -> Self {
+        Self::with_databases(DEFAULT_DATABASES)
+    }
+
+    pub fn with_databases(databases: usize) -> Self {
+        let clock = Arc::new(Clock::default());
+        let dbs = (0..databases.max(1))
+            .map(|_| Arc::new(DB::new(DashMapStorage::new(), 64).with_clock(clock.clone())))
+            .collect();
+        let handle = Self {
+            dbs: Arc::new(dbs),
+            db_index: 0,
+        };
+        // Opened in a tokio runtime, keys nobody reads again are expired in
+        // the background. Elsewhere that is up to the application, calling
+        // active_expire.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(expire_task(Arc::downgrade(&handle.dbs)));
+        }
+        handle
+    }
+
+    // One active expiry cycle over every database, dropping keys whose
+    // deadline passed. Returns how soon the next deadline comes, None when
+    // no key has one.
+    pub fn active_expire(&self) -> Result<Option<Duration>, Error> {
+        expire_cycle(&self.dbs)
+    }
+
+    // Handle on another logical database sharing the same data
+    pub fn select(&self, db_index: usize) -> Result<Self, Error> {
+        if db_index >= self.dbs.len() {
+            return Err(anyhow!(CommandError::DbIndexOutOfRange));
+        }
+        Ok(Self {
+            dbs: self.dbs.clone(),
+            db_index,
+        })
+    }
+
+    // Runs any command, e.g. execute(&["LPUSH", "list", "a"])
+    pub async fn execute(&self, args: &[&str]) -> Result<Arc<RespValue<'static>>, Error> {
+        let args = args
+            .iter()
+            .map(|arg| RespValue::BulkString(Some(Cow::Owned(arg.to_string()))))
+            .collect();
+        let cmd = Command::from_resp(RespValue::Array(Some(args)))?;
+        cmd.exec(ExecContext::new(self.dbs.clone(), self.db_index))
+            .await
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        match &*self.execute(&["GET", key]).await? {
+            RespValue::BulkString(Some(value)) => Ok(Some(value.to_string())),
+            RespValue::BulkString(None) => Ok(None),
+            _ => Err(anyhow!(CommandError::WrongType)),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<(), Error> {
+        self.execute(&["SET", key, value]).await.map(|_| ())
+    }
+
+    // Number of keys removed
+    pub async fn del(&self, keys: &[&str]) -> Result<usize, Error> {
+        let args: Vec<&str> = std::iter::once("DEL").chain(keys.iter().copied()).collect();
+        integer(&*self.execute(&args).await?).map(|n| n as usize)
+    }
+
+    // False when the key does not exist
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, Error> {
+        let ms = ttl.as_millis().to_string();
+        integer(&*self.execute(&["PEXPIRE", key, &ms]).await?).map(|n| n == 1)
+    }
+
+    // None when the key does not exist or never expires
+    pub async fn ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        let ms = integer(&*self.execute(&["PTTL", key]).await?)?;
+        Ok((ms >= 0).then(|| Duration::from_millis(ms as u64)))
+    }
+
+    pub async fn persist(&self, key: &str) -> Result<bool, Error> {
+        integer(&*self.execute(&["PERSIST", key]).await?).map(|n| n == 1)
+    }
+}
+
+impl Default for FoobarDb {
+    fn default() -> Self {
+        Self::open()
+    }
+}
+
+fn expire_cycle(dbs: &[Arc<DB<Storage, String, Value>>]) -> Result<Option<Duration>, Error> {
+    let deadline = Instant::now() + EXPIRE_CYCLE_BUDGET;
+    for db in dbs {
+        // Nothing else holds keys here
+        if db.active_expire(deadline, |_| Some(()))? {
+            break;
+        }
+    }
+    let now = dbs[0].clock().now_ms();
+    Ok(dbs
+        .iter()
+        .filter_map(|db| db.next_expiry())
+        .min()
+        .map(|next| Duration::from_millis(next.saturating_sub(now))))
+}
+
+// Runs a cycle whenever the next deadline comes or a sooner one is set,
+// until every handle on dbs is dropped
+async fn expire_task(dbs: Weak<Vec<Arc<DB<Storage, String, Value>>>>) {
+    while let Some(dbs) = dbs.upgrade() {
+        let due = expire_cycle(&dbs).unwrap_or_else(|e| {
+            error!("Active expiry failed: {}", e);
+            None
+        });
+        let wait = due
+            .unwrap_or(EXPIRE_MAX_SLEEP)
+            .clamp(Duration::from_millis(1), EXPIRE_MAX_SLEEP);
+        let sooner = futures::future::select_all(dbs.iter().map(|db| Box::pin(db.expiry_sooner())));
+        let _ = tokio::time::timeout(wait, sooner).await;
+    }
+}
+
+fn integer(reply: &RespValue) -> Result<i64, Error> {
+    match reply {
+        RespValue::Integer(n) => Ok(*n),
+        other => Err(anyhow!("unexpected reply {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_embedded() {
+        let db = FoobarDb::open();
+        db.set("a", "1").await.unwrap();
+        assert_eq!(db.get("a").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(db.get("missing").await.unwrap(), None);
+
+        assert!(db.expire("a", Duration::from_secs(60)).await.unwrap());
+        assert!(db.ttl("a").await.unwrap().unwrap() > Duration::from_secs(50));
+        assert!(db.persist("a").await.unwrap());
+        assert_eq!(db.ttl("a").await.unwrap(), None);
+
+        // Databases are separate, handles on the same one share data
+        let other = db.select(1).unwrap();
+        assert_eq!(other.get("a").await.unwrap(), None);
+        assert!(db.select(16).is_err());
+
+        db.execute(&["LPUSH", "list", "x"]).await.unwrap();
+        assert!(db.get("list").await.is_err());
+
+        assert_eq!(db.del(&["a", "list", "missing"]).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_active_expire() {
+        let db = FoobarDb::open();
+        assert_eq!(db.active_expire().unwrap(), None);
+        db.set("a", "1").await.unwrap();
+        db.expire("a", Duration::from_millis(10)).await.unwrap();
+        db.set("b", "1").await.unwrap();
+        db.expire("b", Duration::from_secs(60)).await.unwrap();
+
+        // Dropped without being read again
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(db.dbs[0].len(), 1);
+
+        let due = db.active_expire().unwrap().unwrap();
+        assert!(due > Duration::from_secs(50));
+    }
+}
@@ -1,3 +1,11 @@
+#[cfg(feature = "client")]
+pub mod client;
 pub mod db;
+pub mod embed;
 pub mod protocal;
+#[cfg(feature = "client")]
+pub mod sentinel;
 pub mod server;
+pub mod test_util;
+
+pub use embed::FoobarDb;
@@ -1,58 +1,612 @@
-use crate::db::db::DB;
-use crate::db::storage::Storage;
+use crate::db::backend;
+use crate::db::bytestring::{self, ByteString};
+use crate::db::compression::{self, Compression};
+use crate::db::db::{Databases, ExpireCondition, Expiry, TtlUpdate, DB};
+use crate::db::dump;
+use crate::db::hotkeys::HOTKEYS_MAX;
+use crate::db::listpack::{self, ListpackLimits};
+use crate::db::storage::{Storage, Update};
+use crate::db::types::{Typed, ValueType, WrongTypeError};
+use crate::db::value::Value;
+use crate::protocal::glob::{self, glob_match};
+use crate::protocal::index::resolve_range;
+#[cfg(feature = "json")]
+use crate::protocal::json::{self, JsonPath, SetCondition};
+use crate::protocal::migrate::{self, MigrateTarget};
+use crate::protocal::{geo, hash, list, reply, set, slot, sort, table, zset};
+use crate::server::bigkeys;
+use crate::server::clients::ClientRegistry;
+use crate::server::cmdstats::CommandStats;
+use crate::server::keylock::KeyLocks;
+use crate::server::latency::LatencyMonitor;
+use crate::server::loading::Loading;
+use crate::server::namespace::Namespaces;
+use crate::server::panics::Panics;
+use crate::server::propagate::{self, Propagator};
+use crate::server::pubsub::{Kind, PubSub};
+use crate::server::ratelimit::RateLimiter;
+use crate::server::replication::Replication;
+#[cfg(feature = "search")]
+use crate::server::search::{IndexSchema, Query, Search};
+use crate::server::shutdown::{SaveMode, Shutdown};
+use crate::server::tasks::TaskManager;
+use crate::server::timeout::CommandTimeout;
+use crate::server::tracking::{Tracking, TrackingOptions};
 use anyhow::{anyhow, Error};
+use bytes::BytesMut;
+use macros::CommandTable;
 use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::Duration;
 use stream_resp::resp::RespValue;
+use tracing::{debug, error, info};
 
-#[derive(Debug, PartialEq)]
+// Same 512MB cap Redis puts on string values
+const MAX_STRING_LENGTH: usize = 512 * 1024 * 1024;
+
+// Version reported as redis_version, client libraries gate features on it
+const REDIS_COMPAT_VERSION: &str = "7.2.0";
+
+// Keys HOTKEYS lists when no count is given
+const HOTKEYS_DEFAULT: usize = 10;
+
+// Random patterns DEBUG STRINGMATCH-LEN tries, as many as Redis
+const STRINGMATCH_FUZZ_ROUNDS: usize = 1000;
+
+#[derive(Debug, PartialEq, CommandTable)]
 pub enum Command {
+    #[command(name = "get", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "string", summary = "Returns the string value of a key.", parse)]
     Get {
         key: String,
     },
+    #[command(name = "set", arity = -3, flags = WRITE_OOM, keys = ONE_KEY, group = "string", summary = "Sets the string value of a key.")]
     Set {
         key: String,
         value: String,
     },
+    #[command(name = "setex", arity = 4, flags = WRITE_OOM, keys = ONE_KEY, group = "string", summary = "Sets the value and expiration in seconds of a key.")]
+    SetEx {
+        key: String,
+        seconds: i64,
+        value: String,
+    },
+    #[command(name = "psetex", arity = 4, flags = WRITE_OOM, keys = ONE_KEY, group = "string", summary = "Sets the value and expiration in milliseconds of a key.")]
+    PSetEx {
+        key: String,
+        milliseconds: i64,
+        value: String,
+    },
+    #[command(name = "setrange", arity = 4, flags = WRITE_OOM, keys = ONE_KEY, group = "string", summary = "Overwrites part of a string value from an offset.", parse)]
+    SetRange {
+        key: String,
+        offset: i64,
+        value: String,
+    },
+    // GETRANGE and SUBSTR, start/stop are byte offsets
+    #[command(name = "getrange", arity = 4, flags = READ, keys = ONE_KEY, group = "string", summary = "Returns a substring of the string stored at a key.", parse)]
+    #[command(name = "substr", arity = 4, flags = READ, keys = ONE_KEY, group = "string", summary = "Returns a substring from a string value.", parse)]
+    GetRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    #[command(name = "getex", arity = -2, flags = WRITE_FAST, keys = ONE_KEY, group = "string", summary = "Returns the value of a key and sets or clears its expiration.")]
+    GetEx {
+        key: String,
+        option: GetExOption,
+    },
+    #[command(name = "getdel", arity = 2, flags = WRITE_FAST, keys = ONE_KEY, group = "string", summary = "Returns the value of a key and deletes it.", parse)]
+    GetDel {
+        key: String,
+    },
+    #[command(name = "strlen", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "string", summary = "Returns the length of a string value.", parse)]
+    StrLen {
+        key: String,
+    },
+    // INCR, INCRBY, DECR and DECRBY
+    #[command(name = "incr", arity = 2, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "string", summary = "Increments the integer value of a key by one.")]
+    #[command(name = "incrby", arity = 3, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "string", summary = "Increments the integer value of a key by a number.")]
+    #[command(name = "decr", arity = 2, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "string", summary = "Decrements the integer value of a key by one.")]
+    #[command(name = "decrby", arity = 3, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "string", summary = "Decrements a number from the integer value of a key.")]
+    IncrBy {
+        key: String,
+        increment: i64,
+    },
+    #[command(name = "del", arity = -2, flags = WRITE, keys = ALL_KEYS, group = "generic", summary = "Deletes one or more keys.", parse)]
     Del {
         keys: Vec<String>,
     },
+    #[command(name = "unlink", arity = -2, flags = WRITE_FAST, keys = ALL_KEYS, group = "generic", summary = "Deletes one or more keys, freeing large values in the background.", parse)]
+    Unlink {
+        keys: Vec<String>,
+    },
+    #[command(name = "touch", arity = -2, flags = READ_FAST, keys = ALL_KEYS, group = "generic", summary = "Returns the number of existing keys out of those specified after updating the time they were last accessed.", parse)]
+    Touch {
+        keys: Vec<String>,
+    },
+    #[command(name = "copy", arity = -3, flags = WRITE_OOM, keys = TWO_KEYS, group = "generic", summary = "Copies the value of a key to a new key.")]
+    Copy {
+        source: String,
+        destination: String,
+        db: Option<usize>,
+        replace: bool,
+    },
+    #[command(name = "move", arity = 3, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Moves a key to another database.")]
+    Move {
+        key: String,
+        db: usize,
+    },
+    #[command(name = "dump", arity = 2, flags = READ, keys = ONE_KEY, group = "generic", summary = "Returns a serialized representation of the value stored at a key.", parse)]
+    Dump {
+        key: String,
+    },
+    // RESTORE key ttl payload [REPLACE] [ABSTTL]
+    #[command(name = "restore", arity = -4, flags = WRITE_OOM, keys = ONE_KEY, group = "generic", summary = "Creates a key from the serialized representation of a value.")]
+    Restore {
+        key: String,
+        ttl: i64,
+        payload: String,
+        replace: bool,
+        absttl: bool,
+    },
+    // MIGRATE host port key|"" db timeout [COPY] [REPLACE] [KEYS key...]
+    #[command(name = "migrate", arity = -6, flags = WRITE, keys = (3, 3, 1), group = "generic", summary = "Atomically transfers a key from one instance to another.")]
+    Migrate {
+        target: MigrateTarget,
+        keys: Vec<String>,
+        copy: bool,
+        replace: bool,
+    },
+    #[command(name = "select", arity = 2, flags = ADMIN.union(F::FAST), keys = NO_KEYS, group = "connection", summary = "Changes the selected database.")]
+    Select {
+        db: usize,
+    },
+    #[command(name = "reset", arity = 1, flags = CONN, keys = NO_KEYS, group = "connection", summary = "Resets the connection.", parse)]
+    Reset,
+    // HELLO [protover [AUTH username password]]
+    #[command(name = "hello", arity = -1, flags = CONN, keys = NO_KEYS, group = "connection", summary = "Handshakes with the server.")]
+    Hello {
+        protover: Option<i64>,
+        auth: Option<(String, String)>,
+    },
+    // AUTH [username] password, the default user when none is given
+    #[command(name = "auth", arity = -2, flags = CONN, keys = NO_KEYS, group = "connection", summary = "Authenticates the connection.")]
+    Auth {
+        user: String,
+        password: String,
+    },
+
+    #[command(name = "expire", arity = -3, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Sets the expiration time of a key in seconds.")]
+    Expire {
+        key: String,
+        seconds: i64,
+        condition: ExpireCondition,
+    },
+    #[command(name = "pexpire", arity = -3, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Sets the expiration time of a key in milliseconds.")]
+    PExpire {
+        key: String,
+        milliseconds: i64,
+        condition: ExpireCondition,
+    },
+    #[command(name = "expireat", arity = -3, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Sets the expiration time of a key to a unix timestamp.")]
+    ExpireAt {
+        key: String,
+        timestamp: i64,
+        condition: ExpireCondition,
+    },
+    #[command(name = "pexpireat", arity = -3, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Sets the expiration time of a key to a unix milliseconds timestamp.")]
+    PExpireAt {
+        key: String,
+        timestamp: i64,
+        condition: ExpireCondition,
+    },
+    #[command(name = "persist", arity = 2, flags = WRITE_FAST, keys = ONE_KEY, group = "generic", summary = "Removes the expiration time of a key.", parse)]
+    Persist {
+        key: String,
+    },
+    #[command(name = "ttl", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "generic", summary = "Returns the expiration time in seconds of a key.", parse)]
+    Ttl {
+        key: String,
+    },
+    #[command(name = "pttl", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "generic", summary = "Returns the expiration time in milliseconds of a key.", parse)]
+    PTtl {
+        key: String,
+    },
+    #[command(name = "expiretime", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "generic", summary = "Returns the expiration time of a key as a unix timestamp.", parse)]
+    ExpireTime {
+        key: String,
+    },
+    #[command(name = "pexpiretime", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "generic", summary = "Returns the expiration time of a key as a unix milliseconds timestamp.", parse)]
+    PExpireTime {
+        key: String,
+    },
+    #[command(name = "type", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "generic", summary = "Determines the type of value stored at a key.", parse)]
+    Type {
+        key: String,
+    },
 
+    #[command(name = "lpush", arity = -3, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "list", summary = "Prepends one or more elements to a list.", parse)]
     LPush {
         key: String,
         values: Vec<String>,
     },
+    #[command(name = "rpush", arity = -3, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "list", summary = "Appends one or more elements to a list.", parse)]
     RPush {
         key: String,
         values: Vec<String>,
     },
+    #[command(name = "lpop", arity = -2, flags = WRITE_FAST, keys = ONE_KEY, group = "list", summary = "Returns and removes the first elements of a list.")]
     LPop {
         key: String,
+        count: Option<usize>,
     },
+    #[command(name = "rpop", arity = -2, flags = WRITE_FAST, keys = ONE_KEY, group = "list", summary = "Returns and removes the last elements of a list.")]
     RPop {
         key: String,
+        count: Option<usize>,
+    },
+    #[command(name = "llen", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "list", summary = "Returns the length of a list.", parse)]
+    LLen {
+        key: String,
+    },
+    #[command(name = "lrange", arity = 4, flags = READ, keys = ONE_KEY, group = "list", summary = "Returns a range of elements from a list.", parse)]
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    #[command(name = "lindex", arity = 3, flags = READ, keys = ONE_KEY, group = "list", summary = "Returns an element from a list by its index.", parse)]
+    LIndex {
+        key: String,
+        index: i64,
+    },
+    #[command(name = "lpos", arity = -3, flags = READ, keys = ONE_KEY, group = "list", summary = "Returns the index of matching elements in a list.")]
+    LPos {
+        key: String,
+        element: String,
+        options: LPosOptions,
+    },
+    #[command(name = "linsert", arity = 5, flags = WRITE_OOM, keys = ONE_KEY, group = "list", summary = "Inserts an element before or after another element in a list.")]
+    LInsert {
+        key: String,
+        before: bool,
+        pivot: String,
+        element: String,
+    },
+    #[command(name = "lset", arity = 4, flags = WRITE_OOM, keys = ONE_KEY, group = "list", summary = "Sets the value of an element in a list by its index.", parse)]
+    LSet {
+        key: String,
+        index: i64,
+        element: String,
+    },
+    #[command(name = "lrem", arity = 4, flags = WRITE, keys = ONE_KEY, group = "list", summary = "Removes elements from a list.", parse)]
+    LRem {
+        key: String,
+        count: i64,
+        element: String,
+    },
+    #[command(name = "ltrim", arity = 4, flags = WRITE, keys = ONE_KEY, group = "list", summary = "Removes elements from both ends of a list.", parse)]
+    LTrim {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    // RPOPLPUSH parses to LMove { from_left: false, to_left: true }
+    #[command(name = "rpoplpush", arity = 3, flags = WRITE_OOM, keys = TWO_KEYS, group = "list", summary = "Moves the last element of a list to the head of another.")]
+    #[command(name = "lmove", arity = 5, flags = WRITE_OOM, keys = TWO_KEYS, group = "list", summary = "Pops an element from a list and pushes it to another.")]
+    LMove {
+        source: String,
+        destination: String,
+        from_left: bool,
+        to_left: bool,
+    },
+    #[command(name = "blmove", arity = 6, flags = WRITE_OOM.union(F::BLOCKING), keys = TWO_KEYS, group = "list", summary = "Pops an element from a list, pushes it to another, and blocks until one is available.")]
+    BLMove {
+        source: String,
+        destination: String,
+        from_left: bool,
+        to_left: bool,
+        timeout: Option<Duration>,
     },
 
+    #[command(name = "sadd", arity = -3, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "set", summary = "Adds one or more members to a set.", parse)]
     SAdd {
         key: String,
         members: Vec<String>,
     },
+    #[command(name = "srem", arity = -3, flags = WRITE_FAST, keys = ONE_KEY, group = "set", summary = "Removes one or more members from a set.", parse)]
     SRem {
         key: String,
         members: Vec<String>,
     },
+    #[command(name = "smembers", arity = 2, flags = READ, keys = ONE_KEY, group = "set", summary = "Returns all members of a set.", parse)]
+    SMembers {
+        key: String,
+    },
+    #[command(name = "spop", arity = -2, flags = WRITE_FAST, keys = ONE_KEY, group = "set", summary = "Returns and removes random members of a set.")]
+    SPop {
+        key: String,
+        count: Option<usize>,
+    },
+    #[command(name = "srandmember", arity = -2, flags = READ, keys = ONE_KEY, group = "set", summary = "Returns random members of a set.")]
+    SRandMember {
+        key: String,
+        count: Option<i64>,
+    },
+    #[command(name = "sinter", arity = -2, flags = READ, keys = ALL_KEYS, group = "set", summary = "Returns the intersect of multiple sets.")]
+    #[command(name = "sunion", arity = -2, flags = READ, keys = ALL_KEYS, group = "set", summary = "Returns the union of multiple sets.")]
+    #[command(name = "sdiff", arity = -2, flags = READ, keys = ALL_KEYS, group = "set", summary = "Returns the difference of multiple sets.")]
+    SAlgebra {
+        op: SetOp,
+        keys: Vec<String>,
+    },
+    #[command(name = "sinterstore", arity = -3, flags = WRITE_OOM, keys = ALL_KEYS, group = "set", summary = "Stores the intersect of multiple sets in a key.")]
+    #[command(name = "sunionstore", arity = -3, flags = WRITE_OOM, keys = ALL_KEYS, group = "set", summary = "Stores the union of multiple sets in a key.")]
+    #[command(name = "sdiffstore", arity = -3, flags = WRITE_OOM, keys = ALL_KEYS, group = "set", summary = "Stores the difference of multiple sets in a key.")]
+    SAlgebraStore {
+        op: SetOp,
+        destination: String,
+        keys: Vec<String>,
+    },
+    // Stops counting at limit, 0 for no limit
+    #[command(name = "sintercard", arity = -3, flags = READ, keys = NO_KEYS, group = "set", summary = "Returns the number of members of the intersect of multiple sets.")]
+    SInterCard {
+        keys: Vec<String>,
+        limit: usize,
+    },
+    #[command(name = "scan", arity = -2, flags = READ, keys = NO_KEYS, group = "generic", summary = "Iterates over the key names in the database.")]
+    Scan {
+        cursor: u64,
+        pattern: Option<String>,
+        count: usize,
+        kind: Option<ValueType>,
+    },
+    // SORT and SORT_RO, which can't STORE
+    #[command(name = "sort", arity = -2, flags = WRITE_OOM, keys = ONE_KEY, group = "generic", summary = "Sorts the elements in a list or a set, optionally storing the result.")]
+    #[command(name = "sort_ro", arity = -2, flags = READ, keys = ONE_KEY, group = "generic", summary = "Returns the sorted elements of a list or a set.")]
+    Sort {
+        key: String,
+        options: SortOptions,
+        store: Option<String>,
+    },
+
+    #[command(name = "zadd", arity = -4, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "sorted-set", summary = "Adds one or more members to a sorted set.")]
+    ZAdd {
+        key: String,
+        members: Vec<(f64, String)>,
+    },
+    #[command(name = "zscore", arity = 3, flags = READ_FAST, keys = ONE_KEY, group = "sorted-set", summary = "Returns the score of a member in a sorted set.", parse)]
+    ZScore {
+        key: String,
+        member: String,
+    },
+    #[command(name = "zcard", arity = 2, flags = READ_FAST, keys = ONE_KEY, group = "sorted-set", summary = "Returns the number of members in a sorted set.", parse)]
+    ZCard {
+        key: String,
+    },
+    #[command(name = "zrange", arity = -4, flags = READ, keys = ONE_KEY, group = "sorted-set", summary = "Returns members in a sorted set within a range of indexes.")]
+    ZRange {
+        key: String,
+        start: i64,
+        stop: i64,
+        with_scores: bool,
+    },
+
+    #[command(name = "geoadd", arity = -5, flags = WRITE_OOM, keys = ONE_KEY, group = "geo", summary = "Adds one or more members to a geospatial index.")]
+    GeoAdd {
+        key: String,
+        items: Vec<(f64, f64, String)>,
+    },
+    #[command(name = "geopos", arity = -2, flags = READ, keys = ONE_KEY, group = "geo", summary = "Returns the longitude and latitude of members from a geospatial index.", parse)]
+    GeoPos {
+        key: String,
+        members: Vec<String>,
+    },
+    #[command(name = "geodist", arity = -4, flags = READ, keys = ONE_KEY, group = "geo", summary = "Returns the distance between two members of a geospatial index.")]
+    GeoDist {
+        key: String,
+        from: String,
+        to: String,
+        unit: f64,
+    },
+    #[command(name = "geosearch", arity = -7, flags = READ, keys = ONE_KEY, group = "geo", summary = "Queries a geospatial index for members inside an area of a box or a circle.")]
+    GeoSearch {
+        key: String,
+        query: GeoQuery,
+    },
 
+    #[command(name = "hset", arity = -4, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "hash", summary = "Creates or modifies the value of a field in a hash.")]
     HSet {
         key: String,
-        field: String,
-        value: String,
+        fields: Vec<(String, String)>,
     },
+    #[command(name = "hget", arity = 3, flags = READ_FAST, keys = ONE_KEY, group = "hash", summary = "Returns the value of a field in a hash.", parse)]
     HGet {
         key: String,
         field: String,
     },
+    #[command(name = "hincrby", arity = 4, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "hash", summary = "Increments the integer value of a field in a hash by a number.")]
+    HIncrBy {
+        key: String,
+        field: String,
+        increment: i64,
+    },
+    #[command(name = "hincrbyfloat", arity = 4, flags = WRITE_OOM_FAST, keys = ONE_KEY, group = "hash", summary = "Increments the floating point value of a field by a number.")]
+    HIncrByFloat {
+        key: String,
+        field: String,
+        increment: f64,
+    },
+    #[command(name = "hrandfield", arity = -2, flags = READ, keys = ONE_KEY, group = "hash", summary = "Returns random fields from a hash.")]
+    HRandField {
+        key: String,
+        count: Option<i64>,
+        with_values: bool,
+    },
+
+    // FT.CREATE index [ON HASH] [PREFIX count prefix ...] SCHEMA field
+    // TEXT|TAG ...
+    #[cfg(feature = "search")]
+    #[command(name = "ft.create", arity = -5, flags = WRITE, keys = NO_KEYS, group = "search", summary = "Creates an index over the fields of hashes.")]
+    FtCreate {
+        index: String,
+        schema: IndexSchema,
+    },
+    // FT.SEARCH index query [NOCONTENT] [LIMIT offset count]
+    #[cfg(feature = "search")]
+    #[command(name = "ft.search", arity = -3, flags = READ, keys = NO_KEYS, group = "search", summary = "Searches an index with a query.")]
+    FtSearch {
+        index: String,
+        query: Query,
+        offset: usize,
+        count: usize,
+        no_content: bool,
+    },
+    #[cfg(feature = "search")]
+    #[command(name = "ft.dropindex", arity = 2, flags = WRITE, keys = NO_KEYS, group = "search", summary = "Deletes an index, leaving its hashes.")]
+    FtDropIndex {
+        index: String,
+    },
+
+    // JSON.SET key path value [NX|XX]
+    #[cfg(feature = "json")]
+    #[command(name = "json.set", arity = -4, flags = WRITE_OOM, keys = ONE_KEY, group = "json", summary = "Sets or updates the JSON value at a path.")]
+    JsonSet {
+        key: String,
+        path: JsonPath,
+        value: serde_json::Value,
+        condition: SetCondition,
+    },
+    // JSON.GET key [path ...]
+    #[cfg(feature = "json")]
+    #[command(name = "json.get", arity = -2, flags = READ, keys = ONE_KEY, group = "json", summary = "Gets the JSON values at one or more paths.")]
+    JsonGet {
+        key: String,
+        paths: Vec<JsonPath>,
+    },
+    // JSON.DEL key [path]
+    #[cfg(feature = "json")]
+    #[command(name = "json.del", arity = -2, flags = WRITE, keys = ONE_KEY, group = "json", summary = "Deletes the JSON values at a path.")]
+    JsonDel {
+        key: String,
+        path: JsonPath,
+    },
+    // JSON.ARRAPPEND key path value [value ...]
+    #[cfg(feature = "json")]
+    #[command(name = "json.arrappend", arity = -4, flags = WRITE_OOM, keys = ONE_KEY, group = "json", summary = "Appends JSON values to the arrays at a path.")]
+    JsonArrAppend {
+        key: String,
+        path: JsonPath,
+        values: Vec<serde_json::Value>,
+    },
+
+    #[command(name = "object", arity = -2, flags = F::NONE, keys = NO_KEYS, group = "generic", summary = "A container for object introspection commands.")]
+    ObjectFreq {
+        key: String,
+    },
+    ObjectIdleTime {
+        key: String,
+    },
+    ObjectEncoding {
+        key: String,
+    },
+    // CLUSTER KEYSLOT key, the key is not looked up
+    #[command(name = "cluster", arity = -2, flags = F::STALE, keys = NO_KEYS, group = "cluster", summary = "A container for Redis Cluster commands.")]
+    ClusterKeySlot {
+        key: String,
+    },
+    #[command(name = "hotkeys", arity = -1, flags = ADMIN, keys = NO_KEYS, group = "server", summary = "Returns the most accessed keys of the current database.")]
+    HotKeys {
+        count: usize,
+    },
+    // BIGKEYS [COUNT count] [SAMPLES samples], samples 0 for every key
+    #[command(name = "bigkeys", arity = -1, flags = ADMIN, keys = NO_KEYS, group = "server", summary = "Reports the largest keys of a sample of the current database, with advice.")]
+    BigKeys {
+        count: usize,
+        samples: usize,
+    },
+    // CONFIG GET pattern [pattern ...]
+    #[command(name = "config", arity = -2, flags = ADMIN, keys = NO_KEYS, group = "server", summary = "A container for server configuration commands.")]
+    ConfigGet {
+        patterns: Vec<String>,
+    },
+    ConfigSet {
+        parameter: String,
+        value: String,
+    },
+    ConfigResetStat,
+    #[command(name = "client", arity = -2, flags = ADMIN, keys = NO_KEYS, group = "connection", summary = "A container for client connection commands.")]
+    ClientId,
+    // CLIENT TRACKING ON|OFF [REDIRECT id] [BCAST] [PREFIX prefix ...],
+    // None turns it off
+    ClientTracking {
+        options: Option<TrackingOptions>,
+    },
+    // CLIENT KILL ID client-id
+    ClientKill {
+        id: u64,
+    },
+    #[command(name = "latency", arity = -2, flags = F::NONE, keys = NO_KEYS, group = "server", summary = "A container for latency diagnostics commands.")]
+    LatencyLatest,
+    LatencyHistory {
+        event: String,
+    },
+    LatencyReset {
+        events: Vec<String>,
+    },
+    // LATENCY HISTOGRAM [command ...], every command called when none
+    LatencyHistogram {
+        commands: Vec<String>,
+    },
+    // DEBUG RELOAD: every key goes through DUMP and RESTORE
+    #[command(name = "debug", arity = -2, flags = DANGER.union(F::LOADING), keys = NO_KEYS, group = "server", summary = "A container for debugging commands.")]
+    DebugReload,
+    // DEBUG CHANGE-REPL-ID: replicas can't continue from the old history
+    DebugChangeReplId,
+    // DEBUG STRINGMATCH-LEN: fuzzes the glob matcher
+    DebugStringMatchLen,
+    // DEBUG SET-ACTIVE-EXPIRE: keys then only expire as they are looked up
+    DebugSetActiveExpire {
+        enabled: bool,
+    },
+    // DEBUG FREEZE-TIME, RESUME-TIME and ADVANCE-TIME, with the debug-clock
+    // feature
+    DebugTime {
+        change: TimeChange,
+    },
 
+    #[command(name = "ping", arity = -1, flags = F::FAST, keys = NO_KEYS, group = "connection", summary = "Returns the server's liveliness response.")]
     Ping,
+    // The connection closes once the reply is out
+    #[command(name = "quit", arity = -1, flags = CONN, keys = NO_KEYS, group = "connection", summary = "Closes the connection.")]
+    Quit,
+    // SHUTDOWN [NOSAVE|SAVE]
+    #[command(name = "shutdown", arity = -1, flags = DANGER.union(F::LOADING), keys = NO_KEYS, group = "server", summary = "Synchronously saves the database(s) to disk and shuts down the server.")]
+    Shutdown {
+        mode: SaveMode,
+    },
+    #[command(name = "bgrewriteaof", arity = 1, flags = DANGER, keys = NO_KEYS, group = "server", summary = "Asynchronously rewrites the append-only file to disk.", parse)]
+    BgRewriteAof,
+    // SUBSCRIBE and PSUBSCRIBE, answered by the connection itself with one
+    // confirmation per name
+    #[command(name = "subscribe", arity = -2, flags = SUBSCRIBE, keys = NO_KEYS, group = "pubsub", summary = "Listens for messages published to channels.")]
+    #[command(name = "psubscribe", arity = -2, flags = SUBSCRIBE, keys = NO_KEYS, group = "pubsub", summary = "Listens for messages published to channels that match one or more patterns.")]
+    Subscribe {
+        kind: Kind,
+        names: Vec<String>,
+    },
+    // UNSUBSCRIBE and PUNSUBSCRIBE, from everything of kind without names
+    #[command(name = "unsubscribe", arity = -1, flags = SUBSCRIBE, keys = NO_KEYS, group = "pubsub", summary = "Stops listening to messages posted to channels.")]
+    #[command(name = "punsubscribe", arity = -1, flags = SUBSCRIBE, keys = NO_KEYS, group = "pubsub", summary = "Stops listening to messages published to channels that match one or more patterns.")]
+    Unsubscribe {
+        kind: Kind,
+        names: Vec<String>,
+    },
+    #[command(name = "publish", arity = 3, flags = F::PUBSUB.union(F::LOADING).union(F::STALE).union(F::FAST), keys = NO_KEYS, group = "pubsub", summary = "Posts a message to a channel.", parse)]
+    Publish {
+        channel: String,
+        message: String,
+    },
     Echo {
         message: String,
     },
@@ -61,9 +615,141 @@ pub enum Command {
         command: String,
     },
 
-    //todo
-    Info,
+    // INFO [section ...], lowercased
+    #[command(name = "info", arity = -1, flags = ADMIN, keys = NO_KEYS, group = "server", summary = "Returns information and statistics about the server.")]
+    Info {
+        sections: Vec<String>,
+    },
+    #[command(name = "role", arity = 1, flags = CONN, keys = NO_KEYS, group = "server", summary = "Returns the replication role.", parse)]
+    Role,
+    // None for REPLICAOF NO ONE
+    #[command(name = "replicaof", arity = 3, flags = DANGER, keys = NO_KEYS, group = "server", summary = "Configures a server as replica of another, or promotes it to a master.")]
+    #[command(name = "slaveof", arity = 3, flags = DANGER, keys = NO_KEYS, group = "server", summary = "Sets a Redis server as a replica of another, or promotes it to being a master.")]
+    ReplicaOf {
+        master: Option<(String, u16)>,
+    },
+    // What a replica tells about itself before its PSYNC
+    #[command(name = "replconf", arity = -1, flags = DANGER.union(F::LOADING), keys = NO_KEYS, group = "server", summary = "An internal command for configuring the replication stream.")]
+    ReplConf {
+        port: Option<u16>,
+        ip: Option<String>,
+    },
+    // REPLCONF ACK, which gets no reply
+    ReplConfAck {
+        offset: u64,
+    },
+    // Offset is the next byte the replica wants of the history replid,
+    // PSYNC ? -1 asks for a full resync
+    #[command(name = "psync", arity = -3, flags = F::ADMIN.union(F::NOSCRIPT), keys = NO_KEYS, group = "server", summary = "An internal command used in replication.")]
+    Psync {
+        replid: String,
+        offset: i64,
+    },
+    #[command(name = "command", arity = -1, flags = ADMIN, keys = NO_KEYS, group = "server", summary = "Returns detailed information about all commands.")]
     Command,
+    CommandCount,
+    CommandInfo {
+        names: Vec<String>,
+    },
+    CommandDocs {
+        names: Vec<String>,
+    },
+    // HELP subcommand of any container command
+    Help {
+        command: String,
+    },
+}
+
+// Expiry option of GETEX, amounts are converted at execution time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetExOption {
+    None,
+    Ex(i64),
+    Px(i64),
+    ExAt(i64),
+    PxAt(i64),
+    Persist,
+}
+
+// Operation of SINTER/SUNION/SDIFF and their STORE variants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+// What DEBUG does to the clock the dbs go by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeChange {
+    Freeze,
+    Resume,
+    // Milliseconds
+    Advance(u64),
+}
+
+// Options of LPOS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LPosOptions {
+    // Match to start from, 1 for the first; negative counts from the tail
+    pub rank: i64,
+    // Matches to reply with as an array, 0 for all of them
+    pub count: Option<usize>,
+    // Elements compared at most, 0 for the whole list
+    pub maxlen: usize,
+}
+
+impl Default for LPosOptions {
+    fn default() -> Self {
+        Self {
+            rank: 1,
+            count: None,
+            maxlen: 0,
+        }
+    }
+}
+
+// Options of SORT and SORT_RO
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortOptions {
+    // Weights are read from the keys this names instead of the elements
+    pub by: Option<String>,
+    // Offset and count, a negative count takes the rest
+    pub limit: Option<(i64, i64)>,
+    // Each element is replaced by what these name in the reply
+    pub get: Vec<String>,
+    pub desc: bool,
+    // Compares as strings instead of numbers
+    pub alpha: bool,
+}
+
+// Center of a GEOSEARCH
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoOrigin {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+// Area of a GEOSEARCH in meters: radius, or width and height of a box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoQuery {
+    pub origin: GeoOrigin,
+    pub shape: GeoShape,
+    // Meters per reply unit
+    pub unit: f64,
+    // None leaves results in scan order
+    pub ascending: Option<bool>,
+    pub count: Option<usize>,
+    pub any: bool,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
 }
 
 #[derive(Debug)]
@@ -74,6 +760,85 @@ pub enum CommandError {
     InvalidArgumentType,
     NotImplemented,
     UnknownCommand(String),
+    SyntaxError,
+    NotAnInteger,
+    DbIndexOutOfRange,
+    SameObject,
+    InvalidExpireTime { command: String },
+    OffsetOutOfRange,
+    MustBePositive,
+    NoSuchKey,
+    IndexOutOfRange,
+    WrongType,
+    InvalidTimeout,
+    NegativeTimeout,
+    NotAFloat,
+    SortNotDouble,
+    InvalidCursor,
+    UnknownTypeName(String),
+    HashValueNotInteger,
+    HashValueNotFloat,
+    IncrementOverflow,
+    NanOrInfinity,
+    InvalidLonLat(f64, f64),
+    UnsupportedUnit,
+    NoSuchMember,
+    UnknownSubcommand { command: String, subcommand: String },
+    LfuNotSelected,
+    LfuSelected,
+    RateLimited,
+    // Refused while the dataset is loaded at boot
+    Loading,
+    // A write sent to a read only replica
+    ReadOnly,
+    InvalidMasterPort,
+    // PSYNC to a server that is a replica itself
+    NoMasterLink,
+    UnrecognizedReplConf(String),
+    // PSYNC or REPLCONF ACK outside of a client connection
+    ReplicaLinkOnly(String),
+    // (UN)SUBSCRIBE outside of a client connection
+    ConnectionOnly(String),
+    // Anything else from a RESP2 client with subscriptions
+    SubscriberMode(String),
+    UnknownConfig(String),
+    ConfigSet { parameter: String, reason: String },
+    NoSuchRedirect,
+    PrefixWithoutBcast,
+    NoProto,
+    WrongPass,
+    NamespaceKeysQuota,
+    NamespaceMemoryQuota,
+    BusyKey,
+    InvalidTtl,
+    DumpError(dump::DumpError),
+    MigrateIo(String),
+    MigrateTarget(String),
+    UnknownIndex,
+    IndexExists,
+    SearchSyntax(String),
+    InvalidJson(String),
+    InvalidJsonPath(String),
+    // A legacy JSON path that matches nothing
+    NoJsonPath(String),
+    JsonNewAtRoot,
+    JsonNoKey,
+    // The type found where a JSON array was expected
+    JsonNotArray(String),
+    ZeroRank,
+    NegativeCount,
+    NegativeMaxLen,
+    NegativeLimit,
+    NumKeysNotPositive,
+    NumKeysTooMany,
+    // The write ran but couldn't be appended to the AOF
+    AofWrite(String),
+    AofOff,
+    AofRewriteInProgress,
+    // The command ran past command-timeout
+    Timeout,
+    // The command panicked
+    Internal,
     StorageError(Error),
 }
 
@@ -88,6 +853,145 @@ impl std::fmt::Display for CommandError {
             Self::InvalidArgumentType => write!(f, "invalid argument type"),
             Self::NotImplemented => write!(f, "command not implemented"),
             Self::UnknownCommand(cmd) => write!(f, "unknown command '{}'", cmd),
+            Self::SyntaxError => write!(f, "syntax error"),
+            Self::NotAnInteger => write!(f, "value is not an integer or out of range"),
+            Self::DbIndexOutOfRange => write!(f, "DB index is out of range"),
+            Self::SameObject => write!(f, "source and destination objects are the same"),
+            Self::InvalidExpireTime { command } => {
+                write!(f, "invalid expire time in '{}' command", command)
+            }
+            Self::OffsetOutOfRange => write!(f, "offset is out of range"),
+            Self::MustBePositive => write!(f, "value is out of range, must be positive"),
+            Self::NoSuchKey => write!(f, "no such key"),
+            Self::IndexOutOfRange => write!(f, "index out of range"),
+            Self::WrongType => write!(f, "Operation against a key holding the wrong kind of value"),
+            Self::InvalidTimeout => write!(f, "timeout is not a float or out of range"),
+            Self::NegativeTimeout => write!(f, "timeout is negative"),
+            Self::NotAFloat => write!(f, "value is not a valid float"),
+            Self::SortNotDouble => write!(f, "One or more scores can't be converted into double"),
+            Self::InvalidCursor => write!(f, "invalid cursor"),
+            Self::UnknownTypeName(name) => write!(f, "unknown type name '{}'", name),
+            Self::HashValueNotInteger => write!(f, "hash value is not an integer"),
+            Self::HashValueNotFloat => write!(f, "hash value is not a float"),
+            Self::IncrementOverflow => write!(f, "increment or decrement would overflow"),
+            Self::NanOrInfinity => write!(f, "increment would produce NaN or Infinity"),
+            Self::InvalidLonLat(lon, lat) => {
+                write!(f, "invalid longitude,latitude pair {:.6},{:.6}", lon, lat)
+            }
+            Self::UnsupportedUnit => {
+                write!(f, "unsupported unit provided. please use M, KM, FT, MI")
+            }
+            Self::NoSuchMember => write!(f, "could not decode requested zset member"),
+            Self::UnknownSubcommand {
+                command,
+                subcommand,
+            } => write!(
+                f,
+                "unknown subcommand '{}'. Try {} HELP.",
+                subcommand,
+                command.to_uppercase()
+            ),
+            Self::LfuNotSelected => write!(
+                f,
+                "An LFU maxmemory policy is not selected, access frequency not tracked. \
+                 Please note that when switching between policies at runtime LRU and LFU \
+                 data will take some time to adjust."
+            ),
+            Self::LfuSelected => write!(
+                f,
+                "An LFU maxmemory policy is selected, idle time not tracked. Please note that \
+                 when switching between policies at runtime LRU and LFU data will take some \
+                 time to adjust."
+            ),
+            Self::RateLimited => write!(f, "command rate limit exceeded"),
+            Self::Loading => write!(f, "foobar_db is loading the dataset in memory"),
+            Self::ReadOnly => write!(f, "You can't write against a read only replica."),
+            Self::InvalidMasterPort => write!(f, "Invalid master port"),
+            Self::NoMasterLink => write!(f, "Can't SYNC while not connected with my master"),
+            Self::UnrecognizedReplConf(option) => {
+                write!(f, "Unrecognized REPLCONF option: {}", option)
+            }
+            Self::ReplicaLinkOnly(command) => {
+                write!(f, "{} is only served on a replica's connection", command)
+            }
+            Self::ConnectionOnly(command) => {
+                write!(f, "{} is only served on a client connection", command)
+            }
+            Self::SubscriberMode(command) => write!(
+                f,
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET \
+                 are allowed in this context",
+                command
+            ),
+            Self::UnknownConfig(parameter) => write!(
+                f,
+                "Unknown option or number of arguments for CONFIG SET - '{}'",
+                parameter
+            ),
+            Self::ConfigSet { parameter, reason } => write!(
+                f,
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                parameter, reason
+            ),
+            Self::NoSuchRedirect => {
+                write!(f, "The client ID you want redirect to does not exist")
+            }
+            Self::PrefixWithoutBcast => {
+                write!(f, "PREFIX option requires BCAST mode to be enabled")
+            }
+            Self::NoProto => write!(f, "unsupported protocol version"),
+            Self::WrongPass => write!(f, "invalid username-password pair or user is disabled."),
+            Self::NamespaceKeysQuota => write!(
+                f,
+                "command not allowed when the namespace holds its 'max-keys' keys."
+            ),
+            Self::NamespaceMemoryQuota => write!(
+                f,
+                "command not allowed when used memory > the namespace's 'max-memory'."
+            ),
+            Self::BusyKey => write!(f, "Target key name already exists."),
+            Self::InvalidTtl => write!(f, "Invalid TTL value, must be >= 0"),
+            Self::DumpError(e) => write!(f, "{}", e),
+            Self::MigrateIo(e) => write!(f, "error or timeout talking to target instance: {}", e),
+            Self::MigrateTarget(e) => write!(f, "Target instance replied with error: {}", e),
+            Self::UnknownIndex => write!(f, "Unknown index name"),
+            Self::IndexExists => write!(f, "Index already exists"),
+            Self::SearchSyntax(e) => write!(f, "Syntax error: {}", e),
+            Self::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+            Self::InvalidJsonPath(path) => write!(f, "invalid JSON path '{}'", path),
+            Self::NoJsonPath(path) => write!(f, "Path '{}' does not exist", path),
+            Self::JsonNewAtRoot => write!(f, "new objects must be created at the root"),
+            Self::JsonNoKey => write!(
+                f,
+                "could not perform this operation on a key that doesn't exist"
+            ),
+            Self::JsonNotArray(found) => write!(
+                f,
+                "wrong type of path value - expected array but found {}",
+                found
+            ),
+            Self::ZeroRank => write!(
+                f,
+                "RANK can't be zero: use 1 to start from the first match, 2 from the second ... \
+                 or use negative to start from the end of the list"
+            ),
+            Self::NegativeCount => write!(f, "COUNT can't be negative"),
+            Self::NegativeMaxLen => write!(f, "MAXLEN can't be negative"),
+            Self::NegativeLimit => write!(f, "LIMIT can't be negative"),
+            Self::NumKeysNotPositive => write!(f, "numkeys should be greater than 0"),
+            Self::NumKeysTooMany => {
+                write!(f, "Number of keys can't be greater than number of args")
+            }
+            Self::AofWrite(e) => write!(f, "Errors writing to the AOF file: {}", e),
+            Self::AofOff => write!(f, "Append only file is turned off"),
+            Self::AofRewriteInProgress => {
+                write!(
+                    f,
+                    "Background append only file rewriting already in progress"
+                )
+            }
+            Self::Timeout => write!(f, "command timed out"),
+            Self::Internal => write!(f, "internal error"),
             Self::StorageError(e) => write!(f, "storage error: {}", e),
         }
     }
@@ -108,17 +1012,21 @@ impl Command {
                     _ => return Err(anyhow!(CommandError::InvalidCommandName)),
                 };
 
-                match command_name.as_str() {
-                    "GET" => {
-                        if array.len() != 2 {
-                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
-                                command: "get".to_string()
-                            }));
-                        }
-                        let key = Self::extract_string(&array[1])?;
-                        Ok(Command::Get { key })
+                if array.len() == 2 && table::has_subcommands(&command_name) {
+                    let subcommand = Self::extract_string(&array[1])?;
+                    if subcommand.eq_ignore_ascii_case("HELP") {
+                        return Ok(Command::Help {
+                            command: command_name,
+                        });
                     }
+                }
+
+                Self::check_arity(&command_name, array.len())?;
+                if let Some(command) = Self::parse_args(&command_name, &array)? {
+                    return Ok(command);
+                }
 
+                match command_name.as_str() {
                     "SET" => {
                         if array.len() != 3 {
                             return Err(anyhow!(CommandError::WrongNumberOfArguments {
@@ -130,44 +1038,1135 @@ impl Command {
                         Ok(Command::Set { key, value })
                     }
 
-                    "DEL" => {
-                        if array.len() < 2 {
-                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
-                                command: "del".to_string()
-                            }));
+                    "GETEX" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let option = match array.len() {
+                            2 => GetExOption::None,
+                            3 => match Self::extract_string(&array[2])?.to_uppercase().as_str() {
+                                "PERSIST" => GetExOption::Persist,
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            },
+                            4 => {
+                                let amount = Self::extract_integer(&array[3])?;
+                                match Self::extract_string(&array[2])?.to_uppercase().as_str() {
+                                    "EX" => GetExOption::Ex(amount),
+                                    "PX" => GetExOption::Px(amount),
+                                    "EXAT" => GetExOption::ExAt(amount),
+                                    "PXAT" => GetExOption::PxAt(amount),
+                                    _ => return Err(anyhow!(CommandError::SyntaxError)),
+                                }
+                            }
+                            _ => return Err(anyhow!(CommandError::SyntaxError)),
+                        };
+                        Ok(Command::GetEx { key, option })
+                    }
+
+                    "INCR" | "DECR" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let increment = if command_name == "INCR" { 1 } else { -1 };
+                        Ok(Command::IncrBy { key, increment })
+                    }
+
+                    "INCRBY" | "DECRBY" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let amount = Self::extract_integer(&array[2])?;
+                        let increment = if command_name == "INCRBY" {
+                            amount
+                        } else {
+                            amount
+                                .checked_neg()
+                                .ok_or(CommandError::IncrementOverflow)?
+                        };
+                        Ok(Command::IncrBy { key, increment })
+                    }
+
+                    "SETEX" | "PSETEX" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let amount = Self::extract_integer(&array[2])?;
+                        let value = Self::extract_string(&array[3])?;
+                        if command_name == "SETEX" {
+                            Ok(Command::SetEx {
+                                key,
+                                seconds: amount,
+                                value,
+                            })
+                        } else {
+                            Ok(Command::PSetEx {
+                                key,
+                                milliseconds: amount,
+                                value,
+                            })
                         }
-                        let keys = array[1..]
-                            .iter()
-                            .map(Self::extract_string)
-                            .collect::<Result<Vec<_>, _>>()?;
-                        Ok(Command::Del { keys })
                     }
 
-                    "LPUSH" => {
-                        if array.len() < 3 {
+                    "COPY" => {
+                        let source = Self::extract_string(&array[1])?;
+                        let destination = Self::extract_string(&array[2])?;
+                        let mut db = None;
+                        let mut replace = false;
+                        let mut i = 3;
+                        while i < array.len() {
+                            match Self::extract_string(&array[i])?.to_uppercase().as_str() {
+                                "REPLACE" => replace = true,
+                                "DB" if i + 1 < array.len() => {
+                                    i += 1;
+                                    db = Some(Self::extract_db_index(&array[i])?);
+                                }
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 1;
+                        }
+                        Ok(Command::Copy {
+                            source,
+                            destination,
+                            db,
+                            replace,
+                        })
+                    }
+
+                    "MOVE" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let db = Self::extract_db_index(&array[2])?;
+                        Ok(Command::Move { key, db })
+                    }
+
+                    "RESTORE" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let ttl = Self::extract_integer(&array[2])?;
+                        let payload = Self::extract_string(&array[3])?;
+                        let (mut replace, mut absttl) = (false, false);
+                        for arg in &array[4..] {
+                            match Self::extract_string(arg)?.to_uppercase().as_str() {
+                                "REPLACE" => replace = true,
+                                "ABSTTL" => absttl = true,
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                        }
+                        Ok(Command::Restore {
+                            key,
+                            ttl,
+                            payload,
+                            replace,
+                            absttl,
+                        })
+                    }
+
+                    "MIGRATE" => {
+                        let host = Self::extract_string(&array[1])?;
+                        let port = u16::try_from(Self::extract_integer(&array[2])?)
+                            .map_err(|_| CommandError::NotAnInteger)?;
+                        let key = Self::extract_string(&array[3])?;
+                        let db = Self::extract_db_index(&array[4])?;
+                        // Same fallback as Redis for a non-positive timeout
+                        let timeout = match Self::extract_integer(&array[5])? {
+                            ms if ms <= 0 => 1000,
+                            ms => ms as u64,
+                        };
+                        let (mut copy, mut replace, mut keys) = (false, false, None);
+                        let mut i = 6;
+                        while i < array.len() {
+                            match Self::extract_string(&array[i])?.to_uppercase().as_str() {
+                                "COPY" => copy = true,
+                                "REPLACE" => replace = true,
+                                "KEYS" if key.is_empty() => {
+                                    keys = Some(
+                                        array[i + 1..]
+                                            .iter()
+                                            .map(Self::extract_string)
+                                            .collect::<Result<Vec<_>, _>>()?,
+                                    );
+                                    break;
+                                }
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 1;
+                        }
+                        Ok(Command::Migrate {
+                            target: MigrateTarget {
+                                host,
+                                port,
+                                db,
+                                timeout: Duration::from_millis(timeout),
+                            },
+                            keys: keys.unwrap_or_else(|| vec![key]),
+                            copy,
+                            replace,
+                        })
+                    }
+
+                    "SELECT" => {
+                        let db = Self::extract_db_index(&array[1])?;
+                        Ok(Command::Select { db })
+                    }
+
+                    "HELLO" => {
+                        let protover = match array.get(1) {
+                            Some(value) => Some(Self::extract_integer(value)?),
+                            None => None,
+                        };
+                        let auth = match array.len() {
+                            1 | 2 => None,
+                            5 if Self::extract_string(&array[2])?.eq_ignore_ascii_case("AUTH") => {
+                                Some((
+                                    Self::extract_string(&array[3])?,
+                                    Self::extract_string(&array[4])?,
+                                ))
+                            }
+                            _ => return Err(anyhow!(CommandError::SyntaxError)),
+                        };
+                        Ok(Command::Hello { protover, auth })
+                    }
+                    "AUTH" => match array.len() {
+                        2 => Ok(Command::Auth {
+                            user: "default".to_string(),
+                            password: Self::extract_string(&array[1])?,
+                        }),
+                        3 => Ok(Command::Auth {
+                            user: Self::extract_string(&array[1])?,
+                            password: Self::extract_string(&array[2])?,
+                        }),
+                        _ => Err(anyhow!(CommandError::SyntaxError)),
+                    },
+
+                    "LPOP" | "RPOP" => {
+                        if array.len() != 2 && array.len() != 3 {
                             return Err(anyhow!(CommandError::WrongNumberOfArguments {
-                                command: "lpush".to_string()
+                                command: command_name.to_lowercase()
                             }));
                         }
                         let key = Self::extract_string(&array[1])?;
-                        let values = array[2..]
-                            .iter()
-                            .map(Self::extract_string)
-                            .collect::<Result<Vec<_>, _>>()?;
-                        Ok(Command::LPush { key, values })
+                        let count = match array.get(2) {
+                            Some(count) => {
+                                let count = Self::extract_integer(count)?;
+                                if count < 0 {
+                                    return Err(anyhow!(CommandError::MustBePositive));
+                                }
+                                Some(count as usize)
+                            }
+                            None => None,
+                        };
+                        if command_name == "LPOP" {
+                            Ok(Command::LPop { key, count })
+                        } else {
+                            Ok(Command::RPop { key, count })
+                        }
                     }
 
-                    "PING" => Ok(Command::Ping),
+                    "LPOS" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let element = Self::extract_string(&array[2])?;
+                        let mut options = LPosOptions::default();
+                        let args = &array[3..];
+                        let arg = |i: usize| args.get(i).ok_or(anyhow!(CommandError::SyntaxError));
+                        let mut i = 0;
+                        while i < args.len() {
+                            let value = Self::extract_integer(arg(i + 1)?)?;
+                            match Self::extract_string(&args[i])?.to_uppercase().as_str() {
+                                "RANK" => {
+                                    options.rank = match value {
+                                        0 => return Err(anyhow!(CommandError::ZeroRank)),
+                                        i64::MIN => {
+                                            return Err(anyhow!(CommandError::NotAnInteger))
+                                        }
+                                        rank => rank,
+                                    }
+                                }
+                                "COUNT" if value < 0 => {
+                                    return Err(anyhow!(CommandError::NegativeCount))
+                                }
+                                "COUNT" => options.count = Some(value as usize),
+                                "MAXLEN" if value < 0 => {
+                                    return Err(anyhow!(CommandError::NegativeMaxLen))
+                                }
+                                "MAXLEN" => options.maxlen = value as usize,
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 2;
+                        }
+                        Ok(Command::LPos {
+                            key,
+                            element,
+                            options,
+                        })
+                    }
 
-                    "INFO" => Ok(Command::Info),
-                    "COMMAND" => Ok(Command::Command),
+                    "LINSERT" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let before = match Self::extract_string(&array[2])?.to_uppercase().as_str()
+                        {
+                            "BEFORE" => true,
+                            "AFTER" => false,
+                            _ => return Err(anyhow!(CommandError::SyntaxError)),
+                        };
+                        let pivot = Self::extract_string(&array[3])?;
+                        let element = Self::extract_string(&array[4])?;
+                        Ok(Command::LInsert {
+                            key,
+                            before,
+                            pivot,
+                            element,
+                        })
+                    }
 
-                    _ => Ok(Command::Unknown {
-                        command: command_name,
+                    "RPOPLPUSH" => Ok(Command::LMove {
+                        source: Self::extract_string(&array[1])?,
+                        destination: Self::extract_string(&array[2])?,
+                        from_left: false,
+                        to_left: true,
                     }),
-                }
-            }
-            _ => Err(anyhow!(CommandError::InvalidCommandName)),
+
+                    "LMOVE" | "BLMOVE" => {
+                        let blocking = command_name == "BLMOVE";
+                        if array.len() != if blocking { 6 } else { 5 } {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: command_name.to_lowercase()
+                            }));
+                        }
+                        let source = Self::extract_string(&array[1])?;
+                        let destination = Self::extract_string(&array[2])?;
+                        let from_left = Self::extract_side(&array[3])?;
+                        let to_left = Self::extract_side(&array[4])?;
+                        if blocking {
+                            let timeout = Self::extract_timeout(&array[5])?;
+                            Ok(Command::BLMove {
+                                source,
+                                destination,
+                                from_left,
+                                to_left,
+                                timeout,
+                            })
+                        } else {
+                            Ok(Command::LMove {
+                                source,
+                                destination,
+                                from_left,
+                                to_left,
+                            })
+                        }
+                    }
+
+                    "EXPIRE" => {
+                        let (key, seconds, condition) = Self::parse_expire(&array, "expire")?;
+                        Ok(Command::Expire {
+                            key,
+                            seconds,
+                            condition,
+                        })
+                    }
+
+                    "PEXPIRE" => {
+                        let (key, milliseconds, condition) = Self::parse_expire(&array, "pexpire")?;
+                        Ok(Command::PExpire {
+                            key,
+                            milliseconds,
+                            condition,
+                        })
+                    }
+
+                    "EXPIREAT" => {
+                        let (key, timestamp, condition) = Self::parse_expire(&array, "expireat")?;
+                        Ok(Command::ExpireAt {
+                            key,
+                            timestamp,
+                            condition,
+                        })
+                    }
+
+                    "PEXPIREAT" => {
+                        let (key, timestamp, condition) = Self::parse_expire(&array, "pexpireat")?;
+                        Ok(Command::PExpireAt {
+                            key,
+                            timestamp,
+                            condition,
+                        })
+                    }
+
+                    "SPOP" | "SRANDMEMBER" => {
+                        if array.len() != 2 && array.len() != 3 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: command_name.to_lowercase()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let count = match array.get(2) {
+                            Some(count) => Some(Self::extract_integer(count)?),
+                            None => None,
+                        };
+                        if command_name == "SRANDMEMBER" {
+                            return Ok(Command::SRandMember { key, count });
+                        }
+                        let count = match count {
+                            Some(count) if count < 0 => {
+                                return Err(anyhow!(CommandError::MustBePositive))
+                            }
+                            count => count.map(|c| c as usize),
+                        };
+                        Ok(Command::SPop { key, count })
+                    }
+
+                    "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE" | "SUNIONSTORE"
+                    | "SDIFFSTORE" => {
+                        let store = command_name.ends_with("STORE");
+                        if array.len() < if store { 3 } else { 2 } {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: command_name.to_lowercase()
+                            }));
+                        }
+                        let op = match &command_name[..5] {
+                            "SINTE" => SetOp::Inter,
+                            "SUNIO" => SetOp::Union,
+                            _ => SetOp::Diff,
+                        };
+                        let args = array[1..]
+                            .iter()
+                            .map(Self::extract_string)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        if store {
+                            let mut args = args.into_iter();
+                            let destination = args.next().unwrap_or_default();
+                            Ok(Command::SAlgebraStore {
+                                op,
+                                destination,
+                                keys: args.collect(),
+                            })
+                        } else {
+                            Ok(Command::SAlgebra { op, keys: args })
+                        }
+                    }
+
+                    "SINTERCARD" => {
+                        let numkeys = Self::extract_integer(&array[1])?;
+                        if numkeys <= 0 {
+                            return Err(anyhow!(CommandError::NumKeysNotPositive));
+                        }
+                        let numkeys = numkeys as usize;
+                        if numkeys > array.len() - 2 {
+                            return Err(anyhow!(CommandError::NumKeysTooMany));
+                        }
+                        let keys = array[2..2 + numkeys]
+                            .iter()
+                            .map(Self::extract_string)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let limit = match &array[2 + numkeys..] {
+                            [] => 0,
+                            [option, limit]
+                                if Self::extract_string(option)?.eq_ignore_ascii_case("LIMIT") =>
+                            {
+                                match Self::extract_integer(limit)? {
+                                    limit if limit < 0 => {
+                                        return Err(anyhow!(CommandError::NegativeLimit))
+                                    }
+                                    limit => limit as usize,
+                                }
+                            }
+                            _ => return Err(anyhow!(CommandError::SyntaxError)),
+                        };
+                        Ok(Command::SInterCard { keys, limit })
+                    }
+
+                    "SCAN" => {
+                        let cursor = Self::extract_string(&array[1])?
+                            .parse()
+                            .map_err(|_| anyhow!(CommandError::InvalidCursor))?;
+                        let (mut pattern, mut count, mut kind) = (None, 10, None);
+                        let args = &array[2..];
+                        let arg = |i: usize| args.get(i).ok_or(anyhow!(CommandError::SyntaxError));
+                        let mut i = 0;
+                        while i < args.len() {
+                            match Self::extract_string(&args[i])?.to_uppercase().as_str() {
+                                "MATCH" => pattern = Some(Self::extract_string(arg(i + 1)?)?),
+                                "COUNT" => {
+                                    count = Self::extract_integer(arg(i + 1)?)?;
+                                    if count < 1 {
+                                        return Err(anyhow!(CommandError::SyntaxError));
+                                    }
+                                }
+                                "TYPE" => {
+                                    let name = Self::extract_string(arg(i + 1)?)?;
+                                    kind = Some(
+                                        ValueType::from_name(&name)
+                                            .ok_or(CommandError::UnknownTypeName(name))?,
+                                    );
+                                }
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 2;
+                        }
+                        Ok(Command::Scan {
+                            cursor,
+                            pattern,
+                            count: count as usize,
+                            kind,
+                        })
+                    }
+
+                    "SORT" | "SORT_RO" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let (options, store) =
+                            Self::parse_sort_options(&array[2..], command_name == "SORT")?;
+                        Ok(Command::Sort {
+                            key,
+                            options,
+                            store,
+                        })
+                    }
+
+                    "ZADD" => {
+                        if array.len() < 4 || array.len() % 2 != 0 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "zadd".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let members = array[2..]
+                            .chunks(2)
+                            .map(|pair| {
+                                Ok((
+                                    Self::extract_float(&pair[0])?,
+                                    Self::extract_string(&pair[1])?,
+                                ))
+                            })
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Command::ZAdd { key, members })
+                    }
+
+                    "ZRANGE" => {
+                        if array.len() != 4 && array.len() != 5 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "zrange".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let start = Self::extract_integer(&array[2])?;
+                        let stop = Self::extract_integer(&array[3])?;
+                        let with_scores = match array.get(4) {
+                            Some(flag) => {
+                                if Self::extract_string(flag)?.to_uppercase() != "WITHSCORES" {
+                                    return Err(anyhow!(CommandError::SyntaxError));
+                                }
+                                true
+                            }
+                            None => false,
+                        };
+                        Ok(Command::ZRange {
+                            key,
+                            start,
+                            stop,
+                            with_scores,
+                        })
+                    }
+
+                    "GEOADD" => {
+                        if array.len() < 5 || (array.len() - 2) % 3 != 0 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "geoadd".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let items = array[2..]
+                            .chunks(3)
+                            .map(|item| {
+                                let (lon, lat) = Self::extract_lon_lat(&item[0], &item[1])?;
+                                Ok((lon, lat, Self::extract_string(&item[2])?))
+                            })
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Command::GeoAdd { key, items })
+                    }
+
+                    "GEODIST" => {
+                        if array.len() != 4 && array.len() != 5 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "geodist".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let from = Self::extract_string(&array[2])?;
+                        let to = Self::extract_string(&array[3])?;
+                        let unit = match array.get(4) {
+                            Some(unit) => Self::extract_geo_unit(unit)?,
+                            None => 1.0,
+                        };
+                        Ok(Command::GeoDist {
+                            key,
+                            from,
+                            to,
+                            unit,
+                        })
+                    }
+
+                    "GEOSEARCH" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let query = Self::parse_geo_query(&array[2..])?;
+                        Ok(Command::GeoSearch { key, query })
+                    }
+
+                    "HSET" => {
+                        if array.len() < 4 || array.len() % 2 != 0 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "hset".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let fields = array[2..]
+                            .chunks(2)
+                            .map(|pair| {
+                                Ok((
+                                    Self::extract_string(&pair[0])?,
+                                    Self::extract_string(&pair[1])?,
+                                ))
+                            })
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Command::HSet { key, fields })
+                    }
+
+                    "HINCRBY" | "HINCRBYFLOAT" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let field = Self::extract_string(&array[2])?;
+                        if command_name == "HINCRBY" {
+                            let increment = Self::extract_integer(&array[3])?;
+                            Ok(Command::HIncrBy {
+                                key,
+                                field,
+                                increment,
+                            })
+                        } else {
+                            let increment = Self::extract_float(&array[3])?;
+                            Ok(Command::HIncrByFloat {
+                                key,
+                                field,
+                                increment,
+                            })
+                        }
+                    }
+
+                    "HRANDFIELD" => {
+                        if array.len() < 2 || array.len() > 4 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "hrandfield".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let count = match array.get(2) {
+                            Some(count) => Some(Self::extract_integer(count)?),
+                            None => None,
+                        };
+                        let with_values = match array.get(3) {
+                            Some(flag) => {
+                                if Self::extract_string(flag)?.to_uppercase() != "WITHVALUES" {
+                                    return Err(anyhow!(CommandError::SyntaxError));
+                                }
+                                true
+                            }
+                            None => false,
+                        };
+                        Ok(Command::HRandField {
+                            key,
+                            count,
+                            with_values,
+                        })
+                    }
+
+                    #[cfg(feature = "search")]
+                    "FT.CREATE" => {
+                        let index = Self::extract_string(&array[1])?;
+                        let args = array[2..]
+                            .iter()
+                            .map(Self::extract_string)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let schema = IndexSchema::parse(&args)?;
+                        Ok(Command::FtCreate { index, schema })
+                    }
+
+                    #[cfg(feature = "search")]
+                    "FT.SEARCH" => {
+                        let index = Self::extract_string(&array[1])?;
+                        let query = Query::parse(&Self::extract_string(&array[2])?)?;
+                        let mut offset = 0;
+                        let mut count = crate::server::search::DEFAULT_LIMIT;
+                        let mut no_content = false;
+                        let mut i = 3;
+                        while i < array.len() {
+                            match Self::extract_string(&array[i])?.to_uppercase().as_str() {
+                                "NOCONTENT" => no_content = true,
+                                "LIMIT" if i + 2 < array.len() => {
+                                    let limit = |arg| {
+                                        usize::try_from(Self::extract_integer(arg)?)
+                                            .map_err(|_| anyhow!(CommandError::NegativeLimit))
+                                    };
+                                    offset = limit(&array[i + 1])?;
+                                    count = limit(&array[i + 2])?;
+                                    i += 2;
+                                }
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 1;
+                        }
+                        Ok(Command::FtSearch {
+                            index,
+                            query,
+                            offset,
+                            count,
+                            no_content,
+                        })
+                    }
+
+                    #[cfg(feature = "search")]
+                    "FT.DROPINDEX" => {
+                        let index = Self::extract_string(&array[1])?;
+                        Ok(Command::FtDropIndex { index })
+                    }
+
+                    #[cfg(feature = "json")]
+                    "JSON.SET" => {
+                        if array.len() != 4 && array.len() != 5 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "json.set".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let path = JsonPath::parse(&Self::extract_string(&array[2])?)?;
+                        let value = json::parse_value(&Self::extract_string(&array[3])?)?;
+                        let condition = match array.get(4) {
+                            None => SetCondition::Always,
+                            Some(flag) => match Self::extract_string(flag)?.to_uppercase().as_str()
+                            {
+                                "NX" => SetCondition::IfMissing,
+                                "XX" => SetCondition::IfExists,
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            },
+                        };
+                        Ok(Command::JsonSet {
+                            key,
+                            path,
+                            value,
+                            condition,
+                        })
+                    }
+
+                    #[cfg(feature = "json")]
+                    "JSON.GET" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let paths = array[2..]
+                            .iter()
+                            .map(|path| Ok(JsonPath::parse(&Self::extract_string(path)?)?))
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Command::JsonGet { key, paths })
+                    }
+
+                    #[cfg(feature = "json")]
+                    "JSON.DEL" => {
+                        if array.len() != 2 && array.len() != 3 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "json.del".to_string()
+                            }));
+                        }
+                        let key = Self::extract_string(&array[1])?;
+                        let path = match array.get(2) {
+                            Some(path) => JsonPath::parse(&Self::extract_string(path)?)?,
+                            None => JsonPath::root(),
+                        };
+                        Ok(Command::JsonDel { key, path })
+                    }
+
+                    #[cfg(feature = "json")]
+                    "JSON.ARRAPPEND" => {
+                        let key = Self::extract_string(&array[1])?;
+                        let path = JsonPath::parse(&Self::extract_string(&array[2])?)?;
+                        let values = array[3..]
+                            .iter()
+                            .map(|value| Ok(json::parse_value(&Self::extract_string(value)?)?))
+                            .collect::<Result<_, Error>>()?;
+                        Ok(Command::JsonArrAppend { key, path, values })
+                    }
+
+                    "OBJECT" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "FREQ" => {
+                                if array.len() != 3 {
+                                    return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                        command: "object|freq".to_string()
+                                    }));
+                                }
+                                let key = Self::extract_string(&array[2])?;
+                                Ok(Command::ObjectFreq { key })
+                            }
+                            "IDLETIME" => {
+                                if array.len() != 3 {
+                                    return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                        command: "object|idletime".to_string()
+                                    }));
+                                }
+                                let key = Self::extract_string(&array[2])?;
+                                Ok(Command::ObjectIdleTime { key })
+                            }
+                            "ENCODING" => {
+                                if array.len() != 3 {
+                                    return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                        command: "object|encoding".to_string()
+                                    }));
+                                }
+                                let key = Self::extract_string(&array[2])?;
+                                Ok(Command::ObjectEncoding { key })
+                            }
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "object".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    "HOTKEYS" => {
+                        let count = match array.len() {
+                            1 => HOTKEYS_DEFAULT,
+                            2 => match Self::extract_integer(&array[1])? {
+                                count if count > 0 => (count as usize).min(HOTKEYS_MAX),
+                                _ => return Err(anyhow!(CommandError::MustBePositive)),
+                            },
+                            _ => {
+                                return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                    command: "hotkeys".to_string()
+                                }))
+                            }
+                        };
+                        Ok(Command::HotKeys { count })
+                    }
+
+                    "BIGKEYS" => {
+                        let (mut count, mut samples) =
+                            (bigkeys::DEFAULT_COUNT, bigkeys::DEFAULT_SAMPLES);
+                        let args = &array[1..];
+                        let arg = |i: usize| args.get(i).ok_or(anyhow!(CommandError::SyntaxError));
+                        let mut i = 0;
+                        while i < args.len() {
+                            let option = Self::extract_string(&args[i])?.to_uppercase();
+                            let value = Self::extract_integer(arg(i + 1)?)?;
+                            match option.as_str() {
+                                "COUNT" if value > 0 => count = value as usize,
+                                "SAMPLES" if value >= 0 => samples = value as usize,
+                                "COUNT" | "SAMPLES" => {
+                                    return Err(anyhow!(CommandError::MustBePositive))
+                                }
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            }
+                            i += 2;
+                        }
+                        Ok(Command::BigKeys { count, samples })
+                    }
+
+                    "CONFIG" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "GET" if array.len() >= 3 => Ok(Command::ConfigGet {
+                                patterns: array[2..]
+                                    .iter()
+                                    .map(Self::extract_string)
+                                    .collect::<Result<_, _>>()?,
+                            }),
+                            "SET" if array.len() == 4 => Ok(Command::ConfigSet {
+                                parameter: Self::extract_string(&array[2])?,
+                                value: Self::extract_string(&array[3])?,
+                            }),
+                            "RESETSTAT" if array.len() == 2 => Ok(Command::ConfigResetStat),
+                            "GET" | "SET" | "RESETSTAT" => {
+                                Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                    command: format!("config|{}", subcommand.to_lowercase())
+                                }))
+                            }
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "config".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    "CLIENT" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "ID" if array.len() == 2 => Ok(Command::ClientId),
+                            "KILL"
+                                if array.len() == 4
+                                    && Self::extract_string(&array[2])?
+                                        .eq_ignore_ascii_case("ID") =>
+                            {
+                                match Self::extract_integer(&array[3])? {
+                                    id if id > 0 => Ok(Command::ClientKill { id: id as u64 }),
+                                    _ => Err(anyhow!(CommandError::MustBePositive)),
+                                }
+                            }
+                            "TRACKING" if array.len() >= 3 => {
+                                let on = match Self::extract_string(&array[2])?
+                                    .to_uppercase()
+                                    .as_str()
+                                {
+                                    "ON" => true,
+                                    "OFF" => false,
+                                    _ => return Err(anyhow!(CommandError::SyntaxError)),
+                                };
+                                let mut options = TrackingOptions::default();
+                                let mut i = 3;
+                                while i < array.len() {
+                                    let option = Self::extract_string(&array[i])?;
+                                    match option.to_uppercase().as_str() {
+                                        "REDIRECT" if i + 1 < array.len() => {
+                                            let id = Self::extract_integer(&array[i + 1])?;
+                                            options.redirect = Some(id.max(0) as u64);
+                                            i += 1;
+                                        }
+                                        "BCAST" => options.bcast = true,
+                                        "PREFIX" if i + 1 < array.len() => {
+                                            options
+                                                .prefixes
+                                                .push(Self::extract_string(&array[i + 1])?);
+                                            i += 1;
+                                        }
+                                        _ => return Err(anyhow!(CommandError::SyntaxError)),
+                                    }
+                                    i += 1;
+                                }
+                                if !options.prefixes.is_empty() && !options.bcast {
+                                    return Err(anyhow!(CommandError::PrefixWithoutBcast));
+                                }
+                                Ok(Command::ClientTracking {
+                                    options: on.then_some(options),
+                                })
+                            }
+                            "ID" | "KILL" | "TRACKING" => Err(anyhow!(CommandError::SyntaxError)),
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "client".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    "CLUSTER" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "KEYSLOT" if array.len() == 3 => Ok(Command::ClusterKeySlot {
+                                key: Self::extract_string(&array[2])?,
+                            }),
+                            "KEYSLOT" => Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "cluster|keyslot".to_string()
+                            })),
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "cluster".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    "LATENCY" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "LATEST" if array.len() == 2 => Ok(Command::LatencyLatest),
+                            "HISTORY" if array.len() == 3 => Ok(Command::LatencyHistory {
+                                event: Self::extract_string(&array[2])?,
+                            }),
+                            "RESET" => Ok(Command::LatencyReset {
+                                events: array[2..]
+                                    .iter()
+                                    .map(Self::extract_string)
+                                    .collect::<Result<_, _>>()?,
+                            }),
+                            "HISTOGRAM" => Ok(Command::LatencyHistogram {
+                                commands: array[2..]
+                                    .iter()
+                                    .map(Self::extract_string)
+                                    .collect::<Result<_, _>>()?,
+                            }),
+                            "LATEST" | "HISTORY" => {
+                                Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                    command: format!("latency|{}", subcommand.to_lowercase())
+                                }))
+                            }
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "latency".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+                    "DEBUG" => {
+                        let subcommand = Self::extract_string(&array[1])?;
+                        match subcommand.to_uppercase().as_str() {
+                            "RELOAD" if array.len() == 2 => Ok(Command::DebugReload),
+                            "CHANGE-REPL-ID" if array.len() == 2 => Ok(Command::DebugChangeReplId),
+                            "STRINGMATCH-LEN" if array.len() == 2 => {
+                                Ok(Command::DebugStringMatchLen)
+                            }
+                            "SET-ACTIVE-EXPIRE" if array.len() == 3 => {
+                                let enabled = match Self::extract_string(&array[2])?.as_str() {
+                                    "0" => false,
+                                    "1" => true,
+                                    _ => return Err(anyhow!(CommandError::SyntaxError)),
+                                };
+                                Ok(Command::DebugSetActiveExpire { enabled })
+                            }
+                            #[cfg(feature = "debug-clock")]
+                            "FREEZE-TIME" if array.len() == 2 => Ok(Command::DebugTime {
+                                change: TimeChange::Freeze,
+                            }),
+                            #[cfg(feature = "debug-clock")]
+                            "RESUME-TIME" if array.len() == 2 => Ok(Command::DebugTime {
+                                change: TimeChange::Resume,
+                            }),
+                            #[cfg(feature = "debug-clock")]
+                            "ADVANCE-TIME" if array.len() == 3 => {
+                                let ms = Self::extract_string(&array[2])?
+                                    .parse()
+                                    .map_err(|_| anyhow!(CommandError::NotAnInteger))?;
+                                Ok(Command::DebugTime {
+                                    change: TimeChange::Advance(ms),
+                                })
+                            }
+                            #[cfg(feature = "debug-clock")]
+                            "FREEZE-TIME" | "RESUME-TIME" | "ADVANCE-TIME" => {
+                                Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                    command: format!("debug|{}", subcommand.to_lowercase())
+                                }))
+                            }
+                            "RELOAD" | "CHANGE-REPL-ID" | "SET-ACTIVE-EXPIRE"
+                            | "STRINGMATCH-LEN" => {
+                                Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                    command: format!("debug|{}", subcommand.to_lowercase())
+                                }))
+                            }
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "debug".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    "PING" => Ok(Command::Ping),
+                    "QUIT" => Ok(Command::Quit),
+                    "SHUTDOWN" => {
+                        let mode = match array.len() {
+                            1 => SaveMode::Default,
+                            2 => match Self::extract_string(&array[1])?.to_uppercase().as_str() {
+                                "SAVE" => SaveMode::Save,
+                                "NOSAVE" => SaveMode::NoSave,
+                                _ => return Err(anyhow!(CommandError::SyntaxError)),
+                            },
+                            _ => return Err(anyhow!(CommandError::SyntaxError)),
+                        };
+                        Ok(Command::Shutdown { mode })
+                    }
+                    "SUBSCRIBE" | "PSUBSCRIBE" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                        let subscribe =
+                            !command_name.starts_with("UN") && !command_name.starts_with("PUN");
+                        if subscribe && array.len() < 2 {
+                            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: command_name.to_lowercase()
+                            }));
+                        }
+                        let kind = if command_name.starts_with('P') {
+                            Kind::Pattern
+                        } else {
+                            Kind::Channel
+                        };
+                        let names = array[1..]
+                            .iter()
+                            .map(Self::extract_string)
+                            .collect::<Result<_, Error>>()?;
+                        Ok(if subscribe {
+                            Command::Subscribe { kind, names }
+                        } else {
+                            Command::Unsubscribe { kind, names }
+                        })
+                    }
+                    "INFO" => Ok(Command::Info {
+                        sections: array[1..]
+                            .iter()
+                            .map(|section| Ok(Self::extract_string(section)?.to_lowercase()))
+                            .collect::<Result<_, Error>>()?,
+                    }),
+                    "REPLICAOF" | "SLAVEOF" => {
+                        let host = Self::extract_string(&array[1])?;
+                        let port = Self::extract_string(&array[2])?;
+                        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+                            return Ok(Command::ReplicaOf { master: None });
+                        }
+                        let port = port
+                            .parse::<u16>()
+                            .map_err(|_| anyhow!(CommandError::InvalidMasterPort))?;
+                        Ok(Command::ReplicaOf {
+                            master: Some((host, port)),
+                        })
+                    }
+                    // Options come in pairs, ones only meant for replicas
+                    // or for other kinds of sync are accepted and ignored
+                    "REPLCONF" => {
+                        if array.len() % 2 == 0 {
+                            return Err(anyhow!(CommandError::SyntaxError));
+                        }
+                        let args = array[1..]
+                            .iter()
+                            .map(Self::extract_string)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        let (mut port, mut ip) = (None, None);
+                        for (i, pair) in args.chunks_exact(2).enumerate() {
+                            match pair[0].to_lowercase().as_str() {
+                                "ack" if i == 0 => {
+                                    let offset = pair[1]
+                                        .parse()
+                                        .map_err(|_| anyhow!(CommandError::NotAnInteger))?;
+                                    return Ok(Command::ReplConfAck { offset });
+                                }
+                                "listening-port" => {
+                                    port = Some(
+                                        pair[1]
+                                            .parse()
+                                            .map_err(|_| anyhow!(CommandError::NotAnInteger))?,
+                                    )
+                                }
+                                "ip-address" => ip = Some(pair[1].clone()),
+                                "capa" | "rdb-only" | "rdb-filter-only" => {}
+                                _ => {
+                                    return Err(anyhow!(CommandError::UnrecognizedReplConf(
+                                        pair[0].clone()
+                                    )))
+                                }
+                            }
+                        }
+                        Ok(Command::ReplConf { port, ip })
+                    }
+                    "PSYNC" => Ok(Command::Psync {
+                        replid: Self::extract_string(&array[1])?,
+                        offset: Self::extract_string(&array[2])?
+                            .parse()
+                            .map_err(|_| anyhow!(CommandError::NotAnInteger))?,
+                    }),
+                    "COMMAND" => {
+                        let Some(subcommand) = array.get(1) else {
+                            return Ok(Command::Command);
+                        };
+                        let subcommand = Self::extract_string(subcommand)?;
+                        let names = || {
+                            array[2..]
+                                .iter()
+                                .map(Self::extract_string)
+                                .collect::<Result<Vec<_>, _>>()
+                        };
+                        match subcommand.to_uppercase().as_str() {
+                            "COUNT" if array.len() == 2 => Ok(Command::CommandCount),
+                            "COUNT" => Err(anyhow!(CommandError::WrongNumberOfArguments {
+                                command: "command|count".to_string()
+                            })),
+                            "INFO" => Ok(Command::CommandInfo { names: names()? }),
+                            "DOCS" => Ok(Command::CommandDocs { names: names()? }),
+                            _ => Err(anyhow!(CommandError::UnknownSubcommand {
+                                command: "command".to_string(),
+                                subcommand,
+                            })),
+                        }
+                    }
+
+                    _ => Ok(Command::Unknown {
+                        command: command_name,
+                    }),
+                }
+            }
+            _ => Err(anyhow!(CommandError::InvalidCommandName)),
         }
     }
 
@@ -178,47 +2177,1844 @@ impl Command {
         }
     }
 
-    pub async fn exec<S>(
-        self,
-        db: Arc<DB<S, String, RespValue<'static>>>,
-    ) -> Result<Arc<RespValue<'static>>, Error>
-    where
-        S: Storage<String, RespValue<'static>> + 'static,
-    {
-        match self {
-            Command::Get { key } => {
-                match db.get(&key).map_err(|e| CommandError::StorageError(e))? {
-                    Some(value) => Ok(value),
-                    None => Ok(Arc::new(RespValue::Null)),
+    fn extract_integer(value: &RespValue) -> Result<i64, Error> {
+        match value {
+            RespValue::Integer(i) => Ok(*i),
+            RespValue::BulkString(Some(s)) | RespValue::SimpleString(s) => s
+                .parse::<i64>()
+                .map_err(|_| anyhow!(CommandError::NotAnInteger)),
+            _ => Err(anyhow!(CommandError::InvalidArgumentType)),
+        }
+    }
+
+    fn extract_float(value: &RespValue) -> Result<f64, Error> {
+        let f = match value {
+            RespValue::Integer(i) => *i as f64,
+            RespValue::Double(d) => *d,
+            _ => Self::extract_string(value)?
+                .parse::<f64>()
+                .map_err(|_| anyhow!(CommandError::NotAFloat))?,
+        };
+        if f.is_nan() {
+            return Err(anyhow!(CommandError::NotAFloat));
+        }
+        Ok(f)
+    }
+
+    fn extract_lon_lat(lon: &RespValue, lat: &RespValue) -> Result<(f64, f64), Error> {
+        let (lon, lat) = (Self::extract_float(lon)?, Self::extract_float(lat)?);
+        if !geo::valid(lon, lat) {
+            return Err(anyhow!(CommandError::InvalidLonLat(lon, lat)));
+        }
+        Ok((lon, lat))
+    }
+
+    // Unit argument of the GEO commands, as meters per unit
+    fn extract_geo_unit(value: &RespValue) -> Result<f64, Error> {
+        match Self::extract_string(value)?.to_lowercase().as_str() {
+            "m" => Ok(1.0),
+            "km" => Ok(1000.0),
+            "ft" => Ok(0.3048),
+            "mi" => Ok(1609.34),
+            _ => Err(anyhow!(CommandError::UnsupportedUnit)),
+        }
+    }
+
+    // FROMMEMBER m | FROMLONLAT lon lat, BYRADIUS r unit | BYBOX w h unit,
+    // then [ASC|DESC] [COUNT n [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+    // SORT options after the key, and the STORE destination when allowed
+    fn parse_sort_options(
+        args: &[RespValue],
+        can_store: bool,
+    ) -> Result<(SortOptions, Option<String>), Error> {
+        let mut options = SortOptions::default();
+        let mut store = None;
+        let arg = |i: usize| args.get(i).ok_or(anyhow!(CommandError::SyntaxError));
+        let mut i = 0;
+        while i < args.len() {
+            match Self::extract_string(&args[i])?.to_uppercase().as_str() {
+                "ASC" => options.desc = false,
+                "DESC" => options.desc = true,
+                "ALPHA" => options.alpha = true,
+                "LIMIT" => {
+                    let offset = Self::extract_integer(arg(i + 1)?)?;
+                    let count = Self::extract_integer(arg(i + 2)?)?;
+                    options.limit = Some((offset, count));
+                    i += 2;
+                }
+                "BY" => {
+                    options.by = Some(Self::extract_string(arg(i + 1)?)?);
+                    i += 1;
+                }
+                "GET" => {
+                    options.get.push(Self::extract_string(arg(i + 1)?)?);
+                    i += 1;
+                }
+                "STORE" if can_store => {
+                    store = Some(Self::extract_string(arg(i + 1)?)?);
+                    i += 1;
+                }
+                _ => return Err(anyhow!(CommandError::SyntaxError)),
+            }
+            i += 1;
+        }
+        Ok((options, store))
+    }
+
+    fn parse_geo_query(args: &[RespValue]) -> Result<GeoQuery, Error> {
+        let mut origin = None;
+        let mut shape = None;
+        let mut unit = 1.0;
+        let mut ascending = None;
+        let mut count = None;
+        let (mut any, mut with_coord, mut with_dist, mut with_hash) = (false, false, false, false);
+
+        let arg = |i: usize| args.get(i).ok_or(anyhow!(CommandError::SyntaxError));
+        let mut i = 0;
+        while i < args.len() {
+            match Self::extract_string(&args[i])?.to_uppercase().as_str() {
+                "FROMMEMBER" if origin.is_none() => {
+                    origin = Some(GeoOrigin::Member(Self::extract_string(arg(i + 1)?)?));
+                    i += 2;
+                }
+                "FROMLONLAT" if origin.is_none() => {
+                    let (lon, lat) = Self::extract_lon_lat(arg(i + 1)?, arg(i + 2)?)?;
+                    origin = Some(GeoOrigin::LonLat(lon, lat));
+                    i += 3;
+                }
+                "BYRADIUS" if shape.is_none() => {
+                    let radius = Self::extract_float(arg(i + 1)?)?;
+                    unit = Self::extract_geo_unit(arg(i + 2)?)?;
+                    shape = Some(GeoShape::Radius(radius * unit));
+                    i += 3;
+                }
+                "BYBOX" if shape.is_none() => {
+                    let width = Self::extract_float(arg(i + 1)?)?;
+                    let height = Self::extract_float(arg(i + 2)?)?;
+                    unit = Self::extract_geo_unit(arg(i + 3)?)?;
+                    shape = Some(GeoShape::Box(width * unit, height * unit));
+                    i += 4;
+                }
+                "ASC" => {
+                    ascending = Some(true);
+                    i += 1;
+                }
+                "DESC" => {
+                    ascending = Some(false);
+                    i += 1;
+                }
+                "COUNT" => {
+                    let n = Self::extract_integer(arg(i + 1)?)?;
+                    if n <= 0 {
+                        return Err(anyhow!(CommandError::MustBePositive));
+                    }
+                    count = Some(n as usize);
+                    i += 2;
+                    if let Some(next) = args.get(i) {
+                        if Self::extract_string(next)?.eq_ignore_ascii_case("ANY") {
+                            any = true;
+                            i += 1;
+                        }
+                    }
+                }
+                "WITHCOORD" => {
+                    with_coord = true;
+                    i += 1;
+                }
+                "WITHDIST" => {
+                    with_dist = true;
+                    i += 1;
+                }
+                "WITHHASH" => {
+                    with_hash = true;
+                    i += 1;
+                }
+                _ => return Err(anyhow!(CommandError::SyntaxError)),
+            }
+        }
+
+        let (origin, shape) = match (origin, shape) {
+            (Some(origin), Some(shape)) => (origin, shape),
+            _ => return Err(anyhow!(CommandError::SyntaxError)),
+        };
+        // Like Redis, a plain COUNT returns the nearest matches
+        if count.is_some() && ascending.is_none() && !any {
+            ascending = Some(true);
+        }
+        Ok(GeoQuery {
+            origin,
+            shape,
+            unit,
+            ascending,
+            count,
+            any,
+            with_coord,
+            with_dist,
+            with_hash,
+        })
+    }
+
+    // LEFT|RIGHT argument of LMOVE/BLMOVE, true for LEFT
+    fn extract_side(value: &RespValue) -> Result<bool, Error> {
+        match Self::extract_string(value)?.to_uppercase().as_str() {
+            "LEFT" => Ok(true),
+            "RIGHT" => Ok(false),
+            _ => Err(anyhow!(CommandError::SyntaxError)),
+        }
+    }
+
+    // Blocking timeout in (fractional) seconds, 0 meaning wait forever
+    fn extract_timeout(value: &RespValue) -> Result<Option<Duration>, Error> {
+        let seconds = match value {
+            RespValue::Integer(i) => *i as f64,
+            _ => Self::extract_string(value)?
+                .parse::<f64>()
+                .map_err(|_| anyhow!(CommandError::InvalidTimeout))?,
+        };
+        if !seconds.is_finite() {
+            return Err(anyhow!(CommandError::InvalidTimeout));
+        }
+        if seconds < 0.0 {
+            return Err(anyhow!(CommandError::NegativeTimeout));
+        }
+        if seconds == 0.0 {
+            return Ok(None);
+        }
+        Duration::try_from_secs_f64(seconds)
+            .map(Some)
+            .map_err(|_| anyhow!(CommandError::InvalidTimeout))
+    }
+
+    // Shared by EXPIRE/PEXPIRE/EXPIREAT/PEXPIREAT: key, amount, [NX|XX|GT|LT]
+    fn parse_expire(
+        array: &[RespValue],
+        command: &str,
+    ) -> Result<(String, i64, ExpireCondition), Error> {
+        if array.len() != 3 && array.len() != 4 {
+            return Err(anyhow!(CommandError::WrongNumberOfArguments {
+                command: command.to_string()
+            }));
+        }
+        let key = Self::extract_string(&array[1])?;
+        let amount = Self::extract_integer(&array[2])?;
+        let condition = match array.get(3) {
+            None => ExpireCondition::Always,
+            Some(flag) => match Self::extract_string(flag)?.to_uppercase().as_str() {
+                "NX" => ExpireCondition::IfNoExpiry,
+                "XX" => ExpireCondition::IfHasExpiry,
+                "GT" => ExpireCondition::IfGreater,
+                "LT" => ExpireCondition::IfLess,
+                _ => return Err(anyhow!(CommandError::SyntaxError)),
+            },
+        };
+        Ok((key, amount, condition))
+    }
+
+    // Protocol the connection speaks after HELLO. Its AUTH goes through
+    // Namespaces::authenticate like the AUTH command.
+    pub fn hello_protocol(protover: Option<i64>, current: u8) -> Result<u8, CommandError> {
+        match protover {
+            None => Ok(current),
+            Some(version @ 2..=3) => Ok(version as u8),
+            Some(_) => Err(CommandError::NoProto),
+        }
+    }
+
+    fn extract_db_index(value: &RespValue) -> Result<usize, Error> {
+        let index = Self::extract_integer(value)?;
+        usize::try_from(index).map_err(|_| anyhow!(CommandError::DbIndexOutOfRange))
+    }
+
+    // Commands that may wait on other clients, their run time says nothing
+    // about server latency
+    pub fn is_blocking(&self) -> bool {
+        matches!(self, Command::BLMove { .. })
+    }
+
+    // Commands of several steps that a change to their keys in between would
+    // break, run holding their keys: MIGRATE deletes what it sent once the
+    // target took it
+    pub fn is_compound(&self) -> bool {
+        matches!(self, Command::Migrate { copy: false, .. })
+    }
+
+    // Keys the command reads or writes in the selected db. None when it
+    // reaches into another db, so it has to be ordered against everything.
+    pub fn keys(&self) -> Option<Vec<&str>> {
+        let keys = match self {
+            Command::Get { key }
+            | Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::PSetEx { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::GetRange { key, .. }
+            | Command::GetEx { key, .. }
+            | Command::GetDel { key }
+            | Command::StrLen { key }
+            | Command::IncrBy { key, .. }
+            | Command::Expire { key, .. }
+            | Command::PExpire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::PExpireAt { key, .. }
+            | Command::Persist { key }
+            | Command::Ttl { key }
+            | Command::PTtl { key }
+            | Command::ExpireTime { key }
+            | Command::PExpireTime { key }
+            | Command::Type { key }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::LPop { key, .. }
+            | Command::RPop { key, .. }
+            | Command::LLen { key }
+            | Command::LRange { key, .. }
+            | Command::LIndex { key, .. }
+            | Command::LPos { key, .. }
+            | Command::LInsert { key, .. }
+            | Command::LSet { key, .. }
+            | Command::LRem { key, .. }
+            | Command::LTrim { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::SRem { key, .. }
+            | Command::SMembers { key }
+            | Command::SPop { key, .. }
+            | Command::SRandMember { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::ZScore { key, .. }
+            | Command::ZCard { key }
+            | Command::ZRange { key, .. }
+            | Command::GeoAdd { key, .. }
+            | Command::GeoPos { key, .. }
+            | Command::GeoDist { key, .. }
+            | Command::GeoSearch { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HIncrBy { key, .. }
+            | Command::HIncrByFloat { key, .. }
+            | Command::HRandField { key, .. }
+            | Command::ObjectFreq { key }
+            | Command::ObjectIdleTime { key }
+            | Command::ObjectEncoding { key }
+            | Command::Dump { key }
+            | Command::Restore { key, .. } => vec![key.as_str()],
+            #[cfg(feature = "json")]
+            Command::JsonSet { key, .. }
+            | Command::JsonGet { key, .. }
+            | Command::JsonDel { key, .. }
+            | Command::JsonArrAppend { key, .. } => vec![key.as_str()],
+            Command::Del { keys }
+            | Command::Unlink { keys }
+            | Command::Touch { keys }
+            | Command::SAlgebra { keys, .. }
+            | Command::SInterCard { keys, .. }
+            | Command::Migrate { keys, .. } => keys.iter().map(String::as_str).collect(),
+            Command::SAlgebraStore {
+                destination, keys, ..
+            } => std::iter::once(destination)
+                .chain(keys)
+                .map(String::as_str)
+                .collect(),
+            Command::Copy {
+                source,
+                destination,
+                db: None,
+                ..
+            }
+            | Command::LMove {
+                source,
+                destination,
+                ..
+            }
+            | Command::BLMove {
+                source,
+                destination,
+                ..
+            } => vec![source.as_str(), destination.as_str()],
+            // Patterns name keys only known once the elements are read
+            Command::Sort { options, .. }
+                if options.by.is_some() || options.get.iter().any(|get| get != "#") =>
+            {
+                return None
+            }
+            Command::Sort { key, store, .. } => std::iter::once(key)
+                .chain(store)
+                .map(String::as_str)
+                .collect(),
+            // Reads queued after it must already be tracked
+            Command::Copy { .. }
+            | Command::Move { .. }
+            | Command::ClientTracking { .. }
+            | Command::DebugReload => return None,
+            // They go over every key the index covers
+            #[cfg(feature = "search")]
+            Command::FtCreate { .. } | Command::FtSearch { .. } => return None,
+            _ => vec![],
+        };
+        Some(keys)
+    }
+
+    // A panic in the command is caught and answered with an internal error,
+    // the connection or shard running it goes on
+    pub async fn exec<S>(self, ctx: ExecContext<S>) -> Result<Arc<RespValue<'static>>, Error>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        let panics = ctx.panics.clone();
+        panics
+            .guard("Command", self.run(ctx))
+            .await
+            .unwrap_or_else(|| Err(anyhow!(CommandError::Internal)))
+    }
+
+    async fn run<S>(self, ctx: ExecContext<S>) -> Result<Arc<RespValue<'static>>, Error>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        let db = ctx.db();
+        match self {
+            Command::Get { key } => match db
+                .get_typed(&key, ValueType::String)
+                .map_err(CommandError::from_storage)?
+            {
+                Some(value) => Ok(Self::string_reply(&value)),
+                None => Ok(reply::nil()),
+            },
+            Command::GetEx { key, option } => {
+                let ttl = match option {
+                    GetExOption::None => TtlUpdate::Keep,
+                    GetExOption::Persist => TtlUpdate::Persist,
+                    GetExOption::Ex(amount)
+                    | GetExOption::Px(amount)
+                    | GetExOption::ExAt(amount)
+                    | GetExOption::PxAt(amount)
+                        if amount <= 0 =>
+                    {
+                        return Err(anyhow!(CommandError::InvalidExpireTime {
+                            command: "getex".to_string()
+                        }))
+                    }
+                    GetExOption::Ex(seconds) => {
+                        TtlUpdate::At(Self::deadline(seconds, 1000, db.clock().now_ms(), "getex")?)
+                    }
+                    GetExOption::Px(ms) => {
+                        TtlUpdate::At(Self::deadline(ms, 1, db.clock().now_ms(), "getex")?)
+                    }
+                    GetExOption::ExAt(ts) => TtlUpdate::At(Self::deadline(ts, 1000, 0, "getex")?),
+                    GetExOption::PxAt(ts) => TtlUpdate::At(Self::deadline(ts, 1, 0, "getex")?),
+                };
+                match db
+                    .get_ex(&key, ValueType::String, ttl)
+                    .map_err(CommandError::from_storage)?
+                {
+                    Some(value) => Ok(Self::string_reply(&value)),
+                    None => Ok(reply::nil()),
+                }
+            }
+            Command::GetDel { key } => {
+                match db
+                    .get_del(&key, ValueType::String)
+                    .map_err(CommandError::from_storage)?
+                {
+                    Some(value) => Ok(Self::string_reply(&value)),
+                    None => Ok(reply::nil()),
+                }
+            }
+            Command::StrLen { key } => {
+                let value = db
+                    .get_typed(&key, ValueType::String)
+                    .map_err(CommandError::from_storage)?;
+                let len = value.as_deref().and_then(ByteString::stored_len);
+                Ok(reply::integer(len.unwrap_or(0) as i64))
+            }
+            Command::IncrBy { key, increment } => {
+                let value = db
+                    .update_typed(key, ValueType::String, |current| {
+                        let current = match current.as_deref() {
+                            None => 0,
+                            Some(current) => match ByteString::from_stored(current)
+                                .and_then(|s| std::str::from_utf8(s.as_bytes()).ok()?.parse().ok())
+                            {
+                                Some(current) => current,
+                                None => return (Update::Keep, Err(CommandError::NotAnInteger)),
+                            },
+                        };
+                        match i64::checked_add(current, increment) {
+                            Some(value) => (Update::Set(Value::str(value.to_string())), Ok(value)),
+                            None => (Update::Keep, Err(CommandError::IncrementOverflow)),
+                        }
+                    })
+                    .map_err(CommandError::from_storage)??;
+                Ok(reply::integer(value))
+            }
+            Command::Set { key, value } => {
+                match db
+                    .set(key, Self::stored_string(db, value))
+                    .map_err(CommandError::StorageError)
+                {
+                    Ok(_) => Ok(reply::ok()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::SetEx {
+                key,
+                seconds,
+                value,
+            } => Self::exec_set_ex(db, key, seconds, 1000, value, "setex"),
+            Command::PSetEx {
+                key,
+                milliseconds,
+                value,
+            } => Self::exec_set_ex(db, key, milliseconds, 1, value, "psetex"),
+            Command::GetRange { key, start, stop } => {
+                let value = db
+                    .get_typed(&key, ValueType::String)
+                    .map_err(CommandError::from_storage)?;
+                let string = value.as_deref().and_then(ByteString::from_stored);
+                let bytes = string.as_ref().map_or(&[][..], ByteString::as_bytes);
+                let selected = match resolve_range(start, stop, bytes.len()) {
+                    Some((from, to)) => &bytes[from..to],
+                    None => &[],
+                };
+                Ok(Arc::new(ByteString::new(selected).to_reply()))
+            }
+            Command::SetRange { key, offset, value } => {
+                if offset < 0 {
+                    return Err(anyhow!(CommandError::OffsetOutOfRange));
+                }
+                let offset = offset as usize;
+                if offset + value.len() > MAX_STRING_LENGTH {
+                    return Err(anyhow!(CommandError::OffsetOutOfRange));
+                }
+
+                let compression = db.compression();
+                let length = db
+                    .update_typed(key, ValueType::String, |current| {
+                        let mut string = match current.as_deref() {
+                            // Nothing to write, so the key is not created
+                            None if value.is_empty() => return (Update::Keep, Ok(0)),
+                            None => ByteString::new(vec![]),
+                            Some(current) => match ByteString::from_stored(current) {
+                                Some(string) => string,
+                                None => return (Update::Keep, Err(CommandError::WrongType)),
+                            },
+                        };
+                        if value.is_empty() {
+                            return (Update::Keep, Ok(string.len()));
+                        }
+                        // Bytes, so an offset may fall inside a character
+                        string.set_range(offset, value.as_bytes());
+                        let len = string.len();
+                        let stored = string
+                            .into_owned()
+                            .into_stored(&compression, db.compression_stats());
+                        (Update::Set(stored), Ok(len))
+                    })
+                    .map_err(CommandError::from_storage)??;
+                Ok(reply::integer(length as i64))
+            }
+            Command::Del { keys } => match db.delete(&keys).map_err(CommandError::StorageError) {
+                Ok(removed) => Ok(reply::integer(removed as i64)),
+                Err(e) => Err(e.into()),
+            },
+            Command::Unlink { keys } => {
+                match db.unlink(&keys).map_err(CommandError::StorageError) {
+                    Ok(removed) => Ok(reply::integer(removed as i64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Copy {
+                source,
+                destination,
+                db: dst_index,
+                replace,
+            } => {
+                let dst_db = match dst_index {
+                    Some(index) => ctx.dbs.get(index).ok_or(CommandError::DbIndexOutOfRange)?,
+                    None => db,
+                };
+                if Arc::ptr_eq(db, dst_db) && source == destination {
+                    return Err(anyhow!(CommandError::SameObject));
+                }
+                match db
+                    .copy_to(&source, dst_db, destination, replace)
+                    .map_err(CommandError::StorageError)
+                {
+                    Ok(copied) => Ok(reply::integer(copied as i64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Move { key, db: dst_index } => {
+                let dst_db = ctx
+                    .dbs
+                    .get(dst_index)
+                    .ok_or(CommandError::DbIndexOutOfRange)?;
+                if Arc::ptr_eq(db, dst_db) {
+                    return Err(anyhow!(CommandError::SameObject));
+                }
+                match db.move_to(&key, dst_db).map_err(CommandError::StorageError) {
+                    Ok(moved) => Ok(reply::integer(moved as i64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Dump { key } => match db.get(&key).map_err(CommandError::StorageError)? {
+                Some(value) => {
+                    let payload = dump::serialize(&value).map_err(CommandError::DumpError)?;
+                    Ok(Arc::new(RespValue::BulkString(Some(Cow::Owned(
+                        dump::to_hex(&payload),
+                    )))))
+                }
+                None => Ok(reply::nil()),
+            },
+            Command::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+            } => {
+                if ttl < 0 {
+                    return Err(anyhow!(CommandError::InvalidTtl));
+                }
+                let value = dump::from_hex(&payload)
+                    .and_then(|bytes| dump::deserialize(&bytes))
+                    .map_err(CommandError::DumpError)?;
+                let value = listpack::compact(&value, &db.listpack_limits()).unwrap_or(value);
+                let value = bytestring::stored(value, &db.compression(), db.compression_stats());
+                let when = match (ttl, absttl) {
+                    (0, _) => None,
+                    (ttl, true) => Some(ttl as u64),
+                    (ttl, false) => Some(Self::deadline(ttl, 1, db.clock().now_ms(), "restore")?),
+                };
+                // A deadline already behind us restores nothing, like Redis
+                if when.is_some_and(|when| when <= db.clock().now_ms()) {
+                    if replace {
+                        db.delete(&[key]).map_err(CommandError::StorageError)?;
+                    } else if db.exists(&key).map_err(CommandError::StorageError)? {
+                        return Err(anyhow!(CommandError::BusyKey));
+                    }
+                    return Ok(reply::ok());
+                }
+                if !db
+                    .restore(key, value, when, replace)
+                    .map_err(CommandError::StorageError)?
+                {
+                    return Err(anyhow!(CommandError::BusyKey));
+                }
+                Ok(reply::ok())
+            }
+            Command::Migrate {
+                target,
+                keys,
+                copy,
+                replace,
+            } => migrate::migrate(db, target, keys, copy, replace).await,
+            // The connection switches its db_index itself, this only validates
+            Command::Select { db: index } => {
+                if index >= ctx.dbs.len() {
+                    return Err(anyhow!(CommandError::DbIndexOutOfRange));
+                }
+                Ok(reply::ok())
+            }
+            // The connection moves to the user's namespace itself
+            Command::Auth { user, password } => {
+                ctx.namespaces.authenticate(&user, &password)?;
+                Ok(reply::ok())
+            }
+            // Likewise the connection clears its own state on RESET
+            Command::Reset => {
+                ctx.tracking.disable(ctx.client_id);
+                ctx.pubsub.disconnect(ctx.client_id);
+                Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("RESET"))))
+            }
+            // And switches protocol after a successful HELLO
+            Command::Hello { protover, auth } => {
+                let proto = Self::hello_protocol(protover, ctx.protocol)?;
+                if let Some((user, password)) = &auth {
+                    ctx.namespaces.authenticate(user, password)?;
+                }
+                let bulk_str = |s: &'static str| RespValue::BulkString(Some(Cow::Borrowed(s)));
+                let fields = [
+                    ("server", bulk_str("redis")),
+                    ("version", bulk_str(REDIS_COMPAT_VERSION)),
+                    ("proto", RespValue::Integer(proto as i64)),
+                    ("id", RespValue::Integer(ctx.client_id as i64)),
+                    ("mode", bulk_str("standalone")),
+                    ("role", bulk_str("master")),
+                    ("modules", RespValue::Array(Some(vec![]))),
+                ];
+                let fields = fields
+                    .into_iter()
+                    .map(|(name, value)| (bulk_str(name), value))
+                    .collect();
+                Ok(Arc::new(map_reply(proto, fields)))
+            }
+            Command::Expire {
+                key,
+                seconds,
+                condition,
+            } => {
+                let when = Self::deadline(seconds, 1000, db.clock().now_ms(), "expire")?;
+                Self::exec_expire_at(db, key, when, condition)
+            }
+            Command::PExpire {
+                key,
+                milliseconds,
+                condition,
+            } => {
+                let when = Self::deadline(milliseconds, 1, db.clock().now_ms(), "pexpire")?;
+                Self::exec_expire_at(db, key, when, condition)
+            }
+            Command::ExpireAt {
+                key,
+                timestamp,
+                condition,
+            } => {
+                let when = Self::deadline(timestamp, 1000, 0, "expireat")?;
+                Self::exec_expire_at(db, key, when, condition)
+            }
+            Command::PExpireAt {
+                key,
+                timestamp,
+                condition,
+            } => {
+                let when = Self::deadline(timestamp, 1, 0, "pexpireat")?;
+                Self::exec_expire_at(db, key, when, condition)
+            }
+            Command::Persist { key } => {
+                match db.persist(&key).map_err(CommandError::StorageError) {
+                    Ok(removed) => Ok(reply::integer(removed as i64)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+                kind,
+            } => {
+                // The pass over the keyspace holds it and runs off the
+                // worker, the filtering after it yields as it goes
+                let budget = ctx.timeout.budget();
+                let (next, keys) = backend::blocking(|| {
+                    db.scan_checked(cursor, count, |step| Ok(budget.check(step)?))
+                })
+                .map_err(CommandError::from_storage)?;
+                let mut found = vec![];
+                for (step, key) in keys.into_iter().enumerate() {
+                    budget.pause(step).await?;
+                    if let Some(pattern) = &pattern {
+                        if !glob_match(pattern.as_bytes(), key.as_bytes(), false) {
+                            continue;
+                        }
+                    }
+                    if let Some(kind) = kind {
+                        // Also skips keys gone since they were listed
+                        if db.type_of(&key).map_err(CommandError::StorageError)? != Some(kind) {
+                            continue;
+                        }
+                    }
+                    found.push(RespValue::BulkString(Some(Cow::Owned(key))));
+                }
+                Ok(Arc::new(RespValue::Array(Some(vec![
+                    RespValue::BulkString(Some(Cow::Owned(next.to_string()))),
+                    RespValue::Array(Some(found)),
+                ]))))
+            }
+            Command::Type { key } => {
+                let name = match db.get(&key).map_err(CommandError::StorageError)? {
+                    Some(value) => value.value_type().name(),
+                    None => "none",
+                };
+                Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed(name))))
+            }
+            Command::Ttl { key } => Self::exec_expiry(db, key, |when| {
+                // Round up like Redis so a live key never reports 0 too early
+                (when.saturating_sub(db.clock().now_ms()) as i64 + 500) / 1000
+            }),
+            Command::PTtl { key } => Self::exec_expiry(db, key, |when| {
+                when.saturating_sub(db.clock().now_ms()) as i64
+            }),
+            Command::ExpireTime { key } => Self::exec_expiry(db, key, |when| (when / 1000) as i64),
+            Command::PExpireTime { key } => Self::exec_expiry(db, key, |when| when as i64),
+            Command::LPush { key, values } => list::push(db, key, values, true),
+            Command::RPush { key, values } => list::push(db, key, values, false),
+            Command::LPop { key, count } => list::pop(db, key, count, true),
+            Command::RPop { key, count } => list::pop(db, key, count, false),
+            Command::LLen { key } => list::len(db, &key),
+            Command::LRange { key, start, stop } => list::range(db, &key, start, stop),
+            Command::LIndex { key, index } => list::index(db, &key, index),
+            Command::LPos {
+                key,
+                element,
+                options,
+            } => list::pos(db, &key, &element, options),
+            Command::LInsert {
+                key,
+                before,
+                pivot,
+                element,
+            } => list::insert(db, key, before, &pivot, element),
+            Command::LSet {
+                key,
+                index,
+                element,
+            } => list::set(db, key, index, element),
+            Command::LRem {
+                key,
+                count,
+                element,
+            } => list::rem(db, key, count, &element),
+            Command::LTrim { key, start, stop } => list::trim(db, key, start, stop),
+            Command::LMove {
+                source,
+                destination,
+                from_left,
+                to_left,
+            } => list::lmove(db, source, destination, from_left, to_left),
+            Command::BLMove {
+                source,
+                destination,
+                from_left,
+                to_left,
+                timeout,
+            } => {
+                let (source, destination, ctx) = (&source, &destination, &ctx);
+                list::blmove(db, timeout, || async move {
+                    // Moved and sent on holding both keys, like any
+                    // propagated write, and sent as the LMOVE it made
+                    let propagate = ctx.propagator.active();
+                    let _locked = match propagate {
+                        true => Some(
+                            ctx.keylocks
+                                .exclusive(ctx.db_index, &[source, destination])
+                                .await,
+                        ),
+                        false => None,
+                    };
+                    let reply =
+                        list::lmove(db, source.clone(), destination.clone(), from_left, to_left)?;
+                    if propagate && *reply != RespValue::BulkString(None) {
+                        let side = |left| if left { "LEFT" } else { "RIGHT" };
+                        let mut frame = BytesMut::new();
+                        propagate::encode(
+                            &["LMOVE", source, destination, side(from_left), side(to_left)],
+                            &mut frame,
+                        );
+                        ctx.propagator
+                            .propagate(ctx.db_index, &frame)
+                            .map_err(|e| anyhow!(CommandError::AofWrite(e.to_string())))?;
+                    }
+                    Ok(reply)
+                })
+                .await
+            }
+            Command::SAdd { key, members } => set::add(db, key, members),
+            Command::SRem { key, members } => set::rem(db, key, members),
+            Command::SMembers { key } => set::all(db, &key),
+            Command::SPop { key, count } => set::pop(db, key, count),
+            Command::SRandMember { key, count } => set::rand_member(db, &key, count),
+            // These walk whole sets under a lock and can't pause, so the
+            // runtime is told to move other tasks off this worker meanwhile
+            Command::SAlgebra { op, keys } => {
+                let budget = ctx.timeout.budget();
+                backend::blocking(|| set::algebra(db, op, &keys, budget))
+            }
+            Command::SInterCard { keys, limit } => {
+                let budget = ctx.timeout.budget();
+                backend::blocking(|| set::inter_card(db, &keys, limit, budget))
+            }
+            Command::SAlgebraStore {
+                op,
+                destination,
+                keys,
+            } => {
+                let budget = ctx.timeout.budget();
+                backend::blocking(|| set::algebra_store(db, op, destination, &keys, budget))
+            }
+            Command::Sort {
+                key,
+                options,
+                store: None,
+            } => {
+                let budget = ctx.timeout.budget();
+                backend::blocking(|| sort::sort(db, &key, &options, budget))
+            }
+            Command::Sort {
+                key,
+                options,
+                store: Some(destination),
+            } => {
+                let budget = ctx.timeout.budget();
+                backend::blocking(|| sort::sort_store(db, &key, &options, destination, budget))
+            }
+            Command::ZAdd { key, members } => zset::add(db, key, members),
+            Command::ZScore { key, member } => zset::get_score(db, &key, &member),
+            Command::ZCard { key } => zset::card(db, &key),
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => zset::range(db, &key, start, stop, with_scores),
+            Command::GeoAdd { key, items } => geo::add(db, key, items),
+            Command::GeoPos { key, members } => geo::pos(db, &key, &members),
+            Command::GeoDist {
+                key,
+                from,
+                to,
+                unit,
+            } => geo::dist(db, &key, &from, &to, unit),
+            Command::GeoSearch { key, query } => geo::search(db, &key, query),
+            Command::HSet { key, fields } => hash::set(db, key, fields),
+            Command::HGet { key, field } => hash::get(db, &key, &field),
+            Command::HIncrBy {
+                key,
+                field,
+                increment,
+            } => hash::incr_by(db, key, field, increment),
+            Command::HIncrByFloat {
+                key,
+                field,
+                increment,
+            } => hash::incr_by_float(db, key, field, increment),
+            Command::HRandField {
+                key,
+                count,
+                with_values,
+            } => hash::rand_field(db, &key, count, with_values),
+            #[cfg(feature = "search")]
+            Command::FtCreate { index, schema } => {
+                ctx.search.create(db, index, schema)?;
+                Ok(reply::ok())
+            }
+            #[cfg(feature = "search")]
+            Command::FtSearch {
+                index,
+                query,
+                offset,
+                count,
+                no_content,
+            } => Ok(reply::intern(
+                ctx.search
+                    .search(db, &index, &query, offset, count, no_content)?,
+            )),
+            #[cfg(feature = "search")]
+            Command::FtDropIndex { index } => {
+                ctx.search.drop_index(db, &index)?;
+                Ok(reply::ok())
+            }
+            #[cfg(feature = "json")]
+            Command::JsonSet {
+                key,
+                path,
+                value,
+                condition,
+            } => json::set(db, key, &path, value, condition),
+            #[cfg(feature = "json")]
+            Command::JsonGet { key, paths } => json::get(db, &key, &paths),
+            #[cfg(feature = "json")]
+            Command::JsonDel { key, path } => json::del(db, key, &path),
+            #[cfg(feature = "json")]
+            Command::JsonArrAppend { key, path, values } => {
+                json::arr_append(db, key, &path, values)
+            }
+            Command::ObjectFreq { key } => {
+                if !db.eviction_policy().is_lfu() {
+                    return Err(anyhow!(CommandError::LfuNotSelected));
+                }
+                match db.frequency(&key).map_err(CommandError::StorageError)? {
+                    Some(freq) => Ok(reply::integer(freq as i64)),
+                    None => Ok(reply::nil()),
+                }
+            }
+            Command::ObjectIdleTime { key } => {
+                if db.eviction_policy().is_lfu() {
+                    return Err(anyhow!(CommandError::LfuSelected));
+                }
+                match db.idle_time(&key).map_err(CommandError::StorageError)? {
+                    Some(idle) => Ok(reply::integer(idle as i64)),
+                    None => Ok(reply::nil()),
+                }
+            }
+            Command::Touch { keys } => {
+                let found = db.touch_keys(&keys).map_err(CommandError::StorageError)?;
+                Ok(reply::integer(found as i64))
+            }
+            Command::ObjectEncoding { key } => {
+                match db.get(&key).map_err(CommandError::StorageError)? {
+                    Some(value) => Ok(Arc::new(RespValue::BulkString(Some(Cow::Borrowed(
+                        listpack::encoding(&value),
+                    ))))),
+                    None => Ok(reply::nil()),
+                }
+            }
+            Command::ClusterKeySlot { key } => Ok(reply::integer(slot::key_slot(&key) as i64)),
+            Command::HotKeys { count } => {
+                let pairs = db
+                    .hot_keys(count)
+                    .into_iter()
+                    .map(|(key, hits)| {
+                        (
+                            RespValue::BulkString(Some(Cow::Owned(key))),
+                            RespValue::Integer(hits as i64),
+                        )
+                    })
+                    .collect();
+                Ok(Arc::new(map_reply(ctx.protocol, pairs)))
+            }
+            Command::BigKeys { count, samples } => {
+                let budget = ctx.timeout.budget();
+                let report = backend::blocking(|| bigkeys::scan(db, samples, count, budget))?;
+                Ok(Arc::new(RespValue::BulkString(Some(Cow::Owned(
+                    report.to_string(),
+                )))))
+            }
+            Command::ConfigGet { patterns } => {
+                let pairs = ctx
+                    .config_values()
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        patterns
+                            .iter()
+                            .any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes(), true))
+                    })
+                    .map(|(name, value)| {
+                        (
+                            RespValue::BulkString(Some(Cow::Borrowed(name))),
+                            RespValue::BulkString(Some(Cow::Owned(value))),
+                        )
+                    })
+                    .collect();
+                Ok(Arc::new(map_reply(ctx.protocol, pairs)))
+            }
+            Command::ConfigSet { parameter, value } => {
+                ctx.config_set(&parameter, &value)?;
+                Ok(reply::ok())
+            }
+            Command::ConfigResetStat => {
+                ctx.cmdstats.reset();
+                Ok(reply::ok())
+            }
+            Command::ClientId => Ok(reply::integer(ctx.client_id as i64)),
+            Command::ClientTracking { options } => {
+                match options {
+                    Some(options) => {
+                        if !ctx.tracking.enable(ctx.client_id, options) {
+                            return Err(anyhow!(CommandError::NoSuchRedirect));
+                        }
+                    }
+                    None => ctx.tracking.disable(ctx.client_id),
+                }
+                Ok(reply::ok())
+            }
+            // Like Redis' SKIPME default, a client doesn't kill itself
+            Command::ClientKill { id } => {
+                let killed = id != ctx.client_id && ctx.clients.kill(id);
+                Ok(reply::integer(killed as i64))
+            }
+            Command::LatencyLatest => {
+                let latest = ctx
+                    .latency
+                    .latest()
+                    .into_iter()
+                    .map(|(event, sample, max_ms)| {
+                        RespValue::Array(Some(vec![
+                            RespValue::BulkString(Some(Cow::Owned(event))),
+                            RespValue::Integer(sample.time as i64),
+                            RespValue::Integer(sample.latency_ms as i64),
+                            RespValue::Integer(max_ms as i64),
+                        ]))
+                    })
+                    .collect();
+                Ok(Arc::new(RespValue::Array(Some(latest))))
+            }
+            Command::LatencyHistory { event } => {
+                let history = ctx
+                    .latency
+                    .history(&event)
+                    .into_iter()
+                    .map(|sample| {
+                        RespValue::Array(Some(vec![
+                            RespValue::Integer(sample.time as i64),
+                            RespValue::Integer(sample.latency_ms as i64),
+                        ]))
+                    })
+                    .collect();
+                Ok(Arc::new(RespValue::Array(Some(history))))
+            }
+            Command::LatencyReset { events } => {
+                let reset = ctx.latency.reset(&events);
+                Ok(reply::integer(reset as i64))
+            }
+            Command::LatencyHistogram { commands } => {
+                let bulk = |s: &'static str| RespValue::BulkString(Some(Cow::Borrowed(s)));
+                let histograms = ctx
+                    .cmdstats
+                    .histograms(&commands)
+                    .into_iter()
+                    .map(|histogram| {
+                        let buckets = histogram
+                            .buckets
+                            .into_iter()
+                            .map(|(usec, calls)| {
+                                (
+                                    RespValue::Integer(usec as i64),
+                                    RespValue::Integer(calls as i64),
+                                )
+                            })
+                            .collect();
+                        let fields = vec![
+                            (bulk("calls"), RespValue::Integer(histogram.calls as i64)),
+                            (bulk("histogram_usec"), map_reply(ctx.protocol, buckets)),
+                        ];
+                        (bulk(histogram.name), map_reply(ctx.protocol, fields))
+                    })
+                    .collect();
+                Ok(Arc::new(map_reply(ctx.protocol, histograms)))
+            }
+            Command::DebugReload => {
+                for db in ctx.dbs.iter() {
+                    let limits = db.listpack_limits();
+                    let compression = db.compression();
+                    db.reload(|value| {
+                        let payload = dump::serialize(value).map_err(CommandError::DumpError)?;
+                        let value = dump::deserialize(&payload).map_err(CommandError::DumpError)?;
+                        let packed = ByteString::from_stored(&value).and_then(|string| {
+                            compression::compress(string.as_bytes(), &compression)
+                        });
+                        Ok(packed
+                            .unwrap_or_else(|| listpack::compact(&value, &limits).unwrap_or(value)))
+                    })?;
+                }
+                Ok(reply::ok())
+            }
+            Command::DebugStringMatchLen => {
+                let matched = glob::fuzz(STRINGMATCH_FUZZ_ROUNDS);
+                debug!(
+                    "Glob fuzz: {} of {} matched",
+                    matched, STRINGMATCH_FUZZ_ROUNDS
+                );
+                Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed(
+                    "Apparently Redis did not crash: test passed",
+                ))))
+            }
+            Command::DebugChangeReplId => {
+                ctx.replication.change_replid();
+                Ok(reply::ok())
+            }
+            Command::DebugSetActiveExpire { enabled } => {
+                for db in ctx.dbs.iter() {
+                    db.set_active_expire(enabled);
+                }
+                Ok(reply::ok())
+            }
+            // The dbs of a server share one clock
+            Command::DebugTime { change } => {
+                let clock = db.clock();
+                match change {
+                    TimeChange::Freeze => clock.freeze(),
+                    TimeChange::Resume => clock.resume(),
+                    TimeChange::Advance(ms) => clock.advance(ms),
+                }
+                Ok(reply::ok())
+            }
+            Command::Ping => Ok(reply::pong()),
+            Command::Quit => Ok(reply::ok()),
+            // The server stops serving and persists on its way out, which
+            // closes this connection with the others
+            Command::Shutdown { mode } => {
+                ctx.shutdown.request(mode);
+                Ok(reply::ok())
+            }
+            // Answered once the rewrite started, it carries on after
+            Command::BgRewriteAof => {
+                let aof = ctx.propagator.aof().ok_or(CommandError::AofOff)?;
+                let rewrite = aof
+                    .rewrite(ctx.dbs.clone(), ctx.keylocks.clone())
+                    .ok_or(CommandError::AofRewriteInProgress)?;
+                tokio::spawn(async move {
+                    if let Err(e) = rewrite.await {
+                        error!("Failed to rewrite the AOF: {}", e);
+                    }
+                });
+                Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed(
+                    "Background append only file rewriting started",
+                ))))
+            }
+            // The connection answers these itself, its confirmations are
+            // queued with the messages that follow them
+            Command::Subscribe { kind, .. } | Command::Unsubscribe { kind, .. } => {
+                let name = match (kind, matches!(self, Command::Subscribe { .. })) {
+                    (Kind::Channel, true) => "SUBSCRIBE",
+                    (Kind::Pattern, true) => "PSUBSCRIBE",
+                    (Kind::Channel, false) => "UNSUBSCRIBE",
+                    (Kind::Pattern, false) => "PUNSUBSCRIBE",
+                };
+                Err(anyhow!(CommandError::ConnectionOnly(name.to_string())))
+            }
+            Command::Publish { channel, message } => {
+                Ok(reply::integer(ctx.pubsub.publish(&channel, &message) as i64))
+            }
+            Command::Unknown { command } => Err(anyhow!(CommandError::UnknownCommand(command))),
+            Command::Info { sections } => {
+                let mut info = format!(
+                    "# Server\r\nredis_version:{}\r\nfoobardb_version:{}\r\nredis_mode:standalone\r\n\
+                     # Memory\r\nmaxmemory_policy:{}\r\ncache_policy:{}\r\nlazyfree_pending_objects:{}\r\n",
+                    REDIS_COMPAT_VERSION,
+                    env!("CARGO_PKG_VERSION"),
+                    db.eviction_policy(),
+                    db.cache_policy(),
+                    db.lazyfree_pending()
+                );
+                info.push_str(&ctx.loading.info());
+                info.push_str(&ctx.replication.info());
+                info.push_str(&ctx.tasks.info());
+                let (hits, misses) = ctx.dbs.iter().fold((0, 0), |(hits, misses), db| {
+                    let stats = db.cache_stats();
+                    (hits + stats.hits, misses + stats.misses)
+                });
+                let (view_hits, view_misses) = ctx.dbs.iter().fold((0, 0), |(hits, misses), db| {
+                    let stats = db.read_view_stats();
+                    (hits + stats.hits, misses + stats.misses)
+                });
+                // Stale share weighted by each database's volatile keys
+                let (mut expired, mut time_cap, mut cycle_ms) = (0, 0, 0);
+                let (mut stale, mut volatile) = (0.0, 0);
+                for db in ctx.dbs.iter() {
+                    let expire = db.expire_info();
+                    expired += expire.expired_keys;
+                    time_cap += expire.time_cap_reached;
+                    cycle_ms += expire.cycle_cpu_ms;
+                    stale += expire.stale_perc * db.expires_len() as f64;
+                    volatile += db.expires_len();
+                }
+                let stale = if volatile > 0 {
+                    stale / volatile as f64
+                } else {
+                    0.0
+                };
+                info.push_str(&format!(
+                    "# Stats\r\ncache_hits:{}\r\ncache_misses:{}\r\nread_view_hits:{}\r\n\
+                     read_view_misses:{}\r\nexpired_keys:{}\r\n\
+                     expired_stale_perc:{:.2}\r\nexpired_time_cap_reached_count:{}\r\n\
+                     expire_cycle_cpu_milliseconds:{}\r\npanics_caught:{}\r\n",
+                    hits,
+                    misses,
+                    view_hits,
+                    view_misses,
+                    expired,
+                    stale,
+                    time_cap,
+                    cycle_ms,
+                    ctx.panics.count()
+                ));
+                // Plain bytes per stored byte of the strings compressed so far
+                let (compressed, plain, packed) =
+                    ctx.dbs
+                        .iter()
+                        .fold((0, 0, 0), |(values, plain, packed), db| {
+                            let (v, p, s) = db.compression_stats().totals();
+                            (values + v, plain + p, packed + s)
+                        });
+                let ratio = if packed > 0 {
+                    plain as f64 / packed as f64
+                } else {
+                    1.0
+                };
+                info.push_str(&format!(
+                    "compression:{}\r\ncompressed_values:{}\r\ncompressed_plain_bytes:{}\r\n\
+                     compressed_stored_bytes:{}\r\ncompression_ratio:{:.2}\r\n",
+                    db.compression().codec,
+                    compressed,
+                    plain,
+                    packed,
+                    ratio
+                ));
+                // Storage counters summed over all databases
+                let mut storage: Vec<(&str, u64)> = vec![];
+                for db in ctx.dbs.iter() {
+                    for (field, value) in db.storage_info() {
+                        match storage.iter_mut().find(|(f, _)| *f == field) {
+                            Some((_, total)) => *total += value,
+                            None => storage.push((field, value)),
+                        }
+                    }
+                }
+                if !storage.is_empty() {
+                    info.push_str("# Storage\r\n");
+                    for (field, value) in storage {
+                        info.push_str(&format!("{}:{}\r\n", field, value));
+                    }
+                }
+                info.push_str("# Keyspace\r\n");
+                for (index, db) in ctx.dbs.iter().enumerate() {
+                    if !db.is_empty() {
+                        info.push_str(&format!(
+                            "db{}:keys={},expires={}\r\n",
+                            index,
+                            db.len(),
+                            db.expires_len()
+                        ));
+                    }
+                }
+                // The per-command sections are left out unless asked for
+                let named = |name: &str| sections.iter().any(|section| section == name);
+                let all = named("all") || named("everything");
+                if !(sections.is_empty() || all || named("default")) {
+                    info = info_sections(&info, &sections);
+                }
+                if all || named("commandstats") {
+                    info.push_str(&ctx.cmdstats.info());
+                }
+                if all || named("latencystats") {
+                    info.push_str(&ctx.cmdstats.latency_info());
+                }
+                Ok(Arc::new(RespValue::BulkString(Some(Cow::Owned(info)))))
+            }
+            Command::Role => Ok(Arc::new(ctx.replication.role())),
+            Command::ReplicaOf { master } => {
+                match &master {
+                    Some((host, port)) => info!("Replica of {}:{}", host, port),
+                    None => info!("Master mode enabled"),
+                }
+                ctx.replication.set_master(master);
+                Ok(reply::ok())
+            }
+            Command::ReplConf { port, ip } => {
+                ctx.replication.announce(ctx.client_id, port, ip);
+                Ok(reply::ok())
+            }
+            // The connection answers these on the replication stream
+            Command::Psync { .. } => {
+                Err(anyhow!(CommandError::ReplicaLinkOnly("PSYNC".to_string())))
+            }
+            Command::ReplConfAck { .. } => Err(anyhow!(CommandError::ReplicaLinkOnly(
+                "REPLCONF ACK".to_string()
+            ))),
+            Command::Command => Ok(Arc::new(RespValue::Array(Some(
+                table::COMMANDS.iter().map(|spec| spec.info()).collect(),
+            )))),
+            Command::CommandCount => Ok(reply::integer(table::COMMANDS.len() as i64)),
+            Command::Help { command } => Ok(Arc::new(table::help(&command))),
+            // No names means every command, unknown ones get a nil entry
+            Command::CommandInfo { names } => {
+                let infos = if names.is_empty() {
+                    table::COMMANDS.iter().map(|spec| spec.info()).collect()
+                } else {
+                    names
+                        .iter()
+                        .map(|name| {
+                            table::lookup(name).map_or(RespValue::Array(None), |s| s.info())
+                        })
+                        .collect()
+                };
+                Ok(Arc::new(RespValue::Array(Some(infos))))
+            }
+            // Unknown names are left out
+            Command::CommandDocs { names } => {
+                let specs: Vec<_> = if names.is_empty() {
+                    table::COMMANDS.iter().collect()
+                } else {
+                    names
+                        .iter()
+                        .filter_map(|name| table::lookup(name))
+                        .collect()
+                };
+                let docs = specs
+                    .into_iter()
+                    .map(|spec| {
+                        let name = RespValue::BulkString(Some(Cow::Borrowed(spec.name)));
+                        (name, map_reply(ctx.protocol, spec.docs()))
+                    })
+                    .collect();
+                Ok(Arc::new(map_reply(ctx.protocol, docs)))
+            }
+            _ => Err(anyhow!(CommandError::NotImplemented)),
+        }
+    }
+}
+
+impl Command {
+    // Converts an EXPIRE-family argument into an absolute unix-ms deadline
+    fn deadline(amount: i64, unit_ms: i64, base: u64, command: &str) -> Result<u64, Error> {
+        let invalid = || {
+            anyhow!(CommandError::InvalidExpireTime {
+                command: command.to_string()
+            })
+        };
+        let when = amount
+            .checked_mul(unit_ms)
+            .and_then(|ms| ms.checked_add(base as i64))
+            .ok_or_else(invalid)?;
+        // Anything at or before the epoch is simply in the past
+        Ok(when.max(0) as u64)
+    }
+
+    // SETEX/PSETEX: like SET but the TTL must be positive
+    fn exec_set_ex<S>(
+        db: &DB<S, String, Value>,
+        key: String,
+        amount: i64,
+        unit_ms: i64,
+        value: String,
+        command: &str,
+    ) -> Result<Arc<RespValue<'static>>, Error>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        if amount <= 0 {
+            return Err(anyhow!(CommandError::InvalidExpireTime {
+                command: command.to_string()
+            }));
+        }
+        let when = Self::deadline(amount, unit_ms, db.clock().now_ms(), command)?;
+        db.set_with_expiry(key, Self::stored_string(db, value), when)
+            .map_err(CommandError::StorageError)?;
+        Ok(reply::ok())
+    }
+
+    // A string value as the db keeps it, compressed when large
+    fn stored_string<S>(db: &DB<S, String, Value>, value: String) -> Value
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        ByteString::new(value.into_bytes()).into_stored(&db.compression(), db.compression_stats())
+    }
+
+    // A stored string as GET and friends reply it
+    fn string_reply(value: &Value) -> Arc<RespValue<'static>> {
+        match ByteString::from_stored(value) {
+            Some(string) => Arc::new(string.to_reply()),
+            None => reply::nil(),
+        }
+    }
+
+    fn exec_expire_at<S>(
+        db: &DB<S, String, Value>,
+        key: String,
+        when: u64,
+        condition: ExpireCondition,
+    ) -> Result<Arc<RespValue<'static>>, Error>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        match db
+            .expire_at(&key, when, condition)
+            .map_err(CommandError::StorageError)
+        {
+            Ok(updated) => Ok(reply::integer(updated as i64)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // TTL/PTTL/EXPIRETIME/PEXPIRETIME: -2 for a missing key, -1 without TTL
+    fn exec_expiry<S, F>(
+        db: &DB<S, String, Value>,
+        key: String,
+        report: F,
+    ) -> Result<Arc<RespValue<'static>>, Error>
+    where
+        S: Storage<String, Value> + 'static,
+        F: FnOnce(u64) -> i64,
+    {
+        match db.expiry(&key).map_err(CommandError::StorageError)? {
+            Expiry::NoKey => Ok(reply::integer(-2)),
+            Expiry::Persistent => Ok(reply::integer(-1)),
+            Expiry::At(when) => Ok(reply::integer(report(when))),
+        }
+    }
+}
+
+// The sections of an INFO text named in sections, lowercased
+fn info_sections(info: &str, sections: &[String]) -> String {
+    let mut kept = String::new();
+    let mut keep = false;
+    for line in info.split_inclusive("\r\n") {
+        if let Some(header) = line.strip_prefix("# ") {
+            let header = header.trim_end().to_lowercase();
+            keep = sections.contains(&header);
+        }
+        if keep {
+            kept.push_str(line);
+        }
+    }
+    kept
+}
+
+// RESP3 map, or the same pairs flattened into an array for RESP2
+fn map_reply(
+    protocol: u8,
+    pairs: Vec<(RespValue<'static>, RespValue<'static>)>,
+) -> RespValue<'static> {
+    if protocol >= 3 {
+        RespValue::Map(Some(pairs))
+    } else {
+        RespValue::Array(Some(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect()))
+    }
+}
+
+// Per-command view of the server's logical databases
+pub struct ExecContext<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    pub dbs: Databases<S, String, Value>,
+    pub db_index: usize,
+    pub latency: Arc<LatencyMonitor>,
+    pub ratelimit: Arc<RateLimiter>,
+    pub clients: Arc<ClientRegistry>,
+    pub tracking: Arc<Tracking>,
+    pub pubsub: Arc<PubSub>,
+    pub keylocks: Arc<KeyLocks>,
+    pub shutdown: Arc<Shutdown>,
+    pub loading: Arc<Loading>,
+    pub replication: Arc<Replication>,
+    pub namespaces: Arc<Namespaces<S>>,
+    pub timeout: Arc<CommandTimeout>,
+    pub tasks: Arc<TaskManager>,
+    pub panics: Arc<Panics>,
+    pub cmdstats: Arc<CommandStats>,
+    pub propagator: Arc<Propagator>,
+    #[cfg(feature = "search")]
+    pub search: Arc<Search<S>>,
+    pub client_id: u64,
+    // RESP version the connection speaks
+    pub protocol: u8,
+}
+
+impl<S> ExecContext<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    pub fn new(dbs: Databases<S, String, Value>, db_index: usize) -> Self {
+        let clients = Arc::new(ClientRegistry::default());
+        Self {
+            dbs,
+            db_index,
+            latency: Arc::new(LatencyMonitor::default()),
+            ratelimit: Arc::new(RateLimiter::default()),
+            clients: clients.clone(),
+            tracking: Arc::new(Tracking::new(clients.clone())),
+            pubsub: Arc::new(PubSub::new(clients)),
+            keylocks: Arc::new(KeyLocks::default()),
+            shutdown: Arc::new(Shutdown::default()),
+            loading: Arc::new(Loading::default()),
+            replication: Arc::new(Replication::default()),
+            namespaces: Arc::new(Namespaces::default()),
+            timeout: Arc::new(CommandTimeout::default()),
+            tasks: Arc::new(TaskManager::default()),
+            panics: Arc::new(Panics::default()),
+            cmdstats: Arc::new(CommandStats::default()),
+            propagator: Arc::new(Propagator::default()),
+            #[cfg(feature = "search")]
+            search: Arc::new(Search::default()),
+            client_id: 0,
+            protocol: 2,
+        }
+    }
+
+    pub fn with_client(mut self, client_id: u64, protocol: u8) -> Self {
+        self.client_id = client_id;
+        self.protocol = protocol;
+        self
+    }
+
+    // Shares the server's latency monitor instead of a private one
+    pub fn with_latency(mut self, latency: Arc<LatencyMonitor>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    // Shares the server's rate limiter so CONFIG SET reaches every client
+    pub fn with_ratelimit(mut self, ratelimit: Arc<RateLimiter>) -> Self {
+        self.ratelimit = ratelimit;
+        self
+    }
+
+    // Connected clients, for CLIENT KILL
+    pub fn with_clients(mut self, clients: Arc<ClientRegistry>) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    // Shares the server's tracking table, the one its databases report
+    // writes to
+    pub fn with_tracking(mut self, tracking: Arc<Tracking>) -> Self {
+        self.tracking = tracking;
+        self
+    }
+
+    // Subscribers PUBLISH delivers to
+    pub fn with_pubsub(mut self, pubsub: Arc<PubSub>) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    // Locks compound operations hold their keys with
+    pub fn with_keylocks(mut self, keylocks: Arc<KeyLocks>) -> Self {
+        self.keylocks = keylocks;
+        self
+    }
+
+    // Where SHUTDOWN asks the server to stop
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    // Load progress for INFO
+    pub fn with_loading(mut self, loading: Arc<Loading>) -> Self {
+        self.loading = loading;
+        self
+    }
+
+    // Shares the server's replication state for ROLE and INFO
+    pub fn with_replication(mut self, replication: Arc<Replication>) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    // The users AUTH and HELLO know besides the default one
+    pub fn with_namespaces(mut self, namespaces: Arc<Namespaces<S>>) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    // Shares the server's command timeout so CONFIG SET reaches every client
+    pub fn with_timeout(mut self, timeout: Arc<CommandTimeout>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_tasks(mut self, tasks: Arc<TaskManager>) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn with_panics(mut self, panics: Arc<Panics>) -> Self {
+        self.panics = panics;
+        self
+    }
+
+    // Shares the server's command statistics, for INFO and CONFIG RESETSTAT
+    pub fn with_cmdstats(mut self, cmdstats: Arc<CommandStats>) -> Self {
+        self.cmdstats = cmdstats;
+        self
+    }
+
+    // Where writes that propagate themselves, like a BLMOVE that got an
+    // element, send their effects
+    pub fn with_propagator(mut self, propagator: Arc<Propagator>) -> Self {
+        self.propagator = propagator;
+        self
+    }
+
+    // Shares the server's search indexes, the ones its databases report
+    // writes to
+    #[cfg(feature = "search")]
+    pub fn with_search(mut self, search: Arc<Search<S>>) -> Self {
+        self.search = search;
+        self
+    }
+
+    // Parameters CONFIG GET and SET know about, with their current values
+    fn config_values(&self) -> Vec<(&'static str, String)> {
+        let listpack = self.db().listpack_limits();
+        let compression = self.db().compression();
+        vec![
+            (
+                "latency-monitor-threshold",
+                self.latency.threshold_ms().to_string(),
+            ),
+            ("ratelimit-rate", self.ratelimit.rate().to_string()),
+            ("ratelimit-burst", self.ratelimit.burst().to_string()),
+            ("ratelimit-key", self.ratelimit.key().to_string()),
+            ("command-timeout", self.timeout.limit_ms().to_string()),
+            ("maxmemory-policy", self.db().eviction_policy().to_string()),
+            (
+                "hash-max-listpack-entries",
+                listpack.hash_max_entries.to_string(),
+            ),
+            (
+                "hash-max-listpack-value",
+                listpack.hash_max_value.to_string(),
+            ),
+            (
+                "set-max-listpack-entries",
+                listpack.set_max_entries.to_string(),
+            ),
+            ("set-max-listpack-value", listpack.set_max_value.to_string()),
+            ("list-max-listpack-size", listpack.list_max_size.to_string()),
+            ("compression", compression.codec.to_string()),
+            ("compression-threshold", compression.threshold.to_string()),
+            (
+                "repl-backlog-size",
+                self.replication.backlog_size().to_string(),
+            ),
+            (
+                "replica-read-only",
+                if self.replication.read_only() {
+                    "yes"
+                } else {
+                    "no"
                 }
-            }
-            Command::Set { key, value } => {
-                match db
-                    .set(key, RespValue::BulkString(Some(Cow::Owned(value))))
-                    .map_err(|e| CommandError::StorageError(e))
-                {
-                    Ok(_) => Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("OK")))),
-                    Err(e) => Err(e.into()),
+                .to_string(),
+            ),
+        ]
+    }
+
+    // CONFIG SET of one parameter, also how a reloaded config file is applied
+    pub fn config_set(&self, parameter: &str, value: &str) -> Result<(), CommandError> {
+        let failed = |reason: &str| CommandError::ConfigSet {
+            parameter: parameter.to_string(),
+            reason: reason.to_string(),
+        };
+        let integer = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| failed("argument couldn't be parsed into an integer"))
+        };
+        match parameter.to_ascii_lowercase().as_str() {
+            "latency-monitor-threshold" => self.latency.set_threshold_ms(integer()?),
+            "ratelimit-rate" => self.ratelimit.set_rate(integer()?),
+            "ratelimit-burst" => self.ratelimit.set_burst(integer()?),
+            "ratelimit-key" => self
+                .ratelimit
+                .set_key(value.parse().map_err(|e: String| failed(&e))?),
+            "command-timeout" => self.timeout.set_limit_ms(integer()?),
+            "maxmemory-policy" => {
+                let policy = value.parse().map_err(|e: String| failed(&e))?;
+                for db in self.dbs.iter() {
+                    db.set_eviction_policy(policy);
                 }
             }
-            Command::Del { keys } => {
-                match db.delete(&keys).map_err(|e| CommandError::StorageError(e)) {
-                    Ok(_) => Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("OK")))),
-                    Err(e) => Err(e.into()),
-                }
+            "hash-max-listpack-entries" => {
+                let entries = integer()? as usize;
+                self.set_listpack(|limits| limits.hash_max_entries = entries)
             }
-            Command::Ping => Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("PONG")))),
-            Command::Unknown { command } => Err(anyhow!(CommandError::UnknownCommand(command))),
-            Command::Info => Ok(Arc::new(RespValue::BulkString(Some(Cow::Owned(format!(
-                "foobardb_version:1.0.0\r\nmode:standalone"
-            )))))),
-            Command::Command => Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("OK")))),
-            _ => Err(anyhow!(CommandError::NotImplemented)),
+            "hash-max-listpack-value" => {
+                let bytes = integer()? as usize;
+                self.set_listpack(|limits| limits.hash_max_value = bytes)
+            }
+            "set-max-listpack-entries" => {
+                let entries = integer()? as usize;
+                self.set_listpack(|limits| limits.set_max_entries = entries)
+            }
+            "set-max-listpack-value" => {
+                let bytes = integer()? as usize;
+                self.set_listpack(|limits| limits.set_max_value = bytes)
+            }
+            "list-max-listpack-size" => {
+                let size = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|size| *size != 0 && *size >= -5)
+                    .ok_or_else(|| failed("argument must be between -5 and -1 or positive"))?;
+                self.set_listpack(|limits| limits.list_max_size = size)
+            }
+            "compression" => {
+                let codec = value.parse().map_err(|e: String| failed(&e))?;
+                self.set_compression(|compression| compression.codec = codec)
+            }
+            "compression-threshold" => {
+                let bytes = integer()? as usize;
+                self.set_compression(|compression| compression.threshold = bytes)
+            }
+            "repl-backlog-size" => self.replication.set_backlog_size(integer()? as usize),
+            "replica-read-only" => match value.to_ascii_lowercase().as_str() {
+                "yes" => self.replication.set_read_only(true),
+                "no" => self.replication.set_read_only(false),
+                _ => return Err(failed("argument must be 'yes' or 'no'")),
+            },
+            _ => return Err(CommandError::UnknownConfig(parameter.to_string())),
+        }
+        Ok(())
+    }
+
+    // Listpack limits are the same in every db
+    fn set_listpack(&self, f: impl Fn(&mut ListpackLimits)) {
+        for db in self.dbs.iter() {
+            let mut limits = db.listpack_limits();
+            f(&mut limits);
+            db.set_listpack_limits(limits);
+        }
+    }
+
+    // So is compression, which applies to strings as they are next written
+    fn set_compression(&self, f: impl Fn(&mut Compression)) {
+        for db in self.dbs.iter() {
+            let mut compression = db.compression();
+            f(&mut compression);
+            db.set_compression(compression);
+        }
+    }
+
+    pub fn db(&self) -> &Arc<DB<S, String, Value>> {
+        &self.dbs[self.db_index]
+    }
+}
+
+impl<S> Clone for ExecContext<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    fn clone(&self) -> Self {
+        Self {
+            dbs: self.dbs.clone(),
+            db_index: self.db_index,
+            latency: self.latency.clone(),
+            ratelimit: self.ratelimit.clone(),
+            clients: self.clients.clone(),
+            tracking: self.tracking.clone(),
+            pubsub: self.pubsub.clone(),
+            keylocks: self.keylocks.clone(),
+            shutdown: self.shutdown.clone(),
+            loading: self.loading.clone(),
+            replication: self.replication.clone(),
+            namespaces: self.namespaces.clone(),
+            timeout: self.timeout.clone(),
+            tasks: self.tasks.clone(),
+            panics: self.panics.clone(),
+            cmdstats: self.cmdstats.clone(),
+            propagator: self.propagator.clone(),
+            #[cfg(feature = "search")]
+            search: self.search.clone(),
+            client_id: self.client_id,
+            protocol: self.protocol,
         }
     }
 }
 
 impl CommandError {
+    // Error of a DB call, typed access failures become WRONGTYPE. One raised
+    // by the command's own code run inside the call, like a spent budget,
+    // comes back as it was.
+    pub fn from_storage(e: Error) -> Self {
+        match e.downcast::<Self>() {
+            Ok(e) => e,
+            Err(e) if e.is::<WrongTypeError>() => Self::WrongType,
+            Err(e) => Self::StorageError(e),
+        }
+    }
+
+    // Error code that leads the RESP error line
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::WrongType | Self::JsonNotArray(_) => "WRONGTYPE",
+            Self::NoProto => "NOPROTO",
+            Self::WrongPass => "WRONGPASS",
+            Self::NamespaceKeysQuota | Self::NamespaceMemoryQuota => "OOM",
+            Self::BusyKey => "BUSYKEY",
+            Self::MigrateIo(_) => "IOERR",
+            Self::RateLimited => "BUSYRATELIMIT",
+            Self::Loading => "LOADING",
+            Self::ReadOnly => "READONLY",
+            Self::NoMasterLink => "NOMASTERLINK",
+            Self::AofWrite(_) => "MISCONF",
+            _ => "ERR",
+        }
+    }
+
     pub fn as_error_msg(&self) -> &'static str {
         match self {
             Self::WrongNumberOfArguments { .. } => "-ERR wrong number of arguments",
@@ -227,6 +4023,81 @@ impl CommandError {
             Self::InvalidArgumentType => "-ERR invalid argument type",
             Self::NotImplemented => "-ERR command not implemented",
             Self::UnknownCommand(_) => "-ERR unknown command",
+            Self::SyntaxError => "-ERR syntax error",
+            Self::NotAnInteger => "-ERR value is not an integer or out of range",
+            Self::DbIndexOutOfRange => "-ERR DB index is out of range",
+            Self::SameObject => "-ERR source and destination objects are the same",
+            Self::InvalidExpireTime { .. } => "-ERR invalid expire time",
+            Self::OffsetOutOfRange => "-ERR offset is out of range",
+            Self::MustBePositive => "-ERR value is out of range, must be positive",
+            Self::NoSuchKey => "-ERR no such key",
+            Self::IndexOutOfRange => "-ERR index out of range",
+            Self::WrongType => "-WRONGTYPE Operation against a key holding the wrong kind of value",
+            Self::InvalidTimeout => "-ERR timeout is not a float or out of range",
+            Self::NegativeTimeout => "-ERR timeout is negative",
+            Self::NotAFloat => "-ERR value is not a valid float",
+            Self::SortNotDouble => "-ERR One or more scores can't be converted into double",
+            Self::InvalidCursor => "-ERR invalid cursor",
+            Self::UnknownTypeName(_) => "-ERR unknown type name",
+            Self::HashValueNotInteger => "-ERR hash value is not an integer",
+            Self::HashValueNotFloat => "-ERR hash value is not a float",
+            Self::IncrementOverflow => "-ERR increment or decrement would overflow",
+            Self::NanOrInfinity => "-ERR increment would produce NaN or Infinity",
+            Self::InvalidLonLat(..) => "-ERR invalid longitude,latitude pair",
+            Self::UnsupportedUnit => "-ERR unsupported unit provided. please use M, KM, FT, MI",
+            Self::NoSuchMember => "-ERR could not decode requested zset member",
+            Self::UnknownSubcommand { .. } => "-ERR unknown subcommand",
+            Self::LfuNotSelected => "-ERR An LFU maxmemory policy is not selected",
+            Self::LfuSelected => "-ERR An LFU maxmemory policy is selected",
+            Self::RateLimited => "-BUSYRATELIMIT command rate limit exceeded",
+            Self::Loading => "-LOADING foobar_db is loading the dataset in memory",
+            Self::ReadOnly => "-READONLY You can't write against a read only replica.",
+            Self::InvalidMasterPort => "-ERR Invalid master port",
+            Self::NoMasterLink => "-NOMASTERLINK Can't SYNC while not connected with my master",
+            Self::UnrecognizedReplConf(_) => "-ERR Unrecognized REPLCONF option",
+            Self::ReplicaLinkOnly(_) => "-ERR only served on a replica's connection",
+            Self::ConnectionOnly(_) => "-ERR only served on a client connection",
+            Self::SubscriberMode(_) => "-ERR only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            Self::UnknownConfig(_) => "-ERR Unknown option or number of arguments for CONFIG SET",
+            Self::ConfigSet { .. } => "-ERR CONFIG SET failed",
+            Self::NoSuchRedirect => "-ERR The client ID you want redirect to does not exist",
+            Self::PrefixWithoutBcast => "-ERR PREFIX option requires BCAST mode to be enabled",
+            Self::NoProto => "-NOPROTO unsupported protocol version",
+            Self::WrongPass => "-WRONGPASS invalid username-password pair or user is disabled.",
+            Self::NamespaceKeysQuota => "-OOM namespace key quota reached",
+            Self::NamespaceMemoryQuota => "-OOM namespace memory quota reached",
+            Self::BusyKey => "-BUSYKEY Target key name already exists.",
+            Self::InvalidTtl => "-ERR Invalid TTL value, must be >= 0",
+            Self::DumpError(_) => "-ERR DUMP payload version or checksum are wrong",
+            Self::MigrateIo(_) => "-IOERR error or timeout talking to target instance",
+            Self::MigrateTarget(_) => "-ERR Target instance replied with error",
+            Self::UnknownIndex => "-ERR Unknown index name",
+            Self::IndexExists => "-ERR Index already exists",
+            Self::SearchSyntax(_) => "-ERR Syntax error",
+            Self::InvalidJson(_) => "-ERR invalid JSON",
+            Self::InvalidJsonPath(_) => "-ERR invalid JSON path",
+            Self::NoJsonPath(_) => "-ERR Path does not exist",
+            Self::JsonNewAtRoot => "-ERR new objects must be created at the root",
+            Self::JsonNoKey => {
+                "-ERR could not perform this operation on a key that doesn't exist"
+            }
+            Self::JsonNotArray(_) => "-WRONGTYPE wrong type of path value",
+            Self::ZeroRank => {
+                "-ERR RANK can't be zero: use 1 to start from the first match, 2 from the \
+                 second ... or use negative to start from the end of the list"
+            }
+            Self::NegativeCount => "-ERR COUNT can't be negative",
+            Self::NegativeMaxLen => "-ERR MAXLEN can't be negative",
+            Self::NegativeLimit => "-ERR LIMIT can't be negative",
+            Self::NumKeysNotPositive => "-ERR numkeys should be greater than 0",
+            Self::NumKeysTooMany => "-ERR Number of keys can't be greater than number of args",
+            Self::AofWrite(_) => "-MISCONF Errors writing to the AOF file",
+            Self::AofOff => "-ERR Append only file is turned off",
+            Self::AofRewriteInProgress => {
+                "-ERR Background append only file rewriting already in progress"
+            }
+            Self::Timeout => "-ERR command timed out",
+            Self::Internal => "-ERR internal error",
             Self::StorageError(_) => "-ERR storage error",
         }
     }
@@ -249,6 +4120,76 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_table_commands_parse() {
+        // Every command the table describes, and so has flags for, is one
+        // from_resp knows
+        for spec in table::COMMANDS {
+            let mut args = vec![RespValue::BulkString(Some(Cow::Borrowed(spec.name)))];
+            for _ in 1..spec.arity.unsigned_abs() {
+                args.push(RespValue::BulkString(Some(Cow::Borrowed("1"))));
+            }
+            let parsed = Command::from_resp(RespValue::Array(Some(args)));
+            assert!(
+                !matches!(parsed, Ok(Command::Unknown { .. })),
+                "{} is in the table but doesn't parse",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_derived_commands() {
+        let parse = |args: &[&str]| {
+            let args = args
+                .iter()
+                .map(|arg| RespValue::BulkString(Some(Cow::Owned(arg.to_string()))))
+                .collect();
+            Command::from_resp(RespValue::Array(Some(args)))
+        };
+        // Arguments go to the fields in order, under every name of a variant
+        assert_eq!(
+            parse(&["substr", "k", "1", "-1"]).unwrap(),
+            Command::GetRange {
+                key: "k".to_string(),
+                start: 1,
+                stop: -1
+            }
+        );
+        assert_eq!(
+            parse(&["GEOPOS", "k"]).unwrap(),
+            Command::GeoPos {
+                key: "k".to_string(),
+                members: vec![]
+            }
+        );
+        assert!(parse(&["LINDEX", "k", "x"]).is_err());
+
+        // Every command of the table has its arity checked, hand parsed ones
+        // too
+        for args in [
+            &["GET"][..],
+            &["GET", "a", "b"],
+            &["ROLE", "x"],
+            &["GEOSEARCH", "k", "FROMMEMBER", "m", "BYRADIUS", "1"],
+            &["config"],
+        ] {
+            assert_eq!(
+                parse(args).unwrap_err().to_string(),
+                format!(
+                    "wrong number of arguments for '{}' command",
+                    args[0].to_lowercase()
+                )
+            );
+        }
+        assert_eq!(
+            parse(&["NOSUCH", "a"]).unwrap(),
+            Command::Unknown {
+                command: "NOSUCH".to_string()
+            }
+        );
+    }
+
     #[test]
     fn test_parse_set_command() {
         let resp = RespValue::Array(Some(vec![
@@ -266,11 +4207,1298 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_unlink_command() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("UNLINK".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("k1".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("k2".to_string()))),
+        ]));
+
+        match Command::from_resp(resp) {
+            Ok(Command::Unlink { keys }) => assert_eq!(keys, vec!["k1", "k2"]),
+            _ => panic!("Failed to parse UNLINK command"),
+        }
+
+        let resp = RespValue::Array(Some(vec![RespValue::BulkString(Some(Cow::Owned(
+            "UNLINK".to_string(),
+        )))]));
+        assert!(Command::from_resp(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_copy_command() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("COPY".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("src".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("dst".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("db".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("3".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("replace".to_string()))),
+        ]));
+
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::Copy {
+                source: "src".to_string(),
+                destination: "dst".to_string(),
+                db: Some(3),
+                replace: true,
+            }
+        );
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("COPY".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("src".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("dst".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("DB".to_string()))),
+        ]));
+        assert!(Command::from_resp(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_move_command() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("MOVE".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("1".to_string()))),
+        ]));
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::Move {
+                key: "key".to_string(),
+                db: 1
+            }
+        );
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("MOVE".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("-1".to_string()))),
+        ]));
+        assert!(Command::from_resp(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_expire_commands() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("PEXPIREAT".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("1700000000000".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("gt".to_string()))),
+        ]));
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::PExpireAt {
+                key: "key".to_string(),
+                timestamp: 1_700_000_000_000,
+                condition: ExpireCondition::IfGreater,
+            }
+        );
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("EXPIRE".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("ten".to_string()))),
+        ]));
+        assert!(Command::from_resp(resp).is_err());
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("EXPIRETIME".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+        ]));
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::ExpireTime {
+                key: "key".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_getex_command() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("GETEX".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("px".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("1500".to_string()))),
+        ]));
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::GetEx {
+                key: "key".to_string(),
+                option: GetExOption::Px(1500),
+            }
+        );
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("GETEX".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("EX".to_string()))),
+        ]));
+        assert!(Command::from_resp(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_setex_commands() {
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("PSETEX".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("100".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("value".to_string()))),
+        ]));
+        assert_eq!(
+            Command::from_resp(resp).unwrap(),
+            Command::PSetEx {
+                key: "key".to_string(),
+                milliseconds: 100,
+                value: "value".to_string(),
+            }
+        );
+
+        let resp = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Owned("SETRANGE".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("key".to_string()))),
+            RespValue::BulkString(Some(Cow::Owned("6".to_string()))),
+        ]));
+        assert!(Command::from_resp(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_lmove_commands() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        assert_eq!(
+            command(&["RPOPLPUSH", "a", "b"]).unwrap(),
+            Command::LMove {
+                source: "a".to_string(),
+                destination: "b".to_string(),
+                from_left: false,
+                to_left: true,
+            }
+        );
+        assert_eq!(
+            command(&["BLMOVE", "a", "b", "left", "RIGHT", "0.5"]).unwrap(),
+            Command::BLMove {
+                source: "a".to_string(),
+                destination: "b".to_string(),
+                from_left: true,
+                to_left: false,
+                timeout: Some(Duration::from_millis(500)),
+            }
+        );
+        assert!(matches!(
+            command(&["BLMOVE", "a", "b", "LEFT", "LEFT", "0"]).unwrap(),
+            Command::BLMove { timeout: None, .. }
+        ));
+        assert!(command(&["LMOVE", "a", "b", "UP", "LEFT"]).is_err());
+
+        let err = command(&["BLMOVE", "a", "b", "LEFT", "LEFT", "-1"]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::NegativeTimeout)
+        ));
+        let err = command(&["BLMOVE", "a", "b", "LEFT", "LEFT", "soon"]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::InvalidTimeout)
+        ));
+    }
+
+    #[test]
+    fn test_parse_hash_commands() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        assert_eq!(
+            command(&["HSET", "h", "a", "1", "b", "2"]).unwrap(),
+            Command::HSet {
+                key: "h".to_string(),
+                fields: vec![
+                    ("a".to_string(), "1".to_string()),
+                    ("b".to_string(), "2".to_string())
+                ],
+            }
+        );
+        assert!(command(&["HSET", "h", "a"]).is_err());
+        assert_eq!(
+            command(&["HINCRBYFLOAT", "h", "f", "1.5e2"]).unwrap(),
+            Command::HIncrByFloat {
+                key: "h".to_string(),
+                field: "f".to_string(),
+                increment: 150.0,
+            }
+        );
+        assert!(command(&["HINCRBYFLOAT", "h", "f", "nan"]).is_err());
+        assert!(command(&["HINCRBY", "h", "f", "1.5"]).is_err());
+        assert_eq!(
+            command(&["HRANDFIELD", "h", "-3", "withvalues"]).unwrap(),
+            Command::HRandField {
+                key: "h".to_string(),
+                count: Some(-3),
+                with_values: true,
+            }
+        );
+        assert!(command(&["HRANDFIELD", "h", "3", "VALUES"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_set_algebra_commands() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        assert_eq!(
+            command(&["sunion", "a", "b"]).unwrap(),
+            Command::SAlgebra {
+                op: SetOp::Union,
+                keys: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+        assert_eq!(
+            command(&["SDIFFSTORE", "dst", "a"]).unwrap(),
+            Command::SAlgebraStore {
+                op: SetOp::Diff,
+                destination: "dst".to_string(),
+                keys: vec!["a".to_string()],
+            }
+        );
+        assert!(command(&["SINTER"]).is_err());
+        assert_eq!(
+            command(&["SPOP", "s", "2"]).unwrap(),
+            Command::SPop {
+                key: "s".to_string(),
+                count: Some(2),
+            }
+        );
+        assert!(command(&["SPOP", "s", "-2"]).is_err());
+        assert_eq!(
+            command(&["SRANDMEMBER", "s", "-2"]).unwrap(),
+            Command::SRandMember {
+                key: "s".to_string(),
+                count: Some(-2),
+            }
+        );
+        assert!(command(&["SINTERSTORE", "dst"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_sort_command() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        let sort = command(&[
+            "SORT",
+            "ids",
+            "BY",
+            "w_*",
+            "LIMIT",
+            "0",
+            "10",
+            "GET",
+            "#",
+            "GET",
+            "u_*->name",
+            "DESC",
+            "ALPHA",
+            "STORE",
+            "dst",
+        ])
+        .unwrap();
+        assert_eq!(
+            sort,
+            Command::Sort {
+                key: "ids".to_string(),
+                options: SortOptions {
+                    by: Some("w_*".to_string()),
+                    limit: Some((0, 10)),
+                    get: vec!["#".to_string(), "u_*->name".to_string()],
+                    desc: true,
+                    alpha: true,
+                },
+                store: Some("dst".to_string()),
+            }
+        );
+        // Patterns reach keys that can't be known up front
+        assert_eq!(sort.keys(), None);
+        assert_eq!(
+            command(&["SORT", "ids", "STORE", "dst"]).unwrap().keys(),
+            Some(vec!["ids", "dst"])
+        );
+        assert!(command(&["SORT_RO", "ids", "STORE", "dst"]).is_err());
+        assert!(command(&["SORT", "ids", "LIMIT", "0"]).is_err());
+        assert!(command(&["SORT", "ids", "NOSUCH"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_geo_commands() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        assert_eq!(
+            command(&["GEOADD", "k", "13.36", "38.11", "Palermo"]).unwrap(),
+            Command::GeoAdd {
+                key: "k".to_string(),
+                items: vec![(13.36, 38.11, "Palermo".to_string())],
+            }
+        );
+        assert!(command(&["GEOADD", "k", "13.36", "89", "Pole"]).is_err());
+        assert!(command(&["GEODIST", "k", "a", "b", "yards"]).is_err());
+
+        assert_eq!(
+            command(&[
+                "GEOSEARCH",
+                "k",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYRADIUS",
+                "200",
+                "km",
+                "COUNT",
+                "3",
+                "WITHDIST"
+            ])
+            .unwrap(),
+            Command::GeoSearch {
+                key: "k".to_string(),
+                query: GeoQuery {
+                    origin: GeoOrigin::LonLat(15.0, 37.0),
+                    shape: GeoShape::Radius(200_000.0),
+                    unit: 1000.0,
+                    ascending: Some(true),
+                    count: Some(3),
+                    any: false,
+                    with_coord: false,
+                    with_dist: true,
+                    with_hash: false,
+                },
+            }
+        );
+        assert!(command(&["GEOSEARCH", "k", "FROMMEMBER", "a", "ASC", "WITHDIST"]).is_err());
+        assert!(command(&[
+            "GEOSEARCH",
+            "k",
+            "FROMMEMBER",
+            "a",
+            "BYRADIUS",
+            "1",
+            "m",
+            "COUNT",
+            "0"
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_incr() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .map(|cmd| cmd.exec(ctx.clone()))
+        };
+
+        // A missing key counts from zero
+        assert_eq!(
+            *run(&["INCR", "n"]).unwrap().await.unwrap(),
+            RespValue::Integer(1)
+        );
+        assert_eq!(
+            *run(&["INCRBY", "n", "41"]).unwrap().await.unwrap(),
+            RespValue::Integer(42)
+        );
+        assert_eq!(
+            *run(&["DECR", "n"]).unwrap().await.unwrap(),
+            RespValue::Integer(41)
+        );
+        assert_eq!(
+            *run(&["DECRBY", "n", "50"]).unwrap().await.unwrap(),
+            RespValue::Integer(-9)
+        );
+        assert_eq!(
+            *run(&["GET", "n"]).unwrap().await.unwrap(),
+            RespValue::BulkString(Some(Cow::Owned("-9".to_string())))
+        );
+
+        // The deadline stays
+        run(&["PEXPIRE", "n", "100000"]).unwrap().await.unwrap();
+        run(&["INCR", "n"]).unwrap().await.unwrap();
+        assert!(matches!(
+            *run(&["PTTL", "n"]).unwrap().await.unwrap(),
+            RespValue::Integer(ttl) if ttl > 0
+        ));
+
+        run(&["SET", "s", "abc"]).unwrap().await.unwrap();
+        assert!(run(&["INCR", "s"]).unwrap().await.is_err());
+        run(&["SET", "max", &i64::MAX.to_string()])
+            .unwrap()
+            .await
+            .unwrap();
+        assert!(run(&["INCR", "max"]).unwrap().await.is_err());
+        assert!(run(&["DECRBY", "n", &i64::MIN.to_string()]).is_err());
+        assert!(run(&["INCRBY", "n", "x"]).is_err());
+        assert!(run(&["INCR"]).is_err());
+        run(&["RPUSH", "list", "a"]).unwrap().await.unwrap();
+        assert!(run(&["INCR", "list"]).unwrap().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_setrange() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+
+        let setrange = |offset: i64, value: &str| Command::SetRange {
+            key: "key".to_string(),
+            offset,
+            value: value.to_string(),
+        };
+        let reply = setrange(6, "World").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(11));
+        let reply = setrange(0, "Hello").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(11));
+
+        let reply = Command::Get {
+            key: "key".to_string(),
+        }
+        .exec(ctx.clone())
+        .await
+        .unwrap();
+        assert_eq!(
+            *reply,
+            RespValue::BulkString(Some(Cow::Owned("Hello\0World".to_string())))
+        );
+
+        assert!(setrange(-1, "x").exec(ctx.clone()).await.is_err());
+
+        let getrange = |start: i64, stop: i64| Command::GetRange {
+            key: "key".to_string(),
+            start,
+            stop,
+        };
+        for (start, stop, expected) in [
+            (0, 4, "Hello"),
+            (-5, -1, "World"),
+            (0, -1, "Hello\0World"),
+            (6, 100, "World"),
+            (-100, 0, "H"),
+            (5, 3, ""),
+            (-20, -15, ""),
+        ] {
+            let reply = getrange(start, stop).exec(ctx.clone()).await.unwrap();
+            assert_eq!(
+                *reply,
+                RespValue::BulkString(Some(Cow::Owned(expected.to_string())))
+            );
+        }
+        let reply = Command::GetRange {
+            key: "missing".to_string(),
+            start: 0,
+            stop: -1,
+        }
+        .exec(ctx.clone())
+        .await
+        .unwrap();
+        assert_eq!(*reply, RespValue::BulkString(Some(Cow::Borrowed(""))));
+
+        // Offsets count bytes, even inside a character
+        let strlen = |key: &str| Command::StrLen {
+            key: key.to_string(),
+        };
+        let reply = setrange(0, "é").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(11));
+        let reply = setrange(1, "e").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(11));
+        let reply = strlen("key").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(11));
+        let reply = getrange(1, 4).exec(ctx.clone()).await.unwrap();
+        assert_eq!(
+            *reply,
+            RespValue::BulkString(Some(Cow::Owned("ello".to_string())))
+        );
+        let reply = getrange(0, 1).exec(ctx.clone()).await.unwrap();
+        assert_eq!(
+            *reply,
+            RespValue::BulkString(Some(Cow::Owned("\u{fffd}e".to_string())))
+        );
+        setrange(0, "H").exec(ctx.clone()).await.unwrap();
+        let reply = Command::Get {
+            key: "key".to_string(),
+        }
+        .exec(ctx.clone())
+        .await
+        .unwrap();
+        assert_eq!(
+            *reply,
+            RespValue::BulkString(Some(Cow::Owned("Hello\0World".to_string())))
+        );
+        let reply = strlen("missing").exec(ctx.clone()).await.unwrap();
+        assert_eq!(*reply, RespValue::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn test_exec_hello() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0).with_client(7, 2);
+        let hello = |protover: Option<i64>, user: Option<&str>| Command::Hello {
+            protover,
+            auth: user.map(|u| (u.to_string(), "secret".to_string())),
+        };
+
+        // RESP3 gets a map, RESP2 the same pairs flattened
+        match &*hello(Some(3), None).exec(ctx.clone()).await.unwrap() {
+            RespValue::Map(Some(fields)) => {
+                assert_eq!(fields.len(), 7);
+                assert_eq!(fields[2].1, RespValue::Integer(3));
+                assert_eq!(fields[3].1, RespValue::Integer(7));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        match &*hello(None, Some("default"))
+            .exec(ctx.clone())
+            .await
+            .unwrap()
+        {
+            RespValue::Array(Some(items)) => {
+                assert_eq!(items.len(), 14);
+                assert_eq!(items[5], RespValue::Integer(2));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        let err = hello(Some(4), None).exec(ctx.clone()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CommandError>().unwrap().kind(),
+            "NOPROTO"
+        );
+        let err = hello(Some(3), Some("alice")).exec(ctx).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CommandError>().unwrap().kind(),
+            "WRONGPASS"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_dump_restore() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+
+        run(&["RPUSH", "list", "a", "b"]).await.unwrap();
+        let payload = match &*run(&["DUMP", "list"]).await.unwrap() {
+            RespValue::BulkString(Some(payload)) => payload.to_string(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert_eq!(
+            *run(&["DUMP", "missing"]).await.unwrap(),
+            RespValue::BulkString(None)
+        );
+
+        let err = run(&["RESTORE", "list", "0", &payload]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CommandError>().unwrap().kind(),
+            "BUSYKEY"
+        );
+        run(&["RESTORE", "copy", "60000", &payload]).await.unwrap();
+        assert_eq!(
+            *run(&["LRANGE", "copy", "0", "-1"]).await.unwrap(),
+            *run(&["LRANGE", "list", "0", "-1"]).await.unwrap()
+        );
+        assert!(matches!(
+            *run(&["PTTL", "copy"]).await.unwrap(),
+            RespValue::Integer(ms) if ms > 0
+        ));
+        run(&["RESTORE", "list", "0", &payload, "REPLACE"])
+            .await
+            .unwrap();
+
+        assert!(run(&["RESTORE", "bad", "0", "00ff"]).await.is_err());
+        assert!(run(&["RESTORE", "bad", "-1", &payload]).await.is_err());
+    }
+
+    #[test]
+    fn test_command_keys() {
+        let command = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+        };
+
+        assert_eq!(command(&["GET", "a"]).keys(), Some(vec!["a"]));
+        assert_eq!(command(&["DEL", "a", "b"]).keys(), Some(vec!["a", "b"]));
+        assert_eq!(
+            command(&["SUNIONSTORE", "d", "a", "b"]).keys(),
+            Some(vec!["d", "a", "b"])
+        );
+        assert_eq!(
+            command(&["LMOVE", "a", "b", "LEFT", "RIGHT"]).keys(),
+            Some(vec!["a", "b"])
+        );
+        assert_eq!(
+            command(&["MIGRATE", "h", "1", "", "0", "5", "COPY", "KEYS", "a", "b"]).keys(),
+            Some(vec!["a", "b"])
+        );
+        assert_eq!(command(&["PING"]).keys(), Some(vec![]));
+        assert_eq!(command(&["MOVE", "a", "1"]).keys(), None);
+        assert_eq!(command(&["COPY", "a", "b", "DB", "1"]).keys(), None);
+    }
+
+    #[test]
+    fn test_expire_deadline_overflow() {
+        assert!(Command::deadline(i64::MAX, 1000, crate::db::db::now_ms(), "expire").is_err());
+        assert_eq!(Command::deadline(-5, 1000, 0, "expireat").unwrap(), 0);
+        assert_eq!(Command::deadline(2, 1000, 10, "expire").unwrap(), 2010);
+    }
+
     #[test]
     fn test_invalid_command() {
         let resp = RespValue::SimpleString(Cow::Owned("NOT_AN_ARRAY".to_string()));
         assert!(Command::from_resp(resp).is_err());
     }
-}
 
-//EOF
+    #[tokio::test]
+    async fn test_exec_wrong_type() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: Vec<&str>| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+
+        // Per type: how to create a key, then a read and a write of that type
+        type Args = &'static [&'static str];
+        let types: [(&str, Args, Args, Args); 5] = [
+            ("string", &["SET", "v"], &["GET"], &["SETRANGE", "0", "x"]),
+            (
+                "list",
+                &["RPUSH", "a"],
+                &["LRANGE", "0", "-1"],
+                &["LPUSH", "b"],
+            ),
+            ("set", &["SADD", "a"], &["SMEMBERS"], &["SADD", "b"]),
+            ("zset", &["ZADD", "1", "a"], &["ZCARD"], &["ZADD", "2", "b"]),
+            (
+                "hash",
+                &["HSET", "f", "v"],
+                &["HGET", "f"],
+                &["HSET", "g", "v"],
+            ),
+        ];
+        let with_key = |args: &[&'static str], key: &'static str| {
+            let mut full = vec![args[0], key];
+            full.extend_from_slice(&args[1..]);
+            full
+        };
+        for (name, create, _, _) in types {
+            run(with_key(create, name)).await.unwrap();
+            assert_eq!(
+                *run(vec!["TYPE", name]).await.unwrap(),
+                RespValue::SimpleString(Cow::Borrowed(name))
+            );
+        }
+        for (key, ..) in types {
+            for (name, _, read, write) in types {
+                for args in [read, write] {
+                    let result = run(with_key(args, key)).await;
+                    if key == name {
+                        assert!(result.is_ok(), "{:?} on {}", args, key);
+                        continue;
+                    }
+                    let err = result.unwrap_err();
+                    assert_eq!(
+                        err.downcast_ref::<CommandError>().map(|e| e.kind()),
+                        Some("WRONGTYPE"),
+                        "{:?} on {}",
+                        args,
+                        key
+                    );
+                }
+            }
+        }
+
+        // A failed GETDEL leaves the key alone
+        assert!(run(vec!["GETDEL", "list"]).await.is_err());
+        assert!(run(vec!["GETEX", "hash", "PERSIST"]).await.is_err());
+        assert_eq!(
+            *run(vec!["TYPE", "list"]).await.unwrap(),
+            RespValue::SimpleString(Cow::Borrowed("list"))
+        );
+        assert_eq!(
+            *run(vec!["TYPE", "missing"]).await.unwrap(),
+            RespValue::SimpleString(Cow::Borrowed("none"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_scan() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .map(|command| command.exec(ctx.clone()))
+        };
+        for i in 0..30 {
+            run(&["SET", &format!("user:{}", i), "x"])
+                .unwrap()
+                .await
+                .unwrap();
+            run(&["RPUSH", &format!("queue:{}", i), "x"])
+                .unwrap()
+                .await
+                .unwrap();
+        }
+
+        // Walks the cursor to 0, collecting what every step returned
+        let scan = |extra: &'static [&'static str]| {
+            let run = &run;
+            async move {
+                let (mut cursor, mut found) = ("0".to_string(), vec![]);
+                loop {
+                    let mut args = vec!["SCAN", &cursor, "COUNT", "7"];
+                    args.extend_from_slice(extra);
+                    let reply = run(&args).unwrap().await.unwrap();
+                    let RespValue::Array(Some(parts)) = &*reply else {
+                        panic!("unexpected reply {:?}", reply);
+                    };
+                    let (RespValue::BulkString(Some(next)), RespValue::Array(Some(keys))) =
+                        (&parts[0], &parts[1])
+                    else {
+                        panic!("unexpected reply {:?}", reply);
+                    };
+                    for key in keys {
+                        if let RespValue::BulkString(Some(key)) = key {
+                            found.push(key.to_string());
+                        }
+                    }
+                    if next == "0" {
+                        found.sort();
+                        return found;
+                    }
+                    cursor = next.to_string();
+                }
+            }
+        };
+        assert_eq!(scan(&[]).await.len(), 60);
+        let users = scan(&["MATCH", "user:*"]).await;
+        assert_eq!(users.len(), 30);
+        assert!(users.iter().all(|key| key.starts_with("user:")));
+        assert_eq!(scan(&["MATCH", "user:1?"]).await.len(), 10);
+        let lists = scan(&["TYPE", "LIST"]).await;
+        assert_eq!(lists.len(), 30);
+        assert!(lists.iter().all(|key| key.starts_with("queue:")));
+        assert!(scan(&["TYPE", "hash"]).await.is_empty());
+
+        assert!(run(&["SCAN", "-1"]).is_err());
+        assert!(run(&["SCAN", "0", "COUNT", "0"]).is_err());
+        assert!(run(&["SCAN", "0", "TYPE", "nosuch"]).is_err());
+        assert!(run(&["SCAN", "0", "MATCH"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_object_encoding() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+        let encoding = |name: &'static str| RespValue::BulkString(Some(Cow::Borrowed(name)));
+
+        run(&["SET", "n", "12"]).await.unwrap();
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "n"]).await.unwrap(),
+            encoding("int")
+        );
+        run(&["HSET", "h", "f1", "v1"]).await.unwrap();
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "h"]).await.unwrap(),
+            encoding("listpack")
+        );
+
+        // Past the limit the hash converts, and reads see the same fields
+        run(&["CONFIG", "SET", "hash-max-listpack-entries", "1"])
+            .await
+            .unwrap();
+        run(&["HSET", "h", "f2", "v2"]).await.unwrap();
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "h"]).await.unwrap(),
+            encoding("hashtable")
+        );
+        assert_eq!(
+            *run(&["HGET", "h", "f1"]).await.unwrap(),
+            RespValue::BulkString(Some(Cow::Borrowed("v1")))
+        );
+        assert_eq!(
+            *run(&["CONFIG", "GET", "hash-max-listpack-entries"])
+                .await
+                .unwrap(),
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(Cow::Borrowed("hash-max-listpack-entries"))),
+                RespValue::BulkString(Some(Cow::Borrowed("1"))),
+            ]))
+        );
+        assert!(run(&["CONFIG", "SET", "list-max-listpack-size", "-6"])
+            .await
+            .is_err());
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "missing"]).await.unwrap(),
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exec_touch_and_idletime() {
+        let db = Arc::new(DB::new(crate::db::storage::DashMapStorage::new(), 16));
+        let ctx = ExecContext::new(Arc::new(vec![db.clone()]), 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+
+        run(&["SET", "a", "1"]).await.unwrap();
+        run(&["SET", "b", "2"]).await.unwrap();
+        assert_eq!(
+            *run(&["TOUCH", "a", "b", "missing"]).await.unwrap(),
+            RespValue::Integer(2)
+        );
+        assert_eq!(
+            *run(&["OBJECT", "IDLETIME", "a"]).await.unwrap(),
+            RespValue::Integer(0)
+        );
+        assert_eq!(
+            *run(&["OBJECT", "IDLETIME", "missing"]).await.unwrap(),
+            RespValue::BulkString(None)
+        );
+
+        // Idle time isn't tracked under LFU
+        db.set_eviction_policy(crate::db::eviction::EvictionPolicy::AllKeysLfu);
+        let error = run(&["OBJECT", "IDLETIME", "a"]).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::LfuSelected)
+        ));
+    }
+
+    #[cfg(feature = "debug-clock")]
+    #[tokio::test]
+    async fn test_exec_debug_time() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+
+        run(&["DEBUG", "FREEZE-TIME"]).await.unwrap();
+        run(&["SETEX", "k", "10", "v"]).await.unwrap();
+        run(&["DEBUG", "ADVANCE-TIME", "4000"]).await.unwrap();
+        assert_eq!(*run(&["TTL", "k"]).await.unwrap(), RespValue::Integer(6));
+        assert_eq!(
+            *run(&["PTTL", "k"]).await.unwrap(),
+            RespValue::Integer(6000)
+        );
+        run(&["DEBUG", "ADVANCE-TIME", "6000"]).await.unwrap();
+        assert_eq!(
+            *run(&["GET", "k"]).await.unwrap(),
+            RespValue::BulkString(None)
+        );
+        run(&["DEBUG", "RESUME-TIME"]).await.unwrap();
+        assert!(!ctx.db().clock().is_frozen());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[tokio::test]
+    async fn test_exec_compression() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+        let bulk = |s: &str| RespValue::BulkString(Some(Cow::Owned(s.to_string())));
+
+        run(&["CONFIG", "SET", "compression", "lz4"]).await.unwrap();
+        run(&["CONFIG", "SET", "compression-threshold", "100"])
+            .await
+            .unwrap();
+        let large = "abcd".repeat(100);
+        run(&["SET", "k", &large]).await.unwrap();
+        run(&["SET", "small", "abcd"]).await.unwrap();
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "k"]).await.unwrap(),
+            bulk("compressed")
+        );
+        assert_eq!(*run(&["GET", "k"]).await.unwrap(), bulk(&large));
+        assert_eq!(
+            *run(&["TYPE", "k"]).await.unwrap(),
+            RespValue::SimpleString("string".into())
+        );
+        assert_eq!(
+            *run(&["GETRANGE", "k", "0", "5"]).await.unwrap(),
+            bulk("abcdab")
+        );
+        assert_eq!(
+            *run(&["SETRANGE", "k", "0", "xy"]).await.unwrap(),
+            RespValue::Integer(400)
+        );
+        assert_eq!(
+            *run(&["GET", "k"]).await.unwrap(),
+            bulk(&format!("xy{}", &large[2..]))
+        );
+
+        // DUMP carries the plain string
+        let payload = match &*run(&["DUMP", "k"]).await.unwrap() {
+            RespValue::BulkString(Some(payload)) => payload.to_string(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        run(&["CONFIG", "SET", "compression", "none"])
+            .await
+            .unwrap();
+        run(&["RESTORE", "plain", "0", &payload]).await.unwrap();
+        assert_eq!(
+            *run(&["OBJECT", "ENCODING", "plain"]).await.unwrap(),
+            bulk("raw")
+        );
+        assert_eq!(
+            *run(&["GETDEL", "k"]).await.unwrap(),
+            bulk(&format!("xy{}", &large[2..]))
+        );
+
+        let info = match &*run(&["INFO"]).await.unwrap() {
+            RespValue::BulkString(Some(info)) => info.to_string(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert!(info.contains("compressed_values:2\r\n"));
+        assert!(!info.contains("compression_ratio:1.00\r\n"));
+        assert!(run(&["CONFIG", "SET", "compression", "gzip"])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_hotkeys() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+
+        run(&["SET", "hot", "1"]).await.unwrap();
+        run(&["SET", "cold", "1"]).await.unwrap();
+        for _ in 0..5 {
+            run(&["GET", "hot"]).await.unwrap();
+        }
+        run(&["GET", "cold"]).await.unwrap();
+        // Missing keys are not counted
+        run(&["GET", "missing"]).await.unwrap();
+
+        assert_eq!(
+            *run(&["HOTKEYS", "1"]).await.unwrap(),
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(Cow::Borrowed("hot"))),
+                RespValue::Integer(5),
+            ]))
+        );
+        match &*run(&["HOTKEYS"]).await.unwrap() {
+            RespValue::Array(Some(items)) => assert_eq!(items.len(), 4),
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert!(Command::from_resp(RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Borrowed("HOTKEYS"))),
+            RespValue::BulkString(Some(Cow::Borrowed("0"))),
+        ])))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_bigkeys() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let parse = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+        };
+
+        parse(&["RPUSH", "l", "a", "b"])
+            .unwrap()
+            .exec(ctx.clone())
+            .await
+            .unwrap();
+        match &*parse(&["BIGKEYS", "samples", "0", "COUNT", "5"])
+            .unwrap()
+            .exec(ctx.clone())
+            .await
+            .unwrap()
+        {
+            RespValue::BulkString(Some(report)) => {
+                assert!(report.starts_with("Sampled 1 of 1 keys, "));
+                assert!(report.contains("  list: 1 keys, "));
+                assert!(report.contains("    \"l\" 2 elements, "));
+                assert!(report.ends_with("No big keys found in the sample.\n"));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert!(parse(&["BIGKEYS", "COUNT", "0"]).is_err());
+        assert!(parse(&["BIGKEYS", "COUNT"]).is_err());
+        assert!(parse(&["BIGKEYS", "LIMIT", "1"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_config() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+        let bulk = |s: &str| RespValue::BulkString(Some(Cow::Owned(s.to_string())));
+
+        run(&["CONFIG", "SET", "ratelimit-rate", "100"])
+            .await
+            .unwrap();
+        run(&["CONFIG", "SET", "RATELIMIT-KEY", "user"])
+            .await
+            .unwrap();
+        assert_eq!(ctx.ratelimit.rate(), 100);
+        assert_eq!(
+            *run(&["CONFIG", "GET", "ratelimit-r*", "*key"])
+                .await
+                .unwrap(),
+            RespValue::Array(Some(vec![
+                bulk("ratelimit-rate"),
+                bulk("100"),
+                bulk("ratelimit-key"),
+                bulk("user"),
+            ]))
+        );
+
+        for args in [
+            ["CONFIG", "SET", "ratelimit-burst", "lots"],
+            ["CONFIG", "SET", "ratelimit-key", "host"],
+            ["CONFIG", "SET", "no-such-option", "1"],
+        ] {
+            assert!(run(&args).await.is_err());
+        }
+        assert_eq!(ctx.ratelimit.burst(), 0);
+
+        ctx.config_set("maxmemory-policy", "allkeys-lfu").unwrap();
+        assert_eq!(
+            ctx.db().eviction_policy(),
+            crate::db::eviction::EvictionPolicy::AllKeysLfu
+        );
+        assert!(ctx.config_set("maxmemory-policy", "sometimes").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_commandstats() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0).with_client(0, 3);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .unwrap()
+            .exec(ctx.clone())
+        };
+        let info = |args: &[&str]| {
+            let reply = run(args);
+            async move {
+                match &*reply.await.unwrap() {
+                    RespValue::BulkString(Some(info)) => info.to_string(),
+                    other => panic!("unexpected reply {:?}", other),
+                }
+            }
+        };
+        ctx.cmdstats.record("get", Duration::from_micros(3), false);
+
+        let default = info(&["INFO"]).await;
+        assert!(default.contains("# Keyspace\r\n"));
+        assert!(!default.contains("# Commandstats"));
+        let stats = info(&["INFO", "CommandStats", "latencystats"]).await;
+        assert!(stats.starts_with("# Commandstats\r\ncmdstat_get:calls=1,usec=3,"));
+        assert!(stats.contains("# Latencystats\r\nlatency_percentiles_usec_get:p50="));
+        assert_eq!(
+            info(&["INFO", "memory"]).await.lines().next(),
+            Some("# Memory")
+        );
+        assert!(info(&["INFO", "everything"]).await.contains("# Server\r\n"));
+
+        let int = RespValue::Integer;
+        let bulk = |s: &'static str| RespValue::BulkString(Some(Cow::Borrowed(s)));
+        assert_eq!(
+            *run(&["LATENCY", "HISTOGRAM"]).await.unwrap(),
+            RespValue::Map(Some(vec![(
+                bulk("get"),
+                RespValue::Map(Some(vec![
+                    (bulk("calls"), int(1)),
+                    (
+                        bulk("histogram_usec"),
+                        RespValue::Map(Some(vec![(int(4), int(1))]))
+                    ),
+                ]))
+            )]))
+        );
+        assert_eq!(
+            *run(&["LATENCY", "HISTOGRAM", "set"]).await.unwrap(),
+            RespValue::Map(Some(vec![]))
+        );
+
+        run(&["CONFIG", "RESETSTAT"]).await.unwrap();
+        assert_eq!(info(&["INFO", "commandstats"]).await, "# Commandstats\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_exec_cluster_keyslot() {
+        let dbs = Arc::new(vec![Arc::new(DB::new(
+            crate::db::storage::DashMapStorage::new(),
+            16,
+        ))]);
+        let ctx = ExecContext::new(dbs, 0);
+        let run = |args: &[&str]| {
+            Command::from_resp(RespValue::Array(Some(
+                args.iter()
+                    .map(|a| RespValue::BulkString(Some(Cow::Owned(a.to_string()))))
+                    .collect(),
+            )))
+            .map(|cmd| cmd.exec(ctx.clone()))
+        };
+
+        assert_eq!(
+            *run(&["CLUSTER", "KEYSLOT", "foo"]).unwrap().await.unwrap(),
+            RespValue::Integer(12182)
+        );
+        assert_eq!(
+            *run(&["cluster", "keyslot", "{foo}.bar"])
+                .unwrap()
+                .await
+                .unwrap(),
+            RespValue::Integer(12182)
+        );
+        assert!(run(&["CLUSTER", "KEYSLOT"]).is_err());
+        assert!(run(&["CLUSTER", "NOPE"]).is_err());
+    }
+}
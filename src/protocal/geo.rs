@@ -0,0 +1,294 @@
+// GEO commands. Locations live in a sorted set whose scores are 52 bit
+// geohashes (26 bits each of interleaved latitude and longitude), so GEOADD'd
+// keys can also be read with the Z* commands.
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::{CommandError, GeoOrigin, GeoQuery, GeoShape};
+use crate::protocal::zset::{self, bulk, Reply, ZSetDB};
+use stream_resp::resp::RespValue;
+
+const LON_MIN: f64 = -180.0;
+const LON_MAX: f64 = 180.0;
+// Web Mercator limits, same as Redis
+const LAT_MIN: f64 = -85.05112878;
+const LAT_MAX: f64 = 85.05112878;
+const STEP: u32 = 26;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+
+pub fn valid(lon: f64, lat: f64) -> bool {
+    (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat)
+}
+
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    let scale = (1u64 << STEP) as f64;
+    let lat_bits = ((lat - LAT_MIN) / (LAT_MAX - LAT_MIN) * scale) as u64;
+    let lon_bits = ((lon - LON_MIN) / (LON_MAX - LON_MIN) * scale) as u64;
+    let (lat_bits, lon_bits) = (lat_bits.min((1 << STEP) - 1), lon_bits.min((1 << STEP) - 1));
+
+    // Latitude takes the even bits, longitude the odd ones
+    let mut hash = 0;
+    for i in 0..STEP {
+        hash |= ((lat_bits >> i) & 1) << (2 * i);
+        hash |= ((lon_bits >> i) & 1) << (2 * i + 1);
+    }
+    hash
+}
+
+// Center of the cell a geohash names, as (lon, lat)
+pub fn decode(hash: u64) -> (f64, f64) {
+    let (mut lat_bits, mut lon_bits) = (0u64, 0u64);
+    for i in 0..STEP {
+        lat_bits |= ((hash >> (2 * i)) & 1) << i;
+        lon_bits |= ((hash >> (2 * i + 1)) & 1) << i;
+    }
+    let scale = (1u64 << STEP) as f64;
+    let center = |bits: u64, min: f64, max: f64| {
+        let low = min + (bits as f64 / scale) * (max - min);
+        let high = min + ((bits + 1) as f64 / scale) * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (
+        center(lon_bits, LON_MIN, LON_MAX),
+        center(lat_bits, LAT_MIN, LAT_MAX),
+    )
+}
+
+// Haversine distance in meters
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1r, lat2r) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS * (u * u + lat1r.cos() * lat2r.cos() * v * v).sqrt().asin()
+}
+
+// Distance from the center when (lon, lat) falls inside the shape
+fn within(shape: GeoShape, center: (f64, f64), lon: f64, lat: f64) -> Option<f64> {
+    let dist = distance(center.0, center.1, lon, lat);
+    match shape {
+        GeoShape::Radius(radius) => (dist <= radius).then_some(dist),
+        GeoShape::Box(width, height) => {
+            let lat_dist =
+                EARTH_RADIUS_IN_METERS * (lat.to_radians() - center.1.to_radians()).abs();
+            let lon_dist = distance(center.0, lat, lon, lat);
+            (lat_dist <= height / 2.0 && lon_dist <= width / 2.0).then_some(dist)
+        }
+    }
+}
+
+fn format_distance(meters: f64, unit: f64) -> RespValue<'static> {
+    bulk(format!("{:.4}", meters / unit))
+}
+
+// GEOADD: replies with the number of members that were added
+pub fn add<S>(db: &ZSetDB<S>, key: String, items: Vec<(f64, f64, String)>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let members = items
+        .into_iter()
+        .map(|(lon, lat, member)| (encode(lon, lat) as f64, member))
+        .collect();
+    zset::add(db, key, members)
+}
+
+// GEOPOS: [lon, lat] per member, nil for missing ones
+pub fn pos<S>(db: &ZSetDB<S>, key: &String, members: &[String]) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let missing = RespValue::Array(Some(vec![RespValue::Array(None); members.len()]));
+    zset::read(db, key, missing, |items| {
+        let positions = members
+            .iter()
+            .map(|member| match zset::score(items, member) {
+                Some(score) => {
+                    let (lon, lat) = decode(score as u64);
+                    RespValue::Array(Some(vec![bulk(lon.to_string()), bulk(lat.to_string())]))
+                }
+                None => RespValue::Array(None),
+            })
+            .collect();
+        Ok(RespValue::Array(Some(positions)))
+    })
+}
+
+// GEODIST: nil unless both members exist
+pub fn dist<S>(db: &ZSetDB<S>, key: &String, from: &str, to: &str, unit: f64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    zset::read(db, key, RespValue::BulkString(None), |items| {
+        match (zset::score(items, from), zset::score(items, to)) {
+            (Some(a), Some(b)) => {
+                let ((lon1, lat1), (lon2, lat2)) = (decode(a as u64), decode(b as u64));
+                Ok(format_distance(distance(lon1, lat1, lon2, lat2), unit))
+            }
+            _ => Ok(RespValue::BulkString(None)),
+        }
+    })
+}
+
+// GEOSEARCH. Members are scanned in full and filtered by distance; each
+// match carries distance, hash and coordinates when asked for.
+pub fn search<S>(db: &ZSetDB<S>, key: &String, query: GeoQuery) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    zset::read(db, key, RespValue::Array(Some(vec![])), |items| {
+        let center = match &query.origin {
+            GeoOrigin::LonLat(lon, lat) => (*lon, *lat),
+            GeoOrigin::Member(member) => match zset::score(items, member) {
+                Some(score) => decode(score as u64),
+                None => return Err(CommandError::NoSuchMember),
+            },
+        };
+
+        let mut found = vec![];
+        for (member, score) in zset::entries(items) {
+            let hash = score as u64;
+            let (lon, lat) = decode(hash);
+            if let Some(dist) = within(query.shape, center, lon, lat) {
+                found.push((member, dist, hash, lon, lat));
+                if query.any && Some(found.len()) == query.count {
+                    break;
+                }
+            }
+        }
+
+        match query.ascending {
+            Some(true) => found.sort_by(|a, b| a.1.total_cmp(&b.1)),
+            Some(false) => found.sort_by(|a, b| b.1.total_cmp(&a.1)),
+            None => {}
+        }
+        if let Some(count) = query.count {
+            found.truncate(count);
+        }
+
+        let plain = !(query.with_dist || query.with_hash || query.with_coord);
+        let reply = found
+            .into_iter()
+            .map(|(member, dist, hash, lon, lat)| {
+                if plain {
+                    return bulk(member.to_string());
+                }
+                let mut entry = vec![bulk(member.to_string())];
+                if query.with_dist {
+                    entry.push(format_distance(dist, query.unit));
+                }
+                if query.with_hash {
+                    entry.push(RespValue::Integer(hash as i64));
+                }
+                if query.with_coord {
+                    entry.push(RespValue::Array(Some(vec![
+                        bulk(lon.to_string()),
+                        bulk(lat.to_string()),
+                    ])));
+                }
+                RespValue::Array(Some(entry))
+            })
+            .collect();
+        Ok(RespValue::Array(Some(reply)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::DB;
+    use crate::db::storage::DashMapStorage;
+
+    fn new_db() -> ZSetDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn sicily(db: &ZSetDB<DashMapStorage<String, Value>>) {
+        add(
+            db,
+            "Sicily".into(),
+            vec![
+                (13.361389, 38.115556, "Palermo".into()),
+                (15.087269, 37.502669, "Catania".into()),
+            ],
+        )
+        .unwrap();
+    }
+
+    fn query(origin: GeoOrigin, shape: GeoShape) -> GeoQuery {
+        GeoQuery {
+            origin,
+            shape,
+            unit: 1000.0,
+            ascending: None,
+            count: None,
+            any: false,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        // Same score Redis stores for Palermo
+        assert_eq!(encode(13.361389, 38.115556), 3479099956230698);
+        let (lon, lat) = decode(encode(13.361389, 38.115556));
+        assert!((lon - 13.361389).abs() < 1e-5);
+        assert!((lat - 38.115556).abs() < 1e-5);
+        assert!(!valid(0.0, 86.0));
+    }
+
+    #[test]
+    fn test_pos_and_dist() {
+        let db = new_db();
+        sicily(&db);
+        let key = "Sicily".to_string();
+
+        assert_eq!(
+            *dist(&db, &key, "Palermo", "Catania", 1000.0).unwrap(),
+            bulk("166.2742".into())
+        );
+        assert_eq!(
+            *dist(&db, &key, "Palermo", "Rome", 1.0).unwrap(),
+            RespValue::BulkString(None)
+        );
+
+        match &*pos(&db, &key, &["Palermo".into(), "Rome".into()]).unwrap() {
+            RespValue::Array(Some(items)) => {
+                assert!(matches!(&items[0], RespValue::Array(Some(c)) if c.len() == 2));
+                assert_eq!(items[1], RespValue::Array(None));
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let db = new_db();
+        sicily(&db);
+        let key = "Sicily".to_string();
+
+        let mut q = query(GeoOrigin::LonLat(15.0, 37.0), GeoShape::Radius(200_000.0));
+        q.ascending = Some(true);
+        q.with_dist = true;
+        let reply = search(&db, &key, q).unwrap();
+        assert_eq!(
+            *reply,
+            RespValue::Array(Some(vec![
+                RespValue::Array(Some(vec![bulk("Catania".into()), bulk("56.4413".into())])),
+                RespValue::Array(Some(vec![bulk("Palermo".into()), bulk("190.4424".into())])),
+            ]))
+        );
+
+        let mut q = query(
+            GeoOrigin::Member("Palermo".into()),
+            GeoShape::Box(400_000.0, 400_000.0),
+        );
+        q.ascending = Some(false);
+        q.count = Some(1);
+        let reply = search(&db, &key, q).unwrap();
+        assert_eq!(*reply, RespValue::Array(Some(vec![bulk("Catania".into())])));
+
+        let q = query(GeoOrigin::Member("Rome".into()), GeoShape::Radius(1.0));
+        assert!(search(&db, &key, q).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+// Glob-style patterns as Redis matches them in SCAN MATCH and CONFIG GET:
+// `*` any run of bytes, `?` any one byte, `[abc]`, `[a-z]` and `[^...]`
+// classes, and `\` taking the next byte literally. Bytes are compared, not
+// characters.
+//
+// Everything but `*` matches exactly one byte, so a failed match only ever
+// needs to retry from the last `*`, which keeps matching linear in the
+// pattern times the string even for patterns like `*a*a*a*b`. That product
+// can still be large for a hostile pattern, so every match also runs on a
+// step budget and gives up as no match once it is spent.
+use rand::Rng;
+
+// Steps glob_match takes at most, a pattern byte looked at being one
+pub const MAX_MATCH_STEPS: usize = 1 << 20;
+
+// Whether the token at p (anything but `*`) matches c, and where the next
+// token starts
+fn single(pattern: &[u8], mut p: usize, c: u8, nocase: bool) -> (bool, usize) {
+    let eq = |a: u8, b: u8| {
+        if nocase {
+            a.eq_ignore_ascii_case(&b)
+        } else {
+            a == b
+        }
+    };
+    match pattern[p] {
+        b'?' => (true, p + 1),
+        b'\\' if p + 1 < pattern.len() => (eq(pattern[p + 1], c), p + 2),
+        b'[' => {
+            p += 1;
+            let negated = pattern.get(p) == Some(&b'^');
+            if negated {
+                p += 1;
+            }
+            let mut matched = false;
+            // An unclosed class runs to the end of the pattern
+            while p < pattern.len() && pattern[p] != b']' {
+                if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                    p += 1;
+                    matched |= eq(pattern[p], c);
+                } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                    let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+                    if start > end {
+                        (start, end) = (end, start);
+                    }
+                    matched |= (start..=end).contains(&c)
+                        || nocase && (start..=end).contains(&c.to_ascii_lowercase())
+                        || nocase && (start..=end).contains(&c.to_ascii_uppercase());
+                    p += 2;
+                } else {
+                    matched |= eq(pattern[p], c);
+                }
+                p += 1;
+            }
+            (matched != negated, (p + 1).min(pattern.len()))
+        }
+        other => (eq(other, c), p + 1),
+    }
+}
+
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    glob_match_within(pattern, string, nocase, MAX_MATCH_STEPS).unwrap_or(false)
+}
+
+// The match, or None when it would take more than max_steps
+pub fn glob_match_within(
+    pattern: &[u8],
+    string: &[u8],
+    nocase: bool,
+    max_steps: usize,
+) -> Option<bool> {
+    let (mut p, mut s) = (0, 0);
+    // Just past the last `*` seen, and where in the string it took over
+    let mut retry: Option<(usize, usize)> = None;
+    let mut steps = 0;
+    loop {
+        steps += 1;
+        if steps > max_steps {
+            return None;
+        }
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            retry = Some((p, s));
+            continue;
+        }
+        if s == string.len() && p == pattern.len() {
+            return Some(true);
+        }
+        if p < pattern.len() && s < string.len() {
+            let (matched, next) = single(pattern, p, string[s], nocase);
+            // A class costs every byte of it
+            steps += next - p - 1;
+            if matched {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+        // Let the last `*` take one more byte and go again from there
+        match retry {
+            Some((after, taken)) if taken < string.len() => {
+                retry = Some((after, taken + 1));
+                p = after;
+                s = taken + 1;
+            }
+            _ => return Some(false),
+        }
+    }
+}
+
+// DEBUG STRINGMATCH-LEN: matches random patterns made of the special bytes
+// against random strings, which must all end within the budget. Tells how
+// many matched.
+pub fn fuzz(rounds: usize) -> usize {
+    const BYTES: &[u8] = b"*?[]^-\\ab";
+    let mut rng = rand::thread_rng();
+    let mut random = |max_len: usize| -> Vec<u8> {
+        let len = rng.gen_range(0..=max_len);
+        (0..len)
+            .map(|_| BYTES[rng.gen_range(0..BYTES.len())])
+            .collect()
+    };
+    (0..rounds)
+        .filter(|_| {
+            let pattern = random(64);
+            let string = random(256);
+            glob_match(&pattern, &string, false)
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes(), false)
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(matches("*", ""));
+        assert!(matches("user:*", "user:1000"));
+        assert!(!matches("user:*", "users"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(matches("h[c-a]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+        assert!(matches("a\\*b", "a*b"));
+        assert!(!matches("a\\*b", "axb"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("*a*a*a*b", "aaaaaaaaaaab"));
+        assert!(!matches("*a*a*a*b", &"a".repeat(10_000)));
+        assert!(matches("lat?ncy-*", "latency-monitor-threshold"));
+        assert!(!matches("ratelimit", "ratelimit-rate"));
+        // An unclosed class runs to the end
+        assert!(matches("a[bc", "ab"));
+        assert!(glob_match(b"HELLO*", b"hello world", true));
+        assert!(glob_match(b"[A-C]x", b"bx", true));
+    }
+
+    #[test]
+    fn test_step_budget() {
+        let string = "a".repeat(10_000);
+        // Every byte of the string against most of a long class
+        let class = format!("*[{}]b", "c".repeat(1000));
+        assert_eq!(
+            glob_match_within(class.as_bytes(), string.as_bytes(), false, 1 << 16),
+            None
+        );
+        assert!(!glob_match(class.as_bytes(), string.as_bytes(), false));
+        assert_eq!(
+            glob_match_within(b"*a", string.as_bytes(), false, 1 << 16),
+            Some(true)
+        );
+        fuzz(1000);
+    }
+}
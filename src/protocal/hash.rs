@@ -0,0 +1,368 @@
+// Hash commands. A hash is stored as a Value::Hash mapping fields to values,
+// or packed while small (see listpack), which the commands read and edit in
+// place; an empty hash is never stored, the key is removed instead.
+use crate::db::db::DB;
+use crate::db::listpack::{self, Listpack};
+use crate::db::storage::{Storage, Update};
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use crate::protocal::reply;
+use anyhow::{anyhow, Error};
+use indexmap::IndexMap;
+use rand::seq::index::sample;
+use rand::Rng;
+use std::borrow::Cow;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+type HashDB<S> = DB<S, String, Value>;
+type Pairs = IndexMap<String, String>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+// A stored hash in either form
+trait Fields {
+    fn len(&self) -> usize;
+    fn field(&self, field: &str) -> Option<&str>;
+    // The field and value at position i
+    fn pair(&self, i: usize) -> Option<(&str, &str)>;
+    // Sets field, true when it is new
+    fn set_field(&mut self, field: String, value: String) -> bool;
+}
+
+impl Fields for Pairs {
+    fn len(&self) -> usize {
+        IndexMap::len(self)
+    }
+
+    fn field(&self, field: &str) -> Option<&str> {
+        self.get(field).map(String::as_str)
+    }
+
+    fn pair(&self, i: usize) -> Option<(&str, &str)> {
+        self.get_index(i)
+            .map(|(field, value)| (field.as_str(), value.as_str()))
+    }
+
+    fn set_field(&mut self, field: String, value: String) -> bool {
+        self.insert(field, value).is_none()
+    }
+}
+
+impl Fields for Listpack {
+    fn len(&self) -> usize {
+        Listpack::len(self)
+    }
+
+    fn field(&self, field: &str) -> Option<&str> {
+        Listpack::field(self, field)
+    }
+
+    fn pair(&self, i: usize) -> Option<(&str, &str)> {
+        Listpack::pair(self, i)
+    }
+
+    fn set_field(&mut self, field: String, value: String) -> bool {
+        Listpack::set_field(self, &field, &value)
+    }
+}
+
+// Runs a read-only closure against the hash stored at key
+fn read<S, F>(db: &HashDB<S>, key: &String, missing: RespValue<'static>, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&dyn Fields) -> RespValue<'static>,
+{
+    let value = db
+        .get_typed(key, ValueType::Hash)
+        .map_err(CommandError::from_storage)?;
+    match value.as_deref() {
+        None => Ok(reply::intern(missing)),
+        Some(Value::Hash(pairs)) => Ok(reply::intern(f(pairs))),
+        Some(Value::Listpack(packed)) => Ok(reply::intern(f(packed))),
+        Some(_) => Err(anyhow!(CommandError::WrongType)),
+    }
+}
+
+// Runs a mutating closure against the hash stored at key, creating it when
+// missing. Nothing is stored if the closure fails or leaves it empty.
+fn write<S, F>(db: &HashDB<S>, key: String, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&mut dyn Fields) -> Result<RespValue<'static>, CommandError>,
+{
+    let limits = db.listpack_limits();
+    let reply = db
+        .update_typed(key, ValueType::Hash, |value| {
+            listpack::edit_in_place(value, &limits, |value| {
+                let fields: &mut dyn Fields = match value {
+                    None => {
+                        let mut pairs = IndexMap::new();
+                        return match f(&mut pairs) {
+                            Ok(reply) if !pairs.is_empty() => {
+                                (Update::Set(Value::Hash(pairs)), Ok(reply))
+                            }
+                            result => (Update::Keep, result),
+                        };
+                    }
+                    Some(Value::Hash(pairs)) => pairs,
+                    Some(Value::Listpack(packed)) => packed,
+                    Some(_) => return (Update::Keep, Err(CommandError::WrongType)),
+                };
+                let reply = f(fields);
+                if fields.len() == 0 {
+                    (Update::Delete, reply)
+                } else {
+                    (Update::Keep, reply)
+                }
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::intern(reply))
+}
+
+// HSET: replies with the number of fields that were added
+pub fn set<S>(db: &HashDB<S>, key: String, fields: Vec<(String, String)>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, |pairs| {
+        let mut added = 0;
+        for (field, value) in fields {
+            if pairs.set_field(field, value) {
+                added += 1;
+            }
+        }
+        Ok(RespValue::Integer(added))
+    })
+}
+
+pub fn get<S>(db: &HashDB<S>, key: &String, field: &str) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::BulkString(None), |pairs| {
+        match pairs.field(field) {
+            Some(value) => bulk(value.to_string()),
+            None => RespValue::BulkString(None),
+        }
+    })
+}
+
+pub fn incr_by<S>(db: &HashDB<S>, key: String, field: String, increment: i64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, |pairs| {
+        let current = match pairs.field(&field) {
+            Some(current) => current
+                .parse::<i64>()
+                .map_err(|_| CommandError::HashValueNotInteger)?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(increment)
+            .ok_or(CommandError::IncrementOverflow)?;
+        pairs.set_field(field, value.to_string());
+        Ok(RespValue::Integer(value))
+    })
+}
+
+pub fn incr_by_float<S>(db: &HashDB<S>, key: String, field: String, increment: f64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, |pairs| {
+        let current = match pairs.field(&field) {
+            Some(current) => current
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .ok_or(CommandError::HashValueNotFloat)?,
+            None => 0.0,
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(CommandError::NanOrInfinity);
+        }
+        let value = value.to_string();
+        pairs.set_field(field, value.clone());
+        Ok(bulk(value))
+    })
+}
+
+// HRANDFIELD. Without count a single field (or nil); a positive count picks
+// up to count distinct fields, a negative one allows repeats and always
+// returns |count| fields.
+pub fn rand_field<S>(db: &HashDB<S>, key: &String, count: Option<i64>, with_values: bool) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let missing = match count {
+        Some(_) => RespValue::Array(Some(vec![])),
+        None => RespValue::BulkString(None),
+    };
+    read(db, key, missing, |pairs| {
+        let mut rng = rand::thread_rng();
+        let count = match count {
+            None => {
+                let i = rng.gen_range(0..pairs.len());
+                return pairs
+                    .pair(i)
+                    .map_or(RespValue::BulkString(None), |(field, _)| {
+                        bulk(field.to_string())
+                    });
+            }
+            Some(count) => count,
+        };
+
+        let picked: Vec<usize> = if count >= 0 {
+            let n = (count as usize).min(pairs.len());
+            sample(&mut rng, pairs.len(), n).into_vec()
+        } else {
+            (0..count.unsigned_abs())
+                .map(|_| rng.gen_range(0..pairs.len()))
+                .collect()
+        };
+
+        let mut reply = Vec::with_capacity(picked.len() * if with_values { 2 } else { 1 });
+        for (field, value) in picked.into_iter().filter_map(|i| pairs.pair(i)) {
+            reply.push(bulk(field.to_string()));
+            if with_values {
+                reply.push(bulk(value.to_string()));
+            }
+        }
+        RespValue::Array(Some(reply))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    fn new_db() -> HashDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn as_str<'a>(value: &'a RespValue) -> &'a str {
+        match value {
+            RespValue::BulkString(Some(s)) => s,
+            _ => "",
+        }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_set_get() {
+        let db = new_db();
+        let key = "hash".to_string();
+        let reply = set(&db, key.clone(), fields(&[("a", "1"), ("b", "2")])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(2));
+        let reply = set(&db, key.clone(), fields(&[("a", "3"), ("c", "4")])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(1));
+
+        assert_eq!(*get(&db, &key, "a").unwrap(), bulk("3".into()));
+        assert_eq!(*get(&db, &key, "x").unwrap(), RespValue::BulkString(None));
+        assert_eq!(
+            *get(&db, &"missing".to_string(), "a").unwrap(),
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[test]
+    fn test_incr_by() {
+        let db = new_db();
+        let key = "hash".to_string();
+        assert_eq!(
+            *incr_by(&db, key.clone(), "n".into(), 5).unwrap(),
+            RespValue::Integer(5)
+        );
+        assert_eq!(
+            *incr_by(&db, key.clone(), "n".into(), -7).unwrap(),
+            RespValue::Integer(-2)
+        );
+
+        set(
+            &db,
+            key.clone(),
+            fields(&[("s", "abc"), ("max", "9223372036854775807")]),
+        )
+        .unwrap();
+        assert!(incr_by(&db, key.clone(), "s".into(), 1).is_err());
+        assert!(incr_by(&db, key.clone(), "max".into(), 1).is_err());
+    }
+
+    #[test]
+    fn test_incr_by_float() {
+        let db = new_db();
+        let key = "hash".to_string();
+        set(&db, key.clone(), fields(&[("f", "10.50")])).unwrap();
+        assert_eq!(
+            *incr_by_float(&db, key.clone(), "f".into(), 0.1).unwrap(),
+            bulk("10.6".into())
+        );
+        assert_eq!(
+            *incr_by_float(&db, key.clone(), "g".into(), -3.0).unwrap(),
+            bulk("-3".into())
+        );
+        assert!(incr_by_float(&db, key.clone(), "f".into(), f64::MAX).is_ok());
+        assert!(incr_by_float(&db, key, "f".into(), f64::MAX).is_err());
+
+        // A failed increment on a missing key must not create it
+        assert!(incr_by_float(&db, "other".into(), "f".into(), f64::INFINITY).is_err());
+        assert!(!db.exists(&"other".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_rand_field() {
+        let db = new_db();
+        let key = "hash".to_string();
+        set(
+            &db,
+            key.clone(),
+            fields(&[("a", "1"), ("b", "2"), ("c", "3")]),
+        )
+        .unwrap();
+
+        let reply = rand_field(&db, &key, None, false).unwrap();
+        assert!(matches!(&*reply, RespValue::BulkString(Some(_))));
+
+        match &*rand_field(&db, &key, Some(10), false).unwrap() {
+            RespValue::Array(Some(items)) => {
+                let mut items: Vec<_> = items.iter().map(as_str).collect();
+                items.sort();
+                assert_eq!(items, ["a", "b", "c"]);
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        match &*rand_field(&db, &key, Some(-5), true).unwrap() {
+            RespValue::Array(Some(items)) => {
+                assert_eq!(items.len(), 10);
+                for pair in items.chunks(2) {
+                    let expected = match as_str(&pair[0]) {
+                        "a" => "1",
+                        "b" => "2",
+                        _ => "3",
+                    };
+                    assert_eq!(as_str(&pair[1]), expected);
+                }
+            }
+            other => panic!("unexpected reply {:?}", other),
+        }
+        assert_eq!(
+            *rand_field(&db, &"missing".to_string(), Some(2), false).unwrap(),
+            RespValue::Array(Some(vec![]))
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// Index arithmetic shared by the commands that address elements or bytes by
+// position (LRANGE, LINDEX, LSET, LTRIM, ZRANGE, GETRANGE): negative indexes
+// count from the tail, so -1 is the last element and `0 -1` is everything.
+
+// Maps a possibly negative index onto a sequence of len, None when out of
+// range
+pub fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    (index >= 0 && (index as usize) < len).then_some(index as usize)
+}
+
+// Clamps a start/stop pair (inclusive, negatives from the tail) into a
+// half-open range, None when it selects nothing
+pub fn resolve_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop && start < len).then(|| (start as usize, stop as usize + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_index() {
+        assert_eq!(resolve_index(0, 3), Some(0));
+        assert_eq!(resolve_index(-1, 3), Some(2));
+        assert_eq!(resolve_index(-3, 3), Some(0));
+        assert_eq!(resolve_index(-4, 3), None);
+        assert_eq!(resolve_index(3, 3), None);
+        assert_eq!(resolve_index(0, 0), None);
+        assert_eq!(resolve_index(i64::MIN, 3), None);
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(0, -1, 5), Some((0, 5)));
+        assert_eq!(resolve_range(-2, -1, 5), Some((3, 5)));
+        assert_eq!(resolve_range(-100, 1, 5), Some((0, 2)));
+        assert_eq!(resolve_range(1, 100, 5), Some((1, 5)));
+        assert_eq!(resolve_range(3, 1, 5), None);
+        assert_eq!(resolve_range(5, 10, 5), None);
+        assert_eq!(resolve_range(-10, -6, 5), None);
+        assert_eq!(resolve_range(0, -1, 0), None);
+        assert_eq!(resolve_range(i64::MIN, i64::MAX, 2), Some((0, 2)));
+    }
+}
@@ -0,0 +1,587 @@
+// JSON commands, with the json feature. A document is stored as a
+// Value::Json and addressed by a subset of JSONPath: `$`, then any of
+// `.name`, `['name']`, `[index]` (negative counts from the end), and `.*` or
+// `[*]` for every child. A path without the leading `$` is in the older
+// RedisJSON syntax (`.`, `.a.b`, `a[0]`): it stands for one value, its first
+// match, and a command fails when there is none rather than answering for
+// every match.
+use crate::db::db::DB;
+use crate::db::storage::{Storage, Update};
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use crate::protocal::reply;
+use anyhow::{anyhow, Error};
+use serde_json::Value as Json;
+use std::borrow::Cow;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+type JsonDB<S> = DB<S, String, Value>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    Index(i64),
+    // Every child of an object or array
+    All,
+}
+
+// Where a match sits in a document, one object key or array index per level
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Loc {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    text: String,
+    steps: Vec<Step>,
+    legacy: bool,
+}
+
+// JSON.SET's NX and XX
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    IfMissing,
+    IfExists,
+}
+
+impl JsonPath {
+    // The whole document, the path commands default to
+    pub fn root() -> Self {
+        Self {
+            text: ".".to_string(),
+            steps: vec![],
+            legacy: true,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CommandError> {
+        let invalid = || CommandError::InvalidJsonPath(text.to_string());
+        let (rest, legacy) = match text.strip_prefix('$') {
+            Some(rest) => (rest, false),
+            None if text == "." => ("", true),
+            None if text.starts_with(['.', '[']) => (text, true),
+            // `a.b` is short for `.a.b`
+            None if !text.is_empty() => return Self::parse(&format!(".{}", text)),
+            None => return Err(invalid()),
+        };
+        let mut steps = vec![];
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    steps.push(Step::All);
+                }
+                '.' => {
+                    let mut name = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == '.' || c == '[' {
+                            break;
+                        }
+                        name.push(c);
+                        chars.next();
+                    }
+                    if name.is_empty() {
+                        return Err(invalid());
+                    }
+                    steps.push(Step::Key(name));
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    let quote = chars.next_if(|c| *c == '\'' || *c == '"');
+                    loop {
+                        match chars.next() {
+                            Some('\\') if quote.is_some() => {
+                                inner.push(chars.next().ok_or_else(invalid)?)
+                            }
+                            Some(c) if Some(c) == quote => break,
+                            Some(']') if quote.is_none() => break,
+                            Some(c) => inner.push(c),
+                            None => return Err(invalid()),
+                        }
+                    }
+                    if quote.is_some() {
+                        if chars.next() != Some(']') {
+                            return Err(invalid());
+                        }
+                        steps.push(Step::Key(inner));
+                    } else if inner == "*" {
+                        steps.push(Step::All);
+                    } else {
+                        steps.push(Step::Index(inner.trim().parse().map_err(|_| invalid())?));
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Self {
+            text: text.to_string(),
+            steps,
+            legacy,
+        })
+    }
+
+    fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Where the path matches in doc, only the first match for a legacy path
+    fn locate(&self, doc: &Json) -> Vec<Vec<Loc>> {
+        let mut found = vec![];
+        resolve(doc, &self.steps, &mut vec![], &mut found);
+        if self.legacy {
+            found.truncate(1);
+        }
+        found
+    }
+}
+
+fn resolve(doc: &Json, steps: &[Step], at: &mut Vec<Loc>, found: &mut Vec<Vec<Loc>>) {
+    let Some((step, rest)) = steps.split_first() else {
+        found.push(at.clone());
+        return;
+    };
+    let mut descend = |loc: Loc, child: &Json| {
+        at.push(loc);
+        resolve(child, rest, at, found);
+        at.pop();
+    };
+    match (step, doc) {
+        (Step::Key(name), Json::Object(map)) => {
+            if let Some(child) = map.get(name) {
+                descend(Loc::Key(name.clone()), child);
+            }
+        }
+        (Step::Index(index), Json::Array(items)) => {
+            let len = items.len() as i64;
+            let index = if *index < 0 { len + index } else { *index };
+            if (0..len).contains(&index) {
+                descend(Loc::Index(index as usize), &items[index as usize]);
+            }
+        }
+        (Step::All, Json::Object(map)) => {
+            for (name, child) in map {
+                descend(Loc::Key(name.clone()), child);
+            }
+        }
+        (Step::All, Json::Array(items)) => {
+            for (i, child) in items.iter().enumerate() {
+                descend(Loc::Index(i), child);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn at<'a>(doc: &'a Json, locs: &[Loc]) -> Option<&'a Json> {
+    locs.iter().try_fold(doc, |value, loc| match (loc, value) {
+        (Loc::Key(name), Json::Object(map)) => map.get(name),
+        (Loc::Index(i), Json::Array(items)) => items.get(*i),
+        _ => None,
+    })
+}
+
+fn at_mut<'a>(doc: &'a mut Json, locs: &[Loc]) -> Option<&'a mut Json> {
+    locs.iter().try_fold(doc, |value, loc| match (loc, value) {
+        (Loc::Key(name), Json::Object(map)) => map.get_mut(name),
+        (Loc::Index(i), Json::Array(items)) => items.get_mut(*i),
+        _ => None,
+    })
+}
+
+// Type of a value as RedisJSON names it in errors
+fn kind(value: &Json) -> &'static str {
+    match value {
+        Json::Null => "null",
+        Json::Bool(_) => "boolean",
+        Json::Number(n) if n.is_f64() => "number",
+        Json::Number(_) => "integer",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+pub fn parse_value(text: &str) -> Result<Json, CommandError> {
+    serde_json::from_str(text).map_err(|e| CommandError::InvalidJson(e.to_string()))
+}
+
+// Runs a mutating closure against the document stored at key, which must
+// exist
+fn write<S, F>(db: &JsonDB<S>, key: String, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&mut Json) -> Result<RespValue<'static>, CommandError>,
+{
+    let reply = db
+        .update_typed(key, ValueType::Json, |value| match value {
+            None => (Update::Keep, Err(CommandError::JsonNoKey)),
+            Some(Value::Json(doc)) => (Update::Keep, f(doc)),
+            Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::intern(reply))
+}
+
+// JSON.SET: OK, or nil when NX or XX held it back or a $ path matched nowhere
+// a value could go. A new key only takes a document at the root.
+pub fn set<S>(
+    db: &JsonDB<S>,
+    key: String,
+    path: &JsonPath,
+    value: Json,
+    condition: SetCondition,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let done = db
+        .update_typed(key, ValueType::Json, |stored| match stored {
+            None if !path.is_root() => (Update::Keep, Err(CommandError::JsonNewAtRoot)),
+            None if condition == SetCondition::IfExists => (Update::Keep, Ok(false)),
+            None => (Update::Set(Value::Json(value)), Ok(true)),
+            Some(Value::Json(doc)) => (Update::Keep, set_in(doc, path, value, condition)),
+            Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(if done { reply::ok() } else { reply::nil() })
+}
+
+fn set_in(
+    doc: &mut Json,
+    path: &JsonPath,
+    value: Json,
+    condition: SetCondition,
+) -> Result<bool, CommandError> {
+    let found = path.locate(doc);
+    if !found.is_empty() {
+        if condition == SetCondition::IfMissing {
+            return Ok(false);
+        }
+        // Matches are all as deep, none is inside another
+        for locs in found {
+            if let Some(target) = at_mut(doc, &locs) {
+                *target = value.clone();
+            }
+        }
+        return Ok(true);
+    }
+    if condition == SetCondition::IfExists {
+        return Ok(false);
+    }
+    // A missing last key is added to the objects its parent path matches
+    let mut created = false;
+    if let Some((Step::Key(name), parent)) = path.steps.split_last() {
+        let parent = JsonPath {
+            steps: parent.to_vec(),
+            ..path.clone()
+        };
+        for locs in parent.locate(doc) {
+            if let Some(Json::Object(map)) = at_mut(doc, &locs) {
+                map.insert(name.clone(), value.clone());
+                created = true;
+            }
+        }
+    }
+    if !created && path.legacy {
+        return Err(CommandError::NoJsonPath(path.text.clone()));
+    }
+    Ok(created)
+}
+
+// The reply for one path: the value itself for a legacy path, an array of
+// every match for a $ path
+fn select(doc: &Json, path: &JsonPath) -> Result<Json, CommandError> {
+    let mut matches = path
+        .locate(doc)
+        .into_iter()
+        .filter_map(|locs| at(doc, &locs).cloned());
+    if path.legacy {
+        matches
+            .next()
+            .ok_or_else(|| CommandError::NoJsonPath(path.text.clone()))
+    } else {
+        Ok(Json::Array(matches.collect()))
+    }
+}
+
+// JSON.GET: the document, or what the paths select as JSON text. Several
+// paths are answered with an object of each path and its selection.
+pub fn get<S>(db: &JsonDB<S>, key: &String, paths: &[JsonPath]) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let value = db
+        .get_typed(key, ValueType::Json)
+        .map_err(CommandError::from_storage)?;
+    let doc = match value.as_deref() {
+        None => return Ok(reply::nil()),
+        Some(Value::Json(doc)) => doc,
+        Some(_) => return Err(anyhow!(CommandError::WrongType)),
+    };
+    let selected = match paths {
+        [] => doc.clone(),
+        [path] => select(doc, path)?,
+        paths => Json::Object(
+            paths
+                .iter()
+                .map(|path| Ok((path.text.clone(), select(doc, path)?)))
+                .collect::<Result<_, CommandError>>()?,
+        ),
+    };
+    Ok(reply::intern(bulk(selected.to_string())))
+}
+
+// JSON.DEL: the number of values deleted. Deleting the root deletes the key.
+pub fn del<S>(db: &JsonDB<S>, key: String, path: &JsonPath) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let deleted = db
+        .update_typed(key, ValueType::Json, |value| match value {
+            None => (Update::Keep, Ok(0)),
+            Some(Value::Json(_)) if path.is_root() => (Update::Delete, Ok(1)),
+            Some(Value::Json(doc)) => {
+                let mut found = path.locate(doc);
+                // Later indexes of an array first, so earlier ones stay put
+                found.sort_unstable();
+                let mut deleted = 0;
+                for mut locs in found.into_iter().rev() {
+                    let last = locs.pop().expect("not the root");
+                    let removed = match (at_mut(doc, &locs), last) {
+                        (Some(Json::Object(map)), Loc::Key(name)) => map.remove(&name).is_some(),
+                        (Some(Json::Array(items)), Loc::Index(i)) if i < items.len() => {
+                            items.remove(i);
+                            true
+                        }
+                        _ => false,
+                    };
+                    deleted += removed as i64;
+                }
+                (Update::Keep, Ok(deleted))
+            }
+            Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::integer(deleted))
+}
+
+// JSON.ARRAPPEND: the new length of each array matched, nil for a match
+// that isn't one. A legacy path answers with its match's length alone.
+pub fn arr_append<S>(db: &JsonDB<S>, key: String, path: &JsonPath, values: Vec<Json>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, |doc| {
+        let found = path.locate(doc);
+        if path.legacy && found.is_empty() {
+            return Err(CommandError::NoJsonPath(path.text.clone()));
+        }
+        let mut lengths = vec![];
+        for locs in found {
+            lengths.push(match at_mut(doc, &locs) {
+                Some(Json::Array(items)) => {
+                    items.extend(values.iter().cloned());
+                    Ok(items.len() as i64)
+                }
+                Some(other) => Err(kind(other)),
+                None => Err("nothing"),
+            });
+        }
+        if path.legacy {
+            return match lengths.remove(0) {
+                Ok(len) => Ok(RespValue::Integer(len)),
+                Err(found) => Err(CommandError::JsonNotArray(found.to_string())),
+            };
+        }
+        Ok(RespValue::Array(Some(
+            lengths
+                .into_iter()
+                .map(|len| match len {
+                    Ok(len) => RespValue::Integer(len),
+                    Err(_) => RespValue::BulkString(None),
+                })
+                .collect(),
+        )))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+    use serde_json::json;
+
+    fn new_db() -> JsonDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn path(text: &str) -> JsonPath {
+        JsonPath::parse(text).unwrap()
+    }
+
+    fn get_text(db: &JsonDB<DashMapStorage<String, Value>>, key: &str, paths: &[&str]) -> String {
+        let paths: Vec<_> = paths.iter().map(|p| path(p)).collect();
+        match &*get(db, &key.to_string(), &paths).unwrap() {
+            RespValue::BulkString(Some(s)) => s.to_string(),
+            other => panic!("not a bulk string: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(
+            path("$.a['b c'][-1].*").steps,
+            vec![
+                Step::Key("a".into()),
+                Step::Key("b c".into()),
+                Step::Index(-1),
+                Step::All,
+            ]
+        );
+        assert!(!path("$").legacy);
+        assert!(path(".").is_root() && path(".").legacy);
+        assert_eq!(path("a.b[0]").steps, path(".a.b[0]").steps);
+        assert_eq!(path("$[*]").steps, vec![Step::All]);
+        for bad in ["", "$.", "$..a", "$[x]", "$['a'", "$a"] {
+            assert!(JsonPath::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_set_get() {
+        let db = new_db();
+        let key = "doc".to_string();
+        let doc = json!({"a": {"n": 1}, "b": {"n": 2}, "list": [1, 2, 3]});
+        // A new key takes a document at the root only
+        assert!(set(
+            &db,
+            key.clone(),
+            &path("$.a"),
+            json!(1),
+            SetCondition::Always
+        )
+        .is_err());
+        set(
+            &db,
+            key.clone(),
+            &path("$"),
+            doc.clone(),
+            SetCondition::Always,
+        )
+        .unwrap();
+        assert_eq!(get_text(&db, "doc", &[]), doc.to_string());
+
+        assert_eq!(get_text(&db, "doc", &["$.*.n"]), "[1,2]");
+        assert_eq!(get_text(&db, "doc", &[".a.n"]), "1");
+        assert_eq!(get_text(&db, "doc", &["$.list[-1]"]), "[3]");
+        assert_eq!(get_text(&db, "doc", &["$.missing"]), "[]");
+        assert!(get(&db, &key, &[path(".missing")]).is_err());
+        assert_eq!(
+            get_text(&db, "doc", &[".a.n", ".list[0]"]),
+            r#"{".a.n":1,".list[0]":1}"#
+        );
+
+        // Every match is set, a missing last key is added
+        let reply = set(
+            &db,
+            key.clone(),
+            &path("$.*.n"),
+            json!(0),
+            SetCondition::Always,
+        );
+        assert_eq!(*reply.unwrap(), *reply::ok());
+        set(
+            &db,
+            key.clone(),
+            &path("$.a.m"),
+            json!("new"),
+            SetCondition::Always,
+        )
+        .unwrap();
+        assert_eq!(get_text(&db, "doc", &["$.a"]), r#"[{"m":"new","n":0}]"#);
+        let nil = set(
+            &db,
+            key.clone(),
+            &path("$.a.n"),
+            json!(5),
+            SetCondition::IfMissing,
+        );
+        assert_eq!(*nil.unwrap(), *reply::nil());
+        let nil = set(
+            &db,
+            key.clone(),
+            &path("$.x.y"),
+            json!(5),
+            SetCondition::Always,
+        );
+        assert_eq!(*nil.unwrap(), *reply::nil());
+        assert!(set(
+            &db,
+            key.clone(),
+            &path(".x.y"),
+            json!(5),
+            SetCondition::Always
+        )
+        .is_err());
+
+        assert_eq!(*get(&db, &"none".to_string(), &[]).unwrap(), *reply::nil());
+        db.set("str".to_string(), Value::str("v")).unwrap();
+        assert!(get(&db, &"str".to_string(), &[]).is_err());
+    }
+
+    #[test]
+    fn test_del_arr_append() {
+        let db = new_db();
+        let key = "doc".to_string();
+        let doc = json!({"a": [1, 2, 3, 4], "b": {"c": 1}, "s": "x"});
+        set(&db, key.clone(), &path("$"), doc, SetCondition::Always).unwrap();
+
+        let reply = arr_append(&db, key.clone(), &path("$.*"), vec![json!(5), json!("six")]);
+        assert_eq!(
+            *reply.unwrap(),
+            RespValue::Array(Some(vec![
+                RespValue::Integer(6),
+                RespValue::BulkString(None),
+                RespValue::BulkString(None),
+            ]))
+        );
+        assert_eq!(
+            *arr_append(&db, key.clone(), &path(".a"), vec![json!(null)]).unwrap(),
+            RespValue::Integer(7)
+        );
+        assert!(arr_append(&db, key.clone(), &path(".s"), vec![json!(1)]).is_err());
+        assert!(arr_append(&db, "none".to_string(), &path("$"), vec![json!(1)]).is_err());
+
+        // Indexes are deleted back to front
+        assert_eq!(
+            *del(&db, key.clone(), &path("$.a[*]")).unwrap(),
+            RespValue::Integer(7)
+        );
+        assert_eq!(
+            *del(&db, key.clone(), &path("$.b.c")).unwrap(),
+            RespValue::Integer(1)
+        );
+        assert_eq!(get_text(&db, "doc", &[]), r#"{"a":[],"b":{},"s":"x"}"#);
+        assert_eq!(
+            *del(&db, key.clone(), &JsonPath::root()).unwrap(),
+            RespValue::Integer(1)
+        );
+        assert_eq!(db.len(), 0);
+        assert_eq!(
+            *del(&db, key.clone(), &JsonPath::root()).unwrap(),
+            RespValue::Integer(0)
+        );
+    }
+}
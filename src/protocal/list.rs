@@ -0,0 +1,659 @@
+// List commands. A list is stored as a Value::List of its elements, in a
+// quicklist so both ends are O(1), or packed while small (see listpack),
+// which pushes edit in place; an empty list is never stored, the key is
+// removed instead.
+use crate::db::db::DB;
+use crate::db::listpack;
+use crate::db::quicklist::QuickList;
+use crate::db::storage::{Storage, Update};
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use crate::protocal::command::{CommandError, LPosOptions};
+use crate::protocal::index::{resolve_index, resolve_range};
+use crate::protocal::reply;
+use anyhow::{anyhow, Error};
+use std::borrow::Cow;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use stream_resp::resp::RespValue;
+use tokio::time::{timeout_at, Instant};
+
+type ListDB<S> = DB<S, String, Value>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+// Runs a read-only closure against the list stored at key
+fn read<S, F>(db: &ListDB<S>, key: &String, missing: RespValue<'static>, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&QuickList) -> RespValue<'static>,
+{
+    let value = listpack::expanded(
+        db.get_typed(key, ValueType::List)
+            .map_err(CommandError::from_storage)?,
+    );
+    match value.as_deref() {
+        None => Ok(reply::intern(missing)),
+        Some(Value::List(items)) => Ok(reply::intern(f(items))),
+        Some(_) => Err(anyhow!(CommandError::WrongType)),
+    }
+}
+
+// Runs a mutating closure against the list stored at key. The list is
+// deleted if the closure leaves it empty.
+fn write<S, F>(
+    db: &ListDB<S>,
+    key: String,
+    missing: Result<RespValue<'static>, CommandError>,
+    f: F,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&mut QuickList) -> Result<RespValue<'static>, CommandError>,
+{
+    let limits = db.listpack_limits();
+    let reply = db
+        .update_typed(key, ValueType::List, |value| {
+            listpack::edit(value, &limits, |value| match value {
+                None => (Update::Keep, missing),
+                Some(Value::List(items)) => {
+                    let reply = f(items);
+                    if items.is_empty() {
+                        (Update::Delete, reply)
+                    } else {
+                        (Update::Keep, reply)
+                    }
+                }
+                Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::intern(reply))
+}
+
+pub fn push<S>(db: &ListDB<S>, key: String, values: Vec<String>, front: bool) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let len = db
+        .update_typed(key, ValueType::List, |value| {
+            listpack::edit_in_place(value, &limits, |value| {
+                // LPUSH a b c leaves c at the head
+                let push = |items: &mut QuickList, values: Vec<String>| {
+                    for value in values {
+                        if front {
+                            items.push_front(value);
+                        } else {
+                            items.push_back(value);
+                        }
+                    }
+                };
+                match value {
+                    None => {
+                        let mut items = QuickList::new();
+                        push(&mut items, values);
+                        let len = items.len();
+                        (Update::Set(Value::List(items)), Ok(len))
+                    }
+                    Some(Value::List(items)) => {
+                        push(items, values);
+                        (Update::Keep, Ok(items.len()))
+                    }
+                    Some(Value::Listpack(packed)) => {
+                        for value in &values {
+                            if front {
+                                packed.push_front(value);
+                            } else {
+                                packed.push_back(value);
+                            }
+                        }
+                        (Update::Keep, Ok(packed.len()))
+                    }
+                    Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+                }
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    db.signal_ready();
+    Ok(reply::integer(len as i64))
+}
+
+// LPOP/RPOP. Without count the reply is a single element, with count it is
+// an array of up to count elements.
+pub fn pop<S>(db: &ListDB<S>, key: String, count: Option<usize>, front: bool) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let missing = match count {
+        Some(_) => RespValue::Array(None),
+        None => RespValue::BulkString(None),
+    };
+    write(db, key, Ok(missing), |items| {
+        let popped: Vec<_> = (0..count.unwrap_or(1))
+            .map_while(|_| take(items, front))
+            .collect();
+        Ok(match count {
+            Some(_) => RespValue::Array(Some(popped.into_iter().map(bulk).collect())),
+            None => popped
+                .into_iter()
+                .next()
+                .map_or(RespValue::BulkString(None), bulk),
+        })
+    })
+}
+
+pub fn len<S>(db: &ListDB<S>, key: &String) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::Integer(0), |items| {
+        RespValue::Integer(items.len() as i64)
+    })
+}
+
+pub fn range<S>(db: &ListDB<S>, key: &String, start: i64, stop: i64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::Array(Some(vec![])), |items| {
+        let selected = match resolve_range(start, stop, items.len()) {
+            Some((from, to)) => items
+                .iter_from(from)
+                .take(to - from)
+                .cloned()
+                .map(bulk)
+                .collect(),
+            None => vec![],
+        };
+        RespValue::Array(Some(selected))
+    })
+}
+
+pub fn index<S>(db: &ListDB<S>, key: &String, index: i64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(
+        db,
+        key,
+        RespValue::BulkString(None),
+        |items| match resolve_index(index, items.len()).and_then(|i| items.get(i)) {
+            Some(item) => bulk(item.clone()),
+            None => RespValue::BulkString(None),
+        },
+    )
+}
+
+// LPOS: the index of a match, or with COUNT an array of them. Indexes are
+// from the head even when a negative rank walks from the tail.
+pub fn pos<S>(db: &ListDB<S>, key: &String, element: &str, options: LPosOptions) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let missing = match options.count {
+        Some(_) => RespValue::Array(Some(vec![])),
+        None => RespValue::BulkString(None),
+    };
+    read(db, key, missing, |items| {
+        let len = items.len();
+        let scanned = match options.maxlen {
+            0 => len,
+            maxlen => maxlen.min(len),
+        };
+        let skip = (options.rank.unsigned_abs() - 1) as usize;
+        let scan: Box<dyn Iterator<Item = (usize, &String)>> = if options.rank > 0 {
+            Box::new(items.iter().enumerate())
+        } else {
+            Box::new(
+                items
+                    .iter()
+                    .rev()
+                    .enumerate()
+                    .map(|(n, item)| (len - 1 - n, item)),
+            )
+        };
+        let mut matches = scan
+            .take(scanned)
+            .filter(|(_, item)| *item == element)
+            .skip(skip)
+            .map(|(i, _)| RespValue::Integer(i as i64));
+        match options.count {
+            None => matches.next().unwrap_or(RespValue::BulkString(None)),
+            Some(0) => RespValue::Array(Some(matches.collect())),
+            Some(count) => RespValue::Array(Some(matches.take(count).collect())),
+        }
+    })
+}
+
+pub fn set<S>(db: &ListDB<S>, key: String, index: i64, element: String) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, Err(CommandError::NoSuchKey), |items| {
+        let item = resolve_index(index, items.len())
+            .and_then(|i| items.get_mut(i))
+            .ok_or(CommandError::IndexOutOfRange)?;
+        *item = element;
+        Ok(RespValue::SimpleString(Cow::Borrowed("OK")))
+    })
+}
+
+// LINSERT: -1 when the pivot is missing, 0 when the key is missing
+pub fn insert<S>(db: &ListDB<S>, key: String, before: bool, pivot: &str, element: String) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, Ok(RespValue::Integer(0)), |items| {
+        match items.iter().position(|item| item == pivot) {
+            Some(i) => {
+                let at = if before { i } else { i + 1 };
+                items.insert(at, element);
+                Ok(RespValue::Integer(items.len() as i64))
+            }
+            None => Ok(RespValue::Integer(-1)),
+        }
+    })
+}
+
+// LREM: count > 0 removes from the head, < 0 from the tail, 0 removes all
+pub fn rem<S>(db: &ListDB<S>, key: String, count: i64, element: &str) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, Ok(RespValue::Integer(0)), |items| {
+        let limit = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+        let mut removed = 0;
+        if count >= 0 {
+            items.retain(|item| {
+                if removed < limit && item == element {
+                    removed += 1;
+                    return false;
+                }
+                true
+            });
+        } else {
+            // Indexes of the matches nearest the tail, then dropped in one pass
+            let len = items.len();
+            let mut doomed: Vec<_> = items
+                .iter()
+                .rev()
+                .enumerate()
+                .filter(|(_, item)| *item == element)
+                .take(limit)
+                .map(|(n, _)| len - 1 - n)
+                .collect();
+            doomed.reverse();
+            removed = doomed.len();
+            let mut doomed = doomed.into_iter().peekable();
+            let mut i = 0;
+            items.retain(|_| {
+                let drop = doomed.next_if_eq(&i).is_some();
+                i += 1;
+                !drop
+            });
+        }
+        Ok(RespValue::Integer(removed as i64))
+    })
+}
+
+pub fn trim<S>(db: &ListDB<S>, key: String, start: i64, stop: i64) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let ok = RespValue::SimpleString(Cow::Borrowed("OK"));
+    write(db, key, Ok(ok.clone()), |items| {
+        match resolve_range(start, stop, items.len()) {
+            Some((from, to)) => items.keep(from, to),
+            None => items.clear(),
+        }
+        Ok(ok)
+    })
+}
+
+fn take(items: &mut QuickList, front: bool) -> Option<String> {
+    if front {
+        items.pop_front()
+    } else {
+        items.pop_back()
+    }
+}
+
+fn put(items: &mut QuickList, element: String, front: bool) {
+    if front {
+        items.push_front(element);
+    } else {
+        items.push_back(element);
+    }
+}
+
+// LMOVE (and RPOPLPUSH): pops from one end of source and pushes onto one
+// end of destination atomically, replying with the moved element
+pub fn lmove<S>(
+    db: &ListDB<S>,
+    source: String,
+    destination: String,
+    from_front: bool,
+    to_front: bool,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    if source == destination {
+        // Rotation within a single list
+        return write(db, source, Ok(RespValue::BulkString(None)), |items| {
+            let element = take(items, from_front).ok_or(CommandError::NoSuchKey)?;
+            put(items, element.clone(), to_front);
+            Ok(bulk(element))
+        });
+    }
+
+    let limits = db.listpack_limits();
+    let moved = db
+        .update_pair(source, destination, |src, dst| {
+            listpack::edit_pair(src, dst, &limits, |src, dst| {
+                let items = match src {
+                    None => return (Update::Keep, Update::Keep, Ok(None)),
+                    Some(Value::List(items)) => items,
+                    Some(_) => return (Update::Keep, Update::Keep, Err(CommandError::WrongType)),
+                };
+                // Check destination before touching source
+                if !matches!(dst, None | Some(Value::List(_))) {
+                    return (Update::Keep, Update::Keep, Err(CommandError::WrongType));
+                }
+
+                let element = match take(items, from_front) {
+                    Some(element) => element,
+                    None => return (Update::Keep, Update::Keep, Ok(None)),
+                };
+                let src_update = if items.is_empty() {
+                    Update::Delete
+                } else {
+                    Update::Keep
+                };
+                let dst_update = match dst {
+                    Some(Value::List(dst_items)) => {
+                        put(dst_items, element.clone(), to_front);
+                        Update::Keep
+                    }
+                    _ => Update::Set(Value::List(QuickList::from(vec![element.clone()]))),
+                };
+                (src_update, dst_update, Ok(Some(element)))
+            })
+        })
+        .map_err(CommandError::StorageError)??;
+
+    match moved {
+        Some(element) => {
+            db.signal_ready();
+            Ok(Arc::new(bulk(element)))
+        }
+        None => Ok(reply::nil()),
+    }
+}
+
+// BLMOVE: LMOVE that waits up to timeout (forever when None) for source to
+// get an element. Each try is made by attempt, an LMOVE that may hold the
+// keys while it runs. Replies with a null array on timeout.
+pub async fn blmove<S, F, Fut>(db: &ListDB<S>, timeout: Option<Duration>, mut attempt: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Reply>,
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Register interest before checking so a push in between still wakes us
+        let ready = db.ready();
+        tokio::pin!(ready);
+        ready.as_mut().enable();
+
+        let reply = attempt().await?;
+        if *reply != RespValue::BulkString(None) {
+            return Ok(reply);
+        }
+
+        match deadline {
+            Some(deadline) => {
+                if timeout_at(deadline, ready).await.is_err() {
+                    return Ok(Arc::new(RespValue::Array(None)));
+                }
+            }
+            None => ready.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    fn new_db() -> ListDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn elements(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn contents(db: &ListDB<DashMapStorage<String, Value>>) -> RespValue<'static> {
+        range(db, &"list".to_string(), 0, -1)
+            .unwrap()
+            .as_ref()
+            .clone()
+    }
+
+    fn array(values: &[&str]) -> RespValue<'static> {
+        RespValue::Array(Some(values.iter().map(|v| bulk(v.to_string())).collect()))
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let db = new_db();
+        let key = "list".to_string();
+        push(&db, key.clone(), elements(&["a", "b"]), true).unwrap();
+        let reply = push(&db, key.clone(), elements(&["c"]), false).unwrap();
+        assert_eq!(*reply, RespValue::Integer(3));
+        assert_eq!(contents(&db), array(&["b", "a", "c"]));
+
+        assert_eq!(
+            *pop(&db, key.clone(), None, true).unwrap(),
+            bulk("b".into())
+        );
+        assert_eq!(
+            *pop(&db, key.clone(), Some(5), false).unwrap(),
+            array(&["c", "a"])
+        );
+        // Popping the last element removes the key
+        assert!(!db.exists(&key).unwrap());
+        assert_eq!(
+            *pop(&db, key, Some(1), true).unwrap(),
+            RespValue::Array(None)
+        );
+    }
+
+    #[test]
+    fn test_insert_set_and_pos() {
+        let db = new_db();
+        let key = "list".to_string();
+        push(&db, key.clone(), elements(&["a", "c"]), false).unwrap();
+
+        assert_eq!(
+            *insert(&db, key.clone(), true, "c", "b".into()).unwrap(),
+            RespValue::Integer(3)
+        );
+        assert_eq!(
+            *insert(&db, key.clone(), false, "x", "y".into()).unwrap(),
+            RespValue::Integer(-1)
+        );
+        assert_eq!(contents(&db), array(&["a", "b", "c"]));
+
+        set(&db, key.clone(), -1, "z".into()).unwrap();
+        assert!(set(&db, key.clone(), 3, "z".into()).is_err());
+        assert!(set(&db, "missing".into(), 0, "z".into()).is_err());
+        assert_eq!(contents(&db), array(&["a", "b", "z"]));
+
+        let first = LPosOptions::default();
+        assert_eq!(*pos(&db, &key, "b", first).unwrap(), RespValue::Integer(1));
+        assert_eq!(
+            *pos(&db, &key, "q", first).unwrap(),
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[test]
+    fn test_pos_options() {
+        let db = new_db();
+        let key = "list".to_string();
+        push(
+            &db,
+            key.clone(),
+            elements(&["a", "b", "c", "1", "2", "3", "c", "c"]),
+            false,
+        )
+        .unwrap();
+        let pos = |rank, count, maxlen| {
+            let options = LPosOptions {
+                rank,
+                count,
+                maxlen,
+            };
+            (*pos(&db, &key, "c", options).unwrap()).clone()
+        };
+        let indexes = |indexes: &[i64]| {
+            RespValue::Array(Some(
+                indexes.iter().map(|&i| RespValue::Integer(i)).collect(),
+            ))
+        };
+
+        assert_eq!(pos(1, None, 0), RespValue::Integer(2));
+        assert_eq!(pos(2, None, 0), RespValue::Integer(6));
+        assert_eq!(pos(-1, None, 0), RespValue::Integer(7));
+        assert_eq!(pos(4, None, 0), RespValue::BulkString(None));
+        assert_eq!(pos(1, Some(0), 0), indexes(&[2, 6, 7]));
+        assert_eq!(pos(1, Some(2), 0), indexes(&[2, 6]));
+        assert_eq!(pos(-2, Some(0), 0), indexes(&[6, 2]));
+        // MAXLEN bounds the elements compared, from whichever end
+        assert_eq!(pos(1, Some(0), 3), indexes(&[2]));
+        assert_eq!(pos(-1, Some(0), 2), indexes(&[7, 6]));
+        assert_eq!(pos(1, None, 2), RespValue::BulkString(None));
+
+        let missing = "missing".to_string();
+        let options = LPosOptions {
+            count: Some(1),
+            ..LPosOptions::default()
+        };
+        assert_eq!(
+            *super::pos(&db, &missing, "c", options).unwrap(),
+            indexes(&[])
+        );
+    }
+
+    #[test]
+    fn test_rem_and_trim() {
+        let db = new_db();
+        let key = "list".to_string();
+        push(
+            &db,
+            key.clone(),
+            elements(&["a", "x", "b", "x", "c", "x"]),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            *rem(&db, key.clone(), -2, "x").unwrap(),
+            RespValue::Integer(2)
+        );
+        assert_eq!(contents(&db), array(&["a", "x", "b", "c"]));
+        assert_eq!(
+            *rem(&db, key.clone(), 0, "x").unwrap(),
+            RespValue::Integer(1)
+        );
+
+        trim(&db, key.clone(), 1, -1).unwrap();
+        assert_eq!(contents(&db), array(&["b", "c"]));
+        trim(&db, key.clone(), 5, 10).unwrap();
+        assert!(!db.exists(&key).unwrap());
+    }
+
+    #[test]
+    fn test_lmove() {
+        let db = new_db();
+        let (src, dst) = ("list".to_string(), "other".to_string());
+        push(&db, src.clone(), elements(&["a", "b", "c"]), false).unwrap();
+
+        // RPOPLPUSH list list rotates
+        lmove(&db, src.clone(), src.clone(), false, true).unwrap();
+        assert_eq!(contents(&db), array(&["c", "a", "b"]));
+
+        assert_eq!(
+            *lmove(&db, src.clone(), dst.clone(), true, false).unwrap(),
+            bulk("c".into())
+        );
+        lmove(&db, src.clone(), dst.clone(), true, true).unwrap();
+        lmove(&db, src.clone(), dst.clone(), true, false).unwrap();
+        assert!(!db.exists(&src).unwrap());
+        assert_eq!(*range(&db, &dst, 0, -1).unwrap(), array(&["a", "c", "b"]));
+        assert_eq!(
+            *lmove(&db, src.clone(), dst.clone(), true, false).unwrap(),
+            RespValue::BulkString(None)
+        );
+
+        db.set(src.clone(), Value::str("value")).unwrap();
+        assert!(lmove(&db, dst.clone(), src.clone(), true, true).is_err());
+        assert_eq!(*len(&db, &dst).unwrap(), RespValue::Integer(3));
+    }
+
+    #[tokio::test]
+    async fn test_blmove() {
+        let db = Arc::new(new_db());
+        let (src, dst) = ("list".to_string(), "other".to_string());
+
+        fn attempt<'a>(
+            db: &'a ListDB<DashMapStorage<String, Value>>,
+            src: &'a str,
+            dst: &'a str,
+        ) -> impl FnMut() -> std::future::Ready<Reply> + 'a {
+            move || std::future::ready(lmove(db, src.to_string(), dst.to_string(), true, true))
+        }
+        let reply = blmove(
+            &db,
+            Some(Duration::from_millis(20)),
+            attempt(&db, &src, &dst),
+        )
+        .await
+        .unwrap();
+        assert_eq!(*reply, RespValue::Array(None));
+
+        let waiter = {
+            let (db, src, dst) = (db.clone(), src.clone(), dst.clone());
+            tokio::spawn(async move { blmove(&db, None, attempt(&db, &src, &dst)).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        push(&db, src.clone(), elements(&["a"]), false).unwrap();
+
+        let reply = waiter.await.unwrap().unwrap();
+        assert_eq!(*reply, bulk("a".into()));
+        assert!(!db.exists(&src).unwrap());
+        assert_eq!(*range(&db, &dst, 0, -1).unwrap(), array(&["a"]));
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let db = new_db();
+        let key = "string".to_string();
+        db.set(key.clone(), Value::str("value")).unwrap();
+        assert!(push(&db, key.clone(), elements(&["a"]), true).is_err());
+        assert!(range(&db, &key, 0, -1).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+// MIGRATE: DUMPs keys locally and RESTOREs them on another instance over a
+// pooled outbound connection. Like Redis, idle connections to a target are
+// kept for a few seconds so migrating many keys one by one stays cheap.
+use crate::db::db::{Expiry, DB};
+use crate::db::dump;
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use crate::protocal::reply;
+use anyhow::{anyhow, Error};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use stream_resp::parser::{ParseError, Parser};
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+type MigrateDB<S> = DB<S, String, Value>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+// Idle connections older than this are dropped instead of reused
+const MIGRATE_SOCKET_TTL: Duration = Duration::from_secs(10);
+
+// Idle connections per target address, with the time they were returned
+type IdleConns = HashMap<String, Vec<(TcpStream, Instant)>>;
+
+static POOL: LazyLock<Mutex<IdleConns>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrateTarget {
+    pub host: String,
+    pub port: u16,
+    pub db: usize,
+    pub timeout: Duration,
+}
+
+impl MigrateTarget {
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn checkout(addr: &str) -> Option<TcpStream> {
+    let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+    let idle = pool.get_mut(addr)?;
+    while let Some((stream, since)) = idle.pop() {
+        if since.elapsed() < MIGRATE_SOCKET_TTL {
+            return Some(stream);
+        }
+    }
+    None
+}
+
+fn checkin(addr: String, stream: TcpStream) {
+    let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+    pool.entry(addr).or_default().push((stream, Instant::now()));
+}
+
+pub(crate) fn encode(out: &mut Vec<u8>, args: &[&str]) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+}
+
+fn io_error(e: impl std::fmt::Display) -> Error {
+    anyhow!(CommandError::MigrateIo(e.to_string()))
+}
+
+// Sends the batch and collects one reply per command
+pub(crate) async fn round_trip(
+    stream: &mut TcpStream,
+    request: &[u8],
+    replies: usize,
+) -> Result<Vec<RespValue<'static>>, Error> {
+    stream.write_all(request).await.map_err(io_error)?;
+    let mut parser = Parser::new(10, usize::MAX);
+    let mut out = Vec::with_capacity(replies);
+    while out.len() < replies {
+        match parser.try_parse() {
+            Ok(Some(reply)) => out.push(reply),
+            Ok(None) | Err(ParseError::UnexpectedEof | ParseError::NotEnoughData) => {
+                if stream
+                    .read_buf(&mut parser.buffer)
+                    .await
+                    .map_err(io_error)?
+                    == 0
+                {
+                    return Err(io_error("connection closed by target"));
+                }
+            }
+            Err(e) => return Err(io_error(format!("{:?}", e))),
+        }
+    }
+    Ok(out)
+}
+
+pub async fn migrate<S>(
+    db: &MigrateDB<S>,
+    target: MigrateTarget,
+    keys: Vec<String>,
+    copy: bool,
+    replace: bool,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    // Payload and remaining TTL of every key that still exists
+    let mut found = vec![];
+    for key in keys {
+        let Some(value) = db.get(&key).map_err(CommandError::StorageError)? else {
+            continue;
+        };
+        let payload = dump::serialize(&value).map_err(CommandError::DumpError)?;
+        let ttl = match db.expiry(&key).map_err(CommandError::StorageError)? {
+            Expiry::At(when) => when.saturating_sub(db.clock().now_ms()).max(1),
+            _ => 0,
+        };
+        found.push((key, dump::to_hex(&payload), ttl));
+    }
+    if found.is_empty() {
+        return Ok(Arc::new(RespValue::SimpleString(Cow::Borrowed("NOKEY"))));
+    }
+
+    let mut request = vec![];
+    let db_index = target.db.to_string();
+    encode(&mut request, &["SELECT", &db_index]);
+    for (key, payload, ttl) in &found {
+        let ttl = ttl.to_string();
+        let mut args = vec!["RESTORE", key, &ttl, payload];
+        if replace {
+            args.push("REPLACE");
+        }
+        encode(&mut request, &args);
+    }
+
+    let addr = target.addr();
+    let replies = timeout(target.timeout, async {
+        let mut stream = match checkout(&addr) {
+            Some(stream) => stream,
+            None => TcpStream::connect(&addr).await.map_err(io_error)?,
+        };
+        let replies = round_trip(&mut stream, &request, found.len() + 1).await?;
+        checkin(addr.clone(), stream);
+        Ok::<_, Error>(replies)
+    })
+    .await
+    .map_err(|_| io_error("timeout"))??;
+
+    let mut migrated = vec![];
+    let mut failure = None;
+    for (reply, (key, _, _)) in replies.iter().skip(1).zip(found) {
+        match reply {
+            RespValue::Error(e) => failure = failure.or_else(|| Some(e.to_string())),
+            _ => migrated.push(key),
+        }
+    }
+    if let RespValue::Error(e) = &replies[0] {
+        failure = Some(e.to_string());
+        migrated.clear();
+    }
+    if !copy && !migrated.is_empty() {
+        db.delete(&migrated).map_err(CommandError::StorageError)?;
+    }
+    match failure {
+        Some(e) => Err(anyhow!(CommandError::MigrateTarget(e))),
+        None => Ok(reply::ok()),
+    }
+}
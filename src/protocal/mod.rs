@@ -1 +1,16 @@
 pub mod command;
+mod geo;
+pub(crate) mod glob;
+mod hash;
+mod index;
+#[cfg(feature = "json")]
+mod json;
+mod list;
+pub mod migrate;
+pub mod reply;
+pub mod request;
+mod set;
+pub mod slot;
+mod sort;
+pub(crate) mod table;
+mod zset;
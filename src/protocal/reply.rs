@@ -0,0 +1,114 @@
+// Interned replies. +OK, +PONG, nil and small integers make up most of the
+// replies a server sends, so they are built once and handed out as Arc
+// clones, and the encoder copies their wire form from static bytes instead of
+// formatting each one again.
+use bytes::BytesMut;
+use std::borrow::Cow;
+use std::sync::{Arc, LazyLock};
+use stream_resp::resp::RespValue;
+
+// Integers in this range are shared, -2 and -1 for the TTL family
+pub const SHARED_INTEGERS_MIN: i64 = -2;
+pub const SHARED_INTEGERS_MAX: i64 = 10_000;
+
+type Reply = Arc<RespValue<'static>>;
+
+static OK: LazyLock<Reply> =
+    LazyLock::new(|| Arc::new(RespValue::SimpleString(Cow::Borrowed("OK"))));
+static PONG: LazyLock<Reply> =
+    LazyLock::new(|| Arc::new(RespValue::SimpleString(Cow::Borrowed("PONG"))));
+static NIL: LazyLock<Reply> = LazyLock::new(|| Arc::new(RespValue::BulkString(None)));
+
+// The shared integers with their encoded frames, indexed from the minimum
+static INTEGERS: LazyLock<Vec<(Reply, Box<[u8]>)>> = LazyLock::new(|| {
+    (SHARED_INTEGERS_MIN..SHARED_INTEGERS_MAX)
+        .map(|n| {
+            let value = RespValue::Integer(n);
+            let frame = value.as_bytes().into_boxed_slice();
+            (Arc::new(value), frame)
+        })
+        .collect()
+});
+
+fn shared_integer(n: i64) -> Option<&'static (Reply, Box<[u8]>)> {
+    if (SHARED_INTEGERS_MIN..SHARED_INTEGERS_MAX).contains(&n) {
+        INTEGERS.get((n - SHARED_INTEGERS_MIN) as usize)
+    } else {
+        None
+    }
+}
+
+pub fn ok() -> Reply {
+    OK.clone()
+}
+
+pub fn pong() -> Reply {
+    PONG.clone()
+}
+
+pub fn nil() -> Reply {
+    NIL.clone()
+}
+
+pub fn integer(n: i64) -> Reply {
+    match shared_integer(n) {
+        Some((reply, _)) => reply.clone(),
+        None => Arc::new(RespValue::Integer(n)),
+    }
+}
+
+// Wraps a computed reply, swapping in the interned copy when there is one
+pub fn intern(value: RespValue<'static>) -> Reply {
+    match value {
+        RespValue::SimpleString(ref s) if s == "OK" => ok(),
+        RespValue::BulkString(None) => nil(),
+        RespValue::Integer(n) => integer(n),
+        value => Arc::new(value),
+    }
+}
+
+// Appends the wire form of a reply, interned ones without formatting
+pub fn encode(value: &RespValue, buf: &mut BytesMut) {
+    match value {
+        RespValue::SimpleString(s) if s == "OK" => buf.extend_from_slice(b"+OK\r\n"),
+        RespValue::SimpleString(s) if s == "PONG" => buf.extend_from_slice(b"+PONG\r\n"),
+        RespValue::BulkString(None) => buf.extend_from_slice(b"$-1\r\n"),
+        RespValue::Integer(n) => match shared_integer(*n) {
+            Some((_, frame)) => buf.extend_from_slice(frame),
+            None => buf.extend_from_slice(&value.as_bytes()),
+        },
+        _ => buf.extend_from_slice(&value.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interned() {
+        assert!(Arc::ptr_eq(&ok(), &ok()));
+        assert!(Arc::ptr_eq(&integer(42), &integer(42)));
+        assert!(Arc::ptr_eq(&integer(-2), &intern(RespValue::Integer(-2))));
+        assert!(!Arc::ptr_eq(
+            &integer(SHARED_INTEGERS_MAX),
+            &integer(SHARED_INTEGERS_MAX)
+        ));
+        assert!(Arc::ptr_eq(&nil(), &intern(RespValue::BulkString(None))));
+
+        // The static frames match what the generic encoder writes
+        for value in [
+            RespValue::SimpleString(Cow::Borrowed("OK")),
+            RespValue::SimpleString(Cow::Borrowed("PONG")),
+            RespValue::BulkString(None),
+            RespValue::Integer(-2),
+            RespValue::Integer(9_999),
+            RespValue::Integer(10_000),
+            RespValue::Integer(-3),
+        ] {
+            let mut buf = BytesMut::new();
+            encode(&value, &mut buf);
+            assert_eq!(&buf[..], &value.as_bytes()[..]);
+        }
+    }
+}
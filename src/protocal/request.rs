@@ -0,0 +1,319 @@
+// Framing check run on the raw bytes of a request before stream_resp parses
+// it. The parser reserves room for a whole array as soon as it reads the
+// header, so `*1000000\r\n` alone would make it allocate; requests are
+// measured here first and refused once they go past the limits.
+use std::fmt;
+use stream_resp::parser::{ParseError, Parser};
+use stream_resp::resp::RespValue;
+
+// Arrays nested deeper than this are refused, same as the parser's limit
+pub const MAX_DEPTH: usize = 10;
+// `*` or `$` and a 64 bit length fit well within this
+const MAX_HEADER_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    // Elements of any array in a request
+    pub max_args: usize,
+    // Bytes of a whole request
+    pub max_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_args: 64 * 1024,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    TooManyArgs,
+    TooBig,
+    Invalid(&'static str),
+    // A type marker where the framing allows none of that kind
+    Unexpected { expected: char, got: char },
+}
+
+// Worded like Redis' protocol errors, which close the connection
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyArgs => write!(f, "Protocol error: invalid multibulk length"),
+            Self::TooBig => write!(f, "Protocol error: too big request"),
+            Self::Invalid(reason) => write!(f, "Protocol error: {}", reason),
+            Self::Unexpected { expected, got } => {
+                write!(f, "Protocol error: expected '{}', got '{}'", expected, got)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map(|at| from + at)
+}
+
+// Length from a `*` or `$` header line starting at pos, with the position
+// after its CRLF. None while the line is incomplete.
+fn header(buf: &[u8], pos: usize) -> Result<Option<(i64, usize)>, RequestError> {
+    let end = match find_crlf(buf, pos + 1) {
+        Some(end) if end - pos <= MAX_HEADER_LEN => end,
+        Some(_) => return Err(RequestError::Invalid("invalid length")),
+        None if buf.len() - pos > MAX_HEADER_LEN => {
+            return Err(RequestError::Invalid("invalid length"))
+        }
+        None => return Ok(None),
+    };
+    std::str::from_utf8(&buf[pos + 1..end])
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .map(|len| Some((len, end + 2)))
+        .ok_or(RequestError::Invalid("invalid length"))
+}
+
+// Where the check of a request that hasn't arrived in full stopped, so the
+// next read carries on from there instead of going over the whole request
+// again. Offsets are from the start of the request. Reset once a request is
+// complete.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Scan {
+    // Start of the first value not seen in full
+    pos: usize,
+    // Elements still to come in each array it is in, outermost first
+    open: Vec<i64>,
+    // Where to look for the CRLF of a simple value that stopped short
+    line_from: usize,
+}
+
+impl Scan {
+    // Length of the request at the start of buf once all of it has arrived.
+    // buf holds what this scan saw before and the bytes that came since.
+    pub fn check(
+        &mut self,
+        buf: &[u8],
+        limits: &RequestLimits,
+    ) -> Result<Option<usize>, RequestError> {
+        match self.scan(buf, limits)? {
+            Some(end) if end > limits.max_bytes => Err(RequestError::TooBig),
+            Some(end) => {
+                *self = Self::default();
+                Ok(Some(end))
+            }
+            None if buf.len() > limits.max_bytes => Err(RequestError::TooBig),
+            None => Ok(None),
+        }
+    }
+
+    // End of the request, None while it is incomplete
+    fn scan(&mut self, buf: &[u8], limits: &RequestLimits) -> Result<Option<usize>, RequestError> {
+        loop {
+            let pos = self.pos;
+            let Some(&marker) = buf.get(pos) else {
+                return Ok(None);
+            };
+            let depth = self.open.len();
+            // A request is always an array, the values in it may be of any type
+            if depth == 0 && marker != b'*' {
+                return Err(RequestError::Unexpected {
+                    expected: '*',
+                    got: marker as char,
+                });
+            }
+            let end = match marker {
+                b'*' => {
+                    if depth >= MAX_DEPTH {
+                        return Err(RequestError::Invalid("too deeply nested request"));
+                    }
+                    let Some((count, end)) = header(buf, pos)? else {
+                        return Ok(None);
+                    };
+                    if count > limits.max_args as i64 {
+                        return Err(RequestError::TooManyArgs);
+                    }
+                    if count > 0 {
+                        self.open.push(count);
+                        self.pos = end;
+                        continue;
+                    }
+                    end
+                }
+                b'$' => {
+                    let Some((len, pos)) = header(buf, pos)? else {
+                        return Ok(None);
+                    };
+                    if len < 0 {
+                        pos
+                    } else {
+                        if len as u64 > limits.max_bytes as u64 {
+                            return Err(RequestError::TooBig);
+                        }
+                        let end = pos + len as usize;
+                        match buf.get(end..end + 2) {
+                            Some(b"\r\n") => end + 2,
+                            Some(_) => return Err(RequestError::Invalid("invalid bulk length")),
+                            None => return Ok(None),
+                        }
+                    }
+                }
+                // The parser reads the first digit of an integer without
+                // checking there is one
+                b':' if buf.get(pos + 1..pos + 3) == Some(b"\r\n") => {
+                    return Err(RequestError::Invalid("invalid integer"))
+                }
+                b'+' | b'-' | b':' => match find_crlf(buf, self.line_from.max(pos + 1)) {
+                    Some(end) => end + 2,
+                    None => {
+                        // A CR at the very end may be half of the CRLF
+                        self.line_from = buf.len().saturating_sub(1).max(pos + 1);
+                        return Ok(None);
+                    }
+                },
+                _ => {
+                    return Err(RequestError::Unexpected {
+                        expected: '$',
+                        got: marker as char,
+                    })
+                }
+            };
+            // The value is complete, and so is every array it completes
+            self.pos = end;
+            self.line_from = 0;
+            loop {
+                let Some(left) = self.open.last_mut() else {
+                    return Ok(Some(end));
+                };
+                *left -= 1;
+                if *left > 0 {
+                    break;
+                }
+                self.open.pop();
+            }
+        }
+    }
+}
+
+// Length of the request at the start of buf once all of it has arrived,
+// for a buffer that is checked once
+pub fn check(buf: &[u8], limits: &RequestLimits) -> Result<Option<usize>, RequestError> {
+    Scan::default().check(buf, limits)
+}
+
+// Parses a request the framing check has seen arrive in full. The parser
+// stops after a fixed number of steps and picks up where it left off on the
+// next call, so long requests take several calls. Since the request is
+// complete, the parser asking for more data is an error like any other.
+pub fn parse(parser: &mut Parser) -> Result<RespValue<'static>, RequestError> {
+    loop {
+        let reason = match parser.try_parse() {
+            Ok(Some(resp)) => return Ok(resp),
+            Err(ParseError::InvalidFormat(reason))
+                if reason == "Maximum parsing iterations exceeded" =>
+            {
+                continue
+            }
+            Err(ParseError::InvalidUtf8) => "invalid UTF-8 in bulk string",
+            Err(ParseError::InvalidLength | ParseError::Overflow) => "invalid bulk length",
+            Err(ParseError::InvalidDepth) => "too deeply nested request",
+            Ok(None) | Err(ParseError::NotEnoughData | ParseError::UnexpectedEof) => {
+                "unexpected end of request"
+            }
+            Err(ParseError::InvalidFormat(_)) => "malformed request",
+        };
+        return Err(RequestError::Invalid(reason));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let limits = RequestLimits {
+            max_args: 3,
+            max_bytes: 64,
+        };
+        let get = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        assert_eq!(check(get, &limits), Ok(Some(get.len())));
+        // Anything after the first request is left for the next call
+        assert_eq!(check(b"*1\r\n$4\r\nPING\r\n*1\r\n", &limits), Ok(Some(14)));
+        for partial in [&b""[..], b"*2", b"*2\r\n$3\r\nGE", b"*2\r\n$3\r\nGET\r\n"] {
+            assert_eq!(check(partial, &limits), Ok(None));
+        }
+
+        // The header alone is enough to refuse a request
+        assert_eq!(
+            check(b"*1000000\r\n", &limits),
+            Err(RequestError::TooManyArgs)
+        );
+        assert_eq!(check(b"*1\r\n$65\r\n", &limits), Err(RequestError::TooBig));
+        let unterminated = [&b"*1\r\n"[..], &[b'+'; 65]].concat();
+        assert_eq!(check(&unterminated, &limits), Err(RequestError::TooBig));
+        assert_eq!(
+            check(b"*1\r\n$2\r\nabc\r\n", &limits),
+            Err(RequestError::Invalid("invalid bulk length"))
+        );
+        assert_eq!(
+            check(b"PING\r\n", &limits),
+            Err(RequestError::Unexpected {
+                expected: '*',
+                got: 'P'
+            })
+        );
+        // Bare values are no requests either, even when complete
+        assert!(check(b"$4\r\nPING\r\n", &limits).is_err());
+        assert_eq!(
+            check(b"*1\r\n?\r\n", &limits),
+            Err(RequestError::Unexpected {
+                expected: '$',
+                got: '?'
+            })
+        );
+        assert_eq!(
+            check(b"*1\r\n:\r\n", &limits),
+            Err(RequestError::Invalid("invalid integer"))
+        );
+        let long_header = [&b"*"[..], &[b'1'; 40]].concat();
+        assert_eq!(
+            check(&long_header, &limits),
+            Err(RequestError::Invalid("invalid length"))
+        );
+    }
+
+    #[test]
+    fn test_scan_resumes() {
+        let limits = RequestLimits::default();
+        let request = b"*3\r\n$3\r\nSET\r\n*2\r\n+ab\r\n:1\r\n$1\r\nv\r\n";
+        let mut scan = Scan::default();
+        for end in 0..request.len() {
+            assert_eq!(scan.check(&request[..end], &limits), Ok(None));
+        }
+        assert_eq!(scan.check(request, &limits), Ok(Some(request.len())));
+        assert_eq!(scan, Scan::default());
+
+        // What was seen in full isn't looked at again
+        let mut scan = Scan::default();
+        let head = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nva";
+        assert_eq!(scan.check(head, &limits), Ok(None));
+        assert_eq!((scan.pos, &scan.open[..]), (20, &[1][..]));
+        let mut garbled = head.to_vec();
+        garbled[4] = b'?';
+        garbled.extend_from_slice(b"lue\r\n");
+        assert_eq!(scan.check(&garbled, &limits), Ok(Some(garbled.len())));
+
+        // Nor is the start of a long simple value
+        let mut scan = Scan::default();
+        let line = [&b"*1\r\n+"[..], &[b'x'; 100], b"\r"].concat();
+        assert_eq!(scan.check(&line, &limits), Ok(None));
+        assert_eq!(scan.line_from, line.len() - 1);
+        let line = [&line[..], b"\n"].concat();
+        assert_eq!(scan.check(&line, &limits), Ok(Some(line.len())));
+    }
+}
@@ -0,0 +1,521 @@
+// Set commands. A set is stored as a Value::Set, a hash set of its members
+// that can also be indexed for sampling, or packed while small (see
+// listpack), which SADD and SREM edit in place; an empty set is never
+// stored, the key is removed instead.
+use crate::db::db::DB;
+use crate::db::listpack;
+use crate::db::storage::{Storage, Update};
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use crate::protocal::command::{CommandError, SetOp};
+use crate::protocal::reply;
+use crate::server::timeout::Budget;
+use anyhow::Error;
+use indexmap::IndexSet;
+use rand::seq::index::sample;
+use rand::Rng;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+type SetDB<S> = DB<S, String, Value>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+fn array(items: Vec<String>) -> RespValue<'static> {
+    RespValue::Array(Some(items.into_iter().map(bulk).collect()))
+}
+
+// Members of a stored value, None for a missing key
+fn members(value: &Option<Arc<Value>>) -> Result<Option<&IndexSet<String>>, CommandError> {
+    match value.as_deref() {
+        None => Ok(None),
+        Some(Value::Set(items)) => Ok(Some(items)),
+        Some(_) => Err(CommandError::WrongType),
+    }
+}
+
+pub fn add<S>(db: &SetDB<S>, key: String, new_members: Vec<String>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let added = db
+        .update_typed(key, ValueType::Set, |value| {
+            listpack::edit_in_place(value, &limits, |value| {
+                let added = match value {
+                    None => {
+                        let fresh: IndexSet<String> = new_members.into_iter().collect();
+                        let added = fresh.len() as i64;
+                        return (Update::Set(Value::Set(fresh)), Ok(added));
+                    }
+                    Some(Value::Set(items)) => new_members
+                        .into_iter()
+                        .filter(|member| items.insert(member.clone()))
+                        .count(),
+                    Some(Value::Listpack(packed)) => new_members
+                        .iter()
+                        .filter(|member| packed.insert(member))
+                        .count(),
+                    Some(_) => return (Update::Keep, Err(CommandError::WrongType)),
+                };
+                (Update::Keep, Ok(added as i64))
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::integer(added))
+}
+
+pub fn rem<S>(db: &SetDB<S>, key: String, old_members: Vec<String>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let removed = db
+        .update_typed(key, ValueType::Set, |value| {
+            listpack::edit_in_place(value, &limits, |value| {
+                let (removed, empty) = match value {
+                    None => return (Update::Keep, Ok(0)),
+                    Some(Value::Set(items)) => {
+                        let removed = old_members
+                            .iter()
+                            .filter(|member| items.swap_remove(*member))
+                            .count();
+                        (removed, items.is_empty())
+                    }
+                    Some(Value::Listpack(packed)) => {
+                        let removed = old_members
+                            .iter()
+                            .filter(|member| packed.remove(member))
+                            .count();
+                        (removed, packed.is_empty())
+                    }
+                    Some(_) => return (Update::Keep, Err(CommandError::WrongType)),
+                };
+                if empty {
+                    (Update::Delete, Ok(removed as i64))
+                } else {
+                    (Update::Keep, Ok(removed as i64))
+                }
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::integer(removed))
+}
+
+pub fn all<S>(db: &SetDB<S>, key: &String) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let value = listpack::expanded(
+        db.get_typed(key, ValueType::Set)
+            .map_err(CommandError::from_storage)?,
+    );
+    let items = members(&value)?
+        .map(|items| items.iter().cloned().collect())
+        .unwrap_or_default();
+    Ok(Arc::new(array(items)))
+}
+
+// SPOP: removes up to count random members. Without count the reply is a
+// single member (or nil), with count an array.
+pub fn pop<S>(db: &SetDB<S>, key: String, count: Option<usize>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let popped = db
+        .update_typed(key, ValueType::Set, |value| {
+            listpack::edit(value, &limits, |value| match value {
+                None => (Update::Keep, Ok(vec![])),
+                Some(Value::Set(items)) => {
+                    let n = count.unwrap_or(1).min(items.len());
+                    let mut picked = sample(&mut rand::thread_rng(), items.len(), n).into_vec();
+                    // Remove from the back so earlier indexes stay valid
+                    picked.sort_unstable_by(|a, b| b.cmp(a));
+                    let popped = picked
+                        .into_iter()
+                        .filter_map(|i| items.swap_remove_index(i))
+                        .collect();
+                    if items.is_empty() {
+                        (Update::Delete, Ok(popped))
+                    } else {
+                        (Update::Keep, Ok(popped))
+                    }
+                }
+                Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+            })
+        })
+        .map_err(CommandError::from_storage)??;
+    let reply = match count {
+        Some(_) => array(popped),
+        None => popped
+            .into_iter()
+            .next()
+            .map_or(RespValue::BulkString(None), bulk),
+    };
+    Ok(reply::intern(reply))
+}
+
+// SRANDMEMBER: like SPOP without removing. A negative count may repeat
+// members and always returns |count| of them.
+pub fn rand_member<S>(db: &SetDB<S>, key: &String, count: Option<i64>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let value = listpack::expanded(
+        db.get_typed(key, ValueType::Set)
+            .map_err(CommandError::from_storage)?,
+    );
+    let empty = IndexSet::new();
+    let items = members(&value)?.unwrap_or(&empty);
+    let mut rng = rand::thread_rng();
+    let reply = match count {
+        None if items.is_empty() => RespValue::BulkString(None),
+        None => bulk(items[rng.gen_range(0..items.len())].clone()),
+        Some(_) if items.is_empty() => RespValue::Array(Some(vec![])),
+        Some(count) if count >= 0 => {
+            let n = (count as usize).min(items.len());
+            let picked = sample(&mut rng, items.len(), n);
+            array(picked.into_iter().map(|i| items[i].clone()).collect())
+        }
+        Some(count) => array(
+            (0..count.unsigned_abs())
+                .map(|_| items[rng.gen_range(0..items.len())].clone())
+                .collect(),
+        ),
+    };
+    Ok(reply::intern(reply))
+}
+
+// Members found in every one of sets, at most limit of them (0 for no
+// limit). Walks the smallest set and probes the others.
+fn intersect(
+    sets: Vec<Option<&IndexSet<String>>>,
+    limit: usize,
+    budget: Budget,
+) -> Result<Vec<&String>, CommandError> {
+    // Any missing key makes the intersection empty
+    let mut sets: Vec<&IndexSet<String>> = match sets.into_iter().collect() {
+        Some(sets) => sets,
+        None => return Ok(vec![]),
+    };
+    sets.sort_by_key(|items| items.len());
+    let (smallest, others) = match sets.split_first() {
+        Some(split) => split,
+        None => return Ok(vec![]),
+    };
+    let mut result = vec![];
+    for (step, item) in smallest.iter().enumerate() {
+        budget.check(step)?;
+        if others.iter().all(|set| set.contains(item)) {
+            result.push(item);
+            if result.len() == limit {
+                break;
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Computes op over the given sets. Union hashes every member once, difference
+// walks the first set and probes the others. Each walk stops once the
+// budget is spent.
+fn combine(
+    op: SetOp,
+    values: &[Option<Arc<Value>>],
+    budget: Budget,
+) -> Result<Vec<String>, CommandError> {
+    let sets = values.iter().map(members).collect::<Result<Vec<_>, _>>()?;
+
+    match op {
+        SetOp::Inter => Ok(intersect(sets, 0, budget)?.into_iter().cloned().collect()),
+        SetOp::Union => {
+            let mut seen = HashSet::new();
+            let mut result = vec![];
+            for (step, item) in sets.into_iter().flatten().flatten().enumerate() {
+                budget.check(step)?;
+                if seen.insert(item.as_str()) {
+                    result.push(item.clone());
+                }
+            }
+            Ok(result)
+        }
+        SetOp::Diff => {
+            let (first, others) = match sets.split_first() {
+                Some((Some(first), others)) => (*first, others),
+                _ => return Ok(vec![]),
+            };
+            let mut result = vec![];
+            for (step, item) in first.iter().enumerate() {
+                budget.check(step)?;
+                if !others.iter().flatten().any(|set| set.contains(item)) {
+                    result.push(item.clone());
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
+// SINTER/SUNION/SDIFF
+pub fn algebra<S>(db: &SetDB<S>, op: SetOp, keys: &[String], budget: Budget) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let values: Vec<_> = db
+        .get_many(keys)
+        .map_err(CommandError::StorageError)?
+        .into_iter()
+        .map(listpack::expanded)
+        .collect();
+    let result = combine(op, &values, budget)?;
+    Ok(Arc::new(array(result)))
+}
+
+// SINTERCARD: size of the intersection, counting stops at limit
+pub fn inter_card<S>(db: &SetDB<S>, keys: &[String], limit: usize, budget: Budget) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let values: Vec<_> = db
+        .get_many(keys)
+        .map_err(CommandError::StorageError)?
+        .into_iter()
+        .map(listpack::expanded)
+        .collect();
+    let sets = values.iter().map(members).collect::<Result<Vec<_>, _>>()?;
+    let found = intersect(sets, limit, budget)?;
+    Ok(reply::integer(found.len() as i64))
+}
+
+// SINTERSTORE/SUNIONSTORE/SDIFFSTORE: replaces destination with the result
+// (deleting it when empty) and replies with its cardinality
+pub fn algebra_store<S>(
+    db: &SetDB<S>,
+    op: SetOp,
+    destination: String,
+    keys: &[String],
+    budget: Budget,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let len = db
+        .store_from(keys, destination, |values| {
+            let values: Vec<_> = values.into_iter().map(listpack::expanded).collect();
+            match combine(op, &values, budget) {
+                Ok(result) if result.is_empty() => (Update::Delete, Ok(0)),
+                Ok(result) => {
+                    let len = result.len() as i64;
+                    let result = Value::Set(result.into_iter().collect());
+                    let result = listpack::compact(&result, &limits).unwrap_or(result);
+                    (Update::Set(result), Ok(len))
+                }
+                Err(e) => (Update::Keep, Err(e)),
+            }
+        })
+        .map_err(CommandError::StorageError)??;
+    Ok(reply::integer(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    fn new_db() -> SetDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn sorted(reply: &RespValue) -> Vec<String> {
+        let mut items: Vec<String> = match reply {
+            RespValue::Array(Some(items)) | RespValue::Set(Some(items)) => items
+                .iter()
+                .map(|item| match item {
+                    RespValue::BulkString(Some(s)) => s.to_string(),
+                    other => panic!("unexpected member {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn test_add_rem() {
+        let db = new_db();
+        let key = "set".to_string();
+        let reply = add(&db, key.clone(), strings(&["a", "b", "a"])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(2));
+        let reply = add(&db, key.clone(), strings(&["b", "c"])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(1));
+        assert_eq!(sorted(&all(&db, &key).unwrap()), strings(&["a", "b", "c"]));
+
+        let reply = rem(&db, key.clone(), strings(&["a", "b", "c", "x"])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(3));
+        assert!(!db.exists(&key).unwrap());
+    }
+
+    #[test]
+    fn test_pop() {
+        let db = new_db();
+        let key = "set".to_string();
+        add(&db, key.clone(), strings(&["a", "b", "c", "d"])).unwrap();
+
+        let reply = pop(&db, key.clone(), None).unwrap();
+        let first = match &*reply {
+            RespValue::BulkString(Some(s)) => s.to_string(),
+            other => panic!("unexpected reply {:?}", other),
+        };
+        let mut popped = sorted(&pop(&db, key.clone(), Some(10)).unwrap());
+        assert_eq!(popped.len(), 3);
+        popped.push(first);
+        popped.sort();
+        assert_eq!(popped, strings(&["a", "b", "c", "d"]));
+        assert!(!db.exists(&key).unwrap());
+
+        assert_eq!(
+            *pop(&db, key.clone(), None).unwrap(),
+            RespValue::BulkString(None)
+        );
+        assert_eq!(
+            *pop(&db, key, Some(2)).unwrap(),
+            RespValue::Array(Some(vec![]))
+        );
+    }
+
+    #[test]
+    fn test_rand_member() {
+        let db = new_db();
+        let key = "set".to_string();
+        add(&db, key.clone(), strings(&["a", "b", "c"])).unwrap();
+
+        assert_eq!(
+            sorted(&rand_member(&db, &key, Some(5)).unwrap()),
+            strings(&["a", "b", "c"])
+        );
+        assert_eq!(sorted(&rand_member(&db, &key, Some(2)).unwrap()).len(), 2);
+        let repeated = sorted(&rand_member(&db, &key, Some(-7)).unwrap());
+        assert_eq!(repeated.len(), 7);
+        assert!(repeated
+            .iter()
+            .all(|m| ["a", "b", "c"].contains(&m.as_str())));
+        // Sampling leaves the set alone
+        assert_eq!(sorted(&all(&db, &key).unwrap()).len(), 3);
+
+        let missing = "missing".to_string();
+        assert_eq!(
+            *rand_member(&db, &missing, None).unwrap(),
+            RespValue::BulkString(None)
+        );
+        assert_eq!(
+            *rand_member(&db, &missing, Some(-3)).unwrap(),
+            RespValue::Array(Some(vec![]))
+        );
+    }
+
+    #[test]
+    fn test_algebra() {
+        let db = new_db();
+        let budget = Budget::unlimited();
+        add(&db, "s1".into(), strings(&["a", "b", "c", "d"])).unwrap();
+        add(&db, "s2".into(), strings(&["c", "d", "e"])).unwrap();
+        add(&db, "s3".into(), strings(&["d", "f"])).unwrap();
+        let keys = strings(&["s1", "s2", "s3"]);
+
+        let reply = algebra(&db, SetOp::Inter, &keys, budget).unwrap();
+        assert_eq!(sorted(&reply), strings(&["d"]));
+        let reply = algebra(&db, SetOp::Union, &keys, budget).unwrap();
+        assert_eq!(sorted(&reply), strings(&["a", "b", "c", "d", "e", "f"]));
+        let reply = algebra(&db, SetOp::Diff, &keys, budget).unwrap();
+        assert_eq!(sorted(&reply), strings(&["a", "b"]));
+
+        let reply = algebra(&db, SetOp::Inter, &strings(&["s1", "missing"]), budget).unwrap();
+        assert_eq!(*reply, RespValue::Array(Some(vec![])));
+        let reply = algebra(&db, SetOp::Diff, &strings(&["missing", "s1"]), budget).unwrap();
+        assert_eq!(*reply, RespValue::Array(Some(vec![])));
+
+        db.set("str".into(), Value::str("value")).unwrap();
+        assert!(algebra(&db, SetOp::Union, &strings(&["s1", "str"]), budget).is_err());
+
+        // A spent budget stops the walk
+        let spent = Budget::until(std::time::Instant::now());
+        let error = algebra(&db, SetOp::Union, &keys, spent).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn test_inter_card() {
+        let db = new_db();
+        let budget = Budget::unlimited();
+        add(&db, "s1".into(), strings(&["a", "b", "c", "d"])).unwrap();
+        add(&db, "s2".into(), strings(&["b", "c", "d", "e"])).unwrap();
+        let keys = strings(&["s1", "s2"]);
+
+        let card =
+            |keys: &[String], limit| (*inter_card(&db, keys, limit, budget).unwrap()).clone();
+        assert_eq!(card(&keys, 0), RespValue::Integer(3));
+        assert_eq!(card(&keys, 2), RespValue::Integer(2));
+        assert_eq!(card(&keys, 10), RespValue::Integer(3));
+        assert_eq!(card(&strings(&["s1", "missing"]), 0), RespValue::Integer(0));
+    }
+
+    #[test]
+    fn test_algebra_store() {
+        let db = new_db();
+        let budget = Budget::unlimited();
+        add(&db, "s1".into(), strings(&["a", "b", "c"])).unwrap();
+        add(&db, "s2".into(), strings(&["b", "c", "d"])).unwrap();
+
+        let reply = algebra_store(
+            &db,
+            SetOp::Inter,
+            "dst".into(),
+            &strings(&["s1", "s2"]),
+            budget,
+        )
+        .unwrap();
+        assert_eq!(*reply, RespValue::Integer(2));
+        assert_eq!(
+            sorted(&all(&db, &"dst".to_string()).unwrap()),
+            strings(&["b", "c"])
+        );
+
+        // The destination may also be one of the sources
+        let reply = algebra_store(
+            &db,
+            SetOp::Union,
+            "s1".into(),
+            &strings(&["s1", "s2"]),
+            budget,
+        )
+        .unwrap();
+        assert_eq!(*reply, RespValue::Integer(4));
+
+        // An empty result removes the destination
+        let reply = algebra_store(
+            &db,
+            SetOp::Diff,
+            "dst".into(),
+            &strings(&["s2", "s1"]),
+            budget,
+        )
+        .unwrap();
+        assert_eq!(*reply, RespValue::Integer(0));
+        assert!(!db.exists(&"dst".to_string()).unwrap());
+    }
+}
@@ -0,0 +1,55 @@
+// Cluster hash slots. Keys hash to one of SLOT_COUNT slots; CLUSTER KEYSLOT,
+// the cluster client and the shard workers all go by key_slot.
+pub const SLOT_COUNT: usize = 16384;
+
+// CRC16-XMODEM, the checksum keys are hashed with
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in bytes {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Part of the key that is hashed. When the key has a non-empty {tag} only the
+// tag is, so {user1}.name and {user1}.age land in the same slot.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    key.iter()
+        .position(|&b| b == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            let close = tag.iter().position(|&b| b == b'}')?;
+            (close > 0).then(|| &tag[..close])
+        })
+        .unwrap_or(key)
+}
+
+// Hash slot of a key
+pub fn key_slot(key: &str) -> u16 {
+    crc16(hash_tag(key.as_bytes())) % SLOT_COUNT as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot(""), 0);
+        // Only the tag is hashed, empty tags are ignored
+        assert_eq!(key_slot("{user1}.name"), key_slot("user1"));
+        assert_eq!(key_slot("{user1}.name"), key_slot("{user1}.age"));
+        assert_eq!(key_slot("{}foo"), crc16(b"{}foo") % SLOT_COUNT as u16);
+        assert_eq!(key_slot("foo{bar"), crc16(b"foo{bar") % SLOT_COUNT as u16);
+        assert_eq!(hash_tag(b"a{b}{c}"), b"b");
+    }
+}
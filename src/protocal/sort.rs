@@ -0,0 +1,332 @@
+// SORT and SORT_RO over lists and sets. BY and GET patterns name other keys
+// by putting the element in place of the first `*`, and `key->field` reads a
+// field of the hash there. Those keys are only known while sorting, so the
+// command runs with the db held (see DB::read_with) and sees the elements
+// and the keys they name as of one moment.
+use crate::db::bytestring;
+use crate::db::db::DB;
+use crate::db::listpack;
+use crate::db::storage::{Storage, Update};
+use crate::db::value::Value;
+use crate::protocal::command::{CommandError, SortOptions};
+use crate::protocal::reply;
+use crate::server::timeout::Budget;
+use anyhow::Error;
+use std::borrow::Cow;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+type SortDB<S> = DB<S, String, Value>;
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+type Lookup<'a> = &'a dyn Fn(&String) -> Result<Option<Arc<Value>>, Error>;
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+fn fetch(get: Lookup, key: &String) -> Result<Option<Arc<Value>>, CommandError> {
+    get(key)
+        .map(listpack::expanded)
+        .map(bytestring::expanded)
+        .map_err(CommandError::StorageError)
+}
+
+// Elements of the list or set at key, none when it's missing
+fn elements(get: Lookup, key: &String) -> Result<Vec<String>, CommandError> {
+    match fetch(get, key)?.as_deref() {
+        None => Ok(vec![]),
+        Some(Value::List(items)) => Ok(items.iter().cloned().collect()),
+        Some(Value::Set(items)) => Ok(items.iter().cloned().collect()),
+        Some(_) => Err(CommandError::WrongType),
+    }
+}
+
+// What a BY or GET pattern names for element: `#` is the element itself.
+// None when the pattern has no `*`, or the key or field is missing or of
+// another type.
+fn lookup(get: Lookup, pattern: &str, element: &str) -> Result<Option<String>, CommandError> {
+    if pattern == "#" {
+        return Ok(Some(element.to_string()));
+    }
+    let Some(star) = pattern.find('*') else {
+        return Ok(None);
+    };
+    let (key, field) = match pattern[star..].find("->") {
+        Some(arrow) if star + arrow + 2 < pattern.len() => {
+            (&pattern[..star + arrow], Some(&pattern[star + arrow + 2..]))
+        }
+        _ => (pattern, None),
+    };
+    let key = key.replacen('*', element, 1);
+    Ok(match (fetch(get, &key)?.as_deref(), field) {
+        (Some(Value::Str(s)), None) => Some(String::from_utf8_lossy(s).into_owned()),
+        (Some(Value::Hash(pairs)), Some(field)) => pairs.get(field).cloned(),
+        _ => None,
+    })
+}
+
+// The reply of SORT: the selected elements in order, or per element what
+// each GET pattern names. Lookups stop once the budget is spent.
+fn sorted(
+    get: Lookup,
+    key: &String,
+    options: &SortOptions,
+    budget: Budget,
+) -> Result<Vec<Option<String>>, CommandError> {
+    let elements = elements(get, key)?;
+    let mut order: Vec<usize> = (0..elements.len()).collect();
+    // A BY pattern without `*` leaves the elements in stored order
+    if options.by.as_deref().is_none_or(|by| by.contains('*')) {
+        let weights = elements
+            .iter()
+            .enumerate()
+            .map(|(step, element)| {
+                budget.check(step)?;
+                match &options.by {
+                    Some(by) => lookup(get, by, element),
+                    None => Ok(Some(element.clone())),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Equal weights fall back to comparing the elements
+        if options.alpha {
+            order.sort_by(|&a, &b| {
+                weights[a]
+                    .cmp(&weights[b])
+                    .then_with(|| elements[a].cmp(&elements[b]))
+            });
+        } else {
+            // Missing weights count as 0
+            let scores = weights
+                .iter()
+                .map(|weight| match weight {
+                    None => Ok(0.0),
+                    Some(weight) => weight
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|score| !score.is_nan())
+                        .ok_or(CommandError::SortNotDouble),
+                })
+                .collect::<Result<Vec<f64>, _>>()?;
+            order.sort_by(|&a, &b| {
+                scores[a]
+                    .total_cmp(&scores[b])
+                    .then_with(|| elements[a].cmp(&elements[b]))
+            });
+        }
+        if options.desc {
+            order.reverse();
+        }
+    }
+
+    // LIMIT offset count, a negative count takes the rest
+    let (offset, count) = options.limit.unwrap_or((0, -1));
+    let start = (offset.max(0) as usize).min(order.len());
+    let end = match usize::try_from(count) {
+        Ok(count) => start.saturating_add(count).min(order.len()),
+        Err(_) => order.len(),
+    };
+    let mut result = vec![];
+    for (step, &i) in order[start..end].iter().enumerate() {
+        budget.check(step)?;
+        if options.get.is_empty() {
+            result.push(Some(elements[i].clone()));
+        }
+        for pattern in &options.get {
+            result.push(lookup(get, pattern, &elements[i])?);
+        }
+    }
+    Ok(result)
+}
+
+pub fn sort<S>(db: &SortDB<S>, key: &String, options: &SortOptions, budget: Budget) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let result = db
+        .read_with(|get| Ok(sorted(get, key, options, budget)))
+        .map_err(CommandError::StorageError)??;
+    Ok(Arc::new(RespValue::Array(Some(
+        result
+            .into_iter()
+            .map(|item| item.map_or(RespValue::BulkString(None), bulk))
+            .collect(),
+    ))))
+}
+
+// SORT ... STORE: replaces destination with the result as a list (deleting
+// it when empty, missing lookups stored as empty strings) and replies with
+// its length
+pub fn sort_store<S>(
+    db: &SortDB<S>,
+    key: &String,
+    options: &SortOptions,
+    destination: String,
+    budget: Budget,
+) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    let limits = db.listpack_limits();
+    let len = db
+        .store_with(destination, |get| {
+            Ok(match sorted(get, key, options, budget) {
+                Ok(result) if result.is_empty() => (Update::Delete, Ok(0)),
+                Ok(result) => {
+                    let len = result.len() as i64;
+                    let items = result
+                        .into_iter()
+                        .map(|item| item.unwrap_or_default())
+                        .collect();
+                    let list = Value::List(items);
+                    let list = listpack::compact(&list, &limits).unwrap_or(list);
+                    (Update::Set(list), Ok(len))
+                }
+                Err(e) => (Update::Keep, Err(e)),
+            })
+        })
+        .map_err(CommandError::StorageError)??;
+    Ok(reply::integer(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+    use crate::protocal::{hash, list, set};
+
+    fn new_db() -> SortDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn items(reply: &RespValue) -> Vec<Option<String>> {
+        match reply {
+            RespValue::Array(Some(items)) => items
+                .iter()
+                .map(|item| match item {
+                    RespValue::BulkString(Some(s)) => Some(s.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    fn some(values: &[&str]) -> Vec<Option<String>> {
+        values.iter().map(|v| Some(v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        let db = new_db();
+        let budget = Budget::unlimited();
+        let key = "list".to_string();
+        list::push(&db, key.clone(), strings(&["3", "1", "10", "2"]), false).unwrap();
+        let by_value = |options: SortOptions| items(&sort(&db, &key, &options, budget).unwrap());
+
+        assert_eq!(
+            by_value(SortOptions::default()),
+            some(&["1", "2", "3", "10"])
+        );
+        let alpha = SortOptions {
+            alpha: true,
+            ..Default::default()
+        };
+        assert_eq!(by_value(alpha), some(&["1", "10", "2", "3"]));
+        let page = SortOptions {
+            desc: true,
+            limit: Some((1, 2)),
+            ..Default::default()
+        };
+        assert_eq!(by_value(page), some(&["3", "2"]));
+        let rest = SortOptions {
+            limit: Some((2, -1)),
+            ..Default::default()
+        };
+        assert_eq!(by_value(rest), some(&["3", "10"]));
+
+        // Not numbers without ALPHA
+        list::push(&db, key.clone(), strings(&["x"]), false).unwrap();
+        let error = sort(&db, &key, &SortOptions::default(), budget).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::SortNotDouble)
+        ));
+        assert_eq!(
+            items(&sort(&db, &"missing".to_string(), &SortOptions::default(), budget).unwrap()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_sort_by_and_get() {
+        let db = new_db();
+        let budget = Budget::unlimited();
+        let key = "ids".to_string();
+        set::add(&db, key.clone(), strings(&["1", "2", "3"])).unwrap();
+        for (id, weight, name) in [("1", "30", "ann"), ("2", "10", "bob"), ("3", "20", "cy")] {
+            db.set(format!("weight_{}", id), Value::str(weight))
+                .unwrap();
+            hash::set(
+                &db,
+                format!("user_{}", id),
+                vec![("name".to_string(), name.to_string())],
+            )
+            .unwrap();
+        }
+        db.delete(&["weight_3".to_string()]).unwrap();
+
+        let options = SortOptions {
+            by: Some("weight_*".to_string()),
+            get: strings(&["#", "user_*->name", "nosuch_*"]),
+            ..Default::default()
+        };
+        let mut expected = vec![];
+        for (id, name) in [("3", "cy"), ("2", "bob"), ("1", "ann")] {
+            expected.extend([Some(id.to_string()), Some(name.to_string()), None]);
+        }
+        assert_eq!(items(&sort(&db, &key, &options, budget).unwrap()), expected);
+
+        // BY without `*` skips sorting
+        let nosort = SortOptions {
+            by: Some("nosort".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            items(&sort(&db, &key, &nosort, budget).unwrap()),
+            some(&["1", "2", "3"])
+        );
+
+        let stored = sort_store(&db, &key, &options, "out".to_string(), budget).unwrap();
+        assert_eq!(*stored, RespValue::Integer(9));
+        let out = list::range(&db, &"out".to_string(), 0, -1).unwrap();
+        assert_eq!(items(&out)[..3], some(&["3", "cy", ""]));
+        let empty = sort_store(
+            &db,
+            &"missing".to_string(),
+            &options,
+            "out".to_string(),
+            budget,
+        );
+        assert_eq!(*empty.unwrap(), RespValue::Integer(0));
+        assert!(db.get(&"out".to_string()).unwrap().is_none());
+
+        let error = sort(&db, &"weight_1".to_string(), &options, budget).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::WrongType)
+        ));
+
+        // Past the budget the lookups stop
+        let spent = Budget::until(std::time::Instant::now());
+        let error = sort(&db, &key, &options, spent).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::Timeout)
+        ));
+    }
+}
@@ -0,0 +1,404 @@
+// Static command table behind COMMAND, COMMAND INFO/COUNT/DOCS. Its entries
+// are #[command] attributes on the variants of Command (see the macros
+// crate), written with the flag and key shorthands below.
+use crate::protocal::command::Command;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use stream_resp::resp::RespValue;
+
+// Set of command flags. COMMAND INFO lists them, and the server goes by them
+// to refuse commands while loading or on a read only replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandFlags(u16);
+
+impl CommandFlags {
+    pub const NONE: Self = Self(0);
+    pub const WRITE: Self = Self(1);
+    pub const READONLY: Self = Self(1 << 1);
+    // Refused once maxmemory is reached
+    pub const DENYOOM: Self = Self(1 << 2);
+    pub const ADMIN: Self = Self(1 << 3);
+    pub const PUBSUB: Self = Self(1 << 4);
+    pub const NOSCRIPT: Self = Self(1 << 5);
+    pub const BLOCKING: Self = Self(1 << 6);
+    // Served while the dataset is loading
+    pub const LOADING: Self = Self(1 << 7);
+    // Served by a replica that lost its master
+    pub const STALE: Self = Self(1 << 8);
+    pub const FAST: Self = Self(1 << 9);
+
+    // In the order Redis lists them
+    const NAMES: &[(Self, &'static str)] = &[
+        (Self::WRITE, "write"),
+        (Self::READONLY, "readonly"),
+        (Self::DENYOOM, "denyoom"),
+        (Self::ADMIN, "admin"),
+        (Self::PUBSUB, "pubsub"),
+        (Self::NOSCRIPT, "noscript"),
+        (Self::BLOCKING, "blocking"),
+        (Self::LOADING, "loading"),
+        (Self::STALE, "stale"),
+        (Self::FAST, "fast"),
+    ];
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .iter()
+            .filter(move |(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+    }
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    // Argument count including the name, negative means "at least"
+    pub arity: i64,
+    pub flags: CommandFlags,
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub summary: &'static str,
+}
+
+pub(crate) use CommandFlags as F;
+
+pub(crate) const READ: F = F::READONLY;
+pub(crate) const READ_FAST: F = F::READONLY.union(F::FAST);
+pub(crate) const WRITE: F = F::WRITE;
+pub(crate) const WRITE_FAST: F = F::WRITE.union(F::FAST);
+pub(crate) const WRITE_OOM: F = F::WRITE.union(F::DENYOOM);
+pub(crate) const WRITE_OOM_FAST: F = WRITE_OOM.union(F::FAST);
+pub(crate) const CONN: F = F::NOSCRIPT.union(F::LOADING).union(F::STALE).union(F::FAST);
+pub(crate) const ADMIN: F = F::LOADING.union(F::STALE);
+pub(crate) const SUBSCRIBE: F = F::PUBSUB
+    .union(F::NOSCRIPT)
+    .union(F::LOADING)
+    .union(F::STALE);
+// Changes the server: CONFIG SET, DEBUG, REPLICAOF
+pub(crate) const DANGER: F = F::ADMIN.union(F::NOSCRIPT).union(F::STALE);
+
+pub(crate) const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: CommandFlags,
+    keys: (i64, i64, i64),
+    group: &'static str,
+    summary: &'static str,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        step: keys.2,
+        group,
+        summary,
+    }
+}
+
+pub(crate) const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+pub(crate) const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+pub(crate) const TWO_KEYS: (i64, i64, i64) = (1, 2, 1);
+pub(crate) const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+// Built from the #[command] attributes of Command, in variant order
+pub const COMMANDS: &[CommandSpec] = Command::TABLE;
+
+// Subcommand of a container command, the HELP replies are made of these
+pub struct SubcommandSpec {
+    pub command: &'static str,
+    pub name: &'static str,
+    // Usage after the subcommand name, as HELP shows it
+    pub args: &'static str,
+    pub summary: &'static str,
+}
+
+const fn sub(
+    command: &'static str,
+    name: &'static str,
+    args: &'static str,
+    summary: &'static str,
+) -> SubcommandSpec {
+    SubcommandSpec {
+        command,
+        name,
+        args,
+        summary,
+    }
+}
+
+// Every container also answers HELP, which is not listed
+#[rustfmt::skip]
+pub const SUBCOMMANDS: &[SubcommandSpec] = &[
+    sub("object", "encoding", "<key>", "Returns the internal encoding of the value stored at a key."),
+    sub("object", "freq", "<key>", "Returns the logarithmic access frequency counter of a key."),
+    sub("object", "idletime", "<key>", "Returns the seconds since a key was last accessed."),
+    sub("cluster", "keyslot", "<key>", "Returns the hash slot of a key."),
+    sub("client", "id", "", "Returns the unique id of the connection."),
+    sub("client", "kill", "ID <client-id>", "Closes the connection with the given id."),
+    sub("client", "tracking", "(ON|OFF) [REDIRECT <id>] [BCAST] [PREFIX <prefix> ...]", "Controls server-assisted client side caching for the connection."),
+    sub("config", "get", "<pattern> [<pattern> ...]", "Returns the values of the configuration parameters matching the patterns."),
+    sub("config", "set", "<parameter> <value>", "Sets a configuration parameter."),
+    sub("config", "resetstat", "", "Resets the per-command statistics."),
+    sub("latency", "latest", "", "Returns the latest latency samples of every event."),
+    sub("latency", "history", "<event>", "Returns the latency samples of an event."),
+    sub("latency", "reset", "[<event> ...]", "Resets the latency data of some or all events."),
+    sub("latency", "histogram", "[<command> ...]", "Returns the latency histograms of some or all commands."),
+    sub("debug", "reload", "", "Saves and reloads the dataset."),
+    sub("debug", "change-repl-id", "", "Changes the replication id of the server."),
+    sub("debug", "set-active-expire", "(0|1)", "Turns the active expiry of keys off or on."),
+    sub("debug", "stringmatch-len", "", "Runs a fuzz test of the glob-style pattern matcher."),
+    #[cfg(feature = "debug-clock")]
+    sub("debug", "freeze-time", "", "Stops the server clock."),
+    #[cfg(feature = "debug-clock")]
+    sub("debug", "resume-time", "", "Lets a frozen server clock run again."),
+    #[cfg(feature = "debug-clock")]
+    sub("debug", "advance-time", "<milliseconds>", "Moves the server clock forward."),
+    sub("command", "count", "", "Returns a count of commands."),
+    sub("command", "info", "[<command-name> ...]", "Returns information about commands."),
+    sub("command", "docs", "[<command-name> ...]", "Returns documentary information about commands."),
+];
+
+// Whether name is a container command, which takes subcommands
+pub fn has_subcommands(name: &str) -> bool {
+    SUBCOMMANDS
+        .iter()
+        .any(|sub| sub.command.eq_ignore_ascii_case(name))
+}
+
+// Reply to the HELP subcommand of a container: a usage line, then each
+// subcommand with its summary indented below it
+pub fn help(command: &str) -> RespValue<'static> {
+    let command = command.to_ascii_lowercase();
+    let line = |s: String| RespValue::SimpleString(Cow::Owned(s));
+    let mut lines = vec![line(format!(
+        "{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+        command.to_uppercase()
+    ))];
+    for sub in SUBCOMMANDS.iter().filter(|sub| sub.command == command) {
+        let usage = match sub.args {
+            "" => sub.name.to_uppercase(),
+            args => format!("{} {}", sub.name.to_uppercase(), args),
+        };
+        lines.push(line(usage));
+        lines.push(line(format!("    {}", sub.summary)));
+    }
+    lines.push(line("HELP".to_string()));
+    lines.push(line("    Prints this help.".to_string()));
+    RespValue::Array(Some(lines))
+}
+
+// Looked up on every request, to tell reads apart for CLIENT TRACKING
+static BY_NAME: LazyLock<HashMap<&'static str, &'static CommandSpec>> =
+    LazyLock::new(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect());
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    BY_NAME.get(name.to_ascii_lowercase().as_str()).copied()
+}
+
+fn bulk(s: &'static str) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Borrowed(s)))
+}
+
+impl CommandSpec {
+    pub fn is_readonly(&self) -> bool {
+        self.flags.contains(F::READONLY)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(F::WRITE)
+    }
+
+    // Refused once memory runs out
+    pub fn denies_oom(&self) -> bool {
+        self.flags.contains(F::DENYOOM)
+    }
+
+    // Served while the dataset is still loading
+    pub fn allows_loading(&self) -> bool {
+        self.flags.contains(F::LOADING)
+    }
+
+    // ACL categories, without the @, in Redis' order. They follow from the
+    // flags and the group like Redis derives its implicit ones.
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        let flags = self.flags;
+        let group = |name: &str| self.group == name;
+        [
+            ("keyspace", group("generic")),
+            ("read", flags.contains(F::READONLY)),
+            ("write", flags.contains(F::WRITE)),
+            ("set", group("set")),
+            ("sortedset", group("sorted-set")),
+            ("list", group("list")),
+            ("hash", group("hash")),
+            ("string", group("string")),
+            ("geo", group("geo")),
+            ("pubsub", flags.contains(F::PUBSUB) || group("pubsub")),
+            ("admin", flags.contains(F::ADMIN)),
+            ("fast", flags.contains(F::FAST)),
+            ("slow", !flags.contains(F::FAST)),
+            ("blocking", flags.contains(F::BLOCKING)),
+            ("dangerous", flags.contains(F::ADMIN)),
+            ("connection", group("connection")),
+        ]
+        .into_iter()
+        .filter(|(_, applies)| *applies)
+        .map(|(category, _)| category)
+        .collect()
+    }
+
+    // COMMAND INFO entry. Tips, key specs and subcommands are not tracked
+    // and go out empty.
+    pub fn info(&self) -> RespValue<'static> {
+        let flags = self
+            .flags
+            .names()
+            .map(|flag| RespValue::SimpleString(Cow::Borrowed(flag)))
+            .collect();
+        let categories = self
+            .acl_categories()
+            .into_iter()
+            .map(|category| RespValue::SimpleString(Cow::Owned(format!("@{}", category))))
+            .collect();
+        let empty = || RespValue::Array(Some(vec![]));
+        RespValue::Array(Some(vec![
+            bulk(self.name),
+            RespValue::Integer(self.arity),
+            RespValue::Array(Some(flags)),
+            RespValue::Integer(self.first_key),
+            RespValue::Integer(self.last_key),
+            RespValue::Integer(self.step),
+            RespValue::Array(Some(categories)),
+            empty(),
+            empty(),
+            empty(),
+        ]))
+    }
+
+    // COMMAND DOCS fields for this command
+    pub fn docs(&self) -> Vec<(RespValue<'static>, RespValue<'static>)> {
+        vec![
+            (bulk("summary"), bulk(self.summary)),
+            (bulk("group"), bulk(self.group)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_table() {
+        let names: HashSet<_> = COMMANDS.iter().map(|spec| spec.name).collect();
+        assert_eq!(names.len(), COMMANDS.len());
+
+        let get = lookup("GET").unwrap();
+        assert_eq!(
+            (get.arity, get.first_key, get.last_key, get.step),
+            (2, 1, 1, 1)
+        );
+        assert!(lookup("nosuchcommand").is_none());
+        assert!(get.is_readonly());
+        assert!(!lookup("set").unwrap().is_readonly());
+
+        match get.info() {
+            RespValue::Array(Some(items)) => {
+                assert_eq!(items.len(), 10);
+                assert_eq!(items[0], bulk("get"));
+                let status = |s: &'static str| RespValue::SimpleString(Cow::Borrowed(s));
+                assert_eq!(
+                    items[2],
+                    RespValue::Array(Some(vec![status("readonly"), status("fast")]))
+                );
+                assert_eq!(
+                    items[6],
+                    RespValue::Array(Some(vec![
+                        status("@read"),
+                        status("@string"),
+                        status("@fast")
+                    ]))
+                );
+            }
+            other => panic!("unexpected info {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flags() {
+        let blmove = lookup("blmove").unwrap();
+        assert!(blmove.is_write() && blmove.flags.contains(F::DENYOOM));
+        assert_eq!(
+            blmove.flags.names().collect::<Vec<_>>(),
+            ["write", "denyoom", "blocking"]
+        );
+        assert_eq!(
+            blmove.acl_categories(),
+            ["write", "list", "slow", "blocking"]
+        );
+        assert_eq!(
+            lookup("debug").unwrap().flags.names().collect::<Vec<_>>(),
+            ["admin", "noscript", "loading", "stale"]
+        );
+        assert_eq!(
+            lookup("replicaof").unwrap().acl_categories(),
+            ["admin", "slow", "dangerous"]
+        );
+        assert_eq!(
+            lookup("del").unwrap().acl_categories(),
+            ["keyspace", "write", "slow"]
+        );
+
+        // A command either reads or writes keys, never both
+        for spec in COMMANDS {
+            assert!(!(spec.is_readonly() && spec.is_write()), "{}", spec.name);
+            assert!(
+                !spec.flags.contains(F::DENYOOM) || spec.is_write(),
+                "{}",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_help() {
+        // Subcommands belong to commands of the table
+        for sub in SUBCOMMANDS {
+            assert!(lookup(sub.command).is_some(), "{}", sub.command);
+        }
+        assert!(has_subcommands("Object"));
+        assert!(!has_subcommands("get"));
+
+        let line = |s: &'static str| RespValue::SimpleString(Cow::Borrowed(s));
+        assert_eq!(
+            help("cluster"),
+            RespValue::Array(Some(vec![
+                line("CLUSTER <subcommand> [<arg> [value] [opt] ...]. Subcommands are:"),
+                line("KEYSLOT <key>"),
+                line("    Returns the hash slot of a key."),
+                line("HELP"),
+                line("    Prints this help."),
+            ]))
+        );
+        match help("client") {
+            RespValue::Array(Some(lines)) => {
+                assert_eq!(lines[1], line("ID"));
+                assert_eq!(lines.len(), 1 + 2 * 3 + 2);
+            }
+            other => panic!("unexpected help {:?}", other),
+        }
+    }
+}
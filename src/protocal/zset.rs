@@ -0,0 +1,197 @@
+// Sorted set commands. A sorted set is stored as a Value::ZSet, its members
+// ordered by (score, member) and indexed by member (see sortedset). An empty
+// sorted set is never stored.
+use crate::db::db::DB;
+use crate::db::sortedset::SortedSet;
+use crate::db::storage::{Storage, Update};
+use crate::db::types::ValueType;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use crate::protocal::index::resolve_range;
+use crate::protocal::reply;
+use anyhow::{anyhow, Error};
+use std::borrow::Cow;
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+pub(super) type ZSetDB<S> = DB<S, String, Value>;
+pub(super) type Reply = Result<Arc<RespValue<'static>>, Error>;
+
+pub(super) fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+// (member, score) pairs in order
+pub(super) fn entries(items: &SortedSet) -> impl Iterator<Item = (&str, f64)> {
+    items
+        .iter()
+        .map(|(member, score)| (member.as_str(), *score))
+}
+
+pub(super) fn score(items: &SortedSet, member: &str) -> Option<f64> {
+    items.score(member)
+}
+
+// Runs a read-only closure against the sorted set stored at key
+pub(super) fn read<S, F>(db: &ZSetDB<S>, key: &String, missing: RespValue<'static>, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&SortedSet) -> Result<RespValue<'static>, CommandError>,
+{
+    match db
+        .get_typed(key, ValueType::ZSet)
+        .map_err(CommandError::from_storage)?
+        .as_deref()
+    {
+        None => Ok(reply::intern(missing)),
+        Some(Value::ZSet(items)) => Ok(reply::intern(f(items)?)),
+        Some(_) => Err(anyhow!(CommandError::WrongType)),
+    }
+}
+
+// Runs a mutating closure against the sorted set stored at key, creating it
+// when missing. The set is deleted if the closure leaves it empty.
+pub(super) fn write<S, F>(db: &ZSetDB<S>, key: String, f: F) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+    F: FnOnce(&mut SortedSet) -> Result<RespValue<'static>, CommandError>,
+{
+    let reply = db
+        .update_typed(key, ValueType::ZSet, |value| match value {
+            None => {
+                let mut items = SortedSet::new();
+                match f(&mut items) {
+                    Ok(reply) if !items.is_empty() => (Update::Set(Value::ZSet(items)), Ok(reply)),
+                    result => (Update::Keep, result),
+                }
+            }
+            Some(Value::ZSet(items)) => {
+                let reply = f(items);
+                if items.is_empty() {
+                    (Update::Delete, reply)
+                } else {
+                    (Update::Keep, reply)
+                }
+            }
+            Some(_) => (Update::Keep, Err(CommandError::WrongType)),
+        })
+        .map_err(CommandError::from_storage)??;
+    Ok(reply::intern(reply))
+}
+
+// ZADD: replies with the number of members that were added
+pub fn add<S>(db: &ZSetDB<S>, key: String, members: Vec<(f64, String)>) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    write(db, key, |items| {
+        let added = members
+            .into_iter()
+            .map(|(score, member)| items.insert(member, score))
+            .filter(|added| *added)
+            .count();
+        Ok(RespValue::Integer(added as i64))
+    })
+}
+
+pub fn get_score<S>(db: &ZSetDB<S>, key: &String, member: &str) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::BulkString(None), |items| {
+        Ok(match score(items, member) {
+            Some(score) => bulk(score.to_string()),
+            None => RespValue::BulkString(None),
+        })
+    })
+}
+
+pub fn card<S>(db: &ZSetDB<S>, key: &String) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::Integer(0), |items| {
+        Ok(RespValue::Integer(items.len() as i64))
+    })
+}
+
+// ZRANGE by rank, start/stop inclusive with negatives from the tail
+pub fn range<S>(db: &ZSetDB<S>, key: &String, start: i64, stop: i64, with_scores: bool) -> Reply
+where
+    S: Storage<String, Value> + 'static,
+{
+    read(db, key, RespValue::Array(Some(vec![])), |items| {
+        let mut reply = vec![];
+        if let Some((from, to)) = resolve_range(start, stop, items.len()) {
+            for (member, score) in entries(items).skip(from).take(to - from) {
+                reply.push(bulk(member.to_string()));
+                if with_scores {
+                    reply.push(bulk(score.to_string()));
+                }
+            }
+        }
+        Ok(RespValue::Array(Some(reply)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    fn new_db() -> ZSetDB<DashMapStorage<String, Value>> {
+        DB::new(DashMapStorage::new(), 16)
+    }
+
+    fn members(pairs: &[(f64, &str)]) -> Vec<(f64, String)> {
+        pairs.iter().map(|(s, m)| (*s, m.to_string())).collect()
+    }
+
+    fn array(values: &[&str]) -> RespValue<'static> {
+        RespValue::Array(Some(values.iter().map(|v| bulk(v.to_string())).collect()))
+    }
+
+    #[test]
+    fn test_add_and_range() {
+        let db = new_db();
+        let key = "zset".to_string();
+        let reply = add(
+            &db,
+            key.clone(),
+            members(&[(2.0, "b"), (1.0, "a"), (2.0, "aa")]),
+        )
+        .unwrap();
+        assert_eq!(*reply, RespValue::Integer(3));
+        assert_eq!(
+            *range(&db, &key, 0, -1, false).unwrap(),
+            array(&["a", "aa", "b"])
+        );
+
+        // Re-scoring moves the member and does not count as added
+        let reply = add(&db, key.clone(), members(&[(0.5, "b")])).unwrap();
+        assert_eq!(*reply, RespValue::Integer(0));
+        assert_eq!(
+            *range(&db, &key, 0, 1, true).unwrap(),
+            array(&["b", "0.5", "a", "1"])
+        );
+        assert_eq!(*card(&db, &key).unwrap(), RespValue::Integer(3));
+        assert_eq!(*get_score(&db, &key, "aa").unwrap(), bulk("2".into()));
+        assert_eq!(
+            *get_score(&db, &key, "x").unwrap(),
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let db = new_db();
+        let key = "hash".to_string();
+        db.set(
+            key.clone(),
+            Value::Hash([("f".to_string(), "v".to_string())].into()),
+        )
+        .unwrap();
+        assert!(add(&db, key.clone(), members(&[(1.0, "a")])).is_err());
+        assert!(card(&db, &key).is_err());
+    }
+}
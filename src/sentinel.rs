@@ -0,0 +1,586 @@
+// Failover agent run by the foobar-sentinel binary. Each sentinel PINGs the
+// master and reads INFO replication from it and its replicas. A master that
+// gave no valid reply for down_after is down in this sentinel's view; once
+// quorum sentinels agree it is down for good, and the sentinel the majority
+// voted for in the current epoch promotes the replica with the most data with
+// REPLICAOF NO ONE and points the other replicas at it. The others pick up
+// the new master from INFO on their next check.
+//
+// Sentinels talk to each other like Redis Sentinel does, with
+// SENTINEL IS-MASTER-DOWN-BY-ADDR <ip> <port> <epoch> <runid>, which answers
+// [down, leader runid, leader epoch] and casts a vote for runid the first
+// time an epoch is seen.
+use crate::client::{ClientConfig, ClientError, FoobarClient};
+use crate::protocal::reply;
+use crate::protocal::request::{self, RequestLimits, MAX_DEPTH};
+use bytes::{Buf, BytesMut};
+use rand::Rng;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use stream_resp::parser::Parser;
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::interval;
+use tracing::{debug, info, warn};
+
+// Requests from peers and clients are tiny
+const LIMITS: RequestLimits = RequestLimits {
+    max_args: 16,
+    max_bytes: 4096,
+};
+
+#[derive(Debug, Clone)]
+pub struct SentinelConfig {
+    // Name clients ask for with SENTINEL GET-MASTER-ADDR-BY-NAME
+    pub name: String,
+    // host:port of the master at start
+    pub master: String,
+    // Replicas to consider besides the ones the master's INFO lists
+    pub replicas: Vec<String>,
+    // host:port of the other sentinels
+    pub peers: Vec<String>,
+    // Sentinels that must see the master down before a failover
+    pub quorum: usize,
+    // Time without a valid reply after which a node is down
+    pub down_after: Duration,
+    // Wait before another failover attempt after one didn't happen
+    pub failover_timeout: Duration,
+    pub ping_interval: Duration,
+}
+
+impl Default for SentinelConfig {
+    fn default() -> Self {
+        Self {
+            name: "mymaster".to_string(),
+            master: "127.0.0.1:6379".to_string(),
+            replicas: vec![],
+            peers: vec![],
+            quorum: 2,
+            down_after: Duration::from_secs(5),
+            failover_timeout: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// What a node says about itself in INFO replication
+#[derive(Debug, Default, PartialEq)]
+struct ReplInfo {
+    master: bool,
+    // The master a replica follows
+    master_addr: Option<String>,
+    // slave_repl_offset of a replica, master_repl_offset of a master
+    offset: i64,
+    // Replicas a master lists as slaveN:ip=...,port=...
+    replicas: Vec<String>,
+}
+
+fn parse_info(info: &str) -> ReplInfo {
+    let mut repl = ReplInfo::default();
+    let (mut host, mut port) = (None, None);
+    let mut master_offset = 0;
+    for line in info.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        match field {
+            "role" => repl.master = value == "master",
+            "master_host" => host = Some(value),
+            "master_port" => port = Some(value),
+            "slave_repl_offset" => repl.offset = value.parse().unwrap_or(0),
+            "master_repl_offset" => master_offset = value.parse().unwrap_or(0),
+            field if field.starts_with("slave") && field[5..].parse::<u32>().is_ok() => {
+                let fields: HashMap<&str, &str> =
+                    value.split(',').filter_map(|f| f.split_once('=')).collect();
+                if let (Some(ip), Some(port)) = (fields.get("ip"), fields.get("port")) {
+                    repl.replicas.push(format!("{}:{}", ip, port));
+                }
+            }
+            _ => {}
+        }
+    }
+    if repl.master {
+        repl.offset = master_offset;
+    } else {
+        repl.master_addr = host.zip(port).map(|(h, p)| format!("{}:{}", h, p));
+    }
+    repl
+}
+
+// The replica to promote: the one with the most of the master's data, ties
+// going to the lowest address so every sentinel would pick the same
+fn pick_replica(candidates: &[(String, ReplInfo)]) -> Option<&str> {
+    candidates
+        .iter()
+        .filter(|(_, info)| !info.master)
+        .min_by(|(a, x), (b, y)| y.offset.cmp(&x.offset).then(a.cmp(b)))
+        .map(|(addr, _)| addr.as_str())
+}
+
+fn split_addr(addr: &str) -> (&str, &str) {
+    addr.rsplit_once(':').unwrap_or((addr, ""))
+}
+
+fn run_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+fn jitter(max: Duration) -> Duration {
+    max.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+fn bulk(s: impl Into<String>) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s.into())))
+}
+
+struct State {
+    master: String,
+    replicas: BTreeSet<String>,
+    // Last valid reply from the master
+    last_ok: Instant,
+    epoch: u64,
+    // Who this sentinel voted for, and in which epoch
+    voted: Option<(String, u64)>,
+    // When to try to get elected, set once the master is down
+    next_election: Option<Instant>,
+}
+
+impl State {
+    fn master_down(&self, down_after: Duration) -> bool {
+        self.last_ok.elapsed() > down_after
+    }
+
+    // A vote goes to the first sentinel asking in an epoch newer than the
+    // last vote, later ones learn who that was
+    fn vote(&mut self, run_id: &str, epoch: u64) -> (String, u64) {
+        if self.voted.as_ref().is_none_or(|(_, voted)| epoch > *voted) {
+            self.voted = Some((run_id.to_string(), epoch));
+            self.epoch = self.epoch.max(epoch);
+        }
+        self.voted.clone().expect("voted above")
+    }
+
+    // Follows a failover done elsewhere, the old master becoming a replica
+    fn switch_master(&mut self, new: &str) {
+        let old = std::mem::replace(&mut self.master, new.to_string());
+        self.replicas.remove(new);
+        self.replicas.insert(old);
+        self.last_ok = Instant::now();
+        self.next_election = None;
+    }
+}
+
+pub struct Sentinel {
+    config: SentinelConfig,
+    run_id: String,
+    state: Mutex<State>,
+    // A client per node and peer, opened on first use
+    clients: Mutex<HashMap<String, FoobarClient>>,
+}
+
+impl Sentinel {
+    pub fn new(config: SentinelConfig) -> Arc<Self> {
+        let state = State {
+            master: config.master.clone(),
+            replicas: config.replicas.iter().cloned().collect(),
+            last_ok: Instant::now(),
+            epoch: 0,
+            voted: None,
+            next_election: None,
+        };
+        Arc::new(Self {
+            config,
+            run_id: run_id(),
+            state: Mutex::new(state),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    // host:port of the current master
+    pub fn master(&self) -> String {
+        self.state().master.clone()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn client(&self, addr: &str) -> Result<FoobarClient, ClientError> {
+        if let Some(client) = self
+            .clients
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(addr)
+        {
+            return Ok(client.clone());
+        }
+        // A node that doesn't answer within down_after counts as failed,
+        // and is tried again on the next check rather than retried here
+        let client = FoobarClient::with_config(ClientConfig {
+            addr: addr.to_string(),
+            max_connections: 2,
+            connect_timeout: self.config.down_after,
+            connect_attempts: 1,
+            request_timeout: Some(self.config.down_after),
+            ..Default::default()
+        })
+        .await?;
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(clients.entry(addr.to_string()).or_insert(client).clone())
+    }
+
+    async fn info(&self, addr: &str) -> Result<ReplInfo, ClientError> {
+        let info: String = self
+            .client(addr)
+            .await?
+            .query(&["INFO", "replication"])
+            .await?;
+        Ok(parse_info(&info))
+    }
+
+    // Checks the nodes every ping_interval, failing over when elected
+    pub async fn monitor(self: Arc<Self>) {
+        let mut ticker = interval(self.config.ping_interval);
+        loop {
+            ticker.tick().await;
+            self.check().await;
+        }
+    }
+
+    async fn check(&self) {
+        let master = self.master();
+        let alive = match self.client(&master).await {
+            Ok(client) => client.ping().await.is_ok(),
+            Err(_) => false,
+        };
+        if alive {
+            self.state().last_ok = Instant::now();
+            if let Ok(info) = self.info(&master).await {
+                let mut state = self.state();
+                // Demoted by a failover this sentinel didn't run
+                if let Some(new) = info.master_addr.filter(|_| !info.master) {
+                    info!("Master {} now follows {}, switching", master, new);
+                    state.switch_master(&new);
+                    return;
+                }
+                state.replicas.extend(info.replicas);
+                state.next_election = None;
+            }
+            return;
+        }
+
+        let (replicas, down) = {
+            let state = self.state();
+            let replicas: Vec<String> = state.replicas.iter().cloned().collect();
+            (replicas, state.master_down(self.config.down_after))
+        };
+        if !down {
+            return;
+        }
+        // A replica promoted by another sentinel
+        let infos = self.replica_infos(&replicas).await;
+        if let Some((new, _)) = infos.iter().find(|(_, info)| info.master) {
+            info!("Replica {} was promoted, switching from {}", new, master);
+            self.state().switch_master(new);
+            return;
+        }
+
+        let now = Instant::now();
+        let election = {
+            let mut state = self.state();
+            let at = *state
+                .next_election
+                .get_or_insert_with(|| now + jitter(self.config.down_after));
+            if now < at {
+                return;
+            }
+            state.next_election =
+                Some(now + self.config.failover_timeout + jitter(self.config.failover_timeout));
+            let epoch = state.epoch + 1;
+            state.vote(&self.run_id, epoch);
+            epoch
+        };
+        if self.elected(&master, election).await {
+            self.failover(&master, infos).await;
+        }
+    }
+
+    async fn replica_infos(&self, replicas: &[String]) -> Vec<(String, ReplInfo)> {
+        let infos = futures::future::join_all(replicas.iter().map(|addr| self.info(addr))).await;
+        replicas
+            .iter()
+            .cloned()
+            .zip(infos)
+            .filter_map(|(addr, info)| Some((addr, info.ok()?)))
+            .collect()
+    }
+
+    // Asks the peers whether the master is down and for their vote in epoch.
+    // True when quorum sentinels see it down and a majority voted for this
+    // one.
+    async fn elected(&self, master: &str, epoch: u64) -> bool {
+        let (host, port) = split_addr(master);
+        let epoch_arg = epoch.to_string();
+        let args = [
+            "SENTINEL",
+            "IS-MASTER-DOWN-BY-ADDR",
+            host,
+            port,
+            &epoch_arg,
+            &self.run_id,
+        ];
+        let replies = futures::future::join_all(self.config.peers.iter().map(|peer| async move {
+            self.client(peer)
+                .await?
+                .query::<RespValue<'static>>(&args)
+                .await
+        }))
+        .await;
+
+        let (mut down, mut votes, mut seen_epoch) = (1, 1, epoch);
+        for reply in replies.into_iter().flatten() {
+            let RespValue::Array(Some(fields)) = reply else {
+                continue;
+            };
+            if let [RespValue::Integer(is_down), leader, RespValue::Integer(leader_epoch)] =
+                fields.as_slice()
+            {
+                down += usize::from(*is_down == 1);
+                let leader = match leader {
+                    RespValue::BulkString(Some(s)) | RespValue::SimpleString(s) => s.as_ref(),
+                    _ => "",
+                };
+                votes += usize::from(leader == self.run_id && *leader_epoch as u64 == epoch);
+                seen_epoch = seen_epoch.max(*leader_epoch as u64);
+            }
+        }
+        {
+            let mut state = self.state();
+            state.epoch = state.epoch.max(seen_epoch);
+        }
+
+        let sentinels = self.config.peers.len() + 1;
+        let majority = sentinels / 2 + 1;
+        debug!(
+            "Epoch {}: {} sentinels see {} down, {} voted for this one",
+            epoch, down, master, votes
+        );
+        down >= self.config.quorum && votes >= majority.max(self.config.quorum)
+    }
+
+    async fn failover(&self, master: &str, candidates: Vec<(String, ReplInfo)>) {
+        let Some(promoted) = pick_replica(&candidates).map(str::to_string) else {
+            warn!("Master {} is down and no replica can take over", master);
+            return;
+        };
+        info!("Master {} is down, promoting {}", master, promoted);
+        let promote = match self.client(&promoted).await {
+            Ok(client) => client.query::<()>(&["REPLICAOF", "NO", "ONE"]).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = promote {
+            warn!("Failed to promote {}: {}", promoted, e);
+            return;
+        }
+
+        let (host, port) = split_addr(&promoted);
+        for (addr, _) in candidates.iter().filter(|(addr, _)| *addr != promoted) {
+            let result = match self.client(addr).await {
+                Ok(client) => client.query::<()>(&["REPLICAOF", host, port]).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Failed to point {} at {}: {}", addr, promoted, e);
+            }
+        }
+        self.state().switch_master(&promoted);
+        info!("Failover of {} to {} done", master, promoted);
+    }
+
+    // Answers peers and clients on listener
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            debug!("Accepted sentinel connection from {:?}", addr);
+            tokio::spawn(self.clone().serve_connection(stream));
+        }
+    }
+
+    async fn serve_connection(self: Arc<Self>, mut stream: TcpStream) {
+        let mut parser = Parser::new(MAX_DEPTH, LIMITS.max_bytes + 1);
+        let mut scan = request::Scan::default();
+        let mut out = BytesMut::new();
+        loop {
+            match stream.read_buf(&mut parser.buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let mut consumed = 0;
+            loop {
+                let len = match scan.check(&parser.buffer[consumed..], &LIMITS) {
+                    Ok(Some(len)) => len,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = stream.write_all(format!("-ERR {}\r\n", e).as_bytes()).await;
+                        return;
+                    }
+                };
+                consumed += len;
+                let args = match request::parse(&mut parser) {
+                    Ok(RespValue::Array(Some(items))) => items
+                        .iter()
+                        .map(|item| match item {
+                            RespValue::BulkString(Some(s)) => s.to_string(),
+                            _ => String::new(),
+                        })
+                        .collect::<Vec<_>>(),
+                    _ => return,
+                };
+                reply::encode(&self.handle(&args), &mut out);
+            }
+            if stream.write_all(&out).await.is_err() {
+                return;
+            }
+            out.clear();
+            parser.buffer.advance(consumed);
+            parser.clear_buffer(0);
+        }
+    }
+
+    fn handle(&self, args: &[String]) -> RespValue<'static> {
+        let arg = |i: usize| {
+            args.get(i)
+                .map(|s| s.to_ascii_uppercase())
+                .unwrap_or_default()
+        };
+        match (arg(0).as_str(), arg(1).as_str()) {
+            ("PING", _) => RespValue::SimpleString(Cow::Borrowed("PONG")),
+            ("SENTINEL", "MYID") => bulk(self.run_id.as_str()),
+            ("SENTINEL", "GET-MASTER-ADDR-BY-NAME") if args.len() == 3 => {
+                if args[2] != self.config.name {
+                    return RespValue::BulkString(None);
+                }
+                let master = self.master();
+                let (host, port) = split_addr(&master);
+                RespValue::Array(Some(vec![bulk(host), bulk(port)]))
+            }
+            ("SENTINEL", "IS-MASTER-DOWN-BY-ADDR") if args.len() == 6 => {
+                let Ok(epoch) = args[4].parse::<u64>() else {
+                    return RespValue::Error(Cow::Borrowed("ERR invalid epoch"));
+                };
+                let addr = format!("{}:{}", args[2], args[3]);
+                let mut state = self.state();
+                let down = addr == state.master && state.master_down(self.config.down_after);
+                // "*" only asks whether the master is down
+                let (leader, leader_epoch) = match args[5].as_str() {
+                    "*" => ("*".to_string(), 0),
+                    run_id => state.vote(run_id, epoch),
+                };
+                RespValue::Array(Some(vec![
+                    RespValue::Integer(i64::from(down)),
+                    bulk(leader),
+                    RespValue::Integer(leader_epoch as i64),
+                ]))
+            }
+            _ => RespValue::Error(Cow::Owned(format!(
+                "ERR unknown sentinel command '{}'",
+                args.join(" ")
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_info() {
+        let master = parse_info(
+            "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+             slave0:ip=10.0.0.2,port=6380,state=online,offset=120,lag=0\r\n\
+             slave1:ip=10.0.0.3,port=6381,state=online,offset=100,lag=1\r\n\
+             master_repl_offset:120\r\nslave_read_only:1\r\n",
+        );
+        assert_eq!(
+            master,
+            ReplInfo {
+                master: true,
+                master_addr: None,
+                offset: 120,
+                replicas: vec!["10.0.0.2:6380".to_string(), "10.0.0.3:6381".to_string()],
+            }
+        );
+
+        let replica = parse_info(
+            "# Replication\r\nrole:slave\r\nmaster_host:10.0.0.1\r\nmaster_port:6379\r\n\
+             slave_repl_offset:90\r\nmaster_repl_offset:90\r\n",
+        );
+        assert!(!replica.master);
+        assert_eq!(replica.master_addr.as_deref(), Some("10.0.0.1:6379"));
+        assert_eq!(replica.offset, 90);
+    }
+
+    #[test]
+    fn test_pick_replica() {
+        let replica = |offset| ReplInfo {
+            offset,
+            ..Default::default()
+        };
+        let candidates = vec![
+            ("b:1".to_string(), replica(10)),
+            ("a:1".to_string(), replica(10)),
+            ("c:1".to_string(), replica(5)),
+            (
+                "d:1".to_string(),
+                ReplInfo {
+                    master: true,
+                    offset: 50,
+                    ..Default::default()
+                },
+            ),
+        ];
+        assert_eq!(pick_replica(&candidates), Some("a:1"));
+        assert_eq!(pick_replica(&candidates[2..]), Some("c:1"));
+        assert_eq!(pick_replica(&candidates[3..]), None);
+    }
+
+    #[test]
+    fn test_vote() {
+        let sentinel = Sentinel::new(SentinelConfig::default());
+        let ask = |run_id: &str, epoch: &str| {
+            sentinel.handle(&[
+                "SENTINEL".to_string(),
+                "is-master-down-by-addr".to_string(),
+                "127.0.0.1".to_string(),
+                "6379".to_string(),
+                epoch.to_string(),
+                run_id.to_string(),
+            ])
+        };
+        let answer = |leader: &str, epoch| {
+            RespValue::Array(Some(vec![
+                RespValue::Integer(0),
+                bulk(leader),
+                RespValue::Integer(epoch),
+            ]))
+        };
+        // First asker of an epoch gets the vote, the next one learns who
+        assert_eq!(ask("a", "1"), answer("a", 1));
+        assert_eq!(ask("b", "1"), answer("a", 1));
+        assert_eq!(ask("b", "2"), answer("b", 2));
+        assert_eq!(ask("a", "1"), answer("b", 2));
+        assert_eq!(ask("*", "3"), answer("*", 0));
+        assert_eq!(sentinel.state().epoch, 2);
+    }
+}
@@ -0,0 +1,177 @@
+// Keeps the accept loop alive through accept() errors. Errors about one
+// connection are skipped, the rest (running out of file descriptors or
+// memory) are retried with a growing pause. A descriptor is held in reserve
+// so that, with none left, the waiting connection can still be taken and
+// closed instead of sitting in the backlog.
+use std::fs::File;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+const BACKOFF_MIN: Duration = Duration::from_millis(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Descriptors the server needs besides its clients: listeners, logs, storage
+pub const RESERVED_FDS: u64 = 32;
+
+// EMFILE and ENFILE, the same on Linux, macOS and the BSDs
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+// The error concerns the connection being accepted, not the listener
+pub fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+// The process or the system is out of file descriptors
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    cfg!(unix) && matches!(e.raw_os_error(), Some(EMFILE | ENFILE))
+}
+
+// Pause after consecutive accept errors, doubling up to BACKOFF_MAX
+#[derive(Debug, Default)]
+pub struct Backoff {
+    delay: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .delay
+            .map_or(BACKOFF_MIN, |delay| (delay * 2).min(BACKOFF_MAX));
+        self.delay = Some(delay);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = None;
+    }
+}
+
+// A descriptor held open to be given up when the others run out
+#[derive(Debug)]
+pub struct ReservedFd {
+    file: Mutex<Option<File>>,
+}
+
+impl Default for ReservedFd {
+    fn default() -> Self {
+        Self {
+            file: Mutex::new(Self::open()),
+        }
+    }
+}
+
+impl ReservedFd {
+    fn open() -> Option<File> {
+        File::open(if cfg!(windows) { "NUL" } else { "/dev/null" }).ok()
+    }
+
+    // Frees the reserved descriptor to accept one waiting connection and
+    // close it right away, then takes the descriptor back. Tells whether a
+    // connection was turned away.
+    pub async fn shed(&self, listener: &TcpListener) -> bool {
+        let had = self
+            .file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .is_some();
+        let shed = had
+            && matches!(
+                tokio::time::timeout(BACKOFF_MIN, listener.accept()).await,
+                Ok(Ok(_))
+            );
+        *self.file.lock().unwrap_or_else(|e| e.into_inner()) = Self::open();
+        shed
+    }
+}
+
+// Soft limit on open files of the process, where it can be read
+pub fn open_files_limit() -> Option<u64> {
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    parse_open_files_limit(&limits)
+}
+
+fn parse_open_files_limit(limits: &str) -> Option<u64> {
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+// Warns when the open files limit can't fit max_connections clients
+pub fn check_fd_limit(max_connections: usize) {
+    if let Some(limit) = open_files_limit() {
+        let wanted = max_connections as u64 + RESERVED_FDS;
+        if limit < wanted {
+            warn!(
+                "Open files limit is {}, below the {} that max-connections {} needs; \
+                 raise it with ulimit -n",
+                limit, wanted, max_connections
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.next_delay(), BACKOFF_MIN);
+        assert_eq!(backoff.next_delay(), BACKOFF_MIN * 2);
+        for _ in 0..20 {
+            backoff.next_delay();
+        }
+        assert_eq!(backoff.next_delay(), BACKOFF_MAX);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), BACKOFF_MIN);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(is_connection_error(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+        let emfile = io::Error::from_raw_os_error(EMFILE);
+        assert!(!is_connection_error(&emfile));
+        assert_eq!(is_fd_exhaustion(&emfile), cfg!(unix));
+    }
+
+    #[test]
+    fn test_parse_open_files_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             63304                63304                processes \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_open_files_limit(limits), Some(1024));
+        assert_eq!(parse_open_files_limit("Max open files unlimited"), None);
+    }
+
+    #[tokio::test]
+    async fn test_shed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reserved = ReservedFd::default();
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(reserved.shed(&listener).await);
+        // The descriptor is taken back, nothing waits now
+        assert!(reserved.file.lock().unwrap().is_some());
+        assert!(!reserved.shed(&listener).await);
+    }
+}
@@ -0,0 +1,794 @@
+// Append only file: the effects of every write a client was answered for
+// (see propagate.rs), with a SELECT wherever the database changes. A write
+// reaches the OS before its reply goes out, so a killed server loses nothing
+// it acknowledged; appendfsync decides how much a power loss may take.
+// Replaying the file at startup rebuilds the dataset, expiries included, as
+// they are logged with absolute deadlines.
+//
+// Each append is a record closed by a `#CRC64:` line, the checksum of the
+// record's bytes. Other lines starting with `#` are annotations, as in
+// Redis' AOF, and are skipped on replay. A record is only replayed once its
+// checksum matched, so damage is found before any of it is applied.
+//
+// A file starts with an `#ID:` annotation, made up when the file is. A
+// snapshot names the file and how much of it it holds, so startup can load
+// the snapshot and replay only what came after.
+use crate::db::db::{self, Databases, Snapshot};
+use crate::db::dump;
+use crate::db::rdb;
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::{Command, ExecContext};
+use crate::protocal::request::{self, RequestLimits, MAX_DEPTH};
+use crate::server::keylock::KeyLocks;
+use crate::server::propagate;
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stream_resp::parser::Parser;
+use stream_resp::resp::RespValue;
+use tracing::{info, warn};
+
+// How often everysec syncs
+pub const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+const CHECKSUM: &[u8] = b"#CRC64:";
+
+const ID: &[u8] = b"#ID:";
+
+// Length of the id line: the annotation, 16 hex digits and CRLF
+const ID_LINE: usize = 22;
+
+fn id_line(id: u64) -> Vec<u8> {
+    format!("#ID:{:016x}\r\n", id).into_bytes()
+}
+
+// The id a file starts with, if it has one
+fn read_id(head: &[u8]) -> Option<u64> {
+    let hex = head
+        .get(..ID_LINE)?
+        .strip_prefix(ID)?
+        .strip_suffix(b"\r\n")?;
+    u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+// Closes a record with the checksum of its bytes
+fn seal(record: &mut Vec<u8>) {
+    let crc = rdb::crc64(record);
+    record.extend_from_slice(CHECKSUM);
+    record.extend_from_slice(format!("{:016x}\r\n", crc).as_bytes());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    // Before every reply
+    Always,
+    // From a background task once a second
+    #[default]
+    EverySec,
+    // Whenever the OS gets to it
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "everysec" => Ok(Self::EverySec),
+            "no" => Ok(Self::No),
+            _ => Err(format!("invalid appendfsync '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Always => write!(f, "always"),
+            Self::EverySec => write!(f, "everysec"),
+            Self::No => write!(f, "no"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AofFile {
+    file: File,
+    // Bytes of whole commands in the file
+    len: u64,
+    // Database the last command ran in
+    db: Option<usize>,
+    // What was appended since a rewrite took the dataset, while it runs
+    tail: Option<Vec<u8>>,
+    // None for a file written before files had ids
+    id: Option<u64>,
+}
+
+impl AofFile {
+    // Gives an empty file an id of its own
+    fn start(&mut self) -> io::Result<()> {
+        let id = rand::random();
+        self.file.write_all(&id_line(id))?;
+        self.file.sync_data()?;
+        self.len = ID_LINE as u64;
+        self.id = Some(id);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    path: PathBuf,
+    fsync: AppendFsync,
+    file: Mutex<AofFile>,
+    rewriting: AtomicBool,
+}
+
+// Held by a running rewrite, lets the next one start when dropped
+struct Rewriting(Arc<Aof>);
+
+impl Drop for Rewriting {
+    fn drop(&mut self) {
+        self.0.file.lock().unwrap().tail = None;
+        self.0.rewriting.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Aof {
+    // Appends to path, creating it if needed
+    pub fn open(path: &Path, fsync: AppendFsync) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        let mut head = Vec::with_capacity(ID_LINE);
+        File::open(path)?
+            .take(ID_LINE as u64)
+            .read_to_end(&mut head)?;
+        let mut file = AofFile {
+            file,
+            len,
+            db: None,
+            tail: None,
+            id: read_id(&head),
+        };
+        if len == 0 {
+            file.start()?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            fsync,
+            file: Mutex::new(file),
+            rewriting: AtomicBool::new(false),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fsync(&self) -> AppendFsync {
+        self.fsync
+    }
+
+    // Whether the file holds no records
+    pub fn is_empty(&self) -> bool {
+        let aof = self.file.lock().unwrap();
+        aof.len == if aof.id.is_some() { ID_LINE as u64 } else { 0 }
+    }
+
+    // The file's id and how much of it is written, for a snapshot taken
+    // with nothing being appended to say which part of the file it holds.
+    // The next append selects its db again, so what follows stands on its
+    // own.
+    pub fn position(&self) -> Option<(u64, u64)> {
+        let mut aof = self.file.lock().unwrap();
+        aof.db = None;
+        Some((aof.id?, aof.len))
+    }
+
+    // Adds the effects of a write that ran in db. A write that fails is cut
+    // back off, so the file never holds half a command ahead
+    // of later ones.
+    pub fn append(&self, db: usize, request: &[u8]) -> io::Result<()> {
+        let mut aof = self.file.lock().unwrap();
+        let mut frame = Vec::with_capacity(request.len() + 32);
+        if aof.db != Some(db) {
+            let index = db.to_string();
+            frame.extend_from_slice(
+                format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", index.len(), index).as_bytes(),
+            );
+        }
+        frame.extend_from_slice(request);
+        seal(&mut frame);
+        let written = aof.file.write_all(&frame).and_then(|_| match self.fsync {
+            AppendFsync::Always => aof.file.sync_data(),
+            _ => Ok(()),
+        });
+        if let Err(e) = written {
+            let len = aof.len;
+            let _ = aof.file.set_len(len);
+            return Err(e);
+        }
+        aof.len += frame.len() as u64;
+        aof.db = Some(db);
+        if let Some(tail) = &mut aof.tail {
+            tail.extend_from_slice(&frame);
+        }
+        Ok(())
+    }
+
+    // BGREWRITEAOF: the file is replaced with the dataset as one RESTORE per
+    // key, plus a PEXPIREAT for a key with a deadline. None while a rewrite
+    // runs, otherwise the rewrite is done when the future is, with the
+    // length of the new file.
+    //
+    // The dataset is taken with every key locked, so no write is between
+    // running and being appended. What is appended from then on goes to
+    // the current file as usual and is kept aside too, and follows the
+    // dataset in the new file, which is renamed over the current one.
+    pub fn rewrite<S>(
+        self: &Arc<Self>,
+        dbs: Databases<S, String, Value>,
+        keylocks: Arc<KeyLocks>,
+    ) -> Option<impl Future<Output = Result<u64, Box<dyn Error + Send + Sync>>> + Send + 'static>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        if self.rewriting.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let rewriting = Rewriting(self.clone());
+        Some(async move {
+            let aof = &rewriting.0;
+            let snapshots = {
+                let _all = keylocks.all().await;
+                let snapshots = db::snapshot(&dbs);
+                let mut file = aof.file.lock().unwrap();
+                file.tail = Some(Vec::new());
+                // The kept part starts with a SELECT of its own
+                file.db = None;
+                snapshots
+            };
+            let rewritten = aof.path.with_extension("rewrite");
+            let id = rand::random();
+            let written = tokio::task::spawn_blocking({
+                let rewritten = rewritten.clone();
+                move || write_dataset(&rewritten, id, &snapshots)
+            })
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|written| written.map_err(|e| e.to_string()))
+            .and_then(|()| aof.swap_in(&rewritten, id).map_err(|e| e.to_string()));
+            if written.is_err() {
+                let _ = std::fs::remove_file(&rewritten);
+            }
+            let len = written?;
+            info!("Rewrote {} to {} bytes", aof.path.display(), len);
+            Ok(len)
+        })
+    }
+
+    // Puts the rewritten file in place of the current one, followed by what
+    // was appended since the dataset was taken
+    fn swap_in(&self, rewritten: &Path, id: u64) -> io::Result<u64> {
+        let mut aof = self.file.lock().unwrap();
+        let tail = aof.tail.take().unwrap_or_default();
+        let mut file = OpenOptions::new().append(true).open(rewritten)?;
+        file.write_all(&tail)?;
+        file.sync_all()?;
+        std::fs::rename(rewritten, &self.path)?;
+        aof.len = file.metadata()?.len();
+        aof.file = file;
+        aof.db = None;
+        aof.id = Some(id);
+        Ok(aof.len)
+    }
+
+    // Makes what was appended so far durable
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.lock().unwrap().file.sync_data()
+    }
+
+    // Replays the file from byte from on into ctx's databases, before
+    // anything is appended. From is 0, or where a snapshot of the file
+    // loaded first ends.
+    pub async fn replay<S>(
+        &self,
+        ctx: ExecContext<S>,
+        from: u64,
+    ) -> Result<ReplayStats, Box<dyn Error + Send + Sync>>
+    where
+        S: Storage<String, Value> + 'static,
+    {
+        let stats = replay(&self.path, ctx, from as usize).await?;
+        // The replay may have cut the file, down to nothing when a crash
+        // tore its id
+        let mut aof = self.file.lock().unwrap();
+        aof.len = aof.file.metadata()?.len();
+        aof.db = None;
+        if aof.len == 0 {
+            aof.start()?;
+        }
+        Ok(stats)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub commands: usize,
+    // Commands that failed again, which they only can if the file was
+    // written by something else
+    pub failed: usize,
+    // Bytes of a last command cut short, dropped from the file
+    pub truncated: u64,
+}
+
+// The dataset of snapshots as commands, a record per key, after the id of
+// the new file. Synced before it is used.
+fn write_dataset<S>(
+    path: &Path,
+    id: u64,
+    snapshots: &[Snapshot<S, String, Value>],
+) -> Result<(), anyhow::Error>
+where
+    S: Storage<String, Value>,
+{
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&id_line(id))?;
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        let mut selected = false;
+        snapshot.for_each(|key, value, when| {
+            let mut record = BytesMut::new();
+            if !selected {
+                propagate::encode(&["SELECT", &index.to_string()], &mut record);
+                selected = true;
+            }
+            let payload = dump::serialize(value)
+                .map_err(|e| anyhow!("value of key {} can't be rewritten: {}", key, e))?;
+            let payload = dump::to_hex(&payload);
+            propagate::encode(&["RESTORE", key, "0", &payload], &mut record);
+            if let Some(when) = when {
+                propagate::encode(&["PEXPIREAT", key, &when.to_string()], &mut record);
+            }
+            let mut record = record.to_vec();
+            seal(&mut record);
+            out.write_all(&record)?;
+            Ok(())
+        })?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(())
+}
+
+// Reads a file record by record, handing out a record's commands once its
+// checksum matched
+struct Records<'a> {
+    file: &'a [u8],
+    parser: Parser,
+    limits: RequestLimits,
+    // End of the last whole record
+    valid: usize,
+}
+
+impl<'a> Records<'a> {
+    // Starts at from, which is 0 or the end of a record
+    fn new(file: &'a [u8], from: usize) -> Self {
+        let mut parser = Parser::new(MAX_DEPTH, file.len() + 1);
+        parser.buffer.extend_from_slice(&file[from..]);
+        Self {
+            file,
+            parser,
+            // No command in the file is larger than the file
+            limits: RequestLimits {
+                max_args: file.len(),
+                max_bytes: file.len(),
+            },
+            valid: from,
+        }
+    }
+
+    // Commands of the next record, None once what is left is at most a
+    // record cut short. An error is about the record starting at valid.
+    fn next(&mut self) -> Result<Option<Vec<RespValue<'static>>>, String> {
+        let mut pos = self.valid;
+        let mut commands = Vec::new();
+        loop {
+            let rest = &self.file[pos..];
+            if rest.first() == Some(&b'#') {
+                let Some(end) = rest.windows(2).position(|w| w == b"\r\n") else {
+                    return Ok(None);
+                };
+                let line = &rest[..end];
+                self.parser.buffer.advance(end + 2);
+                let start = pos;
+                pos += end + 2;
+                let Some(hex) = line.strip_prefix(CHECKSUM) else {
+                    // Other annotations stand on their own between records
+                    if commands.is_empty() {
+                        self.valid = pos;
+                    }
+                    continue;
+                };
+                let expected = std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                    .ok_or("invalid checksum")?;
+                if rdb::crc64(&self.file[self.valid..start]) != expected {
+                    return Err("checksum mismatch".to_string());
+                }
+                self.valid = pos;
+                return Ok(Some(commands));
+            }
+            let len = match request::check(rest, &self.limits).map_err(|e| e.to_string())? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+            commands.push(request::parse(&mut self.parser).map_err(|e| e.to_string())?);
+            self.parser.buffer.advance(len);
+            self.parser.clear_buffer(0);
+            pos += len;
+        }
+    }
+}
+
+// What check found in a file: the records read before any damage, and how
+// much of the file they cover
+#[derive(Debug, Default, PartialEq)]
+pub struct AofCheck {
+    pub records: usize,
+    pub commands: usize,
+    // Length of the leading part made of whole records whose checksums
+    // matched
+    pub valid: usize,
+    // What is wrong with the record after them, a record cut short
+    // included
+    pub error: Option<String>,
+}
+
+// Reads the whole file like replay without running anything. Whatever
+// follows the valid part can be cut off to leave a file that replays.
+pub fn check(file: &[u8]) -> AofCheck {
+    let mut records = Records::new(file, 0);
+    let mut check = AofCheck::default();
+    loop {
+        match records.next() {
+            Ok(Some(commands)) => {
+                check.records += 1;
+                check.commands += commands.len();
+            }
+            Ok(None) => break,
+            Err(e) => {
+                check.error = Some(e);
+                break;
+            }
+        }
+    }
+    check.valid = records.valid;
+    if check.error.is_none() && check.valid < file.len() {
+        check.error = Some("record cut short".to_string());
+    }
+    check
+}
+
+// Runs the commands of the file at path in ctx's databases, from byte from
+// on. What a crash left of a last record is dropped from the file before
+// anything is appended after it; damage anywhere else is an error.
+async fn replay<S>(
+    path: &Path,
+    mut ctx: ExecContext<S>,
+    from: usize,
+) -> Result<ReplayStats, Box<dyn Error + Send + Sync>>
+where
+    S: Storage<String, Value> + 'static,
+{
+    let file = std::fs::read(path)?;
+    if from > file.len() {
+        return Err(format!(
+            "{} is shorter than the snapshot taken of it, {} bytes of {}",
+            path.display(),
+            file.len(),
+            from
+        )
+        .into());
+    }
+    let mut records = Records::new(&file, from);
+    let mut stats = ReplayStats::default();
+    loop {
+        let commands = match records.next() {
+            Ok(Some(commands)) => commands,
+            Ok(None) => break,
+            Err(e) => {
+                let at = records.valid;
+                return Err(format!("{} at byte {}: {}", path.display(), at, e).into());
+            }
+        };
+        for resp in commands {
+            stats.commands += 1;
+            match Command::from_resp(resp) {
+                Ok(Command::Select { db }) if db < ctx.dbs.len() => ctx.db_index = db,
+                Ok(cmd) => {
+                    if cmd.exec(ctx.clone()).await.is_err() {
+                        stats.failed += 1;
+                    }
+                }
+                Err(_) => stats.failed += 1,
+            }
+        }
+    }
+    let offset = records.valid;
+    if offset < file.len() {
+        stats.truncated = (file.len() - offset) as u64;
+        warn!(
+            "Dropping {} bytes of an incomplete record at the end of {}",
+            stats.truncated,
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(offset as u64)?;
+    }
+    info!(
+        "Replayed {} commands from {}",
+        stats.commands,
+        path.display()
+    );
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::{Databases, Expiry, DB};
+    use crate::db::storage::DashMapStorage;
+    use std::sync::Arc;
+
+    fn dbs() -> Databases<DashMapStorage<String, Value>, String, Value> {
+        Arc::new(
+            (0..2)
+                .map(|_| Arc::new(DB::new(DashMapStorage::new(), 0)))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_append_and_replay() {
+        let dir = std::env::temp_dir().join(format!("foobar_aof_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = std::fs::remove_file(&path);
+
+        let aof = Aof::open(&path, AppendFsync::Always).unwrap();
+        aof.append(0, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+            .unwrap();
+        aof.append(1, b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n")
+            .unwrap();
+        aof.append(1, b"*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n")
+            .unwrap();
+        aof.sync().unwrap();
+        drop(aof);
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(written.windows(6).filter(|w| w == b"SELECT").count(), 2);
+
+        // A crash in the middle of the last command
+        let mut torn = written.clone();
+        torn.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nd");
+        std::fs::write(&path, &torn).unwrap();
+
+        let aof = Aof::open(&path, AppendFsync::No).unwrap();
+        let dbs = dbs();
+        let stats = aof
+            .replay(ExecContext::new(dbs.clone(), 0), 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            ReplayStats {
+                commands: 5,
+                failed: 0,
+                truncated: 18,
+            }
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+        // Appends go on after the last whole command, in a db of their own
+        aof.append(1, b"*2\r\n$3\r\nDEL\r\n$1\r\nc\r\n").unwrap();
+        let appended = std::fs::read(&path).unwrap();
+        let mut record =
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n*2\r\n$3\r\nDEL\r\n$1\r\nc\r\n".to_vec();
+        seal(&mut record);
+        assert_eq!(&appended[written.len()..], record);
+        drop(aof);
+        assert!(dbs[0].get(&"a".to_string()).unwrap().is_some());
+        assert!(dbs[0].get(&"b".to_string()).unwrap().is_none());
+        assert!(dbs[1].get(&"b".to_string()).unwrap().is_some());
+        assert!(dbs[1].get(&"c".to_string()).unwrap().is_some());
+
+        // Damage before the end is not silently skipped
+        std::fs::write(&path, b"*3\r\n$3\r\nSET\r\n#oops\r\n").unwrap();
+        assert!(replay(&path, ExecContext::new(dbs.clone(), 0), 0)
+            .await
+            .is_err());
+
+        // Nor is a record that reads fine but isn't what was written, and
+        // none of it is applied
+        let mut damaged = appended.clone();
+        let del = written.len() + record.windows(3).position(|w| w == b"DEL").unwrap();
+        damaged[del..del + 3].copy_from_slice(b"GET");
+        std::fs::write(&path, &damaged).unwrap();
+        let err = replay(&path, ExecContext::new(dbs.clone(), 0), 0)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("at byte {}: checksum mismatch", written.len())));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_from() {
+        let dir = std::env::temp_dir().join(format!("foobar_aof_from_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = std::fs::remove_file(&path);
+
+        // A new file has an id and nothing else
+        let aof = Aof::open(&path, AppendFsync::No).unwrap();
+        assert!(aof.is_empty());
+        let (id, start) = aof.position().unwrap();
+        assert_eq!(start, ID_LINE as u64);
+        aof.append(1, b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n")
+            .unwrap();
+        assert!(!aof.is_empty());
+        // Where a snapshot would end, the next append selects its db again
+        let (_, at) = aof.position().unwrap();
+        aof.append(1, b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n")
+            .unwrap();
+        drop(aof);
+
+        let aof = Aof::open(&path, AppendFsync::No).unwrap();
+        assert_eq!(aof.position().unwrap().0, id);
+        let dbs = dbs();
+        let stats = aof
+            .replay(ExecContext::new(dbs.clone(), 0), at)
+            .await
+            .unwrap();
+        assert_eq!(stats.commands, 2);
+        assert!(dbs[1].get(&"a".to_string()).unwrap().is_none());
+        assert!(dbs[1].get(&"b".to_string()).unwrap().is_some());
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(aof
+            .replay(ExecContext::new(dbs.clone(), 0), len + 1)
+            .await
+            .is_err());
+        drop(aof);
+
+        // A file whose id a crash tore gets a new one
+        std::fs::write(&path, b"#ID:12").unwrap();
+        let aof = Aof::open(&path, AppendFsync::No).unwrap();
+        assert!(aof.position().is_none());
+        let stats = aof
+            .replay(ExecContext::new(dbs.clone(), 0), 0)
+            .await
+            .unwrap();
+        assert_eq!(stats.truncated, 6);
+        assert!(aof.is_empty());
+        let (new, _) = aof.position().unwrap();
+        assert_ne!(new, id);
+        assert_eq!(read_id(&std::fs::read(&path).unwrap()), Some(new));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let dir = std::env::temp_dir().join(format!("foobar_aof_rewrite_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = std::fs::remove_file(&path);
+
+        let aof = Arc::new(Aof::open(&path, AppendFsync::No).unwrap());
+        let data = dbs();
+        let when = crate::db::db::now_ms() + 60_000;
+        let list = || Value::List(vec!["x".to_string(), "y".to_string()].into());
+        for i in 0..100 {
+            let set = format!(
+                "*3\r\n$3\r\nSET\r\n$1\r\na\r\n${}\r\n{}\r\n",
+                i.to_string().len(),
+                i
+            );
+            aof.append(0, set.as_bytes()).unwrap();
+        }
+        data[0]
+            .set("a".to_string(), Value::Str(b"99".to_vec()))
+            .unwrap();
+        data[1]
+            .set_with_expiry("l".to_string(), list(), when)
+            .unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        let (id, _) = aof.position().unwrap();
+        let keylocks = Arc::new(KeyLocks::default());
+        let rewrite = aof.rewrite(data.clone(), keylocks.clone()).unwrap();
+        // One at a time
+        assert!(aof.rewrite(data.clone(), keylocks.clone()).is_none());
+        let len = rewrite.await.unwrap();
+        assert!(aof.rewrite(data.clone(), keylocks.clone()).is_some());
+        assert!(len < before);
+        // A snapshot of the old file doesn't go with the new one
+        assert_ne!(aof.position().unwrap().0, id);
+        let rewritten = std::fs::read(&path).unwrap();
+        assert_eq!(rewritten.len() as u64, len);
+        let text = String::from_utf8_lossy(&rewritten);
+        assert_eq!(text.matches("RESTORE").count(), 2);
+        assert_eq!(text.matches("PEXPIREAT").count(), 1);
+        assert!(!text.contains("SET\r\n"));
+
+        // Appends go on in the new file, and it replays to the same data
+        aof.append(0, b"*2\r\n$3\r\nDEL\r\n$1\r\na\r\n").unwrap();
+        let replayed = dbs();
+        let stats = aof
+            .replay(ExecContext::new(replayed.clone(), 0), 0)
+            .await
+            .unwrap();
+        assert_eq!(stats.failed, 0);
+        assert!(replayed[0].get(&"a".to_string()).unwrap().is_none());
+        // Restored in whatever encoding fits
+        let restored = replayed[1].get(&"l".to_string()).unwrap().unwrap();
+        assert_eq!(
+            dump::serialize(&restored).unwrap(),
+            dump::serialize(&list()).unwrap()
+        );
+        assert_eq!(
+            replayed[1].expiry(&"l".to_string()).unwrap(),
+            Expiry::At(when)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let mut file = Vec::new();
+        for key in ["a", "b"] {
+            let mut record = format!("*2\r\n$3\r\nDEL\r\n$1\r\n{}\r\n", key).into_bytes();
+            seal(&mut record);
+            file.extend_from_slice(&record);
+        }
+        let whole = file.len();
+        assert_eq!(
+            check(&file),
+            AofCheck {
+                records: 2,
+                commands: 2,
+                valid: whole,
+                error: None,
+            }
+        );
+
+        // Annotations other than checksums are skipped
+        let mut annotated = b"#TS:1700000000\r\n".to_vec();
+        annotated.extend_from_slice(&file);
+        assert_eq!(check(&annotated).error, None);
+
+        // A record without its checksum yet is cut short
+        file.extend_from_slice(b"*2\r\n$3\r\nDEL\r\n$1\r\nc\r\n#CRC64:12");
+        let cut = check(&file);
+        assert_eq!((cut.records, cut.valid), (2, whole));
+        assert_eq!(cut.error.as_deref(), Some("record cut short"));
+
+        // A bad checksum is found where its record starts
+        let mut bad = file[..whole].to_vec();
+        bad[whole - 5] = if bad[whole - 5] == b'0' { b'1' } else { b'0' };
+        let check = check(&bad);
+        assert_eq!((check.records, check.valid), (1, whole / 2));
+        assert_eq!(check.error.as_deref(), Some("checksum mismatch"));
+    }
+}
@@ -0,0 +1,374 @@
+// BIGKEYS: the largest keys of each type in a sample of the keyspace, the
+// ones far bigger than the rest, and what to do about them. The sample is
+// the first keys of a SCAN, which come in the order of a hash of the key,
+// and keys are sized the way namespace memory quotas measure them.
+use crate::db::db::DB;
+use crate::db::listpack;
+use crate::db::storage::Storage;
+use crate::db::types::{Typed, ValueType};
+use crate::db::value::Value;
+use crate::server::namespace::entry_size;
+use crate::server::timeout::Budget;
+use anyhow::Error;
+use std::fmt;
+
+pub const DEFAULT_SAMPLES: usize = 10_000;
+
+// Largest keys listed per type when no COUNT is given
+pub const DEFAULT_COUNT: usize = 3;
+
+// Elements past which a collection makes commands over all of it slow
+const BIG_ELEMENTS: usize = 10_000;
+
+// Bytes past which a string is slow to read or write whole
+const BIG_STRING: usize = 1024 * 1024;
+
+// A key is an outlier at this many times the median key of the sample,
+// once it is at least OUTLIER_MIN_BYTES
+const OUTLIER_FACTOR: usize = 10;
+const OUTLIER_MIN_BYTES: usize = 1024;
+
+const TYPES: &[ValueType] = &[
+    ValueType::String,
+    ValueType::List,
+    ValueType::Set,
+    ValueType::ZSet,
+    ValueType::Hash,
+    ValueType::Stream,
+    #[cfg(feature = "json")]
+    ValueType::Json,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStat {
+    pub key: String,
+    pub value_type: ValueType,
+    // Bytes of a string, elements of anything else
+    pub elements: usize,
+    pub bytes: usize,
+}
+
+impl KeyStat {
+    fn of(key: String, value: &Value) -> Self {
+        let full = listpack::expand(value);
+        let elements = match full.as_ref().unwrap_or(value) {
+            Value::Str(bytes) => bytes.len(),
+            Value::Listpack(packed) => packed.len(),
+            Value::Compressed(compressed) => compressed.len(),
+            Value::List(items) => items.len(),
+            Value::Set(items) => items.len(),
+            Value::Hash(pairs) => pairs.len(),
+            Value::ZSet(pairs) => pairs.len(),
+            Value::Stream(entries) => entries.len(),
+            // Sized as its text, like a string
+            #[cfg(feature = "json")]
+            Value::Json(doc) => doc.to_string().len(),
+        };
+        Self {
+            bytes: entry_size(&key, value),
+            value_type: value.value_type(),
+            key,
+            elements,
+        }
+    }
+
+    fn unit(&self) -> &'static str {
+        match self.value_type {
+            ValueType::String => "bytes",
+            ValueType::Hash => "fields",
+            ValueType::Set | ValueType::ZSet => "members",
+            ValueType::List => "elements",
+            ValueType::Stream => "entries",
+            #[cfg(feature = "json")]
+            ValueType::Json => "bytes",
+        }
+    }
+
+    fn is_big(&self) -> bool {
+        match self.value_type {
+            ValueType::String => self.elements > BIG_STRING,
+            #[cfg(feature = "json")]
+            ValueType::Json => self.elements > BIG_STRING,
+            _ => self.elements > BIG_ELEMENTS,
+        }
+    }
+
+    // What to do about a key this big
+    fn advice(&self) -> String {
+        let how = match self.value_type {
+            ValueType::String => "reading or writing it whole is slow, split it into smaller keys",
+            ValueType::Hash => {
+                "split it into smaller hashes and read it with HSCAN rather than HGETALL"
+            }
+            ValueType::Set => {
+                "split it into smaller sets and read it with SSCAN rather than SMEMBERS"
+            }
+            ValueType::ZSet => {
+                "split it into smaller sorted sets and read it by ranges or with ZSCAN"
+            }
+            ValueType::List => "split it into smaller lists and read it by ranges of LRANGE",
+            ValueType::Stream => "cap it with XTRIM or XADD MAXLEN",
+            #[cfg(feature = "json")]
+            ValueType::Json => "read and write the parts of it needed by path rather than whole",
+        };
+        format!(
+            "{} \"{}\" has {} {}: {}, and delete it with UNLINK so it is freed in the background",
+            self.value_type.name(),
+            self.key,
+            self.elements,
+            self.unit(),
+            how
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeStats {
+    pub value_type: ValueType,
+    pub keys: usize,
+    pub bytes: usize,
+    // Biggest first
+    pub largest: Vec<KeyStat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub sampled: usize,
+    pub keys: usize,
+    pub bytes: usize,
+    // Types with keys in the sample only
+    pub types: Vec<TypeStats>,
+    // Biggest first, with how many times the median key each is
+    pub outliers: Vec<(KeyStat, usize)>,
+    pub advice: Vec<String>,
+}
+
+// Samples up to samples keys of db, every key when 0, listing the count
+// largest of each type. Gives up once the budget is spent.
+pub fn scan<S>(
+    db: &DB<S, String, Value>,
+    samples: usize,
+    count: usize,
+    budget: Budget,
+) -> Result<Report, Error>
+where
+    S: Storage<String, Value>,
+{
+    let samples = if samples == 0 { usize::MAX } else { samples };
+    let (_, mut keys) = db.scan_checked(0, samples, |step| Ok(budget.check(step)?))?;
+    keys.truncate(samples);
+    let mut stats: Vec<KeyStat> = Vec::with_capacity(keys.len());
+    for (step, key) in keys.into_iter().enumerate() {
+        budget.check(step)?;
+        // Gone since the scan
+        if let Some(value) = db.peek(&key)? {
+            stats.push(KeyStat::of(key, &value));
+        }
+    }
+    stats.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+
+    let types = TYPES
+        .iter()
+        .filter_map(|value_type| {
+            let of_type: Vec<&KeyStat> = stats
+                .iter()
+                .filter(|stat| stat.value_type == *value_type)
+                .collect();
+            (!of_type.is_empty()).then(|| TypeStats {
+                value_type: *value_type,
+                keys: of_type.len(),
+                bytes: of_type.iter().map(|stat| stat.bytes).sum(),
+                largest: of_type.into_iter().take(count).cloned().collect(),
+            })
+        })
+        .collect();
+
+    let median = stats
+        .get(stats.len() / 2)
+        .map_or(0, |stat| stat.bytes)
+        .max(1);
+    let outliers: Vec<(KeyStat, usize)> = stats
+        .iter()
+        .take_while(|stat| stat.bytes >= OUTLIER_FACTOR * median)
+        .filter(|stat| stat.bytes >= OUTLIER_MIN_BYTES)
+        .take(count)
+        .map(|stat| (stat.clone(), stat.bytes / median))
+        .collect();
+
+    let bytes: usize = stats.iter().map(|stat| stat.bytes).sum();
+    let mut advice: Vec<String> = stats
+        .iter()
+        .filter(|stat| stat.is_big())
+        .map(KeyStat::advice)
+        .collect();
+    let outlying: usize = stats
+        .iter()
+        .take_while(|stat| stat.bytes >= OUTLIER_FACTOR * median)
+        .filter(|stat| stat.bytes >= OUTLIER_MIN_BYTES)
+        .map(|stat| stat.bytes)
+        .sum();
+    if outlying * 2 > bytes {
+        advice.push(
+            "a few keys hold most of the memory sampled: spread their data over more keys \
+             so no single command or eviction has to handle that much at once"
+                .to_string(),
+        );
+    }
+    Ok(Report {
+        sampled: stats.len(),
+        keys: db.len(),
+        bytes,
+        types,
+        outliers,
+        advice,
+    })
+}
+
+// Bytes the way Redis writes them for people: 1023B, 1.50K, 2.00M
+fn human(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", size, UNITS[unit])
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sampled {} of {} keys, {}",
+            self.sampled,
+            self.keys,
+            human(self.bytes)
+        )?;
+        if self.sampled == 0 {
+            return Ok(());
+        }
+        writeln!(f, "Largest keys by type:")?;
+        for stats in &self.types {
+            writeln!(
+                f,
+                "  {}: {} keys, {}",
+                stats.value_type.name(),
+                stats.keys,
+                human(stats.bytes)
+            )?;
+            for stat in &stats.largest {
+                writeln!(
+                    f,
+                    "    \"{}\" {} {}, {}",
+                    stat.key,
+                    stat.elements,
+                    stat.unit(),
+                    human(stat.bytes)
+                )?;
+            }
+        }
+        if !self.outliers.is_empty() {
+            writeln!(f, "Outliers:")?;
+            for (stat, times) in &self.outliers {
+                writeln!(
+                    f,
+                    "  \"{}\" {}, {}x the median key",
+                    stat.key,
+                    human(stat.bytes),
+                    times
+                )?;
+            }
+        }
+        if self.advice.is_empty() {
+            writeln!(f, "No big keys found in the sample.")
+        } else {
+            writeln!(f, "Advice:")?;
+            for advice in &self.advice {
+                writeln!(f, "  {}", advice)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+    use crate::protocal::command::CommandError;
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human(0), "0B");
+        assert_eq!(human(1023), "1023B");
+        assert_eq!(human(1536), "1.50K");
+        assert_eq!(human(3 * 1024 * 1024), "3.00M");
+    }
+
+    #[test]
+    fn test_scan() {
+        let db = DB::new(DashMapStorage::new(), 0);
+        for i in 0..50 {
+            db.set(format!("k{}", i), Value::str("v")).unwrap();
+        }
+        db.set("long".to_string(), Value::str("v".repeat(100)))
+            .unwrap();
+        let fields = (0..BIG_ELEMENTS + 1)
+            .map(|i| (format!("f{}", i), "v".to_string()))
+            .collect();
+        db.set("big".to_string(), Value::Hash(fields)).unwrap();
+        db.set(
+            "small".to_string(),
+            Value::Hash([("f".to_string(), "v".to_string())].into()),
+        )
+        .unwrap();
+
+        let report = scan(&db, 0, 1, Budget::unlimited()).unwrap();
+        assert_eq!((report.sampled, report.keys), (53, 53));
+        let types: Vec<_> = report
+            .types
+            .iter()
+            .map(|stats| (stats.value_type, stats.keys, stats.largest[0].key.as_str()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![(ValueType::String, 51, "long"), (ValueType::Hash, 2, "big")]
+        );
+        assert_eq!(report.types[1].largest.len(), 1);
+        assert_eq!(report.types[1].largest[0].elements, BIG_ELEMENTS + 1);
+        assert_eq!(report.outliers.len(), 1);
+        assert_eq!(report.outliers[0].0.key, "big");
+        // The hash is too big, and most of the memory
+        assert_eq!(report.advice.len(), 2);
+        assert!(report.advice[0].starts_with("hash \"big\" has 10001 fields: "));
+
+        let text = report.to_string();
+        assert!(text.starts_with("Sampled 53 of 53 keys, "));
+        assert!(text.contains("  hash: 2 keys, "));
+        assert!(text.contains("    \"big\" 10001 fields, "));
+        assert!(text.contains("Outliers:\n  \"big\" "));
+
+        // Sampling and not counting as access
+        let report = scan(&db, 10, DEFAULT_COUNT, Budget::unlimited()).unwrap();
+        assert_eq!(report.sampled, 10);
+        assert!(db.hot_keys(1).is_empty());
+
+        // A spent budget stops the walk
+        let spent = Budget::until(std::time::Instant::now());
+        let error = scan(&db, 0, 1, spent).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CommandError>(),
+            Some(CommandError::Timeout)
+        ));
+
+        let empty = DB::new(DashMapStorage::new(), 0);
+        assert_eq!(
+            scan(&empty, 0, 1, Budget::unlimited()).unwrap().to_string(),
+            "Sampled 0 of 0 keys, 0B\n"
+        );
+    }
+}
@@ -1,83 +1,507 @@
 #![warn(unused_imports)]
-use anyhow::Result;
-use bytes::{Buf, BytesMut};
-use socket2::Socket;
-use std::sync::Arc;
-use std::time::Duration;
+use anyhow::anyhow;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::{self, FutureExt, Shared};
 use stream_resp::parser::Parser;
 use stream_resp::resp::RespValue;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tracing::error;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{error, info, trace, warn};
 
 const INITIAL_BUFFER_SIZE: usize = 4096;
 const MAX_BATCH_SIZE: usize = 1024;
 
 use crate::{
-    db::{db::DB, storage::DashMapStorage},
-    protocal::command::Command,
+    db::{
+        backend::{self, Backend},
+        db::{self, Databases},
+        rdb,
+        value::Value,
+    },
+    protocal::command::{Command, CommandError, ExecContext},
+    protocal::reply,
+    protocal::request::{self, RequestLimits, MAX_DEPTH},
+    protocal::table::{self, CommandSpec},
+    server::clients::{ClientHandle, ClientRegistry, OUTPUT_QUEUE_LEN},
+    server::cmdstats::CommandStats,
+    server::keylock::KeyLocks,
+    server::latency::{LatencyMonitor, EVENT_COMMAND},
+    server::loading::Loading,
+    server::namespace::{Namespace, Namespaces},
+    server::panics::Panics,
+    server::propagate::{Effects, Propagator},
+    server::pubsub::PubSub,
+    server::ratelimit::RateLimiter,
+    server::replication::Replication,
+    server::shard::ShardPool,
+    server::shutdown::Shutdown,
+    server::tasks::TaskManager,
+    server::timeout::CommandTimeout,
+    server::tracking::Tracking,
 };
 
-pub struct ClientConn {
-    reader: tokio::io::BufReader<tokio::io::ReadHalf<TcpStream>>,
-    writer: BufWriter<tokio::io::WriteHalf<TcpStream>>,
-    db: Arc<DB<DashMapStorage<String, RespValue<'static>>, String, RespValue<'static>>>,
+#[cfg(feature = "otel")]
+use crate::server::otel;
+#[cfg(feature = "search")]
+use crate::server::search::Search;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Ids handed out to connections, as reported by HELLO
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+// All a RESP2 client takes while it has subscriptions
+const SUBSCRIBER_COMMANDS: [&str; 7] = [
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "ping",
+    "quit",
+    "reset",
+];
+
+// Resolves once the command it belongs to has finished
+type Done = Shared<oneshot::Receiver<()>>;
+
+// A parsed request, with its bytes when it is a write to stream to replicas
+type Request = (
+    Result<Command, anyhow::Error>,
+    Option<&'static CommandSpec>,
+    Option<Bytes>,
+);
+
+// The table entry of the command the request names. Read-only commands have
+// their keys remembered by CLIENT TRACKING, and the name goes in the logs.
+pub(crate) fn spec_of(resp: &RespValue) -> Option<&'static CommandSpec> {
+    match resp {
+        RespValue::Array(Some(items)) => match items.first() {
+            Some(RespValue::BulkString(Some(name)) | RespValue::SimpleString(name)) => {
+                table::lookup(name)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Slot a key takes in the batch ordering. Collisions only make two commands
+// run one after the other.
+fn key_slot(db_index: usize, key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (db_index, key).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Writes one queued frame and whatever else is already queued behind it, so
+// a pipeline and the pushes around it go out in one flush. False once the
+// connection has nothing more to write to.
+async fn write_frames(
+    writer: &mut BufWriter<WriteHalf<TcpStream>>,
+    output: &mut mpsc::Receiver<BytesMut>,
+) -> bool {
+    let Some(mut frame) = output.recv().await else {
+        return false;
+    };
+    loop {
+        if writer.write_all(&frame).await.is_err() {
+            return false;
+        }
+        match output.try_recv() {
+            Ok(next) => frame = next,
+            Err(_) => break,
+        }
+    }
+    writer.flush().await.is_ok()
+}
+
+// The only task writing to the socket. It runs until every sender is gone,
+// writing out what is left, or until the client is killed. Shutting down the
+// write side then tells a client that half-closed its own side that nothing
+// more is coming.
+async fn write_loop(
+    mut writer: BufWriter<WriteHalf<TcpStream>>,
+    mut output: mpsc::Receiver<BytesMut>,
+    mut killed: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+            Ok(_) = killed.wait_for(|killed| *killed) => break,
+            written = write_frames(&mut writer, &mut output) => {
+                if !written {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+// Where a connection reads its requests from. Everything after the read,
+// from the parser on, is the same whatever the socket is driven by.
+pub trait Input {
+    // Appends what arrived to buf, 0 once the client closed its side
+    fn read_into(&mut self, buf: &mut BytesMut) -> impl Future<Output = io::Result<usize>>;
+}
+
+// The read half of a socket on the tokio reactor, epoll on Linux
+pub type TcpInput = BufReader<ReadHalf<TcpStream>>;
+
+impl Input for TcpInput {
+    async fn read_into(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        self.read_buf(buf).await
+    }
+}
+
+pub struct ClientConn<I = TcpInput> {
+    input: I,
+    // Replies go through the writer task, in the same queue as pushes
+    output: mpsc::Sender<BytesMut>,
+    handle: ClientHandle,
+    killed: watch::Receiver<bool>,
+    clients: Arc<ClientRegistry>,
+    tracking: Arc<Tracking>,
+    pubsub: Arc<PubSub>,
+    keylocks: Arc<KeyLocks>,
+    // Writes are sent to the AOF and replicas here before they are answered
+    propagator: Arc<Propagator>,
+    shutdown: Arc<Shutdown>,
+    // Channels and patterns subscribed to, RESP2 connections with any are
+    // in subscriber mode
+    subscriptions: usize,
+    dbs: Databases<Backend, String, Value>,
+    // The server's own databases, dbs while not in a namespace
+    home: Databases<Backend, String, Value>,
+    namespaces: Arc<Namespaces<Backend>>,
+    // Where the authenticated user works, if it has a namespace
+    namespace: Option<Arc<Namespace<Backend>>>,
+    db_index: usize,
+    id: u64,
+    protocol: u8,
+    latency: Arc<LatencyMonitor>,
+    ratelimit: Arc<RateLimiter>,
+    timeout: Arc<CommandTimeout>,
+    tasks: Arc<TaskManager>,
+    panics: Arc<Panics>,
+    cmdstats: Arc<CommandStats>,
+    loading: Arc<Loading>,
+    replication: Arc<Replication>,
+    // User the connection authenticated as with AUTH or HELLO AUTH
+    user: String,
+    shards: Option<Arc<ShardPool>>,
+    #[cfg(feature = "search")]
+    search: Arc<Search<Backend>>,
     parser: Parser,
+    limits: RequestLimits,
+    // How far the request still arriving was checked
+    scan: request::Scan,
     peer_addr: std::net::SocketAddr,
-    read_buf: BytesMut,
     write_buf: BytesMut,
 }
 
 impl ClientConn {
     pub fn new(
         stream: TcpStream,
-        db: Arc<DB<DashMapStorage<String, RespValue<'static>>, String, RespValue<'static>>>,
+        dbs: Databases<Backend, String, Value>,
+        latency: Arc<LatencyMonitor>,
     ) -> Self {
         // 优化TCP配置
         stream.set_nodelay(true).unwrap();
         let addr = stream.peer_addr().unwrap();
         let (rd, wr) = tokio::io::split(stream);
-        let reader = tokio::io::BufReader::with_capacity(INITIAL_BUFFER_SIZE, rd);
+        let reader = BufReader::with_capacity(INITIAL_BUFFER_SIZE, rd);
         let writer = BufWriter::with_capacity(INITIAL_BUFFER_SIZE, wr);
+        let (output, rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
+        let conn = Self::with_input(reader, addr, output, dbs, latency);
+        tokio::spawn(write_loop(writer, rx, conn.killed()));
+        conn
+    }
+}
+
+impl<I: Input> ClientConn<I> {
+    // A connection reading requests from input and queueing its replies on
+    // output. The caller runs the task taking them off the queue and writing
+    // them to the client, until killed() says to stop.
+    pub fn with_input(
+        input: I,
+        addr: std::net::SocketAddr,
+        output: mpsc::Sender<BytesMut>,
+        dbs: Databases<Backend, String, Value>,
+        latency: Arc<LatencyMonitor>,
+    ) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let handle = ClientHandle::new(id, addr, output.clone());
+        let killed = handle.killed();
+        let clients = Arc::new(ClientRegistry::default());
+        clients.register(handle.clone());
+        let tracking = Arc::new(Tracking::new(clients.clone()));
+        let pubsub = Arc::new(PubSub::new(clients.clone()));
 
         Self {
-            reader,
-            writer,
-            db,
-            parser: Parser::new(10, 1024),
+            input,
+            output,
+            handle,
+            killed,
+            clients,
+            tracking,
+            pubsub,
+            keylocks: Arc::new(KeyLocks::default()),
+            propagator: Arc::new(Propagator::default()),
+            shutdown: Arc::new(Shutdown::default()),
+            subscriptions: 0,
+            home: dbs.clone(),
+            dbs,
+            namespaces: Arc::new(Namespaces::default()),
+            namespace: None,
+            db_index: 0,
+            id,
+            protocol: 2,
+            latency,
+            ratelimit: Arc::new(RateLimiter::default()),
+            timeout: Arc::new(CommandTimeout::default()),
+            tasks: Arc::new(TaskManager::default()),
+            panics: Arc::new(Panics::default()),
+            cmdstats: Arc::new(CommandStats::default()),
+            loading: Arc::new(Loading::default()),
+            replication: Arc::new(Replication::default()),
+            user: "default".to_string(),
+            shards: None,
+            #[cfg(feature = "search")]
+            search: Arc::new(Search::default()),
+            parser: Parser::new(MAX_DEPTH, RequestLimits::default().max_bytes + 1),
+            limits: RequestLimits::default(),
+            scan: request::Scan::default(),
             peer_addr: addr,
-            read_buf: BytesMut::with_capacity(INITIAL_BUFFER_SIZE),
             write_buf: BytesMut::with_capacity(INITIAL_BUFFER_SIZE),
         }
     }
 
-    async fn write_response(&mut self, response: &[u8]) -> Result<()> {
-        self.writer.write_all(response).await?;
-        self.writer.flush().await?;
-        Ok(())
+    // Flips to true when the client is killed
+    pub fn killed(&self) -> watch::Receiver<bool> {
+        self.handle.killed()
     }
 
-    #[inline(always)]
+    // The id CLIENT ID and HELLO report
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Hands keyed commands to the server's shard workers
+    pub fn with_shards(mut self, shards: Option<Arc<ShardPool>>) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    pub fn with_ratelimit(mut self, ratelimit: Arc<RateLimiter>) -> Self {
+        self.ratelimit = ratelimit;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Arc<CommandTimeout>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_tasks(mut self, tasks: Arc<TaskManager>) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn with_panics(mut self, panics: Arc<Panics>) -> Self {
+        self.panics = panics;
+        self
+    }
+
+    pub fn with_cmdstats(mut self, cmdstats: Arc<CommandStats>) -> Self {
+        self.cmdstats = cmdstats;
+        self
+    }
+
+    #[cfg(feature = "search")]
+    pub fn with_search(mut self, search: Arc<Search<Backend>>) -> Self {
+        self.search = search;
+        self
+    }
+
+    // Commands without the `loading` flag are refused while the server loads
+    pub fn with_loading(mut self, loading: Arc<Loading>) -> Self {
+        self.loading = loading;
+        self
+    }
+
+    pub fn with_replication(mut self, replication: Arc<Replication>) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    // Makes the connection reachable from the server's other clients
+    pub fn with_clients(mut self, clients: Arc<ClientRegistry>) -> Self {
+        self.clients.unregister(self.id);
+        clients.register(self.handle.clone());
+        self.clients = clients;
+        self
+    }
+
+    // Users with a namespace of their own, who AUTH moves into it
+    pub fn with_namespaces(mut self, namespaces: Arc<Namespaces<Backend>>) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    // Shares the server's tracking table for CLIENT TRACKING
+    pub fn with_tracking(mut self, tracking: Arc<Tracking>) -> Self {
+        self.tracking = tracking;
+        self
+    }
+
+    // Shares the server's subscriptions, so PUBLISH reaches every client
+    pub fn with_pubsub(mut self, pubsub: Arc<PubSub>) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    // Shares the server's key locks, so compound operations hold their keys
+    // against every client
+    pub fn with_keylocks(mut self, keylocks: Arc<KeyLocks>) -> Self {
+        self.keylocks = keylocks;
+        self
+    }
+
+    pub fn with_propagator(mut self, propagator: Arc<Propagator>) -> Self {
+        self.propagator = propagator;
+        self
+    }
+
+    // Where SHUTDOWN asks the server to stop
+    pub fn with_shutdown(mut self, shutdown: Arc<Shutdown>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: RequestLimits) -> Self {
+        self.parser = Parser::new(MAX_DEPTH, limits.max_bytes.saturating_add(1));
+        self.limits = limits;
+        self
+    }
+
+    // Serves the client until it disconnects or is killed
     pub async fn handle_connection(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut killed = self.killed.clone();
+        tokio::select! {
+            res = self.serve() => res,
+            Ok(_) = killed.wait_for(|killed| *killed) => Ok(()),
+        }
+    }
+
+    // Queues bytes for the writer task, waiting while the client is behind
+    async fn send(&self, frame: BytesMut) -> Result<(), Box<dyn std::error::Error>> {
+        self.output
+            .send(frame)
+            .await
+            .map_err(|_| "connection closed".into())
+    }
+
+    #[inline(always)]
+    async fn serve(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
 
         loop {
-            match self.reader.read_buf(&mut self.parser.buffer).await {
+            match self.input.read_into(&mut self.parser.buffer).await {
                 Ok(0) => break,
                 Ok(_) => {
-                    while let Ok(Some(resp)) = self.parser.try_parse() {
-                        if let Ok(cmd) = Command::from_resp(resp) {
-                            batch.push(cmd);
-
-                            if batch.len() >= MAX_BATCH_SIZE {
-                                self.execute_batch(&mut batch).await?;
+                    // Only requests that arrived in full and within the
+                    // limits reach the parser
+                    let mut consumed = 0;
+                    let mut quit = false;
+                    let refused = loop {
+                        let start = consumed;
+                        let buffer = &self.parser.buffer[consumed..];
+                        match self.scan.check(buffer, &self.limits) {
+                            Ok(Some(len)) => consumed += len,
+                            Ok(None) => break None,
+                            Err(e) => break Some(e),
+                        }
+                        let resp = match request::parse(&mut self.parser) {
+                            Ok(resp) => resp,
+                            Err(e) => break Some(e),
+                        };
+                        // Empty requests are skipped without a reply, like
+                        // in Redis. Any other request that isn't a valid
+                        // command still gets its error reply in order.
+                        if matches!(&resp, RespValue::Array(None))
+                            || matches!(&resp, RespValue::Array(Some(items)) if items.is_empty())
+                        {
+                            continue;
+                        }
+                        let spec = spec_of(&resp);
+                        let cmd = match Command::from_resp(resp) {
+                            Ok(cmd @ (Command::Psync { .. } | Command::ReplConfAck { .. })) => {
+                                if !batch.is_empty() {
+                                    self.execute_batch(&mut batch).await?;
+                                }
+                                self.replica_link(cmd, spec).await?;
+                                continue;
+                            }
+                            Ok(cmd @ (Command::Subscribe { .. } | Command::Unsubscribe { .. })) => {
+                                if !batch.is_empty() {
+                                    self.execute_batch(&mut batch).await?;
+                                }
+                                self.subscription(cmd, spec).await?;
+                                continue;
                             }
+                            Ok(cmd @ Command::Ping) if self.subscriber_mode() => {
+                                if !batch.is_empty() {
+                                    self.execute_batch(&mut batch).await?;
+                                }
+                                self.subscription(cmd, spec).await?;
+                                continue;
+                            }
+                            cmd => cmd,
+                        };
+                        // Most writes go on to replicas and the AOF as the
+                        // client sent them
+                        let raw = (self.propagator.active()
+                            && spec.is_some_and(|spec| spec.is_write()))
+                        .then(|| Bytes::copy_from_slice(&self.parser.buffer[start..consumed]));
+                        // Whatever follows QUIT is never run
+                        quit = matches!(cmd, Ok(Command::Quit));
+                        batch.push((cmd, spec, raw));
+                        if quit {
+                            break None;
                         }
-                    }
+                        if batch.len() >= MAX_BATCH_SIZE {
+                            self.execute_batch(&mut batch).await?;
+                        }
+                    };
 
                     if !batch.is_empty() {
                         self.execute_batch(&mut batch).await?;
                     }
+                    // The writer flushes the +OK before the socket closes
+                    if quit {
+                        return Ok(());
+                    }
+
+                    // Like Redis, a protocol error is answered and the
+                    // connection closed, the stream can't be followed anymore
+                    if let Some(e) = refused {
+                        self.send(BytesMut::from(format!("-ERR {}\r\n", e).as_bytes()))
+                            .await?;
+                        return Ok(());
+                    }
+
+                    // Parsed requests leave the buffer so it doesn't grow
+                    // with the life of the connection
+                    self.parser.buffer.advance(consumed);
+                    self.parser.clear_buffer(0);
                 }
                 Err(e) => {
                     error!("Read error from {}: {}", self.peer_addr, e);
@@ -89,39 +513,427 @@ impl ClientConn {
     }
 
     #[inline(always)]
+    // Back to the state of a fresh connection, as connection pools expect
+    // after RESET
+    fn reset(&mut self) {
+        self.db_index = 0;
+        self.protocol = 2;
+        self.handle.set_protocol(2);
+        // RESET's own run drops the subscriptions
+        self.subscriptions = 0;
+        self.login("default".to_string(), None);
+    }
+
+    // Works as user from the next command on, in its namespace if it has
+    // one. The selected db only carries over outside namespaces.
+    fn login(&mut self, user: String, namespace: Option<Arc<Namespace<Backend>>>) {
+        if namespace.is_some() || self.namespace.is_some() {
+            self.db_index = 0;
+        }
+        self.dbs = match &namespace {
+            Some(namespace) => namespace.dbs.clone(),
+            None => self.home.clone(),
+        };
+        self.namespace = namespace;
+        self.user = user;
+    }
+
+    // Namespace quotas only hold back writes that may grow the data
+    fn over_quota(
+        &self,
+        spec: Option<&'static CommandSpec>,
+        cmd: &Command,
+    ) -> Option<CommandError> {
+        let namespace = self.namespace.as_ref()?;
+        if !spec?.denies_oom() {
+            return None;
+        }
+        namespace.refusal(self.db_index, cmd.keys())
+    }
+
+    // Why the command can't run now, from its flags and the server's state.
+    // Commands the table doesn't know are let through to fail on their own.
+    fn refusal(&self, spec: Option<&'static CommandSpec>) -> Option<CommandError> {
+        if !self.ratelimit.allow(self.id, &self.user) {
+            return Some(CommandError::RateLimited);
+        }
+        let spec = spec?;
+        if self.subscriber_mode() && !SUBSCRIBER_COMMANDS.contains(&spec.name) {
+            Some(CommandError::SubscriberMode(spec.name.to_string()))
+        } else if self.loading.is_loading() && !spec.allows_loading() {
+            Some(CommandError::Loading)
+        } else if self.replication.refuses_writes() && spec.is_write() {
+            Some(CommandError::ReadOnly)
+        } else {
+            None
+        }
+    }
+
+    // RESP3 clients go on taking every command while subscribed
+    fn subscriber_mode(&self) -> bool {
+        self.subscriptions > 0 && self.protocol < 3
+    }
+
+    // (P)SUBSCRIBE and (P)UNSUBSCRIBE, whose confirmations are queued by
+    // the subscriptions themselves, ahead of any message they let in. The
+    // batch before them has to be out already. PING in subscriber mode is
+    // answered here as well, as an array like every other reply then.
+    async fn subscription(
+        &mut self,
+        cmd: Command,
+        spec: Option<&'static CommandSpec>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let name = spec.map_or("unknown", |spec| spec.name);
+        if let Some(e) = self.refusal(spec) {
+            self.cmdstats.reject(name);
+            return self
+                .send(BytesMut::from(
+                    format!("-{} {}\r\n", e.kind(), e).as_bytes(),
+                ))
+                .await;
+        }
+        let start = Instant::now();
+        match cmd {
+            Command::Subscribe { kind, names } => {
+                self.subscriptions = self.pubsub.subscribe(&self.handle, kind, &names);
+            }
+            Command::Unsubscribe { kind, names } => {
+                self.subscriptions = self.pubsub.unsubscribe(&self.handle, kind, &names);
+            }
+            _ => {
+                let mut frame = BytesMut::new();
+                reply::encode(
+                    &RespValue::Array(Some(vec![
+                        RespValue::BulkString(Some("pong".into())),
+                        RespValue::BulkString(Some("".into())),
+                    ])),
+                    &mut frame,
+                );
+                self.send(frame).await?;
+            }
+        }
+        self.cmdstats.record(name, start.elapsed(), false);
+        Ok(())
+    }
+
+    // PSYNC and REPLCONF ACK, answered on the replication stream instead of
+    // in the reply flow: PSYNC's answer has to go out ahead of the writes
+    // streamed after it, and an ACK gets no reply at all
+    async fn replica_link(
+        &mut self,
+        cmd: Command,
+        spec: Option<&'static CommandSpec>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Command::Psync { replid, offset } = cmd else {
+            if let Command::ReplConfAck { offset } = cmd {
+                self.replication.ack(self.id, offset);
+            }
+            return Ok(());
+        };
+        let refused = self.refusal(spec).or_else(|| {
+            self.replication
+                .master()
+                .map(|_| CommandError::NoMasterLink)
+        });
+        let Some(e) = refused else {
+            let dbs = self.home.clone();
+            let frozen = self
+                .replication
+                .psync(self.handle.clone(), &replid, offset, || db::snapshot(&dbs));
+            info!(
+                "Replica {} attached, {} resync",
+                self.peer_addr,
+                if frozen.is_some() { "full" } else { "partial" }
+            );
+            if let Some(snapshots) = frozen {
+                // Serialized off the runtime while writes carry on, the
+                // backlog keeps them for the replica in the meantime
+                let replication = self.replication.clone();
+                let id = self.id;
+                tokio::task::spawn_blocking(move || {
+                    let rdb = rdb::save(&snapshots);
+                    drop(snapshots);
+                    replication.full_sync(id, rdb);
+                });
+            }
+            return Ok(());
+        };
+        let error = anyhow!(e);
+        let kind = error
+            .downcast_ref::<CommandError>()
+            .map_or("ERR", |e| e.kind());
+        self.send(BytesMut::from(
+            format!("-{} {}\r\n", kind, error).as_bytes(),
+        ))
+        .await
+    }
+
     async fn execute_batch(
         &mut self,
-        batch: &mut Vec<Command>,
+        batch: &mut Vec<Request>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut futures = Vec::with_capacity(batch.len());
+        // Per request in order, the error of one that didn't parse
+        let mut failed = Vec::with_capacity(batch.len());
+        // Commands on disjoint keys run concurrently, ones sharing a key wait
+        // for the previous command on it so a pipeline sees its own writes
+        let mut last_on_key: HashMap<u64, Done> = HashMap::new();
+        let mut since_barrier: Vec<Done> = Vec::new();
+        let mut barrier: Option<Done> = None;
 
         // 并发执行命令
-        for cmd in batch.drain(..) {
-            futures.push(cmd.exec(self.db.clone()));
+        for (cmd, spec, raw) in batch.drain(..) {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                // Nothing to run, and nothing waits on it
+                Err(e) => {
+                    trace!("Request refused: {}", e);
+                    if let Some(spec) = spec {
+                        self.cmdstats.reject(spec.name);
+                    }
+                    failed.push(Some(e));
+                    continue;
+                }
+            };
+            trace!("Command {:?}", cmd);
+            let name = spec.map_or("unknown", |spec| spec.name);
+            let read = spec.is_some_and(|spec| spec.is_readonly());
+            failed.push(None);
+            // A refused command still takes its place in the reply order
+            let refused = self.refusal(spec).or_else(|| self.over_quota(spec, &cmd));
+            let allowed = refused.is_none();
+            let (done_tx, done_rx) = oneshot::channel();
+            let done = done_rx.shared();
+            let deps: Vec<Done> = match cmd.keys() {
+                Some(keys) => {
+                    let mut slots: Vec<u64> = keys
+                        .iter()
+                        .map(|key| key_slot(self.db_index, key))
+                        .collect();
+                    // A key named twice must not make the command wait on itself
+                    slots.sort_unstable();
+                    slots.dedup();
+                    let deps = slots
+                        .iter()
+                        .filter_map(|slot| last_on_key.insert(*slot, done.clone()))
+                        .chain(barrier.clone())
+                        .collect();
+                    since_barrier.push(done);
+                    deps
+                }
+                // Reaches outside the selected db: wait for everything
+                // before it and hold back everything after it
+                None => {
+                    last_on_key.clear();
+                    let deps = since_barrier.drain(..).chain(barrier.take()).collect();
+                    barrier = Some(done);
+                    deps
+                }
+            };
+
+            // SELECT, RESET and HELLO take effect for the commands queued
+            // after them
+            let selected = match cmd {
+                Command::Select { db } if allowed && db < self.dbs.len() => Some(db),
+                _ => None,
+            };
+            let reset = allowed && matches!(cmd, Command::Reset);
+            let hello = match &cmd {
+                Command::Hello { protover, auth } if allowed => {
+                    Command::hello_protocol(*protover, self.protocol)
+                        .ok()
+                        .and_then(|protocol| match auth {
+                            None => Some((protocol, None)),
+                            Some((user, password)) => self
+                                .namespaces
+                                .authenticate(user, password)
+                                .ok()
+                                .map(|namespace| (protocol, Some((user.clone(), namespace)))),
+                        })
+                }
+                _ => None,
+            };
+            let auth = match &cmd {
+                Command::Auth { user, password } if allowed => self
+                    .namespaces
+                    .authenticate(user, password)
+                    .ok()
+                    .map(|namespace| (user.clone(), namespace)),
+                _ => None,
+            };
+            // Namespaces are neither replicated, logged nor handed to the
+            // shards, which only know the server's databases
+            let home = self.namespace.is_none();
+            let ctx = ExecContext::new(self.dbs.clone(), self.db_index)
+                .with_latency(self.latency.clone())
+                .with_ratelimit(self.ratelimit.clone())
+                .with_timeout(self.timeout.clone())
+                .with_tasks(self.tasks.clone())
+                .with_panics(self.panics.clone())
+                .with_cmdstats(self.cmdstats.clone())
+                .with_clients(self.clients.clone())
+                .with_tracking(self.tracking.clone())
+                .with_pubsub(self.pubsub.clone())
+                .with_keylocks(self.keylocks.clone())
+                .with_shutdown(self.shutdown.clone())
+                .with_loading(self.loading.clone())
+                .with_replication(self.replication.clone())
+                .with_namespaces(self.namespaces.clone())
+                .with_client(self.id, self.protocol);
+            let ctx = match home {
+                true => ctx.with_propagator(self.propagator.clone()),
+                false => ctx,
+            };
+            #[cfg(feature = "search")]
+            let ctx = ctx.with_search(self.search.clone());
+            let latency = (!cmd.is_blocking()).then(|| self.latency.clone());
+            let cmdstats = self.cmdstats.clone();
+            let feed = raw.filter(|_| home).map(|raw| {
+                (
+                    self.propagator.clone(),
+                    self.dbs[self.db_index].clone(),
+                    self.db_index,
+                    raw,
+                    Effects::of(&cmd),
+                )
+            });
+            let tracking = read.then(|| (self.tracking.clone(), self.id));
+            // Disk storage waits on sled, which is done off the worker.
+            // Blocking commands spend their time waiting for other clients.
+            let on_disk = self.dbs[self.db_index].storage().blocks() && !cmd.is_blocking();
+            let shard = self.shards.as_ref().filter(|_| home).and_then(|pool| {
+                let shard = pool.route(self.db_index, &cmd)?;
+                Some((pool.clone(), shard))
+            });
+            futures.push(async move {
+                if let Some(e) = refused {
+                    cmdstats.reject(name);
+                    let _ = done_tx.send(());
+                    return Err(anyhow!(e));
+                }
+                for dep in deps {
+                    let _ = dep.await;
+                }
+                // Compound commands hold their keys for all their steps, the
+                // rest only wait for those. So do writes that are propagated,
+                // until their effects are sent, so no other write to their
+                // keys can get in between. A blocking command waits for
+                // other clients' writes, so it can't hold the keys they need.
+                // A propagated write that doesn't name its keys up front,
+                // such as MOVE, holds them all.
+                let _locked = match cmd.keys() {
+                    keys if feed.is_some()
+                        && !cmd.is_blocking()
+                        && keys.as_ref().is_none_or(|keys| keys.is_empty()) =>
+                    {
+                        Some(ctx.keylocks.all().await)
+                    }
+                    Some(keys) if cmd.is_compound() || (feed.is_some() && !cmd.is_blocking()) => {
+                        Some(ctx.keylocks.exclusive(ctx.db_index, &keys).await)
+                    }
+                    Some(keys) if !cmd.is_blocking() => {
+                        Some(ctx.keylocks.shared(ctx.db_index, &keys).await)
+                    }
+                    _ => None,
+                };
+                // Before the read, so a write racing it still invalidates
+                if let (Some((tracking, id)), Some(keys)) = (tracking, cmd.keys()) {
+                    tracking.read(id, &keys);
+                }
+                #[cfg(feature = "otel")]
+                let keys = cmd.keys().map_or(0, |keys| keys.len());
+                let start = Instant::now();
+                let exec = async {
+                    match shard {
+                        Some((pool, shard)) => pool.exec(shard, cmd, ctx).await,
+                        None if on_disk => backend::blocking_exec(cmd.exec(ctx)).await,
+                        None => cmd.exec(ctx).await,
+                    }
+                };
+                #[cfg(feature = "otel")]
+                let result = otel::traced(name, keys, exec).await;
+                #[cfg(not(feature = "otel"))]
+                let result = exec.await;
+                let elapsed = start.elapsed();
+                cmdstats.record(name, elapsed, result.is_err());
+                if let Some(latency) = latency {
+                    if latency.record(EVENT_COMMAND, elapsed) {
+                        warn!("Slow command {} took {:?}", name, elapsed);
+                    }
+                }
+                // Still holding the keys and before the commands waiting on
+                // it, so writes to a key are streamed and logged in the order
+                // they were made. A write is only answered once it is in the
+                // AOF.
+                let result = match (result, feed) {
+                    (Ok(resp), Some((propagator, db, index, raw, effects))) => {
+                        let frame = effects.frame(&raw, &resp, &db);
+                        match propagator.propagate(index, &frame) {
+                            Err(e) => {
+                                error!("Failed to append to the AOF: {}", e);
+                                Err(anyhow!(CommandError::AofWrite(e.to_string())))
+                            }
+                            Ok(()) => Ok(resp),
+                        }
+                    }
+                    (result, _) => result,
+                };
+                let _ = done_tx.send(());
+                result
+            });
+            if let Some(db) = selected {
+                self.db_index = db;
+            }
+            if reset {
+                self.reset();
+            }
+            if let Some((protocol, login)) = hello {
+                self.protocol = protocol;
+                self.handle.set_protocol(protocol);
+                if let Some((user, namespace)) = login {
+                    self.login(user, namespace);
+                }
+            }
+            if let Some((user, namespace)) = auth {
+                self.login(user, namespace);
+            }
         }
 
         // 等待所有命令完成
-        let results = futures::future::join_all(futures).await;
+        let mut results = future::join_all(futures).await.into_iter();
 
         // 批量写入响应
-        for result in results {
+        for failed in failed {
+            let result = match failed {
+                Some(e) => Err(e),
+                None => results.next().expect("a result per command"),
+            };
             match result {
-                Ok(resp) => {
-                    self.write_buf.extend(resp.to_owned().as_bytes());
-                }
+                Ok(resp) => reply::encode(&resp, &mut self.write_buf),
                 Err(e) => {
-                    self.write_buf.extend(format!("-ERR {}\r\n", e).as_bytes());
+                    let kind = e.downcast_ref::<CommandError>().map_or("ERR", |e| e.kind());
+                    self.write_buf
+                        .extend(format!("-{} {}\r\n", kind, e).as_bytes());
                 }
             }
         }
 
         // 一次性写入所有响应
-        self.writer.write_all(&self.write_buf).await?;
-        self.writer.flush().await?;
-        self.write_buf.clear();
+        let frame = self.write_buf.split();
+        self.send(frame).await?;
 
         Ok(())
     }
 }
 
+impl<I> Drop for ClientConn<I> {
+    fn drop(&mut self) {
+        self.clients.unregister(self.id);
+        self.tracking.disable(self.id);
+        self.pubsub.disconnect(self.id);
+        self.ratelimit.disconnect(self.id);
+        self.replication.detach(self.id);
+    }
+}
+
 //EOF
@@ -0,0 +1,150 @@
+// Connected clients, reachable from outside their own connection. Each
+// connection writes through a single writer task fed by a channel, so replies
+// and out-of-band pushes from other connections never interleave mid-frame.
+use crate::protocal::reply;
+use bytes::BytesMut;
+use dashmap::DashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+use tokio::sync::{mpsc, watch};
+
+// Frames queued for a connection before its reader has to wait
+pub const OUTPUT_QUEUE_LEN: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    id: u64,
+    addr: SocketAddr,
+    output: mpsc::Sender<BytesMut>,
+    killed: Arc<watch::Sender<bool>>,
+    // RESP version the connection speaks, pushes are encoded to match
+    protocol: Arc<AtomicU8>,
+}
+
+impl ClientHandle {
+    pub fn new(id: u64, addr: SocketAddr, output: mpsc::Sender<BytesMut>) -> Self {
+        let (killed, _) = watch::channel(false);
+        Self {
+            id,
+            addr,
+            output,
+            killed: Arc::new(killed),
+            protocol: Arc::new(AtomicU8::new(2)),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn set_protocol(&self, protocol: u8) {
+        self.protocol.store(protocol, Ordering::Relaxed);
+    }
+
+    // Queues a frame outside the request/reply flow. A client too slow to
+    // keep up is disconnected rather than buffered for without bound.
+    pub fn push(&self, value: &RespValue) -> bool {
+        let mut frame = BytesMut::new();
+        reply::encode(value, &mut frame);
+        self.push_raw(frame)
+    }
+
+    // Like push, for bytes already encoded
+    pub fn push_raw(&self, frame: BytesMut) -> bool {
+        match self.output.try_send(frame) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.kill();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Both tasks of the connection stop at their next await, output still
+    // queued is dropped
+    pub fn kill(&self) {
+        self.killed.send_replace(true);
+    }
+
+    pub fn killed(&self) -> watch::Receiver<bool> {
+        self.killed.subscribe()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    clients: DashMap<u64, ClientHandle>,
+}
+
+impl ClientRegistry {
+    pub fn register(&self, handle: ClientHandle) {
+        self.clients.insert(handle.id(), handle);
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    pub fn get(&self, id: u64) -> Option<ClientHandle> {
+        self.clients.get(&id).map(|entry| entry.value().clone())
+    }
+
+    // CLIENT KILL ID: true when the client was connected
+    pub fn kill(&self, id: u64) -> bool {
+        match self.clients.remove(&id) {
+            Some((_, handle)) => {
+                handle.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_push_and_kill() {
+        let registry = ClientRegistry::default();
+        let (tx, mut rx) = mpsc::channel(1);
+        let handle = ClientHandle::new(7, "127.0.0.1:1".parse().unwrap(), tx);
+        let killed = handle.killed();
+        registry.register(handle);
+
+        let push = RespValue::SimpleString(Cow::Borrowed("hi"));
+        assert!(registry.get(7).unwrap().push(&push));
+        assert_eq!(&rx.try_recv().unwrap()[..], b"+hi\r\n");
+
+        // A full queue disconnects the client instead of growing
+        let handle = registry.get(7).unwrap();
+        assert!(handle.push(&push));
+        assert!(!handle.push(&push));
+        assert!(*killed.borrow());
+
+        assert!(registry.kill(7));
+        assert!(!registry.kill(7));
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::protocal::table::COMMANDS;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Bucket b counts calls of up to 2^b microseconds, the last one everything
+// slower than about 3 days
+const BUCKETS: usize = 38;
+
+// Percentiles INFO latencystats reports, same as Redis
+pub const INFO_PERCENTILES: [f64; 3] = [50.0, 99.0, 99.9];
+
+#[derive(Debug)]
+struct CommandStat {
+    calls: AtomicU64,
+    usec: AtomicU64,
+    // Ran and replied with an error
+    failed: AtomicU64,
+    // Turned away before running: arity, LOADING, rate limit, ...
+    rejected: AtomicU64,
+    histogram: [AtomicU64; BUCKETS],
+}
+
+impl Default for CommandStat {
+    fn default() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            usec: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            histogram: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+fn bucket(usec: u64) -> usize {
+    // Smallest b with usec <= 2^b
+    let b = (u64::BITS - usec.saturating_sub(1).leading_zeros()) as usize;
+    b.min(BUCKETS - 1)
+}
+
+// Per bucket counts, read at once so they add up to calls
+fn counts(stat: &CommandStat) -> [u64; BUCKETS] {
+    std::array::from_fn(|b| stat.histogram[b].load(Ordering::Relaxed))
+}
+
+// LATENCY HISTOGRAM of one command: per bucket from the first to the last
+// one used, its upper bound in microseconds with the calls up to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub name: &'static str,
+    pub calls: u64,
+    pub buckets: Vec<(u64, u64)>,
+}
+
+// Calls, errors and latency of every command in the table. The set of
+// commands is fixed at start, so recording a call is a lookup and a few
+// atomic adds, with no lock on the dispatch path.
+#[derive(Debug)]
+pub struct CommandStats {
+    by_name: HashMap<&'static str, CommandStat>,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self {
+            by_name: COMMANDS
+                .iter()
+                .map(|spec| (spec.name, CommandStat::default()))
+                .collect(),
+        }
+    }
+}
+
+impl CommandStats {
+    // A command that ran, by its table name
+    pub fn record(&self, name: &str, elapsed: Duration, failed: bool) {
+        let Some(stat) = self.by_name.get(name) else {
+            return;
+        };
+        let usec = elapsed.as_micros() as u64;
+        stat.calls.fetch_add(1, Ordering::Relaxed);
+        stat.usec.fetch_add(usec, Ordering::Relaxed);
+        stat.histogram[bucket(usec)].fetch_add(1, Ordering::Relaxed);
+        if failed {
+            stat.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn reject(&self, name: &str) {
+        if let Some(stat) = self.by_name.get(name) {
+            stat.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // CONFIG RESETSTAT
+    pub fn reset(&self) {
+        for stat in self.by_name.values() {
+            stat.calls.store(0, Ordering::Relaxed);
+            stat.usec.store(0, Ordering::Relaxed);
+            stat.failed.store(0, Ordering::Relaxed);
+            stat.rejected.store(0, Ordering::Relaxed);
+            for count in &stat.histogram {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Commands called or rejected since the last reset, by name
+    fn used(&self) -> Vec<(&'static str, &CommandStat)> {
+        let mut used: Vec<_> = self
+            .by_name
+            .iter()
+            .filter(|(_, stat)| {
+                stat.calls.load(Ordering::Relaxed) + stat.rejected.load(Ordering::Relaxed) > 0
+            })
+            .map(|(name, stat)| (*name, stat))
+            .collect();
+        used.sort_unstable_by_key(|(name, _)| *name);
+        used
+    }
+
+    // The INFO commandstats section
+    pub fn info(&self) -> String {
+        let mut info = "# Commandstats\r\n".to_string();
+        for (name, stat) in self.used() {
+            let calls = stat.calls.load(Ordering::Relaxed);
+            let usec = stat.usec.load(Ordering::Relaxed);
+            let per_call = if calls > 0 {
+                usec as f64 / calls as f64
+            } else {
+                0.0
+            };
+            info.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                name,
+                calls,
+                usec,
+                per_call,
+                stat.rejected.load(Ordering::Relaxed),
+                stat.failed.load(Ordering::Relaxed)
+            ));
+        }
+        info
+    }
+
+    // The INFO latencystats section
+    pub fn latency_info(&self) -> String {
+        let mut info = "# Latencystats\r\n".to_string();
+        for (name, stat) in self.used() {
+            let counts = counts(stat);
+            let percentiles: Vec<_> = INFO_PERCENTILES
+                .iter()
+                .filter_map(|p| Some(format!("p{}={:.3}", p, percentile(&counts, *p)?)))
+                .collect();
+            if !percentiles.is_empty() {
+                info.push_str(&format!(
+                    "latency_percentiles_usec_{}:{}\r\n",
+                    name,
+                    percentiles.join(",")
+                ));
+            }
+        }
+        info
+    }
+
+    // Histograms of the commands named, every command called when none are
+    pub fn histograms(&self, names: &[String]) -> Vec<Histogram> {
+        self.used()
+            .into_iter()
+            .filter(|(name, _)| {
+                names.is_empty() || names.iter().any(|n| n.eq_ignore_ascii_case(name))
+            })
+            .filter_map(|(name, stat)| {
+                let counts = counts(stat);
+                let first = counts.iter().position(|count| *count > 0)?;
+                let last = counts.iter().rposition(|count| *count > 0)?;
+                let mut total = counts[..first].iter().sum::<u64>();
+                let buckets = (first..=last)
+                    .map(|b| {
+                        total += counts[b];
+                        (1u64 << b, total)
+                    })
+                    .collect();
+                Some(Histogram {
+                    name,
+                    calls: total,
+                    buckets,
+                })
+            })
+            .collect()
+    }
+}
+
+// Microseconds under which p percent of the calls took, interpolated within
+// the bucket it falls in
+fn percentile(counts: &[u64; BUCKETS], p: f64) -> Option<f64> {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = (p / 100.0 * total as f64).max(1.0);
+    let mut below = 0;
+    for (b, count) in counts.iter().enumerate() {
+        if *count > 0 && (below + count) as f64 >= rank {
+            let lower = if b == 0 {
+                0.0
+            } else {
+                (1u64 << (b - 1)) as f64
+            };
+            let upper = (1u64 << b) as f64;
+            return Some(lower + (upper - lower) * (rank - below as f64) / *count as f64);
+        }
+        below += count;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(1024), 10);
+        assert_eq!(bucket(1025), 11);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = CommandStats::default();
+        for usec in [3, 3, 3, 100] {
+            stats.record("get", Duration::from_micros(usec), false);
+        }
+        stats.record("set", Duration::from_micros(10), true);
+        stats.reject("set");
+        stats.record("no-such-command", Duration::from_micros(10), false);
+
+        let info = stats.info();
+        assert_eq!(
+            info,
+            "# Commandstats\r\n\
+             cmdstat_get:calls=4,usec=109,usec_per_call=27.25,rejected_calls=0,failed_calls=0\r\n\
+             cmdstat_set:calls=1,usec=10,usec_per_call=10.00,rejected_calls=1,failed_calls=1\r\n"
+        );
+        // Three calls in (2, 4], one in (64, 128]
+        let latency = stats.latency_info();
+        assert!(latency
+            .contains("latency_percentiles_usec_get:p50=3.333,p99=125.440,p99.9=127.744\r\n"));
+
+        let histograms = stats.histograms(&["GET".to_string()]);
+        assert_eq!(histograms.len(), 1);
+        let histogram = &histograms[0];
+        assert_eq!((histogram.name, histogram.calls), ("get", 4));
+        assert_eq!(histogram.buckets.first(), Some(&(4, 3)));
+        assert_eq!(histogram.buckets.last(), Some(&(128, 4)));
+        assert_eq!(histogram.buckets.len(), 6);
+
+        stats.reset();
+        assert_eq!(stats.info(), "# Commandstats\r\n");
+        assert!(stats.histograms(&[]).is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+// Config files in the redis.conf format: one "name value" per line, # starts
+// a comment line. Values may be wrapped in double quotes to keep their spaces.
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigEntry {
+    pub line: usize,
+    pub name: String,
+    pub value: String,
+}
+
+pub fn parse(text: &str) -> Result<Vec<ConfigEntry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("line {}: no value for '{}'", i + 1, line))?;
+        let value = value.trim();
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => quoted
+                .strip_suffix('"')
+                .ok_or_else(|| format!("line {}: unbalanced quotes", i + 1))?,
+            None => value,
+        };
+        entries.push(ConfigEntry {
+            line: i + 1,
+            name: name.to_ascii_lowercase(),
+            value: value.to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+pub fn load(path: &Path) -> Result<Vec<ConfigEntry>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "# limits\n\
+                    \n\
+                    Command-Timeout 50\n  \
+                    loglevel   debug  \n\
+                    welcome \"hello there\"\n";
+        let entries = parse(text).unwrap();
+        let pairs: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.line, entry.name.as_str(), entry.value.as_str()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (3, "command-timeout", "50"),
+                (4, "loglevel", "debug"),
+                (5, "welcome", "hello there"),
+            ]
+        );
+
+        assert_eq!(
+            parse("port 6379\nloglevel\n").unwrap_err(),
+            "line 2: no value for 'loglevel'"
+        );
+        assert!(parse("welcome \"hi\n").is_err());
+    }
+}
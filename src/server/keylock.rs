@@ -0,0 +1,155 @@
+// Striped locks over keys, for operations of several steps that must not see
+// a key change between them. Every keyed command a client runs holds the
+// shared side of its keys' stripes while it runs; a compound operation holds
+// the exclusive side for all of its steps, so commands on its keys wait until
+// it is done and it waits for the ones already running. Keys share a stripe
+// by hash, which only ever makes unrelated commands wait a little. Stripes
+// are taken in index order, so lockers can't deadlock each other.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+pub const STRIPES: usize = 1024;
+
+// The stripes taken, let go of when dropped
+#[derive(Debug, Default)]
+pub struct KeyGuard {
+    _shared: Vec<OwnedRwLockReadGuard<()>>,
+    _exclusive: Vec<OwnedRwLockWriteGuard<()>>,
+}
+
+// The stripes are made on first use, as every ExecContext starts with locks
+// of its own until given the server's
+#[derive(Debug, Default)]
+pub struct KeyLocks {
+    stripes: OnceLock<Vec<Arc<RwLock<()>>>>,
+}
+
+impl KeyLocks {
+    fn stripe(&self, stripe: usize) -> Arc<RwLock<()>> {
+        self.stripes
+            .get_or_init(|| (0..STRIPES).map(|_| Arc::new(RwLock::new(()))).collect())[stripe]
+            .clone()
+    }
+
+    // Stripes of keys in db, each once and in order
+    fn stripes(&self, db: usize, keys: &[&str]) -> Vec<usize> {
+        let mut stripes: Vec<usize> = keys
+            .iter()
+            .map(|key| {
+                let mut hasher = DefaultHasher::new();
+                (db, key).hash(&mut hasher);
+                hasher.finish() as usize % STRIPES
+            })
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+    }
+
+    // For a command that reads or changes its keys in one step
+    pub async fn shared(&self, db: usize, keys: &[&str]) -> KeyGuard {
+        let mut guard = KeyGuard::default();
+        for stripe in self.stripes(db, keys) {
+            guard._shared.push(self.stripe(stripe).read_owned().await);
+        }
+        guard
+    }
+
+    // For a compound operation, held across all its steps
+    pub async fn exclusive(&self, db: usize, keys: &[&str]) -> KeyGuard {
+        let mut guard = KeyGuard::default();
+        for stripe in self.stripes(db, keys) {
+            guard
+                ._exclusive
+                .push(self.stripe(stripe).write_owned().await);
+        }
+        guard
+    }
+
+    // Every stripe, for a cut of the whole dataset that no write of a key
+    // can be halfway through
+    pub async fn all(&self) -> KeyGuard {
+        let mut guard = KeyGuard::default();
+        for stripe in 0..STRIPES {
+            guard
+                ._exclusive
+                .push(self.stripe(stripe).write_owned().await);
+        }
+        guard
+    }
+
+    // exclusive without waiting, None when any of the stripes is taken
+    pub fn try_exclusive(&self, db: usize, keys: &[&str]) -> Option<KeyGuard> {
+        let mut guard = KeyGuard::default();
+        for stripe in self.stripes(db, keys) {
+            guard
+                ._exclusive
+                .push(self.stripe(stripe).try_write_owned().ok()?);
+        }
+        Some(guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn test_key_locks() {
+        let locks = Arc::new(KeyLocks::default());
+        // A key of its own stripe
+        let other = (0..)
+            .map(|i| format!("other{}", i))
+            .find(|key| locks.stripes(0, &[key]) != locks.stripes(0, &["k"]))
+            .unwrap();
+
+        let shared = locks.shared(0, &["k", "k"]).await;
+        assert!(timeout(WAIT, locks.shared(0, &["k"])).await.is_ok());
+        assert!(timeout(WAIT, locks.exclusive(0, &["k"])).await.is_err());
+        assert!(locks.try_exclusive(0, &["k"]).is_none());
+        assert!(locks.try_exclusive(0, &[&other]).is_some());
+        drop(shared);
+        assert!(locks.try_exclusive(0, &["k"]).is_some());
+
+        // Between the steps of a compound operation nothing runs on its keys
+        let compound = locks.exclusive(0, &["k"]).await;
+        assert!(timeout(WAIT, locks.shared(0, &["k"])).await.is_err());
+        assert!(timeout(WAIT, locks.shared(0, &[&other])).await.is_ok());
+        let waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move { locks.shared(0, &["k"]).await }
+        });
+        tokio::time::sleep(WAIT).await;
+        assert!(!waiting.is_finished());
+        drop(compound);
+        assert!(timeout(WAIT, waiting).await.is_ok());
+
+        // Keys named in opposite orders don't deadlock
+        let mut tasks = Vec::new();
+        for i in 0..32 {
+            let locks = locks.clone();
+            let other = other.clone();
+            tasks.push(tokio::spawn(async move {
+                let keys = if i % 2 == 0 {
+                    ["k", other.as_str()]
+                } else {
+                    [other.as_str(), "k"]
+                };
+                let _guard = locks.exclusive(0, &keys).await;
+                tokio::task::yield_now().await;
+            }));
+        }
+        for task in tasks {
+            timeout(Duration::from_secs(5), task)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+}
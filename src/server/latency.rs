@@ -0,0 +1,169 @@
+use crate::db::clock::Clock;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Samples kept per event, same as Redis
+pub const LATENCY_HISTORY_LEN: usize = 160;
+
+// Event classes
+pub const EVENT_COMMAND: &str = "command";
+pub const EVENT_EXPIRE_CYCLE: &str = "expire-cycle";
+pub const EVENT_SNAPSHOT: &str = "snapshot";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySample {
+    // Unix seconds
+    pub time: u64,
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct EventHistory {
+    samples: VecDeque<LatencySample>,
+    max_ms: u64,
+}
+
+// Latency monitor: events taking at least threshold ms are kept in a ring
+// buffer per event class. A threshold of 0 disables it.
+#[derive(Debug, Default)]
+pub struct LatencyMonitor {
+    threshold_ms: AtomicU64,
+    events: Mutex<HashMap<String, EventHistory>>,
+    // Where sample times come from
+    clock: Arc<Clock>,
+}
+
+impl LatencyMonitor {
+    pub fn new(threshold_ms: u64) -> Self {
+        Self {
+            threshold_ms: AtomicU64::new(threshold_ms),
+            events: Mutex::new(HashMap::new()),
+            clock: Arc::new(Clock::default()),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn threshold_ms(&self) -> u64 {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold_ms(&self, threshold_ms: u64) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    // Keeps the event if it reached the threshold, and tells whether it did
+    pub fn record(&self, event: &str, elapsed: Duration) -> bool {
+        let threshold = self.threshold_ms();
+        let latency_ms = elapsed.as_millis() as u64;
+        if threshold == 0 || latency_ms < threshold {
+            return false;
+        }
+
+        let time = self.clock.now_secs();
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let history = events.entry(event.to_string()).or_default();
+        history.max_ms = history.max_ms.max(latency_ms);
+        // Several spikes within one second collapse into the worst of them
+        if let Some(last) = history.samples.back_mut() {
+            if last.time == time {
+                last.latency_ms = last.latency_ms.max(latency_ms);
+                return true;
+            }
+        }
+        if history.samples.len() == LATENCY_HISTORY_LEN {
+            history.samples.pop_front();
+        }
+        history
+            .samples
+            .push_back(LatencySample { time, latency_ms });
+        true
+    }
+
+    // LATENCY LATEST: (event, latest sample, all-time max ms) per event
+    pub fn latest(&self) -> Vec<(String, LatencySample, u64)> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let mut latest: Vec<_> = events
+            .iter()
+            .filter_map(|(name, history)| {
+                let last = history.samples.back()?;
+                Some((name.clone(), *last, history.max_ms))
+            })
+            .collect();
+        latest.sort_by(|a, b| a.0.cmp(&b.0));
+        latest
+    }
+
+    // LATENCY HISTORY: oldest first
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events
+            .get(event)
+            .map(|history| history.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    // LATENCY RESET: drops the given events, or all of them when none are
+    // named. Returns how many were dropped.
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if names.is_empty() {
+            let count = events.len();
+            events.clear();
+            return count;
+        }
+        names
+            .iter()
+            .filter(|name| events.remove(name.as_str()).is_some())
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let monitor = LatencyMonitor::new(0);
+        assert!(!monitor.record(EVENT_COMMAND, Duration::from_secs(1)));
+        assert!(monitor.latest().is_empty());
+
+        monitor.set_threshold_ms(100);
+        assert!(!monitor.record(EVENT_COMMAND, Duration::from_millis(50)));
+        assert!(monitor.latest().is_empty());
+        assert!(monitor.record(EVENT_COMMAND, Duration::from_millis(150)));
+        assert!(monitor.record(EVENT_COMMAND, Duration::from_millis(120)));
+
+        // Spikes within the same second share one sample
+        let history = monitor.history(EVENT_COMMAND);
+        assert!(!history.is_empty() && history.len() <= 2);
+        assert_eq!(history[0].latency_ms, 150);
+        assert_eq!(monitor.latest()[0].2, 150);
+    }
+
+    #[test]
+    fn test_latest_and_reset() {
+        let monitor = LatencyMonitor::new(1);
+        monitor.record(EVENT_COMMAND, Duration::from_millis(5));
+        monitor.record(EVENT_EXPIRE_CYCLE, Duration::from_millis(7));
+
+        let latest = monitor.latest();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].0, EVENT_COMMAND);
+        assert_eq!(latest[0].2, 5);
+
+        assert_eq!(
+            monitor.reset(&["command".to_string(), "nope".to_string()]),
+            1
+        );
+        assert!(monitor.history(EVENT_COMMAND).is_empty());
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.latest().is_empty());
+    }
+}
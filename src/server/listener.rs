@@ -0,0 +1,196 @@
+// Listening sockets, built with socket2 so the options that matter under a
+// high connection rate can be set before listen().
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+// Same default as Redis' tcp-backlog
+pub const DEFAULT_BACKLOG: u32 = 511;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenOptions {
+    // Connections the kernel queues before they are accepted
+    pub backlog: u32,
+    // Lets a restarted server bind while old connections are in TIME_WAIT
+    pub reuse_addr: bool,
+    // Socket buffer bytes, inherited by accepted sockets. 0 leaves the
+    // system default.
+    pub send_buffer: usize,
+    pub recv_buffer: usize,
+    // Listeners per address, sharing the port through SO_REUSEPORT so the
+    // kernel spreads accepts over them. 0 for one per runtime worker, 1 for a
+    // single listener.
+    pub listeners: usize,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        Self {
+            backlog: DEFAULT_BACKLOG,
+            reuse_addr: true,
+            send_buffer: 0,
+            recv_buffer: 0,
+            listeners: 1,
+        }
+    }
+}
+
+// A non-blocking listener on addr. Must be called on the runtime.
+pub fn listen(addr: SocketAddr, options: &ListenOptions) -> io::Result<TcpListener> {
+    open(addr, options, false)
+}
+
+fn open(addr: SocketAddr, options: &ListenOptions, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Binding :: shouldn't take the IPv4 port too, a separate 0.0.0.0 does
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(options.reuse_addr)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported",
+        ));
+    }
+    if options.send_buffer > 0 {
+        socket.set_send_buffer_size(options.send_buffer)?;
+    }
+    // Before listen, so the window scale offered covers it
+    if options.recv_buffer > 0 {
+        socket.set_recv_buffer_size(options.recv_buffer)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog.min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Binds the first address host resolves to that takes it
+pub async fn bind(host: &str, port: u16, options: &ListenOptions) -> io::Result<TcpListener> {
+    bind_with(host, port, options, false).await
+}
+
+async fn bind_with(
+    host: &str,
+    port: u16,
+    options: &ListenOptions,
+    reuse_port: bool,
+) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        match open(addr, options, reuse_port) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+// Listeners options.listeners asks for on this runtime
+fn listener_count(options: &ListenOptions) -> usize {
+    match options.listeners {
+        0 => tokio::runtime::Handle::current().metrics().num_workers(),
+        n => n,
+    }
+}
+
+// Binds host like bind, with as many listeners on the port as options ask
+// for. Where SO_REUSEPORT is refused a single listener is bound instead.
+pub async fn bind_all(
+    host: &str,
+    port: u16,
+    options: &ListenOptions,
+) -> io::Result<Vec<TcpListener>> {
+    let count = listener_count(options);
+    if count <= 1 {
+        return Ok(vec![bind(host, port, options).await?]);
+    }
+    let first = match bind_with(host, port, options, true).await {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+            warn!("Binding a single listener on {}: {}", host, e);
+            return Ok(vec![bind(host, port, options).await?]);
+        }
+        Err(e) => return Err(e),
+    };
+    // Port 0 is resolved by the first one, the others take the same
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(open(addr, options, true)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let options = ListenOptions {
+            recv_buffer: 64 * 1024,
+            ..ListenOptions::default()
+        };
+        let listener = bind("127.0.0.1", 0, &options).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = socket2::SockRef::from(&listener);
+        assert!(socket.reuse_address().unwrap());
+        // The kernel may round it up, never below
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(accepted.peer_addr().unwrap(), client.local_addr().unwrap());
+
+        assert!(bind("127.0.0.1", addr.port(), &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_all() {
+        let options = ListenOptions {
+            listeners: 3,
+            ..ListenOptions::default()
+        };
+        let listeners = bind_all("127.0.0.1", 0, &options).await.unwrap();
+        assert_eq!(listeners.len(), 3);
+        let addr = listeners[0].local_addr().unwrap();
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap(), addr);
+        }
+
+        // Each connection is taken by exactly one of them
+        let mut clients = Vec::new();
+        for _ in 0..6 {
+            clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+        }
+        let mut accepted = 0;
+        for listener in &listeners {
+            while let Ok(Ok(_)) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), listener.accept()).await
+            {
+                accepted += 1;
+            }
+        }
+        assert_eq!(accepted, clients.len());
+
+        let single = bind_all("127.0.0.1", 0, &ListenOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(single.len(), 1);
+        // A listener without SO_REUSEPORT keeps its port to itself
+        let port = single[0].local_addr().unwrap().port();
+        assert!(bind_all("127.0.0.1", port, &options).await.is_err());
+    }
+}
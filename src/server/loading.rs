@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+// Progress of the dataset load at boot. Clients are taken while it runs, but
+// only commands flagged `loading` in the command table are served, the rest
+// get a LOADING error until it is done.
+#[derive(Debug, Default)]
+pub struct Loading {
+    loading: AtomicBool,
+    // Unix seconds
+    start_time: AtomicU64,
+    total_bytes: AtomicU64,
+    loaded_bytes: AtomicU64,
+    started: Mutex<Option<Instant>>,
+    finished: Notify,
+}
+
+impl Loading {
+    pub fn start(&self, total_bytes: u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        *self.started.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        self.start_time.store(now, Ordering::Relaxed);
+        self.total_bytes.store(total_bytes, Ordering::Relaxed);
+        self.loaded_bytes.store(0, Ordering::Relaxed);
+        self.loading.store(true, Ordering::Release);
+    }
+
+    pub fn progress(&self, loaded_bytes: u64) {
+        self.loaded_bytes.store(loaded_bytes, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.loading.store(false, Ordering::Release);
+        self.finished.notify_waiters();
+    }
+
+    // Returns once no load is running
+    pub async fn wait(&self) {
+        loop {
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            // Registered before the check so a finish in between isn't missed
+            finished.as_mut().enable();
+            if !self.is_loading() {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Acquire)
+    }
+
+    // The INFO persistence section
+    pub fn info(&self) -> String {
+        if !self.is_loading() {
+            return "# Persistence\r\nloading:0\r\nasync_loading:0\r\n".to_string();
+        }
+        let total = self.total_bytes.load(Ordering::Relaxed);
+        let loaded = self.loaded_bytes.load(Ordering::Relaxed).min(total);
+        let perc = if total > 0 {
+            loaded as f64 * 100.0 / total as f64
+        } else {
+            0.0
+        };
+        // Assumes the rest loads as fast as what is done so far, 1 until
+        // there is anything to go by
+        let elapsed = self
+            .started
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map_or(0.0, |started| started.elapsed().as_secs_f64());
+        let eta = if loaded > 0 {
+            (elapsed * (total - loaded) as f64 / loaded as f64).ceil() as u64
+        } else {
+            1
+        };
+        format!(
+            "# Persistence\r\nloading:1\r\nasync_loading:0\r\nloading_start_time:{}\r\n\
+             loading_total_bytes:{}\r\nloading_loaded_bytes:{}\r\nloading_loaded_perc:{:.2}\r\n\
+             loading_eta_seconds:{}\r\n",
+            self.start_time.load(Ordering::Relaxed),
+            total,
+            loaded,
+            perc,
+            eta
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let loading = Loading::default();
+        assert!(!loading.is_loading());
+        assert!(loading.info().contains("loading:0\r\n"));
+
+        loading.start(200);
+        assert!(loading.is_loading());
+        loading.progress(50);
+        let info = loading.info();
+        assert!(info.contains("loading:1\r\n"));
+        assert!(info.contains("loading_total_bytes:200\r\n"));
+        assert!(info.contains("loading_loaded_bytes:50\r\n"));
+        assert!(info.contains("loading_loaded_perc:25.00\r\n"));
+        assert!(info.contains("loading_eta_seconds:"));
+
+        loading.finish();
+        assert!(!loading.is_loading());
+        assert!(!loading.info().contains("loading_total_bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let loading = std::sync::Arc::new(Loading::default());
+        loading.wait().await;
+
+        loading.start(10);
+        let waiter = tokio::spawn({
+            let loading = loading.clone();
+            async move { loading.wait().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        loading.finish();
+        waiter.await.unwrap();
+    }
+}
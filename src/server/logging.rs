@@ -0,0 +1,42 @@
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+
+// Target the RESP parser logs under
+pub const PARSER_TARGET: &str = "stream_resp";
+
+// Log filter for the server at the given level. The RESP parser logs every
+// step of try_parse along with the whole buffer, which is slow and puts
+// request contents in the log, so it stays quiet unless the server is built
+// with the `parser-trace` feature.
+pub fn targets(level: LevelFilter) -> Targets {
+    let parser_level = if cfg!(feature = "parser-trace") {
+        level
+    } else {
+        level.min(LevelFilter::INFO)
+    };
+    Targets::new()
+        .with_default(level)
+        .with_target(PARSER_TARGET, parser_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+
+    #[test]
+    fn test_parser_target() {
+        let filter = targets(LevelFilter::TRACE);
+        assert!(filter.would_enable("foobar_db::server::client", &Level::TRACE));
+        assert_eq!(
+            filter.would_enable("stream_resp::parser", &Level::DEBUG),
+            cfg!(feature = "parser-trace")
+        );
+        assert!(filter.would_enable("stream_resp::parser", &Level::INFO));
+
+        let filter = targets(LevelFilter::WARN);
+        assert!(!filter.would_enable("stream_resp::parser", &Level::INFO));
+    }
+}
+
+//EOF
@@ -1,2 +1,34 @@
+pub mod accept;
+pub mod aof;
+pub mod bigkeys;
 pub mod client;
+pub mod clients;
+pub mod cmdstats;
+pub mod config_file;
+pub mod keylock;
+pub mod latency;
+pub mod listener;
+pub mod loading;
+pub mod logging;
+pub mod namespace;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod panics;
+pub mod pipe;
+pub mod propagate;
+pub mod pubsub;
+pub mod ratelimit;
+pub mod replication;
+#[cfg(feature = "search")]
+pub mod search;
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod shard;
+pub mod shutdown;
+#[cfg(feature = "systemd")]
+pub mod systemd;
+pub mod tasks;
+pub mod timeout;
+pub mod tracking;
+#[cfg(feature = "uring")]
+pub mod uring;
@@ -0,0 +1,309 @@
+// Named namespaces for sharing one server between tenants. Each belongs to a
+// user, and connections that authenticate as it see only the namespace's own
+// database instead of the server's. Writes that may grow the data are
+// refused once the namespace holds its quota of keys or memory. Namespaces
+// live in memory only: they are neither saved nor replicated.
+use crate::db::db::{Databases, DB};
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How stale the memory figure quotas are checked against may get. Measuring
+// goes over every entry, so it isn't done for each write.
+const MEMORY_REFRESH: Duration = Duration::from_millis(100);
+
+// Rough bytes of bookkeeping per key and per element
+const ENTRY_OVERHEAD: usize = 16;
+
+// A namespace as given on the command line, user:password[:max-keys[:max-memory]]
+// with 0 or nothing for no limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceConfig {
+    pub user: String,
+    pub password: String,
+    pub max_keys: usize,
+    // Bytes
+    pub max_memory: usize,
+}
+
+impl FromStr for NamespaceConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let limit = |i: usize, what: &str| match parts.get(i) {
+            None | Some(&"") => Ok(0),
+            Some(limit) => limit
+                .parse::<usize>()
+                .map_err(|_| format!("invalid {} '{}' in namespace '{}'", what, limit, s)),
+        };
+        match parts[..] {
+            [user, password, ..] if parts.len() <= 4 && !user.is_empty() => {
+                if user == "default" {
+                    return Err("the default user can't have a namespace".to_string());
+                }
+                Ok(Self {
+                    user: user.to_string(),
+                    password: password.to_string(),
+                    max_keys: limit(2, "max-keys")?,
+                    max_memory: limit(3, "max-memory")?,
+                })
+            }
+            _ => Err(format!(
+                "invalid namespace '{}', expected user:password[:max-keys[:max-memory]]",
+                s
+            )),
+        }
+    }
+}
+
+// Rough bytes an entry takes: its strings plus some overhead per element
+pub fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value_size(value)
+}
+
+fn value_size(value: &Value) -> usize {
+    let element = |s: &String| ENTRY_OVERHEAD + s.len();
+    ENTRY_OVERHEAD
+        + match value {
+            Value::Str(bytes) => bytes.len(),
+            Value::Listpack(packed) => packed.bytes(),
+            Value::Compressed(compressed) => compressed.stored_len(),
+            Value::List(items) => items.iter().map(element).sum(),
+            Value::Set(items) => items.iter().map(element).sum(),
+            Value::Hash(pairs) => pairs
+                .iter()
+                .map(|(field, value)| element(field) + element(value))
+                .sum(),
+            Value::ZSet(pairs) => pairs
+                .iter()
+                .map(|(member, _)| element(member) + ENTRY_OVERHEAD)
+                .sum(),
+            Value::Stream(entries) => entries
+                .iter()
+                .map(|entry| {
+                    let fields = entry.fields.iter();
+                    ENTRY_OVERHEAD + fields.map(|(f, v)| element(f) + element(v)).sum::<usize>()
+                })
+                .sum(),
+            #[cfg(feature = "json")]
+            Value::Json(doc) => doc.to_string().len(),
+        }
+}
+
+pub struct Namespace<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    pub user: String,
+    password: String,
+    pub dbs: Databases<S, String, Value>,
+    max_keys: usize,
+    max_memory: usize,
+    // Last memory measured and when
+    memory: Mutex<Option<(Instant, usize)>>,
+}
+
+impl<S> Namespace<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    pub fn new(config: &NamespaceConfig, db: DB<S, String, Value>) -> Self {
+        Self {
+            user: config.user.clone(),
+            password: config.password.clone(),
+            dbs: Arc::new(vec![Arc::new(db)]),
+            max_keys: config.max_keys,
+            max_memory: config.max_memory,
+            memory: Mutex::new(None),
+        }
+    }
+
+    pub fn keys(&self) -> usize {
+        self.dbs.iter().map(|db| db.len()).sum()
+    }
+
+    // Memory of all entries, as measured within the last MEMORY_REFRESH
+    pub fn used_memory(&self) -> usize {
+        let mut memory = self.memory.lock().unwrap_or_else(|e| e.into_inner());
+        match *memory {
+            Some((at, used)) if at.elapsed() < MEMORY_REFRESH => used,
+            _ => {
+                let used = self
+                    .dbs
+                    .iter()
+                    .map(|db| {
+                        db.memory_usage(|key, value| entry_size(key, value))
+                            .unwrap_or(0)
+                    })
+                    .sum();
+                *memory = Some((Instant::now(), used));
+                used
+            }
+        }
+    }
+
+    // Why a write that may grow the data can't run now. At the key quota it
+    // may still change keys that exist; keys is None when the command reaches
+    // outside the selected db, which counts as adding.
+    pub fn refusal(&self, db_index: usize, keys: Option<Vec<&str>>) -> Option<CommandError> {
+        if self.max_keys > 0 && self.keys() >= self.max_keys {
+            let adds = match (keys, self.dbs.get(db_index)) {
+                (Some(keys), Some(db)) => keys
+                    .iter()
+                    .any(|key| db.type_of(&key.to_string()).ok().flatten().is_none()),
+                _ => true,
+            };
+            if adds {
+                return Some(CommandError::NamespaceKeysQuota);
+            }
+        }
+        if self.max_memory > 0 && self.used_memory() >= self.max_memory {
+            return Some(CommandError::NamespaceMemoryQuota);
+        }
+        None
+    }
+}
+
+// The namespaces of a server, by user
+pub struct Namespaces<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    by_user: HashMap<String, Arc<Namespace<S>>>,
+}
+
+impl<S> Default for Namespaces<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    fn default() -> Self {
+        Self {
+            by_user: HashMap::new(),
+        }
+    }
+}
+
+impl<S> Namespaces<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    // open makes the database of each namespace
+    pub fn new<F>(configs: &[NamespaceConfig], mut open: F) -> Self
+    where
+        F: FnMut() -> DB<S, String, Value>,
+    {
+        let by_user = configs
+            .iter()
+            .map(|config| {
+                let namespace = Namespace::new(config, open());
+                (config.user.clone(), Arc::new(namespace))
+            })
+            .collect();
+        Self { by_user }
+    }
+
+    // The namespace a user works in, None for the default user and the
+    // server's own databases. The default user takes any password, as
+    // there is no requirepass.
+    pub fn authenticate(
+        &self,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<Arc<Namespace<S>>>, CommandError> {
+        if user == "default" {
+            return Ok(None);
+        }
+        match self.by_user.get(user) {
+            Some(namespace) if namespace.password == password => Ok(Some(namespace.clone())),
+            _ => Err(CommandError::WrongPass),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    type TestNamespaces = Namespaces<DashMapStorage<String, Value>>;
+
+    fn bulk(s: &str) -> Value {
+        Value::str(s)
+    }
+
+    fn namespaces(configs: &[&str]) -> TestNamespaces {
+        let configs: Vec<NamespaceConfig> = configs.iter().map(|c| c.parse().unwrap()).collect();
+        Namespaces::new(&configs, || DB::new(DashMapStorage::new(), 16))
+    }
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            "app:secret:100:4096".parse::<NamespaceConfig>().unwrap(),
+            NamespaceConfig {
+                user: "app".to_string(),
+                password: "secret".to_string(),
+                max_keys: 100,
+                max_memory: 4096,
+            }
+        );
+        let unlimited: NamespaceConfig = "app:secret".parse().unwrap();
+        assert_eq!((unlimited.max_keys, unlimited.max_memory), (0, 0));
+        let memory_only: NamespaceConfig = "app:secret::4096".parse().unwrap();
+        assert_eq!((memory_only.max_keys, memory_only.max_memory), (0, 4096));
+        assert!("app".parse::<NamespaceConfig>().is_err());
+        assert!(":secret".parse::<NamespaceConfig>().is_err());
+        assert!("app:secret:many".parse::<NamespaceConfig>().is_err());
+        assert!("app:secret:1:2:3".parse::<NamespaceConfig>().is_err());
+        assert!("default:secret".parse::<NamespaceConfig>().is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let namespaces = namespaces(&["app:secret", "other:pw"]);
+        assert!(namespaces.authenticate("default", "any").unwrap().is_none());
+        let app = namespaces.authenticate("app", "secret").unwrap().unwrap();
+        assert_eq!(app.user, "app");
+        assert!(namespaces.authenticate("app", "pw").is_err());
+        assert!(namespaces.authenticate("nobody", "secret").is_err());
+
+        // Each namespace has a keyspace of its own
+        let other = namespaces.authenticate("other", "pw").unwrap().unwrap();
+        app.dbs[0].set("k".to_string(), bulk("v")).unwrap();
+        assert!(other.dbs[0].get(&"k".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_quotas() {
+        let namespaces = namespaces(&["keys:pw:2", "memory:pw::100"]);
+        let keys = namespaces.authenticate("keys", "pw").unwrap().unwrap();
+        let db = &keys.dbs[0];
+        assert!(keys.refusal(0, Some(vec!["a"])).is_none());
+        db.set("a".to_string(), bulk("1")).unwrap();
+        db.set("b".to_string(), bulk("2")).unwrap();
+        // Full, but keys that exist can still change
+        assert!(keys.refusal(0, Some(vec!["a", "b"])).is_none());
+        assert!(matches!(
+            keys.refusal(0, Some(vec!["a", "c"])),
+            Some(CommandError::NamespaceKeysQuota)
+        ));
+        assert!(keys.refusal(0, None).is_some());
+
+        let memory = namespaces.authenticate("memory", "pw").unwrap().unwrap();
+        assert!(memory.refusal(0, Some(vec!["k"])).is_none());
+        memory.dbs[0]
+            .set("k".to_string(), bulk(&"x".repeat(200)))
+            .unwrap();
+        std::thread::sleep(MEMORY_REFRESH);
+        assert!(memory.used_memory() > 200);
+        assert!(matches!(
+            memory.refusal(0, Some(vec!["k"])),
+            Some(CommandError::NamespaceMemoryQuota)
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+// Command spans for OpenTelemetry. Every command runs in a root span of its
+// own under TARGET, which the exporter layer alone listens to, so log levels
+// don't change what is exported and connection spans stay out of the traces.
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::future::Future;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+pub const TARGET: &str = "foobar_db::otel";
+
+const SERVICE_NAME: &str = "foobar_db";
+
+// Exports in batches from a thread of its own to the OTLP/HTTP endpoint,
+// e.g. http://localhost:4318/v1/traces
+pub fn provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build())
+}
+
+// The layer handing command spans to provider
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME))
+        .with_filter(Targets::new().with_target(TARGET, Level::INFO))
+}
+
+// Runs exec in the span of command name on keys keys, recording whether it
+// failed. The span's timing is the command's duration.
+pub async fn traced<T, E, F>(name: &str, keys: usize, exec: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let span = info_span!(
+        target: TARGET,
+        parent: None,
+        "command",
+        otel.name = name,
+        otel.kind = "server",
+        otel.status_code = Empty,
+        otel.status_description = Empty,
+        db.system.name = "redis",
+        db.operation.name = name,
+        db.keys = keys as i64,
+        outcome = Empty,
+    );
+    let result = exec.instrument(span.clone()).await;
+    match &result {
+        Ok(_) => {
+            span.record("outcome", "ok");
+        }
+        Err(e) => {
+            span.record("outcome", "error");
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_description", e.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::trace::{SpanData, SpanExporter as Exporter};
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::prelude::*;
+
+    #[derive(Debug, Default, Clone)]
+    struct Collect(Arc<Mutex<Vec<SpanData>>>);
+
+    impl Exporter for Collect {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traced() {
+        let spans = Collect::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let parent = info_span!("client");
+        async {
+            assert_eq!(traced("GET", 1, async { Ok::<_, String>(7) }).await, Ok(7));
+            assert!(traced("MSET", 2, async { Err::<(), _>("-ERR oom") })
+                .await
+                .is_err());
+        }
+        .instrument(parent)
+        .await;
+
+        let spans = spans.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        let attribute = |span: &SpanData, key: &str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(spans[0].name, "GET");
+        assert_eq!(
+            spans[0].parent_span_id,
+            opentelemetry::trace::SpanId::INVALID
+        );
+        assert_eq!(attribute(&spans[0], "db.keys"), Some(Value::I64(1)));
+        assert_eq!(attribute(&spans[0], "outcome"), Some("ok".into()));
+        assert_eq!(spans[1].name, "MSET");
+        assert_eq!(attribute(&spans[1], "outcome"), Some("error".into()));
+        assert_eq!(
+            spans[1].status,
+            opentelemetry::trace::Status::error("-ERR oom")
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use futures::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+use tracing::error;
+
+thread_local! {
+    // Backtrace of the last panic on this thread, taken by the hook
+    static LAST_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+// Guard catches panics by unwinding, which an abort build would not do
+#[cfg(panic = "abort")]
+compile_error!(
+    "the server must be built with panic = \"unwind\" for Panics::guard to catch panics"
+);
+
+static HOOK: Once = Once::new();
+
+// Keeps a backtrace of every panic for the thread that catches it, then goes
+// on to the hook that was there before
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(Backtrace::force_capture()));
+            previous(info);
+        }));
+    });
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", |message| message.as_str()),
+    }
+}
+
+// Panics caught in command execution and connections, server wide. They are
+// logged with their backtrace and the rest of the server carries on.
+#[derive(Debug, Default)]
+pub struct Panics {
+    count: AtomicU64,
+}
+
+impl Panics {
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    // Runs fut, giving None instead of unwinding when it panics
+    pub async fn guard<F: Future>(&self, what: &str, fut: F) -> Option<F::Output> {
+        install_hook();
+        match AssertUnwindSafe(fut).catch_unwind().await {
+            Ok(output) => Some(output),
+            Err(payload) => {
+                self.count.fetch_add(1, Ordering::Relaxed);
+                let backtrace = LAST_BACKTRACE
+                    .with(|last| last.borrow_mut().take())
+                    .map(|backtrace| backtrace.to_string())
+                    .unwrap_or_default();
+                error!("{} panicked: {}\n{}", what, message(&*payload), backtrace);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_guard() {
+        let panics = Panics::default();
+        assert_eq!(panics.guard("ok", async { 1 }).await, Some(1));
+        assert_eq!(panics.count(), 0);
+
+        let caught = panics
+            .guard("boom", async {
+                if panics.count() == 0 {
+                    panic!("boom");
+                }
+                1
+            })
+            .await;
+        assert_eq!(caught, None);
+        assert_eq!(panics.count(), 1);
+    }
+
+    // Run by CI with --release too, where the optimized build has to unwind
+    // to the guard just the same
+    #[tokio::test]
+    async fn test_guard_unwinds() {
+        let panics = Panics::default();
+        let caught = panics
+            .guard("index", async {
+                let empty: Vec<u8> = Vec::new();
+                std::hint::black_box(&empty)[0]
+            })
+            .await;
+        assert_eq!(caught, None);
+        assert_eq!(panics.count(), 1);
+    }
+}
@@ -0,0 +1,225 @@
+// Bulk load from a stream of RESP commands, like `redis-cli --pipe` without
+// the round trips: nothing is replied, commands are parsed a chunk of the
+// stream at a time and each chunk's run in order before the next is read.
+// Commands that fail are counted and the first few logged. Writes go on to
+// the AOF and replicas through ctx's propagator as they would from a
+// client, so the load is as durable as the server is configured to be.
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::{Command, ExecContext};
+use crate::protocal::request::{self, RequestLimits, MAX_DEPTH};
+use crate::server::client::spec_of;
+use crate::server::propagate::Effects;
+use bytes::{Buf, Bytes};
+use std::error::Error;
+use std::fmt;
+use stream_resp::parser::Parser;
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::warn;
+
+// Bytes read from the stream per batch
+pub const CHUNK: usize = 64 * 1024;
+
+// Failures logged, the rest are only counted
+const LOGGED_ERRORS: usize = 10;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PipeStats {
+    pub commands: usize,
+    pub errors: usize,
+    pub bytes: u64,
+}
+
+// As redis-cli sums up a pipe
+impl fmt::Display for PipeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "errors: {}, replies: {}", self.errors, self.commands)
+    }
+}
+
+// Runs the commands read from reader in ctx's databases, telling progress
+// the bytes done after every batch. A stream that isn't RESP, or ends inside
+// a command, is an error after what came before it was run.
+pub async fn import<R, S>(
+    mut reader: R,
+    mut ctx: ExecContext<S>,
+    progress: impl Fn(u64),
+) -> Result<PipeStats, Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin,
+    S: Storage<String, Value> + 'static,
+{
+    let limits = RequestLimits::default();
+    let mut parser = Parser::new(MAX_DEPTH, limits.max_bytes + 1);
+    let mut scan = request::Scan::default();
+    let mut stats = PipeStats::default();
+    loop {
+        parser.buffer.reserve(CHUNK);
+        let read = reader.read_buf(&mut parser.buffer).await?;
+        let mut batch = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let start = consumed;
+            match scan.check(&parser.buffer[consumed..], &limits) {
+                Ok(Some(len)) => consumed += len,
+                Ok(None) => break,
+                Err(e) => return Err(format!("at byte {}: {}", stats.bytes, e).into()),
+            }
+            let resp = request::parse(&mut parser)
+                .map_err(|e| format!("at byte {}: {}", stats.bytes, e))?;
+            stats.bytes += (consumed - start) as u64;
+            if matches!(&resp, RespValue::Array(None))
+                || matches!(&resp, RespValue::Array(Some(items)) if items.is_empty())
+            {
+                continue;
+            }
+            let raw = (spec_of(&resp).is_some_and(|spec| spec.is_write())
+                && ctx.propagator.active())
+            .then(|| Bytes::copy_from_slice(&parser.buffer[start..consumed]));
+            batch.push((Command::from_resp(resp), raw));
+        }
+        parser.buffer.advance(consumed);
+        parser.clear_buffer(0);
+
+        for (cmd, raw) in batch {
+            stats.commands += 1;
+            let failed = match cmd {
+                Ok(Command::Select { db }) if db < ctx.dbs.len() => {
+                    ctx.db_index = db;
+                    None
+                }
+                Ok(cmd) => {
+                    let effects = Effects::of(&cmd);
+                    match cmd.exec(ctx.clone()).await {
+                        Ok(resp) => match &*resp {
+                            RespValue::Error(e) => Some(e.to_string()),
+                            _ => {
+                                if let Some(raw) = raw {
+                                    let frame = effects.frame(&raw, &resp, ctx.db());
+                                    ctx.propagator.propagate(ctx.db_index, &frame)?;
+                                }
+                                None
+                            }
+                        },
+                        Err(e) => Some(e.to_string()),
+                    }
+                }
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(e) = failed {
+                stats.errors += 1;
+                if stats.errors <= LOGGED_ERRORS {
+                    warn!("Command {} of the pipe failed: {}", stats.commands, e);
+                }
+            }
+        }
+        progress(stats.bytes);
+
+        if read == 0 {
+            if !parser.buffer.is_empty() {
+                return Err(
+                    format!("stream ended inside a command at byte {}", stats.bytes).into(),
+                );
+            }
+            return Ok(stats);
+        }
+        // Lets clients waiting on the load be answered between batches
+        tokio::task::yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::{Databases, DB};
+    use crate::db::storage::DashMapStorage;
+    use crate::server::aof::{Aof, AppendFsync};
+    use crate::server::propagate::Propagator;
+    use std::sync::Arc;
+
+    fn dbs() -> Databases<DashMapStorage<String, Value>, String, Value> {
+        Arc::new(
+            (0..2)
+                .map(|_| Arc::new(DB::new(DashMapStorage::new(), 0)))
+                .collect(),
+        )
+    }
+
+    fn set(key: &str, value: &str) -> String {
+        format!(
+            "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+            key.len(),
+            key,
+            value.len(),
+            value
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pipe_import() {
+        // Several chunks' worth, with commands across their edges
+        let mut stream = String::new();
+        for i in 0..10000 {
+            stream.push_str(&set(&format!("key:{}", i), &i.to_string()));
+        }
+        stream.push_str(&set("key:100", "later"));
+        stream.push_str("*1\r\n$5\r\nBOGUS\r\n");
+        stream.push_str("*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n");
+        stream.push_str(&set("other", "1"));
+        assert!(stream.len() > 2 * CHUNK);
+
+        let dir = std::env::temp_dir().join(format!("foobar_pipe_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = std::fs::remove_file(&path);
+        let aof = Arc::new(Aof::open(&path, AppendFsync::No).unwrap());
+
+        let data = dbs();
+        let propagator = Propagator::new(Arc::default(), Some(aof.clone()));
+        let ctx = ExecContext::new(data.clone(), 0).with_propagator(Arc::new(propagator));
+        let done = std::sync::atomic::AtomicU64::new(0);
+        let stats = import(stream.as_bytes(), ctx, |bytes| {
+            done.store(bytes, std::sync::atomic::Ordering::Relaxed)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            stats,
+            PipeStats {
+                commands: 10004,
+                errors: 1,
+                bytes: stream.len() as u64,
+            }
+        );
+        assert_eq!(stats.to_string(), "errors: 1, replies: 10004");
+        assert_eq!(done.into_inner(), stream.len() as u64);
+        assert_eq!(data[0].len(), 10000);
+        assert_eq!(
+            data[0]
+                .get(&"key:100".to_string())
+                .unwrap()
+                .unwrap()
+                .as_ref(),
+            &Value::Str(b"later".to_vec())
+        );
+        assert_eq!(data[1].len(), 1);
+
+        // The writes were logged and replay to the same dataset
+        let replayed = dbs();
+        aof.replay(ExecContext::new(replayed.clone(), 0), 0)
+            .await
+            .unwrap();
+        assert_eq!(replayed[0].len(), 10000);
+        assert_eq!(replayed[1].len(), 1);
+
+        // A stream cut short runs what it holds, then fails
+        let data = dbs();
+        let cut = set("a", "1") + "*3\r\n$3\r\nSET\r\n";
+        let ctx = ExecContext::new(data.clone(), 0);
+        assert!(import(cut.as_bytes(), ctx, |_| {}).await.is_err());
+        assert_eq!(data[0].len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,317 @@
+// What the AOF and replicas are sent of each write: its effect, not the
+// request. Most writes do the same thing wherever they are replayed and go
+// out as the client sent them. The rest are rewritten from what they did:
+// SPOP as an SREM of the members it took, relative TTLs as the absolute
+// PEXPIREAT they came to, a BLMOVE that got an element as the LMOVE it
+// made, MIGRATE as the DEL of what it moved. Keys expired by the server go
+// out as a DEL. A write and its effect are sent while the command still
+// holds its keys, so the stream has every key's writes in the order they
+// ran.
+use crate::db::db::{Expiry, DB};
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::{Command, GetExOption};
+use crate::server::aof::Aof;
+use crate::server::replication::Replication;
+use bytes::BytesMut;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+use tracing::error;
+
+// Appends a command in the RESP clients send it in
+pub fn encode<A: AsRef<[u8]>>(args: &[A], frame: &mut BytesMut) {
+    frame.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        let arg = arg.as_ref();
+        frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        frame.extend_from_slice(arg);
+        frame.extend_from_slice(b"\r\n");
+    }
+}
+
+// The AOF and the replication stream, fed the same effects
+#[derive(Debug, Default)]
+pub struct Propagator {
+    replication: Arc<Replication>,
+    aof: Option<Arc<Aof>>,
+    // While the AOF is replayed, when what it does is already in the file
+    paused: AtomicBool,
+}
+
+impl Propagator {
+    pub fn new(replication: Arc<Replication>, aof: Option<Arc<Aof>>) -> Self {
+        Self {
+            replication,
+            aof,
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn aof(&self) -> Option<&Arc<Aof>> {
+        self.aof.as_ref()
+    }
+
+    // Whether writes have anywhere to go
+    pub fn active(&self) -> bool {
+        !self.paused.load(Ordering::SeqCst) && (self.aof.is_some() || self.replication.streaming())
+    }
+
+    pub fn pause(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    // Sends the effects of a write that ran in db. It is only answered once
+    // they are in the AOF.
+    pub fn propagate(&self, db: usize, frame: &[u8]) -> io::Result<()> {
+        if frame.is_empty() || self.paused.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.replication.feed(db, frame);
+        match &self.aof {
+            Some(aof) => aof.append(db, frame),
+            None => Ok(()),
+        }
+    }
+
+    // A key of db was expired, lazily or by the active cycle
+    pub fn expired(&self, db: usize, key: &str) {
+        if !self.active() {
+            return;
+        }
+        let mut frame = BytesMut::new();
+        encode(&["DEL", key], &mut frame);
+        if let Err(e) = self.propagate(db, &frame) {
+            error!("Failed to append an expired key to the AOF: {}", e);
+        }
+    }
+}
+
+// How a write is propagated, taken from the command before it runs, as
+// running it consumes the command
+#[derive(Debug, PartialEq)]
+pub enum Effects {
+    // The same wherever it is replayed
+    AsSent,
+    // EXPIRE and friends, GETEX: the key's deadline once it ran
+    Deadline { key: String },
+    // SETEX and PSETEX: a plain SET, then the deadline
+    SetDeadline { key: String, value: String },
+    // RESTORE with a relative TTL: as sent, then the deadline
+    SentDeadline { key: String },
+    // SPOP: an SREM of what it took
+    Popped { key: String },
+    // MIGRATE: a DEL of what left for the target
+    Migrated { keys: Vec<String> },
+    // Nothing to send, or sent by the command itself like BLMOVE
+    None,
+}
+
+impl Effects {
+    pub fn of(cmd: &Command) -> Self {
+        match cmd {
+            // Without an option it only reads
+            Command::GetEx {
+                option: GetExOption::None,
+                ..
+            } => Self::None,
+            Command::Expire { key, .. }
+            | Command::PExpire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::PExpireAt { key, .. }
+            | Command::GetEx { key, .. } => Self::Deadline { key: key.clone() },
+            Command::SetEx { key, value, .. } | Command::PSetEx { key, value, .. } => {
+                Self::SetDeadline {
+                    key: key.clone(),
+                    value: value.clone(),
+                }
+            }
+            Command::Restore {
+                key,
+                ttl,
+                absttl: false,
+                ..
+            } if *ttl > 0 => Self::SentDeadline { key: key.clone() },
+            Command::SPop { key, .. } => Self::Popped { key: key.clone() },
+            Command::Migrate {
+                keys, copy: false, ..
+            } => Self::Migrated { keys: keys.clone() },
+            Command::Migrate { .. } | Command::BLMove { .. } => Self::None,
+            _ => Self::AsSent,
+        }
+    }
+
+    // The frame to send for a write that ran in db, raw as the client sent
+    // it and answered reply. Empty when it changed nothing.
+    pub fn frame<S>(self, raw: &[u8], reply: &RespValue, db: &DB<S, String, Value>) -> BytesMut
+    where
+        S: Storage<String, Value>,
+    {
+        let mut frame = BytesMut::new();
+        if matches!(
+            reply,
+            RespValue::Error(_) | RespValue::BulkString(None) | RespValue::Array(None)
+        ) {
+            return frame;
+        }
+        match self {
+            Self::AsSent => frame.extend_from_slice(raw),
+            Self::Deadline { key } => {
+                // EXPIRE answers 0 when nothing changed
+                if *reply != RespValue::Integer(0) {
+                    deadline(db, &key, &mut frame);
+                }
+            }
+            Self::SetDeadline { key, value } => {
+                encode(&["SET", key.as_str(), value.as_str()], &mut frame);
+                deadline(db, &key, &mut frame);
+            }
+            Self::SentDeadline { key } => {
+                frame.extend_from_slice(raw);
+                deadline(db, &key, &mut frame);
+            }
+            Self::Popped { key } => {
+                let members: Vec<&str> = match reply {
+                    RespValue::BulkString(Some(member)) => vec![member.as_ref()],
+                    RespValue::Array(Some(members)) => members
+                        .iter()
+                        .filter_map(|member| match member {
+                            RespValue::BulkString(Some(member)) => Some(member.as_ref()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                if !members.is_empty() {
+                    let mut args = vec!["SREM", key.as_str()];
+                    args.extend(members);
+                    encode(&args, &mut frame);
+                }
+            }
+            Self::Migrated { keys } => {
+                if !matches!(reply, RespValue::SimpleString(s) if s == "NOKEY") {
+                    let mut args = vec!["DEL"];
+                    args.extend(keys.iter().map(String::as_str));
+                    encode(&args, &mut frame);
+                }
+            }
+            Self::None => {}
+        }
+        frame
+    }
+}
+
+// What the TTL of key came to: an absolute PEXPIREAT, a PERSIST, or a DEL
+// when it was already due
+fn deadline<S>(db: &DB<S, String, Value>, key: &str, frame: &mut BytesMut)
+where
+    S: Storage<String, Value>,
+{
+    match db.expiry(&key.to_string()) {
+        Ok(Expiry::At(when)) => encode(&["PEXPIREAT", key, &when.to_string()], frame),
+        Ok(Expiry::Persistent) => encode(&["PERSIST", key], frame),
+        Ok(Expiry::NoKey) | Err(_) => encode(&["DEL", key], frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::ExpireCondition;
+    use crate::db::storage::DashMapStorage;
+
+    fn frame(args: &[&str]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        encode(args, &mut frame);
+        frame
+    }
+
+    #[test]
+    fn test_effects() {
+        let db = DB::new(DashMapStorage::new(), 0);
+        let when = crate::db::db::now_ms() + 60_000;
+        db.set_with_expiry("t".to_string(), Value::str("v"), when)
+            .unwrap();
+        db.set("p".to_string(), Value::str("v")).unwrap();
+
+        let expire = Command::Expire {
+            key: "t".to_string(),
+            seconds: 60,
+            condition: ExpireCondition::Always,
+        };
+        assert_eq!(
+            Effects::of(&expire).frame(b"raw", &RespValue::Integer(1), &db),
+            frame(&["PEXPIREAT", "t", &when.to_string()])
+        );
+        assert!(Effects::of(&expire)
+            .frame(b"raw", &RespValue::Integer(0), &db)
+            .is_empty());
+        let setex = Command::SetEx {
+            key: "t".to_string(),
+            seconds: 60,
+            value: "v".to_string(),
+        };
+        let mut expected = frame(&["SET", "t", "v"]);
+        expected.extend_from_slice(&frame(&["PEXPIREAT", "t", &when.to_string()]));
+        assert_eq!(
+            Effects::of(&setex).frame(b"raw", &RespValue::SimpleString("OK".into()), &db),
+            expected
+        );
+        // A deadline already due is a DEL
+        let gone = Command::PExpire {
+            key: "gone".to_string(),
+            milliseconds: 1,
+            condition: ExpireCondition::Always,
+        };
+        assert_eq!(
+            Effects::of(&gone).frame(b"raw", &RespValue::Integer(1), &db),
+            frame(&["DEL", "gone"])
+        );
+        let persist = Command::GetEx {
+            key: "p".to_string(),
+            option: GetExOption::Persist,
+        };
+        assert_eq!(
+            Effects::of(&persist).frame(b"raw", &RespValue::BulkString(Some("v".into())), &db),
+            frame(&["PERSIST", "p"])
+        );
+
+        let spop = Command::SPop {
+            key: "s".to_string(),
+            count: Some(2),
+        };
+        let popped = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some("a".into())),
+            RespValue::BulkString(Some("b".into())),
+        ]));
+        assert_eq!(
+            Effects::of(&spop).frame(b"raw", &popped, &db),
+            frame(&["SREM", "s", "a", "b"])
+        );
+        assert!(Effects::of(&spop)
+            .frame(b"raw", &RespValue::Array(Some(vec![])), &db)
+            .is_empty());
+
+        let set = Command::Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        assert_eq!(
+            &Effects::of(&set).frame(b"raw", &RespValue::SimpleString("OK".into()), &db)[..],
+            b"raw"
+        );
+        // Errors and timed out blocking commands send nothing
+        assert!(Effects::of(&set)
+            .frame(b"raw", &RespValue::Error("ERR".into()), &db)
+            .is_empty());
+        let blmove = Command::BLMove {
+            source: "a".to_string(),
+            destination: "b".to_string(),
+            from_left: true,
+            to_left: true,
+            timeout: None,
+        };
+        assert_eq!(Effects::of(&blmove), Effects::None);
+    }
+}
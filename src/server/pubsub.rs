@@ -0,0 +1,306 @@
+// Channels and patterns clients subscribed to, and PUBLISH delivering to
+// them. Confirmations and messages go out through the subscriber's
+// ClientHandle, as pushes in RESP3 and as plain arrays in RESP2, where the
+// connection then only takes the subscription commands and PING.
+use crate::protocal::glob::glob_match;
+use crate::server::clients::{ClientHandle, ClientRegistry};
+use dashmap::DashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Channel,
+    Pattern,
+}
+
+impl Kind {
+    // The words confirmations lead with
+    fn replies(self) -> (&'static str, &'static str) {
+        match self {
+            Kind::Channel => ("subscribe", "unsubscribe"),
+            Kind::Pattern => ("psubscribe", "punsubscribe"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscriptions {
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscriptions {
+    fn of(&mut self, kind: Kind) -> &mut BTreeSet<String> {
+        match kind {
+            Kind::Channel => &mut self.channels,
+            Kind::Pattern => &mut self.patterns,
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+// A client's subscriptions are locked before the channel or pattern entries
+// they name, and a confirmation is queued while both are held, so it always
+// goes out ahead of the first message it announces.
+#[derive(Debug)]
+pub struct PubSub {
+    clients: Arc<ClientRegistry>,
+    channels: DashMap<String, HashSet<u64>>,
+    patterns: DashMap<String, HashSet<u64>>,
+    subscribers: DashMap<u64, Subscriptions>,
+}
+
+fn bulk(s: &str) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s.to_string())))
+}
+
+// A push in RESP3, an array the client reads as one in RESP2
+fn frame(client: &ClientHandle, items: Vec<RespValue<'static>>) -> RespValue<'static> {
+    if client.protocol() >= 3 {
+        RespValue::Push(Some(items))
+    } else {
+        RespValue::Array(Some(items))
+    }
+}
+
+impl PubSub {
+    pub fn new(clients: Arc<ClientRegistry>) -> Self {
+        Self {
+            clients,
+            channels: DashMap::new(),
+            patterns: DashMap::new(),
+            subscribers: DashMap::new(),
+        }
+    }
+
+    fn table(&self, kind: Kind) -> &DashMap<String, HashSet<u64>> {
+        match kind {
+            Kind::Channel => &self.channels,
+            Kind::Pattern => &self.patterns,
+        }
+    }
+
+    // SUBSCRIBE or PSUBSCRIBE, confirming each name with the client's count
+    // of subscriptions after it. Returns that count.
+    pub fn subscribe(&self, client: &ClientHandle, kind: Kind, names: &[String]) -> usize {
+        let mut subscriptions = self.subscribers.entry(client.id()).or_default();
+        for name in names {
+            let mut subscribers = self.table(kind).entry(name.clone()).or_default();
+            subscribers.insert(client.id());
+            subscriptions.of(kind).insert(name.clone());
+            client.push(&frame(
+                client,
+                vec![
+                    bulk(kind.replies().0),
+                    bulk(name),
+                    RespValue::Integer(subscriptions.count() as i64),
+                ],
+            ));
+        }
+        subscriptions.count()
+    }
+
+    // UNSUBSCRIBE or PUNSUBSCRIBE, from every channel or pattern of kind
+    // when names is empty. Returns the count of subscriptions left.
+    pub fn unsubscribe(&self, client: &ClientHandle, kind: Kind, names: &[String]) -> usize {
+        let mut subscriptions = self.subscribers.entry(client.id()).or_default();
+        let names: Vec<String> = match names {
+            [] => subscriptions.of(kind).iter().cloned().collect(),
+            names => names.to_vec(),
+        };
+        let word = kind.replies().1;
+        for name in &names {
+            if subscriptions.of(kind).remove(name) {
+                self.table(kind).remove_if_mut(name, |_, subscribers| {
+                    subscribers.remove(&client.id());
+                    subscribers.is_empty()
+                });
+            }
+            client.push(&frame(
+                client,
+                vec![
+                    bulk(word),
+                    bulk(name),
+                    RespValue::Integer(subscriptions.count() as i64),
+                ],
+            ));
+        }
+        // Like Redis, an unsubscribe from nothing is still answered
+        if names.is_empty() {
+            client.push(&frame(
+                client,
+                vec![
+                    bulk(word),
+                    RespValue::BulkString(None),
+                    RespValue::Integer(subscriptions.count() as i64),
+                ],
+            ));
+        }
+        let count = subscriptions.count();
+        drop(subscriptions);
+        if count == 0 {
+            self.subscribers
+                .remove_if(&client.id(), |_, s| s.count() == 0);
+        }
+        count
+    }
+
+    // Subscriptions the client holds
+    pub fn count(&self, client_id: u64) -> usize {
+        self.subscribers
+            .get(&client_id)
+            .map_or(0, |subscriptions| subscriptions.count())
+    }
+
+    // Drops every subscription of the client without confirming any, when it
+    // disconnects or RESETs
+    pub fn disconnect(&self, client_id: u64) {
+        let Some((_, subscriptions)) = self.subscribers.remove(&client_id) else {
+            return;
+        };
+        for (kind, names) in [
+            (Kind::Channel, subscriptions.channels),
+            (Kind::Pattern, subscriptions.patterns),
+        ] {
+            for name in names {
+                self.table(kind).remove_if_mut(&name, |_, subscribers| {
+                    subscribers.remove(&client_id);
+                    subscribers.is_empty()
+                });
+            }
+        }
+    }
+
+    // PUBLISH: the count of clients the message was sent to, a client with
+    // a matching pattern and the channel itself counting twice like in Redis
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            for id in subscribers.iter() {
+                if let Some(client) = self.clients.get(*id) {
+                    client.push(&frame(
+                        &client,
+                        vec![bulk("message"), bulk(channel), bulk(message)],
+                    ));
+                    receivers += 1;
+                }
+            }
+        }
+        for entry in self.patterns.iter() {
+            let pattern = entry.key();
+            if !glob_match(pattern.as_bytes(), channel.as_bytes(), false) {
+                continue;
+            }
+            for id in entry.value().iter() {
+                if let Some(client) = self.clients.get(*id) {
+                    client.push(&frame(
+                        &client,
+                        vec![
+                            bulk("pmessage"),
+                            bulk(pattern),
+                            bulk(channel),
+                            bulk(message),
+                        ],
+                    ));
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio::sync::mpsc;
+
+    fn client(
+        registry: &ClientRegistry,
+        id: u64,
+        protocol: u8,
+    ) -> (ClientHandle, mpsc::Receiver<BytesMut>) {
+        let (tx, rx) = mpsc::channel(16);
+        let handle = ClientHandle::new(id, "127.0.0.1:1".parse().unwrap(), tx);
+        handle.set_protocol(protocol);
+        registry.register(handle.clone());
+        (handle, rx)
+    }
+
+    fn received(rx: &mut mpsc::Receiver<BytesMut>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Ok(frame) = rx.try_recv() {
+            bytes.extend_from_slice(&frame);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let registry = Arc::new(ClientRegistry::default());
+        let pubsub = PubSub::new(registry.clone());
+        let (resp2, mut rx2) = client(&registry, 1, 2);
+        let (resp3, mut rx3) = client(&registry, 2, 3);
+
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            pubsub.subscribe(&resp2, Kind::Channel, &names(&["news"])),
+            1
+        );
+        assert_eq!(pubsub.subscribe(&resp2, Kind::Pattern, &names(&["n*"])), 2);
+        assert_eq!(
+            pubsub.subscribe(&resp3, Kind::Channel, &names(&["news"])),
+            1
+        );
+        assert_eq!(
+            received(&mut rx2),
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n\
+              *3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:2\r\n"
+        );
+        assert_eq!(
+            received(&mut rx3),
+            b">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+
+        assert_eq!(pubsub.publish("news", "hi"), 3);
+        assert_eq!(pubsub.publish("nope", "hi"), 1);
+        assert_eq!(pubsub.publish("other", "hi"), 0);
+        assert_eq!(
+            received(&mut rx2),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n\
+              *4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$2\r\nhi\r\n\
+              *4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnope\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            received(&mut rx3),
+            b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+
+        // All channels, the pattern stays
+        assert_eq!(pubsub.unsubscribe(&resp2, Kind::Channel, &[]), 1);
+        assert_eq!(
+            received(&mut rx2),
+            b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(pubsub.unsubscribe(&resp2, Kind::Channel, &[]), 1);
+        assert_eq!(
+            received(&mut rx2),
+            b"*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:1\r\n"
+        );
+        assert_eq!(pubsub.count(1), 1);
+
+        pubsub.disconnect(1);
+        pubsub.disconnect(2);
+        assert_eq!(pubsub.count(1), 0);
+        assert_eq!(pubsub.publish("news", "hi"), 0);
+        assert!(pubsub.channels.is_empty() && pubsub.patterns.is_empty());
+        assert!(pubsub.subscribers.is_empty());
+    }
+}
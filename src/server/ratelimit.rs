@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+// What shares a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitKey {
+    // Every connection has its own
+    #[default]
+    Client,
+    // Connections authenticated as the same user share one
+    User,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(Self::Client),
+            "user" => Ok(Self::User),
+            _ => Err(format!("invalid rate limit key '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Client => write!(f, "client"),
+            Self::User => write!(f, "user"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Client(u64),
+    User(String),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    key: RateLimitKey,
+    buckets: HashMap<Subject, Bucket>,
+}
+
+// Token bucket limiting of commands. Buckets refill at `rate` commands per
+// second up to `burst`; a command finding its bucket empty is refused. A
+// rate of 0 turns limiting off.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    rate: AtomicU64,
+    // 0 means the same as rate, one second worth of commands
+    burst: AtomicU64,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: u64, key: RateLimitKey) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            burst: AtomicU64::new(burst),
+            state: Mutex::new(State {
+                key,
+                buckets: HashMap::new(),
+            }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst.load(Ordering::Relaxed)
+    }
+
+    pub fn set_burst(&self, burst: u64) {
+        self.burst.store(burst, Ordering::Relaxed);
+    }
+
+    pub fn key(&self) -> RateLimitKey {
+        self.state().key
+    }
+
+    // Buckets of the old kind mean nothing under the new one
+    pub fn set_key(&self, key: RateLimitKey) {
+        let mut state = self.state();
+        state.key = key;
+        state.buckets.clear();
+    }
+
+    // Takes a token for one command of the client, false when there is none
+    pub fn allow(&self, client_id: u64, user: &str) -> bool {
+        self.allow_at(client_id, user, Instant::now())
+    }
+
+    fn allow_at(&self, client_id: u64, user: &str, now: Instant) -> bool {
+        let rate = self.rate();
+        if rate == 0 {
+            return true;
+        }
+        let burst = match self.burst() {
+            0 => rate,
+            burst => burst,
+        } as f64;
+
+        let mut state = self.state();
+        let subject = match state.key {
+            RateLimitKey::Client => Subject::Client(client_id),
+            RateLimitKey::User => Subject::User(user.to_string()),
+        };
+        let bucket = state.buckets.entry(subject).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let refill = now.saturating_duration_since(bucket.last).as_secs_f64() * rate as f64;
+        bucket.tokens = (bucket.tokens + refill).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Drops the bucket of a closed connection
+    pub fn disconnect(&self, client_id: u64) {
+        self.state().buckets.remove(&Subject::Client(client_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(0, 0, RateLimitKey::Client);
+        let start = Instant::now();
+        assert!((0..1000).all(|_| limiter.allow_at(1, "default", start)));
+
+        // The burst goes at once, then one command per 1/rate seconds
+        limiter.set_rate(10);
+        limiter.set_burst(3);
+        assert!((0..3).all(|_| limiter.allow_at(1, "default", start)));
+        assert!(!limiter.allow_at(1, "default", start));
+        assert!(limiter.allow_at(2, "default", start));
+        let later = start + Duration::from_millis(100);
+        assert!(limiter.allow_at(1, "default", later));
+        assert!(!limiter.allow_at(1, "default", later));
+
+        // Idle time refills no further than the burst
+        let idle = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.allow_at(1, "default", idle)));
+        assert!(!limiter.allow_at(1, "default", idle));
+    }
+
+    #[test]
+    fn test_keyed_by_user() {
+        let limiter = RateLimiter::new(2, 0, RateLimitKey::User);
+        let now = Instant::now();
+        assert!(limiter.allow_at(1, "default", now));
+        assert!(limiter.allow_at(2, "default", now));
+        assert!(!limiter.allow_at(3, "default", now));
+        assert!(limiter.allow_at(3, "other", now));
+
+        limiter.set_key(RateLimitKey::Client);
+        assert!(limiter.allow_at(3, "default", now));
+        limiter.disconnect(3);
+        assert!(limiter.state().buckets.is_empty());
+    }
+}
@@ -0,0 +1,658 @@
+use crate::server::clients::ClientHandle;
+use bytes::BytesMut;
+use rand::Rng;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::Instant;
+use stream_resp::resp::RespValue;
+use tracing::warn;
+
+// Shown as master_replid2 while there was no earlier replication history
+const NO_REPLID: &str = "0000000000000000000000000000000000000000";
+
+// repl-backlog-size default, and the least it can be set to, as in Redis
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+pub const MIN_BACKLOG_SIZE: usize = 16 * 1024;
+
+fn new_replid() -> String {
+    let mut rng = rand::thread_rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+// The last `size` bytes of the replication stream, oldest dropped first, so
+// a replica that lost its link can continue from where it was instead of
+// loading a whole snapshot again. `end` is the offset of the last byte.
+type ReplicaInfo = (IpAddr, String, u16, u64, u64, bool);
+
+#[derive(Debug)]
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    size: usize,
+    end: u64,
+}
+
+impl Backlog {
+    pub fn new(size: usize, end: u64) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(size),
+            size,
+            end,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.end += bytes.len() as u64;
+        // Of a write larger than the whole backlog only the tail is kept
+        let bytes = &bytes[bytes.len().saturating_sub(self.size)..];
+        let overflow = (self.buf.len() + bytes.len()).saturating_sub(self.size);
+        self.buf.drain(..overflow);
+        self.buf.extend(bytes);
+    }
+
+    pub fn resize(&mut self, size: usize) {
+        self.size = size;
+        let overflow = self.buf.len().saturating_sub(size);
+        self.buf.drain(..overflow);
+    }
+
+    // Offset of the oldest byte held, the next one to come when empty
+    pub fn first_offset(&self) -> u64 {
+        self.end + 1 - self.buf.len() as u64
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.buf.len()
+    }
+
+    // The stream from offset on, None when that part is gone or not there
+    // yet
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.first_offset() || offset > self.end + 1 {
+            return None;
+        }
+        let skip = (offset - self.first_offset()) as usize;
+        Some(self.buf.range(skip..).copied().collect())
+    }
+}
+
+#[derive(Debug)]
+struct Ids {
+    // 40 hex characters, new on every start
+    replid: String,
+    // The id before the last promotion, valid for offsets up to second_offset
+    replid2: String,
+    second_offset: i64,
+}
+
+// What a replica told about itself with REPLCONF before its PSYNC
+#[derive(Debug, Default)]
+struct Announced {
+    port: u16,
+    ip: Option<String>,
+}
+
+#[derive(Debug)]
+struct Replica {
+    handle: ClientHandle,
+    announced: Announced,
+    // Last offset the replica acknowledged with REPLCONF ACK
+    ack: u64,
+    last_ack: Instant,
+    // Offset a full resync started at, while its snapshot is on the way
+    syncing: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Stream {
+    // Created by the first PSYNC, writes are only streamed from then on
+    backlog: Option<Backlog>,
+    // Db the stream has selected, None until the next write selects one
+    db: Option<usize>,
+    replicas: HashMap<u64, Replica>,
+    announced: HashMap<u64, Announced>,
+}
+
+// Replication state ROLE and INFO report. As a master the server streams
+// write commands to replicas attached with PSYNC, as clients sent them:
+// commands with random or relative effects (SPOP, EXPIRE) aren't rewritten
+// yet, and a write in flight while a full resync takes its snapshot can
+// reach the replica twice. A full resync saves a copy-on-write snapshot off
+// the runtime while writes go on, then sends what the backlog kept since.
+// REPLICAOF makes the server a replica, refusing
+// writes while replica-read-only is on, but nothing syncs from the master
+// yet: its link always shows as down.
+#[derive(Debug)]
+pub struct Replication {
+    ids: RwLock<Ids>,
+    offset: AtomicU64,
+    // host and port set by REPLICAOF, None for a master
+    master: RwLock<Option<(String, u16)>>,
+    read_only: AtomicBool,
+    backlog_size: AtomicUsize,
+    // Whether there is a backlog, checked on every write before locking
+    streaming: AtomicBool,
+    stream: Mutex<Stream>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            ids: RwLock::new(Ids {
+                replid: new_replid(),
+                replid2: NO_REPLID.to_string(),
+                second_offset: -1,
+            }),
+            offset: AtomicU64::new(0),
+            master: RwLock::new(None),
+            read_only: AtomicBool::new(true),
+            backlog_size: AtomicUsize::new(DEFAULT_BACKLOG_SIZE),
+            streaming: AtomicBool::new(false),
+            stream: Mutex::new(Stream::default()),
+        }
+    }
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Self {
+        let replication = Self::default();
+        replication.set_backlog_size(backlog_size);
+        replication
+    }
+
+    fn stream(&self) -> MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn replid(&self) -> String {
+        self.ids
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .replid
+            .clone()
+    }
+
+    // DEBUG CHANGE-REPL-ID: a new id and no earlier history, so every
+    // replica has to resync in full
+    pub fn change_replid(&self) {
+        let mut ids = self.ids.write().unwrap_or_else(|e| e.into_inner());
+        ids.replid = new_replid();
+        ids.replid2 = NO_REPLID.to_string();
+        ids.second_offset = -1;
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    pub fn master(&self) -> Option<(String, u16)> {
+        self.master
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // REPLICAOF host port, or REPLICAOF NO ONE with None. A promoted
+    // replica keeps its history under replid2, so the other replicas of its
+    // old master can continue from it. Replicas of a server that becomes a
+    // replica itself are disconnected, nothing is streamed to them anymore.
+    pub fn set_master(&self, master: Option<(String, u16)>) {
+        let promoted = master.is_none();
+        let was_replica = std::mem::replace(
+            &mut *self.master.write().unwrap_or_else(|e| e.into_inner()),
+            master,
+        )
+        .is_some();
+        if !promoted {
+            let mut stream = self.stream();
+            for (_, replica) in stream.replicas.drain() {
+                replica.handle.kill();
+            }
+        } else if was_replica {
+            let mut ids = self.ids.write().unwrap_or_else(|e| e.into_inner());
+            ids.replid2 = std::mem::replace(&mut ids.replid, new_replid());
+            ids.second_offset = self.offset() as i64 + 1;
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    // Whether commands flagged `write` are refused with READONLY
+    pub fn refuses_writes(&self) -> bool {
+        self.read_only() && self.master().is_some()
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.backlog_size.load(Ordering::Relaxed)
+    }
+
+    // Shrinking drops the oldest part of the backlog
+    pub fn set_backlog_size(&self, size: usize) {
+        let size = size.max(MIN_BACKLOG_SIZE);
+        self.backlog_size.store(size, Ordering::Relaxed);
+        if let Some(backlog) = &mut self.stream().backlog {
+            backlog.resize(size);
+        }
+    }
+
+    // Whether writes have to be fed to the stream
+    pub fn streaming(&self) -> bool {
+        self.streaming.load(Ordering::Relaxed)
+    }
+
+    // Adds the effects of a write command to the stream. A replica too far
+    // behind to take it is dropped, and continues from the backlog when it
+    // reconnects.
+    pub fn feed(&self, db: usize, request: &[u8]) {
+        let mut stream = self.stream();
+        let stream = &mut *stream;
+        let Some(backlog) = &mut stream.backlog else {
+            return;
+        };
+        let mut frame = BytesMut::new();
+        if stream.db != Some(db) {
+            let index = db.to_string();
+            frame.extend_from_slice(
+                format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", index.len(), index).as_bytes(),
+            );
+            stream.db = Some(db);
+        }
+        frame.extend_from_slice(request);
+        backlog.push(&frame);
+        self.offset.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        stream.replicas.retain(|_, replica| {
+            replica.syncing.is_some() || replica.handle.push_raw(frame.clone())
+        });
+    }
+
+    // REPLCONF listening-port and ip-address, taken into account by the
+    // connection's PSYNC
+    pub fn announce(&self, id: u64, port: Option<u16>, ip: Option<String>) {
+        let mut stream = self.stream();
+        let announced = stream.announced.entry(id).or_default();
+        if let Some(port) = port {
+            announced.port = port;
+        }
+        if ip.is_some() {
+            announced.ip = ip;
+        }
+    }
+
+    // PSYNC replid offset, answered on the replica's connection ahead of the
+    // writes streamed to it from then on. When replid and offset are in this
+    // server's history it continues from the backlog, and None is returned.
+    // Otherwise it's told a full resync starts at the current offset, and
+    // what freeze returns, the data as of that offset, is for full_sync to
+    // send. Writes go on meanwhile, the backlog keeps them for the replica.
+    pub fn psync<T>(
+        &self,
+        handle: ClientHandle,
+        replid: &str,
+        offset: i64,
+        freeze: impl FnOnce() -> T,
+    ) -> Option<T> {
+        let mut stream = self.stream();
+        let size = self.backlog_size();
+        let backlog = stream
+            .backlog
+            .get_or_insert_with(|| Backlog::new(size, self.offset()));
+        self.streaming.store(true, Ordering::Relaxed);
+
+        let (known, current) = {
+            let ids = self.ids.read().unwrap_or_else(|e| e.into_inner());
+            let known =
+                replid == ids.replid || (replid == ids.replid2 && offset <= ids.second_offset);
+            (known, ids.replid.clone())
+        };
+        let partial = u64::try_from(offset)
+            .ok()
+            .filter(|_| known)
+            .and_then(|offset| backlog.since(offset));
+        let mut frame = BytesMut::new();
+        let (frozen, syncing) = match partial {
+            Some(missed) => {
+                frame.extend_from_slice(format!("+CONTINUE {}\r\n", current).as_bytes());
+                frame.extend_from_slice(&missed);
+                (None, None)
+            }
+            None => {
+                let start = self.offset();
+                frame
+                    .extend_from_slice(format!("+FULLRESYNC {} {}\r\n", current, start).as_bytes());
+                (Some(freeze()), Some(start))
+            }
+        };
+        let id = handle.id();
+        if handle.push_raw(frame) {
+            let announced = stream.announced.remove(&id).unwrap_or_default();
+            let replica = Replica {
+                handle,
+                announced,
+                ack: syncing.unwrap_or(offset.max(0) as u64),
+                last_ack: Instant::now(),
+                syncing,
+            };
+            stream.replicas.insert(id, replica);
+            // Whatever the replica had selected, the next write selects again
+            stream.db = None;
+        }
+        frozen
+    }
+
+    // Sends the snapshot of a full resync, then what was streamed since it
+    // was taken. The replica is dropped when there is no snapshot, or when
+    // the backlog no longer reaches back to it.
+    pub fn full_sync<E: fmt::Display>(&self, id: u64, rdb: Result<Vec<u8>, E>) {
+        let mut stream = self.stream();
+        let stream = &mut *stream;
+        let Some(replica) = stream.replicas.get_mut(&id) else {
+            return;
+        };
+        let Some(start) = replica.syncing else {
+            return;
+        };
+        let missed = stream
+            .backlog
+            .as_ref()
+            .and_then(|backlog| backlog.since(start + 1));
+        let addr = replica.handle.addr();
+        let (rdb, missed) = match (rdb, missed) {
+            (Ok(rdb), Some(missed)) => (rdb, missed),
+            (Err(e), _) => {
+                warn!("Full resync of replica {} failed: {}", addr, e);
+                replica.handle.kill();
+                stream.replicas.remove(&id);
+                return;
+            }
+            (Ok(_), None) => {
+                warn!(
+                    "Full resync of replica {} failed: writes made meanwhile \
+                     overflowed repl-backlog-size",
+                    addr
+                );
+                replica.handle.kill();
+                stream.replicas.remove(&id);
+                return;
+            }
+        };
+        let mut frame = BytesMut::from(format!("${}\r\n", rdb.len()).as_bytes());
+        frame.extend_from_slice(&rdb);
+        frame.extend_from_slice(&missed);
+        if replica.handle.push_raw(frame) {
+            replica.syncing = None;
+        } else {
+            stream.replicas.remove(&id);
+        }
+    }
+
+    // REPLCONF ACK offset from the replica on connection id
+    pub fn ack(&self, id: u64, offset: u64) {
+        if let Some(replica) = self.stream().replicas.get_mut(&id) {
+            replica.ack = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    // The connection closed
+    pub fn detach(&self, id: u64) {
+        let mut stream = self.stream();
+        stream.replicas.remove(&id);
+        stream.announced.remove(&id);
+    }
+
+    // Address, listening port, acknowledged offset and ack age of each
+    // replica, and whether it's still waiting for its full resync
+    fn replicas(&self) -> Vec<ReplicaInfo> {
+        let mut replicas: Vec<_> = self
+            .stream()
+            .replicas
+            .values()
+            .map(|replica| {
+                let ip = replica
+                    .announced
+                    .ip
+                    .clone()
+                    .unwrap_or_else(|| replica.handle.addr().ip().to_string());
+                (
+                    replica.handle.addr().ip(),
+                    ip,
+                    replica.announced.port,
+                    replica.ack,
+                    replica.last_ack.elapsed().as_secs(),
+                    replica.syncing.is_some(),
+                )
+            })
+            .collect();
+        replicas.sort();
+        replicas
+    }
+
+    // ROLE reply: ["master", offset, [[ip, port, offset] per replica]] or
+    // ["slave", host, port, state, offset]
+    pub fn role(&self) -> RespValue<'static> {
+        let offset = RespValue::Integer(self.offset() as i64);
+        let bulk = |s: String| RespValue::BulkString(Some(Cow::Owned(s)));
+        let items = match self.master() {
+            None => vec![
+                RespValue::BulkString(Some(Cow::Borrowed("master"))),
+                offset,
+                RespValue::Array(Some(
+                    self.replicas()
+                        .into_iter()
+                        .map(|(_, ip, port, ack, _, _)| {
+                            RespValue::Array(Some(vec![
+                                bulk(ip),
+                                bulk(port.to_string()),
+                                bulk(ack.to_string()),
+                            ]))
+                        })
+                        .collect(),
+                )),
+            ],
+            Some((host, port)) => vec![
+                RespValue::BulkString(Some(Cow::Borrowed("slave"))),
+                bulk(host),
+                RespValue::Integer(port as i64),
+                RespValue::BulkString(Some(Cow::Borrowed("connect"))),
+                offset,
+            ],
+        };
+        RespValue::Array(Some(items))
+    }
+
+    // The INFO replication section
+    pub fn info(&self) -> String {
+        let role = match self.master() {
+            None => "role:master\r\n".to_string(),
+            Some((host, port)) => format!(
+                "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:down\r\n\
+                 master_last_io_seconds_ago:-1\r\nmaster_sync_in_progress:0\r\n\
+                 slave_read_repl_offset:{}\r\nslave_repl_offset:{}\r\nslave_priority:100\r\n\
+                 slave_read_only:{}\r\nreplica_announced:1\r\n",
+                host,
+                port,
+                self.offset(),
+                self.offset(),
+                u8::from(self.read_only())
+            ),
+        };
+        let replicas = self.replicas();
+        let mut slaves = format!("connected_slaves:{}\r\n", replicas.len());
+        for (i, (_, ip, port, ack, lag, syncing)) in replicas.into_iter().enumerate() {
+            let state = if syncing { "wait_bgsave" } else { "online" };
+            slaves.push_str(&format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i, ip, port, state, ack, lag
+            ));
+        }
+        let (active, first, histlen) = match &self.stream().backlog {
+            Some(backlog) => (1, backlog.first_offset(), backlog.histlen()),
+            None => (0, 0, 0),
+        };
+        let ids = self.ids.read().unwrap_or_else(|e| e.into_inner());
+        format!(
+            "# Replication\r\n{}{}master_failover_state:no-failover\r\n\
+             master_replid:{}\r\nmaster_replid2:{}\r\nmaster_repl_offset:{}\r\n\
+             second_repl_offset:{}\r\nrepl_backlog_active:{}\r\nrepl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+            role,
+            slaves,
+            ids.replid,
+            ids.replid2,
+            self.offset(),
+            ids.second_offset,
+            active,
+            self.backlog_size(),
+            first,
+            histlen
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_replid() {
+        let a = Replication::default();
+        let b = Replication::default();
+        assert_eq!(a.replid().len(), 40);
+        assert!(a.replid().chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a.replid(), b.replid());
+
+        let info = a.info();
+        assert!(info.contains("role:master\r\n"));
+        assert!(info.contains(&format!("master_replid:{}\r\n", a.replid())));
+        assert!(info.contains("master_repl_offset:0\r\n"));
+        assert!(info.contains("repl_backlog_active:0\r\n"));
+
+        let old = a.replid();
+        a.change_replid();
+        assert_ne!(a.replid(), old);
+        assert!(a
+            .info()
+            .contains(&format!("master_replid2:{}\r\n", NO_REPLID)));
+    }
+
+    #[test]
+    fn test_replica_of() {
+        let replication = Replication::default();
+        assert!(!replication.refuses_writes());
+
+        replication.set_master(Some(("10.0.0.1".to_string(), 6379)));
+        assert!(replication.refuses_writes());
+        let info = replication.info();
+        assert!(info.contains("role:slave\r\nmaster_host:10.0.0.1\r\nmaster_port:6379\r\n"));
+        assert!(info.contains("slave_read_only:1\r\n"));
+
+        replication.set_read_only(false);
+        assert!(!replication.refuses_writes());
+        replication.set_read_only(true);
+
+        // The old id stays valid for the history up to the promotion
+        let old = replication.replid();
+        replication.set_master(None);
+        assert!(!replication.refuses_writes());
+        let info = replication.info();
+        assert!(info.contains("role:master\r\n"));
+        assert!(info.contains(&format!("master_replid2:{}\r\n", old)));
+        assert!(info.contains("second_repl_offset:1\r\n"));
+        assert_ne!(replication.replid(), old);
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.first_offset(), 101);
+        assert_eq!(backlog.since(101), Some(vec![]));
+        assert_eq!(backlog.since(102), None);
+
+        backlog.push(b"abcde");
+        assert_eq!(backlog.since(101).unwrap(), b"abcde");
+        assert_eq!(backlog.since(104).unwrap(), b"de");
+        backlog.push(b"fghij");
+        // 101 and 102 are gone
+        assert_eq!(backlog.first_offset(), 103);
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(103).unwrap(), b"cdefghij");
+        assert_eq!(backlog.since(111).unwrap(), b"");
+
+        backlog.push(b"0123456789");
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.since(113).unwrap(), b"23456789");
+        backlog.resize(3);
+        assert_eq!(backlog.since(118).unwrap(), b"789");
+        assert_eq!(backlog.since(117), None);
+    }
+
+    #[test]
+    fn test_psync() {
+        let replication = Replication::default();
+        let (tx, mut rx) = mpsc::channel(16);
+        let handle = ClientHandle::new(1, "127.0.0.1:1".parse().unwrap(), tx);
+        let freeze = || "frozen";
+
+        // Nothing is kept before the first replica asks
+        replication.feed(0, b"*1\r\n$4\r\nPING\r\n");
+        assert_eq!(replication.offset(), 0);
+
+        replication.announce(1, Some(7000), None);
+        assert_eq!(
+            replication.psync(handle.clone(), "?", -1, freeze),
+            Some("frozen")
+        );
+        let full = format!("+FULLRESYNC {} 0\r\n", replication.replid());
+        assert_eq!(&rx.try_recv().unwrap()[..], full.as_bytes());
+
+        // Writes made while the snapshot is saved follow it
+        replication.feed(3, b"*1\r\n$4\r\nPING\r\n");
+        assert!(rx.try_recv().is_err());
+        assert!(replication.info().contains("state=wait_bgsave"));
+        replication.full_sync(1, Ok::<_, String>(b"RDB".to_vec()));
+        let select = b"$3\r\nRDB*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(&rx.try_recv().unwrap()[..], select);
+        assert_eq!(replication.offset(), 23 + 14);
+        replication.ack(1, 37);
+        assert!(replication
+            .info()
+            .contains("slave0:ip=127.0.0.1,port=7000,state=online,offset=37"));
+
+        // Reconnected, it only gets what it missed
+        replication.detach(1);
+        replication.feed(3, b"*1\r\n$4\r\nPING\r\n");
+        assert!(rx.try_recv().is_err());
+        let replid = replication.replid();
+        assert_eq!(replication.psync(handle.clone(), &replid, 38, freeze), None);
+        let expected = format!("+CONTINUE {}\r\n*1\r\n$4\r\nPING\r\n", replid);
+        assert_eq!(&rx.try_recv().unwrap()[..], expected.as_bytes());
+
+        // An unknown history or an offset past the backlog resyncs in full
+        assert_eq!(
+            replication.psync(handle.clone(), NO_REPLID, 38, freeze),
+            Some("frozen")
+        );
+        assert_eq!(
+            replication.psync(handle, &replid, 1000, freeze),
+            Some("frozen")
+        );
+        // A failed snapshot drops the replica
+        replication.full_sync(1, Err("no space left"));
+        assert!(replication.info().contains("connected_slaves:0\r\n"));
+        let info = replication.info();
+        assert!(info.contains("repl_backlog_active:1\r\n"));
+        assert!(info.contains("repl_backlog_first_byte_offset:1\r\nrepl_backlog_histlen:51\r\n"));
+    }
+}
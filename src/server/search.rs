@@ -0,0 +1,620 @@
+// Secondary indexes over hash fields, with the search feature: FT.CREATE,
+// FT.SEARCH and FT.DROPINDEX. An index covers the hashes under its key
+// prefixes in the db it was created in, and keeps an inverted index of the
+// terms of each schema field. It isn't updated by the writes themselves:
+// the db's write hook marks each written key stale, and a search first
+// re-reads its index's stale keys, so it sees every write answered before
+// it. Index definitions live in memory only, the AOF recreates them.
+use crate::db::db::DB;
+use crate::db::listpack;
+use crate::db::storage::Storage;
+use crate::db::value::Value;
+use crate::protocal::command::CommandError;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use stream_resp::resp::RespValue;
+
+type SearchDB<S> = DB<S, String, Value>;
+
+// Results FT.SEARCH returns when no LIMIT is given
+pub const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    // Words, matched by term or prefix in any case
+    Text,
+    // Comma separated tags, matched whole in any case
+    Tag,
+}
+
+// What FT.CREATE declares: PREFIX and SCHEMA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexSchema {
+    // Empty covers every key
+    pub prefixes: Vec<String>,
+    pub fields: Vec<(String, FieldKind)>,
+}
+
+impl IndexSchema {
+    // The arguments after the index name: [ON HASH] [PREFIX count prefix
+    // ...] SCHEMA field TEXT|TAG [field TEXT|TAG ...]
+    pub fn parse(args: &[String]) -> Result<Self, CommandError> {
+        let mut prefixes = vec![];
+        let mut i = 0;
+        loop {
+            match args.get(i).map(|arg| arg.to_uppercase()).as_deref() {
+                Some("ON")
+                    if args
+                        .get(i + 1)
+                        .is_some_and(|on| on.eq_ignore_ascii_case("HASH")) =>
+                {
+                    i += 2;
+                }
+                Some("PREFIX") => {
+                    let count: usize = args
+                        .get(i + 1)
+                        .and_then(|count| count.parse().ok())
+                        .ok_or(CommandError::SyntaxError)?;
+                    let end = i + 2 + count;
+                    prefixes.extend(
+                        args.get(i + 2..end)
+                            .ok_or(CommandError::SyntaxError)?
+                            .iter()
+                            .cloned(),
+                    );
+                    i = end;
+                }
+                Some("SCHEMA") => {
+                    i += 1;
+                    break;
+                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+        }
+        let rest = &args[i..];
+        if rest.is_empty() || !rest.len().is_multiple_of(2) {
+            return Err(CommandError::SyntaxError);
+        }
+        let fields = rest
+            .chunks(2)
+            .map(|pair| {
+                let kind = match pair[1].to_uppercase().as_str() {
+                    "TEXT" => FieldKind::Text,
+                    "TAG" => FieldKind::Tag,
+                    _ => {
+                        return Err(CommandError::SearchSyntax(format!(
+                            "unknown field type '{}'",
+                            pair[1]
+                        )))
+                    }
+                };
+                Ok((pair[0].clone(), kind))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { prefixes, fields })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Exact(String),
+    Prefix(String),
+}
+
+// A condition every result meets: one of the terms in the field, or in any
+// text field when it names none
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Clause {
+    field: Option<String>,
+    any_of: Vec<Term>,
+}
+
+// FT.SEARCH's query: `*` for every document, or terms that must all match.
+// A term is `word`, `prefix*`, `@field:word` or `@field:{tag | tag}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    clauses: Vec<Clause>,
+}
+
+fn term(word: &str) -> Term {
+    let word = word.trim().to_lowercase();
+    match word.strip_suffix('*') {
+        Some(prefix) => Term::Prefix(prefix.to_string()),
+        None => Term::Exact(word),
+    }
+}
+
+impl Query {
+    pub fn parse(text: &str) -> Result<Self, CommandError> {
+        let invalid = |why: &str| CommandError::SearchSyntax(why.to_string());
+        let mut clauses = vec![];
+        let mut rest = text.trim();
+        if rest == "*" {
+            return Ok(Self { clauses });
+        }
+        while !rest.is_empty() {
+            let (field, body) = match rest.strip_prefix('@') {
+                Some(named) => {
+                    let (field, body) = named
+                        .split_once(':')
+                        .ok_or_else(|| invalid("expected ':' after the field name"))?;
+                    (Some(field.to_string()), body.trim_start())
+                }
+                None => (None, rest),
+            };
+            let (any_of, after) = match body.strip_prefix('{') {
+                Some(tags) => {
+                    let (tags, after) = tags
+                        .split_once('}')
+                        .ok_or_else(|| invalid("unclosed '{'"))?;
+                    (tags.split('|').map(term).collect(), after)
+                }
+                None => {
+                    let end = body.find(char::is_whitespace).unwrap_or(body.len());
+                    (vec![term(&body[..end])], &body[end..])
+                }
+            };
+            if any_of.iter().any(
+                |term| matches!(term, Term::Exact(word) | Term::Prefix(word) if word.is_empty()),
+            ) {
+                return Err(invalid("empty term"));
+            }
+            clauses.push(Clause { field, any_of });
+            rest = after.trim_start();
+        }
+        Ok(Self { clauses })
+    }
+}
+
+// The terms a field's value is indexed under
+fn terms(kind: FieldKind, value: &str) -> BTreeSet<String> {
+    match kind {
+        FieldKind::Text => value
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect(),
+        FieldKind::Tag => value
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect(),
+    }
+}
+
+#[derive(Default)]
+struct IndexState {
+    // Keys written since they were last indexed
+    stale: HashSet<String>,
+    // Terms of each indexed key per field, to take them out again
+    docs: HashMap<String, Vec<BTreeSet<String>>>,
+    // Per field, the keys under each term
+    postings: Vec<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl IndexState {
+    fn unindex(&mut self, key: &str) {
+        let Some(fields) = self.docs.remove(key) else {
+            return;
+        };
+        for (postings, terms) in self.postings.iter_mut().zip(fields) {
+            for term in terms {
+                if let Some(keys) = postings.get_mut(&term) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        postings.remove(&term);
+                    }
+                }
+            }
+        }
+    }
+
+    fn index(&mut self, key: String, fields: Vec<BTreeSet<String>>) {
+        for (postings, terms) in self.postings.iter_mut().zip(&fields) {
+            for term in terms {
+                postings
+                    .entry(term.clone())
+                    .or_default()
+                    .insert(key.clone());
+            }
+        }
+        self.docs.insert(key, fields);
+    }
+
+    fn matching(&self, field: usize, term: &Term) -> BTreeSet<String> {
+        let postings = &self.postings[field];
+        match term {
+            Term::Exact(word) => postings.get(word).cloned().unwrap_or_default(),
+            Term::Prefix(prefix) => postings
+                .range(prefix.clone()..)
+                .take_while(|(term, _)| term.starts_with(prefix.as_str()))
+                .flat_map(|(_, keys)| keys.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+struct Index<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    db: Weak<SearchDB<S>>,
+    schema: IndexSchema,
+    state: Mutex<IndexState>,
+}
+
+impl<S> Index<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    fn covers(&self, key: &str) -> bool {
+        self.schema.prefixes.is_empty()
+            || self
+                .schema
+                .prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix.as_str()))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, IndexState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Indexes the stale keys as they are now. The db is read with the state
+    // unlocked, a key written meanwhile is stale again for the next search.
+    fn refresh(&self, db: &SearchDB<S>) -> Result<(), CommandError> {
+        let stale = std::mem::take(&mut self.state().stale);
+        let mut read = Vec::with_capacity(stale.len());
+        for key in stale {
+            let value = listpack::expanded(db.peek(&key).map_err(CommandError::StorageError)?);
+            let fields = match value.as_deref() {
+                Some(Value::Hash(pairs)) => Some(
+                    self.schema
+                        .fields
+                        .iter()
+                        .map(|(name, kind)| {
+                            pairs
+                                .get(name)
+                                .map_or_else(BTreeSet::new, |value| terms(*kind, value))
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            };
+            read.push((key, fields));
+        }
+        let mut state = self.state();
+        for (key, fields) in read {
+            state.unindex(&key);
+            if let Some(fields) = fields {
+                state.index(key, fields);
+            }
+        }
+        Ok(())
+    }
+
+    // Keys matching query, in key order
+    fn find(&self, query: &Query) -> Result<Vec<String>, CommandError> {
+        let state = self.state();
+        let mut found: Option<BTreeSet<String>> = None;
+        for clause in &query.clauses {
+            let fields: Vec<usize> = match &clause.field {
+                Some(name) => vec![self
+                    .schema
+                    .fields
+                    .iter()
+                    .position(|(field, _)| field == name)
+                    .ok_or_else(|| {
+                        CommandError::SearchSyntax(format!("unknown field '{}'", name))
+                    })?],
+                None => (0..self.schema.fields.len())
+                    .filter(|i| self.schema.fields[*i].1 == FieldKind::Text)
+                    .collect(),
+            };
+            let mut keys = BTreeSet::new();
+            for field in fields {
+                for term in &clause.any_of {
+                    keys.extend(state.matching(field, term));
+                }
+            }
+            found = Some(match found {
+                Some(found) => found.intersection(&keys).cloned().collect(),
+                None => keys,
+            });
+        }
+        Ok(match found {
+            Some(found) => found.into_iter().collect(),
+            None => {
+                let mut all: Vec<String> = state.docs.keys().cloned().collect();
+                all.sort_unstable();
+                all
+            }
+        })
+    }
+}
+
+// The indexes of a server, by name
+pub struct Search<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    indexes: RwLock<HashMap<String, Arc<Index<S>>>>,
+}
+
+impl<S> Default for Search<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    fn default() -> Self {
+        Self {
+            indexes: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<S> Search<S>
+where
+    S: Storage<String, Value> + 'static,
+{
+    fn get(&self, name: &str, db: &Arc<SearchDB<S>>) -> Result<Arc<Index<S>>, CommandError> {
+        let indexes = self.indexes.read().unwrap_or_else(|e| e.into_inner());
+        // Another db's index is as good as none
+        match indexes.get(name) {
+            Some(index) if std::ptr::eq(index.db.as_ptr(), Arc::as_ptr(db)) => Ok(index.clone()),
+            _ => Err(CommandError::UnknownIndex),
+        }
+    }
+
+    // Called by every db after a write to key. Which db doesn't matter: an
+    // index re-reading a key of another db only finds it as it was.
+    pub fn written(&self, key: &str) {
+        let indexes = self.indexes.read().unwrap_or_else(|e| e.into_inner());
+        for index in indexes.values() {
+            if index.covers(key) {
+                index.state().stale.insert(key.to_string());
+            }
+        }
+    }
+
+    // Every key the index covers is indexed by the first search
+    pub fn create(
+        &self,
+        db: &Arc<SearchDB<S>>,
+        name: String,
+        schema: IndexSchema,
+    ) -> Result<(), CommandError> {
+        let index = Arc::new(Index {
+            db: Arc::downgrade(db),
+            state: Mutex::new(IndexState {
+                postings: vec![BTreeMap::new(); schema.fields.len()],
+                ..Default::default()
+            }),
+            schema,
+        });
+        {
+            let mut indexes = self.indexes.write().unwrap_or_else(|e| e.into_inner());
+            if indexes.contains_key(&name) {
+                return Err(CommandError::IndexExists);
+            }
+            indexes.insert(name, index.clone());
+        }
+        // Registered first, so keys written from here on are marked too
+        let (_, keys) = db.scan(0, usize::MAX).map_err(CommandError::StorageError)?;
+        let mut state = index.state();
+        state
+            .stale
+            .extend(keys.into_iter().filter(|key| index.covers(key)));
+        Ok(())
+    }
+
+    pub fn drop_index(&self, db: &Arc<SearchDB<S>>, name: &str) -> Result<(), CommandError> {
+        self.get(name, db)?;
+        let mut indexes = self.indexes.write().unwrap_or_else(|e| e.into_inner());
+        indexes.remove(name);
+        Ok(())
+    }
+
+    // FT.SEARCH: the number of matches, then count of them from offset on,
+    // each key followed by its fields unless no_content
+    pub fn search(
+        &self,
+        db: &Arc<SearchDB<S>>,
+        name: &str,
+        query: &Query,
+        offset: usize,
+        count: usize,
+        no_content: bool,
+    ) -> Result<RespValue<'static>, CommandError> {
+        let index = self.get(name, db)?;
+        index.refresh(db)?;
+        let found = index.find(query)?;
+        let mut reply = vec![RespValue::Integer(found.len() as i64)];
+        for key in found.into_iter().skip(offset).take(count) {
+            if no_content {
+                reply.push(bulk(key));
+                continue;
+            }
+            let value = listpack::expanded(db.get(&key).map_err(CommandError::StorageError)?);
+            // Deleted since the refresh
+            let Some(Value::Hash(pairs)) = value.as_deref() else {
+                continue;
+            };
+            let fields = pairs
+                .iter()
+                .flat_map(|(field, value)| [bulk(field.clone()), bulk(value.clone())])
+                .collect();
+            reply.push(bulk(key));
+            reply.push(RespValue::Array(Some(fields)));
+        }
+        Ok(RespValue::Array(Some(reply)))
+    }
+}
+
+fn bulk(s: String) -> RespValue<'static> {
+    RespValue::BulkString(Some(Cow::Owned(s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::storage::DashMapStorage;
+
+    type TestDB = SearchDB<DashMapStorage<String, Value>>;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn hset(
+        db: &TestDB,
+        search: &Search<DashMapStorage<String, Value>>,
+        key: &str,
+        pairs: &[(&str, &str)],
+    ) {
+        let pairs = pairs
+            .iter()
+            .map(|(f, v)| (f.to_string(), v.to_string()))
+            .collect();
+        db.set(key.to_string(), Value::Hash(pairs)).unwrap();
+        search.written(key);
+    }
+
+    fn keys(reply: RespValue) -> (i64, Vec<String>) {
+        match reply {
+            RespValue::Array(Some(items)) => {
+                let total = match items[0] {
+                    RespValue::Integer(n) => n,
+                    _ => panic!("no total"),
+                };
+                let keys = items[1..]
+                    .iter()
+                    .filter_map(|item| match item {
+                        RespValue::BulkString(Some(s)) => Some(s.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                (total, keys)
+            }
+            other => panic!("not an array: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let schema = IndexSchema::parse(&strings(&[
+            "ON", "HASH", "PREFIX", "2", "doc:", "post:", "SCHEMA", "title", "TEXT", "tags", "TAG",
+        ]))
+        .unwrap();
+        assert_eq!(schema.prefixes, strings(&["doc:", "post:"]));
+        assert_eq!(schema.fields[1], ("tags".to_string(), FieldKind::Tag));
+        for bad in [
+            &["SCHEMA"][..],
+            &["SCHEMA", "a", "NUMBER"],
+            &["PREFIX", "3", "a", "SCHEMA"],
+        ] {
+            assert!(IndexSchema::parse(&strings(bad)).is_err());
+        }
+
+        let query = Query::parse("hello wor* @tags:{Red | blue}").unwrap();
+        assert_eq!(query.clauses.len(), 3);
+        assert_eq!(
+            query.clauses[1].any_of,
+            vec![Term::Prefix("wor".to_string())]
+        );
+        assert_eq!(
+            query.clauses[2].any_of,
+            vec![
+                Term::Exact("red".to_string()),
+                Term::Exact("blue".to_string())
+            ]
+        );
+        assert!(Query::parse("*").unwrap().clauses.is_empty());
+        assert!(Query::parse("@tags:{red").is_err());
+        assert!(Query::parse("@tags").is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        let db = Arc::new(TestDB::new(DashMapStorage::new(), 0));
+        let search = Search::default();
+        hset(
+            &db,
+            &search,
+            "doc:1",
+            &[("title", "Hello World"), ("tags", "red,green")],
+        );
+        let schema = IndexSchema::parse(&strings(&[
+            "PREFIX", "1", "doc:", "SCHEMA", "title", "TEXT", "tags", "TAG",
+        ]))
+        .unwrap();
+        search
+            .create(&db, "idx".to_string(), schema.clone())
+            .unwrap();
+        assert!(search.create(&db, "idx".to_string(), schema).is_err());
+        // Written after the index, or outside its prefix
+        hset(
+            &db,
+            &search,
+            "doc:2",
+            &[("title", "hello there"), ("tags", "blue")],
+        );
+        hset(&db, &search, "other", &[("title", "hello")]);
+
+        let run = |query: &str, offset, count| {
+            let query = Query::parse(query).unwrap();
+            keys(
+                search
+                    .search(&db, "idx", &query, offset, count, true)
+                    .unwrap(),
+            )
+        };
+        assert_eq!(run("hello", 0, 10), (2, strings(&["doc:1", "doc:2"])));
+        assert_eq!(run("hello", 1, 10), (2, strings(&["doc:2"])));
+        assert_eq!(run("wor*", 0, 10), (1, strings(&["doc:1"])));
+        assert_eq!(
+            run("hello @tags:{blue | green}", 0, 1),
+            (2, strings(&["doc:1"]))
+        );
+        assert_eq!(run("@tags:{red} there", 0, 10), (0, vec![]));
+        assert_eq!(run("*", 0, 10).0, 2);
+
+        // A rewrite and a delete are seen by the next search
+        hset(
+            &db,
+            &search,
+            "doc:1",
+            &[("title", "goodbye"), ("tags", "red")],
+        );
+        db.delete(&strings(&["doc:2"])).unwrap();
+        search.written("doc:2");
+        assert_eq!(run("hello", 0, 10), (0, vec![]));
+        assert_eq!(run("goodbye", 0, 10), (1, strings(&["doc:1"])));
+
+        let query = Query::parse("goodbye").unwrap();
+        let reply = search.search(&db, "idx", &query, 0, 10, false).unwrap();
+        assert_eq!(
+            reply,
+            RespValue::Array(Some(vec![
+                RespValue::Integer(1),
+                bulk("doc:1".to_string()),
+                RespValue::Array(Some(vec![
+                    bulk("title".to_string()),
+                    bulk("goodbye".to_string()),
+                    bulk("tags".to_string()),
+                    bulk("red".to_string()),
+                ])),
+            ]))
+        );
+        assert!(search
+            .search(&db, "idx", &Query::parse("@nope:x").unwrap(), 0, 10, true)
+            .is_err());
+
+        // Other dbs don't see the index
+        let other = Arc::new(TestDB::new(DashMapStorage::new(), 0));
+        assert!(search.search(&other, "idx", &query, 0, 10, true).is_err());
+        assert!(search.drop_index(&other, "idx").is_err());
+        search.drop_index(&db, "idx").unwrap();
+        assert!(search.search(&db, "idx", &query, 0, 10, true).is_err());
+    }
+}
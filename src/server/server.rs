@@ -1,78 +1,959 @@
 #![warn(unused_imports)]
-use crate::db::db::DB;
-use crate::db::storage::DashMapStorage;
-use crate::server::client::ClientConn;
+use crate::db::backend::{self, Backend, StorageKind};
+use crate::db::clock::Clock;
+use crate::db::compression::{Codec, Compression};
+use crate::db::db::{self, Databases, DB};
+use crate::db::eviction::EvictionPolicy;
+use crate::db::expire::{EXPIRE_CYCLE_BUDGET, EXPIRE_MAX_SLEEP};
+use crate::db::listpack::ListpackLimits;
+use crate::db::lru::CachePolicy;
+use crate::db::rdb::{self, RdbStats};
+use crate::db::storage::StorageError;
+use crate::db::value::Value;
+use crate::protocal::command::ExecContext;
+use crate::protocal::request::RequestLimits;
+use crate::server::accept::{self, Backoff, ReservedFd};
+use crate::server::aof::{self, Aof, AppendFsync};
+use crate::server::client::{ClientConn, Input};
+use crate::server::clients::ClientRegistry;
+use crate::server::cmdstats::CommandStats;
+use crate::server::keylock::KeyLocks;
+use crate::server::latency::LatencyMonitor;
+use crate::server::listener::{self, ListenOptions};
+use crate::server::loading::Loading;
+use crate::server::namespace::{NamespaceConfig, Namespaces};
+use crate::server::panics::Panics;
+use crate::server::pipe::{self, PipeStats};
+use crate::server::propagate::Propagator;
+use crate::server::pubsub::PubSub;
+use crate::server::ratelimit::{RateLimitKey, RateLimiter};
+use crate::server::replication::{Replication, DEFAULT_BACKLOG_SIZE};
+#[cfg(feature = "search")]
+use crate::server::search::Search;
+use crate::server::shard::ShardPool;
+use crate::server::shutdown::{SaveMode, Shutdown};
+#[cfg(feature = "systemd")]
+use crate::server::systemd;
+use crate::server::tasks::TaskManager;
+use crate::server::timeout::CommandTimeout;
+use crate::server::tracking::Tracking;
+#[cfg(feature = "uring")]
+use crate::server::uring;
 use std::error::Error;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use stream_resp::resp::RespValue;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::broadcast;
-use tracing::{debug, error, info, trace, warn};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+const CACHE_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sent to clients protected mode turns away, before closing the connection
+const PROTECTED_MODE_DENIED: &[u8] = b"-DENIED Running in protected mode because no password is \
+    set for the default user. In this mode connections are only accepted from the loopback \
+    interface. Connect through the loopback interface or start the server with \
+    --protected-mode no.\r\n";
+
+// Whether a client may connect. There are no passwords yet, so protected mode
+// admits loopback clients only.
+fn admits(protected_mode: bool, peer: &SocketAddr) -> bool {
+    !protected_mode || peer.ip().is_loopback()
+}
+
+// Aux fields of a snapshot naming the AOF it was taken of and how much of
+// it it holds
+const AUX_AOF_ID: &str = "foobar-aof-id";
+const AUX_AOF_OFFSET: &str = "foobar-aof-offset";
+
+// Where in the AOF a snapshot ends, if it was taken of the file with id
+fn snapshot_offset(path: &Path, id: u64) -> std::io::Result<Option<u64>> {
+    let mut head = Vec::new();
+    std::fs::File::open(path)?
+        .take(4096)
+        .read_to_end(&mut head)?;
+    let aux = rdb::aux(&head);
+    let field = |name| aux.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+    Ok(field(AUX_AOF_ID)
+        .filter(|&hex| u64::from_str_radix(hex, 16) == Ok(id))
+        .and_then(|_| field(AUX_AOF_OFFSET)?.parse().ok()))
+}
+
+type ImportResult = Result<RdbStats, Box<dyn Error + Send + Sync>>;
+
+type PipeResult = Result<PipeStats, Box<dyn Error + Send + Sync>>;
+
+// Loads an RDB file into dbs, reporting progress to loading, which was
+// started with the file size
+fn import(path: &str, dbs: &Databases<Backend, String, Value>, loading: &Loading) -> ImportResult {
+    let load = || -> ImportResult {
+        let file = std::fs::read(path)?;
+        Ok(rdb::load_with_progress(&file, dbs, |offset| {
+            loading.progress(offset as u64)
+        })?)
+    };
+    // Clients are served again even when the load fails
+    let result = load();
+    loading.finish();
+    let stats = result?;
+    info!(
+        "Imported {} keys from {} ({} expired, {} skipped)",
+        stats.loaded, path, stats.expired, stats.skipped
+    );
+    Ok(stats)
+}
+
+// Where close() leaves the dataset: the AOF synced, the snapshot saved,
+// disk storage flushed. The AOF goes first, it holds what clients were told
+// is written.
+fn register_persistence(
+    shutdown: &Shutdown,
+    config: &ServerConfig,
+    dbs: &Databases<Backend, String, Value>,
+    aof: Option<Arc<Aof>>,
+) {
+    if let Some(aof) = aof.clone() {
+        shutdown.register("aof", move |_| Ok(aof.sync()?));
+    }
+    let path = config.dir.join(&config.dbfilename);
+    let save_on_shutdown = config.save_on_shutdown;
+    let snapshot_dbs = dbs.clone();
+    shutdown.register("snapshot", move |mode| {
+        let save = match mode {
+            SaveMode::Default => save_on_shutdown,
+            SaveMode::Save => true,
+            SaveMode::NoSave => false,
+        };
+        if save {
+            save_rdb(&path, &snapshot_dbs, aof.as_deref())?;
+        }
+        Ok(())
+    });
+    let dbs = dbs.clone();
+    shutdown.register("storage", move |_| {
+        for db in dbs.iter() {
+            db.storage().flush()?;
+        }
+        Ok(())
+    });
+}
+
+// Written aside and synced first, so a failed save leaves the last snapshot
+// whole. With an AOF, the snapshot says which file it was taken of and how
+// much of it it holds; clients are gone by then, so nothing is appended in
+// between.
+fn save_rdb(
+    path: &Path,
+    dbs: &Databases<Backend, String, Value>,
+    aof: Option<&Aof>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let snapshots = db::snapshot(dbs);
+    let position = aof
+        .and_then(Aof::position)
+        .map(|(id, offset)| (format!("{:016x}", id), offset.to_string()));
+    let aux: Vec<(&str, &str)> = match &position {
+        Some((id, offset)) => vec![(AUX_AOF_ID, id), (AUX_AOF_OFFSET, offset)],
+        None => vec![],
+    };
+    let file = rdb::save_with_aux(&snapshots, &aux)?;
+    drop(snapshots);
+    let saving = path.with_extension("saving");
+    let mut out = std::fs::File::create(&saving)?;
+    out.write_all(&file)?;
+    out.sync_all()?;
+    std::fs::rename(&saving, path)?;
+    info!("Saved {} bytes to {}", file.len(), path.display());
+    Ok(())
+}
 
 pub struct ServerConfig {
-    pub host: String,
+    // Addresses to listen on, IPv4 or IPv6, all with the same port
+    pub bind: Vec<String>,
     pub port: u16,
+    // Refuses clients from outside the loopback interface
+    pub protected_mode: bool,
+    // Options of the listening sockets
+    pub listen: ListenOptions,
+    // Reads and writes client sockets through io_uring instead of epoll
+    #[cfg(feature = "uring")]
+    pub io_uring: bool,
     pub max_connections: usize,
+    pub databases: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    // Milliseconds, 0 disables the latency monitor
+    pub latency_monitor_threshold: u64,
+    // Worker threads keys are hashed to, 0 runs commands on the connection
+    pub shards: usize,
+    pub storage: StorageKind,
+    // Where disk storage keeps its files, and the AOF and snapshot go
+    pub dir: PathBuf,
+    // Logs every write to dir/appendfilename, replayed at startup
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    // Saves the dataset to dir/dbfilename on shutdown, loaded at startup
+    // when there is no AOF to replay
+    pub save_on_shutdown: bool,
+    pub dbfilename: String,
+    // Keys kept in memory per database by tiered storage
+    pub hot_keys: usize,
+    pub cache_policy: CachePolicy,
+    // Entries of the per-database read cache
+    pub cache_size: usize,
+    // Per-worker read views of each database, 0 to read the storage directly
+    pub read_views: usize,
+    // Commands per second a client may send, 0 for no limit
+    pub ratelimit_rate: u64,
+    // Commands let through at once, 0 for one second worth
+    pub ratelimit_burst: u64,
+    pub ratelimit_key: RateLimitKey,
+    // Milliseconds a command may run before it gives up, 0 for no limit
+    pub command_timeout: u64,
+    // Refused before parsing: arrays with more elements, requests with more
+    // bytes
+    pub max_request_args: usize,
+    pub max_request_bytes: usize,
+    // Sizes up to which collections keep the compact encoding
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+    // Elements when positive, -1 to -5 for 4 to 64 KB
+    pub list_max_listpack_size: i64,
+    // Codec for strings of at least compression_threshold bytes
+    pub compression: Codec,
+    pub compression_threshold: usize,
+    // Bytes of the replication stream kept for replicas to continue from
+    pub repl_backlog_size: usize,
+    // Users with a keyspace of their own apart from the databases
+    pub namespaces: Vec<NamespaceConfig>,
+    // Line written to every client as it connects. Off by default, as
+    // clients expect the server to stay silent until they send a command.
+    pub welcome: Option<String>,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            host: "127.0.0.1".to_string(),
+            bind: vec!["127.0.0.1".to_string()],
             port: 6379,
+            protected_mode: true,
+            listen: ListenOptions::default(),
+            #[cfg(feature = "uring")]
+            io_uring: false,
             max_connections: 1000,
+            databases: 16,
+            maxmemory_policy: EvictionPolicy::default(),
+            latency_monitor_threshold: 0,
+            shards: 0,
+            storage: StorageKind::default(),
+            dir: PathBuf::from("."),
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: AppendFsync::default(),
+            save_on_shutdown: false,
+            dbfilename: "dump.rdb".to_string(),
+            hot_keys: 100_000,
+            cache_policy: CachePolicy::default(),
+            cache_size: 64,
+            read_views: 0,
+            ratelimit_rate: 0,
+            ratelimit_burst: 0,
+            ratelimit_key: RateLimitKey::default(),
+            command_timeout: 0,
+            max_request_args: RequestLimits::default().max_args,
+            max_request_bytes: RequestLimits::default().max_bytes,
+            hash_max_listpack_entries: ListpackLimits::default().hash_max_entries,
+            hash_max_listpack_value: ListpackLimits::default().hash_max_value,
+            set_max_listpack_entries: ListpackLimits::default().set_max_entries,
+            set_max_listpack_value: ListpackLimits::default().set_max_value,
+            list_max_listpack_size: ListpackLimits::default().list_max_size,
+            compression: Compression::default().codec,
+            compression_threshold: Compression::default().threshold,
+            repl_backlog_size: DEFAULT_BACKLOG_SIZE,
+            namespaces: Vec::new(),
+            welcome: None,
         }
     }
 }
 
+// What a task accepting on one listener needs of the server
+#[derive(Clone)]
+struct Acceptor {
+    protected_mode: bool,
+    limits: RequestLimits,
+    welcome: Option<String>,
+    dbs: Databases<Backend, String, Value>,
+    latency: Arc<LatencyMonitor>,
+    ratelimit: Arc<RateLimiter>,
+    timeout: Arc<CommandTimeout>,
+    tasks: Arc<TaskManager>,
+    panics: Arc<Panics>,
+    cmdstats: Arc<CommandStats>,
+    reserved_fd: Arc<ReservedFd>,
+    loading: Arc<Loading>,
+    replication: Arc<Replication>,
+    clients: Arc<ClientRegistry>,
+    tracking: Arc<Tracking>,
+    pubsub: Arc<PubSub>,
+    keylocks: Arc<KeyLocks>,
+    namespaces: Arc<Namespaces<Backend>>,
+    shards: Option<Arc<ShardPool>>,
+    propagator: Arc<Propagator>,
+    shutdown: Arc<Shutdown>,
+    #[cfg(feature = "search")]
+    search: Arc<Search<Backend>>,
+    shutdown_tx: broadcast::Sender<()>,
+}
+
+impl Acceptor {
+    async fn accept_loop(self, listener: TcpListener) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut backoff = Backoff::default();
+        loop {
+            let (socket, addr) = self.accept(&listener, &mut backoff).await;
+            let shutdown_rx = self.shutdown_tx.subscribe();
+            let acceptor = self.clone();
+            tokio::spawn(async move {
+                let Some(socket) = acceptor.welcome(socket).await else {
+                    return;
+                };
+                let client_conn =
+                    ClientConn::new(socket, acceptor.dbs.clone(), acceptor.latency.clone());
+                acceptor.serve_client(client_conn, addr, shutdown_rx).await
+            });
+        }
+    }
+
+    // accept_loop with the connections on io_uring, on a thread of its own.
+    // The thread stops taking clients when this is dropped.
+    #[cfg(feature = "uring")]
+    async fn uring_accept_loop(
+        self,
+        listener: TcpListener,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The listener moves to the thread's runtime
+        let listener = listener.into_std()?;
+        let (_stop, mut stopped) = tokio::sync::oneshot::channel::<()>();
+        let (done, result) = tokio::sync::oneshot::channel();
+        std::thread::Builder::new()
+            .name("uring-accept".to_string())
+            .spawn(move || {
+                let accepting = async move {
+                    let listener = TcpListener::from_std(listener)?;
+                    let mut backoff = Backoff::default();
+                    loop {
+                        let (socket, addr) = tokio::select! {
+                            accepted = self.accept(&listener, &mut backoff) => accepted,
+                            _ = &mut stopped => return Ok(()),
+                        };
+                        let shutdown_rx = self.shutdown_tx.subscribe();
+                        let acceptor = self.clone();
+                        tokio_uring::spawn(async move {
+                            let Some(socket) = acceptor.welcome(socket).await else {
+                                return;
+                            };
+                            let dbs = acceptor.dbs.clone();
+                            let client_conn =
+                                match ClientConn::over_uring(socket, dbs, acceptor.latency.clone())
+                                {
+                                    Ok(client_conn) => client_conn,
+                                    Err(e) => {
+                                        debug!("Failed to move {:?} to io_uring: {}", addr, e);
+                                        return;
+                                    }
+                                };
+                            acceptor.serve_client(client_conn, addr, shutdown_rx).await
+                        });
+                    }
+                };
+                let _ = done.send(uring::start(accepting));
+            })?;
+        result.await.unwrap_or(Ok(()))
+    }
+
+    // The next client to serve. Only the listener going away ends the loop,
+    // accept errors are waited out.
+    async fn accept(
+        &self,
+        listener: &TcpListener,
+        backoff: &mut Backoff,
+    ) -> (TcpStream, SocketAddr) {
+        loop {
+            let (mut socket, addr) = match listener.accept().await {
+                Ok(accepted) => {
+                    backoff.reset();
+                    accepted
+                }
+                Err(e) if accept::is_connection_error(&e) => {
+                    debug!("Accept failed: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    if accept::is_fd_exhaustion(&e) && self.reserved_fd.shed(listener).await {
+                        warn!("Out of file descriptors, turned a connection away");
+                    }
+                    let delay = backoff.next_delay();
+                    error!("Accept failed, retrying in {:?}: {}", delay, e);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+            if !admits(self.protected_mode, &addr) {
+                debug!("Refused {:?} in protected mode", addr);
+                tokio::spawn(async move {
+                    let _ = socket.write_all(PROTECTED_MODE_DENIED).await;
+                });
+                continue;
+            }
+            debug!("Accepted connections from {:?}", addr);
+            return (socket, addr);
+        }
+    }
+
+    // Sends the configured welcome line, None when the client already left
+    async fn welcome(&self, mut socket: TcpStream) -> Option<TcpStream> {
+        if let Some(welcome) = &self.welcome {
+            let line = format!("{}\r\n", welcome);
+            if socket.write_all(line.as_bytes()).await.is_err() {
+                return None;
+            }
+        }
+        Some(socket)
+    }
+
+    // Serves client_conn, sharing the server's state, until it ends or the
+    // server shuts down
+    async fn serve_client<I: Input>(
+        self,
+        client_conn: ClientConn<I>,
+        addr: SocketAddr,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) {
+        let panics = self.panics.clone();
+        let mut client_conn = client_conn
+            .with_shards(self.shards)
+            .with_ratelimit(self.ratelimit)
+            .with_timeout(self.timeout)
+            .with_tasks(self.tasks)
+            .with_panics(self.panics)
+            .with_cmdstats(self.cmdstats)
+            .with_loading(self.loading)
+            .with_replication(self.replication)
+            .with_clients(self.clients)
+            .with_tracking(self.tracking)
+            .with_pubsub(self.pubsub)
+            .with_keylocks(self.keylocks)
+            .with_propagator(self.propagator)
+            .with_shutdown(self.shutdown)
+            .with_namespaces(self.namespaces)
+            .with_limits(self.limits);
+        #[cfg(feature = "search")]
+        {
+            client_conn = client_conn.with_search(self.search);
+        }
+        // Everything logged for the connection carries its id and peer
+        let span = info_span!("client", id = client_conn.id(), addr = %addr);
+        async move {
+            tokio::select! {
+                // A panic outside of a command closes this connection only
+                res = panics.guard("Connection", client_conn.handle_connection()) => {
+                    if let Some(Err(e)) = res {
+                        error!("Error handling connection: {}", e);
+                    }
+                }
+                _ = shutdown_rx.recv() => {
+                    debug!("Received shutdown signal, closing connection");
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
 pub struct Server {
     config: ServerConfig,
-    db: Arc<DB<DashMapStorage<String, RespValue<'static>>, String, RespValue<'static>>>,
+    dbs: Databases<Backend, String, Value>,
+    latency: Arc<LatencyMonitor>,
+    ratelimit: Arc<RateLimiter>,
+    timeout: Arc<CommandTimeout>,
+    tasks: Arc<TaskManager>,
+    panics: Arc<Panics>,
+    cmdstats: Arc<CommandStats>,
+    // Given up to turn clients away when file descriptors run out
+    reserved_fd: Arc<ReservedFd>,
+    loading: Arc<Loading>,
+    replication: Arc<Replication>,
+    clients: Arc<ClientRegistry>,
+    tracking: Arc<Tracking>,
+    pubsub: Arc<PubSub>,
+    // Held by compound operations across their steps
+    keylocks: Arc<KeyLocks>,
+    namespaces: Arc<Namespaces<Backend>>,
+    shards: Option<Arc<ShardPool>>,
+    aof: Option<Arc<Aof>>,
+    // Sends what writes did to the AOF and replicas
+    propagator: Arc<Propagator>,
+    // Persistence run on the way out, and SHUTDOWN's request
+    shutdown: Arc<Shutdown>,
+    // Secondary indexes, kept current by the dbs' write hook
+    #[cfg(feature = "search")]
+    search: Arc<Search<Backend>>,
     listener: Option<TcpListener>,
     handle: Option<tokio::task::JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>>,
     shutdown_tx: Option<broadcast::Sender<()>>,
 }
 
 impl Server {
+    // Panics when the storage can't be opened, see try_new
     pub fn new(config: ServerConfig) -> Self {
-        let storage = DashMapStorage::new();
-        let db = DB::new(storage, 64);
+        Self::try_new(config).expect("failed to open storage")
+    }
+
+    pub fn try_new(config: ServerConfig) -> Result<Self, StorageError> {
+        let clients = Arc::new(ClientRegistry::default());
+        let tracking = Arc::new(Tracking::new(clients.clone()));
+        #[cfg(feature = "search")]
+        let search = Arc::new(Search::default());
+        let pubsub = Arc::new(PubSub::new(clients.clone()));
+        let listpack = ListpackLimits {
+            hash_max_entries: config.hash_max_listpack_entries,
+            hash_max_value: config.hash_max_listpack_value,
+            set_max_entries: config.set_max_listpack_entries,
+            set_max_value: config.set_max_listpack_value,
+            list_max_size: config.list_max_listpack_size,
+        };
+        let compression = Compression {
+            codec: config.compression,
+            threshold: config.compression_threshold,
+        };
+        // All dbs and the latency monitor go by the same time
+        let clock = Arc::new(Clock::default());
+        let open_db = |storage| {
+            let db = DB::new(storage, config.cache_size).with_clock(clock.clone());
+            db.set_eviction_policy(config.maxmemory_policy);
+            db.set_cache_policy(config.cache_policy);
+            if config.read_views > 0 {
+                db.set_read_views(config.read_views);
+            }
+            db.set_listpack_limits(listpack);
+            db.set_compression(compression);
+            let tracking = tracking.clone();
+            #[cfg(feature = "search")]
+            let search = search.clone();
+            db.set_write_hook(Box::new(move |key: &String| {
+                tracking.invalidate(key);
+                #[cfg(feature = "search")]
+                search.written(key);
+            }));
+            db
+        };
+        let replication = Arc::new(Replication::new(config.repl_backlog_size));
+        let aof = if config.appendonly {
+            let path = config.dir.join(&config.appendfilename);
+            let aof = Aof::open(&path, config.appendfsync).map_err(|e| {
+                StorageError::Internal(format!("can't open {}: {}", path.display(), e))
+            })?;
+            Some(Arc::new(aof))
+        } else {
+            None
+        };
+        let propagator = Arc::new(Propagator::new(replication.clone(), aof.clone()));
+        let dbs = Backend::open_all(
+            config.storage,
+            &config.dir,
+            config.databases.max(1),
+            config.hot_keys,
+        )?
+        .into_iter()
+        .enumerate()
+        .map(|(index, storage)| {
+            let db = open_db(storage);
+            // Namespaces are not propagated, only the server's dbs
+            let propagator = propagator.clone();
+            db.set_expire_hook(Box::new(move |key: &String| propagator.expired(index, key)));
+            Arc::new(db)
+        })
+        .collect::<Vec<_>>();
+        // Disk storage kept the deadlines of its keys
+        for db in &dbs {
+            db.load_expiries()
+                .map_err(|e| StorageError::Internal(e.to_string()))?;
+        }
+        // Namespaces are kept in memory whatever the storage
+        let namespaces = Arc::new(Namespaces::new(&config.namespaces, || {
+            open_db(Backend::default())
+        }));
         let (shutdown_tx, _) = broadcast::channel(1);
-        Self {
+        let latency = Arc::new(
+            LatencyMonitor::new(config.latency_monitor_threshold).with_clock(clock.clone()),
+        );
+        let ratelimit = Arc::new(RateLimiter::new(
+            config.ratelimit_rate,
+            config.ratelimit_burst,
+            config.ratelimit_key,
+        ));
+        let timeout = Arc::new(CommandTimeout::new(config.command_timeout));
+        let shards = (config.shards > 0).then(|| Arc::new(ShardPool::new(config.shards)));
+        let dbs: Databases<Backend, String, Value> = Arc::new(dbs);
+        let shutdown = Arc::new(Shutdown::default());
+        register_persistence(&shutdown, &config, &dbs, aof.clone());
+        Ok(Self {
             config,
-            db: Arc::new(db),
+            dbs,
+            latency,
+            ratelimit,
+            timeout,
+            tasks: Arc::new(TaskManager::default()),
+            panics: Arc::new(Panics::default()),
+            cmdstats: Arc::new(CommandStats::default()),
+            reserved_fd: Arc::new(ReservedFd::default()),
+            loading: Arc::new(Loading::default()),
+            replication,
+            clients,
+            tracking,
+            pubsub,
+            keylocks: Arc::new(KeyLocks::default()),
+            namespaces,
+            shards,
+            aof,
+            propagator,
+            shutdown,
+            #[cfg(feature = "search")]
+            search,
             shutdown_tx: Some(shutdown_tx),
             listener: None,
             handle: None,
+        })
+    }
+
+    // Loads a Redis RDB file, clients connected meanwhile get LOADING errors
+    pub fn import_rdb(&self, path: &str) -> ImportResult {
+        self.loading.start(std::fs::metadata(path)?.len());
+        import(path, &self.dbs, &self.loading)
+    }
+
+    // Loads a Redis RDB file on a blocking thread while the server takes
+    // clients. The server is loading as soon as this returns.
+    pub fn spawn_import_rdb(
+        &self,
+        path: String,
+    ) -> Result<tokio::task::JoinHandle<ImportResult>, Box<dyn Error + Send + Sync>> {
+        self.loading.start(std::fs::metadata(&path)?.len());
+        let dbs = self.dbs.clone();
+        let loading = self.loading.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            import(&path, &dbs, &loading)
+        }))
+    }
+
+    // Runs a stream of RESP commands from path, or stdin for "-", with clients
+    // taken but answered LOADING until it is done
+    pub fn spawn_pipe_import(
+        &self,
+        path: String,
+    ) -> Result<tokio::task::JoinHandle<PipeResult>, Box<dyn Error + Send + Sync>> {
+        let reader: Box<dyn AsyncRead + Send + Unpin> = if path == "-" {
+            self.loading.start(0);
+            Box::new(tokio::io::stdin())
+        } else {
+            let file = std::fs::File::open(&path)?;
+            self.loading.start(file.metadata()?.len());
+            Box::new(tokio::fs::File::from_std(file))
+        };
+        let ctx = self.context();
+        let loading = self.loading.clone();
+        Ok(tokio::spawn(async move {
+            let result = pipe::import(reader, ctx, |bytes| loading.progress(bytes)).await;
+            // Clients are served again even when the load fails
+            loading.finish();
+            let stats = result?;
+            info!("Imported the pipe from {}: {}", path, stats);
+            Ok(stats)
+        }))
+    }
+
+    pub fn loading(&self) -> Arc<Loading> {
+        self.loading.clone()
+    }
+
+    // Hooks registered here run when the server closes, after its own
+    pub fn shutdown(&self) -> Arc<Shutdown> {
+        self.shutdown.clone()
+    }
+
+    // Loads what the last run left behind. With appendonly on, that is the
+    // snapshot the AOF was taken of and the AOF after it, or the whole AOF
+    // when the snapshot is of another file. An AOF with nothing in it is
+    // started from the snapshot saved on shutdown, so it holds the dataset
+    // from then on. Call before serving.
+    pub async fn recover(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let snapshot = self.config.dir.join(&self.config.dbfilename);
+        let Some(aof) = &self.aof else {
+            if self.config.save_on_shutdown && snapshot.exists() {
+                self.import_rdb(&snapshot.to_string_lossy())?;
+            }
+            return Ok(());
+        };
+        let offset = match aof.position() {
+            Some((id, _)) if snapshot.exists() => snapshot_offset(&snapshot, id)?,
+            _ => None,
+        };
+        if offset.is_none() && aof.is_empty() {
+            if self.config.save_on_shutdown && snapshot.exists() {
+                self.import_rdb(&snapshot.to_string_lossy())?;
+                if let Some(rewrite) = aof.rewrite(self.dbs.clone(), self.keylocks.clone()) {
+                    rewrite.await?;
+                }
+            }
+            return Ok(());
+        }
+        if offset.is_some() {
+            self.import_rdb(&snapshot.to_string_lossy())?;
         }
+        // What the replay does, keys it expires included, is in the file
+        // already
+        self.propagator.pause(true);
+        let stats = aof.replay(self.context(), offset.unwrap_or(0)).await;
+        self.propagator.pause(false);
+        let stats = stats?;
+        if stats.failed > 0 {
+            return Err(format!(
+                "{} of {} commands in {} failed",
+                stats.failed,
+                stats.commands,
+                aof.path().display()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    // A context sharing the server's state, for commands the server runs
+    // itself rather than a client, such as applying a reloaded config
+    pub fn context(&self) -> ExecContext<Backend> {
+        let ctx = ExecContext::new(self.dbs.clone(), 0)
+            .with_latency(self.latency.clone())
+            .with_ratelimit(self.ratelimit.clone())
+            .with_timeout(self.timeout.clone())
+            .with_tasks(self.tasks.clone())
+            .with_panics(self.panics.clone())
+            .with_cmdstats(self.cmdstats.clone())
+            .with_clients(self.clients.clone())
+            .with_tracking(self.tracking.clone())
+            .with_pubsub(self.pubsub.clone())
+            .with_keylocks(self.keylocks.clone())
+            .with_shutdown(self.shutdown.clone())
+            .with_loading(self.loading.clone())
+            .with_replication(self.replication.clone())
+            .with_namespaces(self.namespaces.clone())
+            .with_propagator(self.propagator.clone());
+        #[cfg(feature = "search")]
+        let ctx = ctx.with_search(self.search.clone());
+        ctx
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let addr = format!("{}:{}", self.config.host, self.config.port);
-        let listener = TcpListener::bind(&addr).await?;
-        info!("Server listening on {}", addr);
+        let listeners = self.bind().await?;
+        self.serve(listeners).await
+    }
+
+    // Binds every configured address. Port 0 picks a free port, which the
+    // listeners report.
+    pub async fn bind(&self) -> Result<Vec<TcpListener>, Box<dyn Error + Send + Sync>> {
+        accept::check_fd_limit(self.config.max_connections);
+        // Every address must bind before any client is taken
+        let mut listeners = Vec::with_capacity(self.config.bind.len());
+        for host in &self.config.bind {
+            let bound = listener::bind_all(host, self.config.port, &self.config.listen).await?;
+            match bound.len() {
+                1 => info!("Server listening on {}", bound[0].local_addr()?),
+                n => info!(
+                    "Server listening on {} with {} listeners",
+                    bound[0].local_addr()?,
+                    n
+                ),
+            }
+            listeners.extend(bound);
+        }
+        Ok(listeners)
+    }
 
+    // Takes clients on listeners from bind until the server is closed
+    pub async fn serve(
+        &self,
+        listeners: Vec<TcpListener>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let shutdown_tx = self.shutdown_tx.clone().unwrap();
 
-        loop {
-            let (socket, addr) = listener.accept().await?;
-            let db = self.db.clone();
-            let mut shutdown_rx = shutdown_tx.subscribe();
-            debug!("Accepted connections from {:?}", addr);
-            tokio::spawn(async move {
-                let mut client_conn = ClientConn::new(socket, db);
-                tokio::select! {
-                    res = client_conn.handle_connection() => {
-                        if let Err(e) = res {
-                            error!("Error handling connection: {}", e);
+        // Expired cache entries are purged in the background once a second
+        let dbs = self.dbs.clone();
+        self.tasks
+            .spawn("cache-purge", CACHE_PURGE_INTERVAL, move || {
+                let purged: usize = dbs.iter().map(|db| db.purge_cache()).sum();
+                if purged > 0 {
+                    debug!("Purged {} expired cache entries", purged);
+                }
+                Ok::<_, StorageError>(())
+            });
+
+        // Active expiry runs when the earliest deadline of any db comes, or
+        // one sooner is set
+        let dbs = self.dbs.clone();
+        let wait_dbs = self.dbs.clone();
+        let keylocks = self.keylocks.clone();
+        let mut next_db = 0;
+        self.tasks.spawn_scheduled(
+            "active-expire",
+            EXPIRE_MAX_SLEEP,
+            move || {
+                let dbs = wait_dbs.clone();
+                async move {
+                    if dbs.is_empty() {
+                        return std::future::pending().await;
+                    }
+                    let sooner = futures::future::select_all(
+                        dbs.iter().map(|db| Box::pin(db.expiry_sooner())),
+                    );
+                    let now = dbs[0].clock().now_ms();
+                    match dbs.iter().filter_map(|db| db.next_expiry()).min() {
+                        Some(next) => {
+                            let due = Duration::from_millis(next.saturating_sub(now).max(1));
+                            tokio::select! {
+                                _ = tokio::time::sleep(due) => {}
+                                _ = sooner => {}
+                            }
+                        }
+                        None => {
+                            sooner.await;
                         }
                     }
-                    _ = shutdown_rx.recv() => {
-                        debug!("Received shutdown signal, closing connection from {:?}", addr);
+                }
+            },
+            move || {
+                let deadline = Instant::now() + EXPIRE_CYCLE_BUDGET;
+                let mut result = Ok(());
+                for i in 0..dbs.len() {
+                    let index = (next_db + i) % dbs.len();
+                    // A key a command holds is left to it: its DEL must not
+                    // be sent between that command's write and its effects
+                    let lock = |key: &String| keylocks.try_exclusive(index, &[key]);
+                    let db = &dbs[index];
+                    let expired = match db.storage().blocks() {
+                        true => backend::blocking(|| db.active_expire(deadline, lock)),
+                        false => db.active_expire(deadline, lock),
+                    };
+                    match expired {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => result = Err(e),
                     }
                 }
+                next_db = (next_db + 1) % dbs.len().max(1);
+                result
+            },
+        );
+
+        // appendfsync everysec
+        if let Some(aof) = self
+            .aof
+            .clone()
+            .filter(|aof| aof.fsync() == AppendFsync::EverySec)
+        {
+            self.tasks
+                .spawn("aof-fsync", aof::FSYNC_INTERVAL, move || aof.sync());
+        }
+
+        #[cfg(feature = "systemd")]
+        self.notify_systemd();
+
+        #[cfg(feature = "uring")]
+        if self.config.io_uring {
+            uring::probe().map_err(|e| format!("io_uring is not available: {}", e))?;
+            info!("Serving clients over io_uring");
+        }
+        // Each listener is accepted on by a task of its own, so listeners
+        // sharing a port are served by different workers. Dropping the set
+        // ends them with serve.
+        let acceptor = self.acceptor(shutdown_tx);
+        let mut accepts = JoinSet::new();
+        for listener in listeners {
+            #[cfg(feature = "uring")]
+            if self.config.io_uring {
+                accepts.spawn(acceptor.clone().uring_accept_loop(listener));
+                continue;
+            }
+            accepts.spawn(acceptor.clone().accept_loop(listener));
+        }
+        let accepting = async {
+            while let Some(result) = accepts.join_next().await {
+                result??;
+            }
+            Ok::<_, Box<dyn Error + Send + Sync>>(())
+        };
+        // SHUTDOWN ends serving like the listeners going away, the caller
+        // closes the server after
+        tokio::select! {
+            result = accepting => result,
+            mode = self.shutdown.requested() => {
+                info!("SHUTDOWN requested ({:?})", mode);
+                Ok(())
+            }
+        }
+    }
+
+    // Tells systemd the server is ready once the dataset is loaded, and pings
+    // its watchdog from a background task so a stuck runtime gets restarted
+    #[cfg(feature = "systemd")]
+    fn notify_systemd(&self) {
+        if let Some(interval) = systemd::watchdog_interval() {
+            self.tasks.spawn("watchdog", interval / 2, || {
+                systemd::notify("WATCHDOG=1").map(|_| ())
             });
         }
+        let loading = self.loading.clone();
+        tokio::spawn(async move {
+            loading.wait().await;
+            if let Err(e) = systemd::notify("READY=1\nSTATUS=Ready to accept connections") {
+                warn!("Failed to notify systemd: {}", e);
+            }
+        });
+    }
+
+    fn acceptor(&self, shutdown_tx: broadcast::Sender<()>) -> Acceptor {
+        Acceptor {
+            protected_mode: self.config.protected_mode,
+            limits: RequestLimits {
+                max_args: self.config.max_request_args,
+                max_bytes: self.config.max_request_bytes,
+            },
+            welcome: self.config.welcome.clone(),
+            dbs: self.dbs.clone(),
+            latency: self.latency.clone(),
+            ratelimit: self.ratelimit.clone(),
+            timeout: self.timeout.clone(),
+            tasks: self.tasks.clone(),
+            panics: self.panics.clone(),
+            cmdstats: self.cmdstats.clone(),
+            reserved_fd: self.reserved_fd.clone(),
+            loading: self.loading.clone(),
+            replication: self.replication.clone(),
+            clients: self.clients.clone(),
+            tracking: self.tracking.clone(),
+            pubsub: self.pubsub.clone(),
+            keylocks: self.keylocks.clone(),
+            namespaces: self.namespaces.clone(),
+            shards: self.shards.clone(),
+            propagator: self.propagator.clone(),
+            shutdown: self.shutdown.clone(),
+            #[cfg(feature = "search")]
+            search: self.search.clone(),
+            shutdown_tx,
+        }
     }
 
     pub async fn close(&mut self) {
@@ -84,14 +965,43 @@ impl Server {
         if let Some(shutdown_tx) = self.shutdown_tx.take() {
             let _ = shutdown_tx.send(());
         }
+        self.tasks.stop();
+        #[cfg(feature = "systemd")]
+        let _ = systemd::notify("STOPPING=1");
         info!("Server is shutting down");
 
         // Cancel any running tasks
         if let Some(handle) = self.handle.take() {
             handle.abort();
         }
+
+        // Clients are turned away, what they were answered for is made to
+        // last
+        let shutdown = self.shutdown.clone();
+        let mode = shutdown.mode();
+        match tokio::task::spawn_blocking(move || shutdown.run(mode)).await {
+            Ok(true) => {}
+            Ok(false) => error!("Some data may not have been persisted"),
+            Err(e) => error!("Shutdown hooks panicked: {}", e),
+        }
         info!("Exit")
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protected_mode() {
+        let v4 = "127.0.0.1:5000".parse().unwrap();
+        let v6 = "[::1]:5000".parse().unwrap();
+        let remote = "192.0.2.2:5000".parse().unwrap();
+        assert!(admits(true, &v4));
+        assert!(admits(true, &v6));
+        assert!(!admits(true, &remote));
+        assert!(admits(false, &remote));
+    }
+}
+
 //EOF
@@ -0,0 +1,117 @@
+// Optional thread-per-shard execution. Keys hash to one of N workers, each a
+// dedicated thread running a single-threaded runtime, so commands on a hot
+// key are always executed by the same thread instead of contending across
+// the whole pool. Storage is still shared; what is partitioned is the work.
+// Giving each worker the keys of its slots is noted in todo.md.
+use crate::db::backend::Backend;
+use crate::protocal::command::{Command, ExecContext};
+use crate::protocal::slot::key_slot;
+use anyhow::{anyhow, Error};
+use std::sync::Arc;
+use std::thread;
+use stream_resp::resp::RespValue;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+type Reply = Result<Arc<RespValue<'static>>, Error>;
+type Job = (Command, ExecContext<Backend>, oneshot::Sender<Reply>);
+
+pub struct ShardPool {
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+}
+
+impl ShardPool {
+    // Workers stop once the pool is dropped and their queues drain
+    pub fn new(shards: usize) -> Self {
+        let workers = (0..shards.max(1))
+            .map(|index| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                let spawned = thread::Builder::new()
+                    .name(format!("shard-{}", index))
+                    .spawn(move || run_worker(rx));
+                if let Err(e) = spawned {
+                    error!("Failed to start shard {}: {}", index, e);
+                }
+                tx
+            })
+            .collect();
+        Self { workers }
+    }
+
+    // By hash slot, so keys sharing a {tag} share a worker too
+    pub fn shard_of(&self, db_index: usize, key: &str) -> usize {
+        (key_slot(key) as usize + db_index) % self.workers.len()
+    }
+
+    // Shard owning every key of the command. Commands without keys, or with
+    // keys spread over several shards, stay on the connection's task.
+    pub fn route(&self, db_index: usize, cmd: &Command) -> Option<usize> {
+        let keys = cmd.keys()?;
+        let mut shards = keys.iter().map(|key| self.shard_of(db_index, key));
+        let first = shards.next()?;
+        shards.all(|shard| shard == first).then_some(first)
+    }
+
+    pub async fn exec(&self, shard: usize, cmd: Command, ctx: ExecContext<Backend>) -> Reply {
+        let (tx, rx) = oneshot::channel();
+        self.workers[shard]
+            .send((cmd, ctx, tx))
+            .map_err(|_| anyhow!("shard {} is not running", shard))?;
+        rx.await
+            .map_err(|_| anyhow!("shard {} dropped the command", shard))?
+    }
+}
+
+fn run_worker(mut rx: mpsc::UnboundedReceiver<Job>) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to build shard runtime: {}", e);
+            return;
+        }
+    };
+    let local = tokio::task::LocalSet::new();
+    local.block_on(&runtime, async move {
+        while let Some((cmd, ctx, reply)) = rx.recv().await {
+            // Blocking commands must not hold up the rest of the queue
+            tokio::task::spawn_local(async move {
+                let _ = reply.send(cmd.exec(ctx).await);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::DB;
+    use std::borrow::Cow;
+
+    #[tokio::test]
+    async fn test_shard_exec() {
+        let pool = ShardPool::new(4);
+        let dbs = Arc::new(vec![Arc::new(DB::new(Backend::default(), 16))]);
+        let ctx = ExecContext::new(dbs, 0);
+
+        let set = Command::Set {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        let shard = pool.route(0, &set).unwrap();
+        assert_eq!(shard, pool.shard_of(0, "k"));
+        pool.exec(shard, set, ctx.clone()).await.unwrap();
+
+        let get = Command::Get {
+            key: "k".to_string(),
+        };
+        let reply = pool.exec(shard, get, ctx).await.unwrap();
+        assert_eq!(*reply, RespValue::BulkString(Some(Cow::Owned("v".into()))));
+
+        assert_eq!(pool.route(0, &Command::Ping), None);
+        // Keys sharing a hash tag share a shard
+        assert_eq!(pool.shard_of(0, "{user}.a"), pool.shard_of(0, "{user}.b"));
+    }
+}
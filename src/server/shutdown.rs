@@ -0,0 +1,147 @@
+// What the server does on its way out. Persistence registers hooks here when
+// the server is built, and close() runs them once clients are turned away, so
+// Ctrl+C and SHUTDOWN leave the same files behind.
+use std::error::Error;
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tracing::{error, info};
+
+// SHUTDOWN SAVE and NOSAVE, Default for Ctrl+C and a bare SHUTDOWN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveMode {
+    #[default]
+    Default,
+    Save,
+    NoSave,
+}
+
+type Hook = Box<dyn FnOnce(SaveMode) -> Result<(), Box<dyn Error + Send + Sync>> + Send>;
+
+pub struct Shutdown {
+    hooks: Mutex<Vec<(&'static str, Hook)>>,
+    // Set by SHUTDOWN, serve returns once it is
+    requested: watch::Sender<Option<SaveMode>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            hooks: Mutex::new(Vec::new()),
+            requested: watch::channel(None).0,
+        }
+    }
+}
+
+impl std::fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hooks: Vec<_> = self
+            .hooks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, _)| *name)
+            .collect();
+        f.debug_struct("Shutdown")
+            .field("hooks", &hooks)
+            .field("requested", &*self.requested.borrow())
+            .finish()
+    }
+}
+
+impl Shutdown {
+    // Hooks run in the order they were registered, on a blocking thread
+    pub fn register<F>(&self, name: &'static str, hook: F)
+    where
+        F: FnOnce(SaveMode) -> Result<(), Box<dyn Error + Send + Sync>> + Send + 'static,
+    {
+        self.hooks.lock().unwrap().push((name, Box::new(hook)));
+    }
+
+    // SHUTDOWN: the first request wins
+    pub fn request(&self, mode: SaveMode) {
+        self.requested.send_if_modified(|requested| {
+            let first = requested.is_none();
+            if first {
+                *requested = Some(mode);
+            }
+            first
+        });
+    }
+
+    // Resolves once SHUTDOWN was sent
+    pub async fn requested(&self) -> SaveMode {
+        let mut requested = self.requested.subscribe();
+        let mode = requested
+            .wait_for(Option::is_some)
+            .await
+            .map(|mode| mode.unwrap_or_default());
+        match mode {
+            Ok(mode) => mode,
+            // The sender lives as long as self
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    // What SHUTDOWN asked for, Default when the server stops otherwise
+    pub fn mode(&self) -> SaveMode {
+        self.requested.borrow().unwrap_or_default()
+    }
+
+    // Runs every hook once, carrying on past the ones that fail. False when
+    // any did.
+    pub fn run(&self, mode: SaveMode) -> bool {
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        let mut ok = true;
+        for (name, hook) in hooks {
+            match hook(mode) {
+                Ok(()) => info!("Shutdown hook {} done", name),
+                Err(e) => {
+                    error!("Shutdown hook {} failed: {}", name, e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shutdown = Arc::new(Shutdown::default());
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for name in ["aof", "snapshot"] {
+            let ran = ran.clone();
+            shutdown.register(name, move |mode| {
+                ran.lock().unwrap().push((name, mode));
+                match name {
+                    "aof" => Err("disk full".into()),
+                    _ => Ok(()),
+                }
+            });
+        }
+
+        assert_eq!(shutdown.mode(), SaveMode::Default);
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.requested().await }
+        });
+        shutdown.request(SaveMode::NoSave);
+        shutdown.request(SaveMode::Save);
+        assert_eq!(waiter.await.unwrap(), SaveMode::NoSave);
+        assert_eq!(shutdown.mode(), SaveMode::NoSave);
+
+        // A failing hook doesn't keep the next one from running, and hooks
+        // run once
+        assert!(!shutdown.run(shutdown.mode()));
+        assert!(shutdown.run(SaveMode::Default));
+        assert_eq!(
+            *ran.lock().unwrap(),
+            vec![("aof", SaveMode::NoSave), ("snapshot", SaveMode::NoSave)]
+        );
+    }
+}
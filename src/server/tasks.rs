@@ -0,0 +1,235 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout};
+use tracing::{debug, error};
+
+// How one background job has been doing
+#[derive(Debug, Default)]
+struct TaskStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    // Microseconds the last run took
+    last_run_us: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+struct Task {
+    name: &'static str,
+    period: Duration,
+    stats: Arc<TaskStats>,
+    handle: JoinHandle<()>,
+}
+
+// One run of job, counted in stats
+fn run<F, E>(name: &str, stats: &TaskStats, job: &mut F)
+where
+    F: FnMut() -> Result<(), E>,
+    E: Display,
+{
+    let start = Instant::now();
+    let result = job();
+    stats
+        .last_run_us
+        .store(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+    stats.runs.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = result {
+        error!("Background task {} failed: {}", name, e);
+        stats.failures.fetch_add(1, Ordering::Relaxed);
+        *stats.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e.to_string());
+    }
+}
+
+// Background jobs of the server, each run every period on the runtime until
+// stop. A job that fails is logged and tried again next period; one that
+// panics is reported dead in INFO.
+#[derive(Default)]
+pub struct TaskManager {
+    tasks: Mutex<Vec<Task>>,
+    stopped: AtomicBool,
+}
+
+impl TaskManager {
+    // Runs job every period, starting right away. Must be called on the
+    // runtime.
+    pub fn spawn<F, E>(&self, name: &'static str, period: Duration, mut job: F)
+    where
+        F: FnMut() -> Result<(), E> + Send + 'static,
+        E: Display,
+    {
+        let stats = Arc::new(TaskStats::default());
+        let task_stats = stats.clone();
+        let handle = tokio::spawn(async move {
+            let mut ticker = interval(period);
+            loop {
+                ticker.tick().await;
+                run(name, &task_stats, &mut job);
+            }
+        });
+        self.add(name, period, stats, handle);
+    }
+
+    // Runs job whenever wait resolves, and at least every period, starting
+    // right away. For jobs that know when they are next needed. Must be
+    // called on the runtime.
+    pub fn spawn_scheduled<F, E, W, Fut>(
+        &self,
+        name: &'static str,
+        period: Duration,
+        mut wait: W,
+        mut job: F,
+    ) where
+        F: FnMut() -> Result<(), E> + Send + 'static,
+        E: Display,
+        W: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let stats = Arc::new(TaskStats::default());
+        let task_stats = stats.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                run(name, &task_stats, &mut job);
+                let _ = timeout(period, wait()).await;
+            }
+        });
+        self.add(name, period, stats, handle);
+    }
+
+    fn add(
+        &self,
+        name: &'static str,
+        period: Duration,
+        stats: Arc<TaskStats>,
+        handle: JoinHandle<()>,
+    ) {
+        debug!("Started background task {}", name);
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Task {
+                name,
+                period,
+                stats,
+                handle,
+            });
+    }
+
+    // Ends every job, between runs
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for task in self.tasks.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            task.handle.abort();
+        }
+    }
+
+    // The INFO tasks section
+    pub fn info(&self) -> String {
+        let stopped = self.stopped.load(Ordering::Relaxed);
+        let mut info = "# Tasks\r\n".to_string();
+        for task in self.tasks.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let state = if stopped {
+                "stopped"
+            } else if task.handle.is_finished() {
+                "dead"
+            } else {
+                "running"
+            };
+            let stats = &task.stats;
+            let last_error = stats
+                .last_error
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+                .unwrap_or_default();
+            info.push_str(&format!(
+                "task_{}:state={},period_ms={},runs={},failures={},last_run_us={},last_error={}\r\n",
+                task.name.replace('-', "_"),
+                state,
+                task.period.as_millis(),
+                stats.runs.load(Ordering::Relaxed),
+                stats.failures.load(Ordering::Relaxed),
+                stats.last_run_us.load(Ordering::Relaxed),
+                last_error.replace(['\r', '\n', ','], " ")
+            ));
+        }
+        info
+    }
+}
+
+impl Drop for TaskManager {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tasks() {
+        let tasks = TaskManager::default();
+        tasks.spawn("ok", Duration::from_millis(5), || Ok::<_, String>(()));
+        let mut fails = 0;
+        tasks.spawn("flaky", Duration::from_millis(5), move || {
+            fails += 1;
+            match fails {
+                1 => Err("disk full"),
+                _ => Ok(()),
+            }
+        });
+        tasks.spawn(
+            "broken",
+            Duration::from_millis(5),
+            || -> Result<(), String> { panic!("bug") },
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let info = tasks.info();
+        assert!(info.starts_with("# Tasks\r\n"));
+        assert!(info.contains("task_ok:state=running,period_ms=5,runs="));
+        assert!(info.contains("failures=1,"));
+        assert!(info.contains("last_error=disk full\r\n"));
+        assert!(info.contains("task_broken:state=dead"));
+
+        tasks.stop();
+        assert!(tasks.info().contains("task_ok:state=stopped"));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled() {
+        let tasks = TaskManager::default();
+        let wake = Arc::new(tokio::sync::Notify::new());
+        let runs = Arc::new(AtomicU64::new(0));
+        tasks.spawn_scheduled(
+            "woken",
+            Duration::from_secs(60),
+            {
+                let wake = wake.clone();
+                move || {
+                    let wake = wake.clone();
+                    async move { wake.notified().await }
+                }
+            },
+            {
+                let runs = runs.clone();
+                move || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, String>(())
+                }
+            },
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        // Runs when woken rather than on the period
+        wake.notify_one();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert!(tasks
+            .info()
+            .contains("task_woken:state=running,period_ms=60000,runs=2,"));
+    }
+}
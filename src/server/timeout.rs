@@ -0,0 +1,102 @@
+use crate::protocal::command::CommandError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Loop steps between looks at the clock
+pub const CHECK_INTERVAL: usize = 1024;
+
+// Execution budget of commands, the command-timeout setting. A limit of 0
+// lets commands run as long as they take.
+#[derive(Debug, Default)]
+pub struct CommandTimeout {
+    limit_ms: AtomicU64,
+}
+
+impl CommandTimeout {
+    pub fn new(limit_ms: u64) -> Self {
+        Self {
+            limit_ms: AtomicU64::new(limit_ms),
+        }
+    }
+
+    pub fn limit_ms(&self) -> u64 {
+        self.limit_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit_ms(&self, limit_ms: u64) {
+        self.limit_ms.store(limit_ms, Ordering::Relaxed);
+    }
+
+    // Budget of a command starting now
+    pub fn budget(&self) -> Budget {
+        match self.limit_ms() {
+            0 => Budget::unlimited(),
+            ms => Budget::until(Instant::now() + Duration::from_millis(ms)),
+        }
+    }
+}
+
+// Deadline one command runs under. Long loops call check as they go and
+// give up once it has passed; the command fails, the connection carries on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn until(deadline: Instant) -> Self {
+        Self {
+            deadline: Some(deadline),
+        }
+    }
+
+    // Called at step `step` of a loop, looks at the clock every
+    // CHECK_INTERVAL steps
+    pub fn check(&self, step: usize) -> Result<(), CommandError> {
+        match self.deadline {
+            Some(deadline) if step.is_multiple_of(CHECK_INTERVAL) && Instant::now() >= deadline => {
+                Err(CommandError::Timeout)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // check for loops of commands that can pause between steps, which also
+    // give the rest of the runtime a turn every CHECK_INTERVAL steps
+    pub async fn pause(&self, step: usize) -> Result<(), CommandError> {
+        self.check(step)?;
+        if step > 0 && step.is_multiple_of(CHECK_INTERVAL) {
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_budget() {
+        assert!(CommandTimeout::default().budget().check(0).is_ok());
+
+        let spent = Budget::until(Instant::now());
+        assert!(matches!(spent.check(0), Err(CommandError::Timeout)));
+        // In between checks the clock isn't read
+        assert!(spent.check(1).is_ok());
+        assert!(spent.check(CHECK_INTERVAL).is_err());
+
+        assert!(spent.pause(1).await.is_ok());
+        assert!(spent.pause(CHECK_INTERVAL).await.is_err());
+        assert!(Budget::unlimited().pause(CHECK_INTERVAL).await.is_ok());
+
+        let timeout = CommandTimeout::new(60_000);
+        assert!(timeout.budget().check(0).is_ok());
+        timeout.set_limit_ms(0);
+        assert_eq!(timeout.limit_ms(), 0);
+    }
+}
@@ -0,0 +1,244 @@
+// Server side of client-side caching (CLIENT TRACKING). Keys read by a
+// tracking client are remembered, and the first write to one of them sends
+// the client an invalidation and forgets the key again. In BCAST mode
+// nothing is remembered and every write under the client's prefixes is
+// announced instead.
+use crate::server::clients::{ClientHandle, ClientRegistry};
+use dashmap::DashMap;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use stream_resp::resp::RespValue;
+
+// Channel RESP2 clients receive invalidations on through their REDIRECT
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackingOptions {
+    // Client that receives the invalidations instead of the tracking one
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    // BCAST only, an empty list covers every key
+    pub prefixes: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Tracking {
+    clients: Arc<ClientRegistry>,
+    trackers: DashMap<u64, TrackingOptions>,
+    // Count of trackers, so writes skip everything while nobody tracks
+    active: AtomicUsize,
+    // Keys read by clients in the default mode, with who read them
+    keys: DashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    pub fn new(clients: Arc<ClientRegistry>) -> Self {
+        Self {
+            clients,
+            trackers: DashMap::new(),
+            active: AtomicUsize::new(0),
+            keys: DashMap::new(),
+        }
+    }
+
+    // False when the REDIRECT target isn't connected
+    pub fn enable(&self, client_id: u64, options: TrackingOptions) -> bool {
+        if let Some(target) = options.redirect {
+            if target != client_id && self.clients.get(target).is_none() {
+                return false;
+            }
+        }
+        if self.trackers.insert(client_id, options).is_none() {
+            self.active.fetch_add(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    // Keys the client read stay in the table until their next write, the
+    // invalidation then finds no tracker and is dropped
+    pub fn disable(&self, client_id: u64) {
+        if self.trackers.remove(&client_id).is_some()
+            && self.active.fetch_sub(1, Ordering::Relaxed) == 1
+        {
+            self.keys.clear();
+        }
+    }
+
+    // Remembers keys a read-only command of the client is about to read
+    pub fn read(&self, client_id: u64, keys: &[&str]) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        match self.trackers.get(&client_id) {
+            Some(options) if !options.bcast => {}
+            _ => return,
+        }
+        for key in keys {
+            self.keys
+                .entry(key.to_string())
+                .or_default()
+                .insert(client_id);
+        }
+    }
+
+    // Called after every write to key, in any db
+    pub fn invalidate(&self, key: &str) {
+        if self.active.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut targets: Vec<u64> = self
+            .keys
+            .remove(key)
+            .map(|(_, readers)| readers.into_iter().collect())
+            .unwrap_or_default();
+        targets.extend(
+            self.trackers
+                .iter()
+                .filter(|entry| {
+                    let options = entry.value();
+                    options.bcast
+                        && (options.prefixes.is_empty()
+                            || options.prefixes.iter().any(|p| key.starts_with(p.as_str())))
+                })
+                .map(|entry| *entry.key()),
+        );
+        for client_id in targets {
+            self.notify(client_id, key);
+        }
+    }
+
+    fn notify(&self, client_id: u64, key: &str) {
+        let Some(redirect) = self.trackers.get(&client_id).map(|o| o.redirect) else {
+            return;
+        };
+        let target = redirect.unwrap_or(client_id);
+        let keys = RespValue::Array(Some(vec![RespValue::BulkString(Some(Cow::Owned(
+            key.to_string(),
+        )))]));
+        match self.clients.get(target) {
+            Some(handle) => {
+                if let Some(frame) = invalidation(&handle, target != client_id, keys) {
+                    handle.push(&frame);
+                }
+            }
+            // The client is told its invalidations have nowhere to go
+            None => {
+                if let Some(handle) = self.clients.get(client_id) {
+                    if handle.protocol() >= 3 {
+                        handle.push(&RespValue::Push(Some(vec![
+                            RespValue::BulkString(Some(Cow::Borrowed("tracking-redir-broken"))),
+                            RespValue::Integer(target as i64),
+                        ])));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// A push under RESP3. RESP2 has no pushes, so it only reaches a REDIRECT
+// target, as a message on the invalidation channel.
+fn invalidation(
+    handle: &ClientHandle,
+    redirected: bool,
+    keys: RespValue<'static>,
+) -> Option<RespValue<'static>> {
+    if handle.protocol() >= 3 {
+        return Some(RespValue::Push(Some(vec![
+            RespValue::BulkString(Some(Cow::Borrowed("invalidate"))),
+            keys,
+        ])));
+    }
+    redirected.then(|| {
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(Cow::Borrowed("message"))),
+            RespValue::BulkString(Some(Cow::Borrowed(INVALIDATE_CHANNEL))),
+            keys,
+        ]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use tokio::sync::mpsc;
+
+    fn connect(clients: &ClientRegistry, id: u64, protocol: u8) -> mpsc::Receiver<BytesMut> {
+        let (tx, rx) = mpsc::channel(16);
+        let handle = ClientHandle::new(id, "127.0.0.1:1".parse().unwrap(), tx);
+        handle.set_protocol(protocol);
+        clients.register(handle);
+        rx
+    }
+
+    fn received(rx: &mut mpsc::Receiver<BytesMut>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|frame| String::from_utf8_lossy(&frame).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_default_mode() {
+        let clients = Arc::new(ClientRegistry::default());
+        let tracking = Tracking::new(clients.clone());
+        let mut rx = connect(&clients, 1, 3);
+        assert!(tracking.enable(1, TrackingOptions::default()));
+
+        tracking.read(1, &["k"]);
+        tracking.invalidate("other");
+        tracking.invalidate("k");
+        // Only the first write after a read is announced
+        tracking.invalidate("k");
+        assert_eq!(
+            received(&mut rx),
+            [">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n"]
+        );
+
+        tracking.read(1, &["k"]);
+        tracking.disable(1);
+        tracking.invalidate("k");
+        assert!(received(&mut rx).is_empty());
+        assert!(tracking.keys.is_empty());
+    }
+
+    #[test]
+    fn test_bcast_and_redirect() {
+        let clients = Arc::new(ClientRegistry::default());
+        let tracking = Tracking::new(clients.clone());
+        let mut rx = connect(&clients, 1, 2);
+        let mut target = connect(&clients, 2, 2);
+
+        assert!(!tracking.enable(
+            1,
+            TrackingOptions {
+                redirect: Some(9),
+                ..Default::default()
+            }
+        ));
+        assert!(tracking.enable(
+            1,
+            TrackingOptions {
+                redirect: Some(2),
+                bcast: true,
+                prefixes: vec!["user:".to_string()],
+            }
+        ));
+        // BCAST needs no read first
+        tracking.invalidate("user:1");
+        tracking.invalidate("order:1");
+        assert!(received(&mut rx).is_empty());
+        assert_eq!(
+            received(&mut target),
+            ["*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$6\r\nuser:1\r\n"]
+        );
+
+        // RESP2 without a REDIRECT has nowhere to receive them
+        assert!(tracking.enable(1, TrackingOptions::default()));
+        tracking.read(1, &["k"]);
+        tracking.invalidate("k");
+        assert!(received(&mut rx).is_empty());
+    }
+}
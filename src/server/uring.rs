@@ -0,0 +1,150 @@
+// Client connections driven by io_uring, with the `uring` feature and
+// --io-uring. Each listener is accepted on by a thread of its own running a
+// tokio-uring runtime, and its connections stay on that thread: reads and
+// writes are submitted to the ring instead of waiting for readiness and then
+// making the syscall. From the parser on they are served like any other,
+// see ClientConn.
+use crate::db::backend::Backend;
+use crate::db::db::Databases;
+use crate::db::value::Value;
+use crate::server::client::{ClientConn, Input};
+use crate::server::clients::OUTPUT_QUEUE_LEN;
+use crate::server::latency::LatencyMonitor;
+use bytes::BytesMut;
+use std::io;
+use std::net::Shutdown;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::TcpStream;
+
+// Free room a read asks the ring to fill
+const READ_SIZE: usize = 4096;
+
+// Entries of each thread's submission queue
+const RING_ENTRIES: u32 = 256;
+
+// Whether this kernel lets the process set up a ring. Checked before any
+// listener is handed to one, so a server that can't use io_uring fails to
+// start instead of losing its listeners.
+pub fn probe() -> io::Result<()> {
+    tokio_uring::uring_builder().build(RING_ENTRIES).map(|_| ())
+}
+
+// Runs future on a tokio-uring runtime on the calling thread
+pub fn start<F: std::future::Future>(future: F) -> F::Output {
+    tokio_uring::builder().entries(RING_ENTRIES).start(future)
+}
+
+// The read side of a connection on the ring
+pub struct UringInput(Rc<TcpStream>);
+
+impl Input for UringInput {
+    async fn read_into(&mut self, buf: &mut BytesMut) -> io::Result<usize> {
+        // The ring owns the buffer until the read completes, so the parser's
+        // is lent to it past the bytes it already holds
+        buf.reserve(READ_SIZE);
+        let len = buf.len();
+        let (read, slice) = self.0.read(std::mem::take(buf).slice(len..)).await;
+        *buf = slice.into_inner();
+        read
+    }
+}
+
+// Writes one queued frame with whatever else is already queued behind it,
+// false once the connection has nothing more to write to
+async fn write_frames(stream: &TcpStream, output: &mut mpsc::Receiver<BytesMut>) -> bool {
+    let Some(mut frame) = output.recv().await else {
+        return false;
+    };
+    while let Ok(next) = output.try_recv() {
+        frame.extend_from_slice(&next);
+    }
+    stream.write_all(frame).await.0.is_ok()
+}
+
+// The write_loop of client.rs over the ring
+async fn write_loop(
+    stream: Rc<TcpStream>,
+    mut output: mpsc::Receiver<BytesMut>,
+    mut killed: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            biased;
+            Ok(_) = killed.wait_for(|killed| *killed) => break,
+            written = write_frames(&stream, &mut output) => {
+                if !written {
+                    break;
+                }
+            }
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+impl ClientConn<UringInput> {
+    // Moves a socket accepted on this thread's runtime over to the ring.
+    // Must be called on a tokio-uring runtime, like everything the
+    // connection does after.
+    pub fn over_uring(
+        stream: tokio::net::TcpStream,
+        dbs: Databases<Backend, String, Value>,
+        latency: Arc<LatencyMonitor>,
+    ) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let addr = stream.peer_addr()?;
+        // The ring waits on the socket itself, it mustn't give up with
+        // EAGAIN
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let stream = Rc::new(TcpStream::from_std(stream));
+        let (output, rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
+        let conn = Self::with_input(UringInput(stream.clone()), addr, output, dbs, latency);
+        tokio_uring::spawn(write_loop(stream, rx, conn.killed()));
+        Ok(conn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::DB;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_uring_connection() {
+        if probe().is_err() {
+            eprintln!("io_uring is not available, skipping");
+            return;
+        }
+        start(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio_uring::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let dbs = Arc::new(vec![Arc::new(DB::new(Backend::default(), 16))]);
+                let mut conn =
+                    ClientConn::over_uring(socket, dbs, Arc::new(LatencyMonitor::new(0))).unwrap();
+                let _ = conn.handle_connection().await;
+            });
+
+            // A pipeline split mid-request, read into the buffer past what
+            // it holds
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let pipeline =
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+            client.write_all(&pipeline[..30]).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            client.write_all(&pipeline[30..]).await.unwrap();
+            let expected = b"+OK\r\n$1\r\nv\r\n";
+            let mut reply = vec![0; expected.len()];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, expected);
+
+            drop(client);
+            server.await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,263 @@
+// Fixtures for integration tests: a server on an ephemeral loopback port and
+// a client that speaks RESP to it. Tests no longer pick fixed ports, so they
+// can run side by side and next to a local Redis.
+use crate::protocal::migrate;
+use crate::protocal::request::{self, RequestError, RequestLimits, MAX_DEPTH};
+use crate::server::loading::Loading;
+use crate::server::server::{Server, ServerConfig};
+use anyhow::Error;
+use bytes::Buf;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use stream_resp::parser::{ParseError, Parser};
+use stream_resp::resp::RespValue;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+
+// A running server, stopped when dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    loading: Arc<Loading>,
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::with_config(ServerConfig::default()).await
+    }
+
+    // Starts a server with config, listening on 127.0.0.1 whatever the
+    // config binds. The listener is up and what persistence left behind is
+    // loaded when this returns. Dropping the server kills it; after
+    // SHUTDOWN it closes like the binary does.
+    pub async fn with_config(config: ServerConfig) -> Self {
+        let mut server = Server::new(ServerConfig {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 0,
+            ..config
+        });
+        server
+            .recover()
+            .await
+            .expect("failed to recover test server");
+        let listeners = server.bind().await.expect("failed to bind test server");
+        let addr = listeners[0].local_addr().expect("listener without address");
+        let loading = server.loading();
+        let handle = tokio::spawn(async move {
+            let _ = server.serve(listeners).await;
+            server.close().await;
+        });
+        Self {
+            addr,
+            loading,
+            handle,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    // Lets tests put the server in and out of the loading state
+    pub fn loading(&self) -> Arc<Loading> {
+        self.loading.clone()
+    }
+
+    // Until the server closed, after SHUTDOWN
+    pub async fn stopped(&mut self) {
+        let _ = (&mut self.handle).await;
+    }
+
+    pub async fn connect(&self) -> Result<TestClient, Error> {
+        TestClient::connect(self.addr).await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+// One connection to a TestServer, replies come back parsed
+pub struct TestClient {
+    stream: TcpStream,
+}
+
+impl TestClient {
+    // A connection to any server, such as one running as a child process
+    pub async fn connect(addr: SocketAddr) -> Result<Self, Error> {
+        Ok(Self {
+            stream: TcpStream::connect(addr).await?,
+        })
+    }
+
+    pub async fn command(&mut self, args: &[&str]) -> Result<RespValue<'static>, Error> {
+        let mut replies = self.pipeline(&[args]).await?;
+        Ok(replies.remove(0))
+    }
+
+    // Sends the commands in one write and reads a reply for each
+    pub async fn pipeline(
+        &mut self,
+        commands: &[&[&str]],
+    ) -> Result<Vec<RespValue<'static>>, Error> {
+        let mut request = Vec::new();
+        for args in commands {
+            migrate::encode(&mut request, args);
+        }
+        migrate::round_trip(&mut self.stream, &request, commands.len()).await
+    }
+
+    // The socket itself, for tests that write raw bytes
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+// What a connection takes out of some input: the complete requests in
+// order, then the protocol error that closes it, if any
+#[derive(Debug, PartialEq)]
+pub struct ParseRun {
+    pub requests: Vec<RespValue<'static>>,
+    pub error: Option<RequestError>,
+}
+
+// The parser gives up after a fixed number of steps with this error and
+// carries on where it stopped on the next call
+fn out_of_steps(e: &ParseError) -> bool {
+    matches!(e, ParseError::InvalidFormat(reason) if reason == "Maximum parsing iterations exceeded")
+}
+
+// Feeds data arriving in pieces, cut at the given offsets (out of range
+// ones are ignored), to a bare Parser and to the server's framing check in
+// front of one. Checks what the fuzz target and the property tests rely on
+// and panics when one of them breaks:
+// - try_parse never panics and each call is bounded
+// - an error stays put when asked again without new input
+// - the requests a connection sees don't depend on how the input was split
+// The bare parser is allowed to depend on the split, it reports a chunk
+// ending in the middle of a CRLF as malformed. The framing check is what
+// keeps that from the server.
+pub fn check_parser(data: &[u8], splits: &[usize]) -> ParseRun {
+    let mut cuts: Vec<usize> = splits
+        .iter()
+        .copied()
+        .filter(|&at| at < data.len())
+        .collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+    let mut chunks = Vec::with_capacity(cuts.len() + 1);
+    let mut from = 0;
+    for at in cuts.into_iter().chain([data.len()]) {
+        chunks.push(&data[from..at]);
+        from = at;
+    }
+
+    if !trips_bare_parser(data) {
+        parse_bare(&chunks);
+    }
+    let split = parse_requests(&chunks);
+    let whole = parse_requests(&[data]);
+    assert_eq!(split, whole, "requests depend on how the input is split");
+    split
+}
+
+// Arrays longer than this aren't given to the bare parser
+const BARE_MAX_ARRAY: u64 = 1 << 16;
+
+// Inputs that break the bare parser in ways the server's framing check
+// refuses before it gets there. The parser reserves room for a whole array
+// as soon as it reads the header, so `*1000000000\r\n` alone aborts on
+// allocation, and it panics on an integer without digits.
+fn trips_bare_parser(data: &[u8]) -> bool {
+    data.iter().enumerate().any(|(at, &b)| {
+        let rest = &data[at + 1..];
+        match b {
+            // It takes a minus sign anywhere in the length
+            b'*' => rest
+                .iter()
+                .take_while(|&&b| b.is_ascii_digit() || b == b'-')
+                .filter(|b| b.is_ascii_digit())
+                .try_fold(0u64, |n, b| {
+                    n.checked_mul(10)?.checked_add((b - b'0') as u64)
+                })
+                .is_none_or(|n| n > BARE_MAX_ARRAY),
+            b':' => rest.starts_with(b"\r\n"),
+            _ => false,
+        }
+    })
+}
+
+fn parse_bare(chunks: &[&[u8]]) {
+    let mut parser = Parser::new(MAX_DEPTH, usize::MAX);
+    // Each call either completes a value, which takes at least a byte, or
+    // moves through at most its step limit of states. Resuming after the
+    // limit has to get somewhere within a few calls per byte.
+    let total: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+    let mut budget = 4 * (total + chunks.len()) + 16;
+    for chunk in chunks {
+        parser.read_buf(chunk);
+        loop {
+            budget = budget
+                .checked_sub(1)
+                .expect("parser keeps going without progress");
+            match parser.try_parse() {
+                Ok(Some(_)) => {}
+                Err(e) if out_of_steps(&e) => {}
+                Ok(None) | Err(ParseError::NotEnoughData | ParseError::UnexpectedEof) => break,
+                Err(e) => {
+                    assert_eq!(
+                        parser.try_parse(),
+                        Err(e),
+                        "error changed without new input"
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// The read loop of a client connection, without the commands
+fn parse_requests(chunks: &[&[u8]]) -> ParseRun {
+    let limits = RequestLimits::default();
+    let mut parser = Parser::new(MAX_DEPTH, limits.max_bytes + 1);
+    let mut scan = request::Scan::default();
+    let mut requests = Vec::new();
+    for chunk in chunks {
+        parser.buffer.extend_from_slice(chunk);
+        let mut consumed = 0;
+        loop {
+            match scan.check(&parser.buffer[consumed..], &limits) {
+                Ok(Some(len)) => consumed += len,
+                Ok(None) => break,
+                Err(e) => {
+                    return ParseRun {
+                        requests,
+                        error: Some(e),
+                    }
+                }
+            }
+            match request::parse(&mut parser) {
+                Ok(request) => requests.push(request),
+                Err(e) => {
+                    return ParseRun {
+                        requests,
+                        error: Some(e),
+                    }
+                }
+            }
+        }
+        parser.buffer.advance(consumed);
+        parser.clear_buffer(0);
+    }
+    ParseRun {
+        requests,
+        error: None,
+    }
+}
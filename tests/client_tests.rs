@@ -1,12 +1,20 @@
+use foobar_db::db::rdb;
 use foobar_db::server::server::{Server, ServerConfig};
+use foobar_db::test_util::{TestClient, TestServer};
 use std::error::Error;
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use stream_resp::resp::RespValue;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 async fn send_command(stream: &mut TcpStream, command: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     stream.write_all(command).await?;
 
-    let mut response = vec![0u8; 1024];
+    let mut response = vec![0u8; 4096];
     let n = stream.read(&mut response).await?;
     Ok(response[..n].to_vec())
 }
@@ -14,27 +22,14 @@ async fn send_command(stream: &mut TcpStream, command: &[u8]) -> Result<Vec<u8>,
 #[tokio::test]
 async fn test_set_get_commands() -> Result<(), Box<dyn Error>> {
     // 创建并启动服务器
-    let config = ServerConfig {
-        host: "127.0.0.1".to_string(),
-        port: 6379,
+    let server = TestServer::with_config(ServerConfig {
         max_connections: 10,
-    };
-    let server = Server::new(config);
-
-    // 在新任务中运行服务器
-    let server_handle = tokio::spawn(async move {
-        server.run().await.unwrap();
-    });
-
-    // 等待服务器启动
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        ..Default::default()
+    })
+    .await;
 
     // 创建客户端连接
-    let mut stream = TcpStream::connect("127.0.0.1:6379").await?;
-
-    // 跳过欢迎消息
-    let mut welcome = vec![0u8; 1024];
-    stream.read(&mut welcome).await?;
+    let mut stream = TcpStream::connect(server.addr()).await?;
 
     // 测试 SET 命令
     let set_cmd = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
@@ -48,7 +43,6 @@ async fn test_set_get_commands() -> Result<(), Box<dyn Error>> {
 
     // 关闭连接和服务器
     drop(stream);
-    server_handle.abort();
 
     Ok(())
 }
@@ -56,27 +50,14 @@ async fn test_set_get_commands() -> Result<(), Box<dyn Error>> {
 #[tokio::test]
 async fn test_multiple_commands() -> Result<(), Box<dyn Error>> {
     // 创建并启动服务器
-    let config = ServerConfig {
-        host: "127.0.0.1".to_string(),
-        port: 6380, // 使用不同端口避免冲突
+    let server = TestServer::with_config(ServerConfig {
         max_connections: 10,
-    };
-    let server = Server::new(config);
-
-    // 在新任务中运行服务器
-    let server_handle = tokio::spawn(async move {
-        server.run().await.unwrap();
-    });
-
-    // 等待服务器启动
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        ..Default::default()
+    })
+    .await;
 
     // 创建客户端连接
-    let mut stream = TcpStream::connect("127.0.0.1:6380").await?;
-
-    // 跳过欢迎消息
-    let mut welcome = vec![0u8; 1024];
-    stream.read(&mut welcome).await?;
+    let mut stream = TcpStream::connect(server.addr()).await?;
 
     // 测试 PING 命令
     let ping_cmd = b"*1\r\n$4\r\nPING\r\n";
@@ -98,6 +79,19 @@ async fn test_multiple_commands() -> Result<(), Box<dyn Error>> {
     let response = send_command(&mut stream, get_missing_cmd).await?;
     assert_eq!(&response, b"$-1\r\n");
 
+    // 测试 UNLINK 命令
+    let unlink_cmd = b"*3\r\n$6\r\nUNLINK\r\n$3\r\nkey\r\n$7\r\nmissing\r\n";
+    let response = send_command(&mut stream, unlink_cmd).await?;
+    assert_eq!(&response, b":1\r\n");
+    let response = send_command(&mut stream, get_cmd).await?;
+    assert_eq!(&response, b"$-1\r\n");
+
+    // 流水线中同一个键的命令按发送顺序执行
+    let pipeline = b"*3\r\n$3\r\nSET\r\n$1\r\np\r\n$1\r\n1\r\n*2\r\n$3\r\nGET\r\n$1\r\np\r\n\
+                     *2\r\n$6\r\nUNLINK\r\n$1\r\np\r\n*2\r\n$3\r\nGET\r\n$1\r\np\r\n";
+    let response = send_command(&mut stream, pipeline).await?;
+    assert_eq!(&response, b"+OK\r\n$1\r\n1\r\n:1\r\n$-1\r\n");
+
     // 测试 INFO 命令
     let info_cmd = b"*1\r\n$4\r\nINFO\r\n";
     let response = send_command(&mut stream, info_cmd).await?;
@@ -112,9 +106,12 @@ async fn test_multiple_commands() -> Result<(), Box<dyn Error>> {
         .is_some());
 
     // 测试 COMMAND 命令
-    let command_cmd = b"*1\r\n$7\r\nCOMMAND\r\n";
+    let command_cmd = b"*2\r\n$7\r\nCOMMAND\r\n$5\r\nCOUNT\r\n";
     let response = send_command(&mut stream, command_cmd).await?;
-    assert_eq!(&response, b"+OK\r\n");
+    assert!(response.starts_with(b":"));
+    let command_cmd = b"*3\r\n$7\r\nCOMMAND\r\n$4\r\nINFO\r\n$3\r\nGET\r\n";
+    let response = send_command(&mut stream, command_cmd).await?;
+    assert!(response.starts_with(b"*1\r\n*10\r\n$3\r\nget\r\n:2\r\n"));
 
     // 测试未知命令
     let unknown_cmd = b"*1\r\n$7\r\nUNKNOWN\r\n";
@@ -123,7 +120,1376 @@ async fn test_multiple_commands() -> Result<(), Box<dyn Error>> {
 
     // 关闭连接和服务器
     drop(stream);
-    server_handle.abort();
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_select_copy_move_commands() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig {
+        max_connections: 10,
+        ..Default::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(server.addr()).await?;
+
+    let set_cmd = b"*3\r\n$3\r\nSET\r\n$3\r\nsrc\r\n$5\r\nvalue\r\n";
+    assert_eq!(&send_command(&mut stream, set_cmd).await?, b"+OK\r\n");
+
+    // COPY 到同一个库, 目标已存在时不覆盖
+    let copy_cmd = b"*3\r\n$4\r\nCOPY\r\n$3\r\nsrc\r\n$3\r\ndst\r\n";
+    assert_eq!(&send_command(&mut stream, copy_cmd).await?, b":1\r\n");
+    assert_eq!(&send_command(&mut stream, copy_cmd).await?, b":0\r\n");
+
+    let copy_same_cmd = b"*3\r\n$4\r\nCOPY\r\n$3\r\nsrc\r\n$3\r\nsrc\r\n";
+    let response = send_command(&mut stream, copy_same_cmd).await?;
+    assert!(response.starts_with(b"-ERR"));
+
+    // MOVE 到 1 号库
+    let move_cmd = b"*3\r\n$4\r\nMOVE\r\n$3\r\nsrc\r\n$1\r\n1\r\n";
+    assert_eq!(&send_command(&mut stream, move_cmd).await?, b":1\r\n");
+    let get_cmd = b"*2\r\n$3\r\nGET\r\n$3\r\nsrc\r\n";
+    assert_eq!(&send_command(&mut stream, get_cmd).await?, b"$-1\r\n");
+
+    let select_cmd = b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n";
+    assert_eq!(&send_command(&mut stream, select_cmd).await?, b"+OK\r\n");
+    assert_eq!(
+        &send_command(&mut stream, get_cmd).await?,
+        b"$5\r\nvalue\r\n"
+    );
+
+    // RESET 回到 0 号库
+    let reset_cmd = b"*1\r\n$5\r\nRESET\r\n";
+    assert_eq!(&send_command(&mut stream, reset_cmd).await?, b"+RESET\r\n");
+    assert_eq!(&send_command(&mut stream, get_cmd).await?, b"$-1\r\n");
+
+    let select_cmd = b"*2\r\n$6\r\nSELECT\r\n$3\r\n100\r\n";
+    let response = send_command(&mut stream, select_cmd).await?;
+    assert!(response.starts_with(b"-ERR"));
+
+    drop(stream);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_command() -> Result<(), Box<dyn Error>> {
+    let source_server = TestServer::start().await;
+    let target_server = TestServer::start().await;
+
+    let mut source = TcpStream::connect(source_server.addr()).await?;
+    let mut target = TcpStream::connect(target_server.addr()).await?;
+
+    let set_cmd = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+    assert_eq!(&send_command(&mut source, set_cmd).await?, b"+OK\r\n");
+
+    // MIGRATE 127.0.0.1 <target port> key 1 1000
+    let port = target_server.port().to_string();
+    let migrate_cmd = format!(
+        "*6\r\n$7\r\nMIGRATE\r\n$9\r\n127.0.0.1\r\n${}\r\n{}\r\n$3\r\nkey\r\n$1\r\n1\r\n$4\r\n1000\r\n",
+        port.len(),
+        port
+    );
+    let migrate_cmd = migrate_cmd.as_bytes();
+    assert_eq!(&send_command(&mut source, migrate_cmd).await?, b"+OK\r\n");
+    assert_eq!(
+        &send_command(&mut source, migrate_cmd).await?,
+        b"+NOKEY\r\n"
+    );
+
+    let get_cmd = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+    assert_eq!(&send_command(&mut source, get_cmd).await?, b"$-1\r\n");
+    let select_cmd = b"*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n";
+    assert_eq!(&send_command(&mut target, select_cmd).await?, b"+OK\r\n");
+    assert_eq!(
+        &send_command(&mut target, get_cmd).await?,
+        b"$5\r\nvalue\r\n"
+    );
+
+    drop(source);
+    drop(target);
+    Ok(())
+}
+
+//EOF
+
+#[tokio::test]
+async fn test_rate_limit() -> Result<(), Box<dyn Error>> {
+    // 每个客户端每秒只允许一条命令
+    let server = TestServer::with_config(ServerConfig {
+        ratelimit_rate: 1,
+        ..Default::default()
+    })
+    .await;
+
+    // 同一批里的第二条命令被拒绝
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut stream, b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(
+        &response,
+        b"+PONG\r\n-BUSYRATELIMIT command rate limit exceeded\r\n"
+    );
+
+    // 其他客户端有自己的令牌桶
+    let mut other = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut other, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+
+    drop(stream);
+    drop(other);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_limits() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig {
+        max_request_args: 3,
+        max_request_bytes: 4096,
+        ..Default::default()
+    })
+    .await;
+
+    // 分两次发送的请求在收齐后才执行
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    let value = "v".repeat(2000);
+    let set_cmd = format!("*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$2000\r\n{}\r\n", value);
+    let (head, tail) = set_cmd.as_bytes().split_at(1000);
+    stream.write_all(head).await?;
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    let response = send_command(&mut stream, tail).await?;
+    assert_eq!(&response, b"+OK\r\n");
+
+    let get_cmd = b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n";
+    stream.write_all(get_cmd).await?;
+    let expected = format!("$2000\r\n{}\r\n", value);
+    let mut response = vec![0u8; expected.len()];
+    stream.read_exact(&mut response).await?;
+    assert_eq!(response, expected.as_bytes());
+
+    // 超过参数个数上限的请求只看头部就被拒绝，连接随即关闭
+    let response = send_command(&mut stream, b"*1000000\r\n").await?;
+    assert_eq!(
+        &response,
+        b"-ERR Protocol error: invalid multibulk length\r\n"
+    );
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).await?, 0);
+
+    // 超过大小上限的请求同样被拒绝
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut stream, b"*2\r\n$3\r\nGET\r\n$5000\r\n").await?;
+    assert_eq!(&response, b"-ERR Protocol error: too big request\r\n");
+
+    drop(stream);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_kill() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    let mut victim = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut victim, b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n").await?;
+    let id = std::str::from_utf8(&response)?
+        .trim_start_matches(':')
+        .trim_end()
+        .to_string();
+
+    // 客户端不会杀掉自己
+    let kill_cmd = format!(
+        "*4\r\n$6\r\nCLIENT\r\n$4\r\nKILL\r\n$2\r\nID\r\n${}\r\n{}\r\n",
+        id.len(),
+        id
+    );
+    let response = send_command(&mut victim, kill_cmd.as_bytes()).await?;
+    assert_eq!(&response, b":0\r\n");
+
+    // 另一个连接杀掉它后，它的连接被关闭
+    let mut killer = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut killer, kill_cmd.as_bytes()).await?;
+    assert_eq!(&response, b":1\r\n");
+    let mut rest = Vec::new();
+    assert_eq!(victim.read_to_end(&mut rest).await?, 0);
+
+    // 已经断开的客户端不会再被找到
+    let response = send_command(&mut killer, kill_cmd.as_bytes()).await?;
+    assert_eq!(&response, b":0\r\n");
+    let response = send_command(&mut killer, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+
+    drop(killer);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_tracking() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    // RESP3 客户端在自己的连接上收到失效推送
+    let mut reader = TcpStream::connect(server.addr()).await?;
+    send_command(&mut reader, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").await?;
+    let tracking_on = b"*3\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n";
+    let response = send_command(&mut reader, tracking_on).await?;
+    assert_eq!(&response, b"+OK\r\n");
+    let response = send_command(&mut reader, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").await?;
+    assert_eq!(&response, b"$-1\r\n");
+
+    let mut writer = TcpStream::connect(server.addr()).await?;
+    let set_cmd = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n";
+    let response = send_command(&mut writer, set_cmd).await?;
+    assert_eq!(&response, b"+OK\r\n");
+    let mut push = vec![0u8; 1024];
+    let n = reader.read(&mut push).await?;
+    assert_eq!(
+        &push[..n],
+        b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n"
+    );
+
+    // 再次写入时没有新的读取，不再推送
+    send_command(&mut writer, set_cmd).await?;
+    let response = send_command(&mut reader, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+
+    // RESP2 客户端通过 REDIRECT 在 __redis__:invalidate 频道上收到消息
+    let mut target = TcpStream::connect(server.addr()).await?;
+    let response = send_command(&mut target, b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n").await?;
+    let id = std::str::from_utf8(&response)?
+        .trim_start_matches(':')
+        .trim_end()
+        .to_string();
+    let mut tracker = TcpStream::connect(server.addr()).await?;
+    let redirect = format!(
+        "*8\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n$8\r\nREDIRECT\r\n${}\r\n{}\r\n\
+         $5\r\nBCAST\r\n$6\r\nPREFIX\r\n$5\r\nuser:\r\n",
+        id.len(),
+        id
+    );
+    let response = send_command(&mut tracker, redirect.as_bytes()).await?;
+    assert_eq!(&response, b"+OK\r\n");
+    send_command(
+        &mut writer,
+        b"*3\r\n$3\r\nSET\r\n$6\r\nuser:1\r\n$1\r\nv\r\n",
+    )
+    .await?;
+    let mut message = vec![0u8; 1024];
+    let n = target.read(&mut message).await?;
+    assert_eq!(
+        &message[..n],
+        b"*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$6\r\nuser:1\r\n"
+    );
+
+    // 重定向目标不存在时报错
+    let response = send_command(
+        &mut tracker,
+        b"*5\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n$8\r\nREDIRECT\r\n$6\r\n999999\r\n",
+    )
+    .await?;
+    assert_eq!(
+        &response,
+        b"-ERR The client ID you want redirect to does not exist\r\n"
+    );
+
+    drop(writer);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_protocol_errors() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    // 出错之前的命令照常回复，随后返回协议错误并关闭连接
+    let cases: [(&[u8], &[u8]); 4] = [
+        (
+            b"*1\r\n$4\r\nPING\r\n*1\r\n$2\r\n\xff\xfe\r\n",
+            b"+PONG\r\n-ERR Protocol error: invalid UTF-8 in bulk string\r\n",
+        ),
+        (
+            b"*1\r\n$4\r\nPING\r\n$4\r\nPING\r\n",
+            b"+PONG\r\n-ERR Protocol error: expected '*', got '$'\r\n",
+        ),
+        (
+            b"PING\r\n",
+            b"-ERR Protocol error: expected '*', got 'P'\r\n",
+        ),
+        (b"*1\r\n$x\r\n", b"-ERR Protocol error: invalid length\r\n"),
+    ];
+    for (request, expected) in cases {
+        let mut stream = TcpStream::connect(server.addr()).await?;
+        stream.write_all(request).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        assert_eq!(response, expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_command_errors_in_order() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    // 解析失败的命令在流水线中按顺序返回错误，空请求不回复
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    let pipeline = b"*1\r\n$3\r\nGET\r\n*1\r\n$4\r\nPING\r\n*0\r\n\
+                     *3\r\n$6\r\nEXPIRE\r\n$1\r\nk\r\n$1\r\nx\r\n*1\r\n$4\r\nPING\r\n";
+    let expected: &[u8] = b"-ERR wrong number of arguments for 'get' command\r\n+PONG\r\n\
+                            -ERR value is not an integer or out of range\r\n+PONG\r\n";
+    stream.write_all(pipeline).await?;
+    let mut response = vec![0u8; expected.len()];
+    stream.read_exact(&mut response).await?;
+    assert_eq!(response, expected);
+
+    // 连接保持可用
+    let response = send_command(&mut stream, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quit() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    // QUIT 之前的命令照常回复，之后的命令不再执行
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    stream
+        .write_all(
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*1\r\n$4\r\nQUIT\r\n\
+              *2\r\n$3\r\nDEL\r\n$1\r\nk\r\n",
+        )
+        .await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    assert_eq!(response, b"+OK\r\n+OK\r\n");
+
+    // 客户端半关闭连接后仍能收到全部回复
+    let mut stream = TcpStream::connect(server.addr()).await?;
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n")
+        .await?;
+    stream.shutdown().await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    assert_eq!(response, b"$1\r\nv\r\n+PONG\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_debug_reload() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+
+    // 重新加载后键、值和过期时间都保留下来
+    client.command(&["SET", "string", "value"]).await?;
+    client.command(&["RPUSH", "list", "a", "b", "c"]).await?;
+    client.command(&["HSET", "hash", "field", "1"]).await?;
+    client.command(&["SET", "volatile", "v"]).await?;
+    client.command(&["EXPIRE", "volatile", "100"]).await?;
+    client.command(&["SELECT", "1"]).await?;
+    client.command(&["SADD", "set", "m"]).await?;
+    client.command(&["SELECT", "0"]).await?;
+
+    let reply = client.command(&["DEBUG", "RELOAD"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+
+    let replies = client
+        .pipeline(&[
+            &["GET", "string"],
+            &["LRANGE", "list", "0", "-1"],
+            &["HGET", "hash", "field"],
+            &["TTL", "volatile"],
+            &["OBJECT", "ENCODING", "list"],
+        ])
+        .await?;
+    assert_eq!(replies[0], RespValue::BulkString(Some("value".into())));
+    assert_eq!(
+        replies[1],
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some("a".into())),
+            RespValue::BulkString(Some("b".into())),
+            RespValue::BulkString(Some("c".into())),
+        ]))
+    );
+    assert_eq!(replies[2], RespValue::BulkString(Some("1".into())));
+    assert!(matches!(replies[3], RespValue::Integer(ttl) if ttl > 90 && ttl <= 100));
+    assert_eq!(replies[4], RespValue::BulkString(Some("listpack".into())));
+
+    client.command(&["SELECT", "1"]).await?;
+    let reply = client.command(&["SMEMBERS", "set"]).await?;
+    assert_eq!(
+        reply,
+        RespValue::Array(Some(vec![RespValue::BulkString(Some("m".into()))]))
+    );
+
+    let reply = client.command(&["DEBUG", "NOPE"]).await?;
+    assert!(matches!(reply, RespValue::Error(_)));
+
+    // 关闭主动过期后, 键只在被访问时过期
+    let reply = client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+    let reply = client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "2"]).await?;
+    assert!(matches!(reply, RespValue::Error(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_loading() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+    client.command(&["SET", "k", "v"]).await?;
+
+    // 加载期间只有带 loading 标记的命令能执行, PING 也被拒绝
+    let loading = server.loading();
+    loading.start(100);
+    loading.progress(40);
+    let loading_error =
+        RespValue::Error("LOADING foobar_db is loading the dataset in memory".into());
+    let replies = client
+        .pipeline(&[&["GET", "k"], &["PING"], &["SELECT", "1"], &["INFO"]])
+        .await?;
+    assert_eq!(replies[0], loading_error);
+    assert_eq!(replies[1], loading_error);
+    assert_eq!(replies[2], RespValue::SimpleString("OK".into()));
+    match &replies[3] {
+        RespValue::BulkString(Some(info)) => {
+            assert!(info.contains("loading:1\r\n"));
+            assert!(info.contains("loading_total_bytes:100\r\n"));
+            assert!(info.contains("loading_loaded_perc:40.00\r\n"));
+        }
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    }
+
+    // 加载完成后恢复正常
+    loading.finish();
+    client.command(&["SELECT", "0"]).await?;
+    let reply = client.command(&["GET", "k"]).await?;
+    assert_eq!(reply, RespValue::BulkString(Some("v".into())));
+    let reply = client.command(&["INFO"]).await?;
+    assert!(matches!(reply, RespValue::BulkString(Some(info)) if info.contains("loading:0\r\n")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+
+    // 还不支持从节点, INFO 里总是没有从节点的主节点
+    let reply = client.command(&["INFO"]).await?;
+    let RespValue::BulkString(Some(info)) = reply else {
+        panic!("unexpected INFO reply {:?}", reply);
+    };
+    assert!(info.contains("# Replication\r\nrole:master\r\nconnected_slaves:0\r\n"));
+    assert!(info.contains("master_repl_offset:0\r\n"));
+    let replid = info
+        .lines()
+        .find_map(|line| line.strip_prefix("master_replid:"))
+        .expect("no master_replid in INFO");
+    assert_eq!(replid.len(), 40);
+
+    // 加载期间 ROLE 仍然可用
+    server.loading().start(1);
+    let reply = client.command(&["ROLE"]).await?;
+    assert!(matches!(reply, RespValue::Array(Some(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_only_replica() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+    client.command(&["SET", "k", "v"]).await?;
+
+    // 成为从节点后写命令被拒绝, 读命令照常
+    let reply = client.command(&["REPLICAOF", "127.0.0.1", "6390"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+    let read_only =
+        RespValue::Error("READONLY You can't write against a read only replica.".into());
+    let replies = client
+        .pipeline(&[&["SET", "k", "w"], &["DEL", "k"], &["GET", "k"], &["ROLE"]])
+        .await?;
+    assert_eq!(replies[0], read_only);
+    assert_eq!(replies[1], read_only);
+    assert_eq!(replies[2], RespValue::BulkString(Some("v".into())));
+    assert_eq!(
+        replies[3],
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some("slave".into())),
+            RespValue::BulkString(Some("127.0.0.1".into())),
+            RespValue::Integer(6390),
+            RespValue::BulkString(Some("connect".into())),
+            RespValue::Integer(0),
+        ]))
+    );
+    let reply = client.command(&["REPLICAOF", "127.0.0.1", "port"]).await?;
+    assert_eq!(reply, RespValue::Error("ERR Invalid master port".into()));
+
+    // replica-read-only no 时从节点也接受写入
+    client
+        .command(&["CONFIG", "SET", "replica-read-only", "no"])
+        .await?;
+    let reply = client.command(&["SET", "k", "w"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+    client
+        .command(&["CONFIG", "SET", "replica-read-only", "yes"])
+        .await?;
+
+    // 提升为主节点后恢复写入
+    let reply = client.command(&["SLAVEOF", "no", "one"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+    let reply = client.command(&["SET", "k", "x"]).await?;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+
+    Ok(())
+}
+
+// 从连接读取, 直到收到的内容满足 done
+async fn read_until(
+    stream: &mut TcpStream,
+    done: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut received = vec![];
+    while !done(&received) {
+        let mut chunk = [0u8; 4096];
+        let n = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut chunk))
+            .await??;
+        if n == 0 {
+            return Err("connection closed".into());
+        }
+        received.extend_from_slice(&chunk[..n]);
+    }
+    Ok(received)
+}
+
+#[tokio::test]
+async fn test_psync() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+    client.command(&["SET", "k", "v"]).await?;
+
+    // 全量同步: FULLRESYNC 之后是包含现有数据的 RDB
+    let mut replica = TcpStream::connect(server.addr()).await?;
+    let reply = send_command(
+        &mut replica,
+        b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n7001\r\n",
+    )
+    .await?;
+    assert_eq!(&reply, b"+OK\r\n");
+    replica
+        .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+        .await?;
+    let received = read_until(&mut replica, |received| {
+        let text = String::from_utf8_lossy(received);
+        let Some(rest) = text.split("\r\n$").nth(1) else {
+            return false;
+        };
+        let Some((len, _)) = rest.split_once("\r\n") else {
+            return false;
+        };
+        let header = text.find("\r\n$").unwrap() + 3 + len.len() + 2;
+        received.len() >= header + len.parse::<usize>().unwrap()
+    })
+    .await?;
+    let text = String::from_utf8_lossy(&received);
+    assert!(text.starts_with("+FULLRESYNC "));
+    let replid = text[12..52].to_string();
+    assert!(text[52..].starts_with(" 0\r\n$"));
+    assert!(text.contains("REDIS0011"));
+    assert!(text.contains("\u{1}k\u{1}v"));
+
+    // 之后的写命令原样转发, 前面带上 SELECT
+    client.command(&["SET", "k2", "v2"]).await?;
+    client.command(&["GET", "k2"]).await?;
+    let streamed = b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nSET\r\n$2\r\nk2\r\n$2\r\nv2\r\n";
+    let received = read_until(&mut replica, |received| received.len() >= streamed.len()).await?;
+    assert_eq!(&received, streamed);
+
+    // SPOP 和相对过期时间按实际效果转发
+    client.command(&["SADD", "s", "a"]).await?;
+    client.command(&["SPOP", "s"]).await?;
+    client.command(&["PEXPIRE", "k2", "100000"]).await?;
+    let RespValue::Integer(when) = client.command(&["PEXPIRETIME", "k2"]).await? else {
+        panic!("PEXPIRETIME didn't reply with an integer");
+    };
+    let when = when.to_string();
+    let effects = format!(
+        "*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n\
+         *3\r\n$4\r\nSREM\r\n$1\r\ns\r\n$1\r\na\r\n\
+         *3\r\n$9\r\nPEXPIREAT\r\n$2\r\nk2\r\n${}\r\n{}\r\n",
+        when.len(),
+        when
+    );
+    let received = read_until(&mut replica, |received| received.len() >= effects.len()).await?;
+    assert_eq!(String::from_utf8_lossy(&received), effects);
+    let offset = streamed.len() + effects.len();
+    replica
+        .write_all(
+            format!(
+                "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n${}\r\n{}\r\n",
+                offset.to_string().len(),
+                offset
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let RespValue::BulkString(Some(info)) = client.command(&["INFO"]).await? else {
+        panic!("INFO didn't reply with a bulk string");
+    };
+    assert!(info.contains("connected_slaves:1\r\n"));
+    assert!(info.contains("repl_backlog_active:1\r\n"));
+    assert!(info.contains(&format!("master_repl_offset:{}\r\n", offset)));
+
+    // 断线期间的写命令在重连后从积压缓冲区补上
+    drop(replica);
+    client.command(&["DEL", "k"]).await?;
+    let mut replica = TcpStream::connect(server.addr()).await?;
+    let next = (offset + 1).to_string();
+    replica
+        .write_all(
+            format!(
+                "*3\r\n$5\r\nPSYNC\r\n$40\r\n{}\r\n${}\r\n{}\r\n",
+                replid,
+                next.len(),
+                next
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let expected = format!("+CONTINUE {}\r\n*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n", replid);
+    let received = read_until(&mut replica, |received| received.len() >= expected.len()).await?;
+    assert_eq!(String::from_utf8_lossy(&received), expected);
+
+    // 换了复制 ID 之后旧的历史不再有效
+    client.command(&["DEBUG", "CHANGE-REPL-ID"]).await?;
+    let mut replica = TcpStream::connect(server.addr()).await?;
+    replica
+        .write_all(
+            format!(
+                "*3\r\n$5\r\nPSYNC\r\n$40\r\n{}\r\n${}\r\n{}\r\n",
+                replid,
+                next.len(),
+                next
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let received = read_until(&mut replica, |received| received.len() >= 52).await?;
+    assert!(received.starts_with(b"+FULLRESYNC "));
+    assert!(!received.starts_with(format!("+FULLRESYNC {}", replid).as_bytes()));
+
+    // 积压缓冲区大小可以配置, 不小于 16KB
+    client
+        .command(&["CONFIG", "SET", "repl-backlog-size", "100"])
+        .await?;
+    let reply = client
+        .command(&["CONFIG", "GET", "repl-backlog-size"])
+        .await?;
+    assert_eq!(
+        reply,
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some("repl-backlog-size".into())),
+            RespValue::BulkString(Some("16384".into())),
+        ]))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_is_silent_by_default() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr()).await?;
+
+    // 连接后服务器不应主动发送任何内容
+    let mut buf = [0u8; 64];
+    let silent =
+        tokio::time::timeout(std::time::Duration::from_millis(200), stream.read(&mut buf)).await;
+    assert!(silent.is_err(), "unexpected bytes on connect");
+
+    // 第一条回复就是 PING 的回复
+    let response = send_command(&mut stream, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_welcome_banner_opt_in() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig {
+        welcome: Some("hello from foobar_db".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let mut stream = TcpStream::connect(server.addr()).await?;
+
+    // 配置后连接时先收到欢迎行
+    let mut banner = vec![0u8; "hello from foobar_db\r\n".len()];
+    stream.read_exact(&mut banner).await?;
+    assert_eq!(&banner, b"hello from foobar_db\r\n");
+
+    let response = send_command(&mut stream, b"*1\r\n$4\r\nPING\r\n").await?;
+    assert_eq!(&response, b"+PONG\r\n");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_namespaces() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig {
+        namespaces: vec![
+            "tenant:secret:2".parse().unwrap(),
+            "other:pw".parse().unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+    let ok = RespValue::SimpleString("OK".into());
+    let mut client = server.connect().await?;
+    client.command(&["SET", "shared", "default"]).await?;
+
+    // 错误的密码不会切换命名空间
+    let reply = client.command(&["AUTH", "tenant", "wrong"]).await?;
+    assert!(matches!(reply, RespValue::Error(e) if e.starts_with("WRONGPASS")));
+    assert_eq!(client.command(&["AUTH", "tenant", "secret"]).await?, ok);
+
+    // 命名空间有独立的键空间
+    let reply = client.command(&["GET", "shared"]).await?;
+    assert_eq!(reply, RespValue::Null);
+    assert_eq!(client.command(&["SET", "a", "1"]).await?, ok);
+    assert_eq!(client.command(&["SET", "b", "2"]).await?, ok);
+
+    // 达到键数配额后不能新增键, 但可以修改和删除已有的键
+    let reply = client.command(&["SET", "c", "3"]).await?;
+    assert!(matches!(reply, RespValue::Error(e) if e.starts_with("OOM")));
+    assert_eq!(client.command(&["SET", "a", "10"]).await?, ok);
+    assert_eq!(client.command(&["DEL", "b"]).await?, RespValue::Integer(1));
+    assert_eq!(client.command(&["SET", "c", "3"]).await?, ok);
+
+    // 其他用户看不到这些键
+    let mut other = server.connect().await?;
+    assert_eq!(other.command(&["AUTH", "other", "pw"]).await?, ok);
+    let reply = other.command(&["GET", "a"]).await?;
+    assert_eq!(reply, RespValue::Null);
+
+    // RESET 回到默认用户和服务器自己的数据库
+    client.command(&["RESET"]).await?;
+    let reply = client.command(&["GET", "shared"]).await?;
+    assert_eq!(reply, RespValue::BulkString(Some("default".into())));
+    let reply = client.command(&["GET", "a"]).await?;
+    assert_eq!(reply, RespValue::Null);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_commandstats() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig::default()).await;
+    let mut stream = TcpStream::connect(server.addr()).await?;
+
+    // 成功、失败和被拒绝的调用分别计数
+    send_command(&mut stream, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n").await?;
+    send_command(&mut stream, b"*3\r\n$5\r\nLPUSH\r\n$1\r\nk\r\n$1\r\nx\r\n").await?;
+    send_command(&mut stream, b"*1\r\n$3\r\nGET\r\n").await?;
+
+    let response =
+        send_command(&mut stream, b"*2\r\n$4\r\nINFO\r\n$12\r\ncommandstats\r\n").await?;
+    let info = String::from_utf8(response)?;
+    assert!(info.contains("# Commandstats\r\n"));
+    assert!(info.contains("cmdstat_set:calls=1,"));
+    assert!(info.contains("cmdstat_lpush:calls=1,") && info.contains("failed_calls=1\r\n"));
+    assert!(info.contains("cmdstat_get:calls=0,usec=0,usec_per_call=0.00,rejected_calls=1,"));
+    // 只返回请求的部分
+    assert!(!info.contains("# Server"));
+
+    let response = send_command(
+        &mut stream,
+        b"*3\r\n$7\r\nLATENCY\r\n$9\r\nHISTOGRAM\r\n$3\r\nset\r\n",
+    )
+    .await?;
+    assert!(response.starts_with(b"*2\r\n$3\r\nset\r\n*4\r\n$5\r\ncalls\r\n:1\r\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_subscriber_mode() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+
+    // 按长度读取，确认和消息可能分多次到达
+    async fn expect(stream: &mut TcpStream, expected: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut buf = vec![0u8; expected.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(
+            String::from_utf8_lossy(&buf),
+            String::from_utf8_lossy(expected)
+        );
+        Ok(())
+    }
+
+    // 管道中 SUBSCRIBE 之前的命令照常执行，之后的命令被拒绝
+    let mut subscriber = TcpStream::connect(server.addr()).await?;
+    subscriber
+        .write_all(
+            b"*1\r\n$4\r\nPING\r\n\
+              *3\r\n$9\r\nSUBSCRIBE\r\n$1\r\na\r\n$1\r\nb\r\n\
+              *2\r\n$3\r\nGET\r\n$1\r\nk\r\n\
+              *1\r\n$4\r\nPING\r\n",
+        )
+        .await?;
+    expect(
+        &mut subscriber,
+        b"+PONG\r\n\
+          *3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+          *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n\
+          -ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET \
+          are allowed in this context\r\n\
+          *2\r\n$4\r\npong\r\n$0\r\n\r\n",
+    )
+    .await?;
+
+    let mut publisher = TcpStream::connect(server.addr()).await?;
+    let response = send_command(
+        &mut publisher,
+        b"*3\r\n$7\r\nPUBLISH\r\n$1\r\na\r\n$2\r\nhi\r\n",
+    )
+    .await?;
+    assert_eq!(&response, b":1\r\n");
+    expect(
+        &mut subscriber,
+        b"*3\r\n$7\r\nmessage\r\n$1\r\na\r\n$2\r\nhi\r\n",
+    )
+    .await?;
+
+    // 取消全部订阅后退出订阅模式
+    subscriber
+        .write_all(b"*1\r\n$11\r\nUNSUBSCRIBE\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+        .await?;
+    expect(
+        &mut subscriber,
+        b"*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:1\r\n\
+          *3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:0\r\n\
+          $-1\r\n",
+    )
+    .await?;
+
+    // RESP3 连接订阅后仍可执行任何命令
+    let mut resp3 = TcpStream::connect(server.addr()).await?;
+    send_command(&mut resp3, b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n").await?;
+    resp3
+        .write_all(
+            b"*2\r\n$10\r\nPSUBSCRIBE\r\n$2\r\nn*\r\n\
+              *2\r\n$3\r\nGET\r\n$1\r\nk\r\n",
+        )
+        .await?;
+    expect(
+        &mut resp3,
+        b">3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:1\r\n$-1\r\n",
+    )
+    .await?;
+
+    // RESET 清除订阅
+    subscriber
+        .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$1\r\na\r\n*1\r\n$5\r\nRESET\r\n")
+        .await?;
+    expect(
+        &mut subscriber,
+        b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n+RESET\r\n",
+    )
+    .await?;
+    let response = send_command(
+        &mut publisher,
+        b"*3\r\n$7\r\nPUBLISH\r\n$1\r\na\r\n$2\r\nhi\r\n",
+    )
+    .await?;
+    assert_eq!(&response, b":0\r\n");
+    let response = send_command(&mut subscriber, b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").await?;
+    assert_eq!(&response, b"$-1\r\n");
+
+    Ok(())
+}
+
+// 以子进程运行服务器二进制，等到能连上为止
+async fn spawn_server(dir: &std::path::Path) -> Result<(Child, SocketAddr), Box<dyn Error>> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let child = Command::new(env!("CARGO_BIN_EXE_foobar_db"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--dir")
+        .arg(dir)
+        .arg("--appendonly")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+    for _ in 0..500 {
+        if let Ok(mut client) = TestClient::connect(addr).await {
+            if client.command(&["PING"]).await.is_ok() {
+                return Ok((child, addr));
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Err("server did not start".into())
+}
+
+#[tokio::test]
+async fn test_aof_survives_kill() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("foobar_aof_kill_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    // 不停写入，记下收到回复的条数
+    let (mut server, addr) = spawn_server(&dir).await?;
+    let acked = Arc::new(AtomicUsize::new(0));
+    let writer = tokio::spawn({
+        let acked = acked.clone();
+        async move {
+            let Ok(mut client) = TestClient::connect(addr).await else {
+                return;
+            };
+            for i in 0.. {
+                let key = format!("k{}", i);
+                let value = i.to_string();
+                let commands: [&[&str]; 2] = [&["SET", &key, &value], &["RPUSH", "list", &value]];
+                if client.pipeline(&commands).await.is_err() {
+                    return;
+                }
+                acked.store(i + 1, Ordering::SeqCst);
+            }
+        }
+    });
+
+    // 写入途中用 SIGKILL 杀掉服务器
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    server.kill()?;
+    server.wait()?;
+    writer.await?;
+    let acked = acked.load(Ordering::SeqCst);
+    assert!(acked > 0);
+
+    // 写到一半的命令留在文件末尾
+    let path = dir.join("appendonly.aof");
+    let mut aof = std::fs::OpenOptions::new().append(true).open(&path)?;
+    std::io::Write::write_all(&mut aof, b"*3\r\n$3\r\nSET\r\n$4\r\ntorn")?;
+    drop(aof);
+
+    // 确认过的写入全部恢复，没等到回复的可能也在
+    let (mut server, addr) = spawn_server(&dir).await?;
+    let mut client = TestClient::connect(addr).await?;
+    for i in 0..acked {
+        assert_eq!(
+            client.command(&["GET", &format!("k{}", i)]).await?,
+            RespValue::BulkString(Some(i.to_string().into()))
+        );
+    }
+    let RespValue::Integer(len) = client.command(&["LLEN", "list"]).await? else {
+        panic!("LLEN did not return an integer");
+    };
+    assert!(len as usize >= acked);
+
+    // 恢复后继续追加，再杀一次也能读回
+    client.command(&["SELECT", "1"]).await?;
+    client.command(&["SET", "after", "restart"]).await?;
+    server.kill()?;
+    server.wait()?;
+
+    let (mut server, addr) = spawn_server(&dir).await?;
+    let mut client = TestClient::connect(addr).await?;
+    client.command(&["SELECT", "1"]).await?;
+    assert_eq!(
+        client.command(&["GET", "after"]).await?,
+        RespValue::BulkString(Some("restart".into()))
+    );
+    server.kill()?;
+    server.wait()?;
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bgrewriteaof() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("foobar_aof_rewrite_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let config = || ServerConfig {
+        dir: dir.clone(),
+        appendonly: true,
+        ..ServerConfig::default()
+    };
+    let path = dir.join("appendonly.aof");
+
+    let server = TestServer::with_config(config()).await;
+    let mut client = server.connect().await?;
+    for i in 0..100 {
+        client.command(&["SET", "counter", &i.to_string()]).await?;
+    }
+    client
+        .pipeline(&[&["SELECT", "1"], &["SET", "other", "1"], &["SELECT", "0"]])
+        .await?;
+
+    // 重写期间的写入也要写进新文件，且只写一次
+    let writer = tokio::spawn({
+        let mut client = server.connect().await?;
+        async move {
+            for i in 0..2000 {
+                let value = i.to_string();
+                let commands: [&[&str]; 2] = [&["RPUSH", "list", &value], &["SET", "last", &value]];
+                client.pipeline(&commands).await.unwrap();
+            }
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(
+        client.command(&["BGREWRITEAOF"]).await?,
+        RespValue::SimpleString("Background append only file rewriting started".into())
+    );
+    writer.await?;
+    for _ in 0..500 {
+        if String::from_utf8_lossy(&std::fs::read(&path)?).contains("RESTORE") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let aof = String::from_utf8_lossy(&std::fs::read(&path)?).to_string();
+    assert!(aof.contains("RESTORE"));
+    assert!(!aof.contains("SET\r\n$7\r\ncounter"));
+    drop(client);
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let server = TestServer::with_config(config()).await;
+    let mut client = server.connect().await?;
+    assert_eq!(
+        client.command(&["GET", "counter"]).await?,
+        RespValue::BulkString(Some("99".into()))
+    );
+    assert_eq!(
+        client.command(&["LLEN", "list"]).await?,
+        RespValue::Integer(2000)
+    );
+    assert_eq!(
+        client.command(&["GET", "last"]).await?,
+        RespValue::BulkString(Some("1999".into()))
+    );
+    client.command(&["SELECT", "1"]).await?;
+    assert_eq!(
+        client.command(&["GET", "other"]).await?,
+        RespValue::BulkString(Some("1".into()))
+    );
+    drop(server);
+
+    // 没开 AOF 时拒绝
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+    assert!(matches!(
+        client.command(&["BGREWRITEAOF"]).await?,
+        RespValue::Error(_)
+    ));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aof_replays_effects() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("foobar_aof_effects_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let config = || ServerConfig {
+        dir: dir.clone(),
+        appendonly: true,
+        ..ServerConfig::default()
+    };
+    let members = |reply: RespValue<'static>| -> Vec<String> {
+        let RespValue::Array(Some(items)) = reply else {
+            panic!("not an array: {:?}", reply);
+        };
+        let mut members: Vec<String> = items
+            .into_iter()
+            .map(|item| match item {
+                RespValue::BulkString(Some(s)) => s.to_string(),
+                other => panic!("not a member: {:?}", other),
+            })
+            .collect();
+        members.sort();
+        members
+    };
+
+    let server = TestServer::with_config(config()).await;
+    let mut client = server.connect().await?;
+    let letters = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+    let mut sadd = vec!["SADD", "s"];
+    sadd.extend(letters);
+    client.command(&sadd).await?;
+    client.command(&["SPOP", "s", "3"]).await?;
+    let left = members(client.command(&["SMEMBERS", "s"]).await?);
+    assert_eq!(left.len(), 7);
+
+    // 重启前就已过期的键
+    client.command(&["SET", "t", "v"]).await?;
+    client.command(&["PEXPIRE", "t", "100"]).await?;
+    client.command(&["SETEX", "e", "1", "v"]).await?;
+    client.command(&["SET", "lazy", "v"]).await?;
+    client.command(&["PEXPIRE", "lazy", "100"]).await?;
+    client.command(&["SET", "kept", "v"]).await?;
+    client.command(&["EXPIRE", "kept", "100"]).await?;
+    let ttl = client.command(&["PEXPIRETIME", "kept"]).await?;
+
+    // 超时的 BLMOVE 不记录，拿到元素的记成 LMOVE
+    client
+        .command(&["BLMOVE", "src", "dst", "LEFT", "LEFT", "0.01"])
+        .await?;
+    client.command(&["RPUSH", "src", "x", "y"]).await?;
+    client
+        .command(&["BLMOVE", "src", "dst", "RIGHT", "LEFT", "0"])
+        .await?;
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(client.command(&["GET", "lazy"]).await?, RespValue::Null);
+    drop(server);
+    drop(client);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let aof = String::from_utf8_lossy(&std::fs::read(dir.join("appendonly.aof"))?).to_string();
+    for logged in ["SREM", "PEXPIREAT", "LMOVE"] {
+        assert!(aof.contains(logged), "{} missing from the AOF", logged);
+    }
+    for sent in ["SPOP", "SETEX", "BLMOVE"] {
+        assert!(!aof.contains(sent), "{} logged as sent", sent);
+    }
+
+    let server = TestServer::with_config(config()).await;
+    let mut client = server.connect().await?;
+    assert_eq!(members(client.command(&["SMEMBERS", "s"]).await?), left);
+    for key in ["t", "e", "lazy"] {
+        assert_eq!(client.command(&["GET", key]).await?, RespValue::Null);
+    }
+    assert_eq!(client.command(&["PEXPIRETIME", "kept"]).await?, ttl);
+    assert_eq!(
+        members(client.command(&["LRANGE", "dst", "0", "-1"]).await?),
+        vec!["y".to_string()]
+    );
+    assert_eq!(
+        members(client.command(&["LRANGE", "src", "0", "-1"]).await?),
+        vec!["x".to_string()]
+    );
+    drop(server);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recover_snapshot_and_aof() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("foobar_recover_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let config = |appendonly| ServerConfig {
+        dir: dir.clone(),
+        appendonly,
+        save_on_shutdown: true,
+        ..ServerConfig::default()
+    };
+    let path = dir.join("appendonly.aof");
+
+    // 没开 AOF 时留下的快照
+    let mut server = TestServer::with_config(config(false)).await;
+    let mut client = server.connect().await?;
+    client.command(&["SET", "a", "1"]).await?;
+    client.command(&["SHUTDOWN"]).await?;
+    server.stopped().await;
+
+    // AOF 为空时从快照启动，并把数据写进 AOF
+    let mut server = TestServer::with_config(config(true)).await;
+    let mut client = server.connect().await?;
+    assert_eq!(
+        client.command(&["GET", "a"]).await?,
+        RespValue::BulkString(Some("1".into()))
+    );
+    assert!(String::from_utf8_lossy(&std::fs::read(&path)?).contains("RESTORE"));
+    client.command(&["INCR", "n"]).await?;
+    client.command(&["SHUTDOWN"]).await?;
+    server.stopped().await;
+
+    // 快照之后的写入只在 AOF 里，INCR 不能重放两次
+    let server = TestServer::with_config(config(true)).await;
+    let mut client = server.connect().await?;
+    client.command(&["INCR", "n"]).await?;
+    drop(client);
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let server = TestServer::with_config(config(true)).await;
+    let mut client = server.connect().await?;
+    assert_eq!(
+        client.command(&["GET", "a"]).await?,
+        RespValue::BulkString(Some("1".into()))
+    );
+    assert_eq!(
+        client.command(&["GET", "n"]).await?,
+        RespValue::BulkString(Some("2".into()))
+    );
+    drop(client);
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // 重放失败的命令让启动失败
+    let mut record = b"*3\r\n$5\r\nLPUSH\r\n$1\r\na\r\n$1\r\nx\r\n".to_vec();
+    let crc = rdb::crc64(&record);
+    record.extend_from_slice(format!("#CRC64:{:016x}\r\n", crc).as_bytes());
+    let mut aof = std::fs::OpenOptions::new().append(true).open(&path)?;
+    std::io::Write::write_all(&mut aof, &record)?;
+    drop(aof);
+    let err = Server::new(config(true)).recover().await.unwrap_err();
+    // 只重放快照之后的 SELECT、INCR 和 LPUSH
+    assert!(err.to_string().contains("1 of 3 commands"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_save() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("foobar_shutdown_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut server = TestServer::with_config(ServerConfig {
+        dir: dir.clone(),
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut client = server.connect().await?;
+    client.command(&["SET", "k", "v"]).await?;
+    assert!(matches!(
+        client.command(&["SHUTDOWN", "NOW"]).await?,
+        RespValue::Error(_)
+    ));
+    assert_eq!(
+        client.command(&["SHUTDOWN", "SAVE"]).await?,
+        RespValue::SimpleString("OK".into())
+    );
+    // 关闭时保存快照
+    server.stopped().await;
+    assert!(dir.join("dump.rdb").exists());
+    drop(client);
+
+    let server = TestServer::with_config(ServerConfig {
+        dir: dir.clone(),
+        save_on_shutdown: true,
+        ..ServerConfig::default()
+    })
+    .await;
+    let mut client = server.connect().await?;
+    assert_eq!(
+        client.command(&["GET", "k"]).await?,
+        RespValue::BulkString(Some("v".into()))
+    );
+    drop(server);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_holds_keys() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    // 一个回复很慢的目标实例
+    let target = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = target.local_addr()?.port().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _) = target.accept().await.unwrap();
+        let mut request = vec![0u8; 4096];
+        let _ = socket.read(&mut request).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        socket.write_all(b"+OK\r\n+OK\r\n").await.unwrap();
+    });
+
+    let mut client = server.connect().await?;
+    client.command(&["SET", "k", "old"]).await?;
+    let migrate = tokio::spawn(async move {
+        client
+            .command(&["MIGRATE", "127.0.0.1", &port, "k", "0", "1000"])
+            .await
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // 迁移期间的写入等迁移完成，不会被迁移删掉
+    let mut other = server.connect().await?;
+    assert_eq!(
+        other.command(&["SET", "k", "new"]).await?,
+        RespValue::SimpleString("OK".into())
+    );
+    assert_eq!(migrate.await??, RespValue::SimpleString("OK".into()));
+    assert_eq!(
+        other.command(&["GET", "k"]).await?,
+        RespValue::BulkString(Some("new".into()))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_views() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::with_config(ServerConfig {
+        read_views: 4,
+        ..Default::default()
+    })
+    .await;
+    let mut writer = server.connect().await?;
+    let mut readers = Vec::new();
+    for _ in 0..4 {
+        readers.push(server.connect().await?);
+    }
+
+    // 每次写入后,所有连接都读到新值
+    for i in 0..20 {
+        let value = i.to_string();
+        writer.command(&["SET", "key", &value]).await?;
+        for reader in readers.iter_mut() {
+            for _ in 0..3 {
+                let reply = reader.command(&["GET", "key"]).await?;
+                assert_eq!(reply, RespValue::BulkString(Some(value.clone().into())));
+            }
+        }
+    }
+
+    let reply = writer.command(&["INFO"]).await?;
+    assert!(
+        matches!(reply, RespValue::BulkString(Some(info)) if !info.contains("read_view_hits:0\r\n"))
+    );
+    Ok(())
+}
+
+#[cfg(feature = "uring")]
+#[tokio::test]
+async fn test_io_uring_clients() -> Result<(), Box<dyn Error>> {
+    if foobar_db::server::uring::probe().is_err() {
+        return Ok(());
+    }
+    let mut server = TestServer::with_config(ServerConfig {
+        io_uring: true,
+        ..ServerConfig::default()
+    })
+    .await;
+
+    // 两个连接共享同一份数据，流水线的回复按顺序返回
+    let mut a = server.connect().await?;
+    let mut b = server.connect().await?;
+    a.command(&["SET", "k", "v"]).await?;
+    assert_eq!(
+        b.command(&["GET", "k"]).await?,
+        RespValue::BulkString(Some("v".into()))
+    );
+    let replies = b
+        .pipeline(&[&["INCR", "n"], &["INCR", "n"], &["GET", "n"]])
+        .await?;
+    assert_eq!(replies[2], RespValue::BulkString(Some("2".into())));
+
+    // SHUTDOWN 之后 io_uring 的线程也要停下来
+    a.command(&["SHUTDOWN"]).await?;
+    tokio::time::timeout(Duration::from_secs(5), server.stopped()).await?;
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use foobar_db::client::{key_slot, ClientConfig, ClientError, ClusterClient, FoobarClient};
+use foobar_db::server::server::{Server, ServerConfig};
+use foobar_db::test_util::TestServer;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stream_resp::parser::Parser;
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[tokio::test]
+async fn test_typed_commands() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let client = FoobarClient::connect(server.addr().to_string()).await?;
+
+    client.set("key", "value").await?;
+    assert_eq!(client.get("key").await?, Some("value".to_string()));
+    assert_eq!(client.get("missing").await?, None);
+    assert!(client.expire("key", 100).await?);
+    assert!(client.ttl("key").await? > 90);
+
+    assert_eq!(client.rpush("list", &["a", "b"]).await?, 2);
+    assert_eq!(client.lpush("list", &["z"]).await?, 3);
+    assert_eq!(client.lrange("list", 0, -1).await?, vec!["z", "a", "b"]);
+    assert_eq!(client.lpop("list").await?, Some("z".to_string()));
+    assert_eq!(client.llen("list").await?, 2);
+
+    assert_eq!(client.sadd("set", &["m", "n", "m"]).await?, 2);
+    assert_eq!(client.srem("set", &["n"]).await?, 1);
+    assert_eq!(client.smembers("set").await?, vec!["m"]);
+
+    assert_eq!(client.hset("hash", "field", "1").await?, 1);
+    assert_eq!(client.hget("hash", "field").await?, Some("1".to_string()));
+    assert_eq!(client.del(&["key", "missing"]).await?, 1);
+
+    // INCR 在服务端执行，不存在的键从 0 开始
+    assert_eq!(client.incr("counter").await?, 1);
+    assert_eq!(client.incr("counter").await?, 2);
+    assert_eq!(client.get("counter").await?, Some("2".to_string()));
+    client.set("text", "abc").await?;
+    let err = client.incr("text").await.unwrap_err();
+    assert!(matches!(err, ClientError::Server(msg) if msg.contains("not an integer")));
+
+    // 错误回复变成 ClientError::Server
+    let err = client.lpush("hash", &["x"]).await.unwrap_err();
+    assert!(matches!(err, ClientError::Server(msg) if msg.starts_with("WRONGTYPE")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipeline_and_pool() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let client = FoobarClient::with_config(ClientConfig {
+        addr: server.addr().to_string(),
+        max_connections: 2,
+        ..Default::default()
+    })
+    .await?;
+
+    // 流水线中的错误留在自己的位置上
+    let mut pipeline = client.pipeline();
+    pipeline
+        .cmd(&["SET", "p", "1"])
+        .cmd(&["GET"])
+        .cmd(&["GET", "p"]);
+    assert_eq!(pipeline.len(), 3);
+    let replies = pipeline.execute().await?;
+    assert_eq!(replies[0], RespValue::SimpleString("OK".into()));
+    assert!(matches!(replies[1], RespValue::Error(_)));
+    assert_eq!(replies[2], RespValue::BulkString(Some("1".into())));
+    assert!(pipeline.is_empty());
+
+    // 并发请求超过连接上限时排队等待
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("k{}", i);
+                client.set(&key, "v").await?;
+                client.get(&key).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await??, Some("v".to_string()));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_with_backoff() -> Result<(), Box<dyn Error>> {
+    // 服务器晚一点才启动，客户端重试直到连上
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let connecting = tokio::spawn(FoobarClient::with_config(ClientConfig {
+        addr: format!("127.0.0.1:{}", port),
+        connect_attempts: 20,
+        backoff_min: Duration::from_millis(10),
+        backoff_max: Duration::from_millis(50),
+        ..Default::default()
+    }));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let server = Server::new(ServerConfig {
+        port,
+        ..Default::default()
+    });
+    let listeners = server.bind().await.unwrap();
+    let handle = tokio::spawn(async move {
+        let _ = server.serve(listeners).await;
+    });
+
+    let client = connecting.await??;
+    client.set("key", "value").await?;
+    assert_eq!(client.get("key").await?, Some("value".to_string()));
+
+    // 没有服务器时重试次数用完后报错
+    handle.abort();
+    let err = FoobarClient::with_config(ClientConfig {
+        addr: format!("127.0.0.1:{}", port),
+        connect_attempts: 2,
+        backoff_min: Duration::from_millis(1),
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(err, Err(ClientError::Io(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_check_and_timeout() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let client = FoobarClient::with_config(ClientConfig {
+        addr: server.addr().to_string(),
+        max_connections: 1,
+        min_idle: 1,
+        health_check_after: Duration::ZERO,
+        request_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+
+    // 空闲连接被服务器关闭后，健康检查发现并换一条新连接
+    let id: i64 = client.query(&["CLIENT", "ID"]).await?;
+    let mut admin = server.connect().await?;
+    let killed = admin
+        .command(&["CLIENT", "KILL", "ID", &id.to_string()])
+        .await?;
+    assert_eq!(killed, RespValue::Integer(1));
+    let new_id: i64 = client.query(&["CLIENT", "ID"]).await?;
+    assert_ne!(new_id, id);
+
+    // 超时的请求报错，连接随之关闭，之后的请求不受影响
+    let err = client
+        .query::<RespValue>(&["BLMOVE", "a", "b", "LEFT", "LEFT", "0"])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ClientError::Timeout));
+    client.set("key", "value").await?;
+    assert_eq!(client.get("key").await?, Some("value".to_string()));
+
+    Ok(())
+}
+
+// 模拟的集群节点: 槽位 0..8192 属于节点 0, 其余属于节点 1。
+// {migrating} 所在的槽正从节点 0 迁往节点 1, {loop} 的槽两个节点互相推给对方。
+struct MockNode {
+    id: usize,
+    addrs: [SocketAddr; 2],
+    store: Mutex<HashMap<String, String>>,
+    redirects: AtomicUsize,
+}
+
+impl MockNode {
+    fn handle(&self, args: &[String], asking: &mut bool) -> String {
+        let was_asking = std::mem::take(asking);
+        let cmd = args[0].to_ascii_uppercase();
+        match cmd.as_str() {
+            "PING" => return "+PONG\r\n".to_string(),
+            "ASKING" => {
+                *asking = true;
+                return "+OK\r\n".to_string();
+            }
+            // 过时的映射: 所有槽都在节点 0 上
+            "CLUSTER" => {
+                let a = self.addrs[0];
+                return format!(
+                    "*1\r\n*3\r\n:0\r\n:16383\r\n*2\r\n${}\r\n{}\r\n:{}\r\n",
+                    a.ip().to_string().len(),
+                    a.ip(),
+                    a.port()
+                );
+            }
+            _ => {}
+        }
+
+        let key = &args[1];
+        let slot = key_slot(key);
+        let other = self.addrs[1 - self.id];
+        let owner = if slot == key_slot("{migrating}") {
+            // 节点 0 把迁移中的槽推给节点 1, 节点 1 只在 ASKING 之后接受
+            if self.id == 0 {
+                self.redirects.fetch_add(1, Ordering::SeqCst);
+                return format!("-ASK {} {}\r\n", slot, other);
+            }
+            if was_asking {
+                self.id
+            } else {
+                0
+            }
+        } else if slot == key_slot("{loop}") {
+            1 - self.id
+        } else {
+            usize::from(slot >= 8192)
+        };
+        if owner != self.id {
+            self.redirects.fetch_add(1, Ordering::SeqCst);
+            return format!("-MOVED {} {}\r\n", slot, other);
+        }
+
+        let mut store = self.store.lock().unwrap();
+        match cmd.as_str() {
+            "SET" => {
+                store.insert(key.clone(), args[2].clone());
+                "+OK\r\n".to_string()
+            }
+            "GET" => match store.get(key) {
+                Some(value) => format!("${}\r\n{}\r\n", value.len(), value),
+                None => "$-1\r\n".to_string(),
+            },
+            _ => "-ERR unknown command\r\n".to_string(),
+        }
+    }
+
+    async fn serve(self: Arc<Self>, mut stream: TcpStream) {
+        let mut parser = Parser::new(32, usize::MAX);
+        let mut asking = false;
+        loop {
+            match parser.try_parse() {
+                Ok(Some(RespValue::Array(Some(items)))) => {
+                    let args: Vec<String> = items
+                        .iter()
+                        .map(|item| match item {
+                            RespValue::BulkString(Some(s)) => s.to_string(),
+                            _ => String::new(),
+                        })
+                        .collect();
+                    let reply = self.handle(&args, &mut asking);
+                    if stream.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Some(_)) => return,
+                _ => match stream.read_buf(&mut parser.buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+}
+
+async fn start_mock_cluster() -> Result<[Arc<MockNode>; 2], Box<dyn Error>> {
+    let listeners = [
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+    ];
+    let addrs = [listeners[0].local_addr()?, listeners[1].local_addr()?];
+    let mut nodes = Vec::new();
+    for (id, listener) in listeners.into_iter().enumerate() {
+        let node = Arc::new(MockNode {
+            id,
+            addrs,
+            store: Mutex::new(HashMap::new()),
+            redirects: AtomicUsize::new(0),
+        });
+        let serving = node.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serving.clone().serve(stream));
+            }
+        });
+        nodes.push(node);
+    }
+    Ok([nodes[0].clone(), nodes[1].clone()])
+}
+
+#[tokio::test]
+async fn test_cluster_routing() -> Result<(), Box<dyn Error>> {
+    let [a, b] = start_mock_cluster().await?;
+    let client = ClusterClient::connect(a.addrs[0].to_string()).await?;
+    let (foo, bar) = ("foo", "bar");
+    assert!(key_slot(foo) >= 8192 && key_slot(bar) < 8192);
+    assert_eq!(
+        client.slot_owner(key_slot(foo)),
+        Some(a.addrs[0].to_string())
+    );
+
+    // MOVED 之后客户端记住新的归属, 不再问节点 0
+    client.set(foo, "1").await?;
+    client.set(bar, "2").await?;
+    assert_eq!(a.redirects.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        client.slot_owner(key_slot(foo)),
+        Some(b.addrs[1].to_string())
+    );
+    assert_eq!(client.get(foo).await?, Some("1".to_string()));
+    assert_eq!(client.get(bar).await?, Some("2".to_string()));
+    assert_eq!(a.redirects.load(Ordering::SeqCst), 1);
+    assert!(b.store.lock().unwrap().contains_key(foo));
+    assert!(a.store.lock().unwrap().contains_key(bar));
+
+    // ASK 只对这一条命令有效, 映射不变
+    client.set("{migrating}k", "v").await?;
+    assert_eq!(client.get("{migrating}k").await?, Some("v".to_string()));
+    assert_eq!(a.redirects.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        client.slot_owner(key_slot("{migrating}")),
+        Some(a.addrs[0].to_string())
+    );
+
+    // 没有键的命令发到种子节点
+    client.ping().await?;
+
+    // 来回重定向有上限
+    let err = client.get("{loop}k").await.unwrap_err();
+    assert!(matches!(err, ClientError::TooManyRedirects));
+
+    Ok(())
+}
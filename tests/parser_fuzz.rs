@@ -0,0 +1,73 @@
+use foobar_db::test_util::check_parser;
+use proptest::prelude::*;
+
+// 生成合法的 RESP 帧: 简单字符串, 错误, 整数, 批量字符串和嵌套数组
+fn frame() -> impl Strategy<Value = Vec<u8>> {
+    let leaf = prop_oneof![
+        "[a-zA-Z0-9 ]{0,16}".prop_map(|s| format!("+{}\r\n", s).into_bytes()),
+        "[A-Z]{1,8} [a-z ]{0,16}".prop_map(|s| format!("-{}\r\n", s).into_bytes()),
+        any::<i64>().prop_map(|n| format!(":{}\r\n", n).into_bytes()),
+        "[ -~]{0,32}".prop_map(|s| format!("${}\r\n{}\r\n", s.len(), s).into_bytes()),
+        Just(b"$-1\r\n".to_vec()),
+    ];
+    leaf.prop_recursive(4, 32, 6, |inner| {
+        prop::collection::vec(inner, 0..6).prop_map(|items| {
+            let mut out = format!("*{}\r\n", items.len()).into_bytes();
+            for item in items {
+                out.extend(item);
+            }
+            out
+        })
+    })
+}
+
+// 请求总是数组
+fn request() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(frame(), 0..6).prop_map(|items| {
+        let mut out = format!("*{}\r\n", items.len()).into_bytes();
+        for item in items {
+            out.extend(item);
+        }
+        out
+    })
+}
+
+fn splits() -> impl Strategy<Value = Vec<usize>> {
+    prop::collection::vec(0..512usize, 0..16)
+}
+
+proptest! {
+    // 任意字节: 不崩溃, 不死循环, 错误稳定
+    #[test]
+    fn test_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..256), splits in splits()) {
+        check_parser(&data, &splits);
+    }
+
+    // 合法的请求无论怎样切分都能全部解析出来
+    #[test]
+    fn test_valid_requests(requests in prop::collection::vec(request(), 1..6), splits in splits()) {
+        let data = requests.concat();
+        let run = check_parser(&data, &splits);
+        prop_assert_eq!(run.error, None);
+        prop_assert_eq!(run.requests.len(), requests.len());
+    }
+
+    // 合法请求中改掉一个字节或截断, 同样不能让解析器出问题
+    #[test]
+    fn test_mutated_requests(
+        requests in prop::collection::vec(request(), 1..4),
+        at in any::<prop::sample::Index>(),
+        byte in any::<u8>(),
+        truncate in any::<bool>(),
+        splits in splits(),
+    ) {
+        let mut data = requests.concat();
+        let at = at.index(data.len());
+        if truncate {
+            data.truncate(at);
+        } else {
+            data[at] = byte;
+        }
+        check_parser(&data, &splits);
+    }
+}
@@ -0,0 +1,225 @@
+use foobar_db::test_util::{TestClient, TestServer};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+// 把命令编码成 RESP 数组
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    out
+}
+
+// 发送命令并逐字节比较回复, 多出来的字节会在下一条命令时暴露
+async fn expect(
+    client: &mut TestClient,
+    args: &[&str],
+    expected: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let stream = client.stream();
+    stream.write_all(&encode(args)).await?;
+    let mut reply = vec![0u8; expected.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut reply)).await??;
+    assert_eq!(
+        String::from_utf8_lossy(&reply),
+        String::from_utf8_lossy(expected),
+        "reply to {:?}",
+        args
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_string_and_key_replies() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+
+    expect(&mut client, &["SET", "k", "v"], b"+OK\r\n").await?;
+    expect(&mut client, &["GET", "k"], b"$1\r\nv\r\n").await?;
+    expect(&mut client, &["GET", "missing"], b"$-1\r\n").await?;
+    expect(&mut client, &["SET", "n", "1"], b"+OK\r\n").await?;
+
+    // DEL 和 UNLINK 返回实际删除的键数
+    expect(&mut client, &["DEL", "k", "missing"], b":1\r\n").await?;
+    expect(&mut client, &["DEL", "k"], b":0\r\n").await?;
+    expect(&mut client, &["UNLINK", "n", "missing"], b":1\r\n").await?;
+
+    expect(&mut client, &["SET", "k", "v"], b"+OK\r\n").await?;
+    expect(&mut client, &["TTL", "k"], b":-1\r\n").await?;
+    expect(&mut client, &["TTL", "missing"], b":-2\r\n").await?;
+    expect(&mut client, &["EXPIRE", "missing", "10"], b":0\r\n").await?;
+    expect(&mut client, &["EXPIRE", "k", "10"], b":1\r\n").await?;
+    expect(&mut client, &["PERSIST", "k"], b":1\r\n").await?;
+    expect(&mut client, &["TYPE", "k"], b"+string\r\n").await?;
+    expect(&mut client, &["TYPE", "missing"], b"+none\r\n").await?;
+
+    // 没有从节点的主节点
+    expect(
+        &mut client,
+        &["ROLE"],
+        b"*3\r\n$6\r\nmaster\r\n:0\r\n*0\r\n",
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_replies() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+
+    // 推入返回列表长度
+    expect(&mut client, &["LPUSH", "l", "a", "b"], b":2\r\n").await?;
+    expect(&mut client, &["RPUSH", "l", "c"], b":3\r\n").await?;
+    expect(
+        &mut client,
+        &["LRANGE", "l", "0", "-1"],
+        b"*3\r\n$1\r\nb\r\n$1\r\na\r\n$1\r\nc\r\n",
+    )
+    .await?;
+    expect(
+        &mut client,
+        &["LINSERT", "l", "BEFORE", "a", "x"],
+        b":4\r\n",
+    )
+    .await?;
+    expect(
+        &mut client,
+        &["LINSERT", "l", "BEFORE", "nope", "x"],
+        b":-1\r\n",
+    )
+    .await?;
+    expect(&mut client, &["LREM", "l", "0", "x"], b":1\r\n").await?;
+    expect(&mut client, &["LSET", "l", "0", "z"], b"+OK\r\n").await?;
+    expect(&mut client, &["LPOP", "l"], b"$1\r\nz\r\n").await?;
+    expect(
+        &mut client,
+        &["RPOP", "l", "5"],
+        b"*2\r\n$1\r\nc\r\n$1\r\na\r\n",
+    )
+    .await?;
+    expect(&mut client, &["LTRIM", "l", "0", "0"], b"+OK\r\n").await?;
+
+    // 不存在的键: 单个元素是 nil, 带数量时是 nil 数组, 范围是空数组
+    expect(&mut client, &["LPOP", "missing"], b"$-1\r\n").await?;
+    expect(&mut client, &["LPOP", "missing", "2"], b"*-1\r\n").await?;
+    expect(&mut client, &["LRANGE", "missing", "0", "-1"], b"*0\r\n").await?;
+    expect(&mut client, &["LLEN", "missing"], b":0\r\n").await?;
+    expect(
+        &mut client,
+        &["LINSERT", "missing", "BEFORE", "a", "x"],
+        b":0\r\n",
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_set_hash_zset_replies() -> Result<(), Box<dyn Error>> {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await?;
+
+    // 集合返回新增和删除的成员数
+    expect(&mut client, &["SADD", "s", "a", "b", "a"], b":2\r\n").await?;
+    expect(&mut client, &["SADD", "s", "a"], b":0\r\n").await?;
+    expect(&mut client, &["SREM", "s", "a", "x"], b":1\r\n").await?;
+    expect(&mut client, &["SMEMBERS", "s"], b"*1\r\n$1\r\nb\r\n").await?;
+    expect(&mut client, &["SMEMBERS", "missing"], b"*0\r\n").await?;
+    expect(&mut client, &["SRANDMEMBER", "missing"], b"$-1\r\n").await?;
+    expect(&mut client, &["SRANDMEMBER", "missing", "2"], b"*0\r\n").await?;
+
+    // HSET 返回新字段数
+    expect(&mut client, &["HSET", "h", "f", "1", "g", "2"], b":2\r\n").await?;
+    expect(&mut client, &["HSET", "h", "f", "3"], b":0\r\n").await?;
+    expect(&mut client, &["HGET", "h", "f"], b"$1\r\n3\r\n").await?;
+    expect(&mut client, &["HGET", "h", "nope"], b"$-1\r\n").await?;
+
+    expect(&mut client, &["ZADD", "z", "1", "a", "2", "b"], b":2\r\n").await?;
+    expect(&mut client, &["ZADD", "z", "3", "a"], b":0\r\n").await?;
+    expect(&mut client, &["ZCARD", "z"], b":2\r\n").await?;
+
+    // 类型错误
+    expect(
+        &mut client,
+        &["LPUSH", "h", "x"],
+        b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+    )
+    .await?;
+
+    Ok(())
+}
+
+// 黄金输出脚本中的一条命令: 参数, 期望的回复字节和所在行号
+struct GoldenCase {
+    line: usize,
+    args: Vec<String>,
+    expected: Vec<u8>,
+}
+
+// 脚本格式: "> " 开头的行是命令 (参数以空白分隔), 其后直到下一条命令的行是
+// Redis 给出的回复, 每行一行 RESP, 省略了 \r\n. 第一条命令之前 # 开头的行是注释.
+fn parse_golden(text: &str) -> Vec<GoldenCase> {
+    let mut cases: Vec<GoldenCase> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if let Some(command) = line.strip_prefix("> ") {
+            cases.push(GoldenCase {
+                line: i + 1,
+                args: command.split_whitespace().map(str::to_string).collect(),
+                expected: vec![],
+            });
+        } else if let Some(case) = cases.last_mut() {
+            case.expected.extend_from_slice(line.as_bytes());
+            case.expected.extend_from_slice(b"\r\n");
+        } else {
+            assert!(
+                line.is_empty() || line.starts_with('#'),
+                "line {}: reply before any command",
+                i + 1
+            );
+        }
+    }
+    cases
+}
+
+// 逐个运行 tests/golden 下的脚本, 每个脚本用一个新的服务器
+#[tokio::test]
+async fn test_golden_outputs() -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut scripts = fs::read_dir(&dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    scripts.retain(|path| path.extension().is_some_and(|ext| ext == "golden"));
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no golden scripts in {:?}", dir);
+
+    for path in scripts {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let server = TestServer::start().await;
+        let mut client = server.connect().await?;
+        for case in parse_golden(&fs::read_to_string(&path)?) {
+            let args: Vec<&str> = case.args.iter().map(String::as_str).collect();
+            let stream = client.stream();
+            stream.write_all(&encode(&args)).await?;
+            let mut reply = vec![0u8; case.expected.len()];
+            timeout(Duration::from_secs(5), stream.read_exact(&mut reply)).await??;
+            assert_eq!(
+                String::from_utf8_lossy(&reply),
+                String::from_utf8_lossy(&case.expected),
+                "{}:{}: reply to {:?}",
+                name,
+                case.line,
+                args
+            );
+        }
+        // 回复多出的字节会让这里对不上
+        expect(&mut client, &["PING"], b"+PONG\r\n").await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use foobar_db::client::FoobarClient;
+use foobar_db::sentinel::{Sentinel, SentinelConfig};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use stream_resp::parser::Parser;
+use stream_resp::resp::RespValue;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+
+// 模拟的节点: 回答 PING, INFO replication 和 REPLICAOF, down 之后不再回复
+struct MockNode {
+    addr: SocketAddr,
+    // 跟随的主节点, None 表示自己是主节点
+    master: Mutex<Option<String>>,
+    offset: i64,
+    // 主节点在 INFO 里列出的从节点
+    replicas: Mutex<Vec<SocketAddr>>,
+    down: AtomicBool,
+    promotions: AtomicUsize,
+}
+
+impl MockNode {
+    fn handle(&self, args: &[String]) -> String {
+        match args[0].to_ascii_uppercase().as_str() {
+            "PING" => "+PONG\r\n".to_string(),
+            "INFO" => {
+                let info = match &*self.master.lock().unwrap() {
+                    None => {
+                        let mut info = format!(
+                            "# Replication\r\nrole:master\r\nmaster_repl_offset:{}\r\n",
+                            self.offset
+                        );
+                        for (i, replica) in self.replicas.lock().unwrap().iter().enumerate() {
+                            info.push_str(&format!(
+                                "slave{}:ip={},port={},state=online,offset=0,lag=0\r\n",
+                                i,
+                                replica.ip(),
+                                replica.port()
+                            ));
+                        }
+                        info
+                    }
+                    Some(master) => {
+                        let (host, port) = master.rsplit_once(':').unwrap();
+                        format!(
+                            "# Replication\r\nrole:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\n\
+                             slave_repl_offset:{}\r\n",
+                            host, port, self.offset
+                        )
+                    }
+                };
+                format!("${}\r\n{}\r\n", info.len(), info)
+            }
+            "REPLICAOF" if args[1].eq_ignore_ascii_case("NO") => {
+                *self.master.lock().unwrap() = None;
+                self.promotions.fetch_add(1, Ordering::SeqCst);
+                "+OK\r\n".to_string()
+            }
+            "REPLICAOF" => {
+                *self.master.lock().unwrap() = Some(format!("{}:{}", args[1], args[2]));
+                "+OK\r\n".to_string()
+            }
+            _ => "-ERR unknown command\r\n".to_string(),
+        }
+    }
+
+    async fn serve(self: Arc<Self>, mut stream: TcpStream) {
+        let mut parser = Parser::new(32, usize::MAX);
+        loop {
+            match parser.try_parse() {
+                Ok(Some(RespValue::Array(Some(items)))) => {
+                    if self.down.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let args: Vec<String> = items
+                        .iter()
+                        .map(|item| match item {
+                            RespValue::BulkString(Some(s)) => s.to_string(),
+                            _ => String::new(),
+                        })
+                        .collect();
+                    let reply = self.handle(&args);
+                    if stream.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Some(_)) => return,
+                _ => match stream.read_buf(&mut parser.buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                },
+            }
+        }
+    }
+}
+
+async fn start_node(
+    master: Option<SocketAddr>,
+    offset: i64,
+) -> Result<Arc<MockNode>, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let node = Arc::new(MockNode {
+        addr: listener.local_addr()?,
+        master: Mutex::new(master.map(|addr| addr.to_string())),
+        offset,
+        replicas: Mutex::new(vec![]),
+        down: AtomicBool::new(false),
+        promotions: AtomicUsize::new(0),
+    });
+    let serving = node.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serving.clone().serve(stream));
+        }
+    });
+    Ok(node)
+}
+
+async fn master_of(sentinel: SocketAddr) -> Result<String, Box<dyn Error>> {
+    let client = FoobarClient::connect(sentinel.to_string()).await?;
+    let addr: Vec<String> = client
+        .query(&["SENTINEL", "GET-MASTER-ADDR-BY-NAME", "mymaster"])
+        .await?;
+    Ok(addr.join(":"))
+}
+
+#[tokio::test]
+async fn test_failover() -> Result<(), Box<dyn Error>> {
+    let master = start_node(None, 120).await?;
+    // 数据更多的 a 应该被提升
+    let a = start_node(Some(master.addr), 100).await?;
+    let b = start_node(Some(master.addr), 80).await?;
+    *master.replicas.lock().unwrap() = vec![a.addr, b.addr];
+
+    // 三个哨兵, 法定人数 2; 从节点由主节点的 INFO 得知
+    let listeners = [
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+        TcpListener::bind("127.0.0.1:0").await?,
+    ];
+    let addrs: Vec<SocketAddr> = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<Result<_, _>>()?;
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let sentinel = Sentinel::new(SentinelConfig {
+            master: master.addr.to_string(),
+            peers: addrs
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, addr)| addr.to_string())
+                .collect(),
+            quorum: 2,
+            down_after: Duration::from_millis(300),
+            failover_timeout: Duration::from_secs(1),
+            ping_interval: Duration::from_millis(50),
+            ..Default::default()
+        });
+        tokio::spawn(sentinel.clone().serve(listener));
+        tokio::spawn(sentinel.monitor());
+    }
+
+    sleep(Duration::from_millis(300)).await;
+    for addr in &addrs {
+        assert_eq!(master_of(*addr).await?, master.addr.to_string());
+    }
+
+    // 主节点失联后所有哨兵都切到 a, 只提升一次, b 改为跟随 a
+    master.down.store(true, Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    for addr in &addrs {
+        while master_of(*addr).await? != a.addr.to_string() {
+            assert!(
+                Instant::now() < deadline,
+                "sentinel {} didn't fail over",
+                addr
+            );
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+    assert_eq!(a.promotions.load(Ordering::SeqCst), 1);
+    assert_eq!(b.promotions.load(Ordering::SeqCst), 0);
+    assert_eq!(*a.master.lock().unwrap(), None);
+    assert_eq!(*b.master.lock().unwrap(), Some(a.addr.to_string()));
+
+    Ok(())
+}